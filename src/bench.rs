@@ -0,0 +1,222 @@
+//! Measuring compression settings on a sample of the data, for `sage bench`.
+//!
+//! A sample of up to a few tens of MiB is read into memory once, from a file or from the
+//! files below a directory, and compressed with every [`Setting`] in turn, so the disk does
+//! not limit what is measured. Encryption and error correction cost the same for every
+//! setting and are left out.
+
+use crate::compress::Codec;
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::Instant;
+
+/// How much slower than the fastest setting the recommended one may be, without a minimum
+/// speed.
+const SPEED_TOLERANCE: f64 = 10.0;
+
+/// A codec, level and thread count to compress with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Setting {
+    #[serde(serialize_with = "codec_name")]
+    pub codec: Codec,
+    /// The level, or `None` for codecs without levels.
+    pub level: Option<i32>,
+    pub threads: u32,
+}
+
+fn codec_name<S: serde::Serializer>(codec: &Codec, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(codec)
+}
+
+/// How one [`Setting`] did on the sample.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Measurement {
+    #[serde(flatten)]
+    pub setting: Setting,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub secs: f64,
+}
+
+impl Measurement {
+    /// The compressed size relative to the sample.
+    pub fn ratio(&self) -> f64 {
+        self.bytes_out as f64 / self.bytes_in.max(1) as f64
+    }
+
+    /// The bytes of sample compressed per second.
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.bytes_in as f64 / self.secs.max(f64::EPSILON)
+    }
+}
+
+/// Reads up to `limit` bytes from the file at `path`, or from the files below it if it is a
+/// directory, taking a slice of each so that the sample mixes many files.
+pub fn read_sample(path: &Path, limit: u64) -> crate::Result<Vec<u8>> {
+    let mut sample = Vec::new();
+    if !path.is_dir() {
+        File::open(path)
+            .and_then(|file| file.take(limit).read_to_end(&mut sample))
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        return Ok(sample);
+    }
+    let per_file = (limit / 16).max(64 * 1024);
+    let walker = ignore::WalkBuilder::new(path)
+        .standard_filters(false)
+        .sort_by_file_path(|a, b| a.cmp(b))
+        .build();
+    for entry in walker {
+        let remaining = limit - sample.len() as u64;
+        if remaining == 0 {
+            break;
+        }
+        let entry = entry.with_context(|| format!("Failed to read {}", path.display()))?;
+        if !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+        match File::open(entry.path()) {
+            Ok(file) => {
+                file.take(per_file.min(remaining))
+                    .read_to_end(&mut sample)
+                    .with_context(|| format!("Failed to read {}", entry.path().display()))?;
+            }
+            Err(e) => warn!("Skipping {}: {e}", entry.path().display()),
+        }
+    }
+    debug!(
+        "Read a sample of {} bytes from {}.",
+        sample.len(),
+        path.display()
+    );
+    Ok(sample)
+}
+
+/// The settings worth comparing on a machine with `cpus` processors: a spread of levels of
+/// every codec, on one thread and, for the codecs that can use them, on all of them.
+pub fn settings(codecs: &[Codec], cpus: u32) -> Vec<Setting> {
+    let mut settings = Vec::new();
+    for &codec in codecs {
+        let levels: &[i32] = match codec {
+            Codec::Zstd => &[1, 3, 9, 19],
+            Codec::Xz => &[1, 6],
+            Codec::Brotli => &[1, 5, 9],
+            Codec::Lz4 | Codec::None => &[0],
+        };
+        let threads: &[u32] = if codec.is_multithreaded() && cpus > 1 {
+            &[1, cpus]
+        } else {
+            &[1]
+        };
+        for &level in levels {
+            for &threads in threads {
+                settings.push(Setting {
+                    codec,
+                    level: codec.effective_level(level),
+                    threads,
+                });
+            }
+        }
+    }
+    settings
+}
+
+/// Compresses `sample` with `setting` and measures how long it took.
+pub fn measure(sample: &[u8], setting: Setting) -> crate::Result<Measurement> {
+    crate::cancel::check()?;
+    let started = Instant::now();
+    let mut encoder = setting
+        .codec
+        .encoder(
+            Counter::default(),
+            setting.level.unwrap_or(0),
+            setting.threads,
+        )
+        .with_context(|| format!("Failed to create {} encoder", setting.codec))?;
+    encoder.write_all(sample)?;
+    let bytes_out = encoder.finish()?.0;
+    Ok(Measurement {
+        setting,
+        bytes_in: sample.len() as u64,
+        bytes_out,
+        secs: started.elapsed().as_secs_f64(),
+    })
+}
+
+/// Picks the setting to recommend: the smallest output among those at least `min_speed`
+/// bytes per second fast, or, without a minimum, at least a tenth as fast as the fastest.
+/// If none is fast enough, the fastest is recommended.
+pub fn recommend(results: &[Measurement], min_speed: Option<f64>) -> Option<&Measurement> {
+    let fastest = results
+        .iter()
+        .max_by(|a, b| a.bytes_per_sec().total_cmp(&b.bytes_per_sec()))?;
+    let min_speed = min_speed.unwrap_or(fastest.bytes_per_sec() / SPEED_TOLERANCE);
+    results
+        .iter()
+        .filter(|result| result.bytes_per_sec() >= min_speed)
+        .min_by_key(|result| result.bytes_out)
+        .or(Some(fastest))
+}
+
+/// Counts the bytes written to it.
+#[derive(Default)]
+struct Counter(u64);
+
+impl Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(codec: Codec, bytes_out: u64, secs: f64) -> Measurement {
+        Measurement {
+            setting: Setting {
+                codec,
+                level: None,
+                threads: 1,
+            },
+            bytes_in: 1000,
+            bytes_out,
+            secs,
+        }
+    }
+
+    #[test]
+    fn recommends_the_smallest_output_that_is_fast_enough() {
+        let results = [
+            result(Codec::Lz4, 600, 0.1),
+            result(Codec::Zstd, 405, 0.5),
+            result(Codec::Xz, 400, 4.0),
+        ];
+        let best = recommend(&results, None).unwrap();
+        assert_eq!(best.setting.codec, Codec::Zstd);
+        let best = recommend(&results, Some(5000.0)).unwrap();
+        assert_eq!(best.setting.codec, Codec::Lz4);
+        let best = recommend(&results, Some(100.0)).unwrap();
+        assert_eq!(best.setting.codec, Codec::Xz);
+        let best = recommend(&results, Some(1e9)).unwrap();
+        assert_eq!(best.setting.codec, Codec::Lz4);
+    }
+
+    #[test]
+    fn measures_every_setting() {
+        let sample = b"sage ".repeat(10_000);
+        for setting in settings(&[Codec::Zstd, Codec::Lz4], 2) {
+            let measurement = measure(&sample, setting).unwrap();
+            assert_eq!(measurement.bytes_in, sample.len() as u64);
+            assert!(measurement.ratio() < 0.1, "{setting:?}");
+        }
+    }
+}
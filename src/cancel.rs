@@ -0,0 +1,54 @@
+//! Cooperative cancellation of protect, recover and the other long-running operations.
+//!
+//! [`request`] only sets a flag, so it can be called from a signal handler. Input is read
+//! through a [`CancelReader`], which fails the next read once cancellation was requested;
+//! the error unwinds the operation normally, so unfinished output files are removed and no
+//! half-written archive is left behind.
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Asks every running operation to stop at its next read. Async-signal-safe.
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Whether cancellation was requested.
+pub fn is_requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Fails if cancellation was requested.
+pub fn check() -> io::Result<()> {
+    if is_requested() {
+        return Err(io::Error::other("operation was interrupted"));
+    }
+    Ok(())
+}
+
+/// Fails every read once cancellation was requested.
+pub struct CancelReader<R> {
+    inner: R,
+}
+
+impl<R: Read> CancelReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+}
+
+impl<R: Read> Read for CancelReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        check()?;
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Seek> Seek for CancelReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        check()?;
+        self.inner.seek(pos)
+    }
+}
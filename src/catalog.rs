@@ -0,0 +1,123 @@
+//! A local record of past protect runs, for `sage catalog`.
+//!
+//! The catalog is a JSON Lines file with one [`Run`] per line, appended to after every
+//! successful protect given `--catalog` or the `catalog` configuration key. It tells when an
+//! input was last backed up and where the archive went, without opening any archive.
+
+use anyhow::{Context, Result, anyhow};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// One successful protect run.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Run {
+    /// The number of the run, counting from 1 in each catalog.
+    pub id: u64,
+    /// When the archive was finished, in RFC 3339.
+    pub time: String,
+    pub hostname: String,
+    /// The absolute path of the input, or `-` for standard input.
+    pub input: String,
+    /// The absolute path or URL of the archive.
+    pub output: String,
+    pub bytes_out: u64,
+    pub files: Option<u64>,
+    /// The BLAKE3 hash of the checksum list in the archive, the same for archives of the
+    /// same files.
+    pub manifest_hash: Option<String>,
+    /// The recipients given with `-r`.
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    #[serde(default)]
+    pub recipients_files: Vec<String>,
+    /// Identity files whose recipients were encrypted to.
+    #[serde(default)]
+    pub identity_files: Vec<String>,
+    #[serde(default)]
+    pub passphrase: bool,
+}
+
+/// Returns the default location of the catalog, `$XDG_DATA_HOME/sage/catalog.jsonl`, if
+/// there is a home directory.
+pub fn default_path() -> Option<PathBuf> {
+    let data_home = std::env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| crate::config::home_dir().map(|home| home.join(".local").join("share")))?;
+    Some(data_home.join("sage").join("catalog.jsonl"))
+}
+
+/// Reads every run in the catalog at `path`, oldest first; a missing catalog is empty.
+pub fn read(path: &Path) -> Result<Vec<Run>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            debug!("No catalog at {}.", path.display());
+            return Ok(Vec::new());
+        }
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read catalog {}", path.display()));
+        }
+    };
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| {
+                anyhow!(
+                    "Line {} of catalog {} is invalid: {e}",
+                    i + 1,
+                    path.display()
+                )
+            })
+        })
+        .collect()
+}
+
+/// Appends `run` to the catalog at `path`, numbering it after the last run, and returns
+/// its number.
+pub fn append(path: &Path, mut run: Run) -> Result<u64> {
+    run.id = read(path)?.last().map_or(1, |last| last.id + 1);
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    let mut line = serde_json::to_vec(&run)?;
+    line.push(b'\n');
+    // One write of a whole line, so runs finishing at the same time do not interleave.
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(&line))
+        .with_context(|| format!("Failed to write catalog {}", path.display()))?;
+    Ok(run.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_runs_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sub").join("catalog.jsonl");
+        assert!(read(&path).unwrap().is_empty());
+        let run = |output: &str| Run {
+            input: "/home/me/docs".to_string(),
+            output: output.to_string(),
+            ..Run::default()
+        };
+        assert_eq!(append(&path, run("/backup/1.sage")).unwrap(), 1);
+        assert_eq!(append(&path, run("/backup/2.sage")).unwrap(), 2);
+        let runs = read(&path).unwrap();
+        assert_eq!(runs.len(), 2);
+        assert_eq!((runs[1].id, runs[1].output.as_str()), (2, "/backup/2.sage"));
+    }
+}
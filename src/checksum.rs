@@ -0,0 +1,216 @@
+//! Per-file BLAKE3 checksums, stored in the archive and checked on recover.
+//!
+//! Protect hashes every file as it is read into the tar stream and ends the stream with a
+//! [`CHECKSUM_ENTRY`] mapping each file path to its hash. Recover hashes the extracted files
+//! again and compares them, so corruption that slipped past the archive's own checks cannot
+//! produce a subtly wrong restore. Hard links, directories and symlinks carry no data of
+//! their own and are not listed.
+
+use crate::compress::Codec;
+use crate::progress::{Progress, warn_progress};
+use anyhow::anyhow;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Path of the tar entry that lists the checksum of every file in the archive.
+pub const CHECKSUM_ENTRY: &str = ".sage-checksums.json";
+
+const FORMAT: &str = "sage-checksums";
+const FORMAT_VERSION: u32 = 1;
+const ALGORITHM: &str = "blake3";
+
+/// The contents of the [`CHECKSUM_ENTRY`] of an archive.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Checksums {
+    format: String,
+    version: u32,
+    algorithm: String,
+    /// The hex-encoded hash of every file, by path in the archive.
+    pub files: BTreeMap<String, String>,
+    /// How every file was compressed, by path, in archives that store some files without
+    /// compressing them.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub compression: BTreeMap<String, FileCompression>,
+}
+
+/// The codec and level a file was compressed with.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct FileCompression {
+    pub codec: String,
+    pub level: i32,
+}
+
+impl Default for Checksums {
+    fn default() -> Self {
+        Self {
+            format: FORMAT.to_string(),
+            version: FORMAT_VERSION,
+            algorithm: ALGORITHM.to_string(),
+            files: BTreeMap::new(),
+            compression: BTreeMap::new(),
+        }
+    }
+}
+
+impl Checksums {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the hash of the file stored as `rel_path`.
+    pub fn insert(&mut self, rel_path: &Path, hash: blake3::Hash) {
+        self.files.insert(
+            rel_path.to_string_lossy().into_owned(),
+            hash.to_hex().to_string(),
+        );
+    }
+
+    /// Records that the file stored as `rel_path` was compressed with `codec` at `level`.
+    pub fn insert_compression(&mut self, rel_path: &Path, codec: Codec, level: i32) {
+        self.compression.insert(
+            rel_path.to_string_lossy().into_owned(),
+            FileCompression {
+                codec: codec.to_string(),
+                level,
+            },
+        );
+    }
+
+    /// Parses a checksum entry, or returns `None` for an unrelated file of the same name.
+    pub fn parse(contents: &[u8]) -> Option<Self> {
+        serde_json::from_slice::<Self>(contents).ok().filter(|c| {
+            c.format == FORMAT && c.version == FORMAT_VERSION && c.algorithm == ALGORITHM
+        })
+    }
+
+    /// Returns true if `hash` is the recorded hash of `rel_path`, or `None` if no hash was
+    /// recorded for it.
+    pub fn matches(&self, rel_path: &Path, hash: &blake3::Hash) -> Option<bool> {
+        let expected = self.files.get(rel_path.to_string_lossy().as_ref())?;
+        Some(expected.eq_ignore_ascii_case(hash.to_hex().as_str()))
+    }
+
+    /// Hashes the extracted files, each given by its path in the archive and the path it
+    /// was extracted to, and compares them with the recorded hashes.
+    ///
+    /// Mismatching files are removed and reported as an error, unless `keep_corrupt`, in
+    /// which case they are kept with a warning. Warnings are also passed on to `progress`.
+    pub fn check_extracted(
+        &self,
+        extracted: &[(PathBuf, PathBuf)],
+        keep_corrupt: bool,
+        progress: Option<&Arc<dyn Progress>>,
+    ) -> crate::Result<()> {
+        let (mut checked, mut corrupt) = (0usize, 0usize);
+        for (rel_path, path) in extracted {
+            let hash = match hash_file(path) {
+                Ok(hash) => hash,
+                Err(e) => {
+                    warn_progress!(progress, "Could not check {}: {e}", path.display());
+                    continue;
+                }
+            };
+            match self.matches(rel_path, &hash) {
+                None => debug!("No checksum recorded for: {}", rel_path.display()),
+                Some(true) => checked += 1,
+                Some(false) => {
+                    corrupt += 1;
+                    if keep_corrupt {
+                        warn_progress!(
+                            progress,
+                            "Checksum mismatch; keeping corrupt file: {}",
+                            path.display()
+                        );
+                    } else {
+                        warn_progress!(
+                            progress,
+                            "Checksum mismatch; removing corrupt file: {}",
+                            path.display()
+                        );
+                        fs::remove_file(path)?;
+                    }
+                }
+            }
+        }
+        debug!("{checked} extracted files match their checksums.");
+        if corrupt > 0 && !keep_corrupt {
+            return Err(anyhow!(
+                "{corrupt} extracted files did not match their checksums and were removed."
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Hashes the file at `path`.
+pub fn hash_file(path: &Path) -> io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// Hashes everything read through it.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: blake3::Hasher,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: blake3::Hasher::new(),
+        }
+    }
+
+    /// The hash of everything read so far.
+    pub fn hash(&self) -> blake3::Hash {
+        self.hasher.finalize()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Hashes everything written through it.
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: blake3::Hasher,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: blake3::Hasher::new(),
+        }
+    }
+
+    /// The hash of everything written so far.
+    pub fn hash(&self) -> blake3::Hash {
+        self.hasher.finalize()
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
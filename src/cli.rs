@@ -0,0 +1,1170 @@
+use clap::{Args, Parser, Subcommand};
+use log::LevelFilter;
+use sage::compress::Codec;
+use sage::fec::{DEFAULT_STRIPE_GROUPS, EccLayout, MAX_STRIPE_GROUPS};
+use sage::normalize::Normalization;
+use sage::ownership::IdMap;
+use sage::tar_format::TarFormat;
+use sage::unpack::OnConflict;
+use std::path::PathBuf;
+
+/// A tool to compress, encrypt, and add error correction to a file or directory.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+
+    /// Never prompt for input; fail instead. Implied by SAGE_NON_INTERACTIVE or the lack of a terminal.
+    #[arg(long, global = true, action = clap::ArgAction::SetTrue)]
+    pub non_interactive: bool,
+
+    /// Log more: -v for debug messages, -vv for trace messages. RUST_LOG overrides it.
+    #[arg(short = 'v', long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Log less: -q for warnings and errors only, -qq for errors only.
+    #[arg(
+        short = 'q',
+        long,
+        global = true,
+        action = clap::ArgAction::Count,
+        conflicts_with_all = ["verbose", "debug"]
+    )]
+    pub quiet: u8,
+
+    /// Enable debug logging; same as -v.
+    #[arg(long, global = true, action = clap::ArgAction::SetTrue)]
+    pub debug: bool,
+
+    /// Do not draw progress bars. They are also hidden when stderr is not a terminal.
+    #[arg(long, global = true, action = clap::ArgAction::SetTrue)]
+    pub no_progress: bool,
+
+    /// Write logs, progress and a final summary or error as JSON lines; `list` prints JSON.
+    #[arg(long, global = true, action = clap::ArgAction::SetTrue)]
+    pub json: bool,
+
+    /// Read the passphrase from the first line of PATH instead of prompting for it.
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        conflicts_with = "passphrase_fd"
+    )]
+    pub passphrase_file: Option<PathBuf>,
+
+    /// Read the passphrase from the first line of the open file descriptor N instead of
+    /// prompting for it.
+    #[arg(long, global = true, value_name = "N")]
+    pub passphrase_fd: Option<u32>,
+
+    /// Read defaults from this TOML file [default: ~/.config/sage/config.toml]
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+}
+
+impl Cli {
+    /// The log level chosen with -v, -q and --debug; info by default.
+    pub fn log_level(&self) -> LevelFilter {
+        let verbose = self.verbose.max(self.debug as u8);
+        match (verbose, self.quiet) {
+            (0, 0) => LevelFilter::Info,
+            (0, 1) => LevelFilter::Warn,
+            (0, _) => LevelFilter::Error,
+            (1, _) => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Compress, encrypt, and add error correction to a file or directory.
+    #[command(alias = "encrypt")]
+    Protect(ProtectArgs),
+
+    /// Correct errors, decrypt, and extract a protected archive.
+    #[command(alias = "decrypt")]
+    Recover(RecoverArgs),
+
+    /// List the entries of a protected archive without extracting them.
+    #[command(alias = "ls")]
+    List(ListArgs),
+
+    /// Write the contents of one file in an archive to standard output.
+    Cat(CatArgs),
+
+    /// Mount a seekable archive as a read-only file system, until it is unmounted.
+    Mount(MountArgs),
+
+    /// Check an archive's integrity without writing any plaintext to disk.
+    ///
+    /// Without an identity only the error correction layer is checked.
+    Verify(VerifyArgs),
+
+    /// Report files added, removed or modified in a directory since it was archived.
+    Diff(DiffArgs),
+
+    /// Predict the size of the archive protect would write, from a sample of the input.
+    Estimate(EstimateArgs),
+
+    /// Compare compression settings on a sample of the data and recommend one.
+    Bench(BenchArgs),
+
+    /// Show the unencrypted header of an archive, without needing an identity.
+    Info(InfoArgs),
+
+    /// Rebuild a damaged archive from its parity data, without decrypting it.
+    Repair(RepairArgs),
+
+    /// Rewrite an archive made by an older sage in the current format, without decrypting it.
+    ///
+    /// Identities given are checked to open the archive before it is converted, or with
+    /// --recipient, used to decrypt it.
+    Convert(ConvertArgs),
+
+    /// Generate an age identity to protect and recover archives with.
+    Keygen(KeygenArgs),
+
+    /// Protect a directory now and again whenever it changes, until interrupted.
+    Watch(WatchArgs),
+
+    /// Add files and directories to an existing archive.
+    Append(AppendArgs),
+
+    /// Change the recipients of an archive without decrypting or recompressing its payload.
+    Rekey(RekeyArgs),
+
+    /// Delete old archives in a directory according to a retention policy.
+    Prune(PruneArgs),
+
+    /// Look up past protect runs recorded in the catalog.
+    Catalog(CatalogArgs),
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ProtectArgs {
+    /// Path to the input file or directory to protect, or `-` for standard input
+    #[arg(value_name = "INPUT")]
+    pub input: PathBuf,
+
+    /// Path for the output protected file, a directory to name it in, or `-` for standard output
+    #[arg(short = 'o', long = "output", value_name = "OUTPUT")]
+    pub output: PathBuf,
+
+    /// Name the archive after TEMPLATE when OUTPUT is a directory [default: {hostname}-{input}-{date}.sage]
+    #[arg(long, value_name = "TEMPLATE")]
+    pub name_template: Option<String>,
+
+    /// Also write the archive to MIRROR, a file, directory or URL like OUTPUT, in the same pass. Can be repeated.
+    #[arg(long, value_name = "MIRROR")]
+    pub mirror: Vec<PathBuf>,
+
+    /// Take checkpoints while writing OUTPUT, and continue from the last one if an earlier run with --resume was stopped
+    #[arg(long)]
+    pub resume: bool,
+
+    /// Take a checkpoint after about every SIZE of input with --resume [default: 1GiB]
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "resume")]
+    pub checkpoint_interval: Option<u64>,
+
+    #[command(flatten)]
+    pub lock: LockArgs,
+
+    /// Encrypt to the specified RECIPIENT. Can be repeated.
+    #[arg(short = 'r', long, value_name = "RECIPIENT", num_args = 0..)]
+    pub recipient: Vec<String>,
+
+    /// Encrypt to recipients listed at PATH. Can be repeated.
+    #[arg(short = 'R', long, value_name = "RECIPIENTS_FILE", num_args = 0..)]
+    pub recipients_file: Vec<String>,
+
+    /// Encrypt to the recipients of the identities at PATH. Can be repeated.
+    #[arg(short = 'i', long, value_name = "IDENTITY_FILE")]
+    pub identity_file: Vec<String>,
+
+    /// Encrypt to the recipient of the identity stored in the OS keychain under NAME.
+    /// Can be repeated.
+    #[arg(long, value_name = "NAME")]
+    pub identity_from_keyring: Vec<String>,
+
+    /// Encrypt with a passphrase instead of recipients.
+    #[arg(
+        short = 'p',
+        long,
+        conflicts_with_all = [
+            "recipient",
+            "recipients_file",
+            "identity_file",
+            "identity_from_keyring"
+        ]
+    )]
+    pub passphrase: bool,
+
+    /// Encrypt only to the recipients of the identity files, given with -i or taken from
+    /// SAGE_IDENTITY or the configuration file.
+    #[arg(long, conflicts_with_all = ["recipient", "recipients_file", "passphrase"])]
+    pub to_self: bool,
+
+    /// Do not encrypt: write a compressed, error-corrected archive with checksums that
+    /// anyone can recover, for data that is public anyway.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "recipient",
+            "recipients_file",
+            "identity_file",
+            "identity_from_keyring",
+            "passphrase",
+            "to_self",
+            "shares",
+            "armor",
+            "compat"
+        ]
+    )]
+    pub no_encrypt: bool,
+
+    /// Also encrypt to a one-time key split into N share files, OUTPUT.share1 to
+    /// OUTPUT.shareN, any --threshold of which recover the archive.
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "threshold",
+        conflicts_with = "passphrase",
+        value_parser = clap::value_parser!(u8).range(2..)
+    )]
+    pub shares: Option<u8>,
+
+    /// Number of shares needed to recover the archive, for --shares
+    #[arg(
+        long,
+        value_name = "K",
+        requires = "shares",
+        value_parser = clap::value_parser!(u8).range(2..)
+    )]
+    pub threshold: Option<u8>,
+
+    /// Leave out files and directories matching GLOB. Can be repeated.
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+
+    /// Read exclude patterns from FILE, one per line. Can be repeated.
+    #[arg(long, value_name = "FILE")]
+    pub exclude_from: Vec<PathBuf>,
+
+    /// Skip files ignored by .gitignore, .git/info/exclude and the global git excludes file.
+    #[arg(long)]
+    pub respect_gitignore: bool,
+
+    /// Archive only what changed since the state recorded in the --base file, and update it.
+    #[arg(long, requires = "base")]
+    pub incremental: bool,
+
+    /// State file of the previous backup for --incremental; created if it does not exist.
+    #[arg(long, value_name = "STATE_FILE", requires = "incremental")]
+    pub base: Option<PathBuf>,
+
+    /// Follow symlinks and store what they point to, instead of storing the links.
+    #[arg(short = 'L', long)]
+    pub dereference: bool,
+
+    /// Store the holes of sparse files, such as disk images, as zeros.
+    #[arg(long)]
+    pub no_sparse: bool,
+
+    /// The tar format of the entries: PAX keeps long paths and exact times, GNU suits old
+    /// tools.
+    #[arg(long, value_name = "FORMAT", value_enum, default_value_t = TarFormat::Pax)]
+    pub tar_format: TarFormat,
+
+    #[command(flatten)]
+    pub preserve: PreserveArgs,
+
+    /// Write ASCII-armored age output, without error correction, for pasting as text.
+    #[arg(short = 'a', long)]
+    pub armor: bool,
+
+    /// Write a plain age file of the compressed tar stream, without the sage header or error
+    /// correction, which age, zstd and tar can recover without sage.
+    #[arg(long, conflicts_with_all = ["armor", "dedup", "train_dictionary"])]
+    pub compat: bool,
+
+    /// Write a plain age file, as with --compat, and its parity to OUTPUT.ecc, which repairs
+    /// the archive when both files are there.
+    #[arg(
+        long,
+        conflicts_with_all = ["armor", "compat", "dedup", "train_dictionary", "no_encrypt"]
+    )]
+    pub parity_sidecar: bool,
+
+    /// Store repeated content only once, using content-defined chunks.
+    #[arg(long)]
+    pub dedup: bool,
+
+    /// Compress in independent zstd frames with a seek table, so that list and extracting
+    /// selected paths skip what they do not need (zstd only).
+    #[arg(long, conflicts_with_all = ["dedup", "armor"])]
+    pub seekable: bool,
+
+    /// Store files that are already compressed, such as photos, videos and zip files, at the
+    /// fastest level instead of compressing them again (zstd only).
+    #[arg(long, conflicts_with_all = ["dedup", "seekable"])]
+    pub skip_compressed: bool,
+
+    /// Store files with these extensions, such as jpg,mp4,zip, at the fastest level instead
+    /// of compressing them (zstd only)
+    #[arg(
+        long,
+        value_name = "EXTENSIONS",
+        value_delimiter = ',',
+        conflicts_with_all = ["dedup", "seekable"]
+    )]
+    pub no_compress_extensions: Vec<String>,
+
+    /// Train a zstd dictionary on samples of the input files and compress with it, which
+    /// shrinks directories of many small, similar files (zstd only).
+    #[arg(long, conflicts_with = "seekable")]
+    pub train_dictionary: bool,
+
+    /// Make the payload depend only on the input's contents: sort entries, own them by root,
+    /// date them SOURCE_DATE_EPOCH (or 1970) and compress on one thread
+    #[arg(long)]
+    pub deterministic: bool,
+
+    /// Find repeats far apart, as in disk images and database dumps, with zstd long-distance
+    /// matching over a window of 2^WINDOW_LOG bytes; recovering needs as much memory
+    /// (zstd only)
+    #[arg(
+        long,
+        value_name = "WINDOW_LOG",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "27",
+        value_parser = clap::value_parser!(u32).range(10..=31),
+        conflicts_with = "seekable"
+    )]
+    pub long: Option<u32>,
+
+    /// Compression algorithm
+    #[arg(long, value_name = "CODEC", value_enum, default_value_t = Codec::Zstd)]
+    pub compression: Codec,
+
+    /// Compression level (1-22, clamped to the codec's range: xz 0-9, brotli 0-11)
+    #[arg(
+        short = 'c',
+        long = "compression-level",
+        visible_alias = "level",
+        value_name = "LEVEL",
+        default_value_t = 3,
+        value_parser = clap::value_parser!(i32).range(1..=22)
+    )]
+    pub compression_level: i32,
+
+    /// Compress with the fastest level (1); shorthand for `--level 1`.
+    #[arg(long, conflicts_with = "compression_level")]
+    pub fast: bool,
+
+    /// Store the tar stream without compressing it, for input that is compressed already or
+    /// when CPU time matters most; shorthand for `--compression none`.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "compression",
+            "compression_level",
+            "fast",
+            "long",
+            "seekable",
+            "skip_compressed",
+            "no_compress_extensions",
+            "train_dictionary"
+        ]
+    )]
+    pub no_compress: bool,
+
+    /// Number of compression threads (zstd and xz only) [default: number of CPUs]
+    #[arg(
+        short = 'T',
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub threads: Option<u32>,
+
+    /// Keep memory use to about SIZE, such as 512MiB, by compressing on fewer threads and,
+    /// if needed, with a smaller zstd window
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub max_memory: Option<u64>,
+
+    /// Read the input and write the archive at up to RATE each, such as 20MB/s
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    pub bwlimit: Option<u64>,
+
+    /// Error correction redundancy in percent of the data (1-100)
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        default_value_t = 12,
+        value_parser = clap::value_parser!(u8).range(1..=100)
+    )]
+    pub redundancy: u8,
+
+    /// How the error correction groups are laid out: interleaved spreads a long run of
+    /// damage, such as a bad stretch of sectors, over the groups of a stripe.
+    #[arg(
+        long,
+        value_name = "LAYOUT",
+        value_enum,
+        default_value_t = EccLayout::Sequential,
+        conflicts_with_all = ["armor", "compat", "parity_sidecar"]
+    )]
+    pub ecc_layout: EccLayout,
+
+    /// Number of groups interleaved in a stripe, for --ecc-layout interleaved; a stripe of N
+    /// groups rides out runs of damage N times longer.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = DEFAULT_STRIPE_GROUPS,
+        requires = "ecc_layout",
+        value_parser = clap::value_parser!(u16).range(2..=MAX_STRIPE_GROUPS as i64)
+    )]
+    pub stripe_size: u16,
+
+    /// Permissions of the created archive, in octal
+    #[arg(long, value_name = "OCTAL", default_value = "0600", value_parser = parse_mode)]
+    pub output_mode: u32,
+
+    /// Replace the output file if it already exists.
+    #[arg(short = 'f', long)]
+    pub force: bool,
+
+    /// List what would be archived and estimate the archive size, without writing the output.
+    #[arg(short = 'n', long, conflicts_with_all = ["incremental", "post_command"])]
+    pub dry_run: bool,
+
+    /// Read the finished archive back and check it before reporting success.
+    #[arg(long)]
+    pub verify_after_write: bool,
+
+    /// Sign the finished archive with this minisign secret key, into OUTPUT.minisig.
+    #[arg(long, value_name = "ED25519_KEY")]
+    pub sign: Option<PathBuf>,
+
+    /// Record the run in the catalog at PATH; see `sage catalog`.
+    #[arg(long, value_name = "PATH")]
+    pub catalog: Option<PathBuf>,
+
+    /// Run CMD after a successful protect; `{}` is replaced with the quoted output path, also
+    /// in `SAGE_OUTPUT`.
+    #[arg(long, value_name = "CMD")]
+    pub post_command: Option<String>,
+
+    /// Write nothing, and exit with status 10, if the input is unchanged since this archive.
+    #[arg(
+        long,
+        value_name = "PRIOR",
+        conflicts_with_all = ["incremental", "armor", "dry_run"]
+    )]
+    pub compare_to: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct RecoverArgs {
+    /// Path to the protected archive, or `-` for standard input
+    #[arg(value_name = "INPUT")]
+    pub input: PathBuf,
+
+    /// Incremental archives to apply on top of INPUT, oldest first
+    #[arg(value_name = "INCREMENT", conflicts_with = "manifest_out")]
+    pub increments: Vec<PathBuf>,
+
+    /// Directory to extract the archive into, or `-` to write its contents to standard output
+    #[arg(
+        short = 'o',
+        long = "output",
+        value_name = "OUTPUT",
+        required_unless_present_any = ["manifest_out", "test"]
+    )]
+    pub output: Option<PathBuf>,
+
+    /// Run the whole recovery but discard the files, checking every checksum; writes nothing.
+    #[arg(long, conflicts_with_all = ["output", "manifest_out", "paths"])]
+    pub test: bool,
+
+    #[command(flatten)]
+    pub identity: IdentityArgs,
+
+    /// Extract only entries matching PATTERN, and everything below matching directories.
+    /// Can be repeated.
+    #[arg(long = "path", value_name = "PATTERN", conflicts_with = "manifest_out")]
+    pub paths: Vec<String>,
+
+    /// Write a JSON listing of the archive contents to PATH (or `-`) instead of extracting.
+    #[arg(long, value_name = "PATH", conflicts_with = "output")]
+    pub manifest_out: Option<PathBuf>,
+
+    /// Keep what was extracted if recovery is interrupted, instead of removing the directory.
+    #[arg(long)]
+    pub keep_partial: bool,
+
+    /// Keep extracted files that do not match their stored checksums, with a warning,
+    /// instead of removing them and failing.
+    #[arg(long)]
+    pub keep_corrupt: bool,
+
+    /// Skip what error correction cannot repair instead of failing, extract everything else
+    /// and list the files that were lost or damaged
+    #[arg(long, conflicts_with_all = ["test", "manifest_out"])]
+    pub salvage: bool,
+
+    /// Remove the first N components from the path of every extracted entry, skipping
+    /// entries with no more
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub strip_components: usize,
+
+    /// Extract entries with absolute paths where they point, instead of skipping them
+    #[arg(long)]
+    pub allow_absolute_paths: bool,
+
+    /// What to do with entries whose paths already exist in OUTPUT
+    #[arg(long, value_name = "POLICY", value_enum, default_value_t = OnConflict::Overwrite)]
+    pub on_conflict: OnConflict,
+
+    /// Extract names in this Unicode normalization form: nfc as on Linux, nfd as on macOS
+    #[arg(long, value_name = "FORM", value_enum, default_value_t = Normalization::None)]
+    pub normalize_filenames: Normalization,
+
+    /// Number of threads for archives protected with --seekable [default: number of CPUs]
+    #[arg(
+        short = 'T',
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub threads: Option<u32>,
+
+    /// Refuse archives whose zstd window is larger than 2^N bytes, which recovering needs as
+    /// much memory as [default: the window recorded in the archive, or 27 (128 MiB)]
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(10..=31)
+    )]
+    pub max_window_log: Option<u32>,
+
+    /// Keep memory use to about SIZE, such as 512MiB, by recovering on fewer threads and
+    /// refusing zstd windows that do not fit
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub max_memory: Option<u64>,
+
+    /// Check the minisign signature of INPUT and each increment before decrypting it; PUBKEY
+    /// is a public key or the path of a public key file.
+    #[arg(long, value_name = "PUBKEY")]
+    pub verify_signature: Option<String>,
+
+    /// Signature file of INPUT, for --verify-signature [default: INPUT.minisig]
+    #[arg(long, value_name = "FILE", requires = "verify_signature")]
+    pub signature: Option<PathBuf>,
+
+    /// Replace an existing output file (single-stream archives) or manifest file.
+    #[arg(short = 'f', long)]
+    pub force: bool,
+
+    /// Translate stored owner UIDs, as FROM:TO[,FROM:TO...]
+    #[arg(long, value_name = "MAP", conflicts_with = "no_preserve_owner")]
+    pub uid_map: Option<IdMap>,
+
+    /// Translate stored owner GIDs, as FROM:TO[,FROM:TO...]
+    #[arg(long, value_name = "MAP", conflicts_with = "no_preserve_owner")]
+    pub gid_map: Option<IdMap>,
+
+    #[command(flatten)]
+    pub preserve: PreserveArgs,
+}
+
+/// Identity flags shared by the commands that decrypt an archive.
+#[derive(Args, Clone, Debug)]
+pub struct IdentityArgs {
+    /// Path to the identity file. Can be repeated.
+    #[arg(short = 'i', long, value_name = "IDENTITY_FILE")]
+    pub identity_file: Vec<String>,
+
+    /// Use the identity stored in the OS keychain under NAME. Can be repeated.
+    #[arg(long, value_name = "NAME")]
+    pub identity_from_keyring: Vec<String>,
+
+    /// Key share written by `protect --shares`; give at least its threshold. Can be repeated.
+    #[arg(long = "share", value_name = "SHARE_FILE")]
+    pub shares: Vec<PathBuf>,
+}
+
+impl IdentityArgs {
+    /// Whether no identity or share was given.
+    pub fn is_empty(&self) -> bool {
+        self.identity_file.is_empty()
+            && self.identity_from_keyring.is_empty()
+            && self.shares.is_empty()
+    }
+}
+
+/// Locking flags shared by `protect` (which locks its output) and `repair` (its input).
+#[derive(Args, Clone, Debug)]
+pub struct LockArgs {
+    /// Wait for another run holding the lock on the archive to finish, instead of failing
+    #[arg(long)]
+    pub wait: bool,
+
+    /// Do not lock the archive against other runs
+    #[arg(long, conflicts_with = "wait")]
+    pub no_lock: bool,
+}
+
+/// Metadata fidelity flags shared by `protect` (what is stored) and `recover` (what is restored).
+#[derive(Args, Clone, Debug)]
+pub struct PreserveArgs {
+    /// Keep file owners [default: on; on recover only as root]
+    #[arg(long, overrides_with = "no_preserve_owner")]
+    pub preserve_owner: bool,
+
+    /// Drop file owners; stored as root on protect, left to the current user on recover.
+    #[arg(long, overrides_with = "preserve_owner")]
+    pub no_preserve_owner: bool,
+
+    /// Keep exact permission bits, including setuid, setgid and sticky [default: on]
+    #[arg(long, overrides_with = "no_preserve_permissions")]
+    pub preserve_permissions: bool,
+
+    /// Drop special bits and group and other write access (0755/0644 modes on protect).
+    #[arg(long, overrides_with = "preserve_permissions")]
+    pub no_preserve_permissions: bool,
+
+    /// Keep modification times [default: on]
+    #[arg(long, overrides_with = "no_preserve_mtime")]
+    pub preserve_mtime: bool,
+
+    /// Drop modification times.
+    #[arg(long, overrides_with = "preserve_mtime")]
+    pub no_preserve_mtime: bool,
+
+    /// Keep extended attributes, including POSIX ACLs and SELinux labels [default: on]
+    #[arg(long, overrides_with = "no_preserve_xattrs")]
+    pub preserve_xattrs: bool,
+
+    /// Drop extended attributes.
+    #[arg(long, overrides_with = "preserve_xattrs")]
+    pub no_preserve_xattrs: bool,
+}
+
+impl PreserveArgs {
+    /// The explicit choice for owners, or `None` for the default.
+    pub fn owner(&self) -> Option<bool> {
+        choice(self.preserve_owner, self.no_preserve_owner)
+    }
+
+    /// The explicit choice for permissions, or `None` for the default.
+    pub fn permissions(&self) -> Option<bool> {
+        choice(self.preserve_permissions, self.no_preserve_permissions)
+    }
+
+    /// The explicit choice for modification times, or `None` for the default.
+    pub fn mtime(&self) -> Option<bool> {
+        choice(self.preserve_mtime, self.no_preserve_mtime)
+    }
+
+    /// The explicit choice for extended attributes, or `None` for the default.
+    pub fn xattrs(&self) -> Option<bool> {
+        choice(self.preserve_xattrs, self.no_preserve_xattrs)
+    }
+}
+
+/// Resolves a `--preserve-*`/`--no-preserve-*` pair; the last one given wins.
+fn choice(preserve: bool, no_preserve: bool) -> Option<bool> {
+    match (preserve, no_preserve) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ListArgs {
+    /// Path to the protected archive, or `-` for standard input
+    #[arg(value_name = "INPUT")]
+    pub input: PathBuf,
+
+    #[command(flatten)]
+    pub identity: IdentityArgs,
+
+    /// Also show the type, permissions, owner, size and modification time of each entry.
+    #[arg(short = 'l', long, conflicts_with = "json")]
+    pub long: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct CatArgs {
+    /// Path to the protected archive, or `-` for standard input
+    #[arg(value_name = "ARCHIVE")]
+    pub input: PathBuf,
+
+    /// Path of the file inside the archive, as `list` shows it
+    #[arg(value_name = "PATH")]
+    pub path: PathBuf,
+
+    #[command(flatten)]
+    pub identity: IdentityArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct MountArgs {
+    /// Path to the protected archive, written with `--seekable`
+    #[arg(value_name = "ARCHIVE")]
+    pub input: PathBuf,
+
+    /// Directory to mount the archive on
+    #[arg(value_name = "MOUNTPOINT")]
+    pub mountpoint: PathBuf,
+
+    #[command(flatten)]
+    pub identity: IdentityArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct DiffArgs {
+    /// Path to the protected archive, or `-` for standard input
+    #[arg(value_name = "ARCHIVE")]
+    pub archive: PathBuf,
+
+    /// The directory or file to compare the archive with
+    #[arg(value_name = "DIR")]
+    pub dir: PathBuf,
+
+    #[command(flatten)]
+    pub identity: IdentityArgs,
+
+    /// Leave out files and directories in DIR matching GLOB, as protect did. Can be repeated.
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct EstimateArgs {
+    /// Path to the file or directory to estimate
+    #[arg(value_name = "INPUT")]
+    pub input: PathBuf,
+
+    /// Fail unless the archive is expected to fit in SIZE, such as `4.7G` or `25GiB`
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub fits: Option<u64>,
+
+    /// Fail unless the archive is expected to fit in the free space of DIR
+    #[arg(long, value_name = "DIR")]
+    pub destination: Option<PathBuf>,
+
+    /// Compression algorithm
+    #[arg(long, value_name = "CODEC", value_enum, default_value_t = Codec::Zstd)]
+    pub compression: Codec,
+
+    /// Compression level (1-22, clamped to the codec's range: xz 0-9, brotli 0-11)
+    #[arg(
+        short = 'c',
+        long = "compression-level",
+        visible_alias = "level",
+        value_name = "LEVEL",
+        default_value_t = 3,
+        value_parser = clap::value_parser!(i32).range(1..=22)
+    )]
+    pub compression_level: i32,
+
+    /// Estimate an archive without compression; shorthand for `--compression none`.
+    #[arg(long, conflicts_with_all = ["compression", "compression_level"])]
+    pub no_compress: bool,
+
+    /// Error correction redundancy in percent of the data (1-100)
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        default_value_t = 12,
+        value_parser = clap::value_parser!(u8).range(1..=100)
+    )]
+    pub redundancy: u8,
+
+    /// Estimate ASCII-armored output, without error correction.
+    #[arg(short = 'a', long)]
+    pub armor: bool,
+
+    /// Estimate a plain age file, without error correction.
+    #[arg(long, conflicts_with = "armor")]
+    pub compat: bool,
+
+    /// Leave out files and directories matching GLOB. Can be repeated.
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+
+    /// Read exclude patterns from FILE, one per line. Can be repeated.
+    #[arg(long, value_name = "FILE")]
+    pub exclude_from: Vec<PathBuf>,
+
+    /// Skip files ignored by .gitignore, .git/info/exclude and the global git excludes file.
+    #[arg(long)]
+    pub respect_gitignore: bool,
+
+    /// Follow symlinks and count what they point to.
+    #[arg(short = 'L', long)]
+    pub dereference: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// A file or directory representative of the data to protect
+    #[arg(value_name = "INPUT_SAMPLE")]
+    pub input: PathBuf,
+
+    /// How much of INPUT_SAMPLE to read and compress with each setting
+    #[arg(long, value_name = "SIZE", default_value = "16MiB", value_parser = parse_size)]
+    pub sample_size: u64,
+
+    /// Only try this codec. Can be repeated [default: all but none]
+    #[arg(long, value_name = "CODEC", value_enum)]
+    pub compression: Vec<Codec>,
+
+    /// Number of threads to try besides one [default: number of CPUs]
+    #[arg(
+        short = 'T',
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub threads: Option<u32>,
+
+    /// Recommend the smallest output among settings at least this fast, per second, such as
+    /// `100MB` to keep up with the destination disk
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub min_speed: Option<u64>,
+}
+
+#[derive(Args, Debug)]
+pub struct VerifyArgs {
+    /// Path to the protected archive
+    #[arg(value_name = "INPUT")]
+    pub input: PathBuf,
+
+    #[command(flatten)]
+    pub identity: IdentityArgs,
+
+    /// Fail unless every group could still be repaired after losing N more shards.
+    #[arg(long, value_name = "N")]
+    pub simulate_loss: Option<u8>,
+}
+
+#[derive(Args, Debug)]
+pub struct InfoArgs {
+    /// Path to the protected archive, or `-` for standard input
+    #[arg(value_name = "INPUT")]
+    pub input: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct RepairArgs {
+    /// Path to the damaged archive, or `-` for standard input
+    #[arg(value_name = "INPUT")]
+    pub input: PathBuf,
+
+    /// Path for the repaired archive, or `-` for standard output
+    #[arg(short = 'o', long = "output", value_name = "OUTPUT")]
+    pub output: PathBuf,
+
+    /// Permissions of the repaired archive, in octal
+    #[arg(long, value_name = "OCTAL", default_value = "0600", value_parser = parse_mode)]
+    pub output_mode: u32,
+
+    /// Replace the output file if it already exists.
+    #[arg(short = 'f', long)]
+    pub force: bool,
+
+    #[command(flatten)]
+    pub lock: LockArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct ConvertArgs {
+    /// Path to the archive to convert, or `-` for standard input
+    #[arg(value_name = "INPUT")]
+    pub input: PathBuf,
+
+    /// Path for the converted archive, or `-` for standard output
+    #[arg(short = 'o', long = "output", value_name = "OUTPUT")]
+    pub output: PathBuf,
+
+    #[command(flatten)]
+    pub identity: IdentityArgs,
+
+    /// Import the input into a new archive encrypted to RECIPIENT, with a checksum list of
+    /// its files. The input may also be a tar archive made without sage, plain or compressed
+    /// with gzip, zstd, xz or lz4. Can be repeated.
+    #[arg(short = 'r', long, value_name = "RECIPIENT")]
+    pub recipient: Vec<String>,
+
+    /// Import the input into a new archive encrypted to the recipients listed at PATH. Can
+    /// be repeated.
+    #[arg(short = 'R', long, value_name = "RECIPIENTS_FILE")]
+    pub recipients_file: Vec<String>,
+
+    /// Error correction redundancy of the converted archive, in percent of the data (1-100)
+    /// [default: that of the input]
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        value_parser = clap::value_parser!(u8).range(1..=100)
+    )]
+    pub redundancy: Option<u8>,
+
+    /// Permissions of the converted archive, in octal
+    #[arg(long, value_name = "OCTAL", default_value = "0600", value_parser = parse_mode)]
+    pub output_mode: u32,
+
+    /// Replace the output file if it already exists.
+    #[arg(short = 'f', long)]
+    pub force: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct WatchArgs {
+    /// Protect options; `{timestamp}` in OUTPUT is replaced with the time of each run.
+    #[command(flatten)]
+    pub protect: ProtectArgs,
+
+    /// Protect again once nothing changed for this many seconds.
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    pub quiet_period: u64,
+}
+
+#[derive(Args, Debug)]
+pub struct AppendArgs {
+    /// Path to the archive to add to; replaced with the new archive unless --output is given
+    #[arg(value_name = "ARCHIVE")]
+    pub archive: PathBuf,
+
+    /// Files and directories to add, each under its own file name
+    #[arg(value_name = "PATH", required = true)]
+    pub paths: Vec<PathBuf>,
+
+    /// Write the new archive to OUTPUT, or `-` for standard output, instead of replacing ARCHIVE
+    #[arg(short = 'o', long = "output", value_name = "OUTPUT")]
+    pub output: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub identity: IdentityArgs,
+
+    /// Encrypt the new archive to RECIPIENT instead of the recipients of the identities, or
+    /// the passphrase of a passphrase-encrypted archive. Can be repeated.
+    #[arg(short = 'r', long, value_name = "RECIPIENT", num_args = 0..)]
+    pub recipient: Vec<String>,
+
+    /// Encrypt the new archive to recipients listed at PATH. Can be repeated.
+    #[arg(short = 'R', long, value_name = "RECIPIENTS_FILE", num_args = 0..)]
+    pub recipients_file: Vec<String>,
+
+    /// Leave out files and directories matching GLOB. Can be repeated.
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+
+    /// Follow symlinks and store what they point to, instead of storing the links.
+    #[arg(short = 'L', long)]
+    pub dereference: bool,
+
+    /// Store the holes of sparse files, such as disk images, as zeros.
+    #[arg(long)]
+    pub no_sparse: bool,
+
+    /// The tar format of the entries: PAX keeps long paths and exact times, GNU suits old
+    /// tools.
+    #[arg(long, value_name = "FORMAT", value_enum, default_value_t = TarFormat::Pax)]
+    pub tar_format: TarFormat,
+
+    #[command(flatten)]
+    pub preserve: PreserveArgs,
+
+    /// Permissions of the new archive, in octal
+    #[arg(long, value_name = "OCTAL", default_value = "0600", value_parser = parse_mode)]
+    pub output_mode: u32,
+
+    /// Replace OUTPUT if it already exists.
+    #[arg(short = 'f', long, requires = "output")]
+    pub force: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct RekeyArgs {
+    /// Path to the archive to rekey; replaced with the rekeyed archive unless --output is given
+    #[arg(value_name = "ARCHIVE")]
+    pub archive: PathBuf,
+
+    /// Write the rekeyed archive to OUTPUT, or `-` for standard output, instead of replacing
+    /// ARCHIVE
+    #[arg(short = 'o', long = "output", value_name = "OUTPUT")]
+    pub output: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub identity: IdentityArgs,
+
+    /// Encrypt the file key to RECIPIENT. Can be repeated.
+    #[arg(short = 'r', long, value_name = "RECIPIENT", num_args = 0..)]
+    pub recipient: Vec<String>,
+
+    /// Encrypt the file key to recipients listed at PATH. Can be repeated.
+    #[arg(short = 'R', long, value_name = "RECIPIENTS_FILE", num_args = 0..)]
+    pub recipients_file: Vec<String>,
+
+    /// Encrypt the file key with a new passphrase instead of recipients.
+    #[arg(
+        short = 'p',
+        long,
+        conflicts_with_all = ["recipient", "recipients_file", "keep"]
+    )]
+    pub passphrase: bool,
+
+    /// Keep the existing recipients and add the new ones, instead of replacing them.
+    #[arg(long)]
+    pub keep: bool,
+
+    /// Permissions of the rekeyed archive, in octal
+    #[arg(long, value_name = "OCTAL", default_value = "0600", value_parser = parse_mode)]
+    pub output_mode: u32,
+
+    /// Replace OUTPUT if it already exists.
+    #[arg(short = 'f', long, requires = "output")]
+    pub force: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct KeygenArgs {
+    /// Path for the new identity file, or `-` for standard output; never overwritten
+    #[arg(
+        short = 'o',
+        long = "output",
+        value_name = "OUTPUT",
+        default_value = "-"
+    )]
+    pub output: PathBuf,
+
+    /// Encrypt the identity file with a passphrase.
+    #[arg(short = 'p', long)]
+    pub passphrase: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct PruneArgs {
+    /// Directory of archives with a timestamp in their names, as written by `protect -o DIR`
+    #[arg(value_name = "DIR")]
+    pub dir: PathBuf,
+
+    /// Keep the newest archive of each of the last N days that have one.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub keep_daily: u32,
+
+    /// Keep the newest archive of each of the last N weeks that have one.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub keep_weekly: u32,
+
+    /// Keep the newest archive of each of the last N months that have one.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub keep_monthly: u32,
+
+    /// List what would be deleted without deleting anything.
+    #[arg(short = 'n', long)]
+    pub dry_run: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct CatalogArgs {
+    #[command(subcommand)]
+    pub command: CatalogCommand,
+
+    /// Path to the catalog [default: the `catalog` configuration key, or
+    /// ~/.local/share/sage/catalog.jsonl]
+    #[arg(long, value_name = "PATH", global = true)]
+    pub catalog: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CatalogCommand {
+    /// List the recorded runs, oldest first.
+    List {
+        /// Only list runs that protected this file or directory
+        #[arg(long, value_name = "PATH")]
+        input: Option<PathBuf>,
+    },
+
+    /// Show everything recorded about one run.
+    Show {
+        /// The number of the run, as listed by `sage catalog list`, or `last`
+        #[arg(value_name = "ID")]
+        id: String,
+    },
+}
+
+/// Parses a size in bytes such as `4.7G`, `700MB` or `25GiB`; K, M, G and T are powers of
+/// 1000, and KiB, MiB, GiB and TiB powers of 1024.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let text = s.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("Invalid size: {s}"))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "m" | "mb" => 1000_u64.pow(2),
+        "g" | "gb" => 1000_u64.pow(3),
+        "t" | "tb" => 1000_u64.pow(4),
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => {
+            return Err(format!(
+                "Invalid size unit in {s}; use K, M, G, T or KiB, MiB, GiB, TiB"
+            ));
+        }
+    };
+    Ok((number * multiplier as f64).round() as u64)
+}
+
+/// Parses a rate in bytes per second, such as `20MB/s` or `512KiB`.
+pub fn parse_rate(s: &str) -> Result<u64, String> {
+    let size = s.trim().strip_suffix("/s").unwrap_or(s);
+    match parse_size(size)? {
+        0 => Err(format!("The rate must be above zero: {s}")),
+        rate => Ok(rate),
+    }
+}
+
+/// Parses an octal permission mode such as `0600` or `644`.
+fn parse_mode(s: &str) -> Result<u32, String> {
+    let mode = u32::from_str_radix(s.trim_start_matches("0o"), 8)
+        .map_err(|_| format!("Invalid octal mode: {s}"))?;
+    if mode > 0o7777 {
+        return Err(format!("Mode out of range: {s}"));
+    }
+    Ok(mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_decimal_and_binary_sizes() {
+        assert_eq!(parse_size("4.7G"), Ok(4_700_000_000));
+        assert_eq!(parse_size("700MB"), Ok(700_000_000));
+        assert_eq!(parse_size("25GiB"), Ok(25 << 30));
+        assert_eq!(parse_size("512"), Ok(512));
+        assert!(parse_size("12 parsecs").is_err());
+        assert!(parse_size("G").is_err());
+        assert_eq!(parse_rate("20MB/s"), Ok(20_000_000));
+        assert_eq!(parse_rate("512KiB"), Ok(512 << 10));
+        assert!(parse_rate("0/s").is_err());
+    }
+}
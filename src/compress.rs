@@ -0,0 +1,574 @@
+//! Compression codecs for the tar stream inside the age payload.
+//!
+//! The codec is recorded in the archive file header. Archives whose header was damaged
+//! beyond recovery have their codec detected from the magic bytes of the decrypted payload
+//! instead, which works because every codec but brotli starts with a signature.
+
+use crate::seekable::SeekableWriter;
+use log::debug;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufReader, Cursor, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Offset of the `ustar` signature in a tar header, used to detect uncompressed payloads.
+const TAR_MAGIC_OFFSET: usize = 257;
+/// Number of payload bytes [`Codec::detect`] and [`is_tar`] look at.
+pub const DETECT_LEN: usize = TAR_MAGIC_OFFSET + 5;
+
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const LZ4_MAGIC: &[u8] = &[0x04, 0x22, 0x4d, 0x18];
+/// Magic of the zstd skippable frame that holds a trained dictionary at the start of the
+/// payload.
+const DICTIONARY_MAGIC: &[u8] = &[0x5d, 0x2a, 0x4d, 0x18];
+
+/// Largest dictionary `--train-dictionary` trains, zstd's default.
+pub const DICTIONARY_SIZE: usize = 110 * 1024;
+/// Largest zstd window, as a power of two, that readers accept when an archive does not
+/// record a larger one: 128 MiB, as zstd itself.
+pub const DEFAULT_WINDOW_LOG_MAX: u32 = 27;
+/// Window of `--long` without a value, as a power of two: 128 MiB, as zstd itself.
+pub const DEFAULT_LONG_WINDOW_LOG: u32 = 27;
+
+/// Largest dictionary a reader accepts, which bounds what a damaged length can allocate.
+const MAX_DICTIONARY_SIZE: usize = 16 * 1024 * 1024;
+
+/// Files smaller than this are compressed as usual by `--skip-compressed`, since ending a
+/// zstd frame for them would cost more than it saves.
+pub const SKIP_MIN_SIZE: u64 = 256 * 1024;
+/// Bytes at the start of a file that [`looks_compressed`] is given.
+pub const SNIFF_LEN: usize = 64 * 1024;
+/// The zstd level that files which are already compressed are stored at, which emits
+/// incompressible data as raw blocks at close to the speed of copying it.
+pub const STORED_LEVEL: i32 = 1;
+
+/// Signatures of compressed formats, with their offset in the file.
+const COMPRESSED_SIGNATURES: &[(usize, &[u8])] = &[
+    (0, &[0xff, 0xd8, 0xff]),                   // JPEG
+    (0, b"\x89PNG"),                            // PNG
+    (0, b"GIF8"),                               // GIF
+    (8, b"WEBP"),                               // WebP
+    (4, b"ftyp"),                               // MP4, MOV, M4A, HEIC, AVIF
+    (0, &[0x1a, 0x45, 0xdf, 0xa3]),             // Matroska, WebM
+    (0, b"OggS"),                               // Ogg
+    (0, b"fLaC"),                               // FLAC
+    (0, b"ID3"),                                // MP3
+    (0, b"PK\x03\x04"),                         // ZIP, JAR, DOCX, EPUB
+    (0, &[0x1f, 0x8b]),                         // gzip
+    (0, b"BZh"),                                // bzip2
+    (0, &[0x37, 0x7a, 0xbc, 0xaf, 0x27, 0x1c]), // 7-Zip
+    (0, b"Rar!"),                               // RAR
+    (0, ZSTD_MAGIC),
+    (0, XZ_MAGIC),
+    (0, LZ4_MAGIC),
+    (0, crate::AGE_MAGIC),
+];
+
+/// Returns true if `prefix`, the first [`SNIFF_LEN`] bytes of a file, looks already
+/// compressed or encrypted: it starts with the signature of such a format, or zstd at its
+/// fastest level shrinks it by less than 3%.
+pub fn looks_compressed(prefix: &[u8]) -> bool {
+    let signed = COMPRESSED_SIGNATURES.iter().any(|(offset, signature)| {
+        prefix.get(*offset..offset + signature.len()) == Some(signature)
+    });
+    if signed || crate::fec::has_magic(prefix) {
+        return true;
+    }
+    zstd::bulk::compress(prefix, 1)
+        .is_ok_and(|compressed| compressed.len() as u64 * 100 >= prefix.len() as u64 * 97)
+}
+
+/// Returns true if `prefix` starts with a POSIX or GNU tar header.
+pub fn is_tar(prefix: &[u8]) -> bool {
+    prefix.get(TAR_MAGIC_OFFSET..DETECT_LEN) == Some(b"ustar")
+}
+
+/// A compression algorithm for the archive payload.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Codec {
+    #[default]
+    Zstd,
+    Xz,
+    Lz4,
+    Brotli,
+    None,
+}
+
+impl Codec {
+    /// The identifier stored in the archive file header.
+    pub fn id(self) -> u8 {
+        match self {
+            Codec::Zstd => 0,
+            Codec::None => 1,
+            Codec::Xz => 2,
+            Codec::Lz4 => 3,
+            Codec::Brotli => 4,
+        }
+    }
+
+    /// Parses an identifier from the archive file header.
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Codec::Zstd),
+            1 => Some(Codec::None),
+            2 => Some(Codec::Xz),
+            3 => Some(Codec::Lz4),
+            4 => Some(Codec::Brotli),
+            _ => None,
+        }
+    }
+
+    /// Guesses the codec from the first [`DETECT_LEN`] bytes of a payload.
+    pub fn detect(prefix: &[u8]) -> Self {
+        if prefix.starts_with(ZSTD_MAGIC) || prefix.starts_with(DICTIONARY_MAGIC) {
+            Codec::Zstd
+        } else if prefix.starts_with(XZ_MAGIC) {
+            Codec::Xz
+        } else if prefix.starts_with(LZ4_MAGIC) {
+            Codec::Lz4
+        } else if is_tar(prefix) || prefix.starts_with(crate::dedup::DEDUP_MAGIC) {
+            Codec::None
+        } else {
+            Codec::Brotli
+        }
+    }
+
+    /// Clamps `level` to the range the codec supports, or `None` if it has no levels.
+    pub fn effective_level(self, level: i32) -> Option<i32> {
+        match self {
+            Codec::Zstd => Some(level.clamp(1, 22)),
+            Codec::Xz => Some(level.clamp(0, 9)),
+            Codec::Brotli => Some(level.clamp(0, 11)),
+            Codec::Lz4 | Codec::None => None,
+        }
+    }
+
+    /// Whether the codec can spread compression over several threads.
+    pub fn is_multithreaded(self) -> bool {
+        matches!(self, Codec::Zstd | Codec::Xz)
+    }
+
+    /// Wraps `inner` in a compressor for this codec using up to `threads` worker threads.
+    pub fn encoder<W: Write>(self, inner: W, level: i32, threads: u32) -> io::Result<Encoder<W>> {
+        let level = self.effective_level(level).unwrap_or(0);
+        Ok(match self {
+            Codec::Zstd => {
+                Encoder::Zstd(zstd_encoder(inner, level, threads, &ZstdTuning::default())?)
+            }
+            Codec::Xz if threads > 1 => {
+                debug!(
+                    "Initializing xz compression with level {} and {} threads.",
+                    level, threads
+                );
+                let stream = xz2::stream::MtStreamBuilder::new()
+                    .preset(level as u32)
+                    .threads(threads)
+                    .check(xz2::stream::Check::Crc64)
+                    .encoder()?;
+                Encoder::Xz(xz2::write::XzEncoder::new_stream(inner, stream))
+            }
+            Codec::Xz => {
+                debug!("Initializing xz compression with level {}.", level);
+                Encoder::Xz(xz2::write::XzEncoder::new(inner, level as u32))
+            }
+            Codec::Lz4 => {
+                debug!("Initializing lz4 compression.");
+                let info = lz4_flex::frame::FrameInfo::new().content_checksum(true);
+                Encoder::Lz4(lz4_flex::frame::FrameEncoder::with_frame_info(info, inner))
+            }
+            Codec::Brotli => {
+                debug!("Initializing brotli compression with quality {}.", level);
+                Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                    inner,
+                    64 * 1024,
+                    level as u32,
+                    22,
+                )))
+            }
+            Codec::None => {
+                debug!("Compression disabled.");
+                Encoder::None(inner)
+            }
+        })
+    }
+
+    /// Wraps `inner` in a decompressor for this codec.
+    ///
+    /// Decompression is single-threaded for every codec: the zstd and xz decoders process
+    /// their frames sequentially. A zstd payload that starts with a dictionary, as written
+    /// by [`write_dictionary`], is decompressed with it, and zstd frames with a window
+    /// larger than 2^`window_log_max` bytes are refused.
+    pub fn decoder<'a, R: Read + 'a>(
+        self,
+        inner: R,
+        window_log_max: u32,
+    ) -> io::Result<Box<dyn Read + 'a>> {
+        debug!("Initializing {} decompression.", self);
+        Ok(match self {
+            Codec::Zstd => zstd_decoder(inner, window_log_max)?,
+            Codec::Xz => Box::new(xz2::read::XzDecoder::new(inner)),
+            Codec::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(inner)),
+            Codec::Brotli => Box::new(brotli::Decompressor::new(inner, 64 * 1024)),
+            Codec::None => Box::new(inner),
+        })
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Codec::Zstd => "zstd",
+            Codec::Xz => "xz",
+            Codec::Lz4 => "lz4",
+            Codec::Brotli => "brotli",
+            Codec::None => "none",
+        })
+    }
+}
+
+/// Trains a zstd dictionary of up to [`DICTIONARY_SIZE`] bytes on `samples`, which should
+/// be many small pieces of data like what will be compressed with it.
+pub fn train_dictionary<S: AsRef<[u8]>>(samples: &[S]) -> io::Result<Vec<u8>> {
+    zstd::dict::from_samples(samples, DICTIONARY_SIZE)
+}
+
+/// Writes `dictionary` as a zstd skippable frame, to start a payload compressed with it.
+///
+/// Decoders skip the frame, so the payload stays valid zstd; [`Codec::decoder`] reads the
+/// dictionary from it.
+pub fn write_dictionary<W: Write + ?Sized>(writer: &mut W, dictionary: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(dictionary.len())
+        .ok()
+        .filter(|&len| len as usize <= MAX_DICTIONARY_SIZE)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "dictionary is too large"))?;
+    writer.write_all(DICTIONARY_MAGIC)?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(dictionary)
+}
+
+/// zstd settings beyond the level and threads.
+#[derive(Clone, Debug, Default)]
+pub struct ZstdTuning {
+    /// A dictionary to compress with; the payload must start with it, as written by
+    /// [`write_dictionary`].
+    pub dictionary: Option<Vec<u8>>,
+    /// Long-distance matching, which finds repeats as far apart as the window.
+    pub long: bool,
+    /// The window, as a power of two, or `None` for the window of the level.
+    pub window_log: Option<u32>,
+}
+
+impl ZstdTuning {
+    /// Whether anything differs from compressing at the level alone.
+    pub fn is_tuned(&self) -> bool {
+        self.dictionary.is_some() || self.long || self.window_log.is_some()
+    }
+}
+
+/// Wraps `inner` in a zstd compressor at `level` on `threads` threads, tuned by `tuning`.
+pub fn tuned_encoder<W: Write>(
+    inner: W,
+    level: i32,
+    threads: u32,
+    tuning: &ZstdTuning,
+) -> io::Result<Encoder<W>> {
+    let level = Codec::Zstd.effective_level(level).unwrap_or(level);
+    Ok(Encoder::Zstd(zstd_encoder(inner, level, threads, tuning)?))
+}
+
+/// Wraps `inner` in a zstd decoder, with the dictionary it starts with if there is one.
+fn zstd_decoder<'a, R: Read + 'a>(
+    mut inner: R,
+    window_log_max: u32,
+) -> io::Result<Box<dyn Read + 'a>> {
+    let mut prefix = Vec::with_capacity(8);
+    (&mut inner).take(8).read_to_end(&mut prefix)?;
+    if prefix.len() < 8 || !prefix.starts_with(DICTIONARY_MAGIC) {
+        let inner = Cursor::new(prefix).chain(inner);
+        let mut decoder = zstd::Decoder::with_buffer(BufReader::new(inner))?;
+        decoder.window_log_max(window_log_max)?;
+        return Ok(Box::new(decoder));
+    }
+    let len = u32::from_le_bytes(prefix[4..8].try_into().unwrap()) as usize;
+    if len > MAX_DICTIONARY_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "zstd dictionary at the start of the payload is too large",
+        ));
+    }
+    let mut dictionary = vec![0; len];
+    inner.read_exact(&mut dictionary)?;
+    debug!("Decompressing with a dictionary of {len} bytes.");
+    let mut decoder = zstd::Decoder::with_dictionary(BufReader::new(inner), &dictionary)?;
+    decoder.window_log_max(window_log_max)?;
+    Ok(Box::new(decoder))
+}
+
+/// Starts a zstd frame at `level` on `threads` threads, tuned by `tuning`.
+fn zstd_encoder<W: Write>(
+    inner: W,
+    level: i32,
+    threads: u32,
+    tuning: &ZstdTuning,
+) -> io::Result<zstd::Encoder<'static, W>> {
+    debug!("Initializing zstd compression with level {}.", level);
+    let mut encoder = match &tuning.dictionary {
+        Some(dictionary) => {
+            debug!(
+                "Compressing with a dictionary of {} bytes.",
+                dictionary.len()
+            );
+            zstd::Encoder::with_dictionary(inner, level, dictionary)?
+        }
+        None => zstd::Encoder::new(inner, level)?,
+    };
+    if tuning.long {
+        debug!("Enabling long-distance matching.");
+        encoder.long_distance_matching(true)?;
+    }
+    if let Some(window_log) = tuning.window_log {
+        debug!("Compressing with a window of 2^{window_log} bytes.");
+        encoder.window_log(window_log)?;
+    }
+    encoder.include_checksum(true)?;
+    if threads > 1 {
+        encoder.multithread(threads)?;
+        debug!(
+            "Enabled multithreaded zstd compression with {} threads.",
+            threads
+        );
+    }
+    Ok(encoder)
+}
+
+/// A compressor for one of the supported codecs.
+pub enum Encoder<W: Write> {
+    Zstd(zstd::Encoder<'static, W>),
+    Xz(xz2::write::XzEncoder<W>),
+    Lz4(lz4_flex::frame::FrameEncoder<W>),
+    Brotli(Box<brotli::CompressorWriter<W>>),
+    /// zstd in independent frames with a seek table; see [`crate::seekable`].
+    Seekable(Box<SeekableWriter<W>>),
+    /// zstd that stores what is already compressed at [`STORED_LEVEL`].
+    Switching(Box<SwitchingEncoder<W>>),
+    None(W),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Writes the end of the compressed stream and returns the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Xz(encoder) => encoder.finish(),
+            Encoder::Lz4(encoder) => encoder.finish().map_err(io::Error::from),
+            Encoder::Brotli(encoder) => {
+                let mut inner = encoder.into_inner();
+                inner.flush()?;
+                Ok(inner)
+            }
+            Encoder::Seekable(encoder) => encoder.finish(),
+            Encoder::Switching(encoder) => encoder.finish(),
+            Encoder::None(mut inner) => {
+                inner.flush()?;
+                Ok(inner)
+            }
+        }
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Encoder::Zstd(encoder) => encoder,
+            Encoder::Xz(encoder) => encoder,
+            Encoder::Lz4(encoder) => encoder,
+            Encoder::Brotli(encoder) => encoder.as_mut(),
+            Encoder::Seekable(encoder) => encoder.as_mut(),
+            Encoder::Switching(encoder) => encoder.as_mut(),
+            Encoder::None(inner) => inner,
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer().flush()
+    }
+}
+
+/// Where in the tar stream runs of already compressed files start and end, shared by the
+/// thread writing the tar stream and the [`SwitchingEncoder`] compressing it on another.
+///
+/// The writer counts the bytes of the tar stream with [`StoreMarks::track`] and calls
+/// [`StoreMarks::mark`] before each file; the encoder starts a new zstd frame at every
+/// offset where the answer changes. zstd reads concatenated frames as one stream, so
+/// archives written this way recover with any version of sage.
+#[derive(Debug, Default)]
+pub struct StoreMarks {
+    position: AtomicU64,
+    state: Mutex<MarkState>,
+}
+
+#[derive(Debug, Default)]
+struct MarkState {
+    active: bool,
+    stored: bool,
+    /// Offsets in the tar stream, and whether what follows them is stored.
+    switches: VecDeque<(u64, bool)>,
+}
+
+impl StoreMarks {
+    /// Starts marking a new tar stream.
+    pub fn start(&self) {
+        self.position.store(0, Ordering::Relaxed);
+        *self.lock() = MarkState {
+            active: true,
+            ..MarkState::default()
+        };
+    }
+
+    /// Stops marking, so that tar streams written without an encoder, such as for
+    /// hashing, leave no marks behind.
+    pub fn stop(&self) {
+        *self.lock() = MarkState::default();
+    }
+
+    /// Records that what is written next is, or is no longer, already compressed.
+    pub fn mark(&self, stored: bool) {
+        let mut state = self.lock();
+        if !state.active || state.stored == stored {
+            return;
+        }
+        state.stored = stored;
+        let position = self.position.load(Ordering::Relaxed);
+        state.switches.push_back((position, stored));
+    }
+
+    /// Wraps the writer of the tar stream, counting the bytes written through it.
+    pub fn track<'a, W: Write + ?Sized>(&'a self, inner: &'a mut W) -> Tracked<'a, W> {
+        Tracked { inner, marks: self }
+    }
+
+    fn next_switch(&self) -> Option<(u64, bool)> {
+        self.lock().switches.front().copied()
+    }
+
+    fn pop_switch(&self) {
+        self.lock().switches.pop_front();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MarkState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Counts the bytes of the tar stream for [`StoreMarks`].
+pub struct Tracked<'a, W: ?Sized> {
+    inner: &'a mut W,
+    marks: &'a StoreMarks,
+}
+
+impl<W: Write + ?Sized> Write for Tracked<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.marks.position.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// zstd compression that switches to [`STORED_LEVEL`] for the parts of the stream that
+/// [`StoreMarks`] marks, ending the frame at each switch.
+pub struct SwitchingEncoder<W: Write> {
+    encoder: Option<zstd::Encoder<'static, W>>,
+    level: i32,
+    threads: u32,
+    tuning: ZstdTuning,
+    stored: bool,
+    /// Bytes of the tar stream compressed so far.
+    position: u64,
+    marks: Arc<StoreMarks>,
+}
+
+impl<W: Write> SwitchingEncoder<W> {
+    pub fn new(
+        inner: W,
+        level: i32,
+        threads: u32,
+        tuning: ZstdTuning,
+        marks: Arc<StoreMarks>,
+    ) -> io::Result<Self> {
+        let level = Codec::Zstd.effective_level(level).unwrap_or(level);
+        Ok(Self {
+            encoder: Some(zstd_encoder(inner, level, threads, &tuning)?),
+            level,
+            threads,
+            tuning,
+            stored: false,
+            position: 0,
+            marks,
+        })
+    }
+
+    /// Writes the end of the last frame and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.take()?.finish()
+    }
+
+    fn take(&mut self) -> io::Result<zstd::Encoder<'static, W>> {
+        self.encoder
+            .take()
+            .ok_or_else(|| io::Error::other("zstd encoder failed while starting a frame"))
+    }
+
+    /// Ends the frame and starts one at the level for `stored`.
+    fn switch(&mut self, stored: bool) -> io::Result<()> {
+        if stored == self.stored {
+            return Ok(());
+        }
+        let level = if stored { STORED_LEVEL } else { self.level };
+        debug!(
+            "Starting a zstd frame at level {level} at offset {}.",
+            self.position
+        );
+        let inner = self.take()?.finish()?;
+        self.encoder = Some(zstd_encoder(inner, level, self.threads, &self.tuning)?);
+        self.stored = stored;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for SwitchingEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut len = buf.len();
+        while let Some((offset, stored)) = self.marks.next_switch() {
+            if offset > self.position {
+                len = len.min((offset - self.position) as usize);
+                break;
+            }
+            self.switch(stored)?;
+            self.marks.pop_switch();
+        }
+        let encoder = self
+            .encoder
+            .as_mut()
+            .ok_or_else(|| io::Error::other("zstd encoder failed while starting a frame"))?;
+        let n = encoder.write(&buf[..len])?;
+        self.position += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.encoder {
+            Some(encoder) => encoder.flush(),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,432 @@
+//! Defaults read from the environment and a configuration file.
+//!
+//! The file is TOML, at `$XDG_CONFIG_HOME/sage/config.toml` (by default
+//! `~/.config/sage/config.toml`) or wherever `--config` points. Every key is optional. An
+//! option given on the command line always wins, then [`IDENTITY_ENV`] and
+//! [`RECIPIENTS_FILE_ENV`], then the file.
+
+use crate::cli::{Command, ProtectArgs};
+use anyhow::{Context, Result, anyhow};
+use clap::ArgMatches;
+use clap::ValueEnum;
+use clap::parser::ValueSource;
+use log::{debug, error};
+use sage::compress::Codec;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Environment variable listing identity files, separated like `PATH`.
+pub const IDENTITY_ENV: &str = "SAGE_IDENTITY";
+
+/// Environment variable listing recipient files, separated like `PATH`.
+pub const RECIPIENTS_FILE_ENV: &str = "SAGE_RECIPIENTS_FILE";
+
+/// The keys given through the environment.
+#[derive(Debug, Default)]
+pub struct Environment {
+    pub identity_files: Option<Vec<String>>,
+    pub recipients_files: Option<Vec<String>>,
+}
+
+impl Environment {
+    /// Reads [`IDENTITY_ENV`] and [`RECIPIENTS_FILE_ENV`], treating empty values as unset.
+    pub fn read() -> Self {
+        let paths = |name: &str| {
+            let value = std::env::var_os(name).filter(|value| !value.is_empty())?;
+            debug!("Using key files from {name}.");
+            Some(
+                std::env::split_paths(&value)
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .collect(),
+            )
+        };
+        Self {
+            identity_files: paths(IDENTITY_ENV),
+            recipients_files: paths(RECIPIENTS_FILE_ENV),
+        }
+    }
+}
+
+/// The settings a configuration file can hold.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Recipients to encrypt to when none are given.
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    /// Recipient files to encrypt to when no recipients are given.
+    #[serde(default)]
+    pub recipients_files: Vec<String>,
+    /// Identity files to decrypt with when none are given, and to encrypt to when no
+    /// recipients are given.
+    #[serde(default)]
+    pub identity_files: Vec<String>,
+    pub compression: Option<String>,
+    pub compression_level: Option<i32>,
+    pub redundancy: Option<u8>,
+    pub threads: Option<u32>,
+    /// Memory to keep protect and recover within, such as `512MiB`; see `--max-memory`.
+    pub max_memory: Option<String>,
+    /// Rate to limit protect's reads and writes to, such as `20MB/s`; see `--bwlimit`.
+    pub bwlimit: Option<String>,
+    /// Name of archives written into an output directory; see `--name-template`.
+    pub name_template: Option<String>,
+    /// Catalog to record every protect run in, and for `sage catalog` to read.
+    pub catalog: Option<String>,
+    /// Patterns to exclude in addition to those given with `--exclude`.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// The keys given through the environment, which take precedence over the file.
+    #[serde(skip)]
+    pub env: Environment,
+}
+
+/// Returns the default location of the configuration file, if there is a home directory.
+pub fn default_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|home| home.join(".config")))?;
+    Some(config_home.join("sage").join("config.toml"))
+}
+
+pub fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Reads the environment and the configuration file at `path`, or at the default location
+/// if `path` is `None`.
+///
+/// A missing file at the default location is the same as an empty one; a missing file that
+/// was asked for explicitly is an error.
+pub fn load(path: Option<&Path>) -> Result<Config> {
+    let mut config = read_file(path)?;
+    config.env = Environment::read();
+    Ok(config)
+}
+
+fn read_file(path: Option<&Path>) -> Result<Config> {
+    let (path, explicit) = match path {
+        Some(path) => (path.to_path_buf(), true),
+        None => match default_path() {
+            Some(path) => (path, false),
+            None => return Ok(Config::default()),
+        },
+    };
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => {
+            debug!("No configuration file at {}.", path.display());
+            return Ok(Config::default());
+        }
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Failed to read configuration file {}", path.display()));
+        }
+    };
+    debug!("Reading configuration from {}", path.display());
+    let config: Config = toml::from_str(&text).map_err(|e| {
+        error!("Configuration file {} is invalid.", path.display());
+        anyhow!("Invalid configuration file {}: {e}", path.display())
+    })?;
+    config.validate(&path)?;
+    Ok(config)
+}
+
+impl Config {
+    fn validate(&self, path: &Path) -> Result<()> {
+        let invalid =
+            |message: String| anyhow!("Invalid configuration file {}: {message}", path.display());
+        if let Some(level) = self.compression_level
+            && !(1..=22).contains(&level)
+        {
+            return Err(invalid(format!("compression_level {level} is not in 1-22")));
+        }
+        if let Some(redundancy) = self.redundancy
+            && !(1..=100).contains(&redundancy)
+        {
+            return Err(invalid(format!("redundancy {redundancy} is not in 1-100")));
+        }
+        if self.threads == Some(0) {
+            return Err(invalid("threads must be at least 1".to_string()));
+        }
+        if let Some(size) = &self.max_memory {
+            crate::cli::parse_size(size).map_err(|e| invalid(format!("max_memory: {e}")))?;
+        }
+        if let Some(rate) = &self.bwlimit {
+            crate::cli::parse_rate(rate).map_err(|e| invalid(format!("bwlimit: {e}")))?;
+        }
+        if let Some(template) = &self.name_template {
+            crate::naming::check_template(template)
+                .map_err(|e| invalid(format!("name_template: {e}")))?;
+        }
+        if let Some(codec) = &self.compression {
+            Codec::from_str(codec, true)
+                .map_err(|_| invalid(format!("unknown compression {codec:?}")))?;
+        }
+        Ok(())
+    }
+
+    /// Fills in the options of `command` that were not given on the command line.
+    ///
+    /// `matches` are the parsed arguments, which tell options left at their default apart
+    /// from options given with the default value.
+    pub fn apply(&self, command: &mut Command, matches: &ArgMatches) {
+        let Some((_, matches)) = matches.subcommand() else {
+            return;
+        };
+        match command {
+            Command::Protect(args) => self.apply_protect(args, matches),
+            Command::Watch(args) => self.apply_protect(&mut args.protect, matches),
+            Command::Recover(args) => {
+                self.apply_identities(
+                    &mut args.identity.identity_file,
+                    &args.identity.identity_from_keyring,
+                );
+                if args.max_memory.is_none() {
+                    args.max_memory = self.max_memory();
+                }
+            }
+            Command::List(args) => self.apply_identities(
+                &mut args.identity.identity_file,
+                &args.identity.identity_from_keyring,
+            ),
+            Command::Verify(args) => self.apply_identities(
+                &mut args.identity.identity_file,
+                &args.identity.identity_from_keyring,
+            ),
+            Command::Diff(args) => {
+                self.apply_identities(
+                    &mut args.identity.identity_file,
+                    &args.identity.identity_from_keyring,
+                );
+                args.exclude.extend(self.exclude.iter().cloned());
+            }
+            Command::Append(args) => self.apply_identities(
+                &mut args.identity.identity_file,
+                &args.identity.identity_from_keyring,
+            ),
+            Command::Rekey(args) => self.apply_identities(
+                &mut args.identity.identity_file,
+                &args.identity.identity_from_keyring,
+            ),
+            Command::Estimate(args) => {
+                self.apply_compression(
+                    &mut args.compression,
+                    &mut args.compression_level,
+                    &mut args.redundancy,
+                    matches,
+                );
+                args.exclude.extend(self.exclude.iter().cloned());
+            }
+            Command::Catalog(args) if args.catalog.is_none() => {
+                args.catalog = self.catalog.as_deref().map(|path| expand_home(path).into());
+            }
+            _ => {}
+        }
+    }
+
+    /// The configured memory limit in bytes.
+    fn max_memory(&self) -> Option<u64> {
+        let size = self.max_memory.as_deref()?;
+        Some(crate::cli::parse_size(size).expect("validated on load"))
+    }
+
+    /// Adds the configured identity files, unless identities were given on the command line.
+    fn apply_identities(&self, identity_files: &mut Vec<String>, keyring_names: &[String]) {
+        if !identity_files.is_empty() || !keyring_names.is_empty() {
+            return;
+        }
+        match &self.env.identity_files {
+            Some(paths) => identity_files.extend(paths.iter().cloned()),
+            None => identity_files.extend(self.identity_files.iter().map(|path| expand_home(path))),
+        }
+    }
+
+    /// Fills in the configured codec, level and redundancy where they were left at their
+    /// defaults.
+    fn apply_compression(
+        &self,
+        compression: &mut Codec,
+        compression_level: &mut i32,
+        redundancy: &mut u8,
+        matches: &ArgMatches,
+    ) {
+        let defaulted = |id: &str| matches.value_source(id) == Some(ValueSource::DefaultValue);
+        if let Some(codec) = &self.compression
+            && defaulted("compression")
+        {
+            *compression = Codec::from_str(codec, true).expect("validated on load");
+        }
+        if let Some(level) = self.compression_level
+            && defaulted("compression_level")
+        {
+            *compression_level = level;
+        }
+        if let Some(percent) = self.redundancy
+            && defaulted("redundancy")
+        {
+            *redundancy = percent;
+        }
+    }
+
+    fn apply_protect(&self, args: &mut ProtectArgs, matches: &ArgMatches) {
+        if args.to_self {
+            self.apply_identities(&mut args.identity_file, &args.identity_from_keyring);
+        } else if !args.passphrase
+            && args.recipient.is_empty()
+            && args.recipients_file.is_empty()
+            && args.identity_file.is_empty()
+            && args.identity_from_keyring.is_empty()
+        {
+            if self.env.identity_files.is_some() || self.env.recipients_files.is_some() {
+                args.recipients_file
+                    .extend(self.env.recipients_files.iter().flatten().cloned());
+                args.identity_file
+                    .extend(self.env.identity_files.iter().flatten().cloned());
+            } else {
+                args.recipient.extend(self.recipients.iter().cloned());
+                args.recipients_file
+                    .extend(self.recipients_files.iter().map(|path| expand_home(path)));
+                args.identity_file
+                    .extend(self.identity_files.iter().map(|path| expand_home(path)));
+            }
+        }
+        self.apply_compression(
+            &mut args.compression,
+            &mut args.compression_level,
+            &mut args.redundancy,
+            matches,
+        );
+        if args.threads.is_none() {
+            args.threads = self.threads;
+        }
+        if args.max_memory.is_none() {
+            args.max_memory = self.max_memory();
+        }
+        if args.bwlimit.is_none() {
+            args.bwlimit = self
+                .bwlimit
+                .as_deref()
+                .map(|rate| crate::cli::parse_rate(rate).expect("validated on load"));
+        }
+        if args.catalog.is_none() {
+            args.catalog = self.catalog.as_deref().map(|path| expand_home(path).into());
+        }
+        if args.name_template.is_none() && crate::naming::is_directory(&args.output) {
+            args.name_template = self.name_template.clone();
+        }
+        args.exclude.extend(self.exclude.iter().cloned());
+    }
+}
+
+/// Replaces a leading `~/` in `path` with the home directory.
+fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest).to_string_lossy().into_owned(),
+        _ => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Cli;
+    use clap::{CommandFactory, FromArgMatches};
+
+    fn protect_args(config: &str, args: &[&str]) -> ProtectArgs {
+        protect_args_with(toml::from_str(config).unwrap(), args)
+    }
+
+    fn protect_args_with(config: Config, args: &[&str]) -> ProtectArgs {
+        protect_args_to(config, "out", args)
+    }
+
+    fn protect_args_to(config: Config, output: &str, args: &[&str]) -> ProtectArgs {
+        let matches = Cli::command()
+            .try_get_matches_from(["sage", "protect", "in", "-o", output].iter().chain(args))
+            .unwrap();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        config.apply(&mut cli.command, &matches);
+        match cli.command {
+            Command::Protect(args) => args,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn fills_in_options_left_at_their_default() {
+        let args = protect_args(
+            "recipients = [\"age1x\"]\ncompression = \"xz\"\ncompression_level = 9\n\
+             redundancy = 20\nexclude = [\"*.tmp\"]",
+            &["--exclude", "*.log"],
+        );
+        assert_eq!(args.recipient, ["age1x"]);
+        assert_eq!(args.compression, Codec::Xz);
+        assert_eq!(args.compression_level, 9);
+        assert_eq!(args.redundancy, 20);
+        assert_eq!(args.exclude, ["*.log", "*.tmp"]);
+    }
+
+    #[test]
+    fn command_line_options_win_even_with_the_default_value() {
+        let args = protect_args(
+            "recipients = [\"age1x\"]\ncompression_level = 9\nredundancy = 20",
+            &["-r", "age1y", "--level", "3", "--redundancy", "12"],
+        );
+        assert_eq!(args.recipient, ["age1y"]);
+        assert_eq!(args.compression_level, 3);
+        assert_eq!(args.redundancy, 12);
+    }
+
+    #[test]
+    fn environment_keys_win_over_the_file() {
+        let mut config: Config =
+            toml::from_str("recipients = [\"age1x\"]\nidentity_files = [\"file.txt\"]").unwrap();
+        config.env.recipients_files = Some(vec!["team.txt".to_string()]);
+        let args = protect_args_with(config, &[]);
+        assert!(args.recipient.is_empty());
+        assert_eq!(args.recipients_file, ["team.txt"]);
+        assert!(args.identity_file.is_empty());
+
+        let mut config = Config::default();
+        config.env.identity_files = Some(vec!["env.txt".to_string()]);
+        let args = protect_args_with(config, &["-R", "cli.txt"]);
+        assert_eq!(args.recipients_file, ["cli.txt"]);
+        assert!(args.identity_file.is_empty());
+    }
+
+    #[test]
+    fn to_self_takes_only_the_identity_files() {
+        let args = protect_args(
+            "recipients = [\"age1x\"]\nidentity_files = [\"key.txt\"]",
+            &["--to-self"],
+        );
+        assert!(args.recipient.is_empty());
+        assert_eq!(args.identity_file, ["key.txt"]);
+    }
+
+    #[test]
+    fn name_template_applies_only_to_output_directories() {
+        let config = || toml::from_str("name_template = \"{input}-{date}.sage\"").unwrap();
+        let args = protect_args_to(config(), "backups/", &[]);
+        assert_eq!(args.name_template.as_deref(), Some("{input}-{date}.sage"));
+        let args = protect_args_to(config(), "backups/", &["--name-template", "{date}.sage"]);
+        assert_eq!(args.name_template.as_deref(), Some("{date}.sage"));
+        assert_eq!(protect_args_with(config(), &[]).name_template, None);
+        assert!(
+            toml::from_str::<Config>("name_template = \"{host}.sage\"")
+                .unwrap()
+                .validate(Path::new("config.toml"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(toml::from_str::<Config>("compresion_level = 9").is_err());
+    }
+}
@@ -0,0 +1,371 @@
+//! Rewriting archives made by older versions of sage in the current format.
+//!
+//! Only the layers around the age payload change: the sage header is written in the current
+//! version and the error correction layer is encoded again, optionally with a new geometry.
+//! The encrypted payload is copied as it is, so the recipients, the compression and every
+//! archived file stay exactly the same, and memory use stays bounded by one group.
+//!
+//! Backups made without sage, such as `.tar.gz` files or `.tar.zst.age` files encrypted with
+//! age, can be imported instead: [`open_import`] opens them for reading their tar entries,
+//! which [`ProtectOptions::import`](crate::ProtectOptions::import) then copies into a new
+//! archive, encrypted to new recipients and listing the hashes of the files.
+
+use crate::compress::{self, Codec};
+use crate::fec::{self, FecParams, FecReader, FecStats, FecWriter, FileHeader};
+use crate::recover::header_error;
+use crate::{AGE_MAGIC, ARMOR_BEGIN_MARKER};
+use anyhow::{Context, Result, anyhow};
+use log::{debug, info, warn};
+use std::io::{self, Cursor, Read, Write};
+
+/// What [`convert`] read and wrote.
+#[derive(Clone, Copy, Debug)]
+pub struct Conversion {
+    /// The format version of the input, or 0 for a plain age file without a sage header.
+    pub from_version: u8,
+    /// The header written to the output.
+    pub header: FileHeader,
+    /// The damage repaired while reading the input's error correction layer.
+    pub stats: FecStats,
+}
+
+/// Rewrites the archive read from `input` into `output` in the current format.
+///
+/// The error correction geometry of the input is kept unless `params` is given; plain age
+/// files, which have none, get the default geometry. Armored archives are refused, because
+/// their header does not say how the payload is compressed.
+pub fn convert<R: Read, W: Write>(
+    mut input: R,
+    output: W,
+    params: Option<FecParams>,
+) -> crate::Result<(Conversion, W)> {
+    let len = compress::DETECT_LEN.max(ARMOR_BEGIN_MARKER.len());
+    let mut prefix = Vec::with_capacity(len);
+    (&mut input).take(len as u64).read_to_end(&mut prefix)?;
+    let input = Cursor::new(prefix.clone()).chain(input);
+
+    if is_tarball(&prefix) {
+        warn!("Input is a tar archive, not an encrypted archive.");
+        return Err(anyhow!(
+            "Input is a tar archive without encryption; give --recipient to import it."
+        )
+        .into());
+    }
+
+    if prefix.starts_with(ARMOR_BEGIN_MARKER) {
+        warn!("Armored archives record no compression, so they cannot be converted.");
+        return Err(anyhow!(
+            "Armored archives are not an older format and record no compression; recover \
+             and protect them again to add error correction."
+        )
+        .into());
+    }
+
+    if prefix.starts_with(AGE_MAGIC) {
+        info!("Converting a plain age file without error correction.");
+        // Archives from before error correction hold zstd, but `protect --compat` may have
+        // used any codec, so it is left for recover to detect.
+        let header = FileHeader::new(params.unwrap_or_default());
+        let mut reader = input;
+        let (header, output) = rewrite(&mut reader, output, header)?;
+        let conversion = Conversion {
+            from_version: 0,
+            header,
+            stats: FecStats::default(),
+        };
+        return Ok((conversion, output));
+    }
+
+    let mut reader = FecReader::new(input).map_err(header_error)?;
+    let old = reader.header();
+    if old.version == 0 {
+        warn!("Archive header is damaged; its metadata cannot be carried over.");
+        return Err(anyhow!(
+            "Archive header is damaged, so its compression and flags are unknown; run \
+             `sage repair` on a copy first, or recover it and protect it again."
+        )
+        .into());
+    }
+    let header = FileHeader {
+        compression_level: old.compression_level,
+        codec: old.codec,
+        stream: old.stream,
+        dedup: old.dedup,
+        seekable: old.seekable,
+        dictionary: old.dictionary,
+        unencrypted: old.unencrypted,
+        window_log: old.window_log,
+        created: old.created,
+        content_hash: old.content_hash,
+        stripe_groups: old.stripe_groups,
+        ..FileHeader::new(params.unwrap_or(old.params))
+    };
+    info!(
+        "Converting format version {} to version {}.",
+        old.version,
+        header.format_version()
+    );
+    let (header, output) = rewrite(&mut reader, output, header)?;
+    let conversion = Conversion {
+        from_version: old.version,
+        header,
+        stats: reader.stats(),
+    };
+    Ok((conversion, output))
+}
+
+/// What [`open_import`] found in its input.
+pub enum Import<'a> {
+    /// A sage archive or an age file, to be opened with an identity.
+    Encrypted(Box<dyn Read + 'a>),
+    /// A tar archive, decompressed if it was compressed.
+    Tarball(tar::Archive<Box<dyn Read + 'a>>),
+}
+
+/// Looks at the start of `input` to tell a tar archive, plain or compressed with gzip, zstd,
+/// xz or lz4, from an encrypted archive, and opens the former for reading its entries.
+pub fn open_import<'a, R: Read + 'a>(mut input: R) -> crate::Result<Import<'a>> {
+    let mut prefix = Vec::with_capacity(compress::DETECT_LEN);
+    (&mut input)
+        .take(compress::DETECT_LEN as u64)
+        .read_to_end(&mut prefix)?;
+    let input: Box<dyn Read + 'a> = Box::new(Cursor::new(prefix.clone()).chain(input));
+    // Anything else is left for the error correction layer, which can read archives whose
+    // header is damaged, to open or refuse.
+    if !is_tarball(&prefix) {
+        return Ok(Import::Encrypted(input));
+    }
+    let reader: Box<dyn Read + 'a> = if prefix.starts_with(GZIP_MAGIC) {
+        info!("Importing a tar archive compressed with gzip.");
+        Box::new(flate2::read::MultiGzDecoder::new(input))
+    } else if compress::is_tar(&prefix) {
+        info!("Importing an uncompressed tar archive.");
+        input
+    } else {
+        let codec = Codec::detect(&prefix);
+        info!("Importing a tar archive compressed with {codec}.");
+        codec
+            .decoder(input, compress::DEFAULT_WINDOW_LOG_MAX)
+            .with_context(|| format!("Failed to create {codec} decoder"))?
+    };
+    Ok(Import::Tarball(tar::Archive::new(reader)))
+}
+
+/// Whether `prefix`, the first [`compress::DETECT_LEN`] bytes of an input, starts a tar
+/// archive, plain or compressed with gzip, zstd, xz or lz4.
+fn is_tarball(prefix: &[u8]) -> bool {
+    prefix.starts_with(GZIP_MAGIC)
+        || compress::is_tar(prefix)
+        || matches!(Codec::detect(prefix), Codec::Zstd | Codec::Xz | Codec::Lz4)
+}
+
+/// The first bytes of a gzip stream.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// Copies the age payload read from `payload` into a new error correction layer.
+fn rewrite<R: Read, W: Write>(
+    payload: &mut R,
+    output: W,
+    header: FileHeader,
+) -> Result<(FileHeader, W)> {
+    let params = header.params;
+    debug!(
+        "Writing error correction with {} data + {} parity shards of {} bytes.",
+        params.data_shards, params.parity_shards, params.shard_size
+    );
+    let mut writer = FecWriter::new(output, header).context("Failed to write archive header")?;
+    let bytes = io::copy(payload, &mut writer).map_err(|e| {
+        if fec::newer_format(&e).is_some() {
+            anyhow::Error::new(e)
+        } else {
+            anyhow::Error::new(e).context("Failed to read archive")
+        }
+    })?;
+    debug!("Copied {} bytes of encrypted payload.", bytes);
+    Ok((header, writer.finish()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::{CHECKSUM_ENTRY, Checksums};
+    use crate::{Contents, ProtectOptions, RecoverOptions};
+    use std::path::Path;
+
+    const CONTENTS: &[u8] = b"an archive from an older sage";
+
+    /// Returns the identity, and an archive in the current format holding [`CONTENTS`] as a
+    /// single stream.
+    fn stream_archive() -> (age::x25519::Identity, Vec<u8>) {
+        let identity = age::x25519::Identity::generate();
+        let archive = ProtectOptions::new()
+            .recipient(Box::new(identity.to_public()))
+            .protect_stream(CONTENTS, Vec::new())
+            .unwrap();
+        (identity, archive)
+    }
+
+    /// Returns the identity, and an archive in the current format holding [`CONTENTS`] as a
+    /// file, the only kind of archive older formats held.
+    fn file_archive() -> (age::x25519::Identity, Vec<u8>) {
+        let identity = age::x25519::Identity::generate();
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("file"), CONTENTS).unwrap();
+        let archive = ProtectOptions::new()
+            .recipient(Box::new(identity.to_public()))
+            .protect(&dir.path().join("file"), Vec::new())
+            .unwrap();
+        (identity, archive)
+    }
+
+    /// Replaces the header of `archive` with one of format `version`, as older sages wrote it.
+    fn with_old_header(archive: &[u8], version: u8) -> Vec<u8> {
+        let len = archive[18] as usize;
+        let (current, frames) = archive.split_at(len);
+        let mut header = current[..16].to_vec();
+        header[8] = version;
+        if version == 2 {
+            // Codec, flags and a reserved field follow the shard size.
+            header.extend_from_slice(&current[16..18]);
+            header.extend_from_slice(&[0, 0]);
+        }
+        if version == 3 {
+            // The first version 3 headers end before the content hash, at 40 bytes.
+            header = current[..36].to_vec();
+            header[18] = 40;
+        }
+        let crc = crc32fast::hash(&header);
+        header.extend_from_slice(&crc.to_le_bytes());
+        header.extend_from_slice(frames);
+        header
+    }
+
+    fn assert_converts(identity: &age::x25519::Identity, old: &[u8], from_version: u8) {
+        let (conversion, converted) = convert(old, Vec::new(), None).unwrap();
+        assert_eq!(conversion.from_version, from_version);
+        assert_eq!(converted[8], fec::SEQUENTIAL_VERSION);
+        // Plain age files do not say how they are compressed.
+        assert_eq!(
+            conversion.header.codec,
+            (from_version > 0).then_some(Codec::Zstd)
+        );
+
+        let options = RecoverOptions::new().identity(Box::new(identity.clone()));
+        let mut contents = Vec::new();
+        match options.open_contents(converted.as_slice()).unwrap() {
+            Contents::Stream(mut stream) => stream.read_to_end(&mut contents).unwrap(),
+            Contents::Archive(mut archive) => {
+                let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+                entry.read_to_end(&mut contents).unwrap()
+            }
+        };
+        assert_eq!(contents, CONTENTS);
+    }
+
+    #[test]
+    fn converts_short_version_3_archives() {
+        let (identity, archive) = stream_archive();
+        assert_converts(&identity, &with_old_header(&archive, 3), 3);
+    }
+
+    #[test]
+    fn converts_version_2_archives() {
+        let (identity, archive) = stream_archive();
+        assert_converts(&identity, &with_old_header(&archive, 2), 2);
+    }
+
+    #[test]
+    fn converts_version_1_archives() {
+        let (identity, archive) = file_archive();
+        assert_converts(&identity, &with_old_header(&archive, 1), 1);
+    }
+
+    #[test]
+    fn converts_plain_age_files() {
+        let (identity, archive) = file_archive();
+        let mut payload = Vec::new();
+        FecReader::new(archive.as_slice())
+            .unwrap()
+            .read_to_end(&mut payload)
+            .unwrap();
+        assert!(payload.starts_with(AGE_MAGIC));
+        assert_converts(&identity, &payload, 0);
+    }
+
+    #[test]
+    fn keeps_the_header_fields_and_changes_the_geometry() {
+        let (identity, archive) = stream_archive();
+        let params = FecParams::with_redundancy(25);
+        let (conversion, converted) =
+            convert(archive.as_slice(), Vec::new(), Some(params)).unwrap();
+        assert_eq!(conversion.header.params, params);
+        assert!(conversion.header.stream);
+        assert_eq!(converted[10], params.parity_shards);
+        assert_converts(&identity, &converted, fec::SEQUENTIAL_VERSION);
+    }
+
+    #[test]
+    fn imports_gzip_tarballs_with_checksums() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(CONTENTS.len() as u64);
+        header.set_mode(0o644);
+        let long = format!("{}/file", "d".repeat(120));
+        builder.append_data(&mut header, &long, CONTENTS).unwrap();
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gzip.write_all(&builder.into_inner().unwrap()).unwrap();
+        let tarball = gzip.finish().unwrap();
+
+        let Import::Tarball(archive) = open_import(tarball.as_slice()).unwrap() else {
+            panic!("a gzip tarball was taken for an encrypted archive");
+        };
+        let identity = age::x25519::Identity::generate();
+        let imported = ProtectOptions::new()
+            .recipient(Box::new(identity.to_public()))
+            .import(archive, Vec::new())
+            .unwrap();
+        assert!(matches!(
+            open_import(imported.as_slice()).unwrap(),
+            Import::Encrypted(_)
+        ));
+
+        let mut archive = RecoverOptions::new()
+            .identity(Box::new(identity))
+            .open(imported.as_slice())
+            .unwrap();
+        let mut checksums = None;
+        let mut found = false;
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().into_owned();
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents).unwrap();
+            if path == Path::new(CHECKSUM_ENTRY) {
+                checksums = Checksums::parse(&contents);
+            } else {
+                assert_eq!(path, Path::new(&long));
+                assert_eq!(contents, CONTENTS);
+                found = true;
+            }
+        }
+        assert!(found);
+        assert_eq!(
+            checksums
+                .unwrap()
+                .matches(Path::new(&long), &blake3::hash(CONTENTS)),
+            Some(true)
+        );
+        assert!(convert(tarball.as_slice(), Vec::new(), None).is_err());
+    }
+
+    #[test]
+    fn refuses_armored_archives() {
+        let identity = age::x25519::Identity::generate();
+        let archive = ProtectOptions::new()
+            .recipient(Box::new(identity.to_public()))
+            .armor(true)
+            .protect_stream(CONTENTS, Vec::new())
+            .unwrap();
+        assert!(convert(archive.as_slice(), Vec::new(), None).is_err());
+    }
+}
@@ -0,0 +1,328 @@
+//! Content-defined chunking and deduplication of the archive payload.
+//!
+//! The tar stream is cut into chunks of 16 KiB to 256 KiB (64 KiB on average) wherever a
+//! rolling gear hash of the last bytes matches a mask, as in FastCDC. Cut points depend only
+//! on nearby content, so an insertion or shifted data changes a few chunks instead of every
+//! chunk after it. Each chunk is identified by its SHA-256 hash; the first occurrence is
+//! stored and every repeat becomes a reference to it. The result is compressed, encrypted
+//! and error-corrected as usual, and the archive header is flagged as deduplicated.
+//!
+//! Layout:
+//!
+//! ```text
+//! stream      DEDUP_MAGIC | version | record ... | end
+//! record      TAG_CHUNK | length (u32) | chunk bytes
+//!             TAG_REF | index of an earlier stored chunk (u64)
+//! end         TAG_END
+//! ```
+//!
+//! All integers are little-endian. References can point at any earlier chunk, so the
+//! reader keeps every stored chunk in a temporary file, encrypted with a random key that
+//! never leaves memory.
+
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce};
+use log::{debug, info};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Magic bytes at the start of a deduplicated payload.
+pub const DEDUP_MAGIC: &[u8; 8] = b"SAGEDDP\0";
+/// Version of the deduplicated payload layout.
+pub const VERSION: u8 = 1;
+
+/// Smallest chunk, except for the last one.
+pub const MIN_CHUNK_SIZE: usize = 16 * 1024;
+/// Chunk size the cut points aim for.
+pub const AVG_CHUNK_SIZE: usize = 64 * 1024;
+/// Largest chunk; data without a cut point is split at this size.
+pub const MAX_CHUNK_SIZE: usize = 256 * 1024;
+
+const TAG_END: u8 = 0;
+const TAG_CHUNK: u8 = 1;
+const TAG_REF: u8 = 2;
+
+/// Below the average size, cut only where 18 hash bits are zero, so small chunks are rare.
+const MASK_SMALL: u64 = !0 << (64 - 18);
+/// Above the average size, cut where 14 hash bits are zero, so large chunks are rare.
+const MASK_LARGE: u64 = !0 << (64 - 14);
+
+/// Random values for the gear hash, one per byte value, from a fixed splitmix64 sequence.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x5341_4745_4444_5031;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Returns the length of the first chunk of `data`.
+///
+/// Only the first [`MAX_CHUNK_SIZE`] bytes are looked at, so the result is the same however
+/// much more data follows.
+pub fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    let normal = end.min(AVG_CHUNK_SIZE);
+    let mut hash = 0u64;
+    for (i, &byte) in data.iter().enumerate().take(end).skip(MIN_CHUNK_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        let mask = if i < normal { MASK_SMALL } else { MASK_LARGE };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// Counts of what a [`DedupWriter`] stored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DedupStats {
+    pub chunks: u64,
+    pub unique_chunks: u64,
+    pub bytes: u64,
+    pub unique_bytes: u64,
+}
+
+/// Chunks and deduplicates everything written to it into `inner`.
+pub struct DedupWriter<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+    /// The index of every stored chunk, by hash.
+    index: HashMap<[u8; 32], u64>,
+    stats: DedupStats,
+}
+
+impl<W: Write> DedupWriter<W> {
+    /// Writes the payload header to `inner`.
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        debug!("Initializing deduplication.");
+        inner.write_all(DEDUP_MAGIC)?;
+        inner.write_all(&[VERSION])?;
+        Ok(Self {
+            inner,
+            buffer: Vec::with_capacity(2 * MAX_CHUNK_SIZE),
+            index: HashMap::new(),
+            stats: DedupStats::default(),
+        })
+    }
+
+    /// Writes the remaining chunks and the end marker, and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<(W, DedupStats)> {
+        self.write_chunks(0)?;
+        self.inner.write_all(&[TAG_END])?;
+        let stats = self.stats;
+        info!(
+            "Deduplication stored {} of {} chunks ({} of {} bytes).",
+            stats.unique_chunks, stats.chunks, stats.unique_bytes, stats.bytes
+        );
+        Ok((self.inner, stats))
+    }
+
+    /// Emits chunks while more than `keep` bytes are buffered.
+    ///
+    /// Cut points are only looked for with a full [`MAX_CHUNK_SIZE`] of data ahead, or at the
+    /// end, so they do not depend on how the data was split into writes.
+    fn write_chunks(&mut self, keep: usize) -> io::Result<()> {
+        let mut start = 0;
+        while self.buffer.len() - start > keep {
+            let len = cut_point(&self.buffer[start..]);
+            let chunk = &self.buffer[start..start + len];
+            let hash: [u8; 32] = Sha256::digest(chunk).into();
+            self.stats.chunks += 1;
+            self.stats.bytes += len as u64;
+            match self.index.get(&hash) {
+                Some(&index) => {
+                    self.inner.write_all(&[TAG_REF])?;
+                    self.inner.write_all(&index.to_le_bytes())?;
+                }
+                None => {
+                    self.index.insert(hash, self.stats.unique_chunks);
+                    self.stats.unique_chunks += 1;
+                    self.stats.unique_bytes += len as u64;
+                    self.inner.write_all(&[TAG_CHUNK])?;
+                    self.inner.write_all(&(len as u32).to_le_bytes())?;
+                    self.inner.write_all(chunk)?;
+                }
+            }
+            start += len;
+        }
+        self.buffer.drain(..start);
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for DedupWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        self.write_chunks(MAX_CHUNK_SIZE - 1)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reassembles the payload written by a [`DedupWriter`].
+pub struct DedupReader<R: Read> {
+    inner: R,
+    store: ChunkStore,
+    chunk: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<R: Read> DedupReader<R> {
+    /// Reads and checks the payload header from `inner`.
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut header = [0u8; DEDUP_MAGIC.len() + 1];
+        inner.read_exact(&mut header)?;
+        if &header[..DEDUP_MAGIC.len()] != DEDUP_MAGIC {
+            return Err(invalid_data("payload is not deduplicated"));
+        }
+        match header[DEDUP_MAGIC.len()] {
+            VERSION => {}
+            version if version > VERSION => {
+                return Err(invalid_data(
+                    "payload was deduplicated by a newer version of sage; please upgrade sage",
+                ));
+            }
+            _ => return Err(invalid_data("unsupported deduplication version")),
+        }
+        debug!("Initializing deduplication reader.");
+        Ok(Self {
+            inner,
+            store: ChunkStore::new()?,
+            chunk: Vec::new(),
+            position: 0,
+            finished: false,
+        })
+    }
+
+    /// Loads the next chunk, returning false at the end marker.
+    fn next_chunk(&mut self) -> io::Result<bool> {
+        let mut tag = [0u8; 1];
+        read_record(&mut self.inner, &mut tag)?;
+        match tag[0] {
+            TAG_END => return Ok(false),
+            TAG_CHUNK => {
+                let mut len = [0u8; 4];
+                read_record(&mut self.inner, &mut len)?;
+                let len = u32::from_le_bytes(len) as usize;
+                if len > MAX_CHUNK_SIZE {
+                    return Err(invalid_data("deduplicated chunk is too large"));
+                }
+                self.chunk.resize(len, 0);
+                read_record(&mut self.inner, &mut self.chunk)?;
+                self.store.push(&self.chunk)?;
+            }
+            TAG_REF => {
+                let mut index = [0u8; 8];
+                read_record(&mut self.inner, &mut index)?;
+                self.chunk = self.store.get(u64::from_le_bytes(index))?;
+            }
+            _ => return Err(invalid_data("unknown deduplication record")),
+        }
+        self.position = 0;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for DedupReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            if self.finished || buf.is_empty() {
+                return Ok(0);
+            }
+            if !self.next_chunk()? {
+                self.finished = true;
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.position);
+        buf[..n].copy_from_slice(&self.chunk[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// Reads a whole record field, reporting a cut-off stream as truncated.
+fn read_record<R: Read>(inner: &mut R, buf: &mut [u8]) -> io::Result<()> {
+    inner.read_exact(buf).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            io::Error::new(e.kind(), "deduplicated payload is truncated")
+        } else {
+            e
+        }
+    })
+}
+
+/// The stored chunks, kept encrypted in an anonymous temporary file.
+struct ChunkStore {
+    file: File,
+    cipher: ChaCha20Poly1305,
+    /// Offset and encrypted length of every chunk.
+    chunks: Vec<(u64, usize)>,
+    end: u64,
+}
+
+impl ChunkStore {
+    fn new() -> io::Result<Self> {
+        let file = tempfile::tempfile()?;
+        debug!("Spooling deduplicated chunks to a temporary file.");
+        let key: [u8; 32] = rand::random();
+        Ok(Self {
+            file,
+            cipher: ChaCha20Poly1305::new(&key.into()),
+            chunks: Vec::new(),
+            end: 0,
+        })
+    }
+
+    fn push(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let nonce = nonce(self.chunks.len() as u64);
+        let sealed = self
+            .cipher
+            .encrypt(&nonce, chunk)
+            .map_err(|_| io::Error::other("failed to encrypt a spooled chunk"))?;
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(&sealed)?;
+        self.chunks.push((self.end, sealed.len()));
+        self.end += sealed.len() as u64;
+        Ok(())
+    }
+
+    fn get(&mut self, index: u64) -> io::Result<Vec<u8>> {
+        let &(offset, len) = self
+            .chunks
+            .get(index as usize)
+            .ok_or_else(|| invalid_data("deduplication reference to a missing chunk"))?;
+        let mut sealed = vec![0u8; len];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut sealed)?;
+        self.cipher
+            .decrypt(&nonce(index), sealed.as_slice())
+            .map_err(|_| invalid_data("spooled chunk was modified on disk"))
+    }
+}
+
+fn nonce(index: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&index.to_le_bytes());
+    nonce.into()
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
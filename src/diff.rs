@@ -0,0 +1,260 @@
+//! Comparing the contents of an archive with a directory on disk.
+//!
+//! Entries are matched by their path in the archive, which is relative to the directory
+//! that was protected. Files of the same size are compared by their BLAKE3 hash against the
+//! [checksums](crate::checksum) stored in the archive, so no file contents are needed from
+//! the archive itself; files of archives without checksums are compared by size and
+//! modification time instead. `.sageignore` files in the directory are honored as they are
+//! by protect.
+
+use crate::checksum::{self, CHECKSUM_ENTRY, Checksums};
+use crate::exclude::ExcludeSet;
+use crate::incremental::INCREMENT_ENTRY;
+use crate::manifest::EntryMetadata;
+use crate::protect::SAGEIGNORE_FILE;
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::{self, Metadata};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// How a path differs between the archive and the directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    /// Only in the directory.
+    Added,
+    /// Only in the archive.
+    Removed,
+    /// In both, with different contents or link target.
+    Modified,
+    /// In both, but a file in one and a directory or link in the other.
+    TypeChanged,
+}
+
+impl Change {
+    /// The mark listings show before the path: `+`, `-`, `M` or `T`.
+    pub fn mark(self) -> char {
+        match self {
+            Change::Added => '+',
+            Change::Removed => '-',
+            Change::Modified => 'M',
+            Change::TypeChanged => 'T',
+        }
+    }
+}
+
+/// One path that differs.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Difference {
+    pub path: String,
+    pub change: Change,
+}
+
+/// Compares the archive `entries` with `dir`, leaving out what `exclude` matches in `dir`,
+/// and returns the differences sorted by path.
+pub fn diff_entries<R: Read>(
+    entries: tar::Entries<'_, R>,
+    dir: &Path,
+    exclude: &ExcludeSet,
+) -> crate::Result<Vec<Difference>> {
+    let mut stored = BTreeMap::new();
+    let mut checksums = None;
+    for entry in entries {
+        let mut entry = entry.context("Failed to read archive entry")?;
+        let is_file = entry.header().entry_type().is_file();
+        if is_file && entry.path_bytes().as_ref() == CHECKSUM_ENTRY.as_bytes() {
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            checksums = Checksums::parse(&contents);
+            continue;
+        }
+        if is_file && entry.path_bytes().as_ref() == INCREMENT_ENTRY.as_bytes() {
+            warn!("The archive is an increment; files it does not hold are listed as added.");
+            continue;
+        }
+        let metadata = EntryMetadata::from_entry(&entry)?;
+        stored.insert(metadata.path.trim_end_matches('/').to_string(), metadata);
+    }
+    if checksums.is_none() {
+        debug!("Archive stores no file checksums; comparing sizes and modification times.");
+    }
+    debug!(
+        "Read {} entries; comparing with {}.",
+        stored.len(),
+        dir.display()
+    );
+
+    let live = walk(dir, exclude)?;
+    let mut differences = Vec::new();
+    for (path, (full_path, metadata)) in &live {
+        let change = match stored.get(path) {
+            None => Some(Change::Added),
+            Some(entry) => compare(entry, full_path, metadata, checksums.as_ref())
+                .with_context(|| format!("Failed to compare {}", full_path.display()))?,
+        };
+        if let Some(change) = change {
+            differences.push(Difference {
+                path: path.clone(),
+                change,
+            });
+        }
+    }
+    for path in stored.keys().filter(|path| !live.contains_key(*path)) {
+        differences.push(Difference {
+            path: path.clone(),
+            change: Change::Removed,
+        });
+    }
+    differences.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(differences)
+}
+
+/// Lists what protect would archive from `dir`, by path in the archive.
+fn walk(dir: &Path, exclude: &ExcludeSet) -> Result<BTreeMap<String, (PathBuf, Metadata)>> {
+    let mut live = BTreeMap::new();
+    if !dir.is_dir() {
+        // A file is archived under its own name.
+        let metadata = fs::symlink_metadata(dir)
+            .with_context(|| format!("Failed to read {}", dir.display()))?;
+        let name = dir.file_name().unwrap_or_default().to_string_lossy();
+        live.insert(name.into_owned(), (dir.to_path_buf(), metadata));
+        return Ok(live);
+    }
+    let root = dir.to_path_buf();
+    let filter = exclude.clone();
+    let walker = ignore::WalkBuilder::new(dir)
+        .standard_filters(false)
+        .add_custom_ignore_filename(SAGEIGNORE_FILE)
+        .require_git(false)
+        .filter_entry(move |entry| {
+            let Ok(rel_path) = entry.path().strip_prefix(&root) else {
+                return true;
+            };
+            let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+            rel_path.as_os_str().is_empty() || !filter.is_excluded(rel_path, is_dir)
+        })
+        .build();
+    for entry in walker {
+        let entry = entry.with_context(|| format!("Failed to read {}", dir.display()))?;
+        let rel_path = entry.path().strip_prefix(dir).unwrap_or(entry.path());
+        if rel_path.as_os_str().is_empty() {
+            continue;
+        }
+        let metadata = fs::symlink_metadata(entry.path())
+            .with_context(|| format!("Failed to read {}", entry.path().display()))?;
+        live.insert(
+            archive_path(rel_path),
+            (entry.path().to_path_buf(), metadata),
+        );
+    }
+    Ok(live)
+}
+
+/// The path of `rel_path` in a tar archive, with `/` between components.
+fn archive_path(rel_path: &Path) -> String {
+    rel_path
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Compares the archive `entry` with the file at `path`.
+fn compare(
+    entry: &EntryMetadata,
+    path: &Path,
+    metadata: &Metadata,
+    checksums: Option<&Checksums>,
+) -> Result<Option<Change>> {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_file() {
+        "file"
+    } else if file_type.is_dir() {
+        "directory"
+    } else if file_type.is_symlink() {
+        "symlink"
+    } else {
+        "other"
+    };
+    // A hard link is stored as a link to the first copy, whose contents are compared.
+    if entry.kind == "hardlink" && kind == "file" {
+        return Ok(None);
+    }
+    if entry.kind != kind {
+        return Ok(Some(Change::TypeChanged));
+    }
+    let modified = match kind {
+        "file" if metadata.len() != entry.size => true,
+        "file" => {
+            let matches = match checksums {
+                Some(checksums) => {
+                    checksums.matches(Path::new(&entry.path), &checksum::hash_file(path)?)
+                }
+                None => None,
+            };
+            matches.map_or_else(|| mtime(metadata) != Some(entry.mtime), |matches| !matches)
+        }
+        "symlink" => {
+            let target = fs::read_link(path)?;
+            entry.link_target.as_deref() != Some(&*target.to_string_lossy())
+        }
+        _ => false,
+    };
+    Ok(modified.then_some(Change::Modified))
+}
+
+fn mtime(metadata: &Metadata) -> Option<u64> {
+    let modified = metadata.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ProtectOptions, RecoverOptions};
+
+    #[test]
+    fn reports_added_removed_and_modified_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in");
+        fs::create_dir_all(input.join("sub")).unwrap();
+        fs::write(input.join("same.txt"), "same").unwrap();
+        fs::write(input.join("changed.txt"), "before").unwrap();
+        fs::write(input.join("sub/gone.txt"), "gone").unwrap();
+        fs::write(input.join("retyped"), "file").unwrap();
+
+        let identity = age::x25519::Identity::generate();
+        let archive = ProtectOptions::new()
+            .recipient(Box::new(identity.to_public()))
+            .protect(&input, Vec::new())
+            .unwrap();
+
+        fs::write(input.join("changed.txt"), "after!").unwrap();
+        fs::remove_file(input.join("sub/gone.txt")).unwrap();
+        fs::write(input.join("new.txt"), "new").unwrap();
+        fs::write(input.join("skipped.tmp"), "skipped").unwrap();
+        fs::remove_file(input.join("retyped")).unwrap();
+        fs::create_dir(input.join("retyped")).unwrap();
+
+        let options = RecoverOptions::new().identity(Box::new(identity));
+        let exclude = ExcludeSet::new(["*.tmp"]).unwrap();
+        let differences = options.diff(archive.as_slice(), &input, &exclude).unwrap();
+        let found: Vec<(&str, Change)> = differences
+            .iter()
+            .map(|difference| (difference.path.as_str(), difference.change))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("changed.txt", Change::Modified),
+                ("new.txt", Change::Added),
+                ("retyped", Change::TypeChanged),
+                ("sub/gone.txt", Change::Removed),
+            ]
+        );
+    }
+}
@@ -0,0 +1,226 @@
+//! The errors of the library.
+//!
+//! Every public operation returns a [`Result`] with an [`Error`], so callers can tell why it
+//! failed: no keys at all, keys that do not open the archive, damage that error correction
+//! cannot repair, or a newer format. Failures without a variant of their own are
+//! [`Error::Other`], which keeps the whole chain of context of the failure.
+//!
+//! Inside the library, errors travel as `anyhow::Error` with context added on the way. A
+//! variant is raised where the failure happens, either directly or as the payload of an
+//! `io::Error` from a reader, and is found again in the chain when the error leaves the
+//! library.
+
+use crate::{fec, integrity};
+use std::fmt;
+use std::io;
+
+/// The result of a library operation.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Why a library operation failed.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// There are no recipients or passphrase to encrypt to.
+    NoRecipients,
+    /// There are no identities to decrypt with.
+    NoIdentities,
+    /// The archive is passphrase-encrypted and no passphrase was supplied.
+    NoPassphrase,
+    /// None of the identities, or the passphrase, opens the archive.
+    IdentityRejected,
+    /// An error correction group lost more shards than its parity can rebuild.
+    CorruptBlock {
+        /// The group, counted from the start of the archive.
+        group: u64,
+        /// Where the group starts in the archive file.
+        offset: u64,
+    },
+    /// The archive was written in a newer format version than this sage reads.
+    UnsupportedVersion(u8),
+    /// Reading or writing failed.
+    Io(io::Error),
+    /// Anything else, with the context it failed in.
+    Other(anyhow::Error),
+}
+
+impl Error {
+    /// A copy of the variants that hold no source, which is how they are found again when
+    /// only a reference to them is left in an error chain.
+    fn copy(&self) -> Option<Error> {
+        Some(match self {
+            Error::NoRecipients => Error::NoRecipients,
+            Error::NoIdentities => Error::NoIdentities,
+            Error::NoPassphrase => Error::NoPassphrase,
+            Error::IdentityRejected => Error::IdentityRejected,
+            &Error::CorruptBlock { group, offset } => Error::CorruptBlock { group, offset },
+            &Error::UnsupportedVersion(version) => Error::UnsupportedVersion(version),
+            Error::Io(_) | Error::Other(_) => return None,
+        })
+    }
+
+    /// The variant `error` carries as its payload, looking through the marker that
+    /// [`integrity::SourceReader`] puts around the errors of the readers below age.
+    fn carried_by(error: &io::Error) -> Option<Error> {
+        let inner = error.get_ref()?;
+        if let Some(source) = inner.downcast_ref::<integrity::SourceError>() {
+            return Error::carried_by(&source.0);
+        }
+        inner.downcast_ref::<Error>().and_then(Error::copy)
+    }
+
+    /// Wraps the error into an `io::Error`, for readers to return.
+    pub(crate) fn into_io(self) -> io::Error {
+        match self {
+            Error::Io(error) => error,
+            error => io::Error::new(io::ErrorKind::InvalidData, error),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoRecipients => write!(f, "No valid recipients provided."),
+            Error::NoIdentities => write!(f, "No valid identities provided."),
+            Error::NoPassphrase => write!(
+                f,
+                "Archive is passphrase-encrypted but no passphrase was provided."
+            ),
+            Error::IdentityRejected => write!(
+                f,
+                "None of the identities or the passphrase given can decrypt the archive."
+            ),
+            Error::CorruptBlock { group, offset } => write!(
+                f,
+                "error correction group {group}, at byte {offset}, is damaged beyond repair"
+            ),
+            Error::UnsupportedVersion(version) => fec::NewerFormat { version: *version }.fmt(f),
+            Error::Io(error) => error.fmt(f),
+            // `{:#}` shows the whole chain of context, as it would for the error itself.
+            Error::Other(error) if f.alternate() => write!(f, "{error:#}"),
+            Error::Other(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => error.source(),
+            Error::Other(error) => error.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        anyhow::Error::from(error).into()
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        // Without context around it, the error is returned as it is.
+        let outermost: &(dyn std::error::Error + 'static) = &*error;
+        if outermost.is::<Error>() {
+            return error.downcast().expect("the error is an Error");
+        }
+        for cause in error.chain() {
+            if let Some(typed) = cause.downcast_ref::<Error>().and_then(Error::copy) {
+                return typed;
+            }
+            if let Some(io_error) = cause.downcast_ref::<io::Error>() {
+                if let Some(typed) = Error::carried_by(io_error) {
+                    return typed;
+                }
+                if let Some(newer) = fec::newer_format(io_error) {
+                    return Error::UnsupportedVersion(newer.version);
+                }
+            }
+            if let Some(newer) = cause.downcast_ref::<fec::NewerFormat>() {
+                return Error::UnsupportedVersion(newer.version);
+            }
+            if let Some(age::DecryptError::NoMatchingKeys | age::DecryptError::DecryptionFailed) =
+                cause.downcast_ref::<age::DecryptError>()
+            {
+                return Error::IdentityRejected;
+            }
+        }
+        if error.chain().count() == 1 && outermost.is::<io::Error>() {
+            return Error::Io(error.downcast().expect("the error is an io::Error"));
+        }
+        Error::Other(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+    use std::io::Read;
+
+    /// Fails every read with an `io::Error` carrying its variant.
+    struct Failing(Error);
+
+    impl Read for Failing {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            let error = self.0.copy().expect("the variant holds no source");
+            Err(error.into_io())
+        }
+    }
+
+    #[test]
+    fn finds_the_variant_behind_context_and_readers() {
+        let corrupt = Error::CorruptBlock {
+            group: 3,
+            offset: 4096,
+        };
+        let error = Err::<(), _>(corrupt.into_io())
+            .context("Failed to read archive entry")
+            .unwrap_err();
+        assert!(matches!(
+            Error::from(error),
+            Error::CorruptBlock {
+                group: 3,
+                offset: 4096
+            }
+        ));
+
+        // As age returns it, from below its payload reader.
+        let mut below_age = integrity::SourceReader::new(Failing(Error::CorruptBlock {
+            group: 5,
+            offset: 72,
+        }));
+        let error = below_age.read(&mut [0; 16]).unwrap_err();
+        let error = anyhow::Error::from(error).context("Failed to read archive");
+        assert!(matches!(
+            Error::from(error),
+            Error::CorruptBlock {
+                group: 5,
+                offset: 72
+            }
+        ));
+
+        let error = anyhow::Error::from(Error::NoIdentities).context("Failed to open archive");
+        assert!(matches!(Error::from(error), Error::NoIdentities));
+    }
+
+    #[test]
+    fn keeps_the_context_of_other_failures() {
+        let error = Err::<(), _>(io::Error::from(io::ErrorKind::NotFound))
+            .context("Failed to open input file: missing.sage")
+            .unwrap_err();
+        let error = Error::from(error);
+        assert!(matches!(error, Error::Other(_)));
+        assert_eq!(error.to_string(), "Failed to open input file: missing.sage");
+        assert_eq!(
+            format!("{error:#}"),
+            "Failed to open input file: missing.sage: entity not found"
+        );
+
+        let bare = Error::from(io::Error::from(io::ErrorKind::NotFound));
+        assert!(matches!(bare, Error::Io(_)));
+    }
+}
@@ -0,0 +1,243 @@
+//! Newline-delimited JSON output for `--json`, for driving sage from other programs.
+//!
+//! Every log record, progress update and result is one JSON object on its own line, with a
+//! `type` of `log`, `progress`, `summary` or `error`, or `entry` for the paths of a
+//! `protect --dry-run`. Log records and progress go to standard error. The closing `summary`
+//! or `error` goes to standard output, unless the command writes its data there, in which
+//! case it goes to standard error too.
+
+use sage::progress::Progress;
+use serde_json::{Value, json};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// The least time between two progress events.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Where the result goes, once JSON output is enabled.
+static RESULT_TO_STDOUT: OnceLock<bool> = OnceLock::new();
+
+/// Enables JSON output; `stdout_is_data` sends the result to standard error instead.
+pub fn init(stdout_is_data: bool) {
+    let _ = RESULT_TO_STDOUT.set(!stdout_is_data);
+}
+
+/// Whether `--json` was given.
+pub fn enabled() -> bool {
+    RESULT_TO_STDOUT.get().is_some()
+}
+
+/// Formats a log record for env_logger as a `log` event.
+pub fn format_record(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> io::Result<()> {
+    let event = json!({
+        "type": "log",
+        "time": buf.timestamp().to_string(),
+        "level": record.level().as_str().to_lowercase(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    });
+    writeln!(buf, "{event}")
+}
+
+/// Writes any event to standard error.
+pub fn emit(event: &Value) {
+    let _ = writeln!(io::stderr().lock(), "{event}");
+}
+
+/// Writes the `summary` of a successful command. Does nothing without `--json`.
+pub fn summary(command: &str, mut fields: Value, started: Instant) {
+    if !enabled() {
+        return;
+    }
+    fields["type"] = json!("summary");
+    fields["command"] = json!(command);
+    fields["elapsed_secs"] = json!(started.elapsed().as_secs_f64());
+    write_result(&fields);
+}
+
+/// Writes the `error` that ended the command. Does nothing without `--json`.
+pub fn error(error: &anyhow::Error) {
+    if !enabled() {
+        return;
+    }
+    let causes: Vec<String> = error.chain().skip(1).map(|e| e.to_string()).collect();
+    write_result(&json!({
+        "type": "error",
+        "message": error.to_string(),
+        "causes": causes,
+        "interrupted": sage::cancel::is_requested(),
+    }));
+}
+
+fn write_result(event: &Value) {
+    if RESULT_TO_STDOUT.get() == Some(&true) {
+        let mut stdout = io::stdout().lock();
+        let _ = writeln!(stdout, "{event}");
+        let _ = stdout.flush();
+    } else {
+        emit(event);
+    }
+}
+
+/// A [`Progress`] that writes `progress` events, at most every [`PROGRESS_INTERVAL`].
+pub struct JsonProgress {
+    state: Mutex<ProgressState>,
+}
+
+#[derive(Default)]
+struct ProgressState {
+    bytes: u64,
+    total: Option<u64>,
+    entry: Option<String>,
+    last: Option<Instant>,
+}
+
+impl JsonProgress {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(ProgressState::default()),
+        }
+    }
+
+    fn update(&self, force: bool, f: impl FnOnce(&mut ProgressState)) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut state);
+        if !force
+            && state
+                .last
+                .is_some_and(|last| last.elapsed() < PROGRESS_INTERVAL)
+        {
+            return;
+        }
+        state.last = Some(Instant::now());
+        emit(&json!({
+            "type": "progress",
+            "bytes": state.bytes,
+            "total": state.total,
+            "entry": state.entry,
+        }));
+    }
+}
+
+impl Progress for JsonProgress {
+    fn set_total(&self, total: u64) {
+        self.update(false, |state| state.total = Some(total));
+    }
+
+    fn advance(&self, bytes: u64) {
+        self.update(false, |state| state.bytes += bytes);
+    }
+
+    fn set_entry(&self, path: &Path) {
+        let entry = path.display().to_string();
+        self.update(false, |state| state.entry = Some(entry));
+    }
+
+    fn finish(&self) {
+        self.update(true, |state| state.entry = None);
+    }
+}
+
+/// Counts the entries and bytes an operation reports, for the summary, and passes the
+/// updates on to `inner`.
+pub struct Tally {
+    inner: Option<Arc<dyn Progress>>,
+    entries: AtomicU64,
+    bytes: AtomicU64,
+    manifest_hash: Mutex<Option<blake3::Hash>>,
+}
+
+impl Tally {
+    pub fn new(inner: Option<Arc<dyn Progress>>) -> Self {
+        Self {
+            inner,
+            entries: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            manifest_hash: Mutex::new(None),
+        }
+    }
+
+    /// The number of entries started, which for protect is the number of files.
+    pub fn entries(&self) -> u64 {
+        self.entries.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// The hash of the checksum list of the archive that was protected, if there was one.
+    pub fn manifest_hash(&self) -> Option<blake3::Hash> {
+        *self.manifest_hash.lock().unwrap()
+    }
+}
+
+impl Progress for Tally {
+    fn set_total(&self, total: u64) {
+        if let Some(inner) = &self.inner {
+            inner.set_total(total);
+        }
+    }
+
+    fn advance(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(inner) = &self.inner {
+            inner.advance(bytes);
+        }
+    }
+
+    fn set_entry(&self, path: &Path) {
+        self.entries.fetch_add(1, Ordering::Relaxed);
+        if let Some(inner) = &self.inner {
+            inner.set_entry(path);
+        }
+    }
+
+    fn set_manifest_hash(&self, hash: blake3::Hash) {
+        *self.manifest_hash.lock().unwrap() = Some(hash);
+        if let Some(inner) = &self.inner {
+            inner.set_manifest_hash(hash);
+        }
+    }
+
+    fn finish(&self) {
+        if let Some(inner) = &self.inner {
+            inner.finish();
+        }
+    }
+}
+
+/// Counts the bytes written through it.
+pub struct Counted<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Counted<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, count: 0 }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
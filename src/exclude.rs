@@ -0,0 +1,96 @@
+//! Glob patterns for leaving files out of an archive.
+
+use anyhow::Context;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::fs;
+use std::path::Path;
+
+/// A set of exclude patterns, matched against paths relative to the archived directory.
+///
+/// A pattern without a `/` matches the name of a file or directory at any depth, so
+/// `node_modules` or `*.tmp` skip every match. A pattern containing a `/` matches the whole
+/// relative path, with `*` not crossing directory boundaries and `**` matching any number of
+/// directories. A trailing `/` restricts a pattern to directories. Excluding a directory
+/// skips everything below it.
+#[derive(Clone, Debug, Default)]
+pub struct ExcludeSet {
+    names: GlobSet,
+    paths: GlobSet,
+    dir_names: GlobSet,
+    dir_paths: GlobSet,
+    len: usize,
+}
+
+impl ExcludeSet {
+    /// Compiles `patterns`, failing on the first invalid glob.
+    pub fn new<I, S>(patterns: I) -> crate::Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut names = GlobSetBuilder::new();
+        let mut paths = GlobSetBuilder::new();
+        let mut dir_names = GlobSetBuilder::new();
+        let mut dir_paths = GlobSetBuilder::new();
+        let mut len = 0;
+        for pattern in patterns {
+            let pattern = pattern.as_ref();
+            let (glob, dir_only) = match pattern.strip_suffix('/') {
+                Some(glob) => (glob, true),
+                None => (pattern, false),
+            };
+            let glob = glob.strip_prefix('/').unwrap_or(glob);
+            let anchored = glob.contains('/');
+            let compiled = GlobBuilder::new(glob)
+                .literal_separator(true)
+                .build()
+                .with_context(|| format!("Invalid exclude pattern: {pattern}"))?;
+            match (dir_only, anchored) {
+                (false, false) => names.add(compiled),
+                (false, true) => paths.add(compiled),
+                (true, false) => dir_names.add(compiled),
+                (true, true) => dir_paths.add(compiled),
+            };
+            len += 1;
+        }
+
+        Ok(Self {
+            names: names.build().map_err(anyhow::Error::from)?,
+            paths: paths.build().map_err(anyhow::Error::from)?,
+            dir_names: dir_names.build().map_err(anyhow::Error::from)?,
+            dir_paths: dir_paths.build().map_err(anyhow::Error::from)?,
+            len,
+        })
+    }
+
+    /// Reads patterns from `path`, one per line; blank lines and lines starting with `#` are
+    /// skipped.
+    pub fn read_patterns(path: &Path) -> crate::Result<Vec<String>> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read exclude file: {}", path.display()))?;
+        Ok(contents
+            .lines()
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// The number of patterns in the set.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the set has no patterns.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns true if the entry at `rel_path` should be left out of the archive.
+    pub fn is_excluded(&self, rel_path: &Path, is_dir: bool) -> bool {
+        let name_matches = |set: &GlobSet| rel_path.file_name().is_some_and(|n| set.is_match(n));
+        name_matches(&self.names)
+            || self.paths.is_match(rel_path)
+            || (is_dir && (name_matches(&self.dir_names) || self.dir_paths.is_match(rel_path)))
+    }
+}
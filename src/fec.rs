@@ -0,0 +1,1754 @@
+//! Forward error correction for protected archives.
+//!
+//! The encrypted age stream is cut into groups of `data_shards` equally sized shards, and
+//! `parity_shards` Reed–Solomon parity shards are computed for every group. Each shard is
+//! written as a self-describing frame with its own CRC32, so a damaged shard is detected
+//! and treated as an erasure. Any `data_shards` intact shards of a group are enough to
+//! rebuild it.
+//!
+//! Layout:
+//!
+//! ```text
+//! file header   MAGIC | version | data | parity | level | shard_size | codec | flags
+//!               | header_len | window_log | created | tool_version | stripe
+//!               | content_hash | crc32
+//! group 0       frame 0 .. frame (data + parity - 1)
+//! group 1       ...
+//!
+//! frame         SHARD_MAGIC | group | index | data | parity | flags | shard_size
+//!               | data_len | crc32 | shard bytes
+//! ```
+//!
+//! `codec` identifies the compression of the payload and `level` its compression level,
+//! which is recorded for information only. `window_log` is the window of zstd long-distance
+//! matching as a power of two, or 0 if it was not used; recovering needs as much memory.
+//! The `flags` mark a payload that is a single stream rather than a tar archive, one that
+//! is [deduplicated](crate::dedup), one compressed in [seekable frames](crate::seekable),
+//! one compressed with a trained zstd dictionary, which the encrypted payload starts
+//! with, and one that is not encrypted at all, which older readers fail to decrypt.
+//! Version 1 headers have none of the fields after `shard_size`, and always hold a
+//! zstd-compressed tar archive; version 2 headers end after the byte of `window_log`, with
+//! no `header_len`, `window_log`, `created` (Unix seconds) or `tool_version` (major, minor,
+//! patch), and the first version 3 headers end before `content_hash`, the BLAKE3 hash of
+//! the tar stream before compression and encryption, which is all zeros when it was not
+//! recorded. All integers are little-endian. The last group is flagged and uses smaller
+//! shards sized to its remaining data, so a lost tail of the archive is reported instead of
+//! ignored.
+//!
+//! From version 3 on, every header starts with the magic and version, records its own length
+//! in `header_len` and ends with a CRC32 of the rest. A reader can therefore tell a header
+//! written by a newer sage from a damaged one, and reports [`NewerFormat`] instead of
+//! guessing. Unknown flags are ignored, and so are fields appended before the CRC, which
+//! `header_len` lets a reader skip; anything an older reader cannot handle bumps the
+//! version.
+//!
+//! Every group but the last holds exactly `data_shards * shard_size` bytes of payload, so
+//! [`FecReader`] can seek to any payload offset by jumping to the group that holds it.
+//!
+//! In the [interleaved layout](EccLayout::Interleaved), `stripe` consecutive groups are
+//! written together, frame 0 of each of them first, then frame 1 of each, and so on, so a
+//! long run of damage costs every group of the stripe a frame or two instead of all the
+//! parity of one group. A last group with smaller frames follows its stripe as it is. Such
+//! archives are written as version 4, which only adds `stripe`, so that older readers
+//! report them as newer rather than as damaged; archives in the sequential layout, with a
+//! `stripe` of 0, are still written as version 3.
+
+use crate::compress::Codec;
+use crate::progress::Progress;
+use log::{debug, error, warn};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::Serialize;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Magic bytes at the start of an error-corrected archive.
+pub const MAGIC: &[u8; 8] = b"SAGEFEC\0";
+/// Version of the archive layout.
+pub const VERSION: u8 = 4;
+/// Version written for archives in the sequential layout, which sage from before the
+/// [interleaved layout](EccLayout::Interleaved) reads too.
+pub const SEQUENTIAL_VERSION: u8 = 3;
+/// Default number of data shards per group.
+pub const DEFAULT_DATA_SHARDS: u8 = 32;
+/// Default number of parity shards per group.
+pub const DEFAULT_PARITY_SHARDS: u8 = 4;
+/// Default size of a shard in bytes.
+pub const DEFAULT_SHARD_SIZE: u32 = 64 * 1024;
+/// Largest shard size accepted, which bounds the memory a group needs to about 4 GiB.
+pub const MAX_SHARD_SIZE: u32 = 16 * 1024 * 1024;
+/// Default number of groups interleaved in a stripe.
+pub const DEFAULT_STRIPE_GROUPS: u16 = 16;
+/// Most groups interleaved in a stripe, which is buffered whole while writing and reading.
+pub const MAX_STRIPE_GROUPS: u16 = 256;
+
+const SHARD_MAGIC: &[u8; 4] = b"SGSH";
+const FILE_HEADER_LEN: usize = 72;
+const FILE_HEADER_LEN_V3: usize = 40;
+const FILE_HEADER_LEN_V2: usize = 24;
+const FILE_HEADER_LEN_V1: usize = 20;
+const CODEC_UNKNOWN: u8 = 0xff;
+const HEADER_FLAG_STREAM: u8 = 0x01;
+const HEADER_FLAG_DEDUP: u8 = 0x02;
+const HEADER_FLAG_SEEKABLE: u8 = 0x04;
+const HEADER_FLAG_DICTIONARY: u8 = 0x08;
+const HEADER_FLAG_UNENCRYPTED: u8 = 0x10;
+const FRAME_HEADER_LEN: usize = 28;
+const FLAG_LAST_GROUP: u8 = 0x01;
+
+/// How the frames of the error correction groups are laid out in the archive.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum EccLayout {
+    /// One group after the other.
+    #[default]
+    Sequential,
+    /// The frames of several groups in turn, so a long run of damage hits every group
+    /// of a stripe a little instead of one group entirely.
+    Interleaved,
+}
+
+/// The geometry of the error correction layer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FecParams {
+    pub data_shards: u8,
+    pub parity_shards: u8,
+    pub shard_size: u32,
+}
+
+impl Default for FecParams {
+    fn default() -> Self {
+        Self {
+            data_shards: DEFAULT_DATA_SHARDS,
+            parity_shards: DEFAULT_PARITY_SHARDS,
+            shard_size: DEFAULT_SHARD_SIZE,
+        }
+    }
+}
+
+impl FecParams {
+    /// Builds the default geometry with enough parity shards for `percent` redundancy.
+    pub fn with_redundancy(percent: u8) -> Self {
+        let data_shards = DEFAULT_DATA_SHARDS as u32;
+        let parity_shards = (data_shards * percent as u32).div_ceil(100).max(1);
+        Self {
+            parity_shards: parity_shards as u8,
+            ..Self::default()
+        }
+    }
+
+    /// The parity overhead as a percentage of the data.
+    pub fn redundancy(&self) -> f64 {
+        self.parity_shards as f64 * 100.0 / self.data_shards as f64
+    }
+
+    /// The bytes of parity frames in an archive of `archive_len` bytes with this geometry.
+    ///
+    /// Every shard of a group is framed alike, so parity frames take the same share of the
+    /// archive after its header as parity shards do of a group.
+    pub fn parity_bytes(&self, archive_len: u64) -> u64 {
+        let frames = archive_len.saturating_sub(FILE_HEADER_LEN as u64);
+        frames * self.parity_shards as u64 / self.total_shards() as u64
+    }
+
+    /// Checks that the geometry can be handled by the GF(2^8) Reed–Solomon codec.
+    pub fn validate(&self) -> io::Result<()> {
+        if self.data_shards == 0 || self.parity_shards == 0 {
+            return Err(invalid_data(
+                "data and parity shard counts must be non-zero",
+            ));
+        }
+        if self.data_shards as usize + self.parity_shards as usize > 255 {
+            return Err(invalid_data("at most 255 shards per group are supported"));
+        }
+        if self.shard_size == 0 {
+            return Err(invalid_data("shard size must be non-zero"));
+        }
+        if self.shard_size > MAX_SHARD_SIZE {
+            return Err(invalid_data(&format!(
+                "shard size of {} bytes exceeds the maximum of {MAX_SHARD_SIZE}",
+                self.shard_size
+            )));
+        }
+        Ok(())
+    }
+
+    /// The size of a full group on disk.
+    fn group_len(&self) -> u64 {
+        (self.total_shards() * (FRAME_HEADER_LEN + self.shard_size as usize)) as u64
+    }
+
+    pub(crate) fn total_shards(&self) -> usize {
+        self.data_shards as usize + self.parity_shards as usize
+    }
+
+    pub(crate) fn group_capacity(&self) -> usize {
+        self.data_shards as usize * self.shard_size as usize
+    }
+
+    pub(crate) fn codec(&self) -> io::Result<ReedSolomon> {
+        self.validate()?;
+        ReedSolomon::new(self.data_shards as usize, self.parity_shards as usize)
+            .map_err(|e| invalid_data(&format!("invalid error correction geometry: {e}")))
+    }
+}
+
+/// The archive file header: the error correction geometry plus payload metadata.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FileHeader {
+    /// The format version the header was read from, or 0 if there was none. Headers are
+    /// written as [`FileHeader::format_version`].
+    pub version: u8,
+    pub params: FecParams,
+    /// The compression level of the payload, or 0 if it was not recorded.
+    pub compression_level: u8,
+    /// The compression codec of the payload, or `None` if it is not known.
+    pub codec: Option<Codec>,
+    /// The payload is a single stream, such as standard input, rather than a tar archive.
+    pub stream: bool,
+    /// The payload was chunked and deduplicated before compression.
+    pub dedup: bool,
+    /// The payload was compressed in independent frames with a seek table.
+    pub seekable: bool,
+    /// The payload starts with a zstd dictionary it was compressed with.
+    pub dictionary: bool,
+    /// The payload is compressed but not encrypted with age.
+    pub unencrypted: bool,
+    /// The zstd window of long-distance matching, as a power of two, if it was used.
+    pub window_log: Option<u8>,
+    /// When the archive was made, in seconds since the Unix epoch, if recorded.
+    pub created: Option<u64>,
+    /// The version of sage that made the archive, if recorded.
+    pub tool_version: Option<[u16; 3]>,
+    /// The BLAKE3 hash of the tar stream before compression and encryption, if recorded.
+    pub content_hash: Option<[u8; 32]>,
+    /// The number of groups whose frames are interleaved in every stripe, if the archive
+    /// uses the interleaved layout.
+    pub stripe_groups: Option<u16>,
+}
+
+impl FileHeader {
+    /// Returns the header for a new archive, recording the current time and sage version.
+    pub fn new(params: FecParams) -> Self {
+        let created = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .ok();
+        Self {
+            version: SEQUENTIAL_VERSION,
+            params,
+            created,
+            tool_version: Some(tool_version()),
+            ..Self::default()
+        }
+    }
+
+    /// The format version the header is written as: [`VERSION`] for the interleaved layout,
+    /// which older sage cannot read, and [`SEQUENTIAL_VERSION`] otherwise, so that it can.
+    pub fn format_version(&self) -> u8 {
+        match self.stripe_groups {
+            Some(_) => VERSION,
+            None => SEQUENTIAL_VERSION,
+        }
+    }
+
+    fn encode(self) -> [u8; FILE_HEADER_LEN] {
+        let mut header = [0u8; FILE_HEADER_LEN];
+        header[..8].copy_from_slice(MAGIC);
+        header[8] = self.format_version();
+        header[9] = self.params.data_shards;
+        header[10] = self.params.parity_shards;
+        header[11] = self.compression_level;
+        header[12..16].copy_from_slice(&self.params.shard_size.to_le_bytes());
+        header[16] = self.codec.map_or(CODEC_UNKNOWN, Codec::id);
+        if self.stream {
+            header[17] |= HEADER_FLAG_STREAM;
+        }
+        if self.dedup {
+            header[17] |= HEADER_FLAG_DEDUP;
+        }
+        if self.seekable {
+            header[17] |= HEADER_FLAG_SEEKABLE;
+        }
+        if self.dictionary {
+            header[17] |= HEADER_FLAG_DICTIONARY;
+        }
+        if self.unencrypted {
+            header[17] |= HEADER_FLAG_UNENCRYPTED;
+        }
+        header[18] = FILE_HEADER_LEN as u8;
+        header[19] = self.window_log.unwrap_or(0);
+        header[20..28].copy_from_slice(&self.created.unwrap_or(0).to_le_bytes());
+        for (i, part) in self.tool_version.unwrap_or_default().iter().enumerate() {
+            header[28 + 2 * i..30 + 2 * i].copy_from_slice(&part.to_le_bytes());
+        }
+        header[34..36].copy_from_slice(&self.stripe_groups.unwrap_or(0).to_le_bytes());
+        header[36..68].copy_from_slice(&self.content_hash.unwrap_or_default());
+        let crc = crc32fast::hash(&header[..FILE_HEADER_LEN - 4]);
+        header[FILE_HEADER_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+        header
+    }
+
+    /// Parses a header of version 1 to [`VERSION`], returning `None` if it is damaged.
+    fn decode(header: &[u8]) -> Option<Self> {
+        if !header.starts_with(MAGIC) || header.len() < FILE_HEADER_LEN_V1 {
+            return None;
+        }
+        let version = header[8];
+        let len = match version {
+            1 => FILE_HEADER_LEN_V1,
+            2 => FILE_HEADER_LEN_V2,
+            SEQUENTIAL_VERSION | VERSION if header[18] as usize >= FILE_HEADER_LEN_V3 => {
+                header[18] as usize
+            }
+            _ => return None,
+        };
+        if header.len() != len {
+            return None;
+        }
+        let (checked, crc) = header.split_at(len - 4);
+        if crc32fast::hash(checked) != u32::from_le_bytes(crc.try_into().unwrap()) {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes(header[i..i + 2].try_into().unwrap());
+        let (created, tool_version) = if version >= 3 {
+            let created = u64::from_le_bytes(header[20..28].try_into().unwrap());
+            let tool_version = [u16_at(28), u16_at(30), u16_at(32)];
+            (
+                Some(created).filter(|&t| t != 0),
+                Some(tool_version).filter(|v| v.iter().any(|&part| part != 0)),
+            )
+        } else {
+            (None, None)
+        };
+        let content_hash = header
+            .get(36..68)
+            .filter(|_| len >= FILE_HEADER_LEN)
+            .map(|hash| <[u8; 32]>::try_from(hash).unwrap())
+            .filter(|hash| hash.iter().any(|&byte| byte != 0));
+        Some(Self {
+            version,
+            params: FecParams {
+                data_shards: header[9],
+                parity_shards: header[10],
+                shard_size: u32::from_le_bytes(header[12..16].try_into().unwrap()),
+            },
+            compression_level: header[11],
+            codec: if version == 1 {
+                Some(Codec::Zstd)
+            } else {
+                Codec::from_id(header[16])
+            },
+            stream: version > 1 && header[17] & HEADER_FLAG_STREAM != 0,
+            dedup: version > 1 && header[17] & HEADER_FLAG_DEDUP != 0,
+            seekable: version > 1 && header[17] & HEADER_FLAG_SEEKABLE != 0,
+            dictionary: version > 1 && header[17] & HEADER_FLAG_DICTIONARY != 0,
+            unencrypted: version > 1 && header[17] & HEADER_FLAG_UNENCRYPTED != 0,
+            window_log: Some(header[19]).filter(|&log| version >= 3 && log != 0),
+            created,
+            tool_version,
+            content_hash,
+            stripe_groups: (version >= 4)
+                .then(|| u16_at(34))
+                .filter(|&groups| groups > 1),
+        })
+    }
+}
+
+/// The version of this sage, as recorded in new archive headers.
+fn tool_version() -> [u16; 3] {
+    let mut parts = env!("CARGO_PKG_VERSION")
+        .split(['.', '-', '+'])
+        .map(|part| part.parse().unwrap_or(0));
+    [(); 3].map(|()| parts.next().unwrap_or(0))
+}
+
+/// Returns the version of an intact header written by a newer sage, or `None` if `header`
+/// is of a known version or damaged.
+fn newer_version(header: &[u8]) -> Option<u8> {
+    let version = *header.get(8)?;
+    let len = *header.get(18)? as usize;
+    if !header.starts_with(MAGIC) || version <= VERSION || len < FILE_HEADER_LEN_V2 {
+        return None;
+    }
+    let (checked, crc) = header.get(..len)?.split_at(len - 4);
+    (crc32fast::hash(checked) == u32::from_le_bytes(crc.try_into().unwrap())).then_some(version)
+}
+
+/// The archive was written by a newer version of sage, in a layout this one cannot read.
+#[derive(Debug)]
+pub struct NewerFormat {
+    /// The format version recorded in the archive.
+    pub version: u8,
+}
+
+impl std::fmt::Display for NewerFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "archive was made by a newer version of sage (format version {}; this sage reads up to {VERSION}); please upgrade sage to read it",
+            self.version
+        )
+    }
+}
+
+impl std::error::Error for NewerFormat {}
+
+/// Returns the [`NewerFormat`] behind `error`, if reading failed because of one.
+pub fn newer_format(error: &io::Error) -> Option<&NewerFormat> {
+    error.get_ref()?.downcast_ref()
+}
+
+/// Returns true if `prefix` starts with the error-corrected archive magic.
+pub fn has_magic(prefix: &[u8]) -> bool {
+    prefix.starts_with(MAGIC)
+}
+
+/// Frame metadata shared by every shard of a group.
+struct FrameHeader {
+    group: u64,
+    index: u8,
+    params: FecParams,
+    last: bool,
+    data_len: u32,
+}
+
+impl FrameHeader {
+    fn encode(&self, shard: &[u8]) -> [u8; FRAME_HEADER_LEN] {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        header[..4].copy_from_slice(SHARD_MAGIC);
+        header[4..12].copy_from_slice(&self.group.to_le_bytes());
+        header[12] = self.index;
+        header[13] = self.params.data_shards;
+        header[14] = self.params.parity_shards;
+        header[15] = if self.last { FLAG_LAST_GROUP } else { 0 };
+        header[16..20].copy_from_slice(&self.params.shard_size.to_le_bytes());
+        header[20..24].copy_from_slice(&self.data_len.to_le_bytes());
+        let crc = frame_crc(&header[..24], shard);
+        header[24..28].copy_from_slice(&crc.to_le_bytes());
+        header
+    }
+
+    /// Parses a frame, returning `None` if its header or shard is damaged.
+    fn decode(frame: &[u8]) -> Option<Self> {
+        let (header, shard) = frame.split_at(FRAME_HEADER_LEN);
+        let crc = u32::from_le_bytes(header[24..28].try_into().unwrap());
+        if &header[..4] != SHARD_MAGIC || frame_crc(&header[..24], shard) != crc {
+            return None;
+        }
+        Some(Self {
+            group: u64::from_le_bytes(header[4..12].try_into().unwrap()),
+            index: header[12],
+            params: FecParams {
+                data_shards: header[13],
+                parity_shards: header[14],
+                shard_size: u32::from_le_bytes(header[16..20].try_into().unwrap()),
+            },
+            last: header[15] & FLAG_LAST_GROUP != 0,
+            data_len: u32::from_le_bytes(header[20..24].try_into().unwrap()),
+        })
+    }
+}
+
+fn frame_crc(header: &[u8], shard: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(header);
+    hasher.update(shard);
+    hasher.finalize()
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reads until `buf` is full or EOF is reached, returning the number of bytes read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Checks the number of groups a stripe interleaves.
+fn validate_stripe(groups: u16) -> io::Result<()> {
+    if !(2..=MAX_STRIPE_GROUPS).contains(&groups) {
+        return Err(invalid_data(&format!(
+            "a stripe must interleave 2 to {MAX_STRIPE_GROUPS} groups, not {groups}"
+        )));
+    }
+    Ok(())
+}
+
+/// Reorders the frames of `groups` whole groups of `total` frames of `frame_len` bytes each,
+/// from one group after the other to frame 0 of every group, then frame 1 of every group,
+/// and so on, or back again with `undo`.
+fn interleave(stripe: &[u8], groups: usize, total: usize, frame_len: usize, undo: bool) -> Vec<u8> {
+    let mut out = vec![0u8; stripe.len()];
+    for group in 0..groups {
+        for index in 0..total {
+            let sequential = (group * total + index) * frame_len;
+            let interleaved = (index * groups + group) * frame_len;
+            let (from, to) = match undo {
+                false => (sequential, interleaved),
+                true => (interleaved, sequential),
+            };
+            out[to..to + frame_len].copy_from_slice(&stripe[from..from + frame_len]);
+        }
+    }
+    out
+}
+
+/// Adds Reed–Solomon parity to everything written through it.
+pub struct FecWriter<W: Write> {
+    inner: W,
+    params: FecParams,
+    codec: ReedSolomon,
+    buffer: Vec<u8>,
+    group: u64,
+    /// The groups interleaved in a stripe, 1 for the sequential layout.
+    stripe_groups: usize,
+    /// The frames of the groups of the current stripe, one group after the other.
+    stripe: Vec<u8>,
+}
+
+impl<W: Write> FecWriter<W> {
+    /// Writes `header` to `inner` and prepares to encode groups with its geometry.
+    pub fn new(mut inner: W, header: FileHeader) -> io::Result<Self> {
+        let params = header.params;
+        let codec = params.codec()?;
+        if let Some(groups) = header.stripe_groups {
+            validate_stripe(groups)?;
+            debug!("Interleaving the frames of {groups} groups in every stripe.");
+        }
+        inner.write_all(&header.encode())?;
+        Ok(Self {
+            inner,
+            params,
+            codec,
+            buffer: Vec::with_capacity(params.group_capacity()),
+            group: 0,
+            stripe_groups: header.stripe_groups.map_or(1, usize::from),
+            stripe: Vec::new(),
+        })
+    }
+
+    /// Continues writing groups after `checkpoint`, to an `inner` that holds what the
+    /// writer had written when the checkpoint was taken.
+    pub fn resume(inner: W, checkpoint: FecCheckpoint) -> io::Result<Self> {
+        let params = checkpoint.params;
+        let codec = params.codec()?;
+        let stripe_groups = checkpoint.stripe_groups.map_or(1, usize::from);
+        if checkpoint.buffer.len() > params.group_capacity()
+            || checkpoint.stripe.len() >= stripe_groups * params.group_len() as usize
+        {
+            return Err(invalid_data(
+                "the checkpoint does not fit the group geometry",
+            ));
+        }
+        debug!("Resuming error correction at group {}.", checkpoint.group);
+        let mut buffer = checkpoint.buffer;
+        buffer.reserve(params.group_capacity() - buffer.len());
+        Ok(Self {
+            inner,
+            params,
+            codec,
+            buffer,
+            group: checkpoint.group,
+            stripe_groups,
+            stripe: checkpoint.stripe,
+        })
+    }
+
+    /// Flushes the inner writer and returns what [`FecWriter::resume`] needs to continue
+    /// from here: the groups written so far are all in the inner writer, and the data of the
+    /// group, and the frames of the stripe, that are not complete yet are in the checkpoint.
+    pub fn checkpoint(&mut self) -> io::Result<FecCheckpoint> {
+        self.inner.flush()?;
+        Ok(FecCheckpoint {
+            params: self.params,
+            stripe_groups: (self.stripe_groups > 1).then_some(self.stripe_groups as u16),
+            group: self.group,
+            buffer: self.buffer.clone(),
+            stripe: self.stripe.clone(),
+        })
+    }
+
+    /// The inner writer, which holds every group written so far.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Encodes the final group and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_group(true)?;
+        self.write_stripe()?;
+        self.inner.flush()?;
+        debug!("Wrote {} error correction groups.", self.group);
+        Ok(self.inner)
+    }
+
+    fn write_group(&mut self, last: bool) -> io::Result<()> {
+        let data_len = self.buffer.len();
+        let data_shards = self.params.data_shards as usize;
+        // The last group shrinks its shards to fit the remaining data.
+        let shard_size = if last {
+            data_len.div_ceil(data_shards).max(1)
+        } else {
+            self.params.shard_size as usize
+        };
+
+        let mut shards = vec![vec![0u8; shard_size]; self.params.total_shards()];
+        for (shard, chunk) in shards.iter_mut().zip(self.buffer.chunks(shard_size)) {
+            shard[..chunk.len()].copy_from_slice(chunk);
+        }
+        self.codec
+            .encode(&mut shards)
+            .map_err(|e| invalid_data(&format!("failed to compute parity: {e}")))?;
+
+        for (index, shard) in shards.iter().enumerate() {
+            let header = FrameHeader {
+                group: self.group,
+                index: index as u8,
+                params: FecParams {
+                    shard_size: shard_size as u32,
+                    ..self.params
+                },
+                last,
+                data_len: data_len as u32,
+            };
+            if self.stripe_groups > 1 {
+                self.stripe.extend_from_slice(&header.encode(shard));
+                self.stripe.extend_from_slice(shard);
+            } else {
+                self.inner.write_all(&header.encode(shard))?;
+                self.inner.write_all(shard)?;
+            }
+        }
+
+        self.buffer.clear();
+        self.group += 1;
+        if self.stripe.len() == self.stripe_groups * self.params.group_len() as usize {
+            self.write_stripe()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the groups of the current stripe with their frames interleaved. The smaller
+    /// frames of a last group that does not fill a whole group on disk follow as they are.
+    fn write_stripe(&mut self) -> io::Result<()> {
+        let group_len = self.params.group_len() as usize;
+        let groups = self.stripe.len() / group_len;
+        let (whole, rest) = self.stripe.split_at(groups * group_len);
+        let frame_len = FRAME_HEADER_LEN + self.params.shard_size as usize;
+        let total = self.params.total_shards();
+        self.inner
+            .write_all(&interleave(whole, groups, total, frame_len, false))?;
+        self.inner.write_all(rest)?;
+        self.stripe.clear();
+        Ok(())
+    }
+}
+
+/// The state of a [`FecWriter`] between two writes; see [`FecWriter::checkpoint`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FecCheckpoint {
+    pub params: FecParams,
+    pub stripe_groups: Option<u16>,
+    /// The group the buffered data belongs to.
+    pub group: u64,
+    /// The data of that group received so far.
+    pub buffer: Vec<u8>,
+    /// The frames of the complete groups of the current stripe.
+    pub stripe: Vec<u8>,
+}
+
+impl<W: Write> Write for FecWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let capacity = self.params.group_capacity();
+        let mut remaining = buf;
+        while !remaining.is_empty() {
+            // A full group is only written once more data arrives, so that `finish` can
+            // always flag the final group.
+            if self.buffer.len() == capacity {
+                self.write_group(false)?;
+            }
+            let take = remaining.len().min(capacity - self.buffer.len());
+            self.buffer.extend_from_slice(&remaining[..take]);
+            remaining = &remaining[take..];
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Counters describing the damage found while reading an error-corrected stream.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FecStats {
+    pub groups: u64,
+    pub repaired_groups: u64,
+    pub repaired_shards: u64,
+    pub unrecoverable_groups: u64,
+    /// The most damaged shards found in a group that could still be repaired.
+    pub most_damaged_shards: u64,
+    /// The group with [`FecStats::most_damaged_shards`], or the first group if none is damaged.
+    pub most_damaged_group: u64,
+    /// The first group damaged beyond repair and the byte it starts at, if any.
+    pub first_unrecoverable: Option<(u64, u64)>,
+}
+
+impl FecStats {
+    /// How many more shards any group could lose and still be rebuilt, given the parity
+    /// shards per group of `params`.
+    pub fn spare_shards(&self, params: FecParams) -> u64 {
+        (params.parity_shards as u64).saturating_sub(self.most_damaged_shards)
+    }
+}
+
+/// A group that error correction had to repair, or the file header rebuilt from the
+/// first shard.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Repair {
+    pub group: u64,
+    /// Whether this is the damaged file header rather than a group; `group` is then 0.
+    pub header: bool,
+    /// How many of its shards were damaged.
+    pub shards: u64,
+    /// Where the group, or the header, starts in the archive file.
+    pub offset: u64,
+    /// The length of the group, or the header, in the archive file.
+    pub len: u64,
+    /// Where each damaged shard sits in the archive file.
+    pub ranges: Vec<ShardRange>,
+    /// The archive entry being read when the group was, which is stored in the group or
+    /// next to it.
+    pub entry: Option<String>,
+}
+
+/// A damaged shard of a [`Repair`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ShardRange {
+    /// The index of the shard in its group.
+    pub index: usize,
+    /// Where its frame starts in the archive file.
+    pub offset: u64,
+    /// The length of its frame.
+    pub len: u64,
+}
+
+/// The groups repaired by every [`FecReader`] given it, once each.
+#[derive(Clone, Default)]
+pub struct RepairLog {
+    repairs: Arc<Mutex<Vec<Repair>>>,
+    /// Told of each repair as it is recorded.
+    progress: Option<Arc<dyn Progress>>,
+}
+
+impl RepairLog {
+    /// A log that also reports each repair to `progress` as it is recorded.
+    pub fn reporting_to(progress: Arc<dyn Progress>) -> Self {
+        Self {
+            repairs: Arc::default(),
+            progress: Some(progress),
+        }
+    }
+
+    fn repairs(&self) -> MutexGuard<'_, Vec<Repair>> {
+        self.repairs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Records `repair`, unless its group was recorded before.
+    pub fn record(&self, repair: Repair) {
+        let mut repairs = self.repairs();
+        if (repairs.iter()).any(|r| r.group == repair.group && r.header == repair.header) {
+            return;
+        }
+        repairs.push(repair.clone());
+        drop(repairs);
+        if let Some(progress) = &self.progress {
+            progress.block_corrected(&repair);
+        }
+    }
+
+    /// Sets the entry of the repairs recorded since the last call to `entry`.
+    pub fn attribute(&self, entry: &str) {
+        for repair in self.repairs().iter_mut().filter(|r| r.entry.is_none()) {
+            repair.entry = Some(entry.to_string());
+        }
+    }
+
+    /// Takes the repairs recorded so far, sorted by group after the header.
+    pub fn take(&self) -> Vec<Repair> {
+        let mut repairs = std::mem::take(&mut *self.repairs());
+        repairs.sort_by_key(|r| (!r.header, r.group));
+        repairs
+    }
+}
+
+/// Verifies and, where needed, repairs an error-corrected stream while reading it.
+pub struct FecReader<R: Read> {
+    inner: io::Chain<Cursor<Vec<u8>>, R>,
+    header: FileHeader,
+    params: FecParams,
+    codec: ReedSolomon,
+    stats: FecStats,
+    skip_unrecoverable: bool,
+    /// Where every group repaired is recorded, if anywhere.
+    repairs: Option<RepairLog>,
+    /// The length of the file header on disk.
+    header_len: u64,
+    /// Whether the file header was damaged and its geometry taken from the first shard.
+    header_rebuilt: bool,
+    group: u64,
+    /// Bytes of frames read so far, counted from the first group, in the order of the
+    /// sequential layout.
+    consumed: u64,
+    /// Bytes read from the archive so far, counted from the first group.
+    physical: u64,
+    /// The groups interleaved in a stripe, 1 for the sequential layout.
+    stripe_groups: usize,
+    /// The frames of the current stripe put back in order, where it starts, how long it is
+    /// on disk and how far its groups were read.
+    stripe: Vec<u8>,
+    stripe_start: Option<u64>,
+    stripe_read: u64,
+    stripe_pos: usize,
+    /// The groups interleaved in the current stripe, fewer than `stripe_groups` in a last
+    /// one cut short.
+    stripe_width: usize,
+    /// The payload length, once a seek from the end needed it.
+    len: Option<u64>,
+    /// The group read before the current one, its output and whether it was the last, kept
+    /// because seeks often go back and forth between the end and the current position.
+    previous: Option<(u64, Vec<u8>, bool)>,
+    frames: Vec<u8>,
+    output: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<R: Read> FecReader<R> {
+    /// Reads the file header from `inner`.
+    ///
+    /// If the header is damaged, the geometry is taken from the first shard frame instead,
+    /// which is verified together with the rest of the first group.
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut header = vec![0u8; FILE_HEADER_LEN_V1];
+        if read_full(&mut inner, &mut header)? < FILE_HEADER_LEN_V1 {
+            return Err(invalid_data("archive is too short to contain a header"));
+        }
+        // Version 3 and later headers record their length; a damaged one is caught by the CRC.
+        let len = match header[8] {
+            1 => FILE_HEADER_LEN_V1,
+            2 => FILE_HEADER_LEN_V2,
+            _ => (header[18] as usize).max(FILE_HEADER_LEN_V2),
+        };
+        if len > FILE_HEADER_LEN_V1 {
+            header.resize(len, 0);
+            let read =
+                FILE_HEADER_LEN_V1 + read_full(&mut inner, &mut header[FILE_HEADER_LEN_V1..])?;
+            header.truncate(read);
+        }
+        if let Some(version) = newer_version(&header) {
+            warn!("Archive has format version {version}, but this sage reads up to {VERSION}.");
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                NewerFormat { version },
+            ));
+        }
+
+        let decoded = FileHeader::decode(&header);
+        let header_rebuilt = decoded.is_none();
+        let (mut header, mut replay, header_len) = match decoded {
+            Some(decoded) => (decoded, Vec::new(), header.len()),
+            None => {
+                warn!("Archive header is damaged; reading geometry from the first shard.");
+                // The first frame follows the header, whose length depends on the version, so
+                // look for it at every known offset.
+                let mut buf = header[FILE_HEADER_LEN_V1..].to_vec();
+                let extra = buf.len();
+                buf.resize(
+                    extra.max(FILE_HEADER_LEN - FILE_HEADER_LEN_V1 + FRAME_HEADER_LEN),
+                    0,
+                );
+                let filled = extra + read_full(&mut inner, &mut buf[extra..])?;
+                buf.truncate(filled);
+                let frame = [
+                    FILE_HEADER_LEN_V1,
+                    FILE_HEADER_LEN_V2,
+                    FILE_HEADER_LEN_V3,
+                    FILE_HEADER_LEN,
+                ]
+                .into_iter()
+                .map(|offset| &buf[(offset - FILE_HEADER_LEN_V1).min(buf.len())..])
+                .find(|frame| frame.len() >= FRAME_HEADER_LEN && frame.starts_with(SHARD_MAGIC))
+                .ok_or_else(|| invalid_data("archive header is damaged beyond repair"))?;
+                let header = FileHeader {
+                    params: FecParams {
+                        data_shards: frame[13],
+                        parity_shards: frame[14],
+                        shard_size: u32::from_le_bytes(frame[16..20].try_into().unwrap()),
+                    },
+                    ..FileHeader::default()
+                };
+                (
+                    header,
+                    frame.to_vec(),
+                    FILE_HEADER_LEN_V1 + filled - frame.len(),
+                )
+            }
+        };
+        // Checks the geometry before anything is allocated for it, including geometry taken
+        // from an unverified frame above.
+        let params = header.params;
+        let codec = params.codec()?;
+        if header.version == 0 {
+            header.stripe_groups = detect_stripe(&mut inner, &mut replay, params)?;
+        }
+        if let Some(groups) = header.stripe_groups {
+            validate_stripe(groups)?;
+            debug!("Frames of {groups} groups are interleaved in every stripe.");
+        }
+        debug!(
+            "Error correction: {} data + {} parity shards of {} bytes.",
+            params.data_shards, params.parity_shards, params.shard_size
+        );
+
+        Ok(Self {
+            inner: Cursor::new(replay).chain(inner),
+            header,
+            params,
+            codec,
+            stats: FecStats::default(),
+            skip_unrecoverable: false,
+            repairs: None,
+            header_len: header_len as u64,
+            header_rebuilt,
+            group: 0,
+            consumed: 0,
+            physical: 0,
+            stripe_groups: header.stripe_groups.map_or(1, usize::from),
+            stripe: Vec::new(),
+            stripe_start: None,
+            stripe_read: 0,
+            stripe_pos: 0,
+            stripe_width: 0,
+            len: None,
+            previous: None,
+            frames: Vec::new(),
+            output: Vec::new(),
+            position: 0,
+            finished: false,
+        })
+    }
+
+    /// The file header read from the archive.
+    ///
+    /// If the header was damaged, its geometry comes from the first shard frame, the version
+    /// and compression level are 0 and the codec is unknown, so the stream and dedup flags
+    /// are not meaningful either.
+    pub fn header(&self) -> FileHeader {
+        self.header
+    }
+
+    /// The geometry read from the archive.
+    pub fn params(&self) -> FecParams {
+        self.params
+    }
+
+    /// Replace groups that cannot be repaired with zeros instead of failing.
+    ///
+    /// This is only useful for scanning the damage of an archive; the output is not a
+    /// valid age stream once a group has been skipped.
+    pub fn set_skip_unrecoverable(&mut self, skip: bool) {
+        self.skip_unrecoverable = skip;
+    }
+
+    /// Records every group that had to be repaired in `repairs`, and the file header if it
+    /// was rebuilt from the first shard.
+    pub fn log_repairs(&mut self, repairs: RepairLog) {
+        if self.header_rebuilt {
+            repairs.record(Repair {
+                group: 0,
+                header: true,
+                shards: 0,
+                offset: 0,
+                len: self.header_len,
+                ranges: Vec::new(),
+                entry: None,
+            });
+        }
+        self.repairs = Some(repairs);
+    }
+
+    /// Damage counters for the groups read so far.
+    pub fn stats(&self) -> FecStats {
+        self.stats
+    }
+
+    /// Reads, checks, and repairs the next group into the output buffer.
+    fn read_group(&mut self) -> io::Result<()> {
+        let total = self.params.total_shards();
+        let full_frame = FRAME_HEADER_LEN + self.params.shard_size as usize;
+        self.frames.resize(total * full_frame, 0);
+        let read = self.read_frames()?;
+        self.consumed += read as u64;
+        if read == 0 {
+            error!("Archive ends before its final error correction group.");
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "archive is truncated: final error correction group is missing",
+            ));
+        }
+
+        // Only the last group may be shorter. If a lost tail of the archive cut its frames, their
+        // length comes from one that is intact, and the missing frames are erasures.
+        let short = read < self.frames.len();
+        let frame_len = if short {
+            self.short_frame_len(read).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "archive is truncated in error correction group {}",
+                        self.group
+                    ),
+                )
+            })?
+        } else {
+            full_frame
+        };
+        let shard_size = frame_len - FRAME_HEADER_LEN;
+
+        let mut shards: Vec<Option<Vec<u8>>> = Vec::with_capacity(total);
+        let mut meta: Option<FrameHeader> = None;
+        for index in 0..total {
+            let Some(frame) = self.frames[..read].get(index * frame_len..(index + 1) * frame_len)
+            else {
+                shards.push(None);
+                continue;
+            };
+            let valid = FrameHeader::decode(frame).filter(|h| {
+                h.group == self.group
+                    && h.index as usize == index
+                    && h.params.shard_size as usize == shard_size
+                    && h.params.data_shards == self.params.data_shards
+                    && h.params.parity_shards == self.params.parity_shards
+            });
+            match valid {
+                Some(header) => {
+                    shards.push(Some(frame[FRAME_HEADER_LEN..].to_vec()));
+                    meta.get_or_insert(header);
+                }
+                None => shards.push(None),
+            }
+        }
+
+        let damaged = shards.iter().filter(|s| s.is_none()).count();
+        let meta = match meta {
+            Some(meta) if damaged <= self.params.parity_shards as usize => meta,
+            meta if self.skip_unrecoverable => {
+                warn!(
+                    "Error correction group {}: {} of {} shards damaged, skipping.",
+                    self.group, damaged, total
+                );
+                let (data_len, last) = meta.map_or(
+                    (self.params.data_shards as usize * shard_size, short),
+                    |m| (m.data_len as usize, m.last),
+                );
+                let offset = match self.stripe_start {
+                    Some(start) => start,
+                    None => self.consumed - read as u64,
+                };
+                self.output.clear();
+                self.output.resize(data_len, 0);
+                self.position = 0;
+                self.finished = last;
+                self.stats
+                    .first_unrecoverable
+                    .get_or_insert((self.group, self.header_len + offset));
+                self.group += 1;
+                self.stats.groups += 1;
+                self.stats.unrecoverable_groups += 1;
+                return Ok(());
+            }
+            _ => {
+                error!(
+                    "Error correction group {}: {} of {} shards damaged, at most {} can be repaired.",
+                    self.group, damaged, total, self.params.parity_shards
+                );
+                let offset = match self.stripe_start {
+                    Some(start) => start,
+                    None => self.consumed - read as u64,
+                };
+                return Err(crate::Error::CorruptBlock {
+                    group: self.group,
+                    offset: self.header_len + offset,
+                }
+                .into_io());
+            }
+        };
+        if damaged > 0 {
+            let ranges = (shards.iter().enumerate())
+                .filter(|(_, shard)| shard.is_none())
+                .map(|(index, _)| ShardRange {
+                    index,
+                    offset: self.header_len + self.frame_offset(index, frame_len, read),
+                    len: frame_len as u64,
+                })
+                .collect();
+            self.codec
+                .reconstruct_data(&mut shards)
+                .map_err(|e| invalid_data(&format!("failed to repair group: {e}")))?;
+            self.stats.repaired_groups += 1;
+            self.stats.repaired_shards += damaged as u64;
+            if let Some(repairs) = &self.repairs {
+                let (offset, len) = match self.stripe_start {
+                    Some(start) => (start, self.stripe_read),
+                    None => (self.consumed - read as u64, read as u64),
+                };
+                repairs.record(Repair {
+                    group: self.group,
+                    header: false,
+                    shards: damaged as u64,
+                    offset: self.header_len + offset,
+                    len,
+                    ranges,
+                    entry: None,
+                });
+            }
+            if damaged as u64 > self.stats.most_damaged_shards {
+                self.stats.most_damaged_shards = damaged as u64;
+                self.stats.most_damaged_group = self.group;
+            }
+            warn!(
+                "Error correction group {}: repaired {} damaged shards.",
+                self.group, damaged
+            );
+        }
+
+        let data_len = meta.data_len as usize;
+        if data_len > self.params.data_shards as usize * shard_size {
+            return Err(invalid_data("error correction group has an invalid length"));
+        }
+        self.output.clear();
+        for shard in shards.iter().take(self.params.data_shards as usize) {
+            self.output
+                .extend_from_slice(shard.as_deref().unwrap_or_default());
+        }
+        self.output.truncate(data_len);
+        self.position = 0;
+
+        if meta.last {
+            self.finished = true;
+        } else if short {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "archive is truncated after error correction group {}",
+                    self.group
+                ),
+            ));
+        }
+        self.group += 1;
+        self.stats.groups += 1;
+        Ok(())
+    }
+
+    /// Reads the frames of the next group into `frames`, returning how many bytes of them
+    /// there were.
+    /// Where frame `index` of the group just read, whose frames are `frame_len` bytes and
+    /// which took `read` bytes, starts after the file header.
+    fn frame_offset(&self, index: usize, frame_len: usize, read: usize) -> u64 {
+        let start = self.consumed - read as u64;
+        let Some(stripe_start) = self.stripe_start else {
+            return start + (index * frame_len) as u64;
+        };
+        // Frame `index` of the stripe's group `group` sits in slot `index * width + group`;
+        // groups past the interleaved ones of a cut stripe follow one after the other.
+        let group_len = self.params.group_len();
+        let group = ((start - stripe_start) / group_len) as usize;
+        if group < self.stripe_width {
+            stripe_start + ((index * self.stripe_width + group) * frame_len) as u64
+        } else {
+            start + (index * frame_len) as u64
+        }
+    }
+
+    fn read_frames(&mut self) -> io::Result<usize> {
+        if self.stripe_groups == 1 {
+            let read = read_full(&mut self.inner, &mut self.frames)?;
+            self.physical += read as u64;
+            return Ok(read);
+        }
+        let full = self.stripe_groups as u64 * self.params.group_len();
+        // A stripe cut short is the last one.
+        if self.stripe_pos == self.stripe.len()
+            && self.stripe_start.is_none_or(|_| self.stripe_read == full)
+        {
+            self.read_stripe(self.consumed)?;
+        }
+        let read = self.frames.len().min(self.stripe.len() - self.stripe_pos);
+        self.frames[..read].copy_from_slice(&self.stripe[self.stripe_pos..self.stripe_pos + read]);
+        self.stripe_pos += read;
+        Ok(read)
+    }
+
+    /// Reads the stripe that starts `start` bytes after the file header and puts its frames
+    /// back in order, one group after the other. Frames lost to a cut in the interleaved
+    /// part read as zeros, which their CRCs reject.
+    fn read_stripe(&mut self, start: u64) -> io::Result<()> {
+        let group_len = self.params.group_len() as usize;
+        let full = self.stripe_groups * group_len;
+        let mut raw = vec![0u8; full];
+        let read = read_full(&mut self.inner, &mut raw)?;
+        self.physical += read as u64;
+        raw.truncate(read);
+        let groups = if read == full {
+            self.stripe_groups
+        } else {
+            self.interleaved_groups(&raw, start / group_len as u64)
+        };
+        let mut whole = raw[..(groups * group_len).min(read)].to_vec();
+        whole.resize(groups * group_len, 0);
+        let frame_len = FRAME_HEADER_LEN + self.params.shard_size as usize;
+        self.stripe = interleave(&whole, groups, self.params.total_shards(), frame_len, true);
+        self.stripe
+            .extend_from_slice(raw.get(groups * group_len..).unwrap_or_default());
+        self.stripe_start = Some(start);
+        self.stripe_read = read as u64;
+        self.stripe_width = groups;
+        self.stripe_pos = 0;
+        Ok(())
+    }
+
+    /// The number of whole groups interleaved in the last stripe, whose first group is
+    /// `first_group`, from `raw`, all of it that is left.
+    ///
+    /// Frame `index` of group `first_group + group` sits in slot `index * groups + group`,
+    /// so any intact frame of an index past 0 tells; failing that, the groups that fit.
+    fn interleaved_groups(&self, raw: &[u8], first_group: u64) -> usize {
+        let frame_len = FRAME_HEADER_LEN + self.params.shard_size as usize;
+        let stated = raw
+            .chunks_exact(frame_len)
+            .enumerate()
+            .find_map(|(slot, frame)| {
+                let header = FrameHeader::decode(frame)?;
+                let group = header.group.checked_sub(first_group)? as usize;
+                let index = header.index as usize;
+                let groups = (index > 0 && slot >= group && (slot - group).is_multiple_of(index))
+                    .then(|| (slot - group) / index)?;
+                (group < groups && groups <= self.stripe_groups).then_some(groups)
+            });
+        stated.unwrap_or(raw.len() / self.params.group_len() as usize)
+    }
+
+    /// Returns the frame length of a group that ends after `read` bytes, taken from the
+    /// shard size of its first intact frame, or `None` if no frame of the group is intact.
+    fn short_frame_len(&self, read: usize) -> Option<usize> {
+        let frames = &self.frames[..read];
+        let max_len = FRAME_HEADER_LEN + self.params.shard_size as usize;
+        // An untruncated last group divides evenly; otherwise every frame header found in
+        // the group states a candidate length, which its CRC confirms.
+        let even = read
+            .is_multiple_of(self.params.total_shards())
+            .then(|| read / self.params.total_shards());
+        let stated = (0..read.saturating_sub(FRAME_HEADER_LEN))
+            .filter(|&offset| frames[offset..].starts_with(SHARD_MAGIC))
+            .map(|offset| {
+                FRAME_HEADER_LEN
+                    + u32::from_le_bytes(frames[offset + 16..offset + 20].try_into().unwrap())
+                        as usize
+            });
+        even.into_iter()
+            .chain(stated)
+            .filter(|&len| len > FRAME_HEADER_LEN && len <= max_len)
+            .find(|&len| {
+                frames.chunks_exact(len).enumerate().any(|(index, frame)| {
+                    FrameHeader::decode(frame).is_some_and(|h| {
+                        h.group == self.group
+                            && h.index as usize == index
+                            && h.params.shard_size as usize + FRAME_HEADER_LEN == len
+                    })
+                })
+            })
+    }
+}
+
+impl<R: Read> Read for FecReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.output.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.read_group()?;
+        }
+        let n = buf.len().min(self.output.len() - self.position);
+        buf[..n].copy_from_slice(&self.output[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> FecReader<R> {
+    /// The payload offset of the next byte [`Read::read`] returns.
+    fn payload_position(&self) -> u64 {
+        match self.group {
+            0 => 0,
+            group => (group - 1) * self.params.group_capacity() as u64 + self.position as u64,
+        }
+    }
+
+    /// The size of a full group on disk.
+    fn group_len(&self) -> u64 {
+        self.params.group_len()
+    }
+
+    /// Moves the inner reader to the start of `group` and reads, checks and repairs it.
+    fn load_group(&mut self, group: u64) -> io::Result<()> {
+        let current = self
+            .group
+            .checked_sub(1)
+            .map(|loaded| (loaded, std::mem::take(&mut self.output), self.finished));
+        if let Some((_, output, finished)) = self.previous.take_if(|(loaded, ..)| *loaded == group)
+        {
+            // Reading on continues with the group after it.
+            self.move_to((group + 1) * self.group_len())?;
+            self.previous = current;
+            self.output = output;
+            self.finished = finished;
+            self.group = group + 1;
+            return Ok(());
+        }
+        self.previous = current;
+
+        self.move_to(group * self.group_len())?;
+        self.group = group;
+        self.finished = false;
+        self.read_group()
+    }
+
+    /// Moves to `offset` bytes after the start of the first group, in the order of the
+    /// sequential layout.
+    fn move_to(&mut self, offset: u64) -> io::Result<()> {
+        if self.stripe_groups > 1 {
+            // The stripe holding `offset` is read whole, unless it is read already.
+            let stripe_len = self.stripe_groups as u64 * self.params.group_len();
+            let start = offset - offset % stripe_len;
+            if self.stripe_start != Some(start) {
+                self.seek_inner(start)?;
+                self.read_stripe(start)?;
+            }
+            self.stripe_pos = ((offset - start) as usize).min(self.stripe.len());
+        } else {
+            self.seek_inner(offset)?;
+        }
+        self.consumed = offset;
+        Ok(())
+    }
+
+    /// Moves the inner reader to `offset` bytes after the start of the first group.
+    fn seek_inner(&mut self, offset: u64) -> io::Result<()> {
+        let (replay, inner) = self.inner.get_mut();
+        // Bytes of a damaged header's first frames that were read ahead but not replayed yet.
+        let ahead = replay.get_ref().len() as u64 - replay.position();
+        replay.set_position(replay.get_ref().len() as u64);
+        inner.seek(SeekFrom::Current(
+            offset as i64 - self.physical as i64 - ahead as i64,
+        ))?;
+        self.physical = offset;
+        Ok(())
+    }
+
+    /// The length of the payload, which is only known once the last group was read.
+    fn payload_len(&mut self) -> io::Result<u64> {
+        if let Some(len) = self.len {
+            return Ok(len);
+        }
+        let (replay, inner) = self.inner.get_mut();
+        let ahead = replay.get_ref().len() as u64 - replay.position();
+        let current = inner.stream_position()?;
+        let end = inner.seek(SeekFrom::End(0))?;
+        inner.seek(SeekFrom::Start(current))?;
+        let groups_len = end - (current - ahead - self.physical);
+        let last = groups_len.div_ceil(self.group_len()).saturating_sub(1);
+
+        let position = self.payload_position();
+        self.load_group(last)?;
+        let len = last * self.params.group_capacity() as u64 + self.output.len() as u64;
+        debug!("Payload is {len} bytes in {} groups.", last + 1);
+        self.len = Some(len);
+        self.seek(SeekFrom::Start(position))?;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for FecReader<R> {
+    /// Seeks within the payload, reading only the group that holds the new position.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.payload_position().checked_add_signed(offset),
+            SeekFrom::End(offset) => self.payload_len()?.checked_add_signed(offset),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        let capacity = self.params.group_capacity() as u64;
+        // Past the end, the reader stays at the end of the last group.
+        let (group, offset) = match self.len {
+            Some(len) if target >= len => (len.saturating_sub(1) / capacity, usize::MAX),
+            _ => (target / capacity, (target % capacity) as usize),
+        };
+        if self.group != group + 1 {
+            self.load_group(group)?;
+        }
+        self.position = offset.min(self.output.len());
+        Ok(target)
+    }
+}
+
+/// Works out whether an archive whose header is damaged is interleaved, and how many groups
+/// its stripes hold, from its first frames, which follow `replay` in `inner`; the frames
+/// read are added to `replay`.
+///
+/// An interleaved stripe starts with frame 0 of each of its groups in turn, and the first
+/// intact frame of another index tells how many there are.
+fn detect_stripe<R: Read>(
+    inner: &mut R,
+    replay: &mut Vec<u8>,
+    params: FecParams,
+) -> io::Result<Option<u16>> {
+    let frame_len = FRAME_HEADER_LEN + params.shard_size as usize;
+    let total = params.total_shards();
+    let mut interleaved = false;
+    for slot in 1..=MAX_STRIPE_GROUPS as usize {
+        let end = (slot + 1) * frame_len;
+        if replay.len() < end {
+            let start = replay.len();
+            replay.resize(end, 0);
+            let read = read_full(inner, &mut replay[start..])?;
+            replay.truncate(start + read);
+            if read < end - start {
+                // The archive ends within its first stripe, so any stripe longer than what
+                // is left reads it.
+                let groups = (replay.len() / frame_len).div_ceil(total) + 1;
+                return Ok(
+                    interleaved.then_some(groups.clamp(2, MAX_STRIPE_GROUPS as usize) as u16)
+                );
+            }
+        }
+        let Some(frame) = FrameHeader::decode(&replay[slot * frame_len..end]) else {
+            continue;
+        };
+        let (group, index) = (frame.group as usize, frame.index as usize);
+        if group * total + index == slot {
+            return Ok(None);
+        } else if index == 0 && group == slot {
+            interleaved = true;
+        } else if index > 0 && slot >= group && (slot - group).is_multiple_of(index) {
+            let groups = (slot - group) / index;
+            warn!(
+                "Archive header is damaged; its frames are interleaved in stripes of {groups} groups."
+            );
+            return Ok(Some(groups as u16).filter(|&groups| groups > 1));
+        }
+    }
+    Ok(interleaved.then_some(MAX_STRIPE_GROUPS))
+}
+
+/// Rebuilds a clean error-corrected stream from a damaged one using its parity data.
+///
+/// Only the error correction layer is decoded and re-encoded with the same geometry, so no
+/// identities are needed and the encrypted payload is copied unchanged.
+pub fn repair<R: Read, W: Write>(input: R, output: W) -> io::Result<(FecStats, W)> {
+    let mut reader = FecReader::new(input)?;
+    let mut writer = FecWriter::new(output, reader.header())?;
+    let bytes = io::copy(&mut reader, &mut writer)?;
+    debug!("Re-encoded {} bytes of payload.", bytes);
+    Ok((reader.stats(), writer.finish()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARAMS: FecParams = FecParams {
+        data_shards: 4,
+        parity_shards: 2,
+        shard_size: 1024,
+    };
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    fn protect(payload: &[u8]) -> Vec<u8> {
+        protect_with(FileHeader::new(PARAMS), payload)
+    }
+
+    fn protect_with(header: FileHeader, payload: &[u8]) -> Vec<u8> {
+        let mut writer = FecWriter::new(Vec::new(), header).unwrap();
+        writer.write_all(payload).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn read_back(archive: &[u8]) -> io::Result<(Vec<u8>, FecStats)> {
+        let mut reader = FecReader::new(archive)?;
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output)?;
+        Ok((output, reader.stats()))
+    }
+
+    #[test]
+    fn round_trips() {
+        let data = payload(10_000);
+        let (output, stats) = read_back(&protect(&data)).unwrap();
+        assert_eq!(output, data);
+        assert_eq!(stats.repaired_shards, 0);
+    }
+
+    #[test]
+    fn resumes_from_a_checkpoint_as_if_never_stopped() {
+        let data = payload(30_000);
+        for stripe_groups in [None, Some(3)] {
+            let header = FileHeader {
+                stripe_groups,
+                ..FileHeader::new(PARAMS)
+            };
+            let whole = protect_with(header, &data);
+
+            let mut writer = FecWriter::new(Vec::new(), header).unwrap();
+            writer.write_all(&data[..13_000]).unwrap();
+            let checkpoint = writer.checkpoint().unwrap();
+            let written = writer.get_mut().clone();
+            let mut resumed = FecWriter::resume(written, checkpoint).unwrap();
+            resumed.write_all(&data[13_000..]).unwrap();
+            assert_eq!(resumed.finish().unwrap(), whole);
+        }
+    }
+
+    #[test]
+    fn seeks_to_any_payload_offset() {
+        let data = payload(10_000);
+        let mut reader = FecReader::new(Cursor::new(protect(&data))).unwrap();
+        for pos in [
+            SeekFrom::Start(5_000),
+            SeekFrom::Current(-4_500),
+            SeekFrom::End(-100),
+            SeekFrom::Start(4_096),
+        ] {
+            let offset = reader.seek(pos).unwrap() as usize;
+            let mut buf = [0u8; 50];
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(buf[..], data[offset..offset + 50]);
+        }
+        assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), data.len() as u64);
+        assert_eq!(reader.read(&mut [0u8; 10]).unwrap(), 0);
+    }
+
+    #[test]
+    fn repairs_a_truncated_tail() {
+        let data = payload(10_000);
+        let archive = protect(&data);
+        // The last group holds 1808 bytes in 6 frames of 28 + 452; cut into its last two.
+        for cut in [1, 500, 900] {
+            let (output, stats) = read_back(&archive[..archive.len() - cut]).unwrap();
+            assert_eq!(output, data, "cut {cut}");
+            assert!(stats.repaired_shards > 0);
+        }
+    }
+
+    #[test]
+    fn counts_the_spare_shards_of_the_most_damaged_group() {
+        let data = payload(10_000);
+        let mut archive = protect(&data);
+        let (_, stats) = read_back(&archive).unwrap();
+        assert_eq!(stats.spare_shards(PARAMS), 2);
+
+        // Damage one shard of group 0 and two of group 1.
+        let frame = FRAME_HEADER_LEN + PARAMS.shard_size as usize;
+        let group = PARAMS.total_shards() * frame;
+        for offset in [0, group, group + 2 * frame] {
+            archive[FILE_HEADER_LEN + offset + FRAME_HEADER_LEN] ^= 0xff;
+        }
+        let (output, stats) = read_back(&archive).unwrap();
+        assert_eq!(output, data);
+        assert_eq!(stats.most_damaged_shards, 2);
+        assert_eq!(stats.most_damaged_group, 1);
+        assert_eq!(stats.spare_shards(PARAMS), 0);
+    }
+
+    #[test]
+    fn logs_where_it_repaired() {
+        let data = payload(10_000);
+        let mut archive = protect(&data);
+        let frame = FRAME_HEADER_LEN + PARAMS.shard_size as usize;
+        let group = PARAMS.total_shards() * frame;
+        archive[FILE_HEADER_LEN + group + FRAME_HEADER_LEN] ^= 0xff;
+        let repairs = RepairLog::default();
+        let mut reader = FecReader::new(archive.as_slice()).unwrap();
+        reader.log_repairs(repairs.clone());
+        reader.read_to_end(&mut Vec::new()).unwrap();
+        repairs.attribute("notes.txt");
+        assert_eq!(
+            repairs.take(),
+            [Repair {
+                group: 1,
+                header: false,
+                shards: 1,
+                offset: (FILE_HEADER_LEN + group) as u64,
+                len: group as u64,
+                ranges: vec![ShardRange {
+                    index: 0,
+                    offset: (FILE_HEADER_LEN + group) as u64,
+                    len: frame as u64,
+                }],
+                entry: Some("notes.txt".into()),
+            }]
+        );
+        assert!(repairs.take().is_empty());
+    }
+
+    #[test]
+    fn reports_a_tail_cut_beyond_repair() {
+        let archive = protect(&payload(10_000));
+        let error = read_back(&archive[..archive.len() - 1500]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reads_headers_without_a_content_hash() {
+        let header = FileHeader {
+            content_hash: Some([7; 32]),
+            unencrypted: true,
+            ..FileHeader::new(PARAMS)
+        };
+        assert_eq!(FileHeader::decode(&header.encode()), Some(header));
+
+        // The first version 3 headers end before the content hash.
+        let mut short = header.encode()[..FILE_HEADER_LEN_V3].to_vec();
+        short[18] = FILE_HEADER_LEN_V3 as u8;
+        let crc = crc32fast::hash(&short[..FILE_HEADER_LEN_V3 - 4]);
+        short[FILE_HEADER_LEN_V3 - 4..].copy_from_slice(&crc.to_le_bytes());
+        let decoded = FileHeader::decode(&short).unwrap();
+        assert_eq!(decoded.content_hash, None);
+        assert_eq!(decoded.created, header.created);
+    }
+
+    #[test]
+    fn interleaved_stripes_survive_a_burst_that_sinks_one_group() {
+        let data = payload(40_000);
+        let interleaved = protect_with(
+            FileHeader {
+                stripe_groups: Some(4),
+                ..FileHeader::new(PARAMS)
+            },
+            &data,
+        );
+        let sequential = protect(&data);
+        assert_eq!(interleaved.len(), sequential.len());
+        assert_eq!(interleaved[8], VERSION);
+        assert_eq!(sequential[8], SEQUENTIAL_VERSION);
+
+        // Three frames in a row, more than the two parity shards of a group.
+        let frame = FRAME_HEADER_LEN + PARAMS.shard_size as usize;
+        let burst = FILE_HEADER_LEN + frame..FILE_HEADER_LEN + 4 * frame;
+        let mut damaged = sequential.clone();
+        damaged[burst.clone()].fill(0);
+        assert!(read_back(&damaged).is_err());
+        let mut damaged = interleaved.clone();
+        damaged[burst].fill(0);
+        let (output, stats) = read_back(&damaged).unwrap();
+        assert_eq!(output, data);
+        assert_eq!((stats.repaired_groups, stats.most_damaged_shards), (3, 1));
+
+        // The last stripe holds two whole groups and the smaller last one; cut into it.
+        let (output, _) = read_back(&interleaved[..interleaved.len() - 400]).unwrap();
+        assert_eq!(output, data);
+
+        let mut reader = FecReader::new(Cursor::new(&interleaved)).unwrap();
+        for offset in [30_000, 100, 39_990, 16_384] {
+            reader.seek(SeekFrom::Start(offset)).unwrap();
+            let mut buf = [0u8; 10];
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(buf[..], data[offset as usize..offset as usize + 10]);
+        }
+        assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), data.len() as u64);
+
+        // Without the header, the stripe is told from the order of the first frames.
+        let mut headless = interleaved.clone();
+        headless[..FILE_HEADER_LEN].fill(0);
+        let mut reader = FecReader::new(headless.as_slice()).unwrap();
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn logs_each_damaged_shard_and_a_rebuilt_header() {
+        let data = payload(40_000);
+        let mut archive = protect_with(
+            FileHeader {
+                stripe_groups: Some(4),
+                ..FileHeader::new(PARAMS)
+            },
+            &data,
+        );
+        // Frame 0 of groups 1 to 3 sits in slots 1 to 3 of the first stripe.
+        let frame = FRAME_HEADER_LEN + PARAMS.shard_size as usize;
+        archive[FILE_HEADER_LEN + frame..FILE_HEADER_LEN + 4 * frame].fill(0);
+        archive[..FILE_HEADER_LEN].fill(0);
+        let repairs = RepairLog::default();
+        let mut reader = FecReader::new(archive.as_slice()).unwrap();
+        reader.log_repairs(repairs.clone());
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, data);
+
+        let repairs = repairs.take();
+        assert!(repairs[0].header);
+        assert_eq!(
+            (repairs[0].offset, repairs[0].len),
+            (0, FILE_HEADER_LEN as u64)
+        );
+        let ranges: Vec<_> = repairs[1..]
+            .iter()
+            .map(|repair| (repair.group, repair.ranges.clone()))
+            .collect();
+        let range = |slot: usize| ShardRange {
+            index: 0,
+            offset: (FILE_HEADER_LEN + slot * frame) as u64,
+            len: frame as u64,
+        };
+        assert_eq!(
+            ranges,
+            [
+                (1, vec![range(1)]),
+                (2, vec![range(2)]),
+                (3, vec![range(3)])
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_shards_before_allocating() {
+        let params = FecParams {
+            shard_size: u32::MAX,
+            ..PARAMS
+        };
+        assert!(params.validate().is_err());
+        let mut archive = FileHeader::new(params).encode().to_vec();
+        archive.extend_from_slice(&[0; 64]);
+        let error = FecReader::new(archive.as_slice()).err().unwrap();
+        assert!(error.to_string().contains("exceeds the maximum"));
+    }
+}
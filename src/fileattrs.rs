@@ -0,0 +1,239 @@
+//! Windows file attributes and creation times, stored as PAX records.
+//!
+//! On Windows, protect stores the read-only, hidden and system attributes of each entry in a
+//! `SCHILY.fflags` record, with the flag names bsdtar uses, and its creation time in a
+//! `LIBARCHIVE.creationtime` record. Recover on Windows sets them again once the entry is
+//! written. Elsewhere nothing is stored and the records are ignored, so archives travel
+//! between platforms either way.
+
+use crate::tar_format::{self, Extensions};
+use std::fs::Metadata;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// The PAX record of file flags.
+const PAX_FFLAGS: &[u8] = b"SCHILY.fflags";
+/// The PAX record of the creation time.
+const PAX_CREATION_TIME: &[u8] = b"LIBARCHIVE.creationtime";
+
+const FILE_ATTRIBUTE_READONLY: u32 = 0x1;
+const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+const FILE_ATTRIBUTE_SYSTEM: u32 = 0x4;
+
+/// The attributes that are stored, by flag name.
+const FLAGS: [(&str, u32); 3] = [
+    ("rdonly", FILE_ATTRIBUTE_READONLY),
+    ("hidden", FILE_ATTRIBUTE_HIDDEN),
+    ("system", FILE_ATTRIBUTE_SYSTEM),
+];
+
+/// The attributes and creation time stored for an entry.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Attributes {
+    /// `FILE_ATTRIBUTE_*` bits among those in [`FLAGS`].
+    pub flags: u32,
+    pub created: Option<SystemTime>,
+}
+
+impl Attributes {
+    /// Reads the attributes in `metadata` if `flags` is set, and the creation time if `times`
+    /// is.
+    #[cfg(windows)]
+    pub fn of(metadata: &Metadata, flags: bool, times: bool) -> Self {
+        use std::os::windows::fs::MetadataExt;
+
+        let mask = FLAGS.iter().fold(0, |mask, (_, bit)| mask | bit);
+        Self {
+            flags: if flags {
+                metadata.file_attributes() & mask
+            } else {
+                0
+            },
+            created: times.then(|| metadata.created().ok()).flatten(),
+        }
+    }
+
+    /// Reads the attributes in `metadata`: none on this platform.
+    #[cfg(not(windows))]
+    pub fn of(_metadata: &Metadata, _flags: bool, _times: bool) -> Self {
+        Self::default()
+    }
+
+    /// Adds the PAX records of these attributes to `extensions`.
+    pub fn push_records(&self, extensions: &mut Extensions) {
+        if self.flags != 0 {
+            extensions.push(PAX_FFLAGS, format_flags(self.flags).as_bytes());
+        }
+        if let Some(created) = self.created {
+            extensions.push_time(PAX_CREATION_TIME, created);
+        }
+    }
+
+    /// Returns the attributes stored for `entry`.
+    pub fn from_entry<R: Read>(entry: &mut tar::Entry<R>) -> io::Result<Self> {
+        let mut flags = 0;
+        if let Some(extensions) = entry.pax_extensions()? {
+            for extension in extensions.flatten() {
+                if extension.key_bytes() == PAX_FFLAGS {
+                    flags = parse_flags(&String::from_utf8_lossy(extension.value_bytes()));
+                }
+            }
+        }
+        Ok(Self {
+            flags,
+            created: tar_format::time(entry, PAX_CREATION_TIME)?,
+        })
+    }
+
+    /// Sets these attributes on `path`, keeping the ones it has already.
+    #[cfg(windows)]
+    pub fn apply(&self, path: &Path) -> io::Result<()> {
+        use std::os::windows::fs::{FileTimesExt, MetadataExt};
+
+        if let Some(created) = self.created {
+            open_attributes(path)?.set_times(std::fs::FileTimes::new().set_created(created))?;
+        }
+        if self.flags == 0 {
+            return Ok(());
+        }
+        const FILE_ATTRIBUTE_ARCHIVE: u32 = 0x20;
+        const FILE_ATTRIBUTE_TEMPORARY: u32 = 0x100;
+        const FILE_ATTRIBUTE_OFFLINE: u32 = 0x1000;
+        const FILE_ATTRIBUTE_NOT_CONTENT_INDEXED: u32 = 0x2000;
+        // Only these can be set, besides the stored ones.
+        let settable = FILE_ATTRIBUTE_ARCHIVE
+            | FILE_ATTRIBUTE_TEMPORARY
+            | FILE_ATTRIBUTE_OFFLINE
+            | FILE_ATTRIBUTE_NOT_CONTENT_INDEXED;
+        let current = std::fs::symlink_metadata(path)?.file_attributes();
+        let name = wide(path)?;
+        // SAFETY: `name` is a NUL-terminated UTF-16 string that outlives the call.
+        if unsafe { SetFileAttributesW(name.as_ptr(), (current & settable) | self.flags) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Sets these attributes on `path`: never, on this platform.
+    #[cfg(not(windows))]
+    pub fn apply(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+#[link(name = "kernel32")]
+unsafe extern "system" {
+    fn SetFileAttributesW(name: *const u16, attributes: u32) -> i32;
+}
+
+/// Opens `path`, a file or a directory, for changing its times and nothing else, which
+/// read-only files allow too.
+#[cfg(windows)]
+pub(crate) fn open_attributes(path: &Path) -> io::Result<std::fs::File> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    const FILE_WRITE_ATTRIBUTES: u32 = 0x100;
+    // Needed to open directories.
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+    std::fs::OpenOptions::new()
+        .access_mode(FILE_WRITE_ATTRIBUTES)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(path)
+}
+
+/// `path` as a NUL-terminated wide string for the Windows API, made absolute with the `\\?\`
+/// prefix, which lifts the limit of 260 characters. The standard library does the same for
+/// its own calls.
+#[cfg(windows)]
+fn wide(path: &Path) -> io::Result<Vec<u16>> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::{Component, Prefix};
+
+    let path = std::path::absolute(path)?;
+    let mut wide = Vec::new();
+    match path.components().next() {
+        Some(Component::Prefix(prefix))
+            if prefix.kind().is_verbatim() || matches!(prefix.kind(), Prefix::DeviceNS(_)) =>
+        {
+            wide.extend(path.as_os_str().encode_wide());
+        }
+        Some(Component::Prefix(prefix)) if matches!(prefix.kind(), Prefix::UNC(..)) => {
+            // `\\server\share` becomes `\\?\UNC\server\share`.
+            wide.extend(r"\\?\UNC".encode_utf16());
+            wide.extend(path.as_os_str().encode_wide().skip(1));
+        }
+        _ => {
+            wide.extend(r"\\?\".encode_utf16());
+            wide.extend(path.as_os_str().encode_wide());
+        }
+    }
+    wide.push(0);
+    Ok(wide)
+}
+
+/// The flag names of the attribute bits `flags`, comma-separated.
+fn format_flags(flags: u32) -> String {
+    let names: Vec<_> = FLAGS
+        .iter()
+        .filter(|(_, bit)| flags & bit != 0)
+        .map(|(name, _)| *name)
+        .collect();
+    names.join(",")
+}
+
+/// The attribute bits named in `value`. Flags of other platforms, such as BSD's `uchg`, are
+/// ignored.
+fn parse_flags(value: &str) -> u32 {
+    value
+        .split(',')
+        .filter_map(|name| match name.trim() {
+            "readonly" => Some(FILE_ATTRIBUTE_READONLY),
+            name => FLAGS.iter().find(|(n, _)| *n == name).map(|(_, bit)| *bit),
+        })
+        .fold(0, |flags, bit| flags | bit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn stores_flags_and_creation_times_in_pax_records() {
+        assert_eq!(
+            format_flags(FILE_ATTRIBUTE_READONLY | FILE_ATTRIBUTE_HIDDEN),
+            "rdonly,hidden"
+        );
+        assert_eq!(parse_flags("hidden, readonly,uchg"), 0x3);
+
+        let attributes = Attributes {
+            flags: FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_SYSTEM,
+            created: Some(UNIX_EPOCH + Duration::new(1_600_000_000, 5)),
+        };
+        let mut extensions = Extensions::default();
+        attributes.push_records(&mut extensions);
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_ustar();
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_size(0);
+        tar_format::TarFormat::Pax
+            .append(
+                &mut builder,
+                &mut header,
+                extensions,
+                Path::new("desktop.ini"),
+                None,
+                io::empty(),
+            )
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let mut archive = tar::Archive::new(archive.as_slice());
+        let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(Attributes::from_entry(&mut entry).unwrap(), attributes);
+    }
+}
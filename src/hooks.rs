@@ -0,0 +1,80 @@
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use std::path::Path;
+use std::process::{Command, ExitStatus};
+
+/// Placeholder in a post-command that is replaced with the quoted output path.
+pub const OUTPUT_PLACEHOLDER: &str = "{}";
+
+/// Runs `command` through the platform shell after a successful protect.
+///
+/// Every `{}` in the command is replaced with the shell-quoted output path, which is also
+/// exported as `SAGE_OUTPUT`. A placeholder the user quoted already, as in `cp "{}" /mnt`,
+/// is replaced together with its quotes, so the path does not end up quoted twice. The
+/// command's stdout and stderr are captured and logged.
+pub fn run_post_command(command: &str, output_path: &Path) -> Result<ExitStatus> {
+    let quoted = shell_quote(output_path);
+    let command = ["\"{}\"", "'{}'", OUTPUT_PLACEHOLDER]
+        .iter()
+        .fold(command.to_string(), |command, placeholder| {
+            command.replace(placeholder, &quoted)
+        });
+    info!("Running post-command: {command}");
+
+    let output = shell(&command)
+        .env("SAGE_OUTPUT", output_path)
+        .output()
+        .with_context(|| format!("Failed to run post-command: {command}"))?;
+
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        info!("[post-command] {line}");
+    }
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        warn!("[post-command] {line}");
+    }
+    debug!("Post-command exited with {}", output.status);
+
+    Ok(output.status)
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+#[cfg(unix)]
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', r"'\''"))
+}
+
+#[cfg(not(unix))]
+fn shell_quote(path: &Path) -> String {
+    format!("\"{}\"", path.to_string_lossy())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes_the_path_whether_or_not_the_placeholder_is_quoted() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("it's a backup.sage");
+        let received = dir.path().join("received");
+        for placeholder in ["{}", "\"{}\"", "'{}'"] {
+            let command = format!("printf '%s' {placeholder} > '{}'", received.display());
+            assert!(run_post_command(&command, &output).unwrap().success());
+            let path = std::fs::read_to_string(&received).unwrap();
+            assert_eq!(path, output.to_string_lossy(), "{placeholder}");
+        }
+    }
+}
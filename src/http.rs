@@ -0,0 +1,276 @@
+//! Reading archives from HTTP and HTTPS URLs.
+//!
+//! An input of the form `https://host/path/archive.sage` is read with a single streaming
+//! `GET`, so archives published on a web server, or shared with a presigned S3 URL, are
+//! recovered without being downloaded to a temporary file first. If the connection drops
+//! part way, the download resumes where it stopped with a `Range` request.
+//!
+//! Servers that accept ranges also let the file be read with seeks, each of which starts a
+//! new ranged `GET`. Archives written with `--seekable` are then read like local files:
+//! only the error correction groups and age chunks of the frames that are needed are
+//! downloaded, as the seek table and the fixed chunk size say where they are.
+
+use log::{debug, warn};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use ureq::http::{Request, Response};
+use ureq::{Agent, Body};
+
+/// Prefixes of paths that name a file on a web server.
+pub const HTTP_SCHEMES: [&str; 2] = ["http://", "https://"];
+
+/// How often a failed request is tried before giving up.
+const ATTEMPTS: u32 = 4;
+/// Longest distance a seek forward skips by reading and discarding, rather than with a new
+/// request.
+const SKIP_LIMIT: u64 = 1024 * 1024;
+
+/// Returns true if `path` is an `http://` or `https://` URL rather than a local file.
+pub fn is_http_url(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|path| HTTP_SCHEMES.iter().any(|scheme| path.starts_with(scheme)))
+}
+
+/// Returns `url` without its query, which holds the signature of a presigned URL.
+pub fn redact(url: &str) -> &str {
+    url.split_once('?').map_or(url, |(url, _)| url)
+}
+
+/// Reads a file on a web server as a stream, resuming the download if the connection drops.
+pub struct HttpReader(RangedReader);
+
+impl HttpReader {
+    /// Starts downloading the file at `url`.
+    pub fn open(url: &str) -> io::Result<Self> {
+        let agent: Agent = Agent::config_builder()
+            .http_status_as_error(false)
+            .build()
+            .into();
+        debug!("Downloading {}", redact(url));
+        let name = redact(url).to_string();
+        let url = url.to_string();
+        let fetch = Box::new(move |offset| get(&agent, &url, offset));
+        Ok(Self(RangedReader::open(name, fetch)?))
+    }
+
+    /// The size of the file, if the server reported it.
+    pub fn size(&self) -> Option<u64> {
+        self.0.size()
+    }
+
+    /// Whether the server reported the size of the file and accepts ranges, so that seeks
+    /// do not download the file again from its start.
+    pub fn is_seekable(&self) -> bool {
+        self.0.is_seekable()
+    }
+}
+
+impl Read for HttpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Seek for HttpReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+/// Starts a `GET` of a remote file from the given byte on.
+pub(crate) type Fetch = Box<dyn Fn(u64) -> io::Result<Response<Body>> + Send>;
+
+/// Reads a remote file through `GET` requests, resuming with a `Range` request where the
+/// connection dropped, and seeking by starting another one.
+pub(crate) struct RangedReader {
+    fetch: Fetch,
+    /// The file, as it is logged.
+    name: String,
+    /// The body of the current request, if one is open.
+    inner: Option<Box<dyn Read + Send>>,
+    position: u64,
+    len: Option<u64>,
+    ranges: bool,
+}
+
+impl RangedReader {
+    /// Starts downloading the file `name` with `fetch`.
+    pub(crate) fn open(name: String, fetch: Fetch) -> io::Result<Self> {
+        let response = fetch(0)?;
+        let headers = response.headers();
+        let len = headers
+            .get("content-length")
+            .and_then(|len| len.to_str().ok()?.parse().ok());
+        let ranges = headers
+            .get("accept-ranges")
+            .is_some_and(|ranges| ranges.as_bytes().eq_ignore_ascii_case(b"bytes"));
+        debug!("{name} is {len:?} bytes; ranges accepted: {ranges}.");
+        Ok(Self {
+            fetch,
+            name,
+            inner: Some(Box::new(response.into_body().into_reader())),
+            position: 0,
+            len,
+            ranges,
+        })
+    }
+
+    pub(crate) fn size(&self) -> Option<u64> {
+        self.len
+    }
+
+    pub(crate) fn is_seekable(&self) -> bool {
+        self.len.is_some() && self.ranges
+    }
+
+    /// Starts a new request for the rest of the file.
+    fn resume(&mut self) -> io::Result<()> {
+        self.inner = None;
+        if self.len.is_some_and(|len| self.position >= len) {
+            // There is nothing left to request, and servers refuse an empty range.
+            self.inner = Some(Box::new(io::empty()));
+            return Ok(());
+        }
+        let response = (self.fetch)(self.position)?;
+        let ranged = response.status().as_u16() == 206;
+        let mut inner: Box<dyn Read + Send> = Box::new(response.into_body().into_reader());
+        if !ranged && self.position > 0 {
+            debug!(
+                "Server ignored the range; skipping the first {} bytes again.",
+                self.position
+            );
+            let skipped = io::copy(&mut (&mut inner).take(self.position), &mut io::sink())?;
+            if skipped < self.position {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("{} became shorter while it was read", self.name),
+                ));
+            }
+        }
+        self.inner = Some(inner);
+        Ok(())
+    }
+}
+
+impl Read for RangedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut attempt = 1;
+        loop {
+            let result = match &mut self.inner {
+                Some(inner) => inner.read(buf),
+                None => match self.resume() {
+                    Ok(()) => continue,
+                    Err(e) => Err(e),
+                },
+            };
+            match result {
+                Ok(n) => {
+                    self.position += n as u64;
+                    return Ok(n);
+                }
+                Err(e) if attempt < ATTEMPTS => {
+                    warn!(
+                        "Download of {} failed after {} bytes: {e}; resuming.",
+                        self.name, self.position
+                    );
+                    thread::sleep(Duration::from_secs(1 << attempt));
+                    attempt += 1;
+                    if let Err(e) = self.resume() {
+                        warn!("Resuming the download failed: {e}");
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Seek for RangedReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+            SeekFrom::End(delta) => {
+                let len = self.len.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!("The server did not report the size of {}", self.name),
+                    )
+                })?;
+                len.checked_add_signed(delta)
+            }
+        };
+        let target = target.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the file",
+            )
+        })?;
+        match target.checked_sub(self.position) {
+            Some(0) => {}
+            Some(gap) if gap <= SKIP_LIMIT && self.inner.is_some() => {
+                let skipped = io::copy(&mut self.by_ref().take(gap), &mut io::sink())?;
+                if skipped < gap {
+                    self.position = target;
+                    self.inner = Some(Box::new(io::empty()));
+                }
+            }
+            _ => {
+                debug!("Seeking {} to byte {target}.", self.name);
+                self.position = target;
+                self.inner = None;
+            }
+        }
+        Ok(target)
+    }
+}
+
+/// Requests `url` from byte `offset` on, retrying on network errors and server errors, and
+/// fails on any status other than success.
+fn get(agent: &Agent, url: &str, offset: u64) -> io::Result<Response<Body>> {
+    let mut attempt = 1;
+    loop {
+        let mut request = Request::get(url);
+        if offset > 0 {
+            request = request.header("range", format!("bytes={offset}-"));
+        }
+        let request = request.body(()).map_err(io::Error::other)?;
+        let result = agent.run(request).map_err(io::Error::other);
+        let retry = match &result {
+            Ok(response) => response.status().is_server_error(),
+            Err(_) => true,
+        };
+        if !retry || attempt == ATTEMPTS {
+            let response = result?;
+            if !response.status().is_success() {
+                return Err(status_error(&response, url));
+            }
+            return Ok(response);
+        }
+        match result {
+            Ok(response) => warn!(
+                "Downloading {} failed with {}; retrying.",
+                redact(url),
+                response.status()
+            ),
+            Err(e) => warn!("Downloading {} failed: {e}; retrying.", redact(url)),
+        }
+        thread::sleep(Duration::from_secs(1 << attempt));
+        attempt += 1;
+    }
+}
+
+fn status_error(response: &Response<Body>, url: &str) -> io::Error {
+    let status = response.status();
+    let kind = match status.as_u16() {
+        404 | 410 => io::ErrorKind::NotFound,
+        401 | 403 => io::ErrorKind::PermissionDenied,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(
+        kind,
+        format!("Downloading {} failed: {status}", redact(url)),
+    )
+}
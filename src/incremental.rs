@@ -0,0 +1,278 @@
+//! Incremental backups: a state file of what was archived, and delta archives against it.
+//!
+//! The state file records the size, modification time and permissions of every path below
+//! the protected directory. An incremental protect archives only the files that are new or
+//! differ from the state, plus every directory, and ends the tar stream with an
+//! [`INCREMENT_ENTRY`] listing the paths deleted since. Recovering the base archive and then
+//! each increment into the same directory reconstructs the latest state.
+
+use anyhow::{Context, anyhow};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path};
+use std::time::UNIX_EPOCH;
+
+/// Path of the tar entry that lists the paths deleted since the previous backup.
+pub const INCREMENT_ENTRY: &str = ".sage-increment.json";
+
+const STATE_FORMAT: &str = "sage-state";
+const INCREMENT_FORMAT: &str = "sage-increment";
+const FORMAT_VERSION: u32 = 1;
+
+/// What an incremental backup compares against to find changed files.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct State {
+    format: String,
+    version: u32,
+    /// The protected directory, as given on the command line.
+    pub root: String,
+    /// Every archived path, relative to the root.
+    pub entries: BTreeMap<String, EntryState>,
+}
+
+/// The recorded metadata of one path.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EntryState {
+    pub kind: EntryKind,
+    pub size: u64,
+    pub mtime: u64,
+    pub mtime_nsec: u32,
+    pub mode: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+impl EntryState {
+    /// Records `metadata`, or `None` for special files, which are never archived.
+    pub fn from_metadata(metadata: &fs::Metadata) -> Option<Self> {
+        let kind = if metadata.is_symlink() {
+            EntryKind::Symlink
+        } else if metadata.is_dir() {
+            EntryKind::Directory
+        } else if metadata.is_file() {
+            EntryKind::File
+        } else {
+            return None;
+        };
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        Some(Self {
+            kind,
+            size: if kind == EntryKind::File {
+                metadata.len()
+            } else {
+                0
+            },
+            mtime: mtime.as_secs(),
+            mtime_nsec: mtime.subsec_nanos(),
+            mode: mode(metadata),
+        })
+    }
+}
+
+#[cfg(unix)]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+impl State {
+    /// An empty state for `root`, against which every path is new.
+    pub fn new(root: &Path) -> Self {
+        Self {
+            format: STATE_FORMAT.to_string(),
+            version: FORMAT_VERSION,
+            root: root.to_string_lossy().into_owned(),
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Loads the state file at `path`, or returns `None` if it does not exist yet.
+    pub fn load(path: &Path) -> crate::Result<Option<Self>> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(anyhow!(e)
+                    .context(format!("Failed to read state file: {}", path.display()))
+                    .into());
+            }
+        };
+        let state: Self = serde_json::from_slice(&contents)
+            .with_context(|| format!("Failed to parse state file: {}", path.display()))?;
+        if state.format != STATE_FORMAT || state.version != FORMAT_VERSION {
+            warn!("Unsupported state file: {}", path.display());
+            return Err(anyhow!(
+                "{} is not a version {} sage state file.",
+                path.display(),
+                FORMAT_VERSION
+            )
+            .into());
+        }
+        Ok(Some(state))
+    }
+
+    /// Writes the state to `path`, replacing it only once the new state is complete.
+    pub fn save(&self, path: &Path) -> crate::Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = Path::new(&tmp_path);
+        debug!("Writing state file: {}", path.display());
+        let mut file = fs::File::create(tmp_path)
+            .with_context(|| format!("Failed to create state file: {}", tmp_path.display()))?;
+        serde_json::to_writer(&mut file, self).map_err(anyhow::Error::from)?;
+        file.write_all(b"\n")?;
+        file.sync_all()?;
+        fs::rename(tmp_path, path)
+            .with_context(|| format!("Failed to replace state file: {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Returns true if `rel_path` was recorded with exactly `entry`.
+    pub fn is_unchanged(&self, rel_path: &str, entry: &EntryState) -> bool {
+        self.entries.get(rel_path) == Some(entry)
+    }
+
+    /// The paths recorded in `self` that are missing from `next`.
+    pub fn deleted_since(&self, next: &State) -> Vec<String> {
+        self.entries
+            .keys()
+            .filter(|path| !next.entries.contains_key(*path))
+            .cloned()
+            .collect()
+    }
+}
+
+/// The contents of the [`INCREMENT_ENTRY`] of an incremental archive.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Increment {
+    format: String,
+    version: u32,
+    /// Paths deleted since the previous backup, relative to the root.
+    pub deleted: Vec<String>,
+}
+
+impl Increment {
+    pub fn new(deleted: Vec<String>) -> Self {
+        Self {
+            format: INCREMENT_FORMAT.to_string(),
+            version: FORMAT_VERSION,
+            deleted,
+        }
+    }
+
+    /// Parses an increment entry, or returns `None` for an unrelated file of the same name.
+    pub fn parse(contents: &[u8]) -> Option<Self> {
+        serde_json::from_slice::<Self>(contents)
+            .ok()
+            .filter(|i| i.format == INCREMENT_FORMAT && i.version == FORMAT_VERSION)
+    }
+
+    /// Removes the deleted paths from a directory the previous backups were recovered into.
+    ///
+    /// Paths are only removed where they lie inside `output_path`: a path that leads through
+    /// a symlink, which an archive may have extracted, is skipped.
+    pub fn apply(&self, output_path: &Path) -> crate::Result<()> {
+        let root = output_path
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve output path: {}", output_path.display()))?;
+        // Children sort after their parents; remove them first.
+        for rel_path in self.deleted.iter().rev() {
+            let rel = Path::new(rel_path);
+            if !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+                warn!("Skipping unsafe deleted path: {}", rel_path);
+                continue;
+            }
+            let path = output_path.join(rel);
+            match inside_root(&root, rel) {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!("Skipping deleted path that leads through a symlink: {rel_path}");
+                    continue;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(anyhow!(e)
+                        .context(format!("Failed to check deleted path: {}", path.display()))
+                        .into());
+                }
+            }
+            debug!("Removing deleted path: {}", path.display());
+            let result = match path.symlink_metadata() {
+                Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&path),
+                Ok(_) => fs::remove_file(&path),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => Err(e),
+            };
+            result.with_context(|| format!("Failed to remove deleted path: {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns true if no ancestor of `rel` below `root` is a symlink and its parent resolves to
+/// a directory inside `root`. Fails with [`io::ErrorKind::NotFound`] if an ancestor is missing.
+fn inside_root(root: &Path, rel: &Path) -> io::Result<bool> {
+    let mut ancestor = root.to_path_buf();
+    let parents = rel.parent().map(Path::components).into_iter().flatten();
+    for component in parents {
+        ancestor.push(component);
+        if ancestor.symlink_metadata()?.file_type().is_symlink() {
+            return Ok(false);
+        }
+    }
+    Ok(ancestor.canonicalize()?.starts_with(root))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn does_not_follow_symlinks_out_of_the_output() {
+        let outside = tempfile::tempdir().unwrap();
+        let output = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("file"), b"keep").unwrap();
+        std::os::unix::fs::symlink(outside.path(), output.path().join("evil")).unwrap();
+        fs::write(output.path().join("gone"), b"").unwrap();
+
+        let increment = Increment::new(vec!["evil/file".to_string(), "gone".to_string()]);
+        increment.apply(output.path()).unwrap();
+        assert!(outside.path().join("file").exists());
+        assert!(!output.path().join("gone").exists());
+    }
+
+    #[test]
+    fn removes_symlinks_themselves() {
+        let outside = tempfile::tempdir().unwrap();
+        let output = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), output.path().join("link")).unwrap();
+
+        Increment::new(vec!["link".to_string()])
+            .apply(output.path())
+            .unwrap();
+        assert!(output.path().join("link").symlink_metadata().is_err());
+        assert!(outside.path().exists());
+    }
+}
@@ -0,0 +1,117 @@
+//! What an archive records about itself in the clear, readable without an identity.
+//!
+//! The sage [header](crate::fec::FileHeader) of an error-corrected archive is not encrypted,
+//! and neither is the age header that follows it, which lists one stanza per recipient. This
+//! is enough to tell how an old archive was made and who can open it, before looking for
+//! the key.
+
+use crate::fec::{self, FileHeader};
+use crate::recover::header_error;
+use crate::{AGE_MAGIC, ARMOR_BEGIN_MARKER};
+use anyhow::{Context, Result, anyhow};
+use log::debug;
+use std::io::{BufRead, BufReader, Cursor, Read};
+
+/// First line of an age version 1 header.
+const AGE_V1_LINE: &[u8] = b"age-encryption.org/v1\n";
+/// The most of the payload read while looking for the end of the age header.
+const MAX_AGE_HEADER: u64 = 1024 * 1024;
+/// Suffix of the random stanzas age adds to keep parsers tolerant of unknown ones.
+const GREASE_SUFFIX: &str = "-grease";
+
+/// How an archive is stored on disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// A sage header followed by the error-corrected age payload.
+    ErrorCorrected,
+    /// ASCII-armored age text, with no sage header.
+    Armored,
+    /// A bare binary age file, as written before error correction was added and by
+    /// `protect --compat`.
+    PlainAge,
+}
+
+/// The unencrypted facts about an archive.
+#[derive(Clone, Debug)]
+pub struct ArchiveInfo {
+    pub layout: Layout,
+    /// The sage header of an error-corrected archive. Its version is 0 if it was damaged
+    /// and only the geometry could be recovered.
+    pub header: Option<FileHeader>,
+    /// The type of every recipient stanza in the age header, such as `X25519` or `scrypt`,
+    /// in order and without grease; none for an archive that is not encrypted.
+    pub stanzas: Vec<String>,
+}
+
+/// Reads the sage and age headers at the start of `input`.
+pub fn read_info<R: Read>(mut input: R) -> crate::Result<ArchiveInfo> {
+    let mut prefix = Vec::with_capacity(ARMOR_BEGIN_MARKER.len());
+    (&mut input)
+        .take(ARMOR_BEGIN_MARKER.len() as u64)
+        .read_to_end(&mut prefix)?;
+    let input = Cursor::new(prefix.clone()).chain(input);
+
+    let (layout, header, stanzas) = if prefix.starts_with(ARMOR_BEGIN_MARKER) {
+        debug!("Archive is ASCII-armored.");
+        let armored = age::armor::ArmoredReader::new(BufReader::new(input));
+        (Layout::Armored, None, read_stanza_types(armored)?)
+    } else if prefix.starts_with(AGE_MAGIC) {
+        debug!("Archive is a plain age file.");
+        (Layout::PlainAge, None, read_stanza_types(input)?)
+    } else {
+        debug!("Reading archive header.");
+        let fec_reader = fec::FecReader::new(input).map_err(header_error)?;
+        let header = fec_reader.header();
+        let stanzas = if header.unencrypted {
+            debug!("Archive is not encrypted.");
+            Vec::new()
+        } else {
+            read_stanza_types(fec_reader)?
+        };
+        (Layout::ErrorCorrected, Some(header), stanzas)
+    };
+    Ok(ArchiveInfo {
+        layout,
+        header,
+        stanzas,
+    })
+}
+
+/// Returns the stanza types of the age header at the start of `payload`.
+fn read_stanza_types<R: Read>(payload: R) -> Result<Vec<String>> {
+    let mut reader = BufReader::new(payload.take(MAX_AGE_HEADER));
+    let mut line = Vec::new();
+    reader
+        .read_until(b'\n', &mut line)
+        .context("Failed to read age header")?;
+    if line != AGE_V1_LINE {
+        return Err(anyhow!("Payload is not an age version 1 file."));
+    }
+
+    let mut stanzas = Vec::new();
+    loop {
+        line.clear();
+        if reader
+            .read_until(b'\n', &mut line)
+            .context("Failed to read age header")?
+            == 0
+        {
+            return Err(anyhow!("The age header is truncated."));
+        }
+        if line.starts_with(b"---") {
+            break;
+        }
+        if let Some(stanza) = line.strip_prefix(b"-> ") {
+            let kind = String::from_utf8_lossy(stanza)
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .to_string();
+            if !kind.ends_with(GREASE_SUFFIX) {
+                stanzas.push(kind);
+            }
+        }
+    }
+    debug!("Age header has {} recipient stanzas.", stanzas.len());
+    Ok(stanzas)
+}
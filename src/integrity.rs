@@ -0,0 +1,214 @@
+use log::error;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Size of the plaintext chunks that age's STREAM construction authenticates individually.
+pub const AGE_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Wraps the reader below age so that its errors can be told apart from age's own.
+///
+/// age passes errors from the underlying reader through unchanged, so without this marker a
+/// group that error correction could not rebuild would look like a failed chunk tag.
+pub struct SourceReader<R> {
+    inner: R,
+}
+
+impl<R: Read> SourceReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+}
+
+impl<R: Read> Read for SourceReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner
+            .read(buf)
+            .map_err(|e| io::Error::new(e.kind(), SourceError(e)))
+    }
+}
+
+impl<R: Seek> Seek for SourceReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner
+            .seek(pos)
+            .map_err(|e| io::Error::new(e.kind(), SourceError(e)))
+    }
+}
+
+/// An error from the reader below age, shown exactly like the error it wraps.
+#[derive(Debug)]
+pub(crate) struct SourceError(pub(crate) io::Error);
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for SourceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
+    }
+}
+
+/// Wraps the age payload reader to report where authentication first failed.
+///
+/// age already authenticates the payload chunk by chunk: every 64 KiB chunk carries its own
+/// Poly1305 tag and is bound to its position, so a flipped bit is rejected as soon as its
+/// chunk is decrypted rather than only at the end of the stream. The error age reports does
+/// not say where that happened, so this reader adds the failing chunk index and plaintext
+/// offset to help target repairs. Errors from a [`SourceReader`] below age are returned as
+/// they are.
+pub struct ChunkLocator<R> {
+    inner: R,
+    position: u64,
+}
+
+impl<R: Read> ChunkLocator<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, position: 0 }
+    }
+}
+
+impl<R: Read> Read for ChunkLocator<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner.read(buf) {
+            Ok(n) => {
+                self.position += n as u64;
+                Ok(n)
+            }
+            Err(e) if is_source_error(&e) => Err(unwrap_source_error(e)),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                let chunk = self.position / AGE_CHUNK_SIZE;
+                let start = chunk * AGE_CHUNK_SIZE;
+                error!(
+                    "Payload chunk {} (plaintext bytes {}..{}) failed authentication.",
+                    chunk,
+                    start,
+                    start + AGE_CHUNK_SIZE
+                );
+                Err(io::Error::new(
+                    e.kind(),
+                    format!("corrupted or tampered data in payload chunk {chunk}: {e}"),
+                ))
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<R: Seek> Seek for ChunkLocator<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = self.inner.seek(pos).map_err(|e| {
+            if is_source_error(&e) {
+                unwrap_source_error(e)
+            } else {
+                e
+            }
+        })?;
+        Ok(self.position)
+    }
+}
+
+fn is_source_error(error: &io::Error) -> bool {
+    error
+        .get_ref()
+        .is_some_and(|inner| inner.is::<SourceError>())
+}
+
+/// Returns the error a [`SourceReader`] wrapped in `error`, which must be one.
+fn unwrap_source_error(error: io::Error) -> io::Error {
+    let inner = error.into_inner().expect("checked by is_source_error");
+    inner
+        .downcast::<SourceError>()
+        .expect("checked by is_source_error")
+        .0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const CHUNKS: usize = 4;
+    const TAG_LEN: usize = 16;
+
+    fn encrypt(identity: &age::x25519::Identity, plaintext: &[u8]) -> Vec<u8> {
+        let recipient = identity.to_public();
+        let encryptor =
+            age::Encryptor::with_recipients(std::iter::once(&recipient as &dyn age::Recipient))
+                .unwrap();
+        let mut writer = encryptor.wrap_output(Vec::new()).unwrap();
+        writer.write_all(plaintext).unwrap();
+        writer.finish().unwrap()
+    }
+
+    /// Reads `reader` to its end, returning how many bytes came out before the first error.
+    fn read_until_error(mut reader: impl Read) -> (u64, Option<io::Error>) {
+        let mut buf = [0u8; 8192];
+        let mut total = 0;
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => return (total, None),
+                Ok(n) => total += n as u64,
+                Err(e) => return (total, Some(e)),
+            }
+        }
+    }
+
+    fn open(identity: &age::x25519::Identity, ciphertext: impl Read) -> ChunkLocator<impl Read> {
+        let decryptor = age::Decryptor::new(SourceReader::new(ciphertext)).unwrap();
+        ChunkLocator::new(
+            decryptor
+                .decrypt(std::iter::once(identity as &dyn age::Identity))
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn reports_the_chunk_of_a_flipped_byte() {
+        let identity = age::x25519::Identity::generate();
+        let plaintext = vec![7u8; CHUNKS * AGE_CHUNK_SIZE as usize + 100];
+        let mut ciphertext = encrypt(&identity, &plaintext);
+
+        // Chunks are laid out back to back at the end of the file, each followed by its tag.
+        let full = AGE_CHUNK_SIZE as usize + TAG_LEN;
+        let last = 100 + TAG_LEN;
+        let third_chunk = ciphertext.len() - last - (CHUNKS - 2) * full;
+        ciphertext[third_chunk + 1000] ^= 0x01;
+
+        let (read, error) = read_until_error(open(&identity, &ciphertext[..]));
+        assert_eq!(read, 2 * AGE_CHUNK_SIZE);
+        let error = error.expect("the flipped byte must be detected");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("payload chunk 2"), "{error}");
+    }
+
+    #[test]
+    fn passes_errors_from_below_age_through() {
+        struct Failing<R>(R, usize);
+        impl<R: Read> Read for Failing<R> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.1 == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "group 3 is damaged beyond repair",
+                    ));
+                }
+                let len = buf.len().min(self.1);
+                let n = self.0.read(&mut buf[..len])?;
+                self.1 -= n;
+                Ok(n)
+            }
+        }
+
+        let identity = age::x25519::Identity::generate();
+        let ciphertext = encrypt(&identity, &vec![7u8; CHUNKS * AGE_CHUNK_SIZE as usize]);
+        let source = Failing(&ciphertext[..], ciphertext.len() / 2);
+
+        let (_, error) = read_until_error(open(&identity, source));
+        let error = error.expect("the source error must be returned");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "group 3 is damaged beyond repair");
+    }
+}
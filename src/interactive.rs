@@ -0,0 +1,77 @@
+use anyhow::{Result, anyhow};
+use log::{debug, error};
+use std::fs::File;
+use std::io::{IsTerminal, Read};
+
+/// Environment variable that forces non-interactive mode when set to a truthy value.
+pub const NON_INTERACTIVE_ENV: &str = "SAGE_NON_INTERACTIVE";
+
+use sage::{AGE_MAGIC, ARMOR_BEGIN_MARKER};
+
+/// Decides whether sage may prompt the user for input.
+///
+/// Prompting is disabled when `--non-interactive` is given, when `SAGE_NON_INTERACTIVE` is
+/// set to anything other than an empty string, `0` or `false`, or when there is no terminal to
+/// prompt on. Prompts use the controlling terminal rather than standard input, so piping data
+/// into sage does not disable them.
+pub fn is_non_interactive(flag: bool) -> bool {
+    if flag {
+        return true;
+    }
+    if let Ok(value) = std::env::var(NON_INTERACTIVE_ENV) {
+        let value = value.trim().to_ascii_lowercase();
+        if !value.is_empty() && value != "0" && value != "false" {
+            debug!("{NON_INTERACTIVE_ENV} is set; running non-interactively.");
+            return true;
+        }
+    }
+    if !has_terminal() {
+        debug!("No terminal is available for prompts; running non-interactively.");
+        return true;
+    }
+    false
+}
+
+/// Returns true if standard error is a terminal or the process has a controlling terminal.
+fn has_terminal() -> bool {
+    if std::io::stderr().is_terminal() {
+        return true;
+    }
+    #[cfg(unix)]
+    {
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/tty")
+            .is_ok()
+    }
+    #[cfg(not(unix))]
+    {
+        false
+    }
+}
+
+/// Fails early if reading `identity_files` through `cli_common` would prompt for input.
+///
+/// Passphrase-protected identity files make `cli_common` ask for the passphrase through
+/// pinentry or the TTY, which blocks forever in CI. They are rejected up front instead.
+pub fn ensure_no_prompts(identity_files: &[String]) -> Result<()> {
+    for filename in identity_files.iter().filter(|f| f.as_str() != "-") {
+        let Ok(mut file) = File::open(filename) else {
+            // Missing files are reported by cli_common with a better message.
+            continue;
+        };
+        let mut prefix = [0u8; ARMOR_BEGIN_MARKER.len()];
+        let read = file.read(&mut prefix)?;
+        let prefix = &prefix[..read];
+        if prefix.starts_with(AGE_MAGIC) || prefix.starts_with(ARMOR_BEGIN_MARKER) {
+            error!("Identity file {filename} is passphrase-protected.");
+            return Err(anyhow!(
+                "Identity file {filename} is passphrase-protected and would require a prompt, \
+                 which is disabled in non-interactive mode."
+            ));
+        }
+    }
+
+    Ok(())
+}
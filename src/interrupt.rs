@@ -0,0 +1,70 @@
+//! Handling Ctrl-C and termination signals.
+//!
+//! The first signal requests [cancellation](sage::cancel): the running operation stops at its
+//! next read, removes its unfinished output and sage exits with [`EXIT_INTERRUPTED`]. A
+//! second signal, for when sage is stuck waiting, removes unfinished output files and exits
+//! at once.
+
+use log::error;
+
+/// Exit status after an interruption, as shells report for Ctrl-C.
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+/// Installs the signal handlers.
+#[cfg(unix)]
+pub fn install() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+    static JSON: AtomicBool = AtomicBool::new(false);
+    JSON.store(crate::events::enabled(), Ordering::SeqCst);
+
+    extern "C" fn handle(_signal: libc::c_int) {
+        // Everything here is async-signal-safe: atomics, `write`, `unlink` and `_exit`.
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            sage::output::remove_pending_outputs();
+            print_raw(if JSON.load(Ordering::SeqCst) {
+                b"{\"type\":\"log\",\"level\":\"error\",\"target\":\"sage\",\"message\":\"Interrupted again; exiting immediately.\"}\n"
+            } else {
+                b"\nInterrupted again; exiting immediately.\n"
+            });
+            unsafe { libc::_exit(EXIT_INTERRUPTED) };
+        }
+        sage::cancel::request();
+        print_raw(if JSON.load(Ordering::SeqCst) {
+            b"{\"type\":\"log\",\"level\":\"warn\",\"target\":\"sage\",\"message\":\"Interrupted; stopping. Press Ctrl-C again to exit immediately.\"}\n"
+        } else {
+            b"\nInterrupted; stopping. Press Ctrl-C again to exit immediately.\n"
+        });
+    }
+
+    fn print_raw(message: &[u8]) {
+        // SAFETY: `message` is a valid buffer of the given length.
+        unsafe { libc::write(libc::STDERR_FILENO, message.as_ptr().cast(), message.len()) };
+    }
+
+    for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        // SAFETY: `handle` only calls async-signal-safe functions. Without `SA_RESTART`,
+        // blocking reads return early, so the cancellation is noticed promptly.
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle as *const () as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut());
+        }
+    }
+}
+
+/// Installs the signal handlers.
+#[cfg(not(unix))]
+pub fn install() {}
+
+/// Exits with [`EXIT_INTERRUPTED`] if the operation that just failed with `e` was interrupted.
+pub fn exit_if_interrupted(e: &anyhow::Error) {
+    if sage::cancel::is_requested() {
+        error!("Interrupted before finishing.");
+        crate::events::error(e);
+        log::logger().flush();
+        std::process::exit(EXIT_INTERRUPTED);
+    }
+}
@@ -0,0 +1,141 @@
+//! Age identities kept in the keychain of the operating system.
+//!
+//! `--identity-from-keyring NAME` reads the contents of an identity file from the keychain
+//! instead of from disk, so automated backups need no plaintext key file. Entries are stored
+//! under the service `sage` and the account NAME:
+//!
+//! - on macOS, in the login keychain, read with `security find-generic-password`;
+//! - on Windows, as the generic credential `sage:NAME` of the Credential Manager;
+//! - on Linux and other Unix systems, in the Secret Service (GNOME Keyring, KWallet, KeePassXC),
+//!   read with `secret-tool` from libsecret.
+
+use age::secrecy::SecretString;
+use anyhow::anyhow;
+use log::{debug, error};
+
+/// Service that sage's keychain entries are stored under.
+pub const SERVICE: &str = "sage";
+
+/// Reads the keychain entry `name`, which holds the contents of an identity file.
+pub fn read_identity(name: &str) -> crate::Result<SecretString> {
+    debug!("Reading identity {name} from the keychain.");
+    let contents = platform::read(name)?;
+    if contents.trim().is_empty() {
+        error!("Keychain entry {name} is empty.");
+        return Err(anyhow!("The keychain entry {name} is empty.").into());
+    }
+    Ok(SecretString::from(contents))
+}
+
+/// Explains that the keychain has no entry `name`.
+fn not_found(name: &str) -> anyhow::Error {
+    error!("No keychain entry named {name}.");
+    anyhow!(
+        "No identity named {name} in the keychain; store one under the service {SERVICE} and \
+         the account {name}."
+    )
+}
+
+#[cfg(unix)]
+mod platform {
+    use super::{SERVICE, not_found};
+    use anyhow::{Context, Result, anyhow};
+    use std::io;
+    use std::process::{Command, Stdio};
+
+    /// Prints the secret of the entry, or fails if there is none.
+    #[cfg(target_os = "macos")]
+    fn lookup(name: &str) -> Command {
+        let mut command = Command::new("security");
+        command.args(["find-generic-password", "-s", SERVICE, "-a", name, "-w"]);
+        command
+    }
+
+    /// Prints the secret of the entry, or fails if there is none.
+    #[cfg(not(target_os = "macos"))]
+    fn lookup(name: &str) -> Command {
+        let mut command = Command::new("secret-tool");
+        command.args(["lookup", "service", SERVICE, "account", name]);
+        command
+    }
+
+    pub fn read(name: &str) -> Result<String> {
+        let mut command = lookup(name);
+        let program = command.get_program().to_string_lossy().into_owned();
+        let output = match command.stdin(Stdio::null()).stderr(Stdio::null()).output() {
+            Ok(output) => output,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(anyhow!(
+                    "Reading the keychain needs the {program} program, which was not found."
+                ));
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to run {program}")),
+        };
+        if !output.status.success() {
+            return Err(not_found(name));
+        }
+        String::from_utf8(output.stdout)
+            .map_err(|_| anyhow!("The keychain entry {name} is not valid UTF-8."))
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::{SERVICE, not_found};
+    use anyhow::{Result, anyhow};
+    use std::ptr;
+    use windows_sys::Win32::Security::Credentials::{
+        CRED_TYPE_GENERIC, CREDENTIALW, CredFree, CredReadW,
+    };
+
+    pub fn read(name: &str) -> Result<String> {
+        let target: Vec<u16> = format!("{SERVICE}:{name}")
+            .encode_utf16()
+            .chain(Some(0))
+            .collect();
+        let mut credential: *mut CREDENTIALW = ptr::null_mut();
+        // SAFETY: `target` is NUL-terminated, and the credential is freed below.
+        if unsafe { CredReadW(target.as_ptr(), CRED_TYPE_GENERIC, 0, &mut credential) } == 0 {
+            return Err(not_found(name));
+        }
+        // SAFETY: CredReadW succeeded, so `credential` points to a credential whose blob has
+        // `CredentialBlobSize` bytes.
+        let blob = unsafe {
+            let credential = &*credential;
+            if credential.CredentialBlob.is_null() {
+                Vec::new()
+            } else {
+                std::slice::from_raw_parts(
+                    credential.CredentialBlob,
+                    credential.CredentialBlobSize as usize,
+                )
+                .to_vec()
+            }
+        };
+        // SAFETY: `credential` was allocated by CredReadW and is not used after this.
+        unsafe { CredFree(credential.cast()) };
+        // `cmdkey` and the Credential Manager store UTF-16, other tools UTF-8.
+        let utf16 = blob.len() % 2 == 0 && blob.iter().skip(1).step_by(2).all(|&b| b == 0);
+        if utf16 && !blob.is_empty() {
+            let units: Vec<u16> = blob
+                .chunks_exact(2)
+                .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                .collect();
+            String::from_utf16(&units).ok()
+        } else {
+            String::from_utf8(blob).ok()
+        }
+        .ok_or_else(|| anyhow!("The keychain entry {name} is not valid text."))
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod platform {
+    use anyhow::{Result, anyhow};
+
+    pub fn read(_name: &str) -> Result<String> {
+        Err(anyhow!(
+            "Reading identities from the keychain is not supported on this platform."
+        ))
+    }
+}
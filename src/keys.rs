@@ -0,0 +1,589 @@
+use crate::interactive;
+use crate::progress_bar::Bars;
+use age::Callbacks;
+use age::armor::{ArmoredWriter, Format};
+use age::cli_common::{self, StdinGuard};
+use age::secrecy::{ExposeSecret, SecretString};
+use anyhow::{Context, Result, anyhow};
+use log::{debug, error, info};
+use sage::{keyring, ssh};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Maximum scrypt work factor accepted for encrypted identity files and archives.
+pub const MAX_WORK_FACTOR: u8 = 15;
+
+/// Start of the identities in an identity file that belong to a plugin.
+const PLUGIN_IDENTITY_PREFIX: &str = "AGE-PLUGIN-";
+
+/// Environment variable holding the passphrase itself.
+pub const PASSPHRASE_ENV: &str = "SAGE_PASSPHRASE";
+
+/// Environment variable naming a file whose first line is the passphrase.
+pub const PASSPHRASE_FILE_ENV: &str = "SAGE_PASSPHRASE_FILE";
+
+/// Environment variable naming an open file descriptor whose first line is the passphrase.
+pub const PASSPHRASE_FD_ENV: &str = "SAGE_PASSPHRASE_FD";
+
+/// The passphrase read from the environment, kept because a descriptor can only be read once.
+static SUPPLIED_PASSPHRASE: OnceLock<SecretString> = OnceLock::new();
+
+/// Returns the passphrase given through the environment instead of a prompt, if any.
+///
+/// `SAGE_PASSPHRASE_FD` takes precedence over `SAGE_PASSPHRASE_FILE`, which takes precedence
+/// over `SAGE_PASSPHRASE`.
+pub fn supplied_passphrase() -> Result<Option<SecretString>> {
+    if let Some(passphrase) = SUPPLIED_PASSPHRASE.get() {
+        return Ok(Some(passphrase.clone()));
+    }
+
+    let passphrase = if let Some(fd) = env_value(PASSPHRASE_FD_ENV) {
+        let Ok(fd) = fd.trim().parse::<u32>() else {
+            error!("{PASSPHRASE_FD_ENV} is not a file descriptor: {fd}");
+            return Err(anyhow!(
+                "{PASSPHRASE_FD_ENV} must be a file descriptor number, not {fd:?}."
+            ));
+        };
+        read_passphrase_fd(fd, PASSPHRASE_FD_ENV)?
+    } else if let Some(path) = env_value(PASSPHRASE_FILE_ENV) {
+        read_passphrase_file(Path::new(&path))?
+    } else if let Some(passphrase) = env_value(PASSPHRASE_ENV) {
+        debug!("Using the passphrase from {PASSPHRASE_ENV}.");
+        SecretString::from(passphrase)
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(SUPPLIED_PASSPHRASE.get_or_init(|| passphrase).clone()))
+}
+
+/// Reads the passphrase from the first line of the file at `path`.
+pub fn read_passphrase_file(path: &Path) -> Result<SecretString> {
+    debug!("Reading passphrase from {}.", path.display());
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open passphrase file {}", path.display()))?;
+    read_first_line(file, &path.display().to_string())
+}
+
+/// Reads the passphrase from the first line of the inherited file descriptor `fd`, given by
+/// `source`.
+pub fn read_passphrase_fd(fd: u32, source: &str) -> Result<SecretString> {
+    debug!("Reading passphrase from file descriptor {fd}.");
+    read_first_line(open_fd(fd, source)?, &format!("file descriptor {fd}"))
+}
+
+/// Keeps `passphrase` to use instead of prompting again, as if it came from the environment.
+pub fn remember_passphrase(passphrase: SecretString) {
+    let _ = SUPPLIED_PASSPHRASE.set(passphrase);
+}
+
+/// Returns the value of the environment variable `name`, treating an empty value as unset.
+fn env_value(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Opens an inherited file descriptor without taking ownership of it.
+#[cfg(unix)]
+fn open_fd(fd: u32, source: &str) -> Result<File> {
+    File::open(format!("/dev/fd/{fd}"))
+        .with_context(|| format!("Failed to open file descriptor {fd} from {source}"))
+}
+
+/// Opens an inherited file descriptor without taking ownership of it.
+#[cfg(not(unix))]
+fn open_fd(_fd: u32, source: &str) -> Result<File> {
+    error!("{source} is only supported on Unix.");
+    Err(anyhow!(
+        "{source} is only supported on Unix; use a passphrase file instead."
+    ))
+}
+
+/// Reads the first line of `reader` as a passphrase, without reading past it.
+fn read_first_line(mut reader: impl Read, source: &str) -> Result<SecretString> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    // One byte at a time, so a descriptor shared with other data is not read past the line.
+    while reader
+        .read(&mut byte)
+        .with_context(|| format!("Failed to read passphrase from {source}"))?
+        == 1
+        && byte[0] != b'\n'
+    {
+        line.push(byte[0]);
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    if line.is_empty() {
+        error!("The passphrase from {source} is empty.");
+        return Err(anyhow!("The passphrase read from {source} is empty."));
+    }
+    let line = String::from_utf8(line)
+        .map_err(|_| anyhow!("The passphrase read from {source} is not valid UTF-8."))?;
+    Ok(SecretString::from(line))
+}
+
+/// Shows the messages and prompts of age plugins, such as a PIN request or a reminder to
+/// touch a hardware token, around the progress bars.
+#[derive(Clone)]
+pub struct PluginUi {
+    bars: Bars,
+    non_interactive: bool,
+}
+
+impl PluginUi {
+    pub fn new(bars: &Bars, non_interactive: bool) -> Self {
+        Self {
+            bars: bars.clone(),
+            non_interactive,
+        }
+    }
+
+    /// Runs the prompt `f` with the bars hidden, or refuses it in non-interactive mode.
+    fn prompt<T>(&self, description: &str, f: impl FnOnce() -> Option<T>) -> Option<T> {
+        if self.non_interactive {
+            error!("A plugin asked for input, but prompting is disabled: {description}");
+            return None;
+        }
+        self.bars.suspend(f)
+    }
+}
+
+impl Callbacks for PluginUi {
+    fn display_message(&self, message: &str) {
+        info!("{message}");
+    }
+
+    fn confirm(&self, message: &str, yes_string: &str, no_string: Option<&str>) -> Option<bool> {
+        self.prompt(message, || {
+            cli_common::UiCallbacks.confirm(message, yes_string, no_string)
+        })
+    }
+
+    fn request_public_string(&self, description: &str) -> Option<String> {
+        self.prompt(description, || {
+            cli_common::UiCallbacks.request_public_string(description)
+        })
+    }
+
+    fn request_passphrase(&self, description: &str) -> Option<SecretString> {
+        self.prompt(description, || {
+            cli_common::UiCallbacks.request_passphrase(description)
+        })
+    }
+}
+
+/// Reads the recipients given on the command line, from recipient files and identity files.
+///
+/// SSH keys are read by [`ssh`], plugin recipients and identities are handed to their
+/// plugins with `ui` for prompts, and everything else is read by `cli_common`.
+pub fn read_recipients(
+    recipient_strings: Vec<String>,
+    recipients_file_strings: Vec<String>,
+    identity_strings: Vec<String>,
+    keyring_names: &[String],
+    ui: &PluginUi,
+) -> Result<Vec<Box<dyn age::Recipient + Send>>> {
+    let mut stdin_guard = StdinGuard::new(true);
+    let mut recipients: Vec<Box<dyn age::Recipient + Send>> = Vec::new();
+
+    let mut lines = Vec::with_capacity(recipient_strings.len());
+    lines.extend(recipient_strings.into_iter().map(|r| (r, "-r".to_string())));
+    let mut age_recipients_files = Vec::with_capacity(recipients_file_strings.len());
+    for filename in recipients_file_strings {
+        match read_recipients_file_lines(&filename) {
+            Some(file_lines) => {
+                lines.extend(file_lines.into_iter().map(|line| (line, filename.clone())));
+            }
+            None => age_recipients_files.push(filename),
+        }
+    }
+
+    let mut age_recipients = Vec::new();
+    let mut plugin_recipients = Vec::new();
+    for (line, source) in lines {
+        if ssh::is_public_key(&line) {
+            recipients.push(Box::new(parse_ssh_recipient(&line, &source)?));
+        } else if let Ok(recipient) = line.parse::<age::plugin::Recipient>() {
+            plugin_recipients.push(recipient);
+        } else {
+            age_recipients.push(line);
+        }
+    }
+    let mut names: Vec<&str> = plugin_recipients.iter().map(|r| r.plugin()).collect();
+    names.sort_unstable();
+    names.dedup();
+    for name in names {
+        debug!("Encrypting to {name} recipients through its plugin.");
+        let plugin = age::plugin::RecipientPluginV1::new(name, &plugin_recipients, &[], ui.clone())
+            .map_err(|_| missing_plugin(name))?;
+        recipients.push(Box::new(plugin));
+    }
+
+    let mut age_identities = Vec::with_capacity(identity_strings.len());
+    for filename in identity_strings {
+        if let Some(recipient) = read_ssh_identity_recipient(&filename)? {
+            recipients.push(Box::new(recipient));
+        } else if let Some(identity_file) = read_plugin_identity_file(&filename, ui)? {
+            recipients.extend(
+                identity_file
+                    .to_recipients()
+                    .map_err(|e| plugin_error(&filename, e))?,
+            );
+        } else {
+            age_identities.push(filename);
+        }
+    }
+    for name in keyring_names {
+        let identity_file = read_keyring_identity(name, ui)?;
+        recipients.extend(
+            identity_file
+                .to_recipients()
+                .map_err(|e| plugin_error(name, e))?,
+        );
+    }
+
+    if ui.non_interactive {
+        interactive::ensure_no_prompts(&age_identities)?;
+    }
+
+    let mut age = cli_common::read_recipients(
+        age_recipients,
+        age_recipients_files,
+        age_identities,
+        Some(MAX_WORK_FACTOR),
+        &mut stdin_guard,
+    )?;
+    age.extend(recipients);
+    Ok(age)
+}
+
+/// Reads the identities in the given identity files and keychain entries, showing plugin
+/// prompts with `ui`.
+pub fn read_identities(
+    identity_strings: Vec<String>,
+    keyring_names: &[String],
+    ui: &PluginUi,
+) -> Result<Vec<Box<dyn age::Identity>>> {
+    let mut stdin_guard = StdinGuard::new(true);
+    let mut identities: Vec<Box<dyn age::Identity>> = Vec::new();
+
+    let mut age_identities = Vec::with_capacity(identity_strings.len());
+    for filename in identity_strings {
+        if let Some(identity) = read_ssh_identity(&filename)? {
+            identities.push(Box::new(identity));
+        } else if let Some(identity_file) = read_plugin_identity_file(&filename, ui)? {
+            identities.extend(
+                identity_file
+                    .into_identities()
+                    .map_err(|e| plugin_error(&filename, e))?,
+            );
+        } else {
+            age_identities.push(filename);
+        }
+    }
+    for name in keyring_names {
+        let identity_file = read_keyring_identity(name, ui)?;
+        identities.extend(
+            identity_file
+                .into_identities()
+                .map_err(|e| plugin_error(name, e))?,
+        );
+    }
+
+    if ui.non_interactive {
+        interactive::ensure_no_prompts(&age_identities)?;
+    }
+
+    let mut age = if age_identities.is_empty() {
+        Vec::new()
+    } else {
+        cli_common::read_identities(age_identities, Some(MAX_WORK_FACTOR), &mut stdin_guard)?
+    };
+    age.extend(identities);
+    Ok(age)
+}
+
+/// Reads the identity file `filename` with `ui` for plugin prompts if it holds plugin
+/// identities, or returns `None` to leave it to `cli_common`.
+fn read_plugin_identity_file(
+    filename: &str,
+    ui: &PluginUi,
+) -> Result<Option<age::IdentityFile<PluginUi>>> {
+    if filename == "-" {
+        return Ok(None);
+    }
+    // Missing files are reported by cli_common with a better message, and encrypted ones need
+    // its passphrase prompt.
+    let Ok(contents) = std::fs::read_to_string(filename) else {
+        return Ok(None);
+    };
+    if !contents
+        .lines()
+        .any(|line| line.trim_start().starts_with(PLUGIN_IDENTITY_PREFIX))
+    {
+        return Ok(None);
+    }
+    debug!("Reading plugin identities from {filename}.");
+    let identity_file = age::IdentityFile::from_buffer(contents.as_bytes())
+        .with_context(|| format!("Failed to read identity file {filename}"))?;
+    Ok(Some(identity_file.with_callbacks(ui.clone())))
+}
+
+/// Reads the identity file stored in the keychain entry `name`.
+fn read_keyring_identity(name: &str, ui: &PluginUi) -> Result<age::IdentityFile<PluginUi>> {
+    let contents = keyring::read_identity(name)?;
+    let identity_file = age::IdentityFile::from_buffer(contents.expose_secret().as_bytes())
+        .map_err(|e| {
+            error!("Keychain entry {name} is not an age identity: {e}");
+            anyhow!("The keychain entry {name} does not hold an age identity: {e}")
+        })?;
+    Ok(identity_file.with_callbacks(ui.clone()))
+}
+
+/// Explains that the plugin binary for `name` keys is not installed.
+fn missing_plugin(name: &str) -> anyhow::Error {
+    error!("age-plugin-{name} was not found in PATH.");
+    anyhow!(
+        "Using {name} keys needs the age-plugin-{name} program, which was not found in PATH; \
+         install it, or add the directory that holds it to PATH."
+    )
+}
+
+/// Turns an error from the plugin identities in `source` into a clear one.
+fn plugin_error(source: &str, error: impl Into<PluginError>) -> anyhow::Error {
+    match error.into() {
+        PluginError::Missing(binary_name) => missing_plugin(
+            binary_name
+                .strip_prefix("age-plugin-")
+                .unwrap_or(&binary_name),
+        ),
+        PluginError::Other(e) => {
+            error!("Failed to use the plugin identities in {source}: {e}");
+            anyhow!("Failed to use the plugin identities in {source}: {e}")
+        }
+    }
+}
+
+/// The errors of plugin identities, whether used to encrypt or to decrypt.
+enum PluginError {
+    Missing(String),
+    Other(String),
+}
+
+impl From<age::EncryptError> for PluginError {
+    fn from(error: age::EncryptError) -> Self {
+        match error {
+            age::EncryptError::MissingPlugin { binary_name } => PluginError::Missing(binary_name),
+            e => PluginError::Other(e.to_string()),
+        }
+    }
+}
+
+impl From<age::DecryptError> for PluginError {
+    fn from(error: age::DecryptError) -> Self {
+        match error {
+            age::DecryptError::MissingPlugin { binary_name } => PluginError::Missing(binary_name),
+            e => PluginError::Other(e.to_string()),
+        }
+    }
+}
+
+/// Parses the SSH public key `line`, given by `source`.
+fn parse_ssh_recipient(line: &str, source: &str) -> Result<ssh::Recipient> {
+    line.parse()
+        .inspect_err(|e| error!("Unusable SSH key in {source}: {e}"))
+}
+
+/// Returns the recipients in the file `filename` if it holds SSH keys or plugin recipients,
+/// which `cli_common` leaves out, or `None` to leave the file to `cli_common`.
+fn read_recipients_file_lines(filename: &str) -> Option<Vec<String>> {
+    if filename == "-" {
+        return None;
+    }
+    // Missing files are reported by cli_common with a better message.
+    let contents = std::fs::read_to_string(filename).ok()?;
+    let lines: Vec<String> = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect();
+    if !lines
+        .iter()
+        .any(|line| ssh::is_public_key(line) || line.parse::<age::plugin::Recipient>().is_ok())
+    {
+        return None;
+    }
+    debug!("Reading SSH keys and plugin recipients from {filename}.");
+    Some(lines)
+}
+
+/// Reads the identity file `filename` if it is an SSH private key, or returns `None` to leave
+/// it to `cli_common`.
+fn read_ssh_identity(filename: &str) -> Result<Option<ssh::Identity>> {
+    let Some(contents) = read_ssh_private_key(filename) else {
+        return Ok(None);
+    };
+    let identity = ssh::Identity::from_file_contents(&contents, filename)
+        .inspect_err(|e| error!("Unusable SSH key {filename}: {e}"))?;
+    Ok(Some(identity))
+}
+
+/// Reads the public key of the identity file `filename` if it is an SSH private key, which
+/// does not need its passphrase, or returns `None` to leave it to `cli_common`.
+fn read_ssh_identity_recipient(filename: &str) -> Result<Option<ssh::Recipient>> {
+    let Some(contents) = read_ssh_private_key(filename) else {
+        return Ok(None);
+    };
+    let recipient = ssh::recipient_from_file_contents(&contents, filename)
+        .inspect_err(|e| error!("Unusable SSH key {filename}: {e}"))?;
+    Ok(Some(recipient))
+}
+
+/// Returns the contents of the file `filename` if it is an SSH private key.
+fn read_ssh_private_key(filename: &str) -> Option<String> {
+    if filename == "-" {
+        return None;
+    }
+    // Missing files are reported by cli_common with a better message.
+    let contents = std::fs::read(filename).ok()?;
+    if !ssh::is_private_key(&contents) {
+        return None;
+    }
+    debug!("Reading SSH private key {filename}.");
+    Some(String::from_utf8_lossy(&contents).into_owned())
+}
+
+/// Reads a passphrase from the environment or typed by the user, or generates one if empty.
+pub fn read_new_passphrase(non_interactive: bool) -> Result<SecretString> {
+    if let Some(passphrase) = supplied_passphrase()? {
+        return Ok(passphrase);
+    }
+    if non_interactive {
+        error!("Passphrase encryption requires a prompt, which is disabled.");
+        return Err(
+            anyhow::Error::from(sage::Error::NoRecipients).context(format!(
+                "Passphrase encryption requires a passphrase prompt, \
+             which is disabled in non-interactive mode; set {PASSPHRASE_ENV}, \
+             {PASSPHRASE_FILE_ENV} or {PASSPHRASE_FD_ENV} instead."
+            )),
+        );
+    }
+
+    match cli_common::read_or_generate_passphrase()
+        .map_err(|e| anyhow!("Failed to read passphrase: {e}"))?
+    {
+        cli_common::Passphrase::Typed(passphrase) => Ok(passphrase),
+        cli_common::Passphrase::Generated(passphrase) => {
+            eprintln!(
+                "Using an autogenerated passphrase:\n    {}",
+                passphrase.expose_secret()
+            );
+            Ok(passphrase)
+        }
+    }
+}
+
+/// Reads the passphrase of a passphrase-encrypted archive from the environment or a prompt.
+pub fn request_passphrase(non_interactive: bool) -> Result<SecretString> {
+    if let Some(passphrase) = supplied_passphrase()? {
+        return Ok(passphrase);
+    }
+    if non_interactive {
+        error!("Archive is passphrase-encrypted but prompting is disabled.");
+        return Err(
+            anyhow::Error::from(sage::Error::NoPassphrase).context(format!(
+                "Archive is passphrase-encrypted and requires a passphrase prompt, \
+             which is disabled in non-interactive mode; set {PASSPHRASE_ENV}, \
+             {PASSPHRASE_FILE_ENV} or {PASSPHRASE_FD_ENV} instead."
+            )),
+        );
+    }
+
+    cli_common::read_secret("Enter passphrase", "Passphrase", None)
+        .map_err(|e| anyhow!("Failed to read passphrase: {e}"))
+}
+
+/// Prompts for the passphrase of an encrypted signing key.
+pub fn request_key_passphrase(non_interactive: bool) -> Result<SecretString> {
+    if non_interactive {
+        error!("Signing key is encrypted but prompting is disabled.");
+        return Err(anyhow!(
+            "Signing key is encrypted and requires a passphrase prompt, \
+             which is disabled in non-interactive mode."
+        ));
+    }
+
+    cli_common::read_secret("Enter signing key passphrase", "Passphrase", None)
+        .map_err(|e| anyhow!("Failed to read passphrase: {e}"))
+}
+
+/// Generates an X25519 identity, returning the identity file contents and its recipient.
+///
+/// The contents follow `age-keygen`: a creation time and public key comment, then the key.
+pub fn generate_identity() -> (SecretString, String) {
+    let identity = age::x25519::Identity::generate();
+    let recipient = identity.to_public().to_string();
+    let created = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()
+        .and_then(|d| chrono::DateTime::from_timestamp(d.as_secs() as i64, 0))
+        .map(|t| t.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+        .unwrap_or_default();
+    let contents = format!(
+        "# created: {created}\n# public key: {recipient}\n{}\n",
+        identity.to_string().expose_secret()
+    );
+    (SecretString::from(contents), recipient)
+}
+
+/// Writes identity file `contents` to `output`, encrypted and armored if `passphrase` is set.
+pub fn write_identity<W: Write>(
+    contents: &SecretString,
+    passphrase: Option<SecretString>,
+    mut output: W,
+) -> Result<W> {
+    let Some(passphrase) = passphrase else {
+        output.write_all(contents.expose_secret().as_bytes())?;
+        return Ok(output);
+    };
+    debug!("Encrypting identity file with a passphrase.");
+    let armored = ArmoredWriter::wrap_output(output, Format::AsciiArmor)?;
+    let mut writer = age::Encryptor::with_user_passphrase(passphrase).wrap_output(armored)?;
+    writer.write_all(contents.expose_secret().as_bytes())?;
+    Ok(writer.finish()?.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_only_the_first_line() {
+        let passphrase = read_first_line(&b"secret\r\nnext\n"[..], "test").unwrap();
+        assert_eq!(passphrase.expose_secret(), "secret");
+        let passphrase = read_first_line(&b"no newline"[..], "test").unwrap();
+        assert_eq!(passphrase.expose_secret(), "no newline");
+    }
+
+    #[test]
+    fn rejects_an_empty_passphrase() {
+        assert!(read_first_line(&b"\n"[..], "test").is_err());
+        assert!(read_first_line(&b""[..], "test").is_err());
+    }
+
+    #[test]
+    fn reads_the_passphrase_from_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("passphrase");
+        std::fs::write(&path, "from a file\nignored\n").unwrap();
+        assert_eq!(
+            read_passphrase_file(&path).unwrap().expose_secret(),
+            "from a file"
+        );
+        assert!(read_passphrase_file(&dir.path().join("missing")).is_err());
+    }
+}
@@ -0,0 +1,82 @@
+//! Compress, encrypt, and add error correction to files and directories.
+//!
+//! A protected archive is a tar stream compressed with zstd (or another [`compress::Codec`]),
+//! encrypted with age, and wrapped in a Reed–Solomon error correction layer.
+//! [`ProtectOptions`] writes archives and [`RecoverOptions`] reads them back; both work on
+//! any `Read`/`Write` stream as well as on files.
+//!
+//! ```no_run
+//! use sage::{ProtectOptions, RecoverOptions};
+//! use std::path::Path;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let identity = age::x25519::Identity::generate();
+//!
+//! ProtectOptions::new()
+//!     .recipient(Box::new(identity.to_public()))
+//!     .protect_to_file(Path::new("my_folder"), Path::new("my_folder.sage"))?;
+//!
+//! RecoverOptions::new()
+//!     .identity(Box::new(identity))
+//!     .recover_file(Path::new("my_folder.sage"), Path::new("restored"))?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod bench;
+pub mod cancel;
+pub mod checksum;
+pub mod compress;
+pub mod convert;
+pub mod dedup;
+pub mod diff;
+mod error;
+pub mod exclude;
+pub mod fec;
+pub mod fileattrs;
+pub mod http;
+pub mod incremental;
+pub mod info;
+pub mod integrity;
+pub mod keyring;
+pub mod manifest;
+pub mod memory;
+#[cfg(target_os = "linux")]
+pub mod mount;
+pub mod nonblocking;
+pub mod normalize;
+pub mod output;
+pub mod ownership;
+pub mod pipeline;
+pub mod progress;
+mod protect;
+mod recover;
+pub mod rekey;
+pub mod resume;
+pub mod s3;
+pub mod salvage;
+pub mod seekable;
+pub mod select;
+pub mod sftp;
+pub mod shamir;
+pub mod sidecar;
+pub mod signature;
+pub mod sparse;
+pub mod ssh;
+pub mod tar_format;
+pub mod throttle;
+mod unicode_tables;
+pub mod unpack;
+pub mod verify;
+pub mod xattrs;
+
+pub use error::{Error, Result};
+pub use protect::{DryRun, PlannedEntry, PlannedKind, ProtectOptions};
+pub use recover::{Contents, PassphraseProvider, RecoverOptions};
+
+/// Magic prefix of a plain age file, as written before error correction was added and by
+/// `protect --compat`.
+pub const AGE_MAGIC: &[u8] = b"age-encryption.org/";
+
+/// First line of an ASCII-armored age file, as written by [`ProtectOptions::armor`].
+pub const ARMOR_BEGIN_MARKER: &[u8] = b"-----BEGIN AGE ENCRYPTED FILE-----";
@@ -0,0 +1,215 @@
+//! Advisory locks that keep two runs from writing the same archive at once.
+//!
+//! `protect` locks its output and `repair` its input by creating `ARCHIVE.lock` next to
+//! the archive, holding the PID and host name of the run. Only one run can create the
+//! file, so a second one, such as an overlapping cron job, fails or with `--wait` waits
+//! until the first removes it. A lock left by a process that no longer runs on this host is
+//! stale and taken over. The lock is advisory: other programs, and runs with `--no-lock`,
+//! ignore it.
+
+use crate::naming;
+use anyhow::{Context, Result, anyhow};
+use log::{debug, info, warn};
+use sage::output::{self, PendingRemoval};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often a run that waits for a lock checks whether it was released.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Returns the path of the lock file of `archive`.
+pub fn path(archive: &Path) -> PathBuf {
+    let mut path = OsString::from(archive.as_os_str());
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+/// The run holding a lock, as written in the lock file.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Holder {
+    pid: u32,
+    hostname: String,
+    /// When the lock was taken, in RFC 3339.
+    started: String,
+}
+
+impl Holder {
+    fn this_process() -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs() as i64);
+        Self {
+            pid: std::process::id(),
+            hostname: naming::hostname(),
+            started: chrono::DateTime::from_timestamp(now, 0)
+                .unwrap_or_default()
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        }
+    }
+
+    /// Reads the holder of the lock at `path`, or `None` if the file is gone or does not
+    /// say yet, as when its run is still writing it.
+    fn read(path: &Path) -> Option<Self> {
+        let text = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&text).ok()
+    }
+
+    /// Whether the holder was a process on this host that no longer runs.
+    fn is_stale(&self) -> bool {
+        self.hostname == naming::hostname() && !process_runs(self.pid)
+    }
+}
+
+impl std::fmt::Display for Holder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "PID {} on {} since {}",
+            self.pid, self.hostname, self.started
+        )
+    }
+}
+
+#[cfg(unix)]
+fn process_runs(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks whether the process exists.
+    let signalled = unsafe { libc::kill(pid, 0) } == 0;
+    signalled || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Other platforms cannot tell, so locks are never taken for stale there.
+#[cfg(not(unix))]
+fn process_runs(_pid: u32) -> bool {
+    true
+}
+
+/// A lock on an archive, released when dropped.
+pub struct Lock {
+    path: PathBuf,
+    _pending: PendingRemoval,
+}
+
+impl Lock {
+    /// Locks `archive`, waiting for another run holding it to release it if `wait` is set,
+    /// and failing otherwise.
+    pub fn acquire(archive: &Path, wait: bool) -> Result<Self> {
+        let path = path(archive);
+        let mut waiting = false;
+        loop {
+            debug!("Creating lock file: {}", path.display());
+            let created = OpenOptions::new().write(true).create_new(true).open(&path);
+            let mut file = match created {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    let holder = Holder::read(&path);
+                    if let Some(holder) = holder.as_ref().filter(|holder| holder.is_stale()) {
+                        warn!(
+                            "Removing the stale lock of {holder}, which no longer runs: {}",
+                            path.display()
+                        );
+                        match fs::remove_file(&path) {
+                            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                                return Err(e).with_context(|| {
+                                    format!("Failed to remove stale lock file: {}", path.display())
+                                });
+                            }
+                            _ => continue,
+                        }
+                    }
+                    let holder = holder.map_or("another run".to_string(), |h| h.to_string());
+                    if !wait {
+                        warn!("Archive is locked by {holder}: {}", path.display());
+                        return Err(anyhow!(
+                            "{} is locked by {holder}; use --wait to wait for it, or \
+                             --no-lock if no other run uses it.",
+                            archive.display()
+                        ));
+                    }
+                    if !waiting {
+                        info!("Waiting for {holder} to release {}.", archive.display());
+                        waiting = true;
+                    }
+                    if sage::cancel::is_requested() {
+                        return Err(anyhow!(
+                            "Interrupted while waiting for the lock on {}",
+                            archive.display()
+                        ));
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Failed to create lock file {}: {e}", path.display());
+                    return Err(e).with_context(|| {
+                        format!("Failed to create lock file: {}", path.display())
+                    });
+                }
+            };
+            let lock = Self {
+                _pending: output::remove_on_interrupt(&path),
+                path,
+            };
+            let holder = serde_json::to_string(&Holder::this_process())?;
+            file.write_all(holder.as_bytes())
+                .with_context(|| format!("Failed to write lock file: {}", lock.path.display()))?;
+            debug!("Locked {}.", archive.display());
+            return Ok(lock);
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        debug!("Removing lock file: {}", self.path.display());
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove lock file {}: {e}", self.path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_a_second_run_out_until_released() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("backup.sage");
+        let lock = Lock::acquire(&archive, false).unwrap();
+        let holder = Holder::read(&path(&archive)).unwrap();
+        assert_eq!(holder.pid, std::process::id());
+
+        let error = Lock::acquire(&archive, false).err().unwrap();
+        assert!(error.to_string().contains("is locked by PID"), "{error}");
+        drop(lock);
+        assert!(!path(&archive).exists());
+        Lock::acquire(&archive, false).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn takes_over_the_lock_of_a_process_that_is_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("backup.sage");
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        let stale = Holder {
+            pid,
+            ..Holder::this_process()
+        };
+        fs::write(path(&archive), serde_json::to_string(&stale).unwrap()).unwrap();
+
+        let _lock = Lock::acquire(&archive, false).unwrap();
+        let holder = Holder::read(&path(&archive)).unwrap();
+        assert_eq!(holder.pid, std::process::id());
+    }
+}
@@ -1,275 +1,3000 @@
-use age::cli_common;
-use age::cli_common::StdinGuard;
+mod catalog;
+mod cli;
+mod config;
+mod events;
+mod hooks;
+mod interactive;
+mod interrupt;
+mod keys;
+mod lock;
+mod naming;
+mod progress_bar;
+mod prune;
+mod watch;
+
+use age::secrecy::ExposeSecret;
 use anyhow::{Context, Result, anyhow};
-use clap::Parser;
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
+use cli::{
+    AppendArgs, BenchArgs, CatArgs, CatalogArgs, CatalogCommand, Cli, Command, ConvertArgs,
+    DiffArgs, EstimateArgs, IdentityArgs, InfoArgs, KeygenArgs, ListArgs, LockArgs, MountArgs,
+    ProtectArgs, PruneArgs, RecoverArgs, RekeyArgs, RepairArgs, VerifyArgs, WatchArgs,
+};
+use events::{Counted, Tally};
+use indicatif::HumanBytes;
 use log::{debug, error, info, warn};
-use std::fs::{self, File};
+use progress_bar::Bars;
+use sage::bench;
+use sage::cancel::CancelReader;
+use sage::compress::Codec;
+use sage::diff;
+use sage::exclude::ExcludeSet;
+use sage::fec::EccLayout;
+use sage::http::{self, HttpReader};
+use sage::incremental::State;
+use sage::info::{self as archive_info, Layout};
+use sage::output::{AtomicFile, DEFAULT_OUTPUT_MODE, create_new_output_file};
+use sage::progress::Progress;
+use sage::resume;
+use sage::s3::{self, S3Reader, S3Writer};
+use sage::salvage::Salvage;
+use sage::seekable::ReadSeek;
+use sage::select::PathSelector;
+use sage::sftp::{self, SftpReader, SftpWriter};
+use sage::shamir;
+use sage::sidecar::{self, SidecarWriter};
+use sage::signature;
+use sage::{PlannedKind, ProtectOptions, RecoverOptions, fec, verify};
+use serde_json::{Value, json};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-/// A tool to compress, encrypt, and add error correction to a file or directory.
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Cli {
-    /// Encrypt (protect) the input. Mutually exclusive with --decrypt.
-    #[arg(
-        short = 'e',
-        long = "encrypt",
-        conflicts_with = "decrypt",
-        required_unless_present = "decrypt"
-    )]
-    encrypt: bool,
-
-    /// Decrypt (recover) the input. Mutually exclusive with --encrypt.
-    #[arg(
-        short = 'd',
-        long = "decrypt",
-        conflicts_with = "encrypt",
-        required_unless_present = "encrypt"
-    )]
-    decrypt: bool,
-
-    /// Path to the input file or directory to protect
-    #[arg(value_name = "INPUT", required = true)]
-    input: PathBuf,
-
-    /// Path for the output protected file
-    #[arg(short = 'o', long = "output", value_name = "OUTPUT")]
-    output: PathBuf,
-
-    /// Encrypt to the specified RECIPIENT. Can be repeated.
-    #[arg(short = 'r', long, value_name = "RECIPIENT", required = false, num_args = 0..)]
-    recipient: Vec<String>,
-
-    /// Encrypt to recipients listed at PATH. Can be repeated.
-    #[arg(short = 'R', long, value_name = "RECIPIENTS_FILE", required = false, num_args = 0..)]
-    recipients_file: Vec<String>,
-
-    /// Path to the identity file
-    #[arg(short = 'i', long, value_name = "IDENTITY_FILE")]
-    identity_file: Vec<String>,
-
-    /// Enable debug logging
-    #[arg(long, action = clap::ArgAction::SetTrue)]
-    debug: bool,
-
-    /// Compression level (1-22, default: 3)
-    #[arg(
-        short = 'c',
-        long = "compression-level",
-        value_name = "LEVEL",
-        default_value_t = 3,
-        help = "Set compression level (1-22)"
-    )]
-    compression_level: i32,
-}
-
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-
-    if cli.compression_level < 1 || cli.compression_level > 22 {
-        error!(
-            "Invalid compression level: {}. Please use a value between 1 and 22 (inclusive).",
-            cli.compression_level
-        );
-        return Err(anyhow!(
-            "Invalid compression level: {}. Please use a value between 1 and 22 (inclusive).",
-            cli.compression_level
-        ));
+/// The path that stands for standard input or standard output.
+const STDIO_PATH: &str = "-";
+
+/// Placeholder in the output of `sage watch` that is replaced with the time of each run.
+const TIMESTAMP_PLACEHOLDER: &str = "{timestamp}";
+
+/// Permissions of manifest files, which list file names but no contents.
+const MANIFEST_MODE: u32 = 0o644;
+
+/// Exit status of `protect --compare-to` when the input is unchanged and nothing was written.
+const NO_CHANGES_EXIT_CODE: i32 = 10;
+
+/// Exit status of a usage error, which clap also uses for the arguments it rejects.
+const USAGE_EXIT_CODE: i32 = 2;
+
+/// Exit status when there are no recipients, identities or passphrase to use.
+const NO_KEYS_EXIT_CODE: i32 = 3;
+
+/// Exit status when none of the identities or the passphrase given opens the archive.
+const DECRYPTION_EXIT_CODE: i32 = 4;
+
+/// Exit status when the archive is damaged beyond what error correction can repair.
+const CORRUPTION_EXIT_CODE: i32 = 5;
+
+/// Exit status of `recover --salvage` when files were lost or damaged.
+const SALVAGE_LOSSES_EXIT_CODE: i32 = 6;
+
+/// Exit status of `recover` when it succeeded, but error correction had to repair damage.
+const REPAIRED_EXIT_CODE: i32 = 12;
+
+fn main() {
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let json = cli.json;
+    let Err(e) = run(cli, &matches) else {
+        return;
+    };
+    if json {
+        // The error event replaces the usual message, so the output stays valid JSON lines.
+        events::error(&e);
+    } else {
+        eprintln!("Error: {e:?}");
     }
+    std::process::exit(exit_code(e));
+}
 
-    if cli.debug {
-        unsafe {
-            std::env::set_var("RUST_LOG", "debug");
+/// The exit status for a failure, by its class, so scripts can tell a wrong key from damage.
+fn exit_code(error: anyhow::Error) -> i32 {
+    if error.chain().any(|cause| cause.is::<UsageError>()) {
+        return USAGE_EXIT_CODE;
+    }
+    match sage::Error::from(error) {
+        sage::Error::NoRecipients | sage::Error::NoIdentities | sage::Error::NoPassphrase => {
+            NO_KEYS_EXIT_CODE
         }
-    } else {
-        unsafe {
-            std::env::set_var("RUST_LOG", "info");
-        }
-    }
-    env_logger::init();
-
-    if cli.encrypt {
-        info!("Protecting: {}", cli.input.display());
-        if let Err(e) = protect(
-            &cli.input,
-            &cli.output,
-            cli.recipient,
-            cli.recipients_file,
-            cli.identity_file,
-            cli.compression_level,
-        ) {
-            error!("Failed to protect file: {e}");
-            return Err(e);
+        sage::Error::IdentityRejected => DECRYPTION_EXIT_CODE,
+        sage::Error::CorruptBlock { .. } => CORRUPTION_EXIT_CODE,
+        _ => 1,
+    }
+}
+
+/// A combination of arguments that clap cannot reject by itself.
+#[derive(Debug)]
+struct UsageError(String);
+
+impl std::fmt::Display for UsageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+/// Fails with a usage error.
+fn usage_error<T>(message: impl Into<String>) -> Result<T> {
+    Err(UsageError(message.into()).into())
+}
+
+fn run(mut cli: Cli, matches: &ArgMatches) -> Result<()> {
+    let started = Instant::now();
+    // RUST_LOG, if set, refines or overrides the level from the flags.
+    let mut logger = env_logger::Builder::new();
+    logger
+        .filter_level(cli.log_level())
+        .parse_env(env_logger::Env::default());
+    if cli.json {
+        events::init(writes_to_stdout(&cli.command));
+        logger.format(events::format_record);
+    }
+    let bars = Bars::init(logger.build(), !cli.no_progress)?;
+
+    let non_interactive = interactive::is_non_interactive(cli.non_interactive);
+    if let Some(path) = &cli.passphrase_file {
+        keys::remember_passphrase(keys::read_passphrase_file(path)?);
+    } else if let Some(fd) = cli.passphrase_fd {
+        keys::remember_passphrase(keys::read_passphrase_fd(fd, "--passphrase-fd")?);
+    }
+    interrupt::install();
+    config::load(cli.config.as_deref())?.apply(&mut cli.command, matches);
+
+    match cli.command {
+        Command::Protect(mut args) => {
+            name_output(&mut args, std::time::SystemTime::now())
+                .inspect_err(|e| error!("Failed to name the archive: {e}"))?;
+            if args.post_command.is_some() && is_stdio(&args.output) {
+                return usage_error(
+                    "--post-command needs an output file and cannot be used with standard output.",
+                );
+            }
+            if args.dry_run {
+                let summary = dry_run(&args).inspect_err(|e| {
+                    interrupt::exit_if_interrupted(e);
+                    error!("Failed to plan protect: {e}");
+                })?;
+                events::summary("protect", summary, started);
+                return Ok(());
+            }
+            info!("Protecting: {}", args.input.display());
+            let summary = protect(&args, &bars, non_interactive).inspect_err(|e| {
+                interrupt::exit_if_interrupted(e);
+                error!("Failed to protect file: {e}");
+            })?;
+            if summary["unchanged"] == true {
+                events::summary("protect", summary, started);
+                std::process::exit(NO_CHANGES_EXIT_CODE);
+            }
+            info!("Successfully protected file to: {}", args.output.display());
+            for mirror in &args.mirror {
+                info!("Mirrored archive to: {}", mirror.display());
+            }
+
+            if let Some(command) = args.post_command {
+                let status = hooks::run_post_command(&command, &args.output)?;
+                if !status.success() {
+                    error!("Post-command failed with {status}");
+                    events::error(&anyhow!("Post-command failed with {status}"));
+                    std::process::exit(status.code().unwrap_or(1));
+                }
+            }
+            events::summary("protect", summary, started);
+        }
+        Command::Recover(args) => {
+            if let Some(manifest_path) = &args.manifest_out {
+                info!("Writing manifest of: {}", args.input.display());
+                let summary = export_manifest(&args, manifest_path, &bars, non_interactive)
+                    .inspect_err(|e| {
+                        interrupt::exit_if_interrupted(e);
+                        error!("Failed to write manifest: {e}");
+                    })?;
+                info!(
+                    "Successfully wrote manifest to: {}",
+                    manifest_path.display()
+                );
+                events::summary("recover", summary, started);
+                return Ok(());
+            }
+            if args.test {
+                info!("Test-restoring: {}", args.input.display());
+                let summary = test_recover(&args, &bars, non_interactive).inspect_err(|e| {
+                    interrupt::exit_if_interrupted(e);
+                    error!("Test restore failed: {e}");
+                })?;
+                info!("Archive can be restored: {}", args.input.display());
+                let repaired = has_repairs(&summary);
+                events::summary("recover", summary, started);
+                if repaired {
+                    std::process::exit(REPAIRED_EXIT_CODE);
+                }
+                return Ok(());
+            }
+
+            let output = args
+                .output
+                .as_deref()
+                .map_or_else(|| usage_error("An output path is required to recover."), Ok)?;
+            info!("Recovering file: {}", args.input.display());
+            let created = !is_stdio(output) && !output.exists();
+            let summary = recover(&args, output, &bars, non_interactive).inspect_err(|e| {
+                if sage::cancel::is_requested() && created && output.is_dir() {
+                    remove_partial_extraction(output, args.keep_partial);
+                }
+                interrupt::exit_if_interrupted(e);
+                error!("Failed to recover file: {e}");
+            })?;
+            if summary["salvage"]["complete"] == false {
+                error!(
+                    "Recovered only part of the archive to: {}",
+                    output.display()
+                );
+                events::summary("recover", summary, started);
+                std::process::exit(SALVAGE_LOSSES_EXIT_CODE);
+            }
+            info!("Successfully recovered to: {}", output.display());
+            let repaired = has_repairs(&summary);
+            events::summary("recover", summary, started);
+            if repaired {
+                std::process::exit(REPAIRED_EXIT_CODE);
+            }
+        }
+        Command::List(args) => {
+            debug!("Listing: {}", args.input.display());
+            let summary = list(&args, cli.json, &bars, non_interactive).inspect_err(|e| {
+                interrupt::exit_if_interrupted(e);
+                error!("Failed to list archive: {e}");
+            })?;
+            events::summary("list", summary, started);
+        }
+        Command::Cat(args) => {
+            debug!(
+                "Writing {} from: {}",
+                args.path.display(),
+                args.input.display()
+            );
+            let summary = cat(&args, &bars, non_interactive).inspect_err(|e| {
+                interrupt::exit_if_interrupted(e);
+                error!("Failed to write {}: {e}", args.path.display());
+            })?;
+            events::summary("cat", summary, started);
+        }
+        Command::Mount(args) => {
+            info!(
+                "Mounting {} at {}",
+                args.input.display(),
+                args.mountpoint.display()
+            );
+            mount(&args, &bars, non_interactive).inspect_err(|e| {
+                error!("Failed to mount archive: {e}");
+            })?;
+            events::summary(
+                "mount",
+                json!({ "input": args.input.display().to_string() }),
+                started,
+            );
+        }
+        Command::Verify(args) => {
+            info!("Verifying: {}", args.input.display());
+            let spare = verify(&args, &bars, non_interactive).inspect_err(|e| {
+                interrupt::exit_if_interrupted(e);
+                error!("Verification failed: {e}");
+            })?;
+            info!("Archive verified successfully: {}", args.input.display());
+            let summary = json!({
+                "input": args.input.display().to_string(),
+                "verified": true,
+                "spare_shards": spare,
+                "simulated_loss": args.simulate_loss,
+            });
+            events::summary("verify", summary, started);
+        }
+        Command::Diff(args) => {
+            info!(
+                "Comparing {} with {}",
+                args.archive.display(),
+                args.dir.display()
+            );
+            let summary = diff(&args, &bars, non_interactive).inspect_err(|e| {
+                interrupt::exit_if_interrupted(e);
+                error!("Failed to compare archive: {e}");
+            })?;
+            events::summary("diff", summary, started);
+        }
+        Command::Estimate(args) => {
+            info!("Estimating: {}", args.input.display());
+            let summary = estimate(&args).inspect_err(|e| {
+                interrupt::exit_if_interrupted(e);
+                error!("Failed to estimate the archive size: {e}");
+            })?;
+            events::summary("estimate", summary, started);
+        }
+        Command::Bench(args) => {
+            info!("Benchmarking compression on: {}", args.input.display());
+            let summary = bench(&args).inspect_err(|e| {
+                interrupt::exit_if_interrupted(e);
+                error!("Failed to benchmark: {e}");
+            })?;
+            events::summary("bench", summary, started);
+        }
+        Command::Info(args) => {
+            debug!("Reading header of: {}", args.input.display());
+            let summary = info(&args, non_interactive).inspect_err(|e| {
+                interrupt::exit_if_interrupted(e);
+                error!("Failed to read archive header: {e}");
+            })?;
+            events::summary("info", summary, started);
+        }
+        Command::Repair(args) => {
+            info!("Repairing: {}", args.input.display());
+            let summary = repair(&args, non_interactive).inspect_err(|e| {
+                interrupt::exit_if_interrupted(e);
+                error!("Failed to repair file: {e}");
+            })?;
+            info!("Successfully repaired to: {}", args.output.display());
+            events::summary("repair", summary, started);
+        }
+        Command::Convert(args) => {
+            info!("Converting: {}", args.input.display());
+            let summary = convert(&args, &bars, non_interactive).inspect_err(|e| {
+                interrupt::exit_if_interrupted(e);
+                error!("Failed to convert archive: {e}");
+            })?;
+            info!("Successfully converted to: {}", args.output.display());
+            events::summary("convert", summary, started);
+        }
+        Command::Watch(args) => {
+            info!("Watching: {}", args.protect.input.display());
+            watch(&args, &bars, non_interactive).inspect_err(|e| {
+                interrupt::exit_if_interrupted(e);
+                error!("Failed to watch {}: {e}", args.protect.input.display());
+            })?;
         }
-        info!("Successfully protected file to: {}", cli.output.display());
-    } else if cli.decrypt {
-        info!("Recovering file: {}", cli.input.display());
-        if let Err(e) = recover(&cli.input, &cli.output, cli.identity_file) {
-            error!("Failed to recover file: {e}");
-            return Err(e);
+        Command::Append(args) => {
+            info!("Appending to: {}", args.archive.display());
+            let summary = append(&args, &bars, non_interactive).inspect_err(|e| {
+                interrupt::exit_if_interrupted(e);
+                error!("Failed to append to archive: {e}");
+            })?;
+            let output = args.output.as_deref().unwrap_or(&args.archive);
+            info!("Successfully appended to: {}", output.display());
+            events::summary("append", summary, started);
+        }
+        Command::Rekey(args) => {
+            info!("Rekeying: {}", args.archive.display());
+            let summary = rekey(&args, &bars, non_interactive).inspect_err(|e| {
+                interrupt::exit_if_interrupted(e);
+                error!("Failed to rekey archive: {e}");
+            })?;
+            let output = args.output.as_deref().unwrap_or(&args.archive);
+            info!("Successfully rekeyed to: {}", output.display());
+            events::summary("rekey", summary, started);
+        }
+        Command::Prune(args) => {
+            info!("Pruning: {}", args.dir.display());
+            let summary = prune(&args).inspect_err(|e| {
+                interrupt::exit_if_interrupted(e);
+                error!("Failed to prune archives: {e}");
+            })?;
+            events::summary("prune", summary, started);
+        }
+        Command::Catalog(args) => {
+            let summary = catalog(&args).inspect_err(|e| {
+                error!("Failed to read the catalog: {e}");
+            })?;
+            events::summary("catalog", summary, started);
+        }
+        Command::Keygen(args) => {
+            let summary = keygen(&args, non_interactive).inspect_err(|e| {
+                interrupt::exit_if_interrupted(e);
+                error!("Failed to generate identity: {e}");
+            })?;
+            events::summary("keygen", summary, started);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns true if `command` writes its data, rather than messages, to standard output.
+fn writes_to_stdout(command: &Command) -> bool {
+    match command {
+        Command::Protect(args) => is_stdio(&args.output) && !args.dry_run,
+        Command::Recover(args) => args
+            .manifest_out
+            .as_deref()
+            .or(args.output.as_deref())
+            .is_some_and(is_stdio),
+        Command::List(_) | Command::Cat(_) => true,
+        Command::Verify(_) | Command::Estimate(_) | Command::Info(_) | Command::Mount(_) => false,
+        Command::Diff(_) | Command::Bench(_) => true,
+        Command::Repair(args) => is_stdio(&args.output),
+        Command::Convert(args) => is_stdio(&args.output),
+        Command::Keygen(args) => is_stdio(&args.output),
+        Command::Append(args) => args.output.as_deref().is_some_and(is_stdio),
+        Command::Rekey(args) => args.output.as_deref().is_some_and(is_stdio),
+        Command::Watch(_) | Command::Prune(_) => false,
+        Command::Catalog(_) => true,
+    }
+}
+
+/// Points OUTPUT at a new archive inside it when it is a directory, named after
+/// `--name-template` at `now`.
+fn name_output(args: &mut ProtectArgs, now: std::time::SystemTime) -> Result<()> {
+    let template = args
+        .name_template
+        .as_deref()
+        .unwrap_or(naming::DEFAULT_TEMPLATE);
+    if is_stdio(&args.output) || !naming::is_directory(&args.output) {
+        if args.name_template.is_some() {
+            return usage_error(format!(
+                "--name-template names archives inside an output directory, but {} is not a \
+                 directory.",
+                args.output.display()
+            ));
         }
-        info!("Successfully recovered to: {}", cli.output.display());
     } else {
-        warn!("Neither --encrypt nor --decrypt specified.");
-        return Err(anyhow!(
-            "You must specify either --encrypt (-e) or --decrypt (-d)."
+        let name = naming::render(template, &args.input, now)?;
+        args.output = args.output.join(name);
+        debug!("Writing the archive to {}", args.output.display());
+    }
+    // Mirror directories get the same name as the archive.
+    for mirror in &mut args.mirror {
+        if is_stdio(mirror) || !naming::is_directory(mirror) {
+            continue;
+        }
+        let name = match args.output.file_name() {
+            Some(name) if !is_stdio(&args.output) => PathBuf::from(name),
+            _ => PathBuf::from(naming::render(template, &args.input, now)?),
+        };
+        *mirror = mirror.join(name);
+        debug!("Mirroring the archive to {}", mirror.display());
+    }
+    Ok(())
+}
+
+/// Builds the library options for `protect` from the command line, reading keys as needed.
+fn protect_options(
+    args: &ProtectArgs,
+    bars: &Bars,
+    non_interactive: bool,
+) -> Result<ProtectOptions> {
+    let options = archive_options(args)?;
+    let no_identities = args.identity_file.is_empty() && args.identity_from_keyring.is_empty();
+    if args.no_encrypt {
+        if !no_identities || !args.recipients_file.is_empty() {
+            warn!("Ignoring recipients and identity files for an unencrypted archive.");
+        }
+        return Ok(options.unencrypted());
+    }
+    if args.passphrase {
+        if !no_identities {
+            warn!("Ignoring identity files when encrypting with a passphrase.");
+        }
+        return Ok(options.passphrase(keys::read_new_passphrase(non_interactive)?));
+    }
+
+    if args.shares.is_some()
+        && args.recipient.is_empty()
+        && args.recipients_file.is_empty()
+        && no_identities
+    {
+        debug!("Encrypting only to the key split into shares.");
+        return Ok(options);
+    }
+    if args.to_self && no_identities {
+        error!("No identity files given for --to-self.");
+        return usage_error(format!(
+            "--to-self needs an identity file; use -i, or set {} or identity_files in the \
+             configuration file.",
+            config::IDENTITY_ENV
         ));
     }
+    if args.recipient.is_empty() && args.recipients_file.is_empty() && no_identities {
+        error!("No recipients given.");
+        return Err(
+            anyhow::Error::new(sage::Error::NoRecipients).context(format!(
+                "No recipients given; use -r, -R, -i or -p, or set {} or {}.",
+                config::RECIPIENTS_FILE_ENV,
+                config::IDENTITY_ENV
+            )),
+        );
+    }
+    let recipients = keys::read_recipients(
+        args.recipient.clone(),
+        args.recipients_file.clone(),
+        args.identity_file.clone(),
+        &args.identity_from_keyring,
+        &keys::PluginUi::new(bars, non_interactive),
+    )?;
+    Ok(options.recipients(recipients))
+}
 
-    Ok(())
+/// The time to date the entries of deterministic archives: `SOURCE_DATE_EPOCH`, in seconds
+/// since 1970, or 1970 itself.
+fn source_date_epoch() -> Result<u64> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(secs) => secs.trim().parse().map_err(|_| {
+            warn!("SOURCE_DATE_EPOCH is not a number of seconds: {secs:?}");
+            anyhow!("SOURCE_DATE_EPOCH must be a number of seconds since 1970, not {secs:?}.")
+        }),
+        Err(_) => Ok(0),
+    }
 }
 
-fn protect(
-    input_path: &Path,
-    output_path: &Path,
-    recipient_strings: Vec<String>,
-    recipients_file_strings: Vec<String>,
-    identity_strings: Vec<String>,
-    mut compression_level: i32,
-) -> Result<()> {
-    compression_level = compression_level.clamp(1, 22);
-    let max_work_factor: Option<u8> = Some(15);
-    let mut stdin_guard = StdinGuard::new(true);
-
-    let recipients: Vec<Box<dyn age::Recipient>> = cli_common::read_recipients(
-        recipient_strings,
-        recipients_file_strings,
-        identity_strings,
-        max_work_factor,
-        &mut stdin_guard,
-    )
-    .into_iter()
-    .flatten()
-    .map(|r| {
-        let raw: *mut dyn age::Recipient = Box::into_raw(r) as *mut dyn age::Recipient;
-        unsafe { Box::from_raw(raw) }
-    })
-    .collect();
+/// Builds the `protect` options that decide what is archived and how, without any keys.
+fn archive_options(args: &ProtectArgs) -> Result<ProtectOptions> {
+    let compression_level = if args.fast { 1 } else { args.compression_level };
+    let codec = codec(args.compression, args.no_compress);
+    let mut options = ProtectOptions::new()
+        .compression(codec)
+        .compression_level(compression_level)
+        .redundancy(args.redundancy)
+        .output_mode(args.output_mode)
+        .respect_gitignore(args.respect_gitignore)
+        .dereference(args.dereference)
+        .sparse(!args.no_sparse)
+        .tar_format(args.tar_format)
+        .dedup(args.dedup)
+        .seekable(args.seekable)
+        .skip_compressed(args.skip_compressed)
+        .no_compress_extensions(&args.no_compress_extensions)
+        .train_dictionary(args.train_dictionary)
+        .armor(args.armor)
+        .compat(args.compat || args.parity_sidecar);
+    if args.ecc_layout == EccLayout::Interleaved {
+        options = options.interleave(args.stripe_size);
+    }
+    if let Some(window_log) = args.long {
+        options = options.long_window_log(window_log);
+    }
+    if let Some(bytes) = args.max_memory {
+        options = options.max_memory(bytes);
+    }
+    if let Some(rate) = args.bwlimit {
+        options = options.bwlimit(rate);
+    }
+    if args.deterministic {
+        options = options.deterministic(source_date_epoch()?);
+    }
+    if let Some(preserve) = args.preserve.owner() {
+        options = options.preserve_owner(preserve);
+    }
+    if let Some(preserve) = args.preserve.permissions() {
+        options = options.preserve_permissions(preserve);
+    }
+    if let Some(preserve) = args.preserve.mtime() {
+        options = options.preserve_mtime(preserve);
+    }
+    if let Some(preserve) = args.preserve.xattrs() {
+        options = options.preserve_xattrs(preserve);
+    }
+    if let Some(exclude) = exclude_set(&args.exclude, &args.exclude_from)? {
+        options = options.exclude(exclude);
+    }
+    if let Some(threads) = args.threads {
+        if !codec.is_multithreaded() {
+            warn!("{codec} compression is single-threaded; ignoring --threads.");
+        }
+        options = options.threads(threads);
+    }
+    Ok(options)
+}
 
-    if recipients.is_empty() {
-        warn!("No valid recipients provided.");
-        return Err(anyhow!("No valid recipients provided."));
+/// Builds the library options for reading an archive with the given identity files and
+/// keychain entries.
+fn recover_options(
+    identity_strings: &[String],
+    keyring_names: &[String],
+    bars: &Bars,
+    non_interactive: bool,
+) -> Result<RecoverOptions> {
+    let identities = keys::read_identities(
+        identity_strings.to_vec(),
+        keyring_names,
+        &keys::PluginUi::new(bars, non_interactive),
+    )?;
+    let bars = bars.clone();
+    Ok(RecoverOptions::new()
+        .identities(identities)
+        .max_work_factor(keys::MAX_WORK_FACTOR)
+        .passphrase_provider(Box::new(move || {
+            bars.suspend(|| keys::request_passphrase(non_interactive))
+        })))
+}
+
+/// Removes the directory an interrupted recovery created, unless asked to keep it.
+fn remove_partial_extraction(output: &Path, keep: bool) {
+    if keep {
+        warn!("Keeping partially extracted files in: {}", output.display());
+        return;
     }
+    info!("Removing partially extracted files: {}", output.display());
+    if let Err(e) = std::fs::remove_dir_all(output) {
+        warn!("Failed to remove {}: {e}", output.display());
+    }
+}
 
-    debug!("Creating output file: {}", output_path.display());
-    let output_file = File::create(output_path)
-        .with_context(|| format!("Failed to create output file: {}", output_path.display()))?;
+/// Returns true if `path` is `-`, meaning standard input or output.
+fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == STDIO_PATH
+}
 
-    debug!("Initializing age encryption.");
-    let encryptor = age::Encryptor::with_recipients(recipients.iter().map(|r| r.as_ref()))?;
-    let mut age_writer = encryptor.wrap_output(output_file)?;
+/// Returns true if `path` is an `s3://`, `sftp://` or `http(s)://` URL rather than a local
+/// file.
+fn is_remote(path: &Path) -> bool {
+    s3::is_s3_url(path) || sftp::is_sftp_url(path) || http::is_http_url(path)
+}
 
-    debug!(
-        "Initializing zstd compression with level {}.",
-        compression_level
-    );
-    let mut zstd_encoder = zstd::Encoder::new(&mut age_writer, compression_level)
-        .context("Failed to create zstd encoder")?;
+/// Opens `path` for reading, standard input for `-`, or a remote file for an `s3://`,
+/// `sftp://` or `http(s)://` URL.
+fn open_input(path: &Path, non_interactive: bool) -> Result<Box<dyn Read>> {
+    Ok(open_input_with_len(path, non_interactive)?.0)
+}
 
-    zstd_encoder
-        .multithread(num_cpus::get() as u32)
-        .context("Failed to enable multithreaded zstd encoder")?;
-    debug!(
-        "Enabled multithreaded zstd compression with {} threads.",
-        num_cpus::get()
-    );
+/// Like [`open_input`], also returning the size of the input if it is known.
+fn open_input_with_len(path: &Path, non_interactive: bool) -> Result<(Box<dyn Read>, Option<u64>)> {
+    if is_stdio(path) {
+        debug!("Reading from standard input.");
+        return Ok((Box::new(io::stdin().lock()), None));
+    }
+    if s3::is_s3_url(path) {
+        let url = path.to_string_lossy();
+        let reader = S3Reader::open(&url).with_context(|| format!("Failed to open {url}"))?;
+        let len = reader.size();
+        return Ok((Box::new(reader), len));
+    }
+    if sftp::is_sftp_url(path) {
+        let url = path.to_string_lossy();
+        let reader = SftpReader::open(&url, non_interactive)
+            .with_context(|| format!("Failed to open {url}"))?;
+        let len = reader.size();
+        return Ok((Box::new(reader), len));
+    }
+    if http::is_http_url(path) {
+        let url = path.to_string_lossy();
+        let reader = HttpReader::open(&url)
+            .with_context(|| format!("Failed to open {}", http::redact(&url)))?;
+        let len = reader.size();
+        return Ok((Box::new(reader), len));
+    }
+    if let Some(reader) = open_input_sidecar(path)? {
+        let len = Some(reader.length());
+        return Ok((Box::new(reader), len));
+    }
+    let (file, len) = open_input_file(path)?;
+    Ok((Box::new(file), len))
+}
 
-    debug!("Archiving input {} into tar stream.", input_path.display());
-    {
-        let mut tar_builder = tar::Builder::new(&mut zstd_encoder);
-        if input_path.is_dir() {
-            for entry in walkdir::WalkDir::new(input_path) {
-                let entry = entry?;
-                let path = entry.path();
-                let rel_path = path.strip_prefix(input_path)?;
-                // Skip the root directory itself (empty rel_path)
-                if rel_path.as_os_str().is_empty() {
-                    continue;
-                }
-                if path.is_symlink() {
-                    // Skip broken symlinks
-                    if !path.exists() {
-                        warn!("Skipping broken symlink: {}", path.display());
-                        continue;
-                    }
-                }
-                if path.is_dir() {
-                    tar_builder.append_dir(rel_path, path)?;
-                } else if path.is_file() {
-                    let mut file = File::open(path)?;
-                    tar_builder.append_file(rel_path, &mut file)?;
+/// Opens a local archive through its [parity sidecar](sidecar), if it has one.
+fn open_input_sidecar(path: &Path) -> Result<Option<sidecar::SidecarReader<File, File>>> {
+    sidecar::open(path).with_context(|| format!("Failed to open input file: {}", path.display()))
+}
+
+fn open_input_file(path: &Path) -> Result<(File, Option<u64>)> {
+    debug!("Opening input file: {}", path.display());
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open input file: {}", path.display()))?;
+    let len = file.metadata().ok().map(|metadata| metadata.len());
+    Ok((file, len))
+}
+
+/// An archive to read: one that can be read with seeks, or a stream.
+enum Input {
+    Seekable(Box<dyn ReadSeek>),
+    Stream(Box<dyn Read>),
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Input::Seekable(input) => input.read(buf),
+            Input::Stream(input) => input.read(buf),
+        }
+    }
+}
+
+/// Like [`open_input_with_len`], but keeps local files seekable, so that archives written
+/// with `--seekable` are read only where needed. Web servers and S3 are read with ranged
+/// requests when they accept them.
+fn open_input_seekable(path: &Path, non_interactive: bool) -> Result<(Input, Option<u64>)> {
+    if s3::is_s3_url(path) {
+        let url = path.to_string_lossy();
+        let reader = S3Reader::open(&url).with_context(|| format!("Failed to open {url}"))?;
+        let len = reader.size();
+        return Ok(match reader.is_seekable() {
+            true => (Input::Seekable(Box::new(reader)), len),
+            false => (Input::Stream(Box::new(reader)), len),
+        });
+    }
+    if http::is_http_url(path) {
+        let url = path.to_string_lossy();
+        let reader = HttpReader::open(&url)
+            .with_context(|| format!("Failed to open {}", http::redact(&url)))?;
+        let len = reader.size();
+        return Ok(match reader.is_seekable() {
+            true => (Input::Seekable(Box::new(reader)), len),
+            false => (Input::Stream(Box::new(reader)), len),
+        });
+    }
+    if is_stdio(path) || is_remote(path) {
+        let (input, len) = open_input_with_len(path, non_interactive)?;
+        return Ok((Input::Stream(input), len));
+    }
+    if let Some(reader) = open_input_sidecar(path)? {
+        let len = Some(reader.length());
+        return Ok((Input::Seekable(Box::new(reader)), len));
+    }
+    let (file, len) = open_input_file(path)?;
+    Ok((Input::Seekable(Box::new(file)), len))
+}
+
+/// Where a command writes its result: standard output, or a file that appears on commit.
+enum Output {
+    Stdout(BufWriter<io::Stdout>),
+    File(AtomicFile),
+    /// A local file and its parity sidecar, for `--parity-sidecar`.
+    Sidecar(Box<SidecarWriter<AtomicFile, AtomicFile>>),
+    S3(Box<S3Writer>),
+    Sftp(Box<SftpWriter>),
+    /// The archive and its copies, for `--mirror`, each written with everything.
+    Mirrored(Vec<(PathBuf, Output)>),
+}
+
+impl Output {
+    /// Flushes the output and, for files, moves the finished file into place.
+    fn commit(self) -> Result<()> {
+        match self {
+            Output::Stdout(mut stdout) => Ok(stdout.flush()?),
+            Output::File(file) => Ok(file.commit()?),
+            Output::Sidecar(writer) => {
+                let (file, sidecar) = writer.finish()?;
+                file.commit()?;
+                Ok(sidecar.commit()?)
+            }
+            Output::S3(upload) => Ok(upload.commit()?),
+            Output::Sftp(upload) => Ok(upload.commit()?),
+            Output::Mirrored(outputs) => outputs.into_iter().try_for_each(|(path, output)| {
+                output
+                    .commit()
+                    .with_context(|| format!("Failed to finish {}", path.display()))
+            }),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Stdout(stdout) => stdout.write(buf),
+            Output::File(file) => file.write(buf),
+            Output::Sidecar(writer) => writer.write(buf),
+            Output::S3(upload) => upload.write(buf),
+            Output::Sftp(upload) => upload.write(buf),
+            Output::Mirrored(outputs) => {
+                for (path, output) in outputs {
+                    output.write_all(buf).map_err(|e| {
+                        io::Error::new(e.kind(), format!("Failed to write {}: {e}", path.display()))
+                    })?;
                 }
+                Ok(buf.len())
             }
-            debug!("Directory archived successfully: {}", input_path.display());
-        } else {
-            let mut file = File::open(input_path).context("Failed to open input file")?;
-            let filename = input_path
-                .file_name()
-                .ok_or_else(|| anyhow!("Invalid input file name"))?
-                .to_string_lossy();
-            tar_builder.append_file(Path::new(filename.as_ref()), &mut file)?;
-            debug!("File archived successfully: {}", input_path.display());
         }
-        tar_builder.finish()?;
     }
 
-    debug!("Finishing compression and encryption streams.");
-    zstd_encoder.finish()?;
-    age_writer.finish()?;
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(stdout) => stdout.flush(),
+            Output::File(file) => file.flush(),
+            Output::Sidecar(writer) => writer.flush(),
+            Output::S3(upload) => upload.flush(),
+            Output::Sftp(upload) => upload.flush(),
+            Output::Mirrored(outputs) => outputs
+                .iter_mut()
+                .try_for_each(|(_, output)| output.flush()),
+        }
+    }
+}
+
+/// Opens the output of `protect`, with its sidecar or its mirrors.
+fn open_outputs(args: &ProtectArgs, non_interactive: bool) -> Result<Counted<Output>> {
+    let mut output = if args.parity_sidecar {
+        open_sidecar_output(
+            &args.output,
+            fec::FecParams::with_redundancy(args.redundancy),
+            args.output_mode,
+            args.force,
+            non_interactive,
+        )?
+    } else {
+        open_output(&args.output, args.output_mode, args.force, non_interactive)?
+    };
+    if !args.mirror.is_empty() {
+        let mut outputs = vec![(args.output.clone(), output)];
+        for mirror in &args.mirror {
+            debug!("Opening mirror: {}", mirror.display());
+            let copy = open_output(mirror, args.output_mode, args.force, non_interactive)?;
+            outputs.push((mirror.clone(), copy));
+        }
+        output = Output::Mirrored(outputs);
+    }
+    Ok(Counted::new(output))
+}
+
+/// Fails with a usage error if `--resume` is combined with what a resumable run cannot do:
+/// it writes a local file from a local input, and nothing else.
+fn check_resumable(args: &ProtectArgs) -> Result<()> {
+    if is_stdio(&args.input) || is_stdio(&args.output) || is_remote(&args.output) {
+        return usage_error(
+            "--resume reads a local input into a local output file, not standard input, \
+             standard output or remote storage.",
+        );
+    }
+    let conflicting = [
+        (!args.mirror.is_empty(), "--mirror"),
+        (args.parity_sidecar, "--parity-sidecar"),
+        (args.shares.is_some(), "--shares"),
+        (args.compare_to.is_some(), "--compare-to"),
+        (args.base.is_some(), "--incremental"),
+    ];
+    match conflicting.iter().find(|(set, _)| *set) {
+        Some((_, option)) => usage_error(format!("--resume cannot be combined with {option}.")),
+        None => Ok(()),
+    }
+}
 
+/// Starts writing `path` with permissions `mode`, returns standard output for `-`, or
+/// starts an upload for an `s3://` or `sftp://` URL.
+///
+/// Existing files are only replaced with `force`, and only once the output is committed.
+fn open_output(path: &Path, mode: u32, force: bool, non_interactive: bool) -> Result<Output> {
+    if is_stdio(path) {
+        debug!("Writing to standard output.");
+        return Ok(Output::Stdout(BufWriter::new(io::stdout())));
+    }
+    if s3::is_s3_url(path) {
+        let url = path.to_string_lossy();
+        debug!("Uploading output to {url}; ignoring output mode {mode:04o}.");
+        return match S3Writer::create(&url, force) {
+            Ok(upload) => Ok(Output::S3(Box::new(upload))),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                warn!("Output object already exists: {url}");
+                Err(anyhow!("{url} already exists; use --force to replace it."))
+            }
+            Err(e) => Err(e).with_context(|| format!("Failed to start upload to {url}")),
+        };
+    }
+    if sftp::is_sftp_url(path) {
+        let url = path.to_string_lossy();
+        debug!("Uploading output to {url} (mode {mode:04o}).");
+        return match SftpWriter::create(&url, mode, force, non_interactive) {
+            Ok(upload) => Ok(Output::Sftp(Box::new(upload))),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                warn!("Output file already exists: {url}");
+                Err(anyhow!("{url} already exists; use --force to replace it."))
+            }
+            Err(e) => Err(e).with_context(|| format!("Failed to start upload to {url}")),
+        };
+    }
+    if http::is_http_url(path) {
+        warn!("Cannot write to {}", http::redact(&path.to_string_lossy()));
+        return Err(anyhow!(
+            "HTTP URLs can only be read; write to a file, an s3:// or an sftp:// URL instead."
+        ));
+    }
     debug!(
-        "Protection complete. Output written to: {}",
-        output_path.display()
+        "Creating output file: {} (mode {:04o})",
+        path.display(),
+        mode
     );
+    match AtomicFile::create(path, mode, force) {
+        Ok(file) => Ok(Output::File(file)),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            warn!("Output file already exists: {}", path.display());
+            Err(anyhow!(
+                "{} already exists; use --force to replace it.",
+                path.display()
+            ))
+        }
+        Err(e) => {
+            Err(e).with_context(|| format!("Failed to create output file: {}", path.display()))
+        }
+    }
+}
 
-    Ok(())
+/// Starts writing the local file `path` and its parity sidecar, `path.ecc`.
+fn open_sidecar_output(
+    path: &Path,
+    params: fec::FecParams,
+    mode: u32,
+    force: bool,
+    non_interactive: bool,
+) -> Result<Output> {
+    let sidecar_path = sidecar::path(path);
+    let (Output::File(file), Output::File(sidecar)) = (
+        open_output(path, mode, force, non_interactive)?,
+        open_output(&sidecar_path, mode, force, non_interactive)?,
+    ) else {
+        return usage_error("--parity-sidecar needs a local output file.");
+    };
+    debug!("Writing parity to sidecar: {}", sidecar_path.display());
+    let writer = SidecarWriter::new(file, sidecar, params)
+        .with_context(|| format!("Failed to write {}", sidecar_path.display()))?;
+    Ok(Output::Sidecar(Box::new(writer)))
 }
 
-/// The core recovery pipeline: correct errors -> decrypt -> decompress -> extract.
-fn recover(input_path: &Path, output_path: &Path, identity_strings: Vec<String>) -> Result<()> {
-    let max_work_factor: Option<u8> = Some(15);
-    let mut stdin_guard = StdinGuard::new(true);
+/// Builds the exclude set of `--exclude` and `--exclude-from`, if either is given.
+fn exclude_set(exclude: &[String], exclude_from: &[PathBuf]) -> Result<Option<ExcludeSet>> {
+    if exclude.is_empty() && exclude_from.is_empty() {
+        return Ok(None);
+    }
+    let mut patterns = exclude.to_vec();
+    for path in exclude_from {
+        patterns.extend(ExcludeSet::read_patterns(path)?);
+    }
+    Ok(Some(ExcludeSet::new(&patterns)?))
+}
 
-    let identities: Vec<Box<dyn age::Identity>> =
-        cli_common::read_identities(identity_strings, max_work_factor, &mut stdin_guard)?;
+/// The codec chosen with `--compression`, or none with `--no-compress`.
+fn codec(compression: Codec, no_compress: bool) -> Codec {
+    if no_compress {
+        Codec::None
+    } else {
+        compression
+    }
+}
 
-    if identities.is_empty() {
-        warn!("No valid identities provided.");
-        return Err(anyhow!("No valid identities provided."));
+/// Predicts the size of the archive of INPUT, and checks it against `--fits` and the free
+/// space of `--destination`.
+fn estimate(args: &EstimateArgs) -> Result<Value> {
+    if is_stdio(&args.input) {
+        return usage_error("estimate needs an input path, not standard input.");
+    }
+    let codec = codec(args.compression, args.no_compress);
+    let mut options = ProtectOptions::new()
+        .compression(codec)
+        .compression_level(args.compression_level)
+        .redundancy(args.redundancy)
+        .respect_gitignore(args.respect_gitignore)
+        .dereference(args.dereference)
+        .armor(args.armor)
+        .compat(args.compat);
+    if let Some(exclude) = exclude_set(&args.exclude, &args.exclude_from)? {
+        options = options.exclude(exclude);
     }
+    let plan = options.dry_run(&args.input)?;
+    info!(
+        "Input: {}, {} and {}, {}.",
+        counted(plan.files, "file", "files"),
+        counted(plan.directories, "directory", "directories"),
+        counted(plan.links, "link", "links"),
+        HumanBytes(plan.bytes)
+    );
+    let protection = if args.armor {
+        "armored".to_string()
+    } else if args.compat {
+        "no error correction".to_string()
+    } else {
+        format!("{}% redundancy", args.redundancy)
+    };
+    let compression = match (codec, codec.effective_level(args.compression_level)) {
+        (Codec::None, _) => "no compression".to_string(),
+        (codec, Some(level)) => format!("{codec} level {level}"),
+        (codec, None) => codec.to_string(),
+    };
+    info!(
+        "Estimated archive with {compression} and {protection}: {} ({} compressed), from a {} \
+         sample.",
+        HumanBytes(plan.estimated_archive),
+        HumanBytes(plan.estimated_compressed),
+        HumanBytes(plan.sampled_bytes)
+    );
 
-    debug!("Opening encrypted input file: {}", input_path.display());
-    let input_file = File::open(input_path)
-        .with_context(|| format!("Failed to open input file: {}", input_path.display()))?;
+    let mut room = Vec::new();
+    if let Some(size) = args.fits {
+        room.push((size, format!("{}", HumanBytes(size))));
+    }
+    if let Some(dir) = &args.destination {
+        let free = free_space(dir)?;
+        debug!("{} has {free} bytes free.", dir.display());
+        room.push((
+            free,
+            format!("the {} free in {}", HumanBytes(free), dir.display()),
+        ));
+    }
+    for (space, description) in &room {
+        if plan.estimated_archive > *space {
+            warn!(
+                "The archive is expected to need {}, more than {description}.",
+                HumanBytes(plan.estimated_archive)
+            );
+            return Err(anyhow!(
+                "The archive is not expected to fit in {description}."
+            ));
+        }
+        info!(
+            "Expected to fit in {description}, with {} to spare.",
+            HumanBytes(space - plan.estimated_archive)
+        );
+    }
+    Ok(json!({
+        "input": args.input.display().to_string(),
+        "files": plan.files,
+        "directories": plan.directories,
+        "links": plan.links,
+        "bytes_in": plan.bytes,
+        "sampled_bytes": plan.sampled_bytes,
+        "estimated_compressed_bytes": plan.estimated_compressed,
+        "estimated_bytes_out": plan.estimated_archive,
+        "available_bytes": room.iter().map(|(space, _)| *space).min(),
+        "fits": (!room.is_empty()).then_some(true),
+    }))
+}
 
-    debug!("Initializing age decryption.");
-    let decryptor =
-        age::Decryptor::new(input_file)?.decrypt(identities.iter().map(|i| i.as_ref()))?;
+/// Returns the bytes an unprivileged user can still write to the file system of `dir`.
+#[cfg(unix)]
+fn free_space(dir: &Path) -> Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|_| anyhow!("Invalid path: {}", dir.display()))?;
+    let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is NUL-terminated and `stats` is valid for writes of a statvfs.
+    if unsafe { libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Failed to read the free space of {}", dir.display()));
+    }
+    // SAFETY: statvfs succeeded, so it filled in `stats`.
+    let stats = unsafe { stats.assume_init() };
+    // The field types differ between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
 
-    debug!("Initializing zstd decompression.");
-    let mut zstd_decoder =
-        zstd::Decoder::new(decryptor).context("Failed to create zstd decoder")?;
+#[cfg(not(unix))]
+fn free_space(_dir: &Path) -> Result<u64> {
+    usage_error("--destination is not supported on this platform; give the free space with --fits.")
+}
 
-    debug!(
-        "Extracting tar archive to output path: {}",
-        output_path.display()
+/// Compresses a sample of INPUT_SAMPLE with a range of settings, prints how each did and
+/// logs the one to use.
+fn bench(args: &BenchArgs) -> Result<Value> {
+    if is_stdio(&args.input) {
+        return usage_error("bench needs an input path, not standard input.");
+    }
+    let sample = bench::read_sample(&args.input, args.sample_size)?;
+    if sample.is_empty() {
+        return Err(anyhow!("{} holds no data to sample.", args.input.display()));
+    }
+    let codecs = if args.compression.is_empty() {
+        vec![Codec::Zstd, Codec::Xz, Codec::Lz4, Codec::Brotli]
+    } else {
+        args.compression.clone()
+    };
+    let cpus = args.threads.unwrap_or(num_cpus::get() as u32);
+    info!(
+        "Compressing a {} sample with {} settings.",
+        HumanBytes(sample.len() as u64),
+        bench::settings(&codecs, cpus).len()
     );
-    let mut archive = tar::Archive::new(&mut zstd_decoder);
+    let mut results = Vec::new();
+    let mut output = BufWriter::new(io::stdout().lock());
+    if !events::enabled() {
+        writeln!(
+            output,
+            "{:<8}{:>6}{:>9}{:>9}{:>14}",
+            "codec", "level", "threads", "ratio", "speed"
+        )?;
+    }
+    for setting in bench::settings(&codecs, cpus) {
+        let result = bench::measure(&sample, setting)?;
+        if !events::enabled() {
+            writeln!(
+                output,
+                "{:<8}{:>6}{:>9}{:>8.1}%{:>12}/s",
+                setting.codec.to_string(),
+                setting
+                    .level
+                    .map_or("-".to_string(), |level| level.to_string()),
+                setting.threads,
+                result.ratio() * 100.0,
+                HumanBytes(result.bytes_per_sec() as u64).to_string()
+            )?;
+            output.flush()?;
+        }
+        results.push(result);
+    }
+    let best = bench::recommend(&results, args.min_speed.map(|speed| speed as f64));
+    if let Some(best) = best {
+        let setting = best.setting;
+        let level = setting
+            .level
+            .map_or(String::new(), |level| format!(" --level {level}"));
+        info!(
+            "Recommended: --compression {}{level} --threads {}, {:.1}% at {}/s.",
+            setting.codec,
+            setting.threads,
+            best.ratio() * 100.0,
+            HumanBytes(best.bytes_per_sec() as u64)
+        );
+        if args
+            .min_speed
+            .is_some_and(|speed| best.bytes_per_sec() < speed as f64)
+        {
+            warn!("No setting reached --min-speed; recommending the fastest.");
+        }
+    }
+    Ok(json!({
+        "input": args.input.display().to_string(),
+        "sampled_bytes": sample.len(),
+        "results": results
+            .iter()
+            .map(|result| {
+                let mut value = json!(result);
+                value["ratio"] = json!(result.ratio());
+                value["bytes_per_sec"] = json!(result.bytes_per_sec());
+                value
+            })
+            .collect::<Vec<_>>(),
+        "recommended": best.map(|best| best.setting),
+    }))
+}
 
-    if let Some(parent) = output_path.parent() {
-        if !parent.exists() {
-            debug!(
-                "Output directory does not exist. Creating: {}",
-                parent.display()
-            );
-            fs::create_dir_all(parent)?;
+/// Prints the paths `protect` would archive and a size estimate, without reading keys or
+/// touching the output.
+fn dry_run(args: &ProtectArgs) -> Result<Value> {
+    if is_stdio(&args.input) {
+        return usage_error("--dry-run needs an input path, not standard input.");
+    }
+    info!("Planning protect of: {}", args.input.display());
+    let plan = archive_options(args)?.dry_run(&args.input)?;
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    for entry in &plan.entries {
+        if events::enabled() {
+            let kind = match entry.kind {
+                PlannedKind::File => "file",
+                PlannedKind::Directory => "directory",
+                PlannedKind::Symlink => "symlink",
+                PlannedKind::HardLink => "hardlink",
+            };
+            let event = json!({
+                "type": "entry",
+                "path": entry.path.display().to_string(),
+                "kind": kind,
+                "size": entry.size,
+            });
+            writeln!(stdout, "{event}")?;
+        } else if entry.kind == PlannedKind::Directory {
+            writeln!(stdout, "{}/", entry.path.display())?;
+        } else {
+            writeln!(stdout, "{}", entry.path.display())?;
         }
     }
-    archive.unpack(output_path)?;
-    debug!(
-        "Recovery complete. Files extracted to: {}",
-        output_path.display()
+    stdout.flush()?;
+    info!(
+        "Would archive {}, {} and {}: {}.",
+        counted(plan.files, "file", "files"),
+        counted(plan.directories, "directory", "directories"),
+        counted(plan.links, "link", "links"),
+        HumanBytes(plan.bytes)
     );
+    info!(
+        "Estimated size: {} compressed, {} written to {} (from a {} sample).",
+        HumanBytes(plan.estimated_compressed),
+        HumanBytes(plan.estimated_archive),
+        args.output.display(),
+        HumanBytes(plan.sampled_bytes)
+    );
+    Ok(json!({
+        "dry_run": true,
+        "input": args.input.display().to_string(),
+        "output": args.output.display().to_string(),
+        "files": plan.files,
+        "directories": plan.directories,
+        "links": plan.links,
+        "bytes_in": plan.bytes,
+        "sampled_bytes": plan.sampled_bytes,
+        "estimated_compressed_bytes": plan.estimated_compressed,
+        "estimated_bytes_out": plan.estimated_archive,
+    }))
+}
 
-    Ok(())
+/// Starts counting progress for the `--json` summary, drawing the progress bar if enabled.
+fn tally(bars: &Bars) -> Option<Arc<Tally>> {
+    let bar = bars.bar();
+    (bar.is_some() || events::enabled()).then(|| Arc::new(Tally::new(bar)))
+}
+
+/// Returns `count` followed by `one` or, unless it is 1, `many`, as in "1 file" and "2 files".
+fn counted(count: u64, one: &str, many: &str) -> String {
+    format!("{count} {}", if count == 1 { one } else { many })
+}
+
+/// Returns `part / whole`, or `None` if `whole` is zero.
+fn ratio(part: u64, whole: u64) -> Option<f64> {
+    (whole != 0).then(|| part as f64 / whole as f64)
+}
+
+fn protect(args: &ProtectArgs, bars: &Bars, non_interactive: bool) -> Result<Value> {
+    if args.base.is_some() && is_stdio(&args.input) {
+        return usage_error("--incremental needs a directory to compare, not standard input.");
+    }
+    if args.shares.is_some() && (is_stdio(&args.output) || is_remote(&args.output)) {
+        return usage_error(
+            "--shares writes share files next to the archive and needs a local output file.",
+        );
+    }
+    if args.sign.is_some() && (is_stdio(&args.output) || is_remote(&args.output)) {
+        return usage_error(
+            "--sign needs a local output file and cannot be used with standard output or \
+             remote storage.",
+        );
+    }
+    if args.verify_after_write && (is_stdio(&args.output) || is_remote(&args.output)) {
+        return usage_error(
+            "--verify-after-write reads the archive back and needs a local output file.",
+        );
+    }
+    if args.parity_sidecar && (is_stdio(&args.output) || is_remote(&args.output)) {
+        return usage_error(
+            "--parity-sidecar writes OUTPUT.ecc next to the archive and needs a local output file.",
+        );
+    }
+    if args.parity_sidecar && !args.mirror.is_empty() {
+        return usage_error(
+            "--parity-sidecar keeps parity out of the archive and cannot be used with --mirror.",
+        );
+    }
+    if args.resume {
+        check_resumable(args)?;
+    }
+    let _lock = lock_archive(&args.output, &args.lock)?;
+    // Open the outputs first, so an existing file is reported before any prompt.
+    let output = if args.resume {
+        if !args.force && args.output.exists() {
+            warn!("Output file already exists: {}", args.output.display());
+            return Err(anyhow!(
+                "{} already exists; use --force to replace it.",
+                args.output.display()
+            ));
+        }
+        None
+    } else {
+        Some(open_outputs(args, non_interactive)?)
+    };
+    if let (Some(count), Some(threshold)) = (args.shares, args.threshold)
+        && threshold > count
+    {
+        return usage_error(format!(
+            "--threshold {threshold} is more than the {count} shares."
+        ));
+    }
+    if args.shares.is_some() && !args.force {
+        for index in 1..=args.shares.unwrap_or(0) {
+            let path = share_path(&args.output, index);
+            if path.exists() {
+                warn!("Share file already exists: {}", path.display());
+                return Err(anyhow!(
+                    "{} already exists; use --force to replace it.",
+                    path.display()
+                ));
+            }
+        }
+    }
+    let signature_path = signature::signature_path(&args.output);
+    if args.sign.is_some() && !args.force && signature_path.exists() {
+        warn!(
+            "Signature file already exists: {}",
+            signature_path.display()
+        );
+        return Err(anyhow!(
+            "{} already exists; use --force to replace it.",
+            signature_path.display()
+        ));
+    }
+    let mut options = protect_options(args, bars, non_interactive)?;
+    let escrow = args.shares.map(|_| age::x25519::Identity::generate());
+    if let Some(escrow) = &escrow {
+        options = options.recipient(Box::new(escrow.to_public()));
+    }
+    let signing_key = args
+        .sign
+        .as_deref()
+        .map(|path| {
+            signature::read_secret_key(path, || keys::request_key_passphrase(non_interactive))
+        })
+        .transpose()?;
+    if let Some(prior) = &args.compare_to {
+        let hash = compare_contents(args, &options, prior, non_interactive)?;
+        let Some(hash) = hash else {
+            info!(
+                "Nothing changed since {}; not writing {}.",
+                prior.display(),
+                args.output.display()
+            );
+            return Ok(json!({
+                "input": args.input.display().to_string(),
+                "output": args.output.display().to_string(),
+                "compared_to": prior.display().to_string(),
+                "unchanged": true,
+            }));
+        };
+        options = options.record_content_hash(hash);
+    }
+    // The closing statistics and the catalog need the counts, even without a progress bar.
+    let tally = tally(bars).unwrap_or_else(|| Arc::new(Tally::new(None)));
+    options = options.progress(tally.clone());
+    let writing = Instant::now();
+    let output = match output {
+        Some(output) => {
+            if let Some(state_path) = &args.base {
+                let base = State::load(state_path)?;
+                if base.is_none() {
+                    info!(
+                        "State file {} does not exist yet; archiving everything.",
+                        state_path.display()
+                    );
+                }
+                let (output, state) =
+                    options.protect_incremental(&args.input, base.as_ref(), output)?;
+                let bytes_out = output.count();
+                output.into_inner().commit()?;
+                state.save(state_path)?;
+                info!(
+                    "Recorded {} entries in state file: {}",
+                    state.entries.len(),
+                    state_path.display()
+                );
+                bytes_out
+            } else {
+                let output = if is_stdio(&args.input) {
+                    options.protect_stream(io::stdin().lock(), output)?
+                } else {
+                    options.protect(&args.input, output)?
+                };
+                let bytes_out = output.count();
+                output.into_inner().commit()?;
+                bytes_out
+            }
+        }
+        None => {
+            let mut options = options.overwrite(args.force);
+            if let Some(bytes) = args.checkpoint_interval {
+                options = options.checkpoint_interval(bytes);
+            }
+            let checkpoint = resume::checkpoint_path(&args.output);
+            if checkpoint.exists() {
+                info!("Resuming from checkpoint: {}", checkpoint.display());
+            }
+            options.protect_resumable(&args.input, &args.output)?;
+            std::fs::metadata(&args.output)
+                .with_context(|| format!("Failed to read {}", args.output.display()))?
+                .len()
+        }
+    };
+    let elapsed = writing.elapsed().as_secs_f64();
+    if args.verify_after_write {
+        verify_written(args, escrow.as_ref(), bars, non_interactive)?;
+    }
+    let shares = match (&escrow, args.shares, args.threshold) {
+        (Some(escrow), Some(count), Some(threshold)) => {
+            write_shares(&args.output, escrow, threshold, count, args.force)?
+        }
+        _ => Vec::new(),
+    };
+    let signature = match &signing_key {
+        Some(key) => {
+            let path = signature::sign_file(&args.output, key, args.force)?;
+            info!("Signed archive into: {}", path.display());
+            Some(path.display().to_string())
+        }
+        None => None,
+    };
+
+    let bytes_in = tally.bytes();
+    let (payload_bytes, parity_bytes) = if args.parity_sidecar {
+        let sidecar_path = sidecar::path(&args.output);
+        info!("Wrote parity to sidecar: {}", sidecar_path.display());
+        (output, std::fs::metadata(&sidecar_path)?.len())
+    } else if args.armor || args.compat {
+        (output, 0)
+    } else {
+        let parity_bytes = fec::FecParams::with_redundancy(args.redundancy).parity_bytes(output);
+        (output - parity_bytes, parity_bytes)
+    };
+    let files = (!is_stdio(&args.input)).then(|| tally.entries());
+    let manifest_hash = tally.manifest_hash().map(|hash| hash.to_hex().to_string());
+    let throughput = (elapsed > 0.0).then(|| bytes_in as f64 / elapsed);
+    // The payload holds the tar and age headers too, so for small inputs it is larger than
+    // the input; the ratio is labelled for what it is.
+    info!(
+        "Archived {}: {} compressed to {}{}, with {} of parity ({}), {} in total.",
+        files.map_or("standard input".to_string(), |files| counted(
+            files, "file", "files"
+        )),
+        HumanBytes(bytes_in),
+        HumanBytes(payload_bytes),
+        ratio(payload_bytes, bytes_in).map_or(String::new(), |r| format!(
+            " ({:.1}% output/input)",
+            r * 100.0
+        )),
+        HumanBytes(parity_bytes),
+        ratio(parity_bytes, payload_bytes)
+            .map_or("none".to_string(), |r| format!("+{:.1}%", r * 100.0)),
+        HumanBytes(payload_bytes + parity_bytes),
+    );
+    info!(
+        "Took {elapsed:.2}s{}.",
+        throughput.map_or(String::new(), |t| format!(", {}/s", HumanBytes(t as u64)))
+    );
+    let mut summary = json!({
+        "input": args.input.display().to_string(),
+        "output": args.output.display().to_string(),
+        "files": files,
+        "bytes_in": bytes_in,
+        "bytes_out": output,
+        "compression_ratio": ratio(payload_bytes, bytes_in),
+        "parity_bytes": parity_bytes,
+        "parity_overhead": ratio(parity_bytes, payload_bytes),
+        "write_secs": elapsed,
+        "bytes_per_sec": throughput,
+        "manifest_hash": manifest_hash,
+        "signature": signature,
+        "shares": shares,
+        "mirrors": args.mirror.iter().map(|mirror| mirror.display().to_string()).collect::<Vec<_>>(),
+    });
+    if args.verify_after_write {
+        summary["verified"] = json!(true);
+    }
+    if let Some(path) = &args.catalog {
+        let run = catalog::Run {
+            time: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .ok()
+                .and_then(|d| chrono::DateTime::from_timestamp(d.as_secs() as i64, 0))
+                .map(|t| t.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+                .unwrap_or_default(),
+            hostname: naming::hostname(),
+            input: absolute(&args.input),
+            output: absolute(&args.output),
+            bytes_out: output,
+            files,
+            manifest_hash,
+            recipients: args.recipient.clone(),
+            recipients_files: args
+                .recipients_file
+                .iter()
+                .map(|path| absolute(Path::new(path)))
+                .collect(),
+            identity_files: args
+                .identity_file
+                .iter()
+                .map(|path| absolute(Path::new(path)))
+                .collect(),
+            passphrase: args.passphrase,
+            ..catalog::Run::default()
+        };
+        // The archive is already written, so a catalog that cannot be updated is no failure.
+        match catalog::append(path, run) {
+            Ok(id) => {
+                debug!("Recorded run {id} in catalog {}", path.display());
+                summary["catalog_id"] = json!(id);
+            }
+            Err(e) => warn!("Failed to record the run in the catalog: {e:#}"),
+        }
+    }
+    Ok(summary)
+}
+
+/// Reads the archive protect just wrote back from disk for `--verify-after-write`.
+///
+/// The error correction layer and the age header are always checked. The payload is
+/// decrypted and its checksums compared as well when sage holds a key that opens it: the
+/// one-time key of `--shares`, or the identity files given with `-i`.
+fn verify_written(
+    args: &ProtectArgs,
+    escrow: Option<&age::x25519::Identity>,
+    bars: &Bars,
+    non_interactive: bool,
+) -> Result<()> {
+    info!("Verifying written archive: {}", args.output.display());
+    drop_cached(&args.output);
+    let options = if let Some(escrow) = escrow {
+        Some(RecoverOptions::new().identity(Box::new(escrow.clone())))
+    } else if !args.identity_file.is_empty() || !args.identity_from_keyring.is_empty() {
+        Some(recover_options(
+            &args.identity_file,
+            &args.identity_from_keyring,
+            bars,
+            non_interactive,
+        )?)
+    } else {
+        info!(
+            "No identity to decrypt with; checking only the error correction layer and the age header."
+        );
+        None
+    };
+    verify::verify_file(&args.output, options.as_ref(), None).map_err(|e| {
+        error!("Written archive failed verification: {e:#}");
+        anyhow!(
+            "{} was written but does not read back correctly: {e:#}",
+            args.output.display()
+        )
+    })?;
+    debug!("Written archive verified.");
+    Ok(())
+}
+
+/// Asks the kernel to forget the cached pages of `path`, so reading it back comes from the
+/// disk rather than from memory.
+#[cfg(target_os = "linux")]
+fn drop_cached(path: &Path) {
+    use std::os::fd::AsRawFd;
+    if let Ok(file) = std::fs::File::open(path) {
+        // SAFETY: the descriptor is open for the whole call. The archive was flushed to disk
+        // when it was committed, so its pages are clean and can be dropped.
+        unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn drop_cached(_path: &Path) {}
+
+/// Returns `path` made absolute, as text, unless it is `-` or a URL.
+fn absolute(path: &Path) -> String {
+    if is_stdio(path) || is_remote(path) {
+        return path.display().to_string();
+    }
+    std::path::absolute(path)
+        .map(|path| path.canonicalize().unwrap_or(path))
+        .unwrap_or_else(|_| path.to_path_buf())
+        .display()
+        .to_string()
+}
+
+/// Returns the path of share `index` of the archive at `archive_path`.
+fn share_path(archive_path: &Path, index: u8) -> PathBuf {
+    let mut path = archive_path.as_os_str().to_owned();
+    path.push(format!(".share{index}"));
+    PathBuf::from(path)
+}
+
+/// Splits `identity` into `count` shares next to the archive at `archive_path`, any
+/// `threshold` of which recover it, and returns their paths.
+fn write_shares(
+    archive_path: &Path,
+    identity: &age::x25519::Identity,
+    threshold: u8,
+    count: u8,
+    force: bool,
+) -> Result<Vec<String>> {
+    let secret = identity.to_string();
+    let shares = shamir::split(secret.expose_secret().as_bytes(), threshold, count)?;
+    let name = archive_path.file_name().map_or_else(
+        || archive_path.display().to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    );
+    let mut paths = Vec::with_capacity(shares.len());
+    for share in &shares {
+        let path = share_path(archive_path, share.index);
+        let mut file = AtomicFile::create(&path, DEFAULT_OUTPUT_MODE, force)
+            .with_context(|| format!("Failed to create share file: {}", path.display()))?;
+        writeln!(
+            file,
+            "# sage key share {} of {count} for {name}; any {threshold} shares recover it.",
+            share.index
+        )?;
+        writeln!(
+            file,
+            "# Keep it apart from the archive and the other shares."
+        )?;
+        writeln!(file, "{share}")?;
+        file.commit()?;
+        paths.push(path.display().to_string());
+    }
+    info!(
+        "Wrote {count} key shares for {}; any {threshold} of them recover it.",
+        archive_path.display()
+    );
+    Ok(paths)
+}
+
+/// Makes the options to decrypt an archive with the identities and shares of `identity`.
+fn identity_options(
+    identity: &IdentityArgs,
+    bars: &Bars,
+    non_interactive: bool,
+) -> Result<RecoverOptions> {
+    let options = recover_options(
+        &identity.identity_file,
+        &identity.identity_from_keyring,
+        bars,
+        non_interactive,
+    )?;
+    Ok(match share_identity(&identity.shares)? {
+        Some(share) => options.identity(Box::new(share)),
+        None => options,
+    })
+}
+
+/// Recovers the identity split into the share files at `paths`, if any are given.
+fn share_identity(paths: &[PathBuf]) -> Result<Option<age::x25519::Identity>> {
+    if paths.is_empty() {
+        return Ok(None);
+    }
+    let mut shares = Vec::with_capacity(paths.len());
+    for path in paths {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read share file: {}", path.display()))?;
+        let share: shamir::Share = text
+            .parse()
+            .with_context(|| format!("Invalid share file: {}", path.display()))?;
+        shares.push(share);
+    }
+    let secret = shamir::combine(&shares).map_err(|e| match e {
+        sage::Error::NoIdentities => anyhow::Error::new(e).context(format!(
+            "Too few different shares given; {} are needed.",
+            shares[0].threshold
+        )),
+        e => e.into(),
+    })?;
+    let identity = std::str::from_utf8(&secret)
+        .ok()
+        .and_then(|secret| secret.parse::<age::x25519::Identity>().ok())
+        .ok_or_else(|| {
+            warn!("The shares do not combine into a key.");
+            anyhow!("The shares do not combine into a key; they may be damaged.")
+        })?;
+    debug!("Recovered the key from {} shares.", shares.len());
+    Ok(Some(identity))
+}
+
+/// Hashes the input for `--compare-to`, returning `None` if the content hash recorded in
+/// `prior` matches, or otherwise the hash to record in the new archive.
+fn compare_contents(
+    args: &ProtectArgs,
+    options: &ProtectOptions,
+    prior: &Path,
+    non_interactive: bool,
+) -> Result<Option<blake3::Hash>> {
+    if is_stdio(&args.input) {
+        return usage_error(
+            "--compare-to needs a file or directory to hash, since standard input can only be \
+             read once.",
+        );
+    }
+    let recorded = if !is_remote(prior) && !prior.exists() {
+        info!(
+            "{} does not exist yet; protecting the input.",
+            prior.display()
+        );
+        None
+    } else {
+        let archive = archive_info::read_info(open_input(prior, non_interactive)?)
+            .with_context(|| format!("Failed to read the header of {}", prior.display()))?;
+        let recorded = archive.header.and_then(|header| header.content_hash);
+        if recorded.is_none() {
+            info!(
+                "{} does not record a content hash; protecting the input.",
+                prior.display()
+            );
+        }
+        recorded
+    };
+
+    info!(
+        "Hashing {} to compare with {}.",
+        args.input.display(),
+        prior.display()
+    );
+    let hash = options.hash_contents(&args.input)?;
+    debug!("Content hash: {}", hash.to_hex());
+    if recorded.is_some_and(|recorded| recorded == *hash.as_bytes()) {
+        return Ok(None);
+    }
+    if recorded.is_some() {
+        info!("The input changed since {}.", prior.display());
+    }
+    Ok(Some(hash))
+}
+
+/// The core recovery pipeline: correct errors -> decrypt -> decompress -> extract.
+fn recover(
+    args: &RecoverArgs,
+    output_path: &Path,
+    bars: &Bars,
+    non_interactive: bool,
+) -> Result<Value> {
+    if is_remote(output_path) {
+        return usage_error("recover extracts to a local path and cannot write to remote storage.");
+    }
+    let mut options = identity_options(&args.identity, bars, non_interactive)?
+        .overwrite(args.force)
+        .keep_corrupt(args.keep_corrupt)
+        .strip_components(args.strip_components)
+        .allow_absolute_paths(args.allow_absolute_paths)
+        .normalize_filenames(args.normalize_filenames)
+        .on_conflict(args.on_conflict);
+    if let Some(threads) = args.threads {
+        options = options.threads(threads as usize);
+    }
+    if let Some(window_log) = args.max_window_log {
+        options = options.max_window_log(window_log);
+    }
+    if let Some(bytes) = args.max_memory {
+        options = options.max_memory(bytes);
+    }
+    if let Some(uid_map) = args.uid_map.clone() {
+        options = options.uid_map(uid_map);
+    }
+    if let Some(gid_map) = args.gid_map.clone() {
+        options = options.gid_map(gid_map);
+    }
+    if let Some(preserve) = args.preserve.owner() {
+        options = options.preserve_owner(preserve);
+    }
+    if let Some(preserve) = args.preserve.permissions() {
+        options = options.preserve_permissions(preserve);
+    }
+    if let Some(preserve) = args.preserve.mtime() {
+        options = options.preserve_mtime(preserve);
+    }
+    if let Some(preserve) = args.preserve.xattrs() {
+        options = options.preserve_xattrs(preserve);
+    }
+    if !args.paths.is_empty() {
+        options = options.select(PathSelector::new(&args.paths)?);
+    }
+    verify_signatures(args)?;
+    let (input, input_len) = open_input_seekable(&args.input, non_interactive)?;
+    let tally = tally(bars);
+    if let Some(tally) = &tally {
+        if let Some(len) = input_len {
+            tally.set_total(len);
+        }
+        options = options.progress(tally.clone());
+    }
+    let mut salvage = None;
+    let mut repairs;
+    if is_stdio(output_path) {
+        if !args.increments.is_empty() {
+            return usage_error("Increments can only be applied when extracting to a directory.");
+        }
+        if args.salvage {
+            return usage_error(
+                "--salvage extracts to a directory and cannot write to standard output.",
+            );
+        }
+        options.recover_to_writer(input, BufWriter::new(io::stdout().lock()))?;
+        repairs = take_repairs(&options, &args.input);
+    } else {
+        // An archive read through its parity sidecar is repaired by the sidecar reader, so
+        // it is not opened again by path.
+        let sidecar = sidecar::path(&args.input).exists();
+        // Neither can a remote archive, which is read with ranged requests instead.
+        let reopen = !sidecar && !is_remote(&args.input);
+        salvage = match input {
+            Input::Seekable(input) if !reopen && args.salvage => {
+                Some(options.salvage(input, output_path)?)
+            }
+            Input::Seekable(input) if !reopen => {
+                options.recover_seekable(input, output_path)?;
+                None
+            }
+            // Opened again by path, so that damage can be read past and seekable archives
+            // can be read on several threads.
+            Input::Seekable(_) if args.salvage => {
+                Some(options.salvage_file(&args.input, output_path)?)
+            }
+            Input::Stream(input) if args.salvage => Some(options.salvage(input, output_path)?),
+            Input::Seekable(_) => {
+                options.recover_file(&args.input, output_path)?;
+                None
+            }
+            Input::Stream(input) => {
+                options.recover(input, output_path)?;
+                None
+            }
+        };
+        repairs = take_repairs(&options, &args.input);
+        for increment in &args.increments {
+            info!("Applying increment: {}", increment.display());
+            options.recover(open_input(increment, non_interactive)?, output_path)?;
+            repairs.extend(take_repairs(&options, increment));
+        }
+    }
+    Ok(json!({
+        "input": args.input.display().to_string(),
+        "output": output_path.display().to_string(),
+        "increments": args.increments.len(),
+        "entries": tally.as_ref().map(|tally| tally.entries()),
+        "bytes_in": tally.as_ref().map(|tally| tally.bytes()),
+        "repairs": repairs,
+        "salvage": salvage.map(|salvage: Salvage| {
+            let complete = salvage.is_complete();
+            let mut report = json!(salvage);
+            report["complete"] = json!(complete);
+            report
+        }),
+    }))
+}
+
+/// Logs the error correction groups that `options` had to repair in `archive`, and returns
+/// them for the summary.
+fn take_repairs(options: &RecoverOptions, archive: &Path) -> Vec<Value> {
+    let repairs = options.take_repairs();
+    if repairs.is_empty() {
+        return Vec::new();
+    }
+    let shards: u64 = repairs.iter().map(|repair| repair.shards).sum();
+    let groups = repairs.iter().filter(|repair| !repair.header).count();
+    if groups == 0 {
+        warn!(
+            "Error correction rebuilt the damaged file header of {}; the medium holding it \
+             may be degrading.",
+            archive.display()
+        );
+    } else {
+        warn!(
+            "Error correction repaired {} in {} of {}; the medium holding it may be degrading.",
+            counted(shards, "damaged shard", "damaged shards"),
+            counted(groups as u64, "group", "groups"),
+            archive.display()
+        );
+    }
+    repairs
+        .into_iter()
+        .map(|repair| {
+            if repair.header {
+                warn!(
+                    "File header (bytes {}..{}): rebuilt from the first shard.",
+                    repair.offset,
+                    repair.offset + repair.len
+                );
+            }
+            let near = (repair.entry.as_ref())
+                .map(|entry| format!(", near {entry}"))
+                .unwrap_or_default();
+            for range in &repair.ranges {
+                warn!(
+                    "Group {} shard {} (bytes {}..{}): repaired{near}.",
+                    repair.group,
+                    range.index,
+                    range.offset,
+                    range.offset + range.len
+                );
+            }
+            let mut value = json!(repair);
+            value["archive"] = json!(archive.display().to_string());
+            value
+        })
+        .collect()
+}
+
+/// Whether the summary of a recovery lists groups that error correction repaired.
+fn has_repairs(summary: &Value) -> bool {
+    summary["repairs"]
+        .as_array()
+        .is_some_and(|repairs| !repairs.is_empty())
+}
+
+/// Checks the signatures of the archive and its increments, if `--verify-signature` is given.
+fn verify_signatures(args: &RecoverArgs) -> Result<()> {
+    let Some(key) = &args.verify_signature else {
+        return Ok(());
+    };
+    if is_stdio(&args.input) || is_remote(&args.input) {
+        return usage_error("--verify-signature reads the archive twice and needs a local file.");
+    }
+    let key = signature::read_public_key(key)?;
+    let input_signature = args
+        .signature
+        .clone()
+        .unwrap_or_else(|| signature::signature_path(&args.input));
+    signature::verify_file(&args.input, &input_signature, &key)?;
+    for increment in &args.increments {
+        signature::verify_file(increment, &signature::signature_path(increment), &key)?;
+    }
+    Ok(())
+}
+
+/// Runs `recover` into a sink for `--test`: the archive and its increments are corrected,
+/// decrypted, decompressed and unpacked entry by entry, and every checksum is checked, but
+/// nothing is written.
+fn test_recover(args: &RecoverArgs, bars: &Bars, non_interactive: bool) -> Result<Value> {
+    let mut options = identity_options(&args.identity, bars, non_interactive)?;
+    if let Some(window_log) = args.max_window_log {
+        options = options.max_window_log(window_log);
+    }
+    if let Some(bytes) = args.max_memory {
+        options = options.max_memory(bytes);
+    }
+    verify_signatures(args)?;
+    let (input, input_len) = open_input_with_len(&args.input, non_interactive)?;
+    let tally = tally(bars);
+    if let Some(tally) = &tally {
+        if let Some(len) = input_len {
+            tally.set_total(len);
+        }
+        options = options.progress(tally.clone());
+    }
+    let mut stats = verify::verify_contents(input, &options)?;
+    let mut repairs = take_repairs(&options, &args.input);
+    for increment_path in &args.increments {
+        info!("Testing increment: {}", increment_path.display());
+        let increment =
+            verify::verify_contents(open_input(increment_path, non_interactive)?, &options)?;
+        repairs.extend(take_repairs(&options, increment_path));
+        stats.entries += increment.entries;
+        stats.bytes += increment.bytes;
+        stats.checked_files += increment.checked_files;
+    }
+    Ok(json!({
+        "input": args.input.display().to_string(),
+        "test": true,
+        "increments": args.increments.len(),
+        "entries": stats.entries,
+        "bytes": stats.bytes,
+        "checked_files": stats.checked_files,
+        "bytes_in": tally.as_ref().map(|tally| tally.bytes()),
+        "repairs": repairs,
+    }))
+}
+
+/// Prints the entries of an archive to standard output.
+///
+/// With `json`, the entries are printed as a JSON array, like `recover --manifest-out`.
+fn list(args: &ListArgs, json: bool, bars: &Bars, non_interactive: bool) -> Result<Value> {
+    let options = identity_options(&args.identity, bars, non_interactive)?;
+    let (input, _) = open_input_seekable(&args.input, non_interactive)?;
+    let output = BufWriter::new(io::stdout().lock());
+
+    let count = match (input, json) {
+        (Input::Seekable(input), true) => options.write_manifest_seekable(input, output)?,
+        (Input::Seekable(input), false) => {
+            options.write_listing_seekable(input, output, args.long)?
+        }
+        (Input::Stream(input), true) => options.write_manifest(input, output)?,
+        (Input::Stream(input), false) => options.write_listing(input, output, args.long)?,
+    };
+    debug!("Listing complete. {} entries listed.", count);
+
+    Ok(json!({ "input": args.input.display().to_string(), "entries": count }))
+}
+
+/// Writes the contents of one archived file to standard output.
+fn cat(args: &CatArgs, bars: &Bars, non_interactive: bool) -> Result<Value> {
+    let options = identity_options(&args.identity, bars, non_interactive)?;
+    let (input, _) = open_input_seekable(&args.input, non_interactive)?;
+    let output = BufWriter::new(io::stdout().lock());
+
+    let bytes = match input {
+        Input::Seekable(input) => options.cat_seekable(input, &args.path, output)?,
+        Input::Stream(input) => options.cat(input, &args.path, output)?,
+    };
+    debug!("Wrote {} bytes of {}.", bytes, args.path.display());
+
+    Ok(json!({
+        "input": args.input.display().to_string(),
+        "path": args.path.display().to_string(),
+        "bytes": bytes,
+    }))
+}
+
+/// Serves an archive as a read-only file system until it is unmounted.
+#[cfg(target_os = "linux")]
+fn mount(args: &MountArgs, bars: &Bars, non_interactive: bool) -> Result<()> {
+    if is_stdio(&args.input) || is_remote(&args.input) {
+        return usage_error("mount reads the archive out of order and needs a local file.");
+    }
+    let options = identity_options(&args.identity, bars, non_interactive)?;
+    if let Some(reader) = open_input_sidecar(&args.input)? {
+        return Ok(options.mount(reader, &args.mountpoint)?);
+    }
+    let (input, _) = open_input_file(&args.input)?;
+    Ok(options.mount(input, &args.mountpoint)?)
+}
+
+/// Serves an archive as a read-only file system: not on this platform.
+#[cfg(not(target_os = "linux"))]
+fn mount(_args: &MountArgs, _bars: &Bars, _non_interactive: bool) -> Result<()> {
+    Err(anyhow!("mount is only available on Linux."))
+}
+
+/// Checks an archive end to end; without identities only the error correction layer.
+///
+/// Returns the spare shards every group has left, if the archive has error correction.
+fn verify(args: &VerifyArgs, bars: &Bars, non_interactive: bool) -> Result<Option<u64>> {
+    if is_stdio(&args.input) || is_remote(&args.input) {
+        return usage_error("verify reads the archive twice and needs a local file.");
+    }
+    if args.identity.is_empty() {
+        return Ok(verify::verify_file(&args.input, None, args.simulate_loss)?);
+    }
+    let options = identity_options(&args.identity, bars, non_interactive)?;
+    Ok(verify::verify_file(
+        &args.input,
+        Some(&options),
+        args.simulate_loss,
+    )?)
+}
+
+/// Prints what changed in DIR since the archive was made, one path per line.
+fn diff(args: &DiffArgs, bars: &Bars, non_interactive: bool) -> Result<Value> {
+    if !args.dir.exists() {
+        return Err(anyhow!("{} does not exist.", args.dir.display()));
+    }
+    let options = identity_options(&args.identity, bars, non_interactive)?;
+    let exclude = ExcludeSet::new(&args.exclude)?;
+    let (input, _) = open_input_seekable(&args.archive, non_interactive)?;
+    let differences = match input {
+        Input::Seekable(input) => options.diff_seekable(input, &args.dir, &exclude)?,
+        Input::Stream(input) => options.diff(input, &args.dir, &exclude)?,
+    };
+    if !events::enabled() {
+        let mut output = BufWriter::new(io::stdout().lock());
+        for difference in &differences {
+            writeln!(output, "{} {}", difference.change.mark(), difference.path)?;
+        }
+        output.flush()?;
+    }
+    let count = |change| {
+        differences
+            .iter()
+            .filter(|difference| difference.change == change)
+            .count()
+    };
+    if differences.is_empty() {
+        info!("{} matches the archive.", args.dir.display());
+    } else {
+        info!(
+            "{} added, {} removed, {} modified, {} changed type.",
+            count(diff::Change::Added),
+            count(diff::Change::Removed),
+            count(diff::Change::Modified),
+            count(diff::Change::TypeChanged)
+        );
+    }
+    Ok(json!({
+        "archive": args.archive.display().to_string(),
+        "dir": args.dir.display().to_string(),
+        "added": count(diff::Change::Added),
+        "removed": count(diff::Change::Removed),
+        "modified": count(diff::Change::Modified),
+        "type_changed": count(diff::Change::TypeChanged),
+        "differences": differences,
+    }))
+}
+
+/// Prints the unencrypted header fields of an archive.
+///
+/// With `--json`, they are only given in the summary.
+fn info(args: &InfoArgs, non_interactive: bool) -> Result<Value> {
+    let archive = archive_info::read_info(open_input(&args.input, non_interactive)?)?;
+    // A damaged header only yields the geometry, from the first shard.
+    let header = archive.header.filter(|header| header.version > 0);
+    let sidecar = if is_stdio(&args.input) || is_remote(&args.input) {
+        None
+    } else {
+        open_input_sidecar(&args.input)?
+    };
+    let sidecar_path = sidecar
+        .as_ref()
+        .map(|_| sidecar::path(&args.input).display().to_string());
+    let params = archive
+        .header
+        .map(|header| header.params)
+        .or(sidecar.map(|reader| reader.params()));
+    let layout = match archive.layout {
+        Layout::ErrorCorrected => "error-corrected",
+        Layout::Armored => "armored",
+        Layout::PlainAge => "plain age",
+    };
+    let codec = header.and_then(|header| header.codec);
+    let level = header
+        .map(|header| header.compression_level)
+        .filter(|&level| level > 0);
+    let created = header
+        .and_then(|header| header.created)
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs as i64, 0))
+        .map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true));
+    let tool_version = header
+        .and_then(|header| header.tool_version)
+        .map(|[major, minor, patch]| format!("{major}.{minor}.{patch}"));
+    let content_hash = header
+        .and_then(|header| header.content_hash)
+        .map(|hash| blake3::Hash::from_bytes(hash).to_hex().to_string());
+    let mut stanza_types = std::collections::BTreeMap::<&str, usize>::new();
+    for kind in &archive.stanzas {
+        *stanza_types.entry(kind).or_default() += 1;
+    }
+
+    if !events::enabled() {
+        let unknown = || "unknown".to_string();
+        println!("Archive:          {}", args.input.display());
+        match (archive.layout, header) {
+            (Layout::ErrorCorrected, Some(header)) => println!(
+                "Layout:           {layout}, format version {}",
+                header.version
+            ),
+            (Layout::ErrorCorrected, None) => {
+                println!("Layout:           {layout}, header damaged")
+            }
+            _ => println!("Layout:           {layout}, no sage header"),
+        }
+        println!(
+            "Made by:          {}",
+            tool_version
+                .as_ref()
+                .map_or_else(unknown, |v| format!("sage {v}"))
+        );
+        println!(
+            "Created:          {}",
+            created.clone().unwrap_or_else(unknown)
+        );
+        let compression = match (codec, level) {
+            (None, _) => unknown(),
+            (Some(Codec::None), _) => "none".to_string(),
+            (Some(codec), Some(level)) => format!("{codec} level {level}"),
+            (Some(codec), None) => codec.to_string(),
+        };
+        println!("Compression:      {compression}");
+        let contents = match header {
+            None => unknown(),
+            Some(header) if header.stream => "a single stream".to_string(),
+            Some(header) if header.dedup => "tar archive, deduplicated".to_string(),
+            Some(header) if header.seekable => "tar archive, in seekable frames".to_string(),
+            Some(_) => "tar archive".to_string(),
+        };
+        let contents = match header {
+            Some(header) if header.dictionary => format!("{contents}, with a dictionary"),
+            _ => contents,
+        };
+        println!("Contents:         {contents}");
+        if let Some(window_log) = header.and_then(|header| header.window_log) {
+            println!(
+                "Window:           {} of long-distance matching, needed in memory to recover",
+                HumanBytes(1 << window_log)
+            );
+        }
+        if let Some(hash) = &content_hash {
+            println!("Content hash:     {hash}");
+        }
+        match params {
+            Some(params) => println!(
+                "Error correction: {} data + {} parity shards of {} ({:.1}% redundancy){}",
+                params.data_shards,
+                params.parity_shards,
+                HumanBytes(params.shard_size as u64),
+                params.redundancy(),
+                match (
+                    &sidecar_path,
+                    header.and_then(|header| header.stripe_groups)
+                ) {
+                    (Some(path), _) => format!(", in {path}"),
+                    (None, Some(groups)) => format!(", interleaved in stripes of {groups} groups"),
+                    (None, None) => String::new(),
+                }
+            ),
+            None => println!("Error correction: none"),
+        }
+        let types: Vec<String> = stanza_types
+            .iter()
+            .map(|(kind, count)| match count {
+                1 => kind.to_string(),
+                _ => format!("{count} × {kind}"),
+            })
+            .collect();
+        if header.is_some_and(|header| header.unencrypted) {
+            println!("Recipients:       none, not encrypted");
+        } else {
+            println!(
+                "Recipients:       {} ({})",
+                archive.stanzas.len(),
+                types.join(", ")
+            );
+        }
+    }
+
+    Ok(json!({
+        "input": args.input.display().to_string(),
+        "layout": layout,
+        "format_version": header.map(|header| header.version),
+        "tool_version": tool_version,
+        "created": created,
+        "codec": codec.map(|codec| codec.to_string()),
+        "compression_level": level,
+        "stream": header.map(|header| header.stream),
+        "dedup": header.map(|header| header.dedup),
+        "seekable": header.map(|header| header.seekable),
+        "dictionary": header.map(|header| header.dictionary),
+        "encrypted": header.map(|header| !header.unencrypted),
+        "window_log": header.and_then(|header| header.window_log),
+        "content_hash": content_hash,
+        "data_shards": params.map(|params| params.data_shards),
+        "parity_shards": params.map(|params| params.parity_shards),
+        "shard_size": params.map(|params| params.shard_size),
+        "redundancy_percent": params.map(|params| params.redundancy()),
+        "parity_sidecar": sidecar_path,
+        "stripe_groups": header.and_then(|header| header.stripe_groups),
+        "recipients": archive.stanzas.len(),
+        "stanza_types": stanza_types,
+    }))
+}
+
+/// Rebuilds a clean archive from a damaged one using its parity data.
+fn repair(args: &RepairArgs, non_interactive: bool) -> Result<Value> {
+    let _lock = lock_archive(&args.input, &args.lock)?;
+    if !is_stdio(&args.input)
+        && !is_remote(&args.input)
+        && let Some(reader) = open_input_sidecar(&args.input)?
+    {
+        return repair_sidecar(args, reader, non_interactive);
+    }
+    let input = CancelReader::new(open_input(&args.input, non_interactive)?);
+    let output = open_output(&args.output, args.output_mode, args.force, non_interactive)?;
+
+    let (stats, output) = fec::repair(input, output).map_err(|e| {
+        if fec::newer_format(&e).is_some() {
+            anyhow::Error::new(e)
+        } else {
+            anyhow::Error::new(e).context("Failed to repair archive")
+        }
+    })?;
+    output.commit()?;
+    info!(
+        "Checked {}: repaired {} in {}.",
+        counted(stats.groups, "group", "groups"),
+        counted(stats.repaired_shards, "damaged shard", "damaged shards"),
+        counted(stats.repaired_groups, "group", "groups")
+    );
+
+    Ok(json!({
+        "input": args.input.display().to_string(),
+        "output": args.output.display().to_string(),
+        "groups": stats.groups,
+        "repaired_groups": stats.repaired_groups,
+        "repaired_shards": stats.repaired_shards,
+    }))
+}
+
+/// Takes the lock on the local archive `path` unless `--no-lock` is given, waiting for it
+/// with `--wait`.
+fn lock_archive(path: &Path, args: &LockArgs) -> Result<Option<lock::Lock>> {
+    if args.no_lock || is_stdio(path) || is_remote(path) {
+        return Ok(None);
+    }
+    Ok(Some(lock::Lock::acquire(path, args.wait)?))
+}
+
+/// Rewrites a plain age file repaired with its parity sidecar, and a new sidecar for it.
+fn repair_sidecar(
+    args: &RepairArgs,
+    mut reader: sidecar::SidecarReader<File, File>,
+    non_interactive: bool,
+) -> Result<Value> {
+    if is_stdio(&args.output) || is_remote(&args.output) {
+        return usage_error(format!(
+            "{} has a parity sidecar, so the repaired archive needs a local output file for \
+             its new sidecar.",
+            args.input.display()
+        ));
+    }
+    let mut output = open_sidecar_output(
+        &args.output,
+        reader.params(),
+        args.output_mode,
+        args.force,
+        non_interactive,
+    )?;
+    io::copy(&mut CancelReader::new(&mut reader), &mut output)
+        .context("Failed to repair archive")?;
+    output.commit()?;
+    let stats = reader.stats();
+    info!(
+        "Checked {}: repaired {} in {}.",
+        counted(stats.groups, "group", "groups"),
+        counted(stats.repaired_shards, "damaged shard", "damaged shards"),
+        counted(stats.repaired_groups, "group", "groups")
+    );
+
+    Ok(json!({
+        "input": args.input.display().to_string(),
+        "output": args.output.display().to_string(),
+        "groups": stats.groups,
+        "repaired_groups": stats.repaired_groups,
+        "repaired_shards": stats.repaired_shards,
+    }))
+}
+
+/// Protects a directory now and whenever it changes, until interrupted or a setup error.
+///
+/// A run that fails is logged and the directory is watched on, so a file that vanished
+/// while it was read does not end a long-running watch. Interrupting it, the usual way to
+/// stop it, is a success.
+fn watch(args: &WatchArgs, bars: &Bars, non_interactive: bool) -> Result<()> {
+    let protect_args = &args.protect;
+    let input = &protect_args.input;
+    let template = protect_args.output.to_string_lossy().into_owned();
+    if protect_args.dry_run || protect_args.compare_to.is_some() {
+        return usage_error("--dry-run and --compare-to cannot be used with watch.");
+    }
+    if protect_args.shares.is_some() {
+        return usage_error(
+            "--shares would hand out new shares on every run and cannot be used with watch.",
+        );
+    }
+    if is_stdio(input) || !input.is_dir() {
+        return Err(anyhow!("{} is not a directory to watch.", input.display()));
+    }
+    if is_stdio(&protect_args.output) {
+        return Err(anyhow!(
+            "watch writes an archive on every change and cannot write to standard output."
+        ));
+    }
+    let directory = naming::is_directory(&protect_args.output);
+    let unique = if directory {
+        let name_template = protect_args.name_template.as_deref();
+        naming::check_template(name_template.unwrap_or(naming::DEFAULT_TEMPLATE))?;
+        name_template.is_none_or(|name_template| name_template.contains("{date}"))
+    } else {
+        template.contains(TIMESTAMP_PLACEHOLDER)
+    };
+    if !unique && !protect_args.force {
+        return Err(anyhow!(
+            "OUTPUT needs {TIMESTAMP_PLACEHOLDER}, or the name template {{date}}, so every run \
+             writes a new archive, or --force to replace the same archive every time."
+        ));
+    }
+    let root = input
+        .canonicalize()
+        .with_context(|| format!("Failed to open {}", input.display()))?;
+    let written = [
+        Some(protect_args.output.as_path()),
+        protect_args.base.as_deref(),
+    ];
+    for path in written
+        .into_iter()
+        .flatten()
+        .filter(|path| !is_remote(path))
+    {
+        let parent = match path.parent() {
+            _ if directory && path == protect_args.output => path,
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        if parent
+            .canonicalize()
+            .is_ok_and(|parent| parent.starts_with(&root))
+        {
+            return Err(anyhow!(
+                "{} is inside {}, so writing it would count as a change; keep it outside.",
+                path.display(),
+                input.display()
+            ));
+        }
+    }
+    if protect_args.passphrase && keys::supplied_passphrase()?.is_none() {
+        // Every run encrypts with the same passphrase, so it is asked for only once.
+        keys::remember_passphrase(keys::read_new_passphrase(non_interactive)?);
+    }
+
+    let mut watcher = watch::Watcher::new(&root)
+        .with_context(|| format!("Failed to watch {}", input.display()))?;
+    let quiet_period = Duration::from_secs(args.quiet_period);
+    let watched = loop {
+        if !protect_watched(protect_args, &template, bars, non_interactive) {
+            break Ok(());
+        }
+        info!("Waiting for changes in {}", input.display());
+        if let Err(e) = watcher.wait(None) {
+            break Err(e);
+        }
+        debug!(
+            "Change detected; waiting until nothing changed for {} seconds.",
+            args.quiet_period
+        );
+        if let Err(e) = watcher.settle(quiet_period) {
+            break Err(e);
+        }
+    };
+    match watched {
+        Err(e) if !sage::cancel::is_requested() => Err(e.into()),
+        _ => {
+            info!("Stopped watching {}.", input.display());
+            Ok(())
+        }
+    }
+}
+
+/// Runs one protect for [`watch`], naming the output after the current time. Returns
+/// whether to watch on, which is not the case once interrupted.
+fn protect_watched(args: &ProtectArgs, template: &str, bars: &Bars, non_interactive: bool) -> bool {
+    let started = Instant::now();
+    let now = std::time::SystemTime::now();
+    let mut args = args.clone();
+    args.output = template
+        .replace(TIMESTAMP_PLACEHOLDER, &naming::timestamp(now))
+        .into();
+    if let Err(e) = name_output(&mut args, now) {
+        error!("Failed to name the archive: {e}");
+        return true;
+    }
+
+    info!("Protecting: {}", args.input.display());
+    let summary = match protect(&args, bars, non_interactive) {
+        Ok(summary) => summary,
+        Err(_) if sage::cancel::is_requested() => {
+            info!("Interrupted; removed the unfinished archive.");
+            return false;
+        }
+        Err(e) => {
+            error!("Failed to protect file: {e}");
+            return true;
+        }
+    };
+    info!("Successfully protected file to: {}", args.output.display());
+    if let Some(command) = &args.post_command {
+        match hooks::run_post_command(command, &args.output) {
+            Ok(status) if !status.success() => error!("Post-command failed with {status}"),
+            Ok(_) => {}
+            Err(e) => error!("{e:#}"),
+        }
+    }
+    events::summary("protect", summary, started);
+    true
+}
+
+/// Rewrites an archive in the current format, checking first that the identities open it.
+fn convert(args: &ConvertArgs, bars: &Bars, non_interactive: bool) -> Result<Value> {
+    if !args.recipient.is_empty() || !args.recipients_file.is_empty() {
+        return import(args, bars, non_interactive);
+    }
+    let check_identities = !args.identity.is_empty();
+    if check_identities && is_stdio(&args.input) {
+        return usage_error(
+            "--identity-file reads the archive twice and cannot be used with standard input.",
+        );
+    }
+    // Open the output first, so an existing file is reported before any prompt.
+    let output = open_output(&args.output, args.output_mode, args.force, non_interactive)?;
+    if check_identities {
+        debug!("Checking that the identities open the archive.");
+        let options = identity_options(&args.identity, bars, non_interactive)?;
+        options
+            .open_contents(open_input(&args.input, non_interactive)?)
+            .context("The given identities cannot open the archive")?;
+    }
+
+    let input = CancelReader::new(open_input(&args.input, non_interactive)?);
+    let params = args.redundancy.map(fec::FecParams::with_redundancy);
+    let (conversion, output) = sage::convert::convert(input, output, params)?;
+    output.commit()?;
+    let params = conversion.header.params;
+
+    Ok(json!({
+        "input": args.input.display().to_string(),
+        "output": args.output.display().to_string(),
+        "from_version": conversion.from_version,
+        "to_version": conversion.header.format_version(),
+        "data_shards": params.data_shards,
+        "parity_shards": params.parity_shards,
+        "repaired_shards": conversion.stats.repaired_shards,
+    }))
+}
+
+/// Copies the entries of an archive, an age file or a tar archive made without sage into a
+/// new archive encrypted to the recipients given.
+fn import(args: &ConvertArgs, bars: &Bars, non_interactive: bool) -> Result<Value> {
+    let output = open_output(&args.output, args.output_mode, args.force, non_interactive)?;
+    let recipients = keys::read_recipients(
+        args.recipient.clone(),
+        args.recipients_file.clone(),
+        Vec::new(),
+        &[],
+        &keys::PluginUi::new(bars, non_interactive),
+    )?;
+    let mut options = ProtectOptions::new()
+        .recipients(recipients)
+        .output_mode(args.output_mode);
+    if let Some(redundancy) = args.redundancy {
+        options = options.redundancy(redundancy);
+    }
+
+    let input = CancelReader::new(open_input(&args.input, non_interactive)?);
+    let archive = match sage::convert::open_import(input)? {
+        sage::convert::Import::Tarball(archive) => archive,
+        sage::convert::Import::Encrypted(input) => {
+            debug!("Decrypting the input to import its entries.");
+            identity_options(&args.identity, bars, non_interactive)?.open(input)?
+        }
+    };
+    let output = options.import(archive, output)?;
+    output.commit()?;
+
+    Ok(json!({
+        "input": args.input.display().to_string(),
+        "output": args.output.display().to_string(),
+        "imported": true,
+        "to_version": fec::SEQUENTIAL_VERSION,
+    }))
+}
+
+/// Adds paths to an archive by copying its entries into a new archive made the same way.
+///
+/// The sage header says how the archive was compressed and protected, and the new archive
+/// is written alike; only the recipients, which the archive does not reveal, come from the
+/// command line.
+fn append(args: &AppendArgs, bars: &Bars, non_interactive: bool) -> Result<Value> {
+    if is_stdio(&args.archive) || is_remote(&args.archive) {
+        return usage_error("append reads the archive twice and needs a local file.");
+    }
+    let (file, _) = open_input_with_len(&args.archive, non_interactive)?;
+    let archive = archive_info::read_info(file)?;
+    let sidecar_params = open_input_sidecar(&args.archive)?.map(|reader| reader.params());
+    let mut options = ProtectOptions::new()
+        .dereference(args.dereference)
+        .sparse(!args.no_sparse)
+        .tar_format(args.tar_format)
+        .output_mode(args.output_mode);
+    match (archive.layout, archive.header) {
+        (Layout::Armored, _) => options = options.armor(true),
+        // Made with `--compat`, or by a sage from before error correction; either way, it is
+        // kept readable without sage.
+        (Layout::PlainAge, _) => options = options.compat(true),
+        (_, Some(header)) if header.version == 0 => {
+            warn!("Archive header is damaged.");
+            return Err(anyhow!(
+                "Archive header is damaged, so it is unknown how the archive was made; run \
+                 `sage repair` on it first."
+            ));
+        }
+        (_, Some(header)) => {
+            if header.stream {
+                return Err(anyhow!(
+                    "Archive holds a single stream rather than files; nothing can be appended."
+                ));
+            }
+            options = options
+                .compression(header.codec.unwrap_or(Codec::Zstd))
+                .compression_level(header.compression_level.into())
+                .fec_params(header.params)
+                .dedup(header.dedup)
+                .seekable(header.seekable);
+            if let Some(groups) = header.stripe_groups {
+                options = options.interleave(groups);
+            }
+        }
+        (_, None) => {}
+    }
+    if let Some(preserve) = args.preserve.owner() {
+        options = options.preserve_owner(preserve);
+    }
+    if let Some(preserve) = args.preserve.permissions() {
+        options = options.preserve_permissions(preserve);
+    }
+    if let Some(preserve) = args.preserve.mtime() {
+        options = options.preserve_mtime(preserve);
+    }
+    if let Some(preserve) = args.preserve.xattrs() {
+        options = options.preserve_xattrs(preserve);
+    }
+    if !args.exclude.is_empty() {
+        options = options.exclude(ExcludeSet::new(&args.exclude)?);
+    }
+
+    let output_path = args.output.as_deref().unwrap_or(&args.archive);
+    // The archive is replaced only once the new one is complete.
+    let force = args.force || args.output.is_none();
+    let output = Counted::new(match sidecar_params {
+        // The new archive gets a sidecar of its own, with the same geometry.
+        Some(params) => open_sidecar_output(
+            output_path,
+            params,
+            args.output_mode,
+            force,
+            non_interactive,
+        )?,
+        None => open_output(output_path, args.output_mode, force, non_interactive)?,
+    });
+    let mut recover = identity_options(&args.identity, bars, non_interactive)?;
+    let passphrase_encrypted = archive.stanzas == ["scrypt"];
+    if !args.recipient.is_empty() || !args.recipients_file.is_empty() {
+        let recipients = keys::read_recipients(
+            args.recipient.clone(),
+            args.recipients_file.clone(),
+            Vec::new(),
+            &[],
+            &keys::PluginUi::new(bars, non_interactive),
+        )?;
+        options = options.recipients(recipients);
+    } else if archive.header.is_some_and(|header| header.unencrypted) {
+        debug!("Leaving the new archive unencrypted, like the old one.");
+        options = options.unencrypted();
+    } else if passphrase_encrypted {
+        debug!("Encrypting the new archive with the passphrase of the old one.");
+        let passphrase = bars.suspend(|| keys::request_passphrase(non_interactive))?;
+        recover = recover.passphrase(passphrase.clone());
+        options = options.passphrase(passphrase);
+    } else if args.identity.is_empty() {
+        error!("No identities given.");
+        return Err(anyhow!(
+            "No identities given; use -i or --share to open the archive, or set {}.",
+            config::IDENTITY_ENV
+        ));
+    } else {
+        info!("Encrypting the new archive to the recipients of the identities.");
+        let mut recipients = keys::read_recipients(
+            Vec::new(),
+            Vec::new(),
+            args.identity.identity_file.clone(),
+            &args.identity.identity_from_keyring,
+            &keys::PluginUi::new(bars, non_interactive),
+        )?;
+        // The shares keep opening the new archive.
+        if let Some(share) = share_identity(&args.identity.shares)? {
+            recipients.push(Box::new(share.to_public()));
+        }
+        options = options.recipients(recipients);
+    }
+    let tally = tally(bars);
+    if let Some(tally) = &tally {
+        options = options.progress(tally.clone());
+    }
+
+    let (input, _) = open_input_with_len(&args.archive, non_interactive)?;
+    let entries = recover.open(input)?;
+    let output = options.append(entries, &args.paths, output)?;
+    let bytes_out = output.count();
+    output.into_inner().commit()?;
+
+    Ok(json!({
+        "archive": args.archive.display().to_string(),
+        "output": output_path.display().to_string(),
+        "appended": args.paths.iter().map(|path| path.display().to_string()).collect::<Vec<_>>(),
+        "files": tally.as_ref().map(|tally| tally.entries()),
+        "bytes_in": tally.as_ref().map_or(0, |tally| tally.bytes()),
+        "bytes_out": bytes_out,
+    }))
+}
+
+/// Rewraps the file key of an archive for new recipients and copies the rest of it.
+/// Lists the runs in the catalog, or shows one of them.
+fn catalog(args: &CatalogArgs) -> Result<Value> {
+    let path = args
+        .catalog
+        .clone()
+        .or_else(catalog::default_path)
+        .ok_or_else(|| anyhow!("No home directory for the catalog; give --catalog."))?;
+    debug!("Reading catalog {}", path.display());
+    let runs = catalog::read(&path)?;
+    match &args.command {
+        CatalogCommand::List { input } => {
+            let input = input.as_deref().map(absolute);
+            let runs: Vec<&catalog::Run> = runs
+                .iter()
+                .filter(|run| input.as_ref().is_none_or(|input| &run.input == input))
+                .collect();
+            if !events::enabled() {
+                for run in &runs {
+                    println!(
+                        "{:>4}  {}  {:>10}  {} -> {}",
+                        run.id,
+                        run.time,
+                        HumanBytes(run.bytes_out).to_string(),
+                        run.input,
+                        run.output
+                    );
+                }
+            }
+            if runs.is_empty() {
+                info!("No runs recorded in {}.", path.display());
+            }
+            Ok(json!({
+                "catalog": path.display().to_string(),
+                "runs": runs,
+            }))
+        }
+        CatalogCommand::Show { id } => {
+            let run = if id == "last" {
+                runs.last()
+            } else {
+                let id: u64 = id
+                    .parse()
+                    .map_err(|_| anyhow!("{id} is not a run number or `last`."))?;
+                runs.iter().find(|run| run.id == id)
+            }
+            .ok_or_else(|| anyhow!("No run {id} in catalog {}.", path.display()))?;
+            if !events::enabled() {
+                let none = || "-".to_string();
+                println!("Run:            {}", run.id);
+                println!("Time:           {}", run.time);
+                println!("Host:           {}", run.hostname);
+                println!("Input:          {}", run.input);
+                println!("Output:         {}", run.output);
+                println!(
+                    "Size:           {} ({} bytes)",
+                    HumanBytes(run.bytes_out),
+                    run.bytes_out
+                );
+                println!(
+                    "Files:          {}",
+                    run.files.map_or_else(none, |files| files.to_string())
+                );
+                println!(
+                    "Manifest hash:  {}",
+                    run.manifest_hash.clone().unwrap_or_else(none)
+                );
+                let mut keys: Vec<String> = run.recipients.clone();
+                keys.extend(
+                    run.recipients_files
+                        .iter()
+                        .map(|path| format!("file {path}")),
+                );
+                keys.extend(
+                    run.identity_files
+                        .iter()
+                        .map(|path| format!("identity {path}")),
+                );
+                if run.passphrase {
+                    keys.push("passphrase".to_string());
+                }
+                println!(
+                    "Recipients:     {}",
+                    if keys.is_empty() {
+                        none()
+                    } else {
+                        keys.join(", ")
+                    }
+                );
+            }
+            Ok(json!({
+                "catalog": path.display().to_string(),
+                "run": run,
+            }))
+        }
+    }
+}
+
+/// Deletes the archives in DIR that the retention policy does not keep.
+fn prune(args: &PruneArgs) -> Result<Value> {
+    let policy = prune::Policy {
+        daily: args.keep_daily,
+        weekly: args.keep_weekly,
+        monthly: args.keep_monthly,
+    };
+    if policy.daily == 0 && policy.weekly == 0 && policy.monthly == 0 {
+        return Err(anyhow!(
+            "Give --keep-daily, --keep-weekly or --keep-monthly; without any, every archive \
+             would be deleted."
+        ));
+    }
+    if is_remote(&args.dir) || !args.dir.is_dir() {
+        return Err(anyhow!("{} is not a local directory.", args.dir.display()));
+    }
+    let mut archives = prune::find_archives(&args.dir)?;
+    debug!("Found {} archives with a timestamp.", archives.len());
+    prune::apply(&mut archives, policy);
+
+    let mut kept = Vec::new();
+    let mut deleted = Vec::new();
+    for archive in &archives {
+        if !archive.kept_by.is_empty() {
+            debug!(
+                "Keeping {} ({})",
+                archive.path.display(),
+                archive.kept_by.join(", ")
+            );
+            kept.push(archive.path.display().to_string());
+            continue;
+        }
+        let sidecars = prune::sidecars(&archive.path)?;
+        for path in std::iter::once(&archive.path).chain(&sidecars) {
+            if args.dry_run {
+                info!("Would delete {}", path.display());
+            } else {
+                info!("Deleting {}", path.display());
+                std::fs::remove_file(path)
+                    .with_context(|| format!("Failed to delete {}", path.display()))?;
+            }
+            deleted.push(path.display().to_string());
+        }
+    }
+    info!(
+        "Kept {} and {} {}.",
+        counted(kept.len() as u64, "archive", "archives"),
+        if args.dry_run {
+            "would delete"
+        } else {
+            "deleted"
+        },
+        counted(deleted.len() as u64, "file", "files")
+    );
+    Ok(json!({
+        "kept": kept,
+        "deleted": deleted,
+        "dry_run": args.dry_run,
+    }))
+}
+
+fn rekey(args: &RekeyArgs, bars: &Bars, non_interactive: bool) -> Result<Value> {
+    if is_stdio(&args.archive) && args.output.is_none() {
+        return Err(anyhow!(
+            "Standard input cannot be replaced; give --output for the rekeyed archive."
+        ));
+    }
+    if !args.passphrase && args.recipient.is_empty() && args.recipients_file.is_empty() {
+        error!("No recipients given.");
+        return Err(anyhow::Error::new(sage::Error::NoRecipients)
+            .context("No recipients given; use -r, -R or -p."));
+    }
+    let output_path = args.output.as_deref().unwrap_or(&args.archive);
+    // The archive is replaced only once the rekeyed one is complete.
+    let force = args.force || args.output.is_none();
+    let output = open_output(output_path, args.output_mode, force, non_interactive)?;
+
+    let mut identities = keys::read_identities(
+        args.identity.identity_file.clone(),
+        &args.identity.identity_from_keyring,
+        &keys::PluginUi::new(bars, non_interactive),
+    )?;
+    if let Some(share) = share_identity(&args.identity.shares)? {
+        identities.push(Box::new(share));
+    }
+    if !is_stdio(&args.archive) {
+        let archive = archive_info::read_info(open_input(&args.archive, non_interactive)?)?;
+        if archive.header.is_some_and(|header| header.unencrypted) {
+            warn!("Archive is not encrypted, so it has no recipients to change.");
+            return Err(anyhow!(
+                "Archive is not encrypted; recover it and protect it again to encrypt it."
+            ));
+        }
+        if archive.stanzas == ["scrypt"] {
+            info!("Archive is passphrase-encrypted.");
+            let mut identity =
+                age::scrypt::Identity::new(keys::request_passphrase(non_interactive)?);
+            identity.set_max_work_factor(keys::MAX_WORK_FACTOR);
+            identities.push(Box::new(identity));
+        }
+    }
+    if identities.is_empty() {
+        error!("No identities given.");
+        return Err(anyhow!(
+            "No identities given; use -i or --share to open the archive, or set {}.",
+            config::IDENTITY_ENV
+        ));
+    }
+    let recipients: Vec<Box<dyn age::Recipient + Send>> = if args.passphrase {
+        vec![Box::new(age::scrypt::Recipient::new(
+            keys::read_new_passphrase(non_interactive)?,
+        ))]
+    } else {
+        keys::read_recipients(
+            args.recipient.clone(),
+            args.recipients_file.clone(),
+            Vec::new(),
+            &[],
+            &keys::PluginUi::new(bars, non_interactive),
+        )?
+    };
+
+    let input = CancelReader::new(open_input(&args.archive, non_interactive)?);
+    let (rekeyed, output) = sage::rekey::rekey(input, output, &identities, &recipients, args.keep)?;
+    output.commit()?;
+    info!(
+        "The age header had {} recipient stanzas and now has {}.",
+        rekeyed.old_stanzas, rekeyed.new_stanzas
+    );
+
+    Ok(json!({
+        "archive": args.archive.display().to_string(),
+        "output": output_path.display().to_string(),
+        "old_stanzas": rekeyed.old_stanzas,
+        "new_stanzas": rekeyed.new_stanzas,
+        "repaired_shards": rekeyed.stats.repaired_shards,
+    }))
+}
+
+/// Generates an identity file and prints its recipient.
+///
+/// With `--json`, the recipient is only given in the summary.
+fn keygen(args: &KeygenArgs, non_interactive: bool) -> Result<Value> {
+    let passphrase = args
+        .passphrase
+        .then(|| keys::read_new_passphrase(non_interactive))
+        .transpose()?;
+    let (contents, recipient) = keys::generate_identity();
+
+    if is_stdio(&args.output) {
+        let mut output = keys::write_identity(&contents, passphrase, io::stdout().lock())?;
+        output.flush()?;
+        if !events::enabled() {
+            eprintln!("Public key: {recipient}");
+        }
+        return Ok(json!({ "output": STDIO_PATH, "recipient": recipient }));
+    }
+    debug!("Creating identity file: {}", args.output.display());
+    let file = create_new_output_file(&args.output, DEFAULT_OUTPUT_MODE)
+        .with_context(|| format!("Failed to create identity file: {}", args.output.display()))?;
+    keys::write_identity(&contents, passphrase, file)?.sync_all()?;
+    info!("Wrote identity to: {}", args.output.display());
+    if !events::enabled() {
+        println!("{recipient}");
+    }
+    Ok(json!({ "output": args.output.display().to_string(), "recipient": recipient }))
+}
+
+/// Decrypts an archive and writes a JSON listing of its entries without extracting them.
+fn export_manifest(
+    args: &RecoverArgs,
+    manifest_path: &Path,
+    bars: &Bars,
+    non_interactive: bool,
+) -> Result<Value> {
+    let options = identity_options(&args.identity, bars, non_interactive)?;
+
+    let input = open_input(&args.input, non_interactive)?;
+
+    let mut manifest = open_output(manifest_path, MANIFEST_MODE, args.force, non_interactive)?;
+
+    let count = options.write_manifest(input, &mut manifest)?;
+    manifest.commit()?;
+    debug!("Manifest complete. {} entries listed.", count);
+
+    Ok(json!({
+        "input": args.input.display().to_string(),
+        "manifest": manifest_path.display().to_string(),
+        "entries": count,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_in_the_singular_only_for_one() {
+        assert_eq!(counted(1, "directory", "directories"), "1 directory");
+        assert_eq!(counted(0, "file", "files"), "0 files");
+        assert_eq!(counted(2, "file", "files"), "2 files");
+    }
+
+    #[test]
+    fn exits_with_the_no_keys_status_without_recipients() {
+        let matches = Cli::command()
+            .try_get_matches_from(["sage", "protect", "in", "-o", "out.sage"])
+            .unwrap();
+        let Command::Protect(args) = Cli::from_arg_matches(&matches).unwrap().command else {
+            panic!("not protect");
+        };
+        let error = protect_options(&args, &Bars::hidden(), true).err().unwrap();
+        assert!(
+            error.to_string().starts_with("No recipients given"),
+            "{error}"
+        );
+        assert_eq!(exit_code(error), NO_KEYS_EXIT_CODE);
+    }
+
+    #[test]
+    fn exits_with_the_no_keys_status_with_too_few_shares() {
+        let dir = tempfile::tempdir().unwrap();
+        let share = shamir::split(b"AGE-SECRET-KEY-1", 2, 3).unwrap().remove(0);
+        let path = dir.path().join("key.share1");
+        std::fs::write(&path, format!("{share}\n")).unwrap();
+        let error = share_identity(&[path]).err().unwrap();
+        assert!(error.to_string().contains("2 are needed"), "{error}");
+        assert_eq!(exit_code(error), NO_KEYS_EXIT_CODE);
+    }
 }
@@ -0,0 +1,240 @@
+use crate::checksum::{CHECKSUM_ENTRY, Checksums, HashingReader};
+use crate::incremental::INCREMENT_ENTRY;
+use crate::sparse;
+use anyhow::{Context, Result, anyhow};
+use chrono::DateTime;
+use log::{debug, warn};
+use serde::Serialize;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+/// Metadata describing a single entry of a protected archive.
+#[derive(Serialize, Debug)]
+pub struct EntryMetadata {
+    pub path: String,
+    pub kind: &'static str,
+    pub size: u64,
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    pub mtime: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<String>,
+    /// The hex-encoded BLAKE3 hash of a regular file's contents.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blake3: Option<String>,
+}
+
+impl EntryMetadata {
+    /// Reads the metadata of a tar entry from its header without touching its contents.
+    pub fn from_entry<R: Read>(entry: &tar::Entry<R>) -> crate::Result<Self> {
+        let header = entry.header();
+        let link_target = entry
+            .link_name()?
+            .map(|target| target.to_string_lossy().into_owned());
+
+        Ok(Self {
+            path: entry.path()?.to_string_lossy().into_owned(),
+            kind: entry_kind(header.entry_type()),
+            size: header.size()?,
+            mode: header.mode()?,
+            uid: header.uid()?,
+            gid: header.gid()?,
+            mtime: header.mtime()?,
+            link_target,
+            blake3: None,
+        })
+    }
+}
+
+/// Reads the entries of an archive for a listing, hashing the files if asked to.
+///
+/// The [`CHECKSUM_ENTRY`] and [`INCREMENT_ENTRY`] are not shown. The checksums are stored at
+/// the end of the archive, so the listed hashes are computed from the contents and, once
+/// the whole archive has been read, [`Lister::finish`] checks them against the stored ones.
+struct Lister {
+    hash: bool,
+    hashes: Vec<(PathBuf, blake3::Hash)>,
+    checksums: Option<Checksums>,
+}
+
+impl Lister {
+    fn new(hash: bool) -> Self {
+        Self {
+            hash,
+            hashes: Vec::new(),
+            checksums: None,
+        }
+    }
+
+    /// Returns the metadata of `entry`, or `None` for the entries sage adds itself.
+    fn read<R: Read>(&mut self, entry: &mut tar::Entry<R>) -> Result<Option<EntryMetadata>> {
+        let is_file = sparse::is_file(entry.header().entry_type());
+        if is_file && entry.path_bytes().as_ref() == CHECKSUM_ENTRY.as_bytes() {
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            self.checksums = Checksums::parse(&contents);
+            return Ok(None);
+        }
+        if is_file && entry.path_bytes().as_ref() == INCREMENT_ENTRY.as_bytes() {
+            return Ok(None);
+        }
+        let mut metadata = EntryMetadata::from_entry(entry)?;
+        if self.hash && is_file {
+            let path = entry.path()?.into_owned();
+            let mut reader = HashingReader::new(&mut *entry);
+            io::copy(&mut reader, &mut io::sink())
+                .with_context(|| format!("Failed to read entry: {}", path.display()))?;
+            let hash = reader.hash();
+            metadata.blake3 = Some(hash.to_hex().to_string());
+            self.hashes.push((path, hash));
+        }
+        Ok(Some(metadata))
+    }
+
+    /// Checks the hashes of the listed files against the checksums stored in the archive.
+    fn finish(self) -> Result<()> {
+        let Some(checksums) = self.checksums else {
+            if self.hash {
+                debug!("Archive stores no file checksums; listed hashes are not checked.");
+            }
+            return Ok(());
+        };
+        let mut corrupt = 0;
+        for (path, hash) in &self.hashes {
+            if checksums.matches(path, hash) == Some(false) {
+                warn!("Checksum mismatch: {}", path.display());
+                corrupt += 1;
+            }
+        }
+        if corrupt > 0 {
+            return Err(anyhow!(
+                "{corrupt} files do not match the checksums stored in the archive."
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn entry_kind(entry_type: tar::EntryType) -> &'static str {
+    match entry_type {
+        tar::EntryType::Regular | tar::EntryType::Continuous | tar::EntryType::GNUSparse => "file",
+        tar::EntryType::Directory => "directory",
+        tar::EntryType::Symlink => "symlink",
+        tar::EntryType::Link => "hardlink",
+        tar::EntryType::Char => "char",
+        tar::EntryType::Block => "block",
+        tar::EntryType::Fifo => "fifo",
+        _ => "other",
+    }
+}
+
+/// Streams the metadata of every entry of `entries` to `writer` as a JSON array.
+///
+/// Entries are serialized one at a time as they are read from the archive, so memory usage
+/// grows only by a hash per file. Files carry the BLAKE3 hash of their contents, which is
+/// checked against the stored checksums at the end. Returns the number of entries written.
+pub fn write_manifest<R: Read, W: Write>(
+    entries: tar::Entries<'_, R>,
+    mut writer: W,
+) -> crate::Result<usize> {
+    let mut count = 0;
+    let mut lister = Lister::new(true);
+    writer.write_all(b"[")?;
+    for entry in entries {
+        let mut entry = entry.context("Failed to read archive entry")?;
+        let Some(metadata) = lister.read(&mut entry)? else {
+            continue;
+        };
+        if count > 0 {
+            writer.write_all(b",")?;
+        }
+        writer.write_all(b"\n  ")?;
+        serde_json::to_writer(&mut writer, &metadata).map_err(anyhow::Error::from)?;
+        count += 1;
+    }
+    writer.write_all(b"\n]\n")?;
+    writer.flush()?;
+    lister.finish()?;
+
+    Ok(count)
+}
+
+/// Streams one line per entry of `entries` to `writer`, like `tar -t`.
+///
+/// With `long`, each line also shows the entry type and permissions, owner, size,
+/// modification time (in UTC) and, for files, the first 16 hex digits of their BLAKE3 hash,
+/// like `tar -tv`. Returns the number of entries written.
+pub fn write_listing<R: Read, W: Write>(
+    entries: tar::Entries<'_, R>,
+    mut writer: W,
+    long: bool,
+) -> crate::Result<usize> {
+    let mut count = 0;
+    let mut lister = Lister::new(long);
+    for entry in entries {
+        let mut entry = entry.context("Failed to read archive entry")?;
+        let Some(metadata) = lister.read(&mut entry)? else {
+            continue;
+        };
+        if long {
+            let mtime = DateTime::from_timestamp(metadata.mtime as i64, 0)
+                .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_else(|| metadata.mtime.to_string());
+            let hash = metadata.blake3.as_deref().map_or("-", |hash| &hash[..16]);
+            write!(
+                writer,
+                "{} {:>11} {:>12} {} {:<16} {}",
+                mode_string(entry.header().entry_type(), metadata.mode),
+                format!("{}/{}", metadata.uid, metadata.gid),
+                metadata.size,
+                mtime,
+                hash,
+                metadata.path
+            )?;
+            if let Some(target) = &metadata.link_target {
+                let arrow = if metadata.kind == "hardlink" {
+                    "link to"
+                } else {
+                    "->"
+                };
+                write!(writer, " {arrow} {target}")?;
+            }
+            writeln!(writer)?;
+        } else {
+            writeln!(writer, "{}", metadata.path)?;
+        }
+        count += 1;
+    }
+    writer.flush()?;
+    lister.finish()?;
+
+    Ok(count)
+}
+
+/// Formats an entry type and permission bits like `ls -l`, e.g. `drwxr-xr-x`.
+fn mode_string(entry_type: tar::EntryType, mode: u32) -> String {
+    let kind = match entry_type {
+        tar::EntryType::Directory => 'd',
+        tar::EntryType::Symlink => 'l',
+        tar::EntryType::Link => 'h',
+        tar::EntryType::Char => 'c',
+        tar::EntryType::Block => 'b',
+        tar::EntryType::Fifo => 'p',
+        _ => '-',
+    };
+    let mut out = String::with_capacity(10);
+    out.push(kind);
+    for (shift, special, set_char) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+        let bits = (mode >> shift) & 0o7;
+        out.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        out.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        out.push(match (bits & 0o1 != 0, mode & special != 0) {
+            (true, true) => set_char,
+            (false, true) => set_char.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    out
+}
@@ -0,0 +1,193 @@
+//! Estimating the memory protect and recover use, for `--max-memory`.
+//!
+//! The estimates cover the buffers that grow with the settings: the compression window and
+//! the state of each compression thread, one error correction group on each side of the
+//! encoder, and the blocks waiting between [pipeline](crate::pipeline) stages. They are
+//! rough upper bounds modelled on zstd's and xz's documented needs, and leave out what does
+//! not depend on the settings, such as the list of files being archived, so a limit should
+//! keep some headroom.
+
+use crate::compress::Codec;
+use crate::fec::FecParams;
+use crate::pipeline::{BLOCK_SIZE, DEPTH};
+use anyhow::anyhow;
+use log::{debug, warn};
+
+/// Pipeline stages with blocks waiting between them: compression, encryption and error
+/// correction.
+const PIPE_STAGES: u64 = 3;
+/// Smallest zstd window a limit may shrink compression to, as a power of two: 1 MiB.
+const MIN_WINDOW_LOG: u32 = 20;
+/// Decompression memory besides the window: zstd's block buffers, the tar and age buffers.
+const DECODER_OVERHEAD: u64 = 4 << 20;
+
+/// The window zstd compresses with at `level`, as a power of two, when the size of the
+/// input is not known.
+pub fn zstd_window_log(level: i32) -> u32 {
+    match level {
+        ..=1 => 19,
+        2 => 20,
+        3..=7 => 21,
+        8..=16 => 22,
+        17..=19 => 23,
+        20 => 25,
+        21 => 26,
+        _ => 27,
+    }
+}
+
+/// The memory one compression thread of `codec` at `level` needs, with a zstd window of
+/// 2^`window_log` bytes if given, and long-distance matching if `long` is set.
+pub fn compression_per_thread(
+    codec: Codec,
+    level: i32,
+    window_log: Option<u32>,
+    long: bool,
+) -> u64 {
+    match codec {
+        Codec::Zstd => {
+            let window = 1u64 << window_log.unwrap_or_else(|| zstd_window_log(level));
+            // The window, the match tables and the job buffers of a worker are each about
+            // the window's size; long-distance matching adds a table of its own.
+            window * if long { 5 } else { 4 }
+        }
+        // The memory of xz's presets, from its manual.
+        Codec::Xz => {
+            let preset: u64 = match level {
+                ..=0 => 3,
+                1 => 9,
+                2 => 17,
+                3 => 32,
+                4 => 48,
+                5 | 6 => 94,
+                7 => 186,
+                8 => 370,
+                _ => 674,
+            };
+            preset << 20
+        }
+        // The 4 MiB window brotli is used with, and its tables at high qualities.
+        Codec::Brotli => 24 << 20,
+        Codec::Lz4 => 1 << 20,
+        Codec::None => 0,
+    }
+}
+
+/// The memory that does not depend on the compression: an error correction group being
+/// filled and one being encoded, and the blocks between pipeline stages.
+pub fn fixed(params: FecParams) -> u64 {
+    let group =
+        (params.data_shards as u64 + params.parity_shards as u64) * params.shard_size as u64;
+    2 * group + PIPE_STAGES * (DEPTH as u64 + 1) * BLOCK_SIZE as u64
+}
+
+/// What compression may use to stay within a limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompressionLimits {
+    pub threads: u32,
+    /// The zstd window to shrink to, as a power of two, or `None` to keep the level's.
+    pub window_log: Option<u32>,
+}
+
+/// Fits compression with `codec` at `level` on up to `threads` threads, with a zstd window
+/// of 2^`window_log` bytes if given, into `limit` bytes along with error correction with
+/// `params`.
+///
+/// Threads are dropped first; if even one is too many, the zstd window is shrunk. Fails if
+/// neither is enough.
+pub fn fit_compression(
+    limit: u64,
+    codec: Codec,
+    level: i32,
+    threads: u32,
+    window_log: Option<u32>,
+    params: FecParams,
+) -> crate::Result<CompressionLimits> {
+    let long = window_log.is_some();
+    let fixed = fixed(params);
+    let available = limit.saturating_sub(fixed);
+    let per_thread = compression_per_thread(codec, level, window_log, long);
+    let fitting = available.checked_div(per_thread).unwrap_or(u64::MAX);
+    if fitting >= 1 {
+        let fitted = threads.min(fitting.min(u32::MAX as u64) as u32).max(1);
+        if fitted < threads {
+            debug!(
+                "Compressing on {fitted} threads instead of {threads} to stay within the memory \
+                 limit."
+            );
+        }
+        return Ok(CompressionLimits {
+            threads: fitted,
+            window_log,
+        });
+    }
+    if codec == Codec::Zstd {
+        let per_window = if long { 5 } else { 4 };
+        let fits = (available / per_window).checked_ilog2().unwrap_or(0);
+        if fits >= MIN_WINDOW_LOG {
+            let current = window_log.unwrap_or_else(|| zstd_window_log(level));
+            warn!(
+                "Shrinking the zstd window from 2^{current} to 2^{fits} bytes to stay within the \
+                 memory limit; compression may be worse."
+            );
+            return Ok(CompressionLimits {
+                threads: 1,
+                window_log: Some(fits),
+            });
+        }
+    }
+    warn!("The memory limit is too low for {codec} compression.");
+    Err(anyhow!(
+        "{codec} compression at level {level} needs about {} MiB, and error correction and \
+         buffers {} MiB, more than the memory limit of {} MiB; lower the compression level or \
+         raise the limit.",
+        per_thread.div_ceil(1 << 20),
+        fixed.div_ceil(1 << 20),
+        limit >> 20
+    )
+    .into())
+}
+
+/// The largest zstd window, as a power of two, that decompression may use to stay within
+/// `limit` bytes along with error correction with `params`, or `None` if even the smallest
+/// does not fit.
+pub fn window_log_within(limit: u64, params: FecParams) -> Option<u32> {
+    let available = limit.checked_sub(fixed(params) + DECODER_OVERHEAD)?;
+    available.checked_ilog2().filter(|&log| log >= 10)
+}
+
+/// How many threads recover may decompress on within `limit` bytes, each with error
+/// correction with `params` and a zstd window of 2^`window_log` bytes.
+pub fn recovery_threads(limit: u64, params: FecParams, window_log: u32) -> usize {
+    let per_thread = fixed(params) + DECODER_OVERHEAD + (1u64 << window_log);
+    (limit / per_thread).max(1) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_threads_before_shrinking_the_window() {
+        let params = FecParams::default();
+        let limits = fit_compression(1 << 30, Codec::Zstd, 3, 8, None, params).unwrap();
+        assert_eq!(
+            limits,
+            CompressionLimits {
+                threads: 8,
+                window_log: None
+            }
+        );
+
+        let limits = fit_compression(40 << 20, Codec::Zstd, 3, 8, None, params).unwrap();
+        assert_eq!(limits.threads, 3);
+        assert_eq!(limits.window_log, None);
+
+        let limits = fit_compression(64 << 20, Codec::Zstd, 19, 8, Some(27), params).unwrap();
+        assert_eq!(limits.threads, 1);
+        assert_eq!(limits.window_log, Some(23));
+
+        assert!(fit_compression(64 << 20, Codec::Xz, 9, 2, None, params).is_err());
+        assert!(fit_compression(4 << 20, Codec::Zstd, 3, 1, None, params).is_err());
+    }
+}
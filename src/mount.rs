@@ -0,0 +1,946 @@
+//! Serving a seekable archive as a read-only file system, for `sage mount`.
+//!
+//! The entry headers are read once, skipping the file contents through the seek table, into
+//! a tree of inodes that remembers where each file's data is in the tar stream. Sage then
+//! speaks the FUSE protocol with the kernel over `/dev/fuse` itself: the kernel forwards the
+//! lookups, directory listings and reads of whoever uses the mount point, and each read
+//! seeks the decrypted tar stream to the data asked for, so only the frames holding it are
+//! decrypted and decompressed. The mount is made with `mount(2)` when sage runs as root and
+//! through the setuid `fusermount3` helper otherwise, and lasts until it is unmounted or
+//! sage is interrupted.
+//!
+//! The layouts below follow `linux/fuse.h`, at protocol version 7.31.
+
+use crate::checksum::CHECKSUM_ENTRY;
+use crate::incremental::INCREMENT_ENTRY;
+use crate::{cancel, sparse, tar_format};
+use anyhow::{Context, Result, anyhow};
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The inode of the mount point itself.
+const ROOT: u64 = 1;
+/// How long the kernel may cache names and attributes, none of which ever change.
+const TTL: Duration = Duration::from_secs(3600);
+/// The most a single read asks for, and what the request buffer must hold besides headers.
+const MAX_READ: u32 = 128 * 1024;
+/// The protocol minor version sage speaks.
+const MINOR_VERSION: u32 = 31;
+
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_READLINK: u32 = 5;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+/// Requests that would change the file system: SETATTR, SYMLINK, MKNOD, MKDIR, UNLINK,
+/// RMDIR, RENAME, LINK, WRITE, SETXATTR, REMOVEXATTR, CREATE, FALLOCATE, RENAME2 and
+/// COPY_FILE_RANGE.
+const WRITES: [u32; 15] = [4, 6, 8, 9, 10, 11, 12, 13, 16, 21, 24, 35, 43, 45, 47];
+
+/// Tells the kernel to keep the cached pages of a file between opens.
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+/// The kind of an inode, with the file type bits of its mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    File = 0o100000,
+    Directory = 0o040000,
+    Symlink = 0o120000,
+    CharDevice = 0o020000,
+    BlockDevice = 0o060000,
+    Fifo = 0o010000,
+}
+
+/// Where a part of a file's data is stored in the tar stream.
+#[derive(Clone, Debug)]
+struct Region {
+    /// The offset in the file.
+    start: u64,
+    len: u64,
+    /// The offset in the tar stream.
+    stored: u64,
+}
+
+#[derive(Debug)]
+struct Inode {
+    parent: u64,
+    kind: Kind,
+    /// The permission bits.
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: SystemTime,
+    size: u64,
+    /// The major and minor number of a device.
+    device: (u32, u32),
+    links: u32,
+    /// The data of a file; anything between the regions reads as zeros.
+    regions: Vec<Region>,
+    target: Option<PathBuf>,
+    children: BTreeMap<OsString, u64>,
+}
+
+impl Inode {
+    fn directory(parent: u64, mtime: SystemTime) -> Self {
+        Self {
+            parent,
+            kind: Kind::Directory,
+            mode: 0o755,
+            // SAFETY: these calls cannot fail.
+            uid: unsafe { libc::geteuid() },
+            gid: unsafe { libc::getegid() },
+            mtime,
+            size: 0,
+            device: (0, 0),
+            links: 1,
+            regions: Vec::new(),
+            target: None,
+            children: BTreeMap::new(),
+        }
+    }
+
+    /// The inode of `entry`, a child of `parent`.
+    fn from_entry<R: Read>(entry: &mut tar::Entry<R>, parent: u64) -> Result<Self> {
+        let mtime = tar_format::mtime(entry)?;
+        let header = entry.header();
+        let path = entry.path()?;
+        let kind = match header.entry_type() {
+            kind if sparse::is_file(kind) => Kind::File,
+            tar::EntryType::Directory => Kind::Directory,
+            tar::EntryType::Symlink => Kind::Symlink,
+            tar::EntryType::Char => Kind::CharDevice,
+            tar::EntryType::Block => Kind::BlockDevice,
+            tar::EntryType::Fifo => Kind::Fifo,
+            kind => {
+                return Err(anyhow!(
+                    "Unsupported entry type {:?}: {}",
+                    kind,
+                    path.display()
+                ));
+            }
+        };
+        let mtime = match mtime {
+            Some(mtime) => mtime,
+            None => UNIX_EPOCH + Duration::from_secs(header.mtime()?),
+        };
+        let size = entry.size();
+        let regions = match header.as_gnu() {
+            Some(gnu) if header.entry_type().is_gnu_sparse() => {
+                let mut regions = Vec::new();
+                add_regions(&mut regions, &gnu.sparse, entry.raw_file_position())?;
+                regions
+            }
+            _ if kind == Kind::File => vec![Region {
+                start: 0,
+                len: size,
+                stored: entry.raw_file_position(),
+            }],
+            _ => Vec::new(),
+        };
+        let device = match kind {
+            Kind::CharDevice | Kind::BlockDevice => {
+                match (header.device_major()?, header.device_minor()?) {
+                    (Some(major), Some(minor)) => (major, minor),
+                    _ => (0, 0),
+                }
+            }
+            _ => (0, 0),
+        };
+        Ok(Self {
+            parent,
+            kind,
+            mode: header.mode()? & 0o7777,
+            uid: header.uid()? as u32,
+            gid: header.gid()? as u32,
+            mtime,
+            size: match kind {
+                Kind::File => size,
+                Kind::Symlink => entry
+                    .link_name_bytes()
+                    .map_or(0, |target| target.len() as u64),
+                _ => 0,
+            },
+            device,
+            links: 1,
+            regions,
+            target: entry.link_name()?.map(|target| target.into_owned()),
+            children: BTreeMap::new(),
+        })
+    }
+}
+
+/// The files and directories of an archive, by inode number minus one.
+struct Tree {
+    inodes: Vec<Inode>,
+    /// The sparse files whose regions past the header are still to be read, and where
+    /// their extension headers are.
+    sparse: Vec<(u64, u64)>,
+}
+
+impl Tree {
+    /// Reads the entry headers of `entries` into a tree.
+    fn read<R: Read>(entries: tar::Entries<'_, R>) -> Result<Self> {
+        let mut tree = Self {
+            inodes: vec![Inode::directory(ROOT, SystemTime::now())],
+            sparse: Vec::new(),
+        };
+        for entry in entries {
+            let mut entry = entry.context("Failed to read archive entry")?;
+            let path = entry.path()?.into_owned();
+            let kind = entry.header().entry_type();
+            if sparse::is_file(kind)
+                && (path == Path::new(CHECKSUM_ENTRY) || path == Path::new(INCREMENT_ENTRY))
+            {
+                continue;
+            }
+            let Some(names) = names(&path) else {
+                warn!("Skipping entry with an unsafe path: {}", path.display());
+                continue;
+            };
+            let Some((name, parents)) = names.split_last() else {
+                continue;
+            };
+            let parent = tree.directory(parents);
+            if kind == tar::EntryType::Link {
+                let target = entry.link_name()?.map(|target| target.into_owned());
+                match target.as_deref().and_then(|t| tree.find(t)) {
+                    Some(ino) => {
+                        tree.inodes[ino as usize - 1].links += 1;
+                        tree.inode_mut(parent).children.insert(name.clone(), ino);
+                    }
+                    None => warn!("Skipping hard link to a missing file: {}", path.display()),
+                }
+                continue;
+            }
+            let inode = Inode::from_entry(&mut entry, parent)?;
+            // A directory stored again, as appended archives do, keeps its contents.
+            if let Some(&ino) = tree.inode(parent).children.get(name)
+                && inode.kind == Kind::Directory
+                && tree.inode(ino).kind == Kind::Directory
+            {
+                let existing = tree.inode_mut(ino);
+                (existing.mode, existing.uid, existing.gid) = (inode.mode, inode.uid, inode.gid);
+                existing.mtime = inode.mtime;
+                continue;
+            }
+            if kind.is_gnu_sparse() && entry.header().as_gnu().is_some_and(|gnu| gnu.is_extended())
+            {
+                let ino = tree.inodes.len() as u64 + 1;
+                tree.sparse.push((ino, entry.raw_file_position()));
+            }
+            tree.inodes.push(inode);
+            let ino = tree.inodes.len() as u64;
+            tree.inode_mut(parent).children.insert(name.clone(), ino);
+        }
+        debug!("Read {} inodes from the archive.", tree.inodes.len());
+        Ok(tree)
+    }
+
+    /// Reads the regions of the sparse files listed in extension headers from `payload`,
+    /// which the header of each only has room for the first few of.
+    fn read_sparse_maps<R: Read + Seek>(&mut self, payload: &mut R) -> Result<()> {
+        for (ino, mut pos) in std::mem::take(&mut self.sparse) {
+            let inode = &mut self.inodes[ino as usize - 1];
+            let mut extension = tar::GnuExtSparseHeader::new();
+            let mut blocks = 0;
+            loop {
+                payload.seek(SeekFrom::Start(pos))?;
+                payload
+                    .read_exact(extension.as_mut_bytes())
+                    .context("Failed to read a sparse file map")?;
+                pos += 512;
+                blocks += 1;
+                // The data follows the extension headers, so every region read so far moves.
+                for region in &mut inode.regions {
+                    region.stored += 512;
+                }
+                add_regions(&mut inode.regions, extension.sparse(), pos)?;
+                if !extension.is_extended() {
+                    break;
+                }
+            }
+            debug!("Read {blocks} sparse map blocks of inode {ino}.");
+        }
+        Ok(())
+    }
+
+    fn inode(&self, ino: u64) -> &Inode {
+        &self.inodes[ino as usize - 1]
+    }
+
+    fn inode_mut(&mut self, ino: u64) -> &mut Inode {
+        &mut self.inodes[ino as usize - 1]
+    }
+
+    /// The inode at `path`, if there is one.
+    fn find(&self, path: &Path) -> Option<u64> {
+        names(path)?.iter().try_fold(ROOT, |ino, name| {
+            self.inodes
+                .get(ino as usize - 1)?
+                .children
+                .get(name)
+                .copied()
+        })
+    }
+
+    /// The directory at `names`, made along with its parents if the archive has no entry for
+    /// them.
+    fn directory(&mut self, names: &[OsString]) -> u64 {
+        let mut ino = ROOT;
+        for name in names {
+            ino = match self.inode(ino).children.get(name) {
+                Some(&child) if self.inode(child).kind == Kind::Directory => child,
+                _ => {
+                    let mtime = self.inode(ino).mtime;
+                    self.inodes.push(Inode::directory(ino, mtime));
+                    let child = self.inodes.len() as u64;
+                    self.inode_mut(ino).children.insert(name.clone(), child);
+                    child
+                }
+            };
+        }
+        ino
+    }
+
+    /// Reads up to `len` bytes of the file `ino` at `offset` from `payload`.
+    fn read_at<R: Read + Seek>(
+        &self,
+        payload: &mut R,
+        ino: u64,
+        offset: u64,
+        len: u32,
+    ) -> io::Result<Vec<u8>> {
+        let inode = self.inode(ino);
+        let end = inode.size.min(offset.saturating_add(len.into()));
+        let mut data = vec![0; end.saturating_sub(offset) as usize];
+        for region in &inode.regions {
+            let (start, stop) = (
+                region.start.max(offset),
+                (region.start + region.len).min(end),
+            );
+            if start >= stop {
+                continue;
+            }
+            payload.seek(SeekFrom::Start(region.stored + (start - region.start)))?;
+            payload.read_exact(&mut data[(start - offset) as usize..(stop - offset) as usize])?;
+        }
+        Ok(data)
+    }
+}
+
+/// The names along `path`, or `None` if it leads out of the archive.
+fn names(path: &Path) -> Option<Vec<OsString>> {
+    let mut names = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => names.push(name.to_owned()),
+            Component::CurDir | Component::RootDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(names)
+}
+
+/// Adds the sparse file regions listed in `blocks` to `regions`, with their data stored one
+/// after the other, from `stored` for the first.
+fn add_regions(
+    regions: &mut Vec<Region>,
+    blocks: &[tar::GnuSparseHeader],
+    stored: u64,
+) -> io::Result<()> {
+    for block in blocks.iter().filter(|block| !block.is_empty()) {
+        let stored = regions.last().map_or(stored, |last| last.stored + last.len);
+        regions.push(Region {
+            start: block.offset()?,
+            len: block.length()?,
+            stored,
+        });
+    }
+    Ok(())
+}
+
+/// Serves `archive`, a tar archive read through layers that all seek, at `mountpoint` until
+/// it is unmounted or sage is interrupted.
+pub fn mount<R: Read + Seek>(mut archive: tar::Archive<R>, mountpoint: &Path) -> crate::Result<()> {
+    if !mountpoint.is_dir() {
+        warn!("Mount point is not a directory: {}", mountpoint.display());
+        return Err(anyhow!(
+            "The mount point {} is not a directory.",
+            mountpoint.display()
+        )
+        .into());
+    }
+    let mut tree = Tree::read(
+        archive
+            .entries_with_seek()
+            .context("Failed to read archive entries")?,
+    )?;
+    let mut payload = archive.into_inner();
+    tree.read_sparse_maps(&mut payload)?;
+
+    let mount = Mount::new(mountpoint)?;
+    info!(
+        "Mounted at {}; unmount it or press Ctrl-C to stop.",
+        mountpoint.display()
+    );
+    let served = Session {
+        tree,
+        payload,
+        device: &mount.device,
+    }
+    .run();
+    drop(mount);
+    Ok(served?)
+}
+
+/// A mounted FUSE file system, unmounted when dropped.
+struct Mount {
+    device: File,
+    mountpoint: PathBuf,
+    /// Whether `fusermount3` made the mount and must undo it.
+    helper: Option<&'static str>,
+}
+
+impl Mount {
+    fn new(mountpoint: &Path) -> Result<Self> {
+        let mountpoint = std::path::absolute(mountpoint)?;
+        // SAFETY: geteuid cannot fail.
+        if unsafe { libc::geteuid() } == 0 {
+            let device = File::options()
+                .read(true)
+                .write(true)
+                .open("/dev/fuse")
+                .context("Failed to open /dev/fuse; is the fuse module loaded?")?;
+            let options = format!(
+                "fd={},rootmode=40000,user_id=0,group_id=0,default_permissions",
+                device.as_raw_fd()
+            );
+            let target = CString::new(mountpoint.as_os_str().as_bytes())?;
+            let options = CString::new(options)?;
+            // SAFETY: every argument is a NUL-terminated string that outlives the call.
+            let result = unsafe {
+                libc::mount(
+                    c"sage".as_ptr(),
+                    target.as_ptr(),
+                    c"fuse.sage".as_ptr(),
+                    libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV,
+                    options.as_ptr().cast(),
+                )
+            };
+            if result != 0 {
+                return Err(io::Error::last_os_error())
+                    .with_context(|| format!("Failed to mount {}", mountpoint.display()));
+            }
+            debug!("Mounted {} with mount(2).", mountpoint.display());
+            return Ok(Self {
+                device,
+                mountpoint,
+                helper: None,
+            });
+        }
+        for helper in ["fusermount3", "fusermount"] {
+            match mount_with_helper(helper, &mountpoint) {
+                Ok(device) => {
+                    debug!("Mounted {} with {helper}.", mountpoint.display());
+                    return Ok(Self {
+                        device,
+                        mountpoint,
+                        helper: Some(helper),
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Failed to mount {}", mountpoint.display()));
+                }
+            }
+        }
+        warn!("Neither fusermount3 nor fusermount is installed.");
+        Err(anyhow!(
+            "Mounting without root needs fusermount3, which comes with FUSE 3 (the fuse3 package)."
+        ))
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        let unmounted = match self.helper {
+            Some(helper) => std::process::Command::new(helper)
+                .arg("-u")
+                .arg("-z")
+                .arg("--")
+                .arg(&self.mountpoint)
+                .stderr(std::process::Stdio::null())
+                .status()
+                .is_ok_and(|status| status.success()),
+            None => CString::new(self.mountpoint.as_os_str().as_bytes()).is_ok_and(|target| {
+                // SAFETY: `target` is a NUL-terminated string that outlives the call.
+                unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) == 0 }
+            }),
+        };
+        // Unmounted from outside already, if it failed.
+        debug!(
+            "Unmounting {}: {}",
+            self.mountpoint.display(),
+            if unmounted { "done" } else { "not mounted" }
+        );
+    }
+}
+
+/// Mounts `mountpoint` through the setuid `helper`, which opens `/dev/fuse` and passes it
+/// back over a socket named in `_FUSE_COMMFD`.
+fn mount_with_helper(helper: &str, mountpoint: &Path) -> io::Result<File> {
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for the two descriptors.
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: socketpair just opened both, and nothing else owns them.
+    let (ours, theirs) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    // SAFETY: setting a flag on a descriptor this function owns.
+    unsafe { libc::fcntl(ours.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) };
+    let status = std::process::Command::new(helper)
+        .arg("-o")
+        .arg("ro,nosuid,nodev,default_permissions,fsname=sage,subtype=sage")
+        .arg("--")
+        .arg(mountpoint)
+        .env("_FUSE_COMMFD", theirs.as_raw_fd().to_string())
+        .status()?;
+    drop(theirs);
+    if !status.success() {
+        return Err(io::Error::other(format!("{helper} failed: {status}")));
+    }
+
+    let mut byte = 0u8;
+    let mut iov = libc::iovec {
+        iov_base: (&mut byte as *mut u8).cast(),
+        iov_len: 1,
+    };
+    // SAFETY: CMSG_SPACE only computes a size.
+    let space = unsafe { libc::CMSG_SPACE(size_of::<libc::c_int>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+    // SAFETY: an all-zero msghdr is valid, and the buffers it points to outlive recvmsg.
+    let fd = unsafe {
+        let mut message: libc::msghdr = std::mem::zeroed();
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr().cast();
+        message.msg_controllen = space as _;
+        if libc::recvmsg(ours.as_raw_fd(), &mut message, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let header = libc::CMSG_FIRSTHDR(&message);
+        if header.is_null() || (*header).cmsg_type != libc::SCM_RIGHTS {
+            return Err(io::Error::other(format!(
+                "{helper} did not pass back /dev/fuse"
+            )));
+        }
+        std::ptr::read_unaligned(libc::CMSG_DATA(header).cast::<libc::c_int>())
+    };
+    // SAFETY: the descriptor was just received, and nothing else owns it.
+    let device = unsafe { File::from_raw_fd(fd) };
+    // SAFETY: setting a flag on a descriptor this function owns.
+    unsafe { libc::fcntl(device.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) };
+    Ok(device)
+}
+
+/// The requests of one mount, answered from the tree and the tar stream.
+struct Session<'a, R> {
+    tree: Tree,
+    payload: R,
+    device: &'a File,
+}
+
+impl<R: Read + Seek> Session<'_, R> {
+    /// Answers requests until the file system is unmounted or sage is interrupted.
+    fn run(mut self) -> Result<()> {
+        let mut buffer = vec![0u8; MAX_READ as usize + 4096];
+        loop {
+            let n = match (&mut &*self.device).read(&mut buffer) {
+                Ok(n) => n,
+                // Interrupted by a signal: Ctrl-C or a termination.
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                    if cancel::is_requested() {
+                        info!("Interrupted; unmounting.");
+                        return Ok(());
+                    }
+                    continue;
+                }
+                // The request was interrupted before it was read.
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => continue,
+                // Unmounted.
+                Err(e) if e.raw_os_error() == Some(libc::ENODEV) => {
+                    info!("Unmounted.");
+                    return Ok(());
+                }
+                Err(e) => return Err(e).context("Failed to read from /dev/fuse"),
+            };
+            let Some(request) = Request::parse(&buffer[..n]) else {
+                return Err(anyhow!("Short request from /dev/fuse: {n} bytes"));
+            };
+            if request.opcode == FUSE_DESTROY {
+                debug!("File system destroyed.");
+                return Ok(());
+            }
+            let reply = self.answer(&request);
+            if let Some(reply) = reply {
+                self.send(request.unique, reply)?;
+            }
+        }
+    }
+
+    /// The reply to `request`, the data or an errno, or `None` if it takes none.
+    fn answer(&mut self, request: &Request) -> Option<Result<Vec<u8>, i32>> {
+        let ino = request.nodeid;
+        let known = ino >= 1 && ino as usize <= self.tree.inodes.len();
+        let mut body = Body(request.body);
+        Some(match request.opcode {
+            FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_INTERRUPT => return None,
+            FUSE_INIT => {
+                let (major, minor) = (body.u32(), body.u32());
+                let readahead = body.u32();
+                if major != 7 {
+                    warn!("Unsupported FUSE protocol version {major}.{minor}.");
+                    return Some(Err(libc::EPROTO));
+                }
+                debug!("Kernel speaks FUSE {major}.{minor}.");
+                let mut out = Vec::new();
+                put32(&mut out, 7);
+                put32(&mut out, minor.min(MINOR_VERSION));
+                put32(&mut out, readahead);
+                put32(&mut out, 0);
+                put16(&mut out, 16);
+                put16(&mut out, 12);
+                put32(&mut out, MAX_READ);
+                if minor >= 23 {
+                    put32(&mut out, 1);
+                    out.resize(64, 0);
+                }
+                Ok(out)
+            }
+            _ if !known => Err(libc::ENOENT),
+            FUSE_LOOKUP => {
+                let name = OsStr::from_bytes(body.name());
+                let inode = self.tree.inode(ino);
+                let child = match name.as_bytes() {
+                    b"." => Some(ino),
+                    b".." => Some(inode.parent),
+                    _ => inode.children.get(name).copied(),
+                };
+                match child {
+                    Some(child) => {
+                        let mut out = Vec::new();
+                        put64(&mut out, child);
+                        put64(&mut out, 0);
+                        put_ttl(&mut out);
+                        put_ttl(&mut out);
+                        put_nsec(&mut out);
+                        put_nsec(&mut out);
+                        self.put_attr(&mut out, child);
+                        Ok(out)
+                    }
+                    None => Err(libc::ENOENT),
+                }
+            }
+            FUSE_GETATTR => {
+                let mut out = Vec::new();
+                put_ttl(&mut out);
+                put_nsec(&mut out);
+                put32(&mut out, 0);
+                self.put_attr(&mut out, ino);
+                Ok(out)
+            }
+            FUSE_READLINK => match &self.tree.inode(ino).target {
+                Some(target) => Ok(target.as_os_str().as_bytes().to_vec()),
+                None => Err(libc::EINVAL),
+            },
+            FUSE_OPEN | FUSE_OPENDIR => {
+                let flags = body.u32() as i32;
+                if flags & libc::O_ACCMODE != libc::O_RDONLY {
+                    return Some(Err(libc::EROFS));
+                }
+                let mut out = Vec::new();
+                put64(&mut out, 0);
+                put32(&mut out, FOPEN_KEEP_CACHE);
+                put32(&mut out, 0);
+                Ok(out)
+            }
+            FUSE_READ => {
+                let (_, offset, size) = (body.u64(), body.u64(), body.u32());
+                match self.tree.read_at(&mut self.payload, ino, offset, size) {
+                    Ok(data) => Ok(data),
+                    Err(e) => {
+                        warn!("Failed to read inode {ino}: {e}");
+                        Err(libc::EIO)
+                    }
+                }
+            }
+            FUSE_READDIR => {
+                let (_, offset, size) = (body.u64(), body.u64(), body.u32());
+                Ok(self.list(ino, offset, size as usize))
+            }
+            FUSE_STATFS => {
+                let stored: u64 = (self.tree.inodes.iter())
+                    .map(|inode| inode.size.div_ceil(4096))
+                    .sum();
+                let mut out = Vec::new();
+                for value in [stored, 0, 0, self.tree.inodes.len() as u64, 0] {
+                    put64(&mut out, value);
+                }
+                for value in [4096, 255, 4096] {
+                    put32(&mut out, value);
+                }
+                out.resize(80, 0);
+                Ok(out)
+            }
+            FUSE_RELEASE | FUSE_RELEASEDIR | FUSE_FLUSH => Ok(Vec::new()),
+            opcode if WRITES.contains(&opcode) => Err(libc::EROFS),
+            _ => Err(libc::ENOSYS),
+        })
+    }
+
+    /// The directory entries of `ino` from the `offset`-th, as many as fit in `size` bytes.
+    fn list(&self, ino: u64, offset: u64, size: usize) -> Vec<u8> {
+        let inode = self.tree.inode(ino);
+        let entries = [(OsStr::new("."), ino), (OsStr::new(".."), inode.parent)]
+            .into_iter()
+            .chain(
+                inode
+                    .children
+                    .iter()
+                    .map(|(name, &ino)| (name.as_os_str(), ino)),
+            );
+        let mut out = Vec::new();
+        for (i, (name, child)) in entries.enumerate().skip(offset as usize) {
+            let name = name.as_bytes();
+            let len = (24 + name.len()).next_multiple_of(8);
+            if out.len() + len > size {
+                break;
+            }
+            put64(&mut out, child);
+            put64(&mut out, i as u64 + 1);
+            put32(&mut out, name.len() as u32);
+            put32(&mut out, self.tree.inode(child).kind as u32 >> 12);
+            out.extend_from_slice(name);
+            out.resize(out.len().next_multiple_of(8), 0);
+        }
+        out
+    }
+
+    /// Writes the attributes of `ino` to `out`.
+    fn put_attr(&self, out: &mut Vec<u8>, ino: u64) {
+        let inode = self.tree.inode(ino);
+        let stored: u64 = inode.regions.iter().map(|region| region.len).sum();
+        let links = match inode.kind {
+            Kind::Directory => {
+                let directories = (inode.children.values())
+                    .filter(|&&child| self.tree.inode(child).kind == Kind::Directory)
+                    .count();
+                2 + directories as u32
+            }
+            _ => inode.links,
+        };
+        let (secs, nsecs) = match inode.mtime.duration_since(UNIX_EPOCH) {
+            Ok(since) => (since.as_secs() as i64, since.subsec_nanos()),
+            Err(e) => (-(e.duration().as_secs_f64().ceil() as i64), 0),
+        };
+        let (major, minor) = inode.device;
+        put64(out, ino);
+        put64(out, inode.size);
+        put64(out, stored.div_ceil(512));
+        for _ in 0..3 {
+            put64(out, secs as u64);
+        }
+        for _ in 0..3 {
+            put32(out, nsecs);
+        }
+        put32(out, inode.kind as u32 | inode.mode);
+        put32(out, links);
+        put32(out, inode.uid);
+        put32(out, inode.gid);
+        // The kernel's encoding of device numbers.
+        put32(out, (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12));
+        put32(out, 4096);
+        put32(out, 0);
+    }
+
+    /// Writes the reply to the request `unique`.
+    fn send(&self, unique: u64, reply: Result<Vec<u8>, i32>) -> Result<()> {
+        let (error, body) = match reply {
+            Ok(body) => (0, body),
+            Err(errno) => (-errno, Vec::new()),
+        };
+        let mut out = Vec::with_capacity(16 + body.len());
+        put32(&mut out, 16 + body.len() as u32);
+        out.extend_from_slice(&error.to_ne_bytes());
+        put64(&mut out, unique);
+        out.extend_from_slice(&body);
+        match io::Write::write(&mut &*self.device, &out) {
+            Ok(_) => Ok(()),
+            // The request was interrupted and the kernel no longer waits for the reply.
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            Err(e) => Err(e).context("Failed to write to /dev/fuse"),
+        }
+    }
+}
+
+/// A request from the kernel: `fuse_in_header` and what follows it.
+struct Request<'a> {
+    opcode: u32,
+    unique: u64,
+    nodeid: u64,
+    body: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    fn parse(bytes: &'a [u8]) -> Option<Self> {
+        let mut header = Body(bytes.get(..40)?);
+        let _len = header.u32();
+        Some(Self {
+            opcode: header.u32(),
+            unique: header.u64(),
+            nodeid: header.u64(),
+            body: &bytes[40..],
+        })
+    }
+}
+
+/// Reads the fields of a request in order, as zeros past its end.
+struct Body<'a>(&'a [u8]);
+
+impl Body<'_> {
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let mut field = [0; N];
+        let n = N.min(self.0.len());
+        field[..n].copy_from_slice(&self.0[..n]);
+        self.0 = &self.0[n..];
+        field
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_ne_bytes(self.take())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_ne_bytes(self.take())
+    }
+
+    /// The NUL-terminated name that ends the request.
+    fn name(&mut self) -> &[u8] {
+        let end = self.0.iter().position(|&b| b == 0).unwrap_or(self.0.len());
+        &self.0[..end]
+    }
+}
+
+fn put16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn put32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn put64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn put_ttl(out: &mut Vec<u8>) {
+    put64(out, TTL.as_secs());
+}
+
+fn put_nsec(out: &mut Vec<u8>) {
+    put32(out, TTL.subsec_nanos());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_header(mode: u32) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(mode);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header
+    }
+
+    #[test]
+    fn reads_files_and_holes_from_where_the_tree_says() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = new_header(0o640);
+        header.set_size(5);
+        builder
+            .append_data(&mut header, "docs/notes.txt", &b"notes"[..])
+            .unwrap();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "copy.txt", "docs/notes.txt")
+            .unwrap();
+        // A sparse file with more regions than its header holds.
+        let len = 16 * 8192;
+        let mut contents = vec![0u8; len];
+        let data: Vec<_> = (0..8).map(|i| i * 2 * 8192..i * 2 * 8192 + 512).collect();
+        for (i, region) in data.iter().enumerate() {
+            contents[region.start..region.end].fill(i as u8 + 1);
+        }
+        let mut file = tempfile::tempfile().unwrap();
+        io::Write::write_all(&mut file, &contents).unwrap();
+        let mut header = new_header(0o644);
+        let region = |r: &std::ops::Range<usize>| r.start as u64..r.end as u64;
+        let map = sparse::SparseMap::new(data.iter().map(region), len as u64).unwrap();
+        let mut reader = sparse::SparseReader::new(file, map, &mut header);
+        builder
+            .append_data(&mut header, "disk.img", &mut reader)
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let mut payload = io::Cursor::new(archive);
+        let mut tree = Tree::read(tar::Archive::new(&mut payload).entries().unwrap()).unwrap();
+        tree.read_sparse_maps(&mut payload).unwrap();
+
+        let docs = tree.find(Path::new("docs")).unwrap();
+        assert_eq!(tree.inode(docs).kind, Kind::Directory);
+        let notes = tree.find(Path::new("./docs/notes.txt")).unwrap();
+        assert_eq!(tree.find(Path::new("copy.txt")), Some(notes));
+        assert_eq!(
+            (tree.inode(notes).links, tree.inode(notes).mode),
+            (2, 0o640)
+        );
+        assert_eq!(tree.read_at(&mut payload, notes, 1, 3).unwrap(), b"ote");
+        assert_eq!(tree.read_at(&mut payload, notes, 4, 100).unwrap(), b"s");
+
+        let disk = tree.find(Path::new("disk.img")).unwrap();
+        assert_eq!(tree.inode(disk).size, len as u64);
+        let read = tree
+            .read_at(&mut payload, disk, 0, len as u32 + 10)
+            .unwrap();
+        assert!(read == contents);
+        let window = 5 * 8192 - 100..14 * 8192 + 700;
+        assert!(
+            tree.read_at(&mut payload, disk, window.start as u64, window.len() as u32)
+                .unwrap()
+                == contents[window]
+        );
+        assert_eq!(names(Path::new("../etc/passwd")), None);
+    }
+}
@@ -0,0 +1,190 @@
+//! Archive names built from a template, for `protect -o DIRECTORY`.
+//!
+//! When OUTPUT is a directory, the archive is written inside it under a name made from
+//! `--name-template`, so repeated backups get names of their own. The template may use:
+//!
+//! - `{hostname}`: the name of this machine;
+//! - `{input}`: the file name of INPUT, or `stdin` for standard input;
+//! - `{date}`: the UTC time of the run, as in `20261014T093000Z`.
+
+use anyhow::{Result, anyhow};
+use std::path::{MAIN_SEPARATOR, Path};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The name template used when OUTPUT is a directory and none is given.
+pub const DEFAULT_TEMPLATE: &str = "{hostname}-{input}-{date}.sage";
+
+/// The placeholders a name template may use.
+const PLACEHOLDERS: [&str; 3] = ["{hostname}", "{input}", "{date}"];
+
+/// Returns whether `output` names a directory: an existing local one, or any path ending
+/// with a separator, which also covers remote prefixes such as `s3://bucket/backups/`.
+pub fn is_directory(output: &Path) -> bool {
+    let text = output.to_string_lossy();
+    text.ends_with('/') || text.ends_with(MAIN_SEPARATOR) || output.is_dir()
+}
+
+/// Checks that `template` only uses known placeholders and makes a plain file name.
+pub fn check_template(template: &str) -> Result<()> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end + 1)
+            .ok_or_else(|| anyhow!("The name template {template:?} has an unclosed `{{`."))?;
+        let placeholder = &rest[start..end];
+        if !PLACEHOLDERS.contains(&placeholder) {
+            return Err(anyhow!(
+                "Unknown placeholder {placeholder} in the name template; use {}.",
+                PLACEHOLDERS.join(", ")
+            ));
+        }
+        rest = &rest[end..];
+    }
+    if template.is_empty() || template.contains(['/', MAIN_SEPARATOR]) {
+        return Err(anyhow!(
+            "The name template {template:?} must be a file name, without directories."
+        ));
+    }
+    Ok(())
+}
+
+/// Fills in `template` for an archive of `input` made at `now`.
+pub fn render(template: &str, input: &Path, now: SystemTime) -> Result<String> {
+    check_template(template)?;
+    Ok(template
+        .replace("{hostname}", &hostname())
+        .replace("{input}", &input_name(input))
+        .replace("{date}", &timestamp(now)))
+}
+
+/// Formats `time` in UTC as `20261014T093000Z`, which sorts by time and suits file names.
+pub fn timestamp(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .ok()
+        .and_then(|d| chrono::DateTime::from_timestamp(d.as_secs() as i64, 0))
+        .unwrap_or_default()
+        .format("%Y%m%dT%H%M%SZ")
+        .to_string()
+}
+
+/// Finds a time written by [`timestamp`] in the file name `name`.
+pub fn parse_timestamp(name: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    let bytes = name.as_bytes();
+    (0..bytes.len().saturating_sub(15)).find_map(|start| {
+        let candidate = &bytes[start..start + 16];
+        let digits = |range: std::ops::Range<usize>| {
+            candidate[range.clone()]
+                .iter()
+                .all(u8::is_ascii_digit)
+                .then(|| {
+                    candidate[range]
+                        .iter()
+                        .fold(0, |n, &b| n * 10 + u32::from(b - b'0'))
+                })
+        };
+        if candidate[8] != b'T' || candidate[15] != b'Z' {
+            return None;
+        }
+        chrono::NaiveDate::from_ymd_opt(digits(0..4)? as i32, digits(4..6)?, digits(6..8)?)?
+            .and_hms_opt(digits(9..11)?, digits(11..13)?, digits(13..15)?)
+            .map(|time| time.and_utc())
+    })
+}
+
+/// The name of INPUT to put in archive names.
+fn input_name(input: &Path) -> String {
+    if input == Path::new(crate::STDIO_PATH) {
+        return "stdin".to_string();
+    }
+    // `.` and paths ending in `..` have no file name of their own.
+    let name = input
+        .file_name()
+        .map(|name| name.to_os_string())
+        .or_else(|| {
+            input
+                .canonicalize()
+                .ok()?
+                .file_name()
+                .map(|n| n.to_os_string())
+        });
+    match name {
+        Some(name) => safe(&name.to_string_lossy()),
+        None => "root".to_string(),
+    }
+}
+
+/// The name of this machine, up to its first dot.
+pub fn hostname() -> String {
+    let name = system_hostname()
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_default();
+    let name = name.split('.').next().unwrap_or_default().trim();
+    if name.is_empty() {
+        "localhost".to_string()
+    } else {
+        safe(name)
+    }
+}
+
+#[cfg(unix)]
+fn system_hostname() -> Option<String> {
+    let mut buffer = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length, which is passed along.
+    if unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } != 0 {
+        return None;
+    }
+    let len = buffer.iter().position(|&b| b == 0)?;
+    String::from_utf8(buffer[..len].to_vec()).ok()
+}
+
+#[cfg(not(unix))]
+fn system_hostname() -> Option<String> {
+    None
+}
+
+/// Replaces characters that cannot appear in a file name.
+fn safe(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn fills_in_the_placeholders() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_760_434_200);
+        let name = render("{input}-{date}.sage", Path::new("/srv/photos"), now).unwrap();
+        assert_eq!(name, "photos-20251014T093000Z.sage");
+        let name = render("{hostname}-{input}.sage", Path::new("-"), now).unwrap();
+        assert!(name.ends_with("-stdin.sage") && name.len() > "-stdin.sage".len());
+        let parsed = parse_timestamp("photos-20251014T093000Z.sage").unwrap();
+        assert_eq!(parsed.timestamp(), 1_760_434_200);
+        assert_eq!(parse_timestamp("photos-20251314T093000Z.sage"), None);
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders_and_directories() {
+        assert!(check_template(DEFAULT_TEMPLATE).is_ok());
+        assert!(check_template("{host}.sage").is_err());
+        assert!(check_template("{date.sage").is_err());
+        assert!(check_template("sub/{date}.sage").is_err());
+        assert!(check_template("").is_err());
+    }
+
+    #[test]
+    fn a_trailing_separator_names_a_directory() {
+        assert!(is_directory(Path::new("s3://bucket/backups/")));
+        assert!(!is_directory(Path::new("does-not-exist.sage")));
+        assert!(is_directory(&std::env::temp_dir()));
+    }
+}
@@ -0,0 +1,318 @@
+//! Protecting and recovering streams from async code.
+//!
+//! The functions here take any [`AsyncRead`] and [`AsyncWrite`] of the `futures` crate.
+//! Tokio streams do not implement these traits: they work only when wrapped with
+//! `tokio_util::compat` (`.compat()` for readers, `.compat_write()` for writers).
+//!
+//! Compressing, encrypting and error correction run on a worker thread of their own,
+//! exactly as in the blocking API, and the future only moves blocks of [`BLOCK_SIZE`]
+//! between the caller's streams and that thread over bounded channels. No thread of the
+//! async runtime ever waits on the pipeline, and at most [`DEPTH`] blocks wait on either
+//! side of it.
+//!
+//! # Limitations
+//!
+//! This is not a poll-based implementation. Every call spawns a new OS thread for the
+//! whole operation, and that thread blocks on the channels with
+//! [`futures::executor::block_on`] while it waits for the future. A server running many
+//! operations at once therefore runs as many threads, outside the runtime's own pool and
+//! limits; bound the number of concurrent calls if that matters.
+//!
+//! Dropping the future abandons the operation: the worker sees its streams close and stops
+//! without finishing the archive, as when a blocking call fails half-way.
+
+use crate::pipeline::{BLOCK_SIZE, DEPTH};
+use crate::{ProtectOptions, RecoverOptions};
+use anyhow::{Result, anyhow};
+use futures::channel::{mpsc, oneshot};
+use futures::executor::block_on;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures::{SinkExt, StreamExt};
+use log::{debug, warn};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::thread;
+
+/// Protects everything read from `input` into `output`, like
+/// [`ProtectOptions::protect_stream`], and returns `output` once the archive is complete.
+pub async fn protect_stream<R, W>(options: ProtectOptions, input: R, output: W) -> crate::Result<W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let output = run(
+        "sage-protect",
+        input,
+        Some(output),
+        move |reader, writer| {
+            let mut writer = options.protect_stream(reader, writer)?;
+            writer.flush()?;
+            Ok(())
+        },
+    )
+    .await?;
+    Ok(output.expect("protect writes to an output"))
+}
+
+/// Writes the contents of the archive stream `input` to `output`, like
+/// [`RecoverOptions::recover_to_writer`], and returns `output` once they are all written.
+///
+/// The options are made by `options` on the worker thread, as the identities they hold
+/// need not be `Send`.
+pub async fn recover_to_writer<F, R, W>(options: F, input: R, output: W) -> crate::Result<W>
+where
+    F: FnOnce() -> RecoverOptions + Send + 'static,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let output = run(
+        "sage-recover",
+        input,
+        Some(output),
+        move |reader, writer| {
+            options().recover_to_writer(reader, writer)?;
+            Ok(())
+        },
+    )
+    .await?;
+    Ok(output.expect("recover writes to an output"))
+}
+
+/// Recovers the archive stream `input` into `output_path`, like [`RecoverOptions::recover`].
+///
+/// The options are made by `options` on the worker thread, as for [`recover_to_writer`].
+pub async fn recover<F, R>(options: F, input: R, output_path: &Path) -> crate::Result<()>
+where
+    F: FnOnce() -> RecoverOptions + Send + 'static,
+    R: AsyncRead + Unpin,
+{
+    let output_path = output_path.to_path_buf();
+    run::<_, futures::io::Sink, _>("sage-recover", input, None, move |reader, _| {
+        Ok(options().recover(reader, &output_path)?)
+    })
+    .await?;
+    Ok(())
+}
+
+/// Runs `work` on a thread named `name`, feeding it `input` and copying what it writes to
+/// `output`, and returns `output` once `work` succeeded and everything it wrote is flushed.
+async fn run<R, W, F>(name: &str, mut input: R, output: Option<W>, work: F) -> Result<Option<W>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: FnOnce(ChannelReader, ChannelWriter) -> Result<()> + Send + 'static,
+{
+    let (mut input_sender, input_receiver) = mpsc::channel::<io::Result<Vec<u8>>>(DEPTH);
+    let (output_sender, mut output_receiver) = mpsc::channel::<Vec<u8>>(DEPTH);
+    let (done_sender, done_receiver) = oneshot::channel();
+    let reader = ChannelReader {
+        receiver: input_receiver,
+        block: Vec::new(),
+        position: 0,
+    };
+    let writer = ChannelWriter {
+        sender: output_sender,
+        block: Vec::with_capacity(BLOCK_SIZE),
+    };
+    debug!("Starting worker thread {name}.");
+    thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            let _ = done_sender.send(work(reader, writer));
+        })?;
+
+    let feed = async {
+        loop {
+            let mut block = vec![0; BLOCK_SIZE];
+            let block = match input.read(&mut block).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    block.truncate(n);
+                    Ok(block)
+                }
+                // The worker fails with the error, instead of taking it for the end of the
+                // input and finishing an archive of what came before it.
+                Err(e) => Err(e),
+            };
+            let failed = block.is_err();
+            if input_sender.send(block).await.is_err() || failed {
+                break;
+            }
+        }
+        input_sender.close_channel();
+    };
+    let drain = async {
+        let Some(mut output) = output else {
+            while output_receiver.next().await.is_some() {}
+            return Ok(None);
+        };
+        while let Some(block) = output_receiver.next().await {
+            // Dropping the receiver stops the worker at its next write.
+            output.write_all(&block).await?;
+        }
+        output.flush().await?;
+        Ok::<_, io::Error>(Some(output))
+    };
+    let ((), drained, done) = futures::join!(feed, drain, done_receiver);
+
+    let output = drained.map_err(|e| {
+        warn!("Failed to write the output stream: {e}");
+        anyhow!(e).context("Failed to write output stream")
+    })?;
+    done.map_err(|_| anyhow!("The {name} worker thread panicked."))??;
+    debug!("Worker thread {name} finished.");
+    Ok(output)
+}
+
+/// Reads the blocks the future sends, waiting for them on the worker thread.
+struct ChannelReader {
+    receiver: mpsc::Receiver<io::Result<Vec<u8>>>,
+    block: Vec<u8>,
+    position: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.block.len() {
+            match block_on(self.receiver.next()) {
+                Some(block) => {
+                    self.block = block.map_err(|e| {
+                        io::Error::new(e.kind(), format!("Failed to read input stream: {e}"))
+                    })?;
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.block.len() - self.position);
+        buf[..n].copy_from_slice(&self.block[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// Collects what the worker writes into blocks and sends them to the future.
+struct ChannelWriter {
+    sender: mpsc::Sender<Vec<u8>>,
+    block: Vec<u8>,
+}
+
+impl ChannelWriter {
+    fn send_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let block = std::mem::replace(&mut self.block, Vec::with_capacity(BLOCK_SIZE));
+        block_on(self.sender.send(block)).map_err(|_| io::Error::other("output stream closed"))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let n = buf.len().min(BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..n]);
+        if self.block.len() == BLOCK_SIZE {
+            self.send_block()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_block()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::Cursor;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Returns `len` bytes that compress a little, but not to nothing.
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    /// Yields `limit` bytes of zeros, then fails.
+    struct Failing {
+        limit: usize,
+    }
+
+    impl AsyncRead for Failing {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.limit == 0 {
+                return Poll::Ready(Err(io::Error::other("connection reset")));
+            }
+            let n = buf.len().min(self.limit);
+            buf[..n].fill(0);
+            self.limit -= n;
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    #[test]
+    fn round_trips_a_stream() {
+        let identity = age::x25519::Identity::generate();
+        let recipient = identity.to_public();
+        let data = contents(3 * BLOCK_SIZE + 17);
+
+        let options = ProtectOptions::new().recipient(Box::new(recipient));
+        let archive = block_on(protect_stream(
+            options,
+            Cursor::new(data.clone()),
+            Vec::new(),
+        ))
+        .unwrap();
+        let restored = block_on(recover_to_writer(
+            move || RecoverOptions::new().identity(Box::new(identity)),
+            Cursor::new(archive),
+            Vec::new(),
+        ))
+        .unwrap();
+        assert_eq!(restored, data);
+    }
+
+    #[test]
+    fn fails_instead_of_protecting_part_of_a_failed_input() {
+        let identity = age::x25519::Identity::generate();
+        let options = ProtectOptions::new().recipient(Box::new(identity.to_public()));
+        let error = block_on(protect_stream(
+            options,
+            Failing {
+                limit: BLOCK_SIZE + 3,
+            },
+            Vec::new(),
+        ))
+        .unwrap_err();
+        assert!(
+            format!("{error:#}").contains("connection reset"),
+            "{error:#}"
+        );
+    }
+
+    #[test]
+    fn reports_an_archive_it_cannot_decrypt() {
+        let identity = age::x25519::Identity::generate();
+        let options = ProtectOptions::new().recipient(Box::new(identity.to_public()));
+        let archive = block_on(protect_stream(
+            options,
+            Cursor::new(contents(1000)),
+            Vec::new(),
+        ))
+        .unwrap();
+        let result = block_on(recover_to_writer(
+            || RecoverOptions::new().identity(Box::new(age::x25519::Identity::generate())),
+            Cursor::new(archive),
+            Vec::new(),
+        ));
+        assert!(result.is_err());
+    }
+}
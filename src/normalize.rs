@@ -0,0 +1,201 @@
+//! Unicode normalization of the names recover extracts, for `--normalize-filenames`.
+//!
+//! The same name can be spelled in Unicode with precomposed characters, such as `é`, or
+//! with a base character and combining marks, `e` and U+0301. macOS tends to write names
+//! in the decomposed form, NFD, and Linux tools the composed one, NFC, so a name typed on
+//! one system does not match the file restored from the other, and a tree merged from
+//! both can hold two files whose names look the same. Recover can rewrite every name into
+//! one form, leaving names that are not valid UTF-8 alone.
+
+use crate::unicode_tables::{COMBINING_CLASSES, COMPOSITIONS, DECOMPOSITIONS};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+/// The Unicode normalization form names are extracted in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Normalization {
+    /// Precomposed characters, as Linux and Windows tools usually write them.
+    Nfc,
+    /// Base characters followed by combining marks, as macOS usually writes them.
+    Nfd,
+    /// Keep names as they are stored.
+    #[default]
+    None,
+}
+
+/// The first Hangul syllable, and the counts of its leading consonants, vowels and
+/// trailing consonants, from which syllables are composed by arithmetic.
+const HANGUL_BASE: u32 = 0xac00;
+const LEADS: u32 = 19;
+const VOWELS: u32 = 21;
+const TRAILS: u32 = 28;
+const LEAD_BASE: u32 = 0x1100;
+const VOWEL_BASE: u32 = 0x1161;
+const TRAIL_BASE: u32 = 0x11a7;
+const SYLLABLES: u32 = LEADS * VOWELS * TRAILS;
+
+impl Normalization {
+    /// `path` with every component in this form.
+    pub fn path<'a>(self, path: &'a Path) -> Cow<'a, Path> {
+        if self == Normalization::None {
+            return Cow::Borrowed(path);
+        }
+        match path.to_str().map(|s| self.apply(s)) {
+            Some(Cow::Owned(normalized)) => Cow::Owned(PathBuf::from(normalized)),
+            _ => Cow::Borrowed(path),
+        }
+    }
+
+    /// `s` in this form.
+    pub fn apply(self, s: &str) -> Cow<'_, str> {
+        // Text without combining marks or precomposed characters is the same in every form.
+        if self == Normalization::None || s.is_ascii() {
+            return Cow::Borrowed(s);
+        }
+        let normalized = match self {
+            Normalization::Nfd => decompose(s),
+            _ => compose(decompose(s)),
+        };
+        if normalized == s {
+            Cow::Borrowed(s)
+        } else {
+            Cow::Owned(normalized)
+        }
+    }
+}
+
+/// The canonical combining class of `c`: 0 for starters.
+fn combining_class(c: char) -> u8 {
+    let c = c as u32;
+    match COMBINING_CLASSES.binary_search_by(|&(start, end, _)| {
+        if end < c {
+            std::cmp::Ordering::Less
+        } else if start > c {
+            std::cmp::Ordering::Greater
+        } else {
+            std::cmp::Ordering::Equal
+        }
+    }) {
+        Ok(i) => COMBINING_CLASSES[i].2,
+        Err(_) => 0,
+    }
+}
+
+/// `s` fully decomposed, with the combining marks after each starter in canonical order:
+/// NFD.
+fn decompose(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + s.len() / 2);
+    for c in s.chars() {
+        let syllable = (c as u32).wrapping_sub(HANGUL_BASE);
+        if syllable < SYLLABLES {
+            let (lead, vowel, trail) = (
+                syllable / (VOWELS * TRAILS),
+                syllable % (VOWELS * TRAILS) / TRAILS,
+                syllable % TRAILS,
+            );
+            out.extend(char::from_u32(LEAD_BASE + lead));
+            out.extend(char::from_u32(VOWEL_BASE + vowel));
+            if trail != 0 {
+                out.extend(char::from_u32(TRAIL_BASE + trail));
+            }
+            continue;
+        }
+        match DECOMPOSITIONS.binary_search_by_key(&c, |&(from, _)| from) {
+            Ok(i) => out.push_str(DECOMPOSITIONS[i].1),
+            Err(_) => out.push(c),
+        }
+    }
+    let mut chars: Vec<char> = out.chars().collect();
+    let mut start = 0;
+    while start < chars.len() {
+        if combining_class(chars[start]) == 0 {
+            start += 1;
+            continue;
+        }
+        let end = (start..chars.len())
+            .find(|&i| combining_class(chars[i]) == 0)
+            .unwrap_or(chars.len());
+        // A stable sort keeps marks of the same class in their order.
+        chars[start..end].sort_by_key(|&c| combining_class(c));
+        start = end;
+    }
+    chars.into_iter().collect()
+}
+
+/// The character the pair `first`, `second` composes to, if any.
+fn compose_pair(first: char, second: char) -> Option<char> {
+    let (a, b) = (first as u32, second as u32);
+    let lead = a.wrapping_sub(LEAD_BASE);
+    let vowel = b.wrapping_sub(VOWEL_BASE);
+    if lead < LEADS && vowel < VOWELS {
+        return char::from_u32(HANGUL_BASE + (lead * VOWELS + vowel) * TRAILS);
+    }
+    let syllable = a.wrapping_sub(HANGUL_BASE);
+    let trail = b.wrapping_sub(TRAIL_BASE);
+    if syllable < SYLLABLES && syllable % TRAILS == 0 && trail > 0 && trail < TRAILS {
+        return char::from_u32(a + trail);
+    }
+    COMPOSITIONS
+        .binary_search_by_key(&(first, second), |&(a, b, _)| (a, b))
+        .ok()
+        .map(|i| COMPOSITIONS[i].2)
+}
+
+/// The decomposed `s` with every pair that can be recombined composed again: NFC.
+fn compose(s: String) -> String {
+    let mut chars: Vec<char> = s.chars().collect();
+    let mut starter: Option<usize> = None;
+    // The class of the last character kept after the starter, which blocks marks of the
+    // same class or lower from composing with it.
+    let mut last_class = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let class = combining_class(c);
+        if let Some(s) = starter {
+            let blocked = i > s + 1 && (last_class == 0 || last_class >= class);
+            if !blocked && let Some(composed) = compose_pair(chars[s], c) {
+                chars[s] = composed;
+                chars.remove(i);
+                continue;
+            }
+        }
+        if class == 0 {
+            starter = Some(i);
+            last_class = 0;
+        } else {
+            last_class = class;
+        }
+        i += 1;
+    }
+    chars.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_between_forms() {
+        let composed = "Caf\u{e9} \u{1e69} \u{d55c}\u{ae00}.txt";
+        let decomposed =
+            "Cafe\u{301} s\u{323}\u{307} \u{1112}\u{1161}\u{11ab}\u{1100}\u{1173}\u{11af}.txt";
+        assert_eq!(Normalization::Nfd.apply(composed), decomposed);
+        assert_eq!(Normalization::Nfc.apply(decomposed), composed);
+        assert_eq!(Normalization::Nfc.apply(composed), composed);
+        assert!(matches!(
+            Normalization::Nfc.apply(composed),
+            Cow::Borrowed(_)
+        ));
+        assert_eq!(Normalization::None.apply(decomposed), decomposed);
+        // Marks are put in canonical order, and only the ones not blocked compose.
+        assert_eq!(Normalization::Nfc.apply("s\u{307}\u{323}"), "\u{1e69}");
+        assert_eq!(Normalization::Nfc.apply("a\u{316}\u{300}"), "\u{e0}\u{316}");
+        // Composition exclusions stay decomposed.
+        assert_eq!(Normalization::Nfc.apply("\u{958}"), "\u{915}\u{93c}");
+        assert_eq!(
+            Normalization::Nfc.path(Path::new("docs/Re\u{301}sume\u{301}")),
+            Path::new("docs/R\u{e9}sum\u{e9}")
+        );
+    }
+}
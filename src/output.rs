@@ -0,0 +1,239 @@
+//! Creating output files: with restrictive permissions, and atomically.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Default permissions of created archives, so backups are not world-readable.
+pub const DEFAULT_OUTPUT_MODE: u32 = 0o600;
+
+/// Creates the output file with the requested permissions, failing if it already exists.
+pub fn create_new_output_file(output_path: &Path, mode: u32) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    open_with_mode(options, output_path, mode)
+}
+
+/// Opens the output file for writing with the requested permissions, creating it if it does
+/// not exist and keeping what it holds if it does.
+pub(crate) fn open_output_file(output_path: &Path, mode: u32) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true);
+    open_with_mode(options, output_path, mode)
+}
+
+fn open_with_mode(mut options: OpenOptions, output_path: &Path, mode: u32) -> io::Result<File> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(mode);
+        let file = options.open(output_path)?;
+        // The creation mode is filtered by the umask and ignored for existing files.
+        file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+        Ok(file)
+    }
+    #[cfg(not(unix))]
+    {
+        log::debug!("Ignoring output mode {mode:o} on this platform.");
+        Ok(options.open(output_path)?)
+    }
+}
+
+/// An output file that only appears at its path once it is complete.
+///
+/// Data is written to a temporary file next to the destination, which
+/// [`AtomicFile::commit`] renames into place. Dropping the file without committing it, for
+/// example on an error, removes the temporary file, and so does
+/// [`remove_pending_outputs`] when the process is interrupted.
+pub struct AtomicFile {
+    temp: tempfile::NamedTempFile,
+    path: PathBuf,
+    overwrite: bool,
+    _pending: pending::Guard,
+}
+
+impl AtomicFile {
+    /// Starts writing `path` with permissions `mode`.
+    ///
+    /// Fails with [`io::ErrorKind::AlreadyExists`] if `path` exists, unless `overwrite` is set.
+    pub fn create(path: &Path, mode: u32, overwrite: bool) -> io::Result<Self> {
+        if !overwrite && path.symlink_metadata().is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "file already exists",
+            ));
+        }
+        let name = path.file_name().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "output path has no file name")
+        })?;
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let temp = tempfile::Builder::new()
+            .prefix(&format!(".{}.", name.to_string_lossy()))
+            .suffix(".tmp")
+            .tempfile_in(dir)?;
+        let pending = pending::register(temp.path());
+        log::debug!("Writing to temporary file: {}", temp.path().display());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            temp.as_file()
+                .set_permissions(std::fs::Permissions::from_mode(mode))?;
+        }
+        #[cfg(not(unix))]
+        log::debug!("Ignoring output mode {mode:o} on this platform.");
+        Ok(Self {
+            temp,
+            path: path.to_path_buf(),
+            overwrite,
+            _pending: pending,
+        })
+    }
+
+    /// Flushes the data to disk and moves the file to its path.
+    pub fn commit(self) -> io::Result<()> {
+        self.temp.as_file().sync_all()?;
+        let persisted = if self.overwrite {
+            self.temp.persist(&self.path)
+        } else {
+            self.temp.persist_noclobber(&self.path)
+        };
+        persisted.map_err(|e| e.error)?;
+        log::debug!("Moved output into place: {}", self.path.display());
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.temp.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.temp.flush()
+    }
+}
+
+/// Removes the temporary files of every [`AtomicFile`] that has not been committed, and the
+/// files passed to [`remove_on_interrupt`].
+///
+/// Only calls async-signal-safe functions, so it can be called from a signal handler right
+/// before the process exits.
+pub fn remove_pending_outputs() {
+    pending::remove_all();
+}
+
+/// Keeps `path` for [`remove_pending_outputs`] to remove, until the guard is dropped.
+pub struct PendingRemoval {
+    _pending: pending::Guard,
+}
+
+/// Has [`remove_pending_outputs`] remove `path`, a file that must not outlive the process,
+/// until the returned guard is dropped.
+pub fn remove_on_interrupt(path: &Path) -> PendingRemoval {
+    PendingRemoval {
+        _pending: pending::register(path),
+    }
+}
+
+/// The paths of uncommitted temporary files, kept where a signal handler can read them.
+#[cfg(unix)]
+mod pending {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+    use std::ptr;
+    use std::sync::atomic::{AtomicPtr, Ordering};
+
+    const SLOTS: usize = 16;
+
+    static PATHS: [AtomicPtr<libc::c_char>; SLOTS] =
+        [const { AtomicPtr::new(ptr::null_mut()) }; SLOTS];
+
+    /// Unregisters a path when dropped.
+    pub struct Guard(Option<usize>);
+
+    pub fn register(path: &Path) -> Guard {
+        let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+            return Guard(None);
+        };
+        let raw = path.into_raw();
+        for (i, slot) in PATHS.iter().enumerate() {
+            if slot
+                .compare_exchange(ptr::null_mut(), raw, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                return Guard(Some(i));
+            }
+        }
+        // SAFETY: `raw` came from `into_raw` above and was not stored anywhere.
+        drop(unsafe { CString::from_raw(raw) });
+        Guard(None)
+    }
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            if let Some(i) = self.0 {
+                let raw = PATHS[i].swap(ptr::null_mut(), Ordering::AcqRel);
+                if !raw.is_null() {
+                    // SAFETY: only `register` stores pointers, all from `CString::into_raw`.
+                    drop(unsafe { CString::from_raw(raw) });
+                }
+            }
+        }
+    }
+
+    pub fn remove_all() {
+        for slot in &PATHS {
+            // The strings are leaked: freeing memory is not async-signal-safe.
+            let raw = slot.swap(ptr::null_mut(), Ordering::AcqRel);
+            if !raw.is_null() {
+                // SAFETY: `raw` is a valid NUL-terminated path from `register`.
+                unsafe { libc::unlink(raw) };
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod pending {
+    use std::path::Path;
+
+    pub struct Guard;
+
+    pub fn register(_path: &Path) -> Guard {
+        Guard
+    }
+
+    pub fn remove_all() {}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn mode_of(path: &Path) -> u32 {
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn archives_are_private_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.sage");
+        let mut file = AtomicFile::create(&path, DEFAULT_OUTPUT_MODE, false).unwrap();
+        file.write_all(b"data").unwrap();
+        file.commit().unwrap();
+        assert_eq!(mode_of(&path), 0o600);
+    }
+
+    #[test]
+    fn output_mode_is_not_filtered_by_the_umask() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifest.json");
+        drop(create_new_output_file(&path, 0o666).unwrap());
+        assert_eq!(mode_of(&path), 0o666);
+    }
+}
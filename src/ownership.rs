@@ -0,0 +1,150 @@
+use anyhow::Result;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+/// A translation table for user or group IDs, parsed from `from:to[,from:to...]`.
+#[derive(Clone, Debug, Default)]
+pub struct IdMap {
+    entries: HashMap<u64, u64>,
+}
+
+impl IdMap {
+    /// Returns the translated ID, or `id` unchanged if it has no mapping.
+    pub fn map(&self, id: u64) -> u64 {
+        self.entries.get(&id).copied().unwrap_or(id)
+    }
+}
+
+impl FromStr for IdMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut entries = HashMap::new();
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (from, to) = pair
+                .split_once(':')
+                .ok_or_else(|| format!("Invalid ID mapping '{pair}', expected FROM:TO"))?;
+            let from = from
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid source ID in mapping '{pair}'"))?;
+            let to = to
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid target ID in mapping '{pair}'"))?;
+            if entries.insert(from, to).is_some() {
+                return Err(format!("ID {from} is mapped more than once"));
+            }
+        }
+        if entries.is_empty() {
+            return Err("ID mapping is empty".to_string());
+        }
+
+        Ok(Self { entries })
+    }
+}
+
+/// Returns true if the process runs as root and can therefore restore file ownership.
+#[cfg(unix)]
+pub fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Returns true if the process runs as root and can therefore restore file ownership.
+#[cfg(not(unix))]
+pub fn is_root() -> bool {
+    false
+}
+
+/// Applies stored tar ownership, translated through the uid/gid maps, to extracted paths.
+///
+/// Changing ownership requires root. The first permission failure is logged once and all
+/// further ownership changes are skipped, so unprivileged restores still succeed.
+pub struct OwnershipMapper {
+    uid_map: IdMap,
+    gid_map: IdMap,
+    enabled: bool,
+}
+
+impl OwnershipMapper {
+    pub fn new(uid_map: Option<IdMap>, gid_map: Option<IdMap>) -> Self {
+        Self {
+            uid_map: uid_map.unwrap_or_default(),
+            gid_map: gid_map.unwrap_or_default(),
+            enabled: true,
+        }
+    }
+
+    /// Changes the owner of `path` to the mapped `uid`/`gid` recorded in the archive.
+    pub fn apply(&mut self, path: &Path, uid: u64, gid: u64) -> crate::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let (uid, gid) = (self.uid_map.map(uid), self.gid_map.map(gid));
+        debug!("Setting owner of {} to {}:{}", path.display(), uid, gid);
+        Ok(self.chown(path, uid, gid)?)
+    }
+
+    #[cfg(unix)]
+    fn chown(&mut self, path: &Path, uid: u64, gid: u64) -> Result<()> {
+        let (Ok(uid), Ok(gid)) = (u32::try_from(uid), u32::try_from(gid)) else {
+            warn!(
+                "Skipping ownership of {}: ID {}:{} is out of range.",
+                path.display(),
+                uid,
+                gid
+            );
+            return Ok(());
+        };
+        match std::os::unix::fs::lchown(path, Some(uid), Some(gid)) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                warn!("Not permitted to change file ownership (requires root); skipping.");
+                self.enabled = false;
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn chown(&mut self, _path: &Path, _uid: u64, _gid: u64) -> Result<()> {
+        warn!("Ownership mapping is not supported on this platform; skipping.");
+        self.enabled = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_listed_ids_and_keeps_the_rest() {
+        let map: IdMap = "1000:2000, 0:65534".parse().unwrap();
+        assert_eq!(map.map(1000), 2000);
+        assert_eq!(map.map(0), 65534);
+        assert_eq!(map.map(1001), 1001);
+    }
+
+    #[test]
+    fn default_map_is_the_identity() {
+        assert_eq!(IdMap::default().map(42), 42);
+    }
+
+    #[test]
+    fn ignores_empty_pairs() {
+        let map: IdMap = "1:2,,".parse().unwrap();
+        assert_eq!(map.map(1), 2);
+    }
+
+    #[test]
+    fn rejects_malformed_mappings() {
+        for bad in ["", ",", "1000", "a:1", "1:b", "1:-2", "1:2,1:3"] {
+            assert!(bad.parse::<IdMap>().is_err(), "{bad:?} was accepted");
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Running the layers of an archive on separate threads.
+//!
+//! A [`PipeWriter`] hands what is written to it, in blocks, to a stage running on its own
+//! thread over a bounded channel. Protecting chains several of them, so reading the input,
+//! compressing, encrypting and error correction all make progress at the same time instead
+//! of waiting for one another. At most [`DEPTH`] blocks wait between two stages, which keeps
+//! memory use bounded when a later stage is slower.
+
+use std::io::{self, Write};
+use std::mem;
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::{self, Scope, ScopedJoinHandle};
+
+/// Bytes collected before a block is handed to the next stage.
+pub const BLOCK_SIZE: usize = 256 * 1024;
+
+/// Blocks that may wait between two stages.
+pub const DEPTH: usize = 4;
+
+/// Writes to a stage running on another thread, and returns what the stage finished with.
+///
+/// The stage is only finished by [`PipeWriter::finish`]. A pipe dropped without it abandons
+/// the stage, which then drops its writer without finishing that either, so an operation that
+/// failed half-way does not write the end of a stream it never completed.
+pub struct PipeWriter<'scope, T> {
+    sender: Option<SyncSender<Vec<u8>>>,
+    block: Vec<u8>,
+    worker: Option<ScopedJoinHandle<'scope, io::Result<T>>>,
+}
+
+impl<'scope, T: Send + 'scope> PipeWriter<'scope, T> {
+    /// Starts a thread named `name` in `scope` that writes every block to `inner`, then
+    /// calls `finish` on it once the pipe is finished.
+    pub fn spawn<'env, W: Write + Send + 'scope>(
+        scope: &'scope Scope<'scope, 'env>,
+        name: &str,
+        mut inner: W,
+        finish: impl FnOnce(W) -> io::Result<T> + Send + 'scope,
+    ) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(DEPTH);
+        let worker =
+            thread::Builder::new()
+                .name(name.to_string())
+                .spawn_scoped(scope, move || {
+                    for block in receiver {
+                        // An empty block marks the end of a complete stream.
+                        if block.is_empty() {
+                            return finish(inner);
+                        }
+                        inner.write_all(&block)?;
+                    }
+                    Err(io::Error::other("pipeline stage was abandoned"))
+                })?;
+        Ok(Self {
+            sender: Some(sender),
+            block: Vec::with_capacity(BLOCK_SIZE),
+            worker: Some(worker),
+        })
+    }
+
+    /// Hands the rest of the stream to the stage, waits for it to finish and returns the
+    /// result. Fails with the error of the stage if it failed.
+    pub fn finish(mut self) -> io::Result<T> {
+        self.send_block()?;
+        self.send(Vec::new())?;
+        self.sender = None;
+        self.join()
+    }
+
+    fn send_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let block = mem::replace(&mut self.block, Vec::with_capacity(BLOCK_SIZE));
+        self.send(block)
+    }
+
+    /// Sends `block` to the stage, or returns why the stage stopped taking blocks.
+    fn send(&mut self, block: Vec<u8>) -> io::Result<()> {
+        let sent = match &self.sender {
+            Some(sender) => sender.send(block).is_ok(),
+            None => false,
+        };
+        if sent {
+            return Ok(());
+        }
+        self.sender = None;
+        match self.join() {
+            Err(e) => Err(e),
+            Ok(_) => Err(io::Error::other("pipeline stage already finished")),
+        }
+    }
+
+    /// Waits for the stage to stop, passing on a panic.
+    fn join(&mut self) -> io::Result<T> {
+        match self.worker.take() {
+            Some(worker) => worker
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic)),
+            None => Err(io::Error::other("pipeline stage already stopped")),
+        }
+    }
+}
+
+impl<'scope, T: Send + 'scope> Write for PipeWriter<'scope, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let n = buf.len().min(BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..n]);
+        if self.block.len() == BLOCK_SIZE {
+            self.send_block()?;
+        }
+        Ok(n)
+    }
+
+    /// Hands the collected bytes to the stage, without waiting for it to write them.
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_block()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fails every write after the first `limit` bytes.
+    struct Failing {
+        limit: usize,
+        written: usize,
+    }
+
+    impl Write for Failing {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.written + buf.len() > self.limit {
+                return Err(io::Error::other("disk full"));
+            }
+            self.written += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn passes_everything_through_in_order() {
+        let data: Vec<u8> = (0..3 * BLOCK_SIZE + 17).map(|i| (i % 251) as u8).collect();
+        let output = thread::scope(|scope| {
+            let last = PipeWriter::spawn(scope, "last", Vec::new(), Ok)?;
+            let mut first =
+                PipeWriter::spawn(scope, "first", last, |last: PipeWriter<_>| last.finish())?;
+            for chunk in data.chunks(1000) {
+                first.write_all(chunk)?;
+            }
+            first.finish()
+        })
+        .unwrap();
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn reports_the_error_of_a_later_stage() {
+        let error = thread::scope(|scope| {
+            let failing = Failing {
+                limit: BLOCK_SIZE,
+                written: 0,
+            };
+            let mut pipe = PipeWriter::spawn(scope, "failing", failing, |_| Ok(()))?;
+            for _ in 0..(DEPTH + 4) {
+                pipe.write_all(&vec![0; BLOCK_SIZE])?;
+            }
+            pipe.finish()
+        })
+        .unwrap_err();
+        assert_eq!(error.to_string(), "disk full");
+    }
+
+    #[test]
+    fn does_not_finish_abandoned_stages() {
+        let finished = std::sync::atomic::AtomicBool::new(false);
+        thread::scope(|scope| {
+            let mut pipe = PipeWriter::spawn(scope, "stage", Vec::new(), |_| {
+                finished.store(true, std::sync::atomic::Ordering::SeqCst);
+                Ok(())
+            })
+            .unwrap();
+            pipe.write_all(b"half an archive").unwrap();
+        });
+        assert!(!finished.load(std::sync::atomic::Ordering::SeqCst));
+    }
+}
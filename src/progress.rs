@@ -0,0 +1,75 @@
+//! Progress reporting for long-running protect and recover operations.
+
+use crate::fec::Repair;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Receives progress updates while an archive is protected or recovered.
+///
+/// Protecting reports the bytes read from the input files (or the input stream); recovering
+/// reports the bytes of the archive read so far.
+pub trait Progress: Send + Sync {
+    /// The total number of bytes to process, once it is known.
+    fn set_total(&self, _total: u64) {}
+
+    /// Another `bytes` bytes were processed.
+    fn advance(&self, bytes: u64);
+
+    /// Work on the archive entry at `path` started.
+    fn set_entry(&self, _path: &Path) {}
+
+    /// The archive being protected lists its files' checksums in a manifest with this
+    /// BLAKE3 `hash`, which is the same for archives of the same files.
+    fn set_manifest_hash(&self, _hash: blake3::Hash) {}
+
+    /// Error correction repaired a damaged group of the archive being read.
+    fn block_corrected(&self, _repair: &Repair) {}
+
+    /// A warning was logged about the files or the archive, such as a file skipped
+    /// while protecting or a checksum mismatch while recovering.
+    fn warning(&self, _message: &str) {}
+
+    /// All work is done.
+    fn finish(&self) {}
+}
+
+/// Logs a warning, like `log::warn!`, and passes it on to the [`Progress`] `$progress`, an
+/// `Option<&Arc<dyn Progress>>`.
+macro_rules! warn_progress {
+    ($progress:expr, $($arg:tt)+) => {{
+        let message = format!($($arg)+);
+        log::warn!("{message}");
+        if let Some(progress) = $progress {
+            progress.warning(&message);
+        }
+    }};
+}
+pub(crate) use warn_progress;
+
+/// Reports every byte read through it to a [`Progress`].
+pub struct ProgressReader<R> {
+    inner: R,
+    progress: Arc<dyn Progress>,
+}
+
+impl<R: Read> ProgressReader<R> {
+    pub fn new(inner: R, progress: Arc<dyn Progress>) -> Self {
+        Self { inner, progress }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.advance(n as u64);
+        Ok(n)
+    }
+}
+
+/// Bytes skipped by seeking are not reported, so progress only counts what was read.
+impl<R: Seek> Seek for ProgressReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
@@ -0,0 +1,134 @@
+//! Terminal progress bars on standard error, drawn alongside the log output.
+
+use crate::events::{self, JsonProgress};
+use anyhow::Result;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif_log_bridge::LogWrapper;
+use log::{Log, Metadata, Record};
+use sage::progress::Progress;
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use std::sync::Arc;
+
+const SPINNER_TEMPLATE: &str =
+    "{spinner} [{elapsed_precise}] {bytes} {binary_bytes_per_sec} {wide_msg}";
+const BAR_TEMPLATE: &str = "[{elapsed_precise}] [{bar:30}] {bytes}/{total_bytes} {binary_bytes_per_sec} ETA {eta} {wide_msg}";
+
+/// Owns the progress bars and routes log records around them so lines do not tear.
+#[derive(Clone)]
+pub struct Bars {
+    multi: MultiProgress,
+    enabled: bool,
+}
+
+impl Bars {
+    /// Installs `logger` as the global logger; bars are drawn only if `enabled` and standard
+    /// error is a terminal.
+    pub fn init(logger: env_logger::Logger, enabled: bool) -> Result<Self> {
+        let multi = MultiProgress::new();
+        let level = logger.filter();
+        let draws = enabled && !events::enabled() && io::stderr().is_terminal();
+        // Only drawn bars need log lines routed around them.
+        if draws {
+            log::set_boxed_logger(Box::new(Preformatted(LogWrapper::new(
+                multi.clone(),
+                logger,
+            ))))?;
+        } else {
+            log::set_boxed_logger(Box::new(logger))?;
+        }
+        log::set_max_level(level);
+        Ok(Self {
+            multi,
+            enabled: enabled && (events::enabled() || io::stderr().is_terminal()),
+        })
+    }
+
+    /// Bars that are never drawn, without installing a logger.
+    #[cfg(test)]
+    pub fn hidden() -> Self {
+        Self {
+            multi: MultiProgress::new(),
+            enabled: false,
+        }
+    }
+
+    /// Starts a new bar, or returns `None` if progress is disabled.
+    ///
+    /// With `--json`, progress is reported as JSON events instead, even without a terminal.
+    pub fn bar(&self) -> Option<Arc<dyn Progress>> {
+        if !self.enabled {
+            return None;
+        }
+        if events::enabled() {
+            return Some(Arc::new(JsonProgress::new()));
+        }
+        let bar = ProgressBar::no_length().with_style(style(SPINNER_TEMPLATE));
+        Some(Arc::new(Bar(self.multi.add(bar))))
+    }
+
+    /// Hides the bars while `f` runs, for example while prompting for a passphrase.
+    pub fn suspend<T>(&self, f: impl FnOnce() -> T) -> T {
+        self.multi.suspend(f)
+    }
+}
+
+/// Formats the message of every record before passing it on.
+///
+/// The bridge holds the bars' lock while the record is written. Formatting some errors, such
+/// as age's, logs as well, which would wait for that lock forever.
+struct Preformatted<L>(L);
+
+impl<L: Log> Log for Preformatted<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.0.enabled(record.metadata()) {
+            return;
+        }
+        let message = record.args().to_string();
+        self.0.log(
+            &Record::builder()
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .args(format_args!("{message}"))
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .expect("progress template is valid")
+        .progress_chars("=> ")
+}
+
+/// A [`Progress`] that draws an indicatif bar with throughput and ETA.
+struct Bar(ProgressBar);
+
+impl Progress for Bar {
+    fn set_total(&self, total: u64) {
+        self.0.set_length(total);
+        self.0.set_style(style(BAR_TEMPLATE));
+    }
+
+    fn advance(&self, bytes: u64) {
+        self.0.inc(bytes);
+    }
+
+    fn set_entry(&self, path: &Path) {
+        self.0.set_message(path.display().to_string());
+    }
+
+    fn finish(&self) {
+        self.0.finish_and_clear();
+    }
+}
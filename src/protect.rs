@@ -0,0 +1,2548 @@
+use crate::cancel::{self, CancelReader};
+use crate::checksum::{CHECKSUM_ENTRY, Checksums, HashingReader, HashingWriter};
+use crate::compress::{
+    self, Codec, Encoder, SKIP_MIN_SIZE, SNIFF_LEN, STORED_LEVEL, StoreMarks, SwitchingEncoder,
+    ZstdTuning,
+};
+use crate::dedup::DedupWriter;
+use crate::exclude::ExcludeSet;
+use crate::fec::{FecParams, FecWriter, FileHeader};
+use crate::fileattrs::Attributes;
+use crate::incremental::{EntryKind, EntryState, INCREMENT_ENTRY, Increment, State};
+use crate::memory::{self, CompressionLimits};
+use crate::output::{AtomicFile, DEFAULT_OUTPUT_MODE};
+use crate::pipeline::PipeWriter;
+use crate::progress::{Progress, ProgressReader, warn_progress};
+use crate::resume::{self, CheckpointReader, Run, SavedFile};
+use crate::seekable::SeekableWriter;
+use crate::sparse::{SparseMap, SparseReader};
+use crate::tar_format::{Extensions, TarFormat};
+use crate::throttle::{Throttle, ThrottledReader, ThrottledWriter};
+use crate::xattrs;
+use age::armor::{ArmoredWriter, Format};
+use age::secrecy::SecretString;
+use anyhow::{Context, Result, anyhow};
+use log::{debug, warn};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+/// Default compression level.
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+/// Bytes taken from the start of each file to train a dictionary on.
+const SAMPLE_LEN: u64 = 64 * 1024;
+/// Total bytes of samples to train a dictionary on, about a hundred times its size, as zstd
+/// recommends.
+const TRAINING_LEN: usize = 100 * compress::DICTIONARY_SIZE;
+
+/// Name of the per-directory ignore file, in `.gitignore` syntax, honored when archiving.
+pub const SAGEIGNORE_FILE: &str = ".sageignore";
+
+/// How the age payload of an archive is encrypted.
+enum Encryption {
+    Recipients(Vec<Box<dyn age::Recipient + Send>>),
+    Passphrase(SecretString),
+    /// Not encrypted: the compressed payload is error-corrected as it is.
+    None,
+}
+
+/// Settings for writing a protected archive: tar -> compression -> age -> error correction.
+pub struct ProtectOptions {
+    encryption: Encryption,
+    codec: Codec,
+    compression_level: i32,
+    threads: u32,
+    fec_params: FecParams,
+    stripe_groups: Option<u16>,
+    output_mode: u32,
+    overwrite: bool,
+    exclude: ExcludeSet,
+    respect_gitignore: bool,
+    preserve_owner: bool,
+    preserve_permissions: bool,
+    preserve_mtime: bool,
+    preserve_xattrs: bool,
+    dereference: bool,
+    sparse: bool,
+    tar_format: TarFormat,
+    dedup: bool,
+    armor: bool,
+    compat: bool,
+    seekable: bool,
+    /// Set with `skip_compressed` or `no_compress_extensions`, where the files to store
+    /// are marked.
+    store_marks: Option<Arc<StoreMarks>>,
+    skip_compressed: bool,
+    /// Lowercase extensions, without the dot, of the files to store.
+    store_extensions: Vec<String>,
+    train_dictionary: bool,
+    long_window_log: Option<u32>,
+    max_memory: Option<u64>,
+    /// Set with `bwlimit`, shared by every input file read.
+    read_throttle: Option<Arc<Throttle>>,
+    bwlimit: Option<u64>,
+    /// Set with `deterministic`: the modification time of every entry.
+    deterministic_mtime: Option<u64>,
+    content_hash: Option<blake3::Hash>,
+    checkpoint_interval: u64,
+    progress: Option<Arc<dyn Progress>>,
+}
+
+impl Default for ProtectOptions {
+    fn default() -> Self {
+        Self {
+            encryption: Encryption::Recipients(Vec::new()),
+            codec: Codec::default(),
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            threads: num_cpus::get() as u32,
+            fec_params: FecParams::default(),
+            stripe_groups: None,
+            output_mode: DEFAULT_OUTPUT_MODE,
+            overwrite: false,
+            exclude: ExcludeSet::default(),
+            respect_gitignore: false,
+            preserve_owner: true,
+            preserve_permissions: true,
+            preserve_mtime: true,
+            preserve_xattrs: true,
+            dereference: false,
+            sparse: true,
+            tar_format: TarFormat::Pax,
+            dedup: false,
+            armor: false,
+            compat: false,
+            seekable: false,
+            store_marks: None,
+            skip_compressed: false,
+            store_extensions: Vec::new(),
+            train_dictionary: false,
+            long_window_log: None,
+            max_memory: None,
+            read_throttle: None,
+            bwlimit: None,
+            deterministic_mtime: None,
+            content_hash: None,
+            checkpoint_interval: resume::DEFAULT_CHECKPOINT_INTERVAL,
+            progress: None,
+        }
+    }
+}
+
+impl ProtectOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Encrypts to `recipient`, in addition to any recipients added before.
+    pub fn recipient(self, recipient: Box<dyn age::Recipient + Send>) -> Self {
+        self.recipients([recipient])
+    }
+
+    /// Encrypts to every recipient in `recipients`, replacing a passphrase if one was set.
+    pub fn recipients(
+        mut self,
+        recipients: impl IntoIterator<Item = Box<dyn age::Recipient + Send>>,
+    ) -> Self {
+        match &mut self.encryption {
+            Encryption::Recipients(existing) => existing.extend(recipients),
+            Encryption::Passphrase(_) | Encryption::None => {
+                self.encryption = Encryption::Recipients(recipients.into_iter().collect())
+            }
+        }
+        self
+    }
+
+    /// Encrypts with an scrypt passphrase instead of recipients.
+    pub fn passphrase(mut self, passphrase: SecretString) -> Self {
+        self.encryption = Encryption::Passphrase(passphrase);
+        self
+    }
+
+    /// Leaves the payload unencrypted, for data anyone may read, replacing any recipients or
+    /// passphrase. The archive is still compressed, error-corrected and checksummed, and
+    /// recover reads it without identities. Cannot be combined with armor or `compat`.
+    pub fn unencrypted(mut self) -> Self {
+        self.encryption = Encryption::None;
+        self
+    }
+
+    /// Sets the compression codec; zstd by default.
+    pub fn compression(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Sets the compression level, clamped to the range of the codec when compressing.
+    pub fn compression_level(mut self, level: i32) -> Self {
+        self.compression_level = level;
+        self
+    }
+
+    /// Sets the number of compression worker threads; all CPUs by default.
+    ///
+    /// Only zstd and xz compress on several threads; the other codecs always use one.
+    pub fn threads(mut self, threads: u32) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Uses the default error correction geometry with `percent` redundancy.
+    pub fn redundancy(mut self, percent: u8) -> Self {
+        self.fec_params = FecParams::with_redundancy(percent);
+        self
+    }
+
+    /// Sets the error correction geometry explicitly.
+    pub fn fec_params(mut self, params: FecParams) -> Self {
+        self.fec_params = params;
+        self
+    }
+
+    /// Interleaves the frames of `groups` consecutive error correction groups, so a long run
+    /// of damage is spread over all of them; see [`crate::fec::EccLayout`]. Off by default,
+    /// and needs 2 to [`MAX_STRIPE_GROUPS`](crate::fec::MAX_STRIPE_GROUPS) groups.
+    pub fn interleave(mut self, groups: u16) -> Self {
+        self.stripe_groups = Some(groups);
+        self
+    }
+
+    /// Sets the permissions of archives created by [`ProtectOptions::protect_to_file`].
+    pub fn output_mode(mut self, mode: u32) -> Self {
+        self.output_mode = mode;
+        self
+    }
+
+    /// Lets [`ProtectOptions::protect_to_file`] replace an existing file; off by default.
+    pub fn overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Leaves entries matching `exclude` out when archiving a directory.
+    pub fn exclude(mut self, exclude: ExcludeSet) -> Self {
+        self.exclude = exclude;
+        self
+    }
+
+    /// Also skips files matched by `.gitignore` files, `.git/info/exclude` and the global git
+    /// excludes file. `.sageignore` files are always honored.
+    pub fn respect_gitignore(mut self, respect: bool) -> Self {
+        self.respect_gitignore = respect;
+        self
+    }
+
+    /// Whether to store owner UIDs, GIDs and names; on by default. Without them, entries are
+    /// stored as owned by root.
+    pub fn preserve_owner(mut self, preserve: bool) -> Self {
+        self.preserve_owner = preserve;
+        self
+    }
+
+    /// Whether to store exact permission bits; on by default. Without them, directories and
+    /// executables are stored as `0755` and other files as `0644`.
+    pub fn preserve_permissions(mut self, preserve: bool) -> Self {
+        self.preserve_permissions = preserve;
+        self
+    }
+
+    /// Whether to store modification times; on by default. Without them, entries are stored
+    /// with the Unix epoch as their modification time.
+    pub fn preserve_mtime(mut self, preserve: bool) -> Self {
+        self.preserve_mtime = preserve;
+        self
+    }
+
+    /// Whether to store extended attributes, including POSIX ACLs and SELinux labels, as PAX
+    /// records; on by default.
+    pub fn preserve_xattrs(mut self, preserve: bool) -> Self {
+        self.preserve_xattrs = preserve;
+        self
+    }
+
+    /// Follows symlinks and stores the files and directories they point to; off by default,
+    /// which stores symlinks as links. Broken symlinks and symlink loops are skipped.
+    pub fn dereference(mut self, dereference: bool) -> Self {
+        self.dereference = dereference;
+        self
+    }
+
+    /// Stores only the data of sparse files, such as disk images, and where their holes
+    /// are; on by default. See [`crate::sparse`]. Deterministic archives store holes as
+    /// zeros, since where a filesystem keeps holes does not depend on the contents alone.
+    pub fn sparse(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    /// Writes tar entries in `format`; PAX by default. See [`crate::tar_format`].
+    pub fn tar_format(mut self, format: TarFormat) -> Self {
+        self.tar_format = format;
+        self
+    }
+
+    /// Splits the payload into content-defined chunks and stores repeated chunks only once;
+    /// off by default. See [`crate::dedup`].
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Writes the age payload as ASCII armor, which survives being pasted as text; off by
+    /// default. Armored archives are plain age files without the error correction layer.
+    pub fn armor(mut self, armor: bool) -> Self {
+        self.armor = armor;
+        self
+    }
+
+    /// Writes a bare binary age file of the compressed tar stream, without the sage header
+    /// or error correction, so that age, the codec's own tool and tar can recover it without
+    /// sage; off by default. Cannot be combined with dedup, a trained dictionary or armor.
+    pub fn compat(mut self, compat: bool) -> Self {
+        self.compat = compat;
+        self
+    }
+
+    /// Compresses in independent zstd frames with a seek table, so that listing and
+    /// extracting selected paths can skip what they do not need; off by default. See
+    /// [`crate::seekable`]. Requires zstd, and cannot be combined with dedup or armor.
+    pub fn seekable(mut self, seekable: bool) -> Self {
+        self.seekable = seekable;
+        self
+    }
+
+    /// Stores files that are already compressed, such as photos, videos and zip files, at
+    /// zstd's fastest level instead of compressing them again; off by default. Files are
+    /// recognized by their signature, or by barely compressing at the start. Requires zstd,
+    /// and cannot be combined with dedup or seekable frames.
+    pub fn skip_compressed(mut self, skip: bool) -> Self {
+        self.skip_compressed = skip;
+        self.with_store_marks()
+    }
+
+    /// Stores files whose extension is one of `extensions`, such as `jpg` or `mp4`, at
+    /// zstd's fastest level instead of compressing them; none by default. Extensions are
+    /// matched without regard to case, with or without their dot. Like
+    /// [`skip_compressed`](Self::skip_compressed), this requires zstd, and cannot be combined
+    /// with dedup or seekable frames.
+    pub fn no_compress_extensions<S: AsRef<str>>(
+        mut self,
+        extensions: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.store_extensions = extensions
+            .into_iter()
+            .map(|extension| extension.as_ref().trim_start_matches('.').to_lowercase())
+            .filter(|extension| !extension.is_empty())
+            .collect();
+        self.with_store_marks()
+    }
+
+    fn with_store_marks(mut self) -> Self {
+        let marking = self.skip_compressed || !self.store_extensions.is_empty();
+        self.store_marks = marking.then(|| Arc::new(StoreMarks::default()));
+        self
+    }
+
+    /// Trains a zstd dictionary on samples of the files in the input directory and
+    /// compresses with it, which shrinks archives of many small, similar files; off by
+    /// default. The dictionary is stored at the start of the encrypted payload. Requires
+    /// zstd, and cannot be combined with seekable frames; files and streams are compressed
+    /// without a dictionary.
+    pub fn train_dictionary(mut self, train: bool) -> Self {
+        self.train_dictionary = train;
+        self
+    }
+
+    /// Enables zstd long-distance matching with a window of 2^`window_log` bytes, which
+    /// finds repeats far apart in large files, such as disk images and database dumps; off
+    /// by default. Recovering needs as much memory as the window, so it is recorded in the
+    /// archive header. Requires zstd, and cannot be combined with seekable frames.
+    pub fn long_window_log(mut self, window_log: u32) -> Self {
+        self.long_window_log = Some(window_log);
+        self
+    }
+
+    /// Keeps the memory compression and error correction use to about `bytes`, by
+    /// compressing on fewer threads and, if one is still too many, with a smaller zstd
+    /// window; see [`crate::memory`]. Fails if that is not enough.
+    pub fn max_memory(mut self, bytes: u64) -> Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    /// Reads the input and writes the archive at up to `bytes_per_sec` bytes per second
+    /// each, so that a backup in the background leaves disk and network to others.
+    pub fn bwlimit(mut self, bytes_per_sec: u64) -> Self {
+        self.read_throttle = Some(Arc::new(Throttle::new(bytes_per_sec)));
+        self.bwlimit = Some(bytes_per_sec);
+        self
+    }
+
+    /// Makes the payload depend only on the contents of the input, so that protecting the
+    /// same files again, on any machine, compresses to the same bytes: entries are sorted
+    /// by name, owned by root and dated `mtime`, such as `SOURCE_DATE_EPOCH`, and
+    /// compression runs on one thread. The encryption still differs, since age picks a new
+    /// key for every archive.
+    pub fn deterministic(mut self, mtime: u64) -> Self {
+        self.deterministic_mtime = Some(mtime);
+        self
+    }
+
+    /// Records `hash`, as returned by [`ProtectOptions::hash_contents`], in the archive
+    /// header, so that later archives can be compared with this one without decrypting it.
+    ///
+    /// The hash is stored unencrypted: it shows whether two archives hold the same contents.
+    pub fn record_content_hash(mut self, hash: blake3::Hash) -> Self {
+        self.content_hash = Some(hash);
+        self
+    }
+
+    /// Takes a checkpoint of [`ProtectOptions::protect_resumable`] after about every `bytes`
+    /// of tar stream; by default every 1 GiB. Each checkpoint waits for the archive to
+    /// reach the disk, so much smaller intervals slow the run down.
+    pub fn checkpoint_interval(mut self, bytes: u64) -> Self {
+        self.checkpoint_interval = bytes.max(1);
+        self
+    }
+
+    /// Reports the bytes read from the input, and each file archived, to `progress`.
+    pub fn progress(mut self, progress: Arc<dyn Progress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// The age encryptor of the payload, or `None` if it is left unencrypted.
+    fn encryptor(&self) -> Result<Option<age::Encryptor>> {
+        let encryptor = match &self.encryption {
+            Encryption::Recipients(recipients) => {
+                if recipients.is_empty() {
+                    warn!("No valid recipients provided.");
+                    return Err(crate::Error::NoRecipients.into());
+                }
+                debug!(
+                    "Initializing age encryption for {} recipients.",
+                    recipients.len()
+                );
+                age::Encryptor::with_recipients(
+                    recipients.iter().map(|r| r.as_ref() as &dyn age::Recipient),
+                )?
+            }
+            Encryption::Passphrase(passphrase) => {
+                debug!("Initializing age passphrase encryption.");
+                age::Encryptor::with_user_passphrase(passphrase.clone())
+            }
+            Encryption::None => {
+                debug!("Leaving the payload unencrypted.");
+                return Ok(None);
+            }
+        };
+        Ok(Some(encryptor))
+    }
+
+    /// Protects the file or directory at `input_path` into a new file at `output_path`.
+    ///
+    /// The archive only appears at `output_path` once it is complete; see [`AtomicFile`].
+    pub fn protect_to_file(&self, input_path: &Path, output_path: &Path) -> crate::Result<()> {
+        debug!(
+            "Creating output file: {} (mode {:04o})",
+            output_path.display(),
+            self.output_mode
+        );
+        let output_file = AtomicFile::create(output_path, self.output_mode, self.overwrite)
+            .with_context(|| format!("Failed to create output file: {}", output_path.display()))?;
+        self.protect(input_path, output_file)?
+            .commit()
+            .with_context(|| format!("Failed to write output file: {}", output_path.display()))?;
+
+        debug!(
+            "Protection complete. Output written to: {}",
+            output_path.display()
+        );
+        Ok(())
+    }
+
+    /// Protects the file or directory at `input_path` into a new file at `output_path`, so
+    /// that a run that is stopped, by a crash or a reboot, carries on where it was when
+    /// this is called again.
+    ///
+    /// The archive is written to [`resume::partial_path`] and moved to `output_path` once it
+    /// is complete. After about every [`checkpoint_interval`](Self::checkpoint_interval) of
+    /// tar stream, a checkpoint is recorded at [`resume::checkpoint_path`]; if one is there,
+    /// the run continues from it, and the archive stays encrypted to the recipients of the
+    /// run that began it. Directories are archived in name order, and the input must not
+    /// change between the runs. The checkpoint holds the key of the payload until the run
+    /// completes, so it is only readable by its owner.
+    ///
+    /// Only zstd and no compression are supported, without dedup, seekable frames, armor,
+    /// `compat`, skipping compressed files, a dictionary, long-distance matching or a
+    /// recorded content hash.
+    pub fn protect_resumable(&self, input_path: &Path, output_path: &Path) -> crate::Result<()> {
+        if !matches!(self.codec, Codec::Zstd | Codec::None) {
+            warn!("A resumable run cannot compress with {}.", self.codec);
+            return Err(anyhow!(
+                "Resumable runs compress with zstd or not at all, not with {}.",
+                self.codec
+            )
+            .into());
+        }
+        if self.dedup
+            || self.seekable
+            || self.armor
+            || self.compat
+            || self.store_marks.is_some()
+            || self.train_dictionary
+            || self.long_window_log.is_some()
+            || self.content_hash.is_some()
+        {
+            warn!("A resumable run was combined with an option it does not support.");
+            return Err(anyhow!(
+                "Resumable runs cannot be combined with dedup, seekable frames, armor, \
+                 --compat, --skip-compressed, --no-compress-extensions, --train-dictionary, \
+                 --long or a recorded content hash."
+            )
+            .into());
+        }
+        if !self.overwrite && output_path.symlink_metadata().is_ok() {
+            return Err(anyhow!(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "file already exists"
+            ))
+            .context(format!(
+                "Failed to create output file: {}",
+                output_path.display()
+            ))
+            .into());
+        }
+        let level = self
+            .codec
+            .effective_level(self.compression_level)
+            .unwrap_or(0);
+        let threads = match self.max_memory {
+            Some(limit) => {
+                let limits = memory::fit_compression(
+                    limit,
+                    self.codec,
+                    level,
+                    self.threads,
+                    None,
+                    self.fec_params,
+                )?;
+                if limits.window_log.is_some() {
+                    warn!("The memory limit is too low for a resumable run.");
+                    return Err(anyhow!(
+                        "A resumable run needs the full zstd window of level {level}; lower \
+                         the compression level or raise the memory limit."
+                    )
+                    .into());
+                }
+                limits.threads
+            }
+            None => self.threads,
+        };
+        let threads = if self.deterministic_mtime.is_some() {
+            1
+        } else {
+            threads
+        };
+        let scrypt;
+        let encryption = match &self.encryption {
+            Encryption::Recipients(recipients) => {
+                if recipients.is_empty() {
+                    warn!("No valid recipients provided.");
+                    return Err(crate::Error::NoRecipients);
+                }
+                resume::Encryption::Recipients(
+                    recipients
+                        .iter()
+                        .map(|r| r.as_ref() as &dyn age::Recipient)
+                        .collect(),
+                )
+            }
+            Encryption::Passphrase(passphrase) => {
+                scrypt = age::scrypt::Recipient::new(passphrase.clone());
+                resume::Encryption::Recipients(vec![&scrypt])
+            }
+            Encryption::None => resume::Encryption::None,
+        };
+        let header = FileHeader {
+            compression_level: level as u8,
+            codec: Some(self.codec),
+            unencrypted: matches!(self.encryption, Encryption::None),
+            stripe_groups: self.stripe_groups,
+            ..FileHeader::new(self.fec_params)
+        };
+        let settings = resume::Settings {
+            header,
+            level: self.compression_level,
+            threads,
+            interval: self.checkpoint_interval,
+            output_mode: self.output_mode,
+            throttle: self.bwlimit.map(|rate| Arc::new(Throttle::new(rate))),
+        };
+        let mut run = Run::open(input_path, output_path, settings, encryption)?;
+        self.archive(input_path, &mut run.writer(), Some(&mut run))?;
+        run.finish(output_path, self.overwrite)?;
+        if let Some(progress) = &self.progress {
+            progress.finish();
+        }
+        debug!(
+            "Protection complete. Output written to: {}",
+            output_path.display()
+        );
+        Ok(())
+    }
+
+    /// Protects the file or directory at `input_path` into `output`, returning the writer.
+    pub fn protect<W: Write + Send>(&self, input_path: &Path, output: W) -> crate::Result<W> {
+        let dictionary = self.dictionary(input_path)?;
+        let output = self.write_archive(output, false, dictionary.as_deref(), |encoder| {
+            self.archive(input_path, encoder, None)
+        })?;
+        Ok(output)
+    }
+
+    /// Returns the hash of the tar stream that [`ProtectOptions::protect`] would write for
+    /// `input_path`, before compression and encryption, without writing an archive.
+    ///
+    /// The hash stays the same as long as the archived files and their metadata do, so
+    /// comparing it with the [`content_hash`](crate::fec::FileHeader::content_hash) of an
+    /// earlier archive tells whether anything changed. The input is read in full, and
+    /// reported to the progress as it is.
+    pub fn hash_contents(&self, input_path: &Path) -> crate::Result<blake3::Hash> {
+        debug!("Hashing the tar stream of {}.", input_path.display());
+        let mut hasher = HashingWriter::new(io::sink());
+        self.archive(input_path, &mut hasher, None)?;
+        Ok(hasher.hash())
+    }
+
+    /// Writes the tar stream of the file or directory at `input_path` to `encoder`, as part
+    /// of the resumable `run` if there is one.
+    fn archive(
+        &self,
+        input_path: &Path,
+        encoder: &mut dyn Write,
+        mut run: Option<&mut Run>,
+    ) -> Result<()> {
+        debug!("Archiving input {} into tar stream.", input_path.display());
+        let mut tar_builder = tar::Builder::new(encoder);
+        if let Some(progress) = &self.progress {
+            progress.set_total(self.input_size(input_path)?);
+            if let Some(run) = &run {
+                // Tar headers make this a little more than the input read before.
+                progress.advance(run.resumed_bytes());
+            }
+        }
+        let mut checksums = run
+            .as_mut()
+            .and_then(|run| run.take_checksums())
+            .unwrap_or_default();
+        if input_path.is_dir() {
+            self.archive_dir(
+                &mut tar_builder,
+                input_path,
+                Path::new(""),
+                None,
+                &mut checksums,
+                run.as_deref_mut(),
+            )?;
+            debug!("Directory archived successfully: {}", input_path.display());
+        } else {
+            let filename = input_path
+                .file_name()
+                .ok_or_else(|| anyhow!("Invalid input file name"))?
+                .to_string_lossy();
+            let rel_path = Path::new(filename.as_ref());
+            match run.as_deref_mut() {
+                Some(run) if run.skips(0) => {
+                    if let Some(saved) = run.take_file(0) {
+                        self.resume_file(run, 0, rel_path, input_path, saved, &mut checksums)?;
+                    }
+                }
+                run => self
+                    .append_file(
+                        &mut tar_builder,
+                        rel_path,
+                        input_path,
+                        &mut checksums,
+                        run.map(|run| (run, 0)),
+                    )
+                    .context("Failed to open input file")?,
+            }
+            debug!("File archived successfully: {}", input_path.display());
+        }
+        if let Some(run) = &run {
+            run.check_finished_file()?;
+        }
+        self.append_checksums(
+            &mut tar_builder,
+            &checksums,
+            self.metadata_mtime(input_path),
+        )?;
+        tar_builder.finish()?;
+        Ok(())
+    }
+
+    /// Protects only what changed in the directory `input_path` since `base` was recorded.
+    ///
+    /// Without a base, everything is archived. The archive ends with an
+    /// [`INCREMENT_ENTRY`](crate::incremental::INCREMENT_ENTRY) listing the paths deleted
+    /// since the base. Returns the writer and the state to use as the base of the next
+    /// increment.
+    pub fn protect_incremental<W: Write + Send>(
+        &self,
+        input_path: &Path,
+        base: Option<&State>,
+        output: W,
+    ) -> crate::Result<(W, State)> {
+        if !input_path.is_dir() {
+            warn!("Incremental backups need a directory.");
+            return Err(anyhow!(
+                "Incremental backups need a directory, not {}.",
+                input_path.display()
+            )
+            .into());
+        }
+        let empty = State::new(input_path);
+        let base_state = base.unwrap_or(&empty);
+        if base.is_some_and(|base| Path::new(&base.root) != input_path) {
+            warn!(
+                "The base state was recorded for {}, not {}.",
+                base_state.root,
+                input_path.display()
+            );
+        }
+        let mut next = State::new(input_path);
+        let dictionary = self.dictionary(input_path)?;
+        let output = self.write_archive(output, false, dictionary.as_deref(), |encoder| {
+            debug!(
+                "Archiving changes in {} since the base state ({} entries).",
+                input_path.display(),
+                base_state.entries.len()
+            );
+            let mut tar_builder = tar::Builder::new(encoder);
+            if let Some(progress) = &self.progress {
+                progress.set_total(self.input_size(input_path)?);
+            }
+            let mut checksums = Checksums::new();
+            self.archive_dir(
+                &mut tar_builder,
+                input_path,
+                Path::new(""),
+                Some((base_state, &mut next)),
+                &mut checksums,
+                None,
+            )?;
+            if base.is_some() {
+                let deleted = base_state.deleted_since(&next);
+                debug!("{} paths deleted since the base state.", deleted.len());
+                let data = serde_json::to_vec(&Increment::new(deleted))?;
+                let mtime = self.metadata_mtime(input_path);
+                append_metadata_entry(
+                    self.tar_format,
+                    &mut tar_builder,
+                    INCREMENT_ENTRY,
+                    &data,
+                    mtime,
+                )?;
+            }
+            self.append_checksums(
+                &mut tar_builder,
+                &checksums,
+                self.metadata_mtime(input_path),
+            )?;
+            tar_builder.finish()?;
+            Ok(())
+        })?;
+        Ok((output, next))
+    }
+
+    /// Protects everything read from `input`, such as standard input, into `output`.
+    ///
+    /// A tar entry must know its size up front, so the stream is stored as it is instead of
+    /// being archived, and the archive header marks it as a single stream.
+    pub fn protect_stream<R: Read, W: Write + Send>(
+        &self,
+        input: R,
+        output: W,
+    ) -> crate::Result<W> {
+        if self.train_dictionary {
+            warn!("A dictionary is only trained on files; compressing the stream without one.");
+        }
+        Ok(self.write_archive(output, true, None, |encoder| {
+            debug!("Compressing input stream.");
+            let input = ThrottledReader::new(CancelReader::new(input), self.read_throttle.clone());
+            let mut input: Box<dyn Read> = match &self.progress {
+                Some(progress) => Box::new(ProgressReader::new(input, progress.clone())),
+                None => Box::new(input),
+            };
+            let bytes = io::copy(&mut input, encoder).context("Failed to read input stream")?;
+            debug!("Input stream complete. {} bytes read.", bytes);
+            Ok(())
+        })?)
+    }
+
+    /// Writes the entries of `archive` into `output` as a new archive, followed by the files
+    /// and directories at `paths`, each stored under its file name.
+    ///
+    /// The existing entries are copied as they are, without reading the files they were
+    /// made from again; only their checksum list is merged with that of the new files. The
+    /// payload is compressed and encrypted again, to the recipients of these options. Fails
+    /// if the archive already holds an entry with the name of a new path.
+    pub fn append<R: Read, W: Write + Send>(
+        &self,
+        archive: tar::Archive<R>,
+        paths: &[PathBuf],
+        output: W,
+    ) -> crate::Result<W> {
+        let mut names = Vec::with_capacity(paths.len());
+        for path in paths {
+            let name = path
+                .file_name()
+                .ok_or_else(|| anyhow!("Cannot append {}: it has no file name.", path.display()))?;
+            if names.contains(&name) {
+                warn!("Two paths to append have the name {}.", path.display());
+                return Err(anyhow!(
+                    "Cannot append two paths named {}.",
+                    Path::new(name).display()
+                )
+                .into());
+            }
+            names.push(name);
+        }
+        Ok(self.write_archive(output, false, None, |encoder| {
+            let mut tar_builder = tar::Builder::new(encoder);
+            let (mut checksums, mtime) = copy_entries(archive, &mut tar_builder, &names, false)?;
+            if let Some(progress) = &self.progress {
+                let mut total = 0;
+                for path in paths {
+                    total += self.input_size(path)?;
+                }
+                progress.set_total(total);
+            }
+            for (path, name) in paths.iter().zip(&names) {
+                debug!(
+                    "Appending {} as {}",
+                    path.display(),
+                    Path::new(name).display()
+                );
+                let name = Path::new(name);
+                if path.is_dir() {
+                    self.append_dir(&mut tar_builder, name, path, &fs::symlink_metadata(path)?)?;
+                    self.archive_dir(&mut tar_builder, path, name, None, &mut checksums, None)?;
+                } else {
+                    self.append_file(&mut tar_builder, name, path, &mut checksums, None)
+                        .with_context(|| format!("Failed to append {}", path.display()))?;
+                }
+            }
+            self.append_checksums(&mut tar_builder, &checksums, mtime)?;
+            tar_builder.finish()?;
+            Ok(())
+        })?)
+    }
+
+    /// Writes the entries of `archive`, a tar archive made by another tool or recovered from
+    /// another archive, into a new archive at `output`, listing the hashes of its files.
+    ///
+    /// The entries are copied header by header, with their metadata and extension records as
+    /// they are; a checksum list already in `archive` is merged into the new one.
+    pub fn import<R: Read, W: Write + Send>(
+        &self,
+        archive: tar::Archive<R>,
+        output: W,
+    ) -> crate::Result<W> {
+        Ok(self.write_archive(output, false, None, |encoder| {
+            let mut tar_builder = tar::Builder::new(encoder);
+            let (checksums, mtime) = copy_entries(archive, &mut tar_builder, &[], true)?;
+            self.append_checksums(&mut tar_builder, &checksums, mtime)?;
+            tar_builder.finish()?;
+            Ok(())
+        })?)
+    }
+
+    /// Finds what protecting `input_path` would archive, and estimates the archive size,
+    /// without writing anything.
+    ///
+    /// The compression ratio is measured on samples of up to 8 MiB from the start of the
+    /// files; deduplication is not taken into account.
+    pub fn dry_run(&self, input_path: &Path) -> crate::Result<DryRun> {
+        let mut plan = DryRun::default();
+        let metadata = input_path
+            .metadata()
+            .with_context(|| format!("Failed to read input: {}", input_path.display()))?;
+        let mut files = Vec::new();
+        if metadata.is_dir() {
+            let mut hard_links = HashSet::new();
+            for entry in self.walk(input_path) {
+                cancel::check()?;
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        warn!("Would skip: {e}");
+                        continue;
+                    }
+                };
+                let path = entry.path();
+                let rel_path = path.strip_prefix(input_path).map_err(anyhow::Error::from)?;
+                if rel_path.as_os_str().is_empty() {
+                    continue;
+                }
+                let metadata = if self.dereference {
+                    path.metadata()
+                } else {
+                    path.symlink_metadata()
+                };
+                let Ok(metadata) = metadata else {
+                    warn!("Would skip unreadable path: {}", path.display());
+                    continue;
+                };
+                let kind = if metadata.is_symlink() {
+                    PlannedKind::Symlink
+                } else if metadata.is_dir() {
+                    PlannedKind::Directory
+                } else if !metadata.is_file() {
+                    warn!("Would skip special file: {}", path.display());
+                    continue;
+                } else if hard_link_id(&metadata).is_some_and(|id| !hard_links.insert(id)) {
+                    PlannedKind::HardLink
+                } else {
+                    files.push(path.to_path_buf());
+                    PlannedKind::File
+                };
+                plan.add(rel_path.to_path_buf(), kind, metadata.len());
+            }
+        } else {
+            let filename = input_path
+                .file_name()
+                .ok_or_else(|| anyhow!("Invalid input file name"))?;
+            files.push(input_path.to_path_buf());
+            plan.add(PathBuf::from(filename), PlannedKind::File, metadata.len());
+        }
+        self.estimate(&mut plan, &files)?;
+        Ok(plan)
+    }
+
+    /// Fills in the size estimates of `plan` by compressing samples of `files`.
+    fn estimate(&self, plan: &mut DryRun, files: &[PathBuf]) -> Result<()> {
+        const SAMPLE_LIMIT: u64 = 8 * 1024 * 1024;
+        const MIN_FILE_SAMPLE: u64 = 64 * 1024;
+
+        let per_file = (SAMPLE_LIMIT / files.len().max(1) as u64).max(MIN_FILE_SAMPLE);
+        let mut encoder = self
+            .codec
+            .encoder(
+                CountingWriter::default(),
+                self.compression_level,
+                self.threads,
+            )
+            .with_context(|| format!("Failed to create {} encoder", self.codec))?;
+        for path in files {
+            if plan.sampled_bytes >= SAMPLE_LIMIT {
+                break;
+            }
+            cancel::check()?;
+            let Ok(file) = File::open(path) else {
+                warn!("Would fail to read: {}", path.display());
+                continue;
+            };
+            let limit = per_file.min(SAMPLE_LIMIT - plan.sampled_bytes);
+            plan.sampled_bytes += io::copy(&mut file.take(limit), &mut encoder)?;
+        }
+        let compressed = encoder.finish()?.0;
+        let ratio = if plan.sampled_bytes == 0 {
+            1.0
+        } else {
+            compressed as f64 / plan.sampled_bytes as f64
+        };
+        debug!(
+            "Sampled {} bytes, compressed to {} ({:.1}%).",
+            plan.sampled_bytes,
+            compressed,
+            ratio * 100.0
+        );
+
+        // Tar stores a 512-byte header per entry, pads file data to 512 bytes and ends with
+        // two empty records.
+        let tar_size =
+            plan.entries.len() as u64 * 512 + files.len() as u64 * 511 + plan.bytes + 1024;
+        plan.estimated_compressed = (tar_size as f64 * ratio).ceil() as u64;
+        if self.armor {
+            plan.estimated_archive = plan.estimated_compressed.div_ceil(3) * 4 * 65 / 64;
+        } else if self.compat {
+            plan.estimated_archive = plan.estimated_compressed;
+        } else {
+            let params = self.fec_params;
+            plan.estimated_archive = (plan.estimated_compressed as f64
+                * (1.0 + params.redundancy() / 100.0))
+                .ceil() as u64;
+        }
+        Ok(())
+    }
+
+    /// Archives the contents of the directory `input_path`, below `prefix` in the archive.
+    ///
+    /// With `delta`, entries that are unchanged from the base state are skipped and every
+    /// archived path is recorded into the next state. The hash of every file archived is
+    /// recorded into `checksums`.
+    ///
+    /// With a resumable `run`, entries are archived in name order, those the run resumed
+    /// after are skipped, and checkpoints are taken between and within entries.
+    fn archive_dir<W: Write>(
+        &self,
+        tar_builder: &mut tar::Builder<W>,
+        input_path: &Path,
+        prefix: &Path,
+        mut delta: Option<(&State, &mut State)>,
+        checksums: &mut Checksums,
+        mut run: Option<&mut Run>,
+    ) -> Result<()> {
+        if !self.exclude.is_empty() {
+            debug!("Applying {} exclude patterns.", self.exclude.len());
+        }
+        let mut hard_links: HashMap<(u64, u64), PathBuf> = HashMap::new();
+        for (index, entry) in self.walk_sorted(input_path, run.is_some()).enumerate() {
+            let index = index as u64;
+            cancel::check()?;
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) if is_loop(&e) => {
+                    warn_progress!(self.progress.as_ref(), "Skipping symlink loop: {e}");
+                    continue;
+                }
+                Err(e)
+                    if self.dereference
+                        && e.io_error()
+                            .is_some_and(|e| e.kind() == io::ErrorKind::NotFound) =>
+                {
+                    warn_progress!(self.progress.as_ref(), "Skipping broken symlink: {e}");
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let path = entry.path();
+            let rel_path = path.strip_prefix(input_path)?;
+            // Skip the root directory itself (empty rel_path)
+            if rel_path.as_os_str().is_empty() {
+                continue;
+            }
+            let rel_path = &prefix.join(rel_path);
+            let metadata = if self.dereference {
+                match path.metadata() {
+                    Ok(metadata) => metadata,
+                    Err(_) if path.is_symlink() => {
+                        warn_progress!(
+                            self.progress.as_ref(),
+                            "Skipping broken symlink: {}",
+                            path.display()
+                        );
+                        continue;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            } else {
+                path.symlink_metadata()?
+            };
+            if let Some(run) = run.as_deref_mut() {
+                if run.skips(index) {
+                    if let Some(saved) = run.take_file(index) {
+                        self.resume_file(run, index, rel_path, path, saved, checksums)?;
+                    } else if metadata.is_file()
+                        && let Some(progress) = &self.progress
+                    {
+                        // Counted as archived, as it was before the checkpoint.
+                        progress.set_entry(rel_path);
+                    }
+                    // Later links to the file are stored as links again.
+                    if metadata.is_file()
+                        && let Some(id) = hard_link_id(&metadata)
+                    {
+                        hard_links
+                            .entry(id)
+                            .or_insert_with(|| rel_path.to_path_buf());
+                    }
+                    continue;
+                }
+                if run.due() {
+                    run.save(index, checksums, None)
+                        .context("Failed to take a checkpoint")?;
+                }
+            }
+            if let Some((base, next)) = &mut delta
+                && let Some(state) = EntryState::from_metadata(&metadata)
+            {
+                let key = rel_path.to_string_lossy().into_owned();
+                // Directories are always stored, so their metadata stays current.
+                let unchanged =
+                    state.kind != EntryKind::Directory && base.is_unchanged(&key, &state);
+                next.entries.insert(key, state);
+                if unchanged {
+                    debug!("Unchanged since the base backup: {}", rel_path.display());
+                    continue;
+                }
+            }
+            if metadata.is_symlink() {
+                let target = fs::read_link(path)?;
+                let (mut header, extensions) = self.header(rel_path, &metadata)?;
+                header.set_size(0);
+                self.append_entry(
+                    tar_builder,
+                    &mut header,
+                    extensions,
+                    rel_path,
+                    Some(&target),
+                )?;
+            } else if metadata.is_dir() {
+                self.append_dir(tar_builder, rel_path, path, &metadata)?;
+            } else if metadata.is_file() {
+                let link_target = match hard_link_id(&metadata) {
+                    Some(id) => match hard_links.entry(id) {
+                        Entry::Occupied(first) => Some(first.get().clone()),
+                        Entry::Vacant(slot) => {
+                            slot.insert(rel_path.to_path_buf());
+                            None
+                        }
+                    },
+                    None => None,
+                };
+                match link_target {
+                    Some(target) => {
+                        debug!(
+                            "Storing {} as a hard link to {}",
+                            rel_path.display(),
+                            target.display()
+                        );
+                        let (mut header, extensions) = self.header(rel_path, &metadata)?;
+                        header.set_entry_type(tar::EntryType::Link);
+                        header.set_size(0);
+                        self.append_entry(
+                            tar_builder,
+                            &mut header,
+                            extensions,
+                            rel_path,
+                            Some(&target),
+                        )?;
+                    }
+                    None => self.append_file(
+                        tar_builder,
+                        rel_path,
+                        path,
+                        checksums,
+                        run.as_deref_mut().map(|run| (run, index)),
+                    )?,
+                }
+            } else {
+                warn_progress!(
+                    self.progress.as_ref(),
+                    "Skipping special file: {}",
+                    path.display()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Trains the dictionary to compress `input_path` with, if one is to be trained.
+    ///
+    /// Training on too few samples fails; the archive is then compressed without one.
+    fn dictionary(&self, input_path: &Path) -> Result<Option<Vec<u8>>> {
+        if !self.train_dictionary {
+            return Ok(None);
+        }
+        if self.codec != Codec::Zstd || self.seekable {
+            warn!("--train-dictionary needs zstd compression without seekable frames.");
+            return Err(anyhow!(
+                "Training a dictionary needs zstd compression, and cannot be combined with \
+                 seekable frames."
+            ));
+        }
+        if !input_path.is_dir() {
+            warn!("A dictionary is only trained for directories; compressing without one.");
+            return Ok(None);
+        }
+        let mut samples = Vec::new();
+        let mut total = 0;
+        // Unreadable files are reported when they are archived.
+        for entry in self.walk(input_path).flatten() {
+            if total >= TRAINING_LEN {
+                break;
+            }
+            if !entry.file_type().is_some_and(|t| t.is_file()) {
+                continue;
+            }
+            let mut sample = Vec::new();
+            let read = File::open(entry.path())
+                .and_then(|file| file.take(SAMPLE_LEN).read_to_end(&mut sample));
+            if read.is_ok() && !sample.is_empty() {
+                total += sample.len();
+                samples.push(sample);
+            }
+        }
+        debug!(
+            "Training a dictionary on {} bytes from {} files.",
+            total,
+            samples.len()
+        );
+        match compress::train_dictionary(&samples) {
+            Ok(dictionary) => {
+                debug!("Trained a dictionary of {} bytes.", dictionary.len());
+                Ok(Some(dictionary))
+            }
+            Err(e) => {
+                warn!(
+                    "Could not train a dictionary on {} files ({e}); compressing without one.",
+                    samples.len()
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Walks `input_path`, skipping excluded and ignored entries and everything below
+    /// excluded directories.
+    fn walk(&self, input_path: &Path) -> ignore::Walk {
+        self.walk_sorted(input_path, false)
+    }
+
+    /// Walks `input_path` like [`ProtectOptions::walk`], in name order if `sorted` is set or
+    /// the archive is deterministic.
+    fn walk_sorted(&self, input_path: &Path, sorted: bool) -> ignore::Walk {
+        let root = input_path.to_path_buf();
+        let exclude = self.exclude.clone();
+        let mut builder = ignore::WalkBuilder::new(input_path);
+        if sorted || self.deterministic_mtime.is_some() {
+            builder.sort_by_file_name(|a, b| a.cmp(b));
+        }
+        builder
+            .standard_filters(false)
+            .add_custom_ignore_filename(SAGEIGNORE_FILE)
+            .git_ignore(self.respect_gitignore)
+            .git_exclude(self.respect_gitignore)
+            .git_global(self.respect_gitignore)
+            .require_git(false)
+            .parents(self.respect_gitignore)
+            .follow_links(self.dereference)
+            .filter_entry(move |entry| {
+                let Ok(rel_path) = entry.path().strip_prefix(&root) else {
+                    return true;
+                };
+                let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+                if rel_path.as_os_str().is_empty() || !exclude.is_excluded(rel_path, is_dir) {
+                    return true;
+                }
+                debug!("Excluding: {}", rel_path.display());
+                false
+            })
+            .build()
+    }
+
+    /// Sums the sizes of the regular files that will be archived from `input_path`.
+    fn input_size(&self, input_path: &Path) -> Result<u64> {
+        let mut total = 0;
+        // Errors are reported when the entries are archived.
+        for entry in self.walk(input_path).flatten() {
+            if entry.file_type().is_some_and(|t| t.is_file()) {
+                total += entry.metadata().map_or(0, |m| m.len());
+            }
+        }
+        Ok(total)
+    }
+
+    /// Appends the file at `path` to the tar stream as `rel_path`, recording its hash into
+    /// `checksums`, checkpointing in it if it is the entry `index` of a resumable run.
+    fn append_file<W: Write>(
+        &self,
+        tar_builder: &mut tar::Builder<W>,
+        rel_path: &Path,
+        path: &Path,
+        checksums: &mut Checksums,
+        run: Option<(&mut Run, u64)>,
+    ) -> Result<()> {
+        let mut file = File::open(path)?;
+        let metadata = file.metadata()?;
+        if let Some(marks) = &self.store_marks {
+            let stored = self.stores_extension(rel_path)
+                || (self.skip_compressed
+                    && metadata.len() >= SKIP_MIN_SIZE
+                    && is_compressed(&mut file, path)?);
+            marks.mark(stored);
+            let level = match stored {
+                true => STORED_LEVEL,
+                false => Codec::Zstd
+                    .effective_level(self.compression_level)
+                    .unwrap_or(self.compression_level),
+            };
+            checksums.insert_compression(rel_path, Codec::Zstd, level);
+        }
+        let map = if self.sparse && self.deterministic_mtime.is_none() {
+            SparseMap::find(&file, &metadata)?
+        } else {
+            None
+        };
+        // Only GNU headers hold sparse maps.
+        let (mut header, mut extensions) = match map {
+            Some(_) => self.header_in(tar::Header::new_gnu(), rel_path, &metadata)?,
+            None => self.header(rel_path, &metadata)?,
+        };
+        self.store_xattrs(&mut extensions, rel_path, path)?;
+        let hash = match map {
+            Some(map) => {
+                debug!(
+                    "Storing {} bytes of the sparse file {}, of {}.",
+                    map.stored(),
+                    rel_path.display(),
+                    map.size()
+                );
+                let holes = map.size() - map.stored();
+                let mut file = SparseReader::new(file, map, &mut header);
+                self.append_contents(tar_builder, &mut header, extensions, rel_path, &mut file)?;
+                if let Some(progress) = &self.progress {
+                    progress.advance(holes);
+                }
+                file.hash()
+            }
+            None => match run {
+                Some((run, index)) if metadata.len() > run.block_len() => {
+                    let mut file = run.reader(file, index, rel_path, &metadata, checksums, None)?;
+                    self.append_contents(
+                        tar_builder,
+                        &mut header,
+                        extensions,
+                        rel_path,
+                        &mut file,
+                    )?;
+                    file.hash()
+                }
+                _ => {
+                    let mut file = HashingReader::new(file);
+                    self.append_contents(
+                        tar_builder,
+                        &mut header,
+                        extensions,
+                        rel_path,
+                        &mut file,
+                    )?;
+                    file.hash()
+                }
+            },
+        };
+        checksums.insert(rel_path, hash);
+        Ok(())
+    }
+
+    /// Whether `rel_path` has one of the extensions of `no_compress_extensions`.
+    fn stores_extension(&self, rel_path: &Path) -> bool {
+        let Some(extension) = rel_path.extension() else {
+            return false;
+        };
+        let extension = extension.to_string_lossy().to_lowercase();
+        let stored = self.store_extensions.contains(&extension);
+        if stored {
+            debug!("Storing {} by its extension.", rel_path.display());
+        }
+        stored
+    }
+
+    /// Archives the rest of the file at `path`, the entry `index` at `rel_path` that the
+    /// earlier run of `run` stopped in, and the padding of its entry.
+    fn resume_file(
+        &self,
+        run: &mut Run,
+        index: u64,
+        rel_path: &Path,
+        path: &Path,
+        saved: SavedFile,
+        checksums: &mut Checksums,
+    ) -> Result<()> {
+        let mut file = File::open(path)
+            .with_context(|| format!("Failed to open input file: {}", path.display()))?;
+        let metadata = file.metadata()?;
+        saved.check(rel_path, &metadata)?;
+        debug!(
+            "Resuming {} at byte {} of {}.",
+            rel_path.display(),
+            saved.offset(),
+            metadata.len()
+        );
+        file.seek(SeekFrom::Start(saved.offset()))?;
+        let mut writer = run.writer();
+        let hash = {
+            let mut reader =
+                run.reader(file, index, rel_path, &metadata, checksums, Some(saved))?;
+            self.copy_contents(rel_path, &mut reader, &mut writer)?;
+            reader.hash()
+        };
+        run.pad(metadata.len())?;
+        checksums.insert(rel_path, hash);
+        Ok(())
+    }
+
+    /// Copies what `reader` reads to `writer` at the pace of `--bwlimit`, reporting
+    /// progress, as [`ProtectOptions::append_contents`] does for the contents of an entry.
+    fn copy_contents(
+        &self,
+        rel_path: &Path,
+        reader: &mut CheckpointReader,
+        writer: &mut impl Write,
+    ) -> Result<()> {
+        let mut reader =
+            ThrottledReader::new(CancelReader::new(reader), self.read_throttle.clone());
+        match &self.progress {
+            Some(progress) => {
+                progress.set_entry(rel_path);
+                io::copy(&mut ProgressReader::new(reader, progress.clone()), writer)
+            }
+            None => io::copy(&mut reader, writer),
+        }
+        .with_context(|| format!("Failed to archive {}", rel_path.display()))?;
+        Ok(())
+    }
+
+    /// Appends an entry with `header` and the contents read from `reader`, at the pace of
+    /// `--bwlimit` and reporting progress.
+    fn append_contents<W: Write>(
+        &self,
+        tar_builder: &mut tar::Builder<W>,
+        header: &mut tar::Header,
+        extensions: Extensions,
+        rel_path: &Path,
+        reader: impl Read,
+    ) -> Result<()> {
+        let reader = ThrottledReader::new(CancelReader::new(reader), self.read_throttle.clone());
+        match &self.progress {
+            Some(progress) => {
+                progress.set_entry(rel_path);
+                let reader = ProgressReader::new(reader, progress.clone());
+                self.tar_format
+                    .append(tar_builder, header, extensions, rel_path, None, reader)
+            }
+            None => self
+                .tar_format
+                .append(tar_builder, header, extensions, rel_path, None, reader),
+        }
+        .with_context(|| format!("Failed to archive {}", rel_path.display()))
+    }
+
+    /// Appends the directory at `path`, whose metadata is `metadata`, as `rel_path`.
+    fn append_dir<W: Write>(
+        &self,
+        tar_builder: &mut tar::Builder<W>,
+        rel_path: &Path,
+        path: &Path,
+        metadata: &fs::Metadata,
+    ) -> Result<()> {
+        let (mut header, mut extensions) = self.header(rel_path, metadata)?;
+        header.set_size(0);
+        self.store_xattrs(&mut extensions, rel_path, path)?;
+        self.append_entry(tar_builder, &mut header, extensions, rel_path, None)
+    }
+
+    /// Appends an entry without contents, linking to `link` if it is a link.
+    fn append_entry<W: Write>(
+        &self,
+        tar_builder: &mut tar::Builder<W>,
+        header: &mut tar::Header,
+        extensions: Extensions,
+        rel_path: &Path,
+        link: Option<&Path>,
+    ) -> Result<()> {
+        self.tar_format
+            .append(tar_builder, header, extensions, rel_path, link, io::empty())
+            .with_context(|| format!("Failed to archive {}", rel_path.display()))
+    }
+
+    /// Adds the extended attributes of `path` to the `extensions` of its entry, if enabled.
+    fn store_xattrs(
+        &self,
+        extensions: &mut Extensions,
+        rel_path: &Path,
+        path: &Path,
+    ) -> Result<()> {
+        if !self.preserve_xattrs {
+            return Ok(());
+        }
+        let attrs = xattrs::read(path)
+            .with_context(|| format!("Failed to read extended attributes: {}", path.display()))?;
+        if !attrs.is_empty() {
+            debug!(
+                "Storing {} extended attributes of {}",
+                attrs.len(),
+                rel_path.display()
+            );
+            xattrs::push_records(extensions, &attrs);
+        }
+        Ok(())
+    }
+
+    /// Builds a tar header in the tar format from the `metadata` of `rel_path`, leaving out
+    /// what should not be preserved, and the PAX records of what it cannot hold.
+    fn header(
+        &self,
+        rel_path: &Path,
+        metadata: &fs::Metadata,
+    ) -> Result<(tar::Header, Extensions)> {
+        self.header_in(self.tar_format.header(), rel_path, metadata)
+    }
+
+    /// Like [`Self::header`], filling in `header` instead of a header of the tar format.
+    fn header_in(
+        &self,
+        mut header: tar::Header,
+        rel_path: &Path,
+        metadata: &fs::Metadata,
+    ) -> Result<(tar::Header, Extensions)> {
+        let mut extensions = Extensions::default();
+        header.set_metadata(metadata);
+        if !self.preserve_owner || self.deterministic_mtime.is_some() {
+            header.set_uid(0);
+            header.set_gid(0);
+            // Clearing the names only fails for headers without name fields, which GNU and
+            // ustar headers always have.
+            let _ = header.set_username("");
+            let _ = header.set_groupname("");
+        }
+        if !self.preserve_permissions {
+            let executable = header.mode().is_ok_and(|mode| mode & 0o111 != 0);
+            header.set_mode(if metadata.is_dir() || executable {
+                0o755
+            } else {
+                0o644
+            });
+        }
+        if let Some(mtime) = self.deterministic_mtime {
+            header.set_mtime(mtime);
+        } else if !self.preserve_mtime {
+            header.set_mtime(0);
+        } else if let Ok(mtime) = metadata.modified() {
+            self.tar_format
+                .set_mtime(&mut header, &mut extensions, mtime)
+                .with_context(|| format!("Failed to archive {}", rel_path.display()))?;
+        }
+        let times = self.preserve_mtime && self.deterministic_mtime.is_none();
+        Attributes::of(metadata, self.preserve_permissions, times).push_records(&mut extensions);
+        Ok((header, extensions))
+    }
+
+    /// The modification time of entries that sage itself writes: that of the input, so the
+    /// tar stream of an unchanged input does not depend on when it was archived.
+    fn metadata_mtime(&self, input_path: &Path) -> u64 {
+        self.deterministic_mtime.unwrap_or_else(|| {
+            fs::metadata(input_path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|mtime| mtime.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_secs())
+        })
+    }
+
+    /// Sets up error correction (or armor), encryption and compression, then lets `body`
+    /// write the payload through the compressor.
+    ///
+    /// `body` runs on the calling thread while compression, encryption and error correction
+    /// each run on a thread of their own, connected by [`PipeWriter`]s.
+    fn write_archive<W: Write + Send>(
+        &self,
+        output: W,
+        stream: bool,
+        dictionary: Option<&[u8]>,
+        body: impl FnOnce(&mut dyn Write) -> Result<()>,
+    ) -> Result<W> {
+        if self.store_marks.is_some() && (self.codec != Codec::Zstd || self.dedup || self.seekable)
+        {
+            warn!(
+                "--skip-compressed and --no-compress-extensions need zstd compression without \
+                 dedup or seekable frames."
+            );
+            return Err(anyhow!(
+                "Storing files without compressing them needs zstd compression, and cannot be \
+                 combined with dedup or seekable frames."
+            ));
+        }
+        if self.long_window_log.is_some() && (self.codec != Codec::Zstd || self.seekable) {
+            warn!("--long needs zstd compression without seekable frames.");
+            return Err(anyhow!(
+                "Long-distance matching needs zstd compression, and cannot be combined with \
+                 seekable frames."
+            ));
+        }
+        if self.seekable && (self.codec != Codec::Zstd || self.dedup || self.armor) {
+            warn!("Seekable frames need zstd compression without dedup or armor.");
+            return Err(anyhow!(
+                "Seekable archives must be compressed with zstd, and cannot be deduplicated or armored."
+            ));
+        }
+        if self.compat && (self.dedup || self.armor || dictionary.is_some()) {
+            warn!("--compat cannot be combined with dedup, a dictionary or armor.");
+            return Err(anyhow!(
+                "Compatible archives are read without sage, which rules out dedup, trained \
+                 dictionaries and armor."
+            ));
+        }
+        let level = self
+            .codec
+            .effective_level(self.compression_level)
+            .unwrap_or(0);
+        let limits = match self.max_memory {
+            Some(limit) => memory::fit_compression(
+                limit,
+                self.codec,
+                level,
+                self.threads,
+                self.long_window_log,
+                self.fec_params,
+            )?,
+            None => CompressionLimits {
+                threads: self.threads,
+                window_log: self.long_window_log,
+            },
+        };
+        let limits = if self.deterministic_mtime.is_some() {
+            // Multithreaded compression splits the stream differently from one thread.
+            debug!("Compressing on one thread for a deterministic payload.");
+            CompressionLimits {
+                threads: 1,
+                ..limits
+            }
+        } else {
+            limits
+        };
+        let unencrypted = matches!(self.encryption, Encryption::None);
+        if unencrypted && (self.armor || self.compat) {
+            warn!("An unencrypted archive cannot be armored or written as a plain age file.");
+            return Err(anyhow!(
+                "Armor and --compat write age files, so they cannot be combined with \
+                 --no-encrypt."
+            ));
+        }
+        let encryptor = self.encryptor()?;
+        if let Some(rate) = self.bwlimit {
+            debug!("Limiting input and output to {rate} bytes per second each.");
+        }
+        let output = ThrottledWriter::new(
+            output,
+            self.bwlimit.map(|rate| Arc::new(Throttle::new(rate))),
+        );
+
+        let sink = if self.armor {
+            debug!("Writing ASCII-armored output without error correction.");
+            Sink::Armor(ArmoredWriter::wrap_output(output, Format::AsciiArmor)?)
+        } else if self.compat {
+            debug!("Writing a plain age file without error correction.");
+            Sink::Plain(output)
+        } else {
+            let fec_params = self.fec_params;
+            debug!(
+                "Initializing error correction with {} data + {} parity shards ({:.1}% redundancy).",
+                fec_params.data_shards,
+                fec_params.parity_shards,
+                fec_params.redundancy()
+            );
+            let header = FileHeader {
+                compression_level: self
+                    .codec
+                    .effective_level(self.compression_level)
+                    .unwrap_or(0) as u8,
+                codec: Some(self.codec),
+                stream,
+                dedup: self.dedup,
+                seekable: self.seekable,
+                dictionary: dictionary.is_some(),
+                unencrypted,
+                window_log: limits
+                    .window_log
+                    .filter(|_| self.long_window_log.is_some())
+                    .map(|window_log| window_log as u8),
+                content_hash: self.content_hash.map(|hash| *hash.as_bytes()),
+                stripe_groups: self.stripe_groups,
+                ..FileHeader::new(fec_params)
+            };
+            Sink::Fec(Box::new(
+                FecWriter::new(output, header).context("Failed to write archive header")?,
+            ))
+        };
+        let output = thread::scope(|scope| -> Result<ThrottledWriter<W>> {
+            let sink = PipeWriter::spawn(scope, "sage-ecc", sink, Sink::finish)?;
+            let mut encrypted = match encryptor {
+                Some(encryptor) => {
+                    let age_writer = encryptor.wrap_output(sink)?;
+                    PipeWriter::spawn(scope, "sage-encrypt", age_writer, |writer| {
+                        writer.finish()?.finish()
+                    })?
+                }
+                None => sink,
+            };
+            let tuning = ZstdTuning {
+                dictionary: dictionary.map(<[u8]>::to_vec),
+                long: self.long_window_log.is_some(),
+                window_log: limits.window_log,
+            };
+            if let Some(dictionary) = &tuning.dictionary {
+                compress::write_dictionary(&mut encrypted, dictionary)
+                    .context("Failed to write the dictionary")?;
+            }
+            let encoder = if self.seekable {
+                Encoder::Seekable(Box::new(
+                    SeekableWriter::new(encrypted, level, limits.threads)
+                        .context("Failed to create seekable zstd encoder")?,
+                ))
+            } else if let Some(marks) = &self.store_marks {
+                Encoder::Switching(Box::new(
+                    SwitchingEncoder::new(
+                        encrypted,
+                        self.compression_level,
+                        limits.threads,
+                        tuning,
+                        marks.clone(),
+                    )
+                    .context("Failed to create zstd encoder")?,
+                ))
+            } else if tuning.is_tuned() {
+                compress::tuned_encoder(encrypted, self.compression_level, limits.threads, &tuning)
+                    .context("Failed to create zstd encoder")?
+            } else {
+                self.codec
+                    .encoder(encrypted, self.compression_level, limits.threads)
+                    .with_context(|| format!("Failed to create {} encoder", self.codec))?
+            };
+            let mut compressed = if self.dedup {
+                let dedup = DedupWriter::new(encoder)?;
+                PipeWriter::spawn(scope, "sage-compress", dedup, |dedup| {
+                    dedup.finish()?.0.finish()?.finish()
+                })?
+            } else {
+                PipeWriter::spawn(scope, "sage-compress", encoder, |encoder| {
+                    encoder.finish()?.finish()
+                })?
+            };
+            let written = match &self.store_marks {
+                Some(marks) => {
+                    marks.start();
+                    let written = self.write_body(&mut marks.track(&mut compressed), body);
+                    marks.stop();
+                    written?
+                }
+                None => self.write_body(&mut compressed, body)?,
+            };
+            if let (Some(recorded), Some(written)) = (self.content_hash, written)
+                && recorded != written
+            {
+                warn_progress!(
+                    self.progress.as_ref(),
+                    "The input changed while it was protected; the recorded content hash is stale."
+                );
+            }
+
+            debug!("Finishing compression, encryption and error correction.");
+            Ok(compressed.finish()?)
+        })?;
+        if let Some(progress) = &self.progress {
+            progress.finish();
+        }
+        Ok(output.into_inner())
+    }
+
+    /// Ends the tar stream with the [`CHECKSUM_ENTRY`] listing `checksums`, and reports the
+    /// hash of the list to the progress.
+    fn append_checksums<W: Write>(
+        &self,
+        tar_builder: &mut tar::Builder<W>,
+        checksums: &Checksums,
+        mtime: u64,
+    ) -> Result<()> {
+        debug!("Storing checksums of {} files.", checksums.files.len());
+        let data = serde_json::to_vec(checksums)?;
+        if let Some(progress) = &self.progress {
+            progress.set_manifest_hash(blake3::hash(&data));
+        }
+        append_metadata_entry(self.tar_format, tar_builder, CHECKSUM_ENTRY, &data, mtime)
+    }
+
+    /// Runs `body` on `encoder`, returning the hash of what it wrote if a content hash is
+    /// being recorded, to check that it still matches.
+    fn write_body(
+        &self,
+        encoder: &mut dyn Write,
+        body: impl FnOnce(&mut dyn Write) -> Result<()>,
+    ) -> Result<Option<blake3::Hash>> {
+        if self.content_hash.is_none() {
+            body(encoder)?;
+            return Ok(None);
+        }
+        let mut hasher = HashingWriter::new(encoder);
+        body(&mut hasher)?;
+        Ok(Some(hasher.hash()))
+    }
+}
+
+/// Copies every entry of `archive` into `tar_builder` as it is, except the
+/// [`CHECKSUM_ENTRY`], and returns the checksums it listed and its modification time.
+///
+/// Fails if an entry is named like one of `names`, or lies below one of them.
+fn copy_entries<R: Read, W: Write>(
+    archive: tar::Archive<R>,
+    tar_builder: &mut tar::Builder<W>,
+    names: &[&std::ffi::OsStr],
+    hash: bool,
+) -> Result<(Checksums, u64)> {
+    let mut reader = archive.into_inner();
+    let mut checksums = Checksums::new();
+    let mut mtime = 0;
+    let mut copied = 0u64;
+    // The name from the long name or PAX header before the entry, when hashing files.
+    let mut long_path: Option<Vec<u8>> = None;
+    // The archive is walked header by header, which copies the PAX and long name headers
+    // along with the entries they belong to. The raw entries of `tar::Entries` would do too,
+    // but leave the extension headers of a sparse map in the data, and lose its end.
+    loop {
+        cancel::check()?;
+        let mut header = tar::Header::new_old();
+        reader
+            .read_exact(header.as_mut_bytes())
+            .context("Failed to read archive")?;
+        if header.as_bytes().iter().all(|&b| b == 0) {
+            break;
+        }
+        let mut expected = header.clone();
+        expected.set_cksum();
+        if header.cksum()? != expected.cksum()? {
+            warn!("An entry header of the archive is damaged.");
+            return Err(anyhow!(
+                "Failed to read archive: an entry header is damaged."
+            ));
+        }
+        let entry_type = header.entry_type();
+        let mut extensions = Vec::new();
+        if entry_type.is_gnu_sparse() && header.as_gnu().is_some_and(|h| h.is_extended()) {
+            let mut extension = tar::GnuExtSparseHeader::new();
+            loop {
+                reader
+                    .read_exact(extension.as_mut_bytes())
+                    .context("Failed to read archive")?;
+                extensions.extend_from_slice(extension.as_bytes());
+                if !extension.is_extended() {
+                    break;
+                }
+            }
+        }
+        let size = header.entry_size()?;
+        let padding = size.next_multiple_of(512) - size;
+        if entry_type.is_file() && header.path_bytes().as_ref() == CHECKSUM_ENTRY.as_bytes() {
+            let mut contents = Vec::new();
+            (&mut reader).take(size).read_to_end(&mut contents)?;
+            io::copy(&mut (&mut reader).take(padding), &mut io::sink())?;
+            match Checksums::parse(&contents) {
+                Some(parsed) => {
+                    checksums.files.extend(parsed.files);
+                    checksums.compression.extend(parsed.compression);
+                }
+                None => warn!("{CHECKSUM_ENTRY} is not a sage checksum list; dropping it."),
+            }
+            mtime = header.mtime().unwrap_or(0);
+            continue;
+        }
+        let is_extension = entry_type.is_pax_global_extensions()
+            || entry_type.is_pax_local_extensions()
+            || entry_type.is_gnu_longname()
+            || entry_type.is_gnu_longlink();
+        if !is_extension
+            && let Some(first) = header.path()?.components().next()
+            && names.contains(&first.as_os_str())
+        {
+            warn!("The archive already holds {}.", header.path()?.display());
+            return Err(anyhow!(
+                "The archive already holds {}; rename the path to append.",
+                Path::new(first.as_os_str()).display()
+            ));
+        }
+        if hash && (entry_type.is_gnu_longname() || entry_type.is_pax_local_extensions()) {
+            let mut contents = Vec::new();
+            (&mut reader).take(size).read_to_end(&mut contents)?;
+            if entry_type.is_gnu_longname() {
+                let name = contents.split(|&b| b == 0).next().unwrap_or_default();
+                long_path = Some(name.to_vec());
+            } else if let Some(path) = tar::PaxExtensions::new(&contents)
+                .flatten()
+                .find(|record| record.key_bytes() == b"path")
+            {
+                long_path = Some(path.value_bytes().to_vec());
+            }
+            tar_builder.append(&header, contents.as_slice())?;
+        } else if hash && entry_type.is_file() {
+            let path = long_path
+                .take()
+                .unwrap_or_else(|| header.path_bytes().into_owned());
+            let mut data = HashingReader::new((&mut reader).take(size));
+            tar_builder.append(&header, &mut data)?;
+            checksums.insert(Path::new(&*String::from_utf8_lossy(&path)), data.hash());
+        } else {
+            if !is_extension {
+                long_path = None;
+            }
+            let data = io::Cursor::new(extensions).chain((&mut reader).take(size));
+            tar_builder.append(&header, data)?;
+        }
+        io::copy(&mut (&mut reader).take(padding), &mut io::sink())?;
+        copied += 1;
+    }
+    debug!("Copied {copied} entries of the existing archive.");
+    Ok((checksums, mtime))
+}
+
+/// Appends a file entry that sage itself wrote, owned by root and dated `mtime`.
+fn append_metadata_entry<W: Write>(
+    format: TarFormat,
+    tar_builder: &mut tar::Builder<W>,
+    path: &str,
+    data: &[u8],
+    mtime: u64,
+) -> Result<()> {
+    let mut header = format.header();
+    header.set_mode(0o644);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(mtime);
+    header.set_size(data.len() as u64);
+    format.append(
+        tar_builder,
+        &mut header,
+        Extensions::default(),
+        Path::new(path),
+        None,
+        data,
+    )?;
+    Ok(())
+}
+
+/// What [`ProtectOptions::dry_run`] would archive.
+#[derive(Debug, Default)]
+pub struct DryRun {
+    /// Every entry that would be archived, in archive order.
+    pub entries: Vec<PlannedEntry>,
+    pub files: u64,
+    pub directories: u64,
+    /// Symlinks and hard links.
+    pub links: u64,
+    /// The total size of the files.
+    pub bytes: u64,
+    /// The bytes of file data compressed to estimate the compression ratio.
+    pub sampled_bytes: u64,
+    /// The estimated size of the compressed tar stream.
+    pub estimated_compressed: u64,
+    /// The estimated size of the archive, with error correction, armor or neither.
+    pub estimated_archive: u64,
+}
+
+impl DryRun {
+    fn add(&mut self, path: PathBuf, kind: PlannedKind, size: u64) {
+        let size = match kind {
+            PlannedKind::File => {
+                self.files += 1;
+                self.bytes += size;
+                size
+            }
+            PlannedKind::Directory => {
+                self.directories += 1;
+                0
+            }
+            PlannedKind::Symlink | PlannedKind::HardLink => {
+                self.links += 1;
+                0
+            }
+        };
+        self.entries.push(PlannedEntry { path, kind, size });
+    }
+}
+
+/// One entry found by [`ProtectOptions::dry_run`].
+#[derive(Debug, Clone)]
+pub struct PlannedEntry {
+    /// The path in the archive, relative to the input.
+    pub path: PathBuf,
+    pub kind: PlannedKind,
+    /// The bytes of data stored for the entry; only files have any.
+    pub size: u64,
+}
+
+/// How an entry would be stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlannedKind {
+    File,
+    Directory,
+    Symlink,
+    /// A further path of a file with several links, stored without its data.
+    HardLink,
+}
+
+/// Returns whether the file at `path`, opened as `file`, looks already compressed, leaving
+/// `file` at its start.
+fn is_compressed(file: &mut File, path: &Path) -> Result<bool> {
+    let mut prefix = Vec::with_capacity(SNIFF_LEN);
+    (&mut *file)
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut prefix)?;
+    file.seek(SeekFrom::Start(0))?;
+    let compressed = compress::looks_compressed(&prefix);
+    if compressed {
+        debug!("Storing already compressed file: {}", path.display());
+    }
+    Ok(compressed)
+}
+
+/// Counts the bytes written to it.
+#[derive(Default)]
+struct CountingWriter(u64);
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Where the age payload goes: the error correction layer, ASCII armor, or straight to the
+/// output.
+enum Sink<W: Write> {
+    Fec(Box<FecWriter<W>>),
+    Armor(ArmoredWriter<W>),
+    Plain(W),
+}
+
+impl<W: Write> Sink<W> {
+    /// Writes the end of the layer and returns the inner writer.
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Sink::Fec(writer) => writer.finish(),
+            Sink::Armor(writer) => writer.finish(),
+            Sink::Plain(writer) => Ok(writer),
+        }
+    }
+}
+
+impl<W: Write> Write for Sink<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Fec(writer) => writer.write(buf),
+            Sink::Armor(writer) => writer.write(buf),
+            Sink::Plain(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Fec(writer) => writer.flush(),
+            Sink::Armor(writer) => writer.flush(),
+            Sink::Plain(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Returns true if `error` reports a symlink that points back to one of its ancestors.
+fn is_loop(error: &ignore::Error) -> bool {
+    match error {
+        ignore::Error::Loop { .. } => true,
+        ignore::Error::WithPath { err, .. } | ignore::Error::WithDepth { err, .. } => is_loop(err),
+        _ => false,
+    }
+}
+
+/// Identifies a file with more than one hard link by device and inode.
+#[cfg(unix)]
+fn hard_link_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+/// Identifies a file with more than one hard link by device and inode.
+#[cfg(not(unix))]
+fn hard_link_id(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::info;
+
+    fn options() -> ProtectOptions {
+        ProtectOptions::new().recipient(Box::new(age::x25519::Identity::generate().to_public()))
+    }
+
+    #[test]
+    fn content_hash_is_stable_until_the_input_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/file.txt"), "unchanged").unwrap();
+        let options = options();
+
+        let first = options.hash_contents(dir.path()).unwrap();
+        assert_eq!(options.hash_contents(dir.path()).unwrap(), first);
+
+        fs::write(dir.path().join("sub/file.txt"), "changed").unwrap();
+        assert_ne!(options.hash_contents(dir.path()).unwrap(), first);
+    }
+
+    #[test]
+    fn deterministic_archives_ignore_order_and_times() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+        for (root, names) in [(&first, ["a", "b", "c"]), (&second, ["c", "a", "b"])] {
+            fs::create_dir(root).unwrap();
+            for name in names {
+                fs::write(root.join(name), name).unwrap();
+            }
+        }
+        let old = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        File::options()
+            .write(true)
+            .open(second.join("a"))
+            .and_then(|file| file.set_modified(old))
+            .unwrap();
+
+        let deterministic = options().deterministic(1_700_000_000);
+        let hash = deterministic.hash_contents(&first).unwrap();
+        assert_eq!(deterministic.hash_contents(&second).unwrap(), hash);
+        assert_ne!(options().hash_contents(&second).unwrap(), hash);
+    }
+
+    #[test]
+    fn records_the_content_hash_in_the_header() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("file.txt"), "contents").unwrap();
+        let hash = options().hash_contents(dir.path()).unwrap();
+
+        let archive = options()
+            .record_content_hash(hash)
+            .protect(dir.path(), Vec::new())
+            .unwrap();
+        let header = info::read_info(archive.as_slice()).unwrap().header.unwrap();
+        assert_eq!(header.content_hash, Some(*hash.as_bytes()));
+
+        let archive = options().protect(dir.path(), Vec::new()).unwrap();
+        let header = info::read_info(archive.as_slice()).unwrap().header.unwrap();
+        assert_eq!(header.content_hash, None);
+    }
+
+    #[test]
+    fn stores_compressed_files_in_frames_of_their_own() {
+        let identity = age::x25519::Identity::generate();
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input");
+        fs::create_dir(&input).unwrap();
+        fs::write(input.join("a.txt"), "text ".repeat(100_000)).unwrap();
+        let mut noise = vec![0x1f, 0x8b];
+        let mut state = 0x2545_f491_u32;
+        noise.extend((0..400_000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }));
+        fs::write(input.join("b.gz"), &noise).unwrap();
+        fs::write(input.join("c.txt"), "more text ".repeat(50_000)).unwrap();
+
+        let archive = ProtectOptions::new()
+            .recipient(Box::new(identity.to_public()))
+            .compression_level(19)
+            .skip_compressed(true)
+            .protect(&input, Vec::new())
+            .unwrap();
+        let output = dir.path().join("output");
+        crate::RecoverOptions::new()
+            .identity(Box::new(identity))
+            .recover(archive.as_slice(), &output)
+            .unwrap();
+        for name in ["a.txt", "b.gz", "c.txt"] {
+            assert_eq!(
+                fs::read(output.join(name)).unwrap(),
+                fs::read(input.join(name)).unwrap()
+            );
+        }
+        assert!(compress::looks_compressed(&noise[..SNIFF_LEN]));
+        assert!(!compress::looks_compressed(
+            &fs::read(input.join("a.txt")).unwrap()
+        ));
+    }
+
+    #[test]
+    fn stores_files_with_the_listed_extensions() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input");
+        fs::create_dir(&input).unwrap();
+        for name in ["a.txt", "b.JPG", "c.mp4", "d.log", "e"] {
+            fs::write(input.join(name), "text ".repeat(10_000)).unwrap();
+        }
+
+        let archive = ProtectOptions::new()
+            .unencrypted()
+            .compression_level(19)
+            .no_compress_extensions(["jpg", ".mp4"])
+            .protect(&input, Vec::new())
+            .unwrap();
+        let mut archive = crate::RecoverOptions::new()
+            .open(archive.as_slice())
+            .unwrap();
+        let mut checksums = None;
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            if entry.path().unwrap() == Path::new(CHECKSUM_ENTRY) {
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents).unwrap();
+                checksums = Checksums::parse(&contents);
+            }
+        }
+        let compression = checksums.unwrap().compression;
+        let levels: Vec<_> = compression
+            .iter()
+            .map(|(path, compression)| {
+                assert_eq!(compression.codec, "zstd");
+                (path.as_str(), compression.level)
+            })
+            .collect();
+        assert_eq!(
+            levels,
+            [
+                ("a.txt", 19),
+                ("b.JPG", STORED_LEVEL),
+                ("c.mp4", STORED_LEVEL),
+                ("d.log", 19),
+                ("e", 19)
+            ]
+        );
+    }
+
+    #[test]
+    fn compresses_with_a_trained_dictionary() {
+        let identity = age::x25519::Identity::generate();
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input");
+        fs::create_dir(&input).unwrap();
+        for i in 0..2000 {
+            let record = format!(
+                r#"{{"id": {i}, "level": "info", "host": "web-{}", "latency_ms": {}}}"#,
+                i % 7,
+                i * 37 % 1000
+            );
+            fs::write(input.join(format!("{i}.json")), record).unwrap();
+        }
+
+        let archive = ProtectOptions::new()
+            .recipient(Box::new(identity.to_public()))
+            .train_dictionary(true)
+            .protect(&input, Vec::new())
+            .unwrap();
+        let header = info::read_info(archive.as_slice()).unwrap().header.unwrap();
+        assert!(header.dictionary);
+        let output = dir.path().join("output");
+        crate::RecoverOptions::new()
+            .identity(Box::new(identity))
+            .recover(archive.as_slice(), &output)
+            .unwrap();
+        for name in ["0.json", "1999.json"] {
+            assert_eq!(
+                fs::read(output.join(name)).unwrap(),
+                fs::read(input.join(name)).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn writes_plain_age_files_that_need_no_sage() {
+        let identity = age::x25519::Identity::generate();
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input");
+        fs::create_dir(&input).unwrap();
+        fs::write(input.join("notes.txt"), "readable with age, zstd and tar").unwrap();
+
+        for codec in [Codec::Zstd, Codec::Xz] {
+            let archive = ProtectOptions::new()
+                .recipient(Box::new(identity.to_public()))
+                .compression(codec)
+                .compat(true)
+                .protect(&input, Vec::new())
+                .unwrap();
+            assert!(archive.starts_with(crate::AGE_MAGIC));
+
+            let output = dir.path().join(format!("{codec}"));
+            crate::RecoverOptions::new()
+                .identity(Box::new(identity.clone()))
+                .recover(archive.as_slice(), &output)
+                .unwrap();
+            assert_eq!(
+                fs::read(output.join("notes.txt")).unwrap(),
+                b"readable with age, zstd and tar"
+            );
+        }
+
+        let archive = ProtectOptions::new()
+            .recipient(Box::new(identity.to_public()))
+            .compat(true)
+            .protect(&input, Vec::new())
+            .unwrap();
+        let decrypted = age::Decryptor::new(archive.as_slice())
+            .unwrap()
+            .decrypt(std::iter::once(&identity as &dyn age::Identity))
+            .unwrap();
+        let tar = zstd::decode_all(decrypted).unwrap();
+        let mut tar = tar::Archive::new(tar.as_slice());
+        let names: Vec<_> = (tar.entries().unwrap())
+            .map(|entry| entry.unwrap().path().unwrap().into_owned())
+            .collect();
+        assert!(names.contains(&PathBuf::from("notes.txt")));
+
+        assert!(
+            ProtectOptions::new()
+                .recipient(Box::new(identity.to_public()))
+                .compat(true)
+                .dedup(true)
+                .protect(&input, Vec::new())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn records_uncompressed_payloads_in_the_header() {
+        let identity = age::x25519::Identity::generate();
+        let dir = tempfile::tempdir().unwrap();
+        let data = b"already compressed ".repeat(10_000);
+        fs::write(dir.path().join("video.mp4"), &data).unwrap();
+
+        let archive = ProtectOptions::new()
+            .recipient(Box::new(identity.to_public()))
+            .compression(Codec::None)
+            .protect(&dir.path().join("video.mp4"), Vec::new())
+            .unwrap();
+        let header = crate::fec::FecReader::new(archive.as_slice())
+            .unwrap()
+            .header();
+        assert_eq!(header.codec, Some(Codec::None));
+        assert!(archive.len() > data.len());
+
+        let output = dir.path().join("output");
+        crate::RecoverOptions::new()
+            .identity(Box::new(identity))
+            .recover(archive.as_slice(), &output)
+            .unwrap();
+        assert_eq!(fs::read(output.join("video.mp4")).unwrap(), data);
+    }
+
+    #[test]
+    fn writes_unencrypted_archives_that_recover_without_identities() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input");
+        fs::create_dir(&input).unwrap();
+        fs::write(input.join("data.csv"), "public,data\n".repeat(1000)).unwrap();
+
+        for seekable in [false, true] {
+            let archive = ProtectOptions::new()
+                .unencrypted()
+                .seekable(seekable)
+                .protect(&input, Vec::new())
+                .unwrap();
+            let header = crate::fec::FecReader::new(archive.as_slice())
+                .unwrap()
+                .header();
+            assert!(header.unencrypted);
+
+            let output = dir.path().join(format!("output-{seekable}"));
+            crate::RecoverOptions::new()
+                .recover(io::Cursor::new(&archive), &output)
+                .unwrap();
+            assert_eq!(
+                fs::read(output.join("data.csv")).unwrap(),
+                "public,data\n".repeat(1000).as_bytes()
+            );
+        }
+
+        assert!(
+            ProtectOptions::new()
+                .unencrypted()
+                .armor(true)
+                .protect(&input, Vec::new())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn records_the_long_distance_window_for_recovery() {
+        let identity = age::x25519::Identity::generate();
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("image.bin"), b"disk image ".repeat(100_000)).unwrap();
+
+        let archive = ProtectOptions::new()
+            .recipient(Box::new(identity.to_public()))
+            .long_window_log(20)
+            .protect(dir.path(), Vec::new())
+            .unwrap();
+        let header = info::read_info(archive.as_slice()).unwrap().header.unwrap();
+        assert_eq!(header.window_log, Some(20));
+        let recover = || crate::RecoverOptions::new().identity(Box::new(identity.clone()));
+        assert!(
+            recover()
+                .max_window_log(19)
+                .recover(archive.as_slice(), &dir.path().join("refused"))
+                .is_err()
+        );
+        let output = dir.path().join("output");
+        recover().recover(archive.as_slice(), &output).unwrap();
+        assert_eq!(
+            fs::read(output.join("image.bin")).unwrap(),
+            fs::read(dir.path().join("image.bin")).unwrap()
+        );
+    }
+
+    #[test]
+    fn appends_paths_and_merges_checksums() {
+        let identity = age::x25519::Identity::generate();
+        let options = || ProtectOptions::new().recipient(Box::new(identity.to_public()));
+        let recover = || crate::RecoverOptions::new().identity(Box::new(identity.clone()));
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("input")).unwrap();
+        fs::write(dir.path().join("input/old.txt"), "old").unwrap();
+        fs::create_dir_all(dir.path().join("photos/2024")).unwrap();
+        fs::write(dir.path().join("photos/2024/new.jpg"), "new").unwrap();
+        let archive = options()
+            .protect(&dir.path().join("input"), Vec::new())
+            .unwrap();
+
+        let entries = recover().open(archive.as_slice()).unwrap();
+        let photos = dir.path().join("photos");
+        let appended = options()
+            .append(entries, std::slice::from_ref(&photos), Vec::new())
+            .unwrap();
+        let mut archive = recover().open(appended.as_slice()).unwrap();
+        let mut paths = Vec::new();
+        let mut checksums = None;
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            if path == CHECKSUM_ENTRY {
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents).unwrap();
+                checksums = Checksums::parse(&contents);
+            } else {
+                paths.push(path);
+            }
+        }
+        assert_eq!(
+            paths,
+            ["old.txt", "photos", "photos/2024", "photos/2024/new.jpg"]
+        );
+        let files = checksums.unwrap().files;
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            ["old.txt", "photos/2024/new.jpg"]
+        );
+
+        let entries = recover().open(appended.as_slice()).unwrap();
+        assert!(options().append(entries, &[photos], Vec::new()).is_err());
+    }
+
+    /// Copies the partial archive and checkpoint of `output` to `saved` once `at` bytes of
+    /// input were read, as a crash at that moment would leave them.
+    struct Snapshot {
+        output: PathBuf,
+        saved: PathBuf,
+        at: u64,
+        read: std::sync::atomic::AtomicU64,
+    }
+
+    impl Progress for Snapshot {
+        fn advance(&self, bytes: u64) {
+            let before = self
+                .read
+                .fetch_add(bytes, std::sync::atomic::Ordering::Relaxed);
+            if before < self.at && before + bytes >= self.at {
+                fs::create_dir_all(&self.saved).unwrap();
+                fs::copy(
+                    resume::partial_path(&self.output),
+                    self.saved.join("partial"),
+                )
+                .unwrap();
+                fs::copy(
+                    resume::checkpoint_path(&self.output),
+                    self.saved.join("checkpoint"),
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn resumes_a_stopped_run_from_its_last_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input");
+        fs::create_dir_all(input.join("docs")).unwrap();
+        fs::write(input.join("a.txt"), "first file\n".repeat(1000)).unwrap();
+        let big: Vec<u8> = (0..300_000u32).map(|i| (i * 7 / 3 % 251) as u8).collect();
+        fs::write(input.join("big.bin"), &big).unwrap();
+        for i in 0..6 {
+            fs::write(
+                input.join("docs").join(format!("{i}.txt")),
+                format!("document {i}\n").repeat(2000),
+            )
+            .unwrap();
+        }
+        let identity = age::x25519::Identity::generate();
+        let recipient = identity.to_public();
+
+        for (encrypted, at) in [(true, 100_000), (true, 350_000), (false, 200_000)] {
+            let resumable = || {
+                let options = ProtectOptions::new().checkpoint_interval(16 * 1024);
+                match encrypted {
+                    true => options.recipient(Box::new(recipient.clone())),
+                    false => options.unencrypted().interleave(3),
+                }
+            };
+            let output = dir.path().join(format!("archive-{encrypted}-{at}.sage"));
+            let saved = dir.path().join(format!("saved-{encrypted}-{at}"));
+            resumable()
+                .progress(Arc::new(Snapshot {
+                    output: output.clone(),
+                    saved: saved.clone(),
+                    at,
+                    read: Default::default(),
+                }))
+                .protect_resumable(&input, &output)
+                .unwrap();
+            assert!(!resume::checkpoint_path(&output).exists());
+            assert!(!resume::partial_path(&output).exists());
+
+            // Stopped at `at`, and run again.
+            fs::remove_file(&output).unwrap();
+            fs::rename(saved.join("partial"), resume::partial_path(&output)).unwrap();
+            fs::rename(saved.join("checkpoint"), resume::checkpoint_path(&output)).unwrap();
+            resumable().protect_resumable(&input, &output).unwrap();
+            assert!(!resume::checkpoint_path(&output).exists());
+
+            let restored = dir.path().join(format!("restored-{encrypted}-{at}"));
+            crate::RecoverOptions::new()
+                .identity(Box::new(identity.clone()))
+                .recover_file(&output, &restored)
+                .unwrap();
+            assert_eq!(fs::read(restored.join("big.bin")).unwrap(), big);
+            assert_eq!(
+                fs::read(restored.join("a.txt")).unwrap(),
+                fs::read(input.join("a.txt")).unwrap()
+            );
+            for i in 0..6 {
+                let name = format!("{i}.txt");
+                assert_eq!(
+                    fs::read(restored.join("docs").join(&name)).unwrap(),
+                    fs::read(input.join("docs").join(&name)).unwrap()
+                );
+            }
+        }
+
+        let unsupported = ProtectOptions::new().unencrypted().compression(Codec::Xz);
+        assert!(
+            unsupported
+                .protect_resumable(&input, &dir.path().join("xz.sage"))
+                .is_err()
+        );
+    }
+}
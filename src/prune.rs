@@ -0,0 +1,183 @@
+//! Retention policies for `sage prune`.
+//!
+//! Archives are dated by the UTC time in their file name, as written by `protect -o DIR` and
+//! by `{timestamp}` in `watch`, or else by the creation time in their sage header. Each rule keeps the newest archive of each of the last N
+//! days, ISO weeks or months that have one; an archive kept by any rule stays, and the rest
+//! are deleted.
+
+use crate::naming;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, Utc};
+use log::debug;
+use sage::fec::FecReader;
+use std::path::{Path, PathBuf};
+
+/// How many periods of each length to keep an archive for.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Policy {
+    pub daily: u32,
+    pub weekly: u32,
+    pub monthly: u32,
+}
+
+/// An archive in the directory being pruned.
+#[derive(Debug)]
+pub struct Archive {
+    pub path: PathBuf,
+    pub time: DateTime<Utc>,
+    /// The rules that keep the archive, empty if it is to be deleted.
+    pub kept_by: Vec<&'static str>,
+}
+
+/// Lists the archives in `dir` with a timestamp in their names or headers, newest first.
+///
+/// Hidden files, such as unfinished temporary archives, and signatures and key shares next
+/// to an archive are left out.
+pub fn find_archives(dir: &Path) -> Result<Vec<Archive>> {
+    let mut archives = Vec::new();
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Failed to read {}", dir.display()))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let sidecar = name.ends_with(".minisig") || is_share(&name);
+        if name.starts_with('.') || sidecar || !entry.file_type()?.is_file() {
+            continue;
+        }
+        let path = entry.path();
+        match naming::parse_timestamp(&name).or_else(|| created(&path)) {
+            Some(time) => archives.push(Archive {
+                path,
+                time,
+                kept_by: Vec::new(),
+            }),
+            None => debug!("Skipping {name}, which has no timestamp in its name or header."),
+        }
+    }
+    archives.sort_by(|a, b| b.time.cmp(&a.time).then_with(|| b.path.cmp(&a.path)));
+    Ok(archives)
+}
+
+/// Reads the creation time from the sage header of the archive at `path`, if it is one.
+fn created(path: &Path) -> Option<DateTime<Utc>> {
+    let file = std::fs::File::open(path).ok()?;
+    let created = FecReader::new(file).ok()?.header().created?;
+    DateTime::from_timestamp(created as i64, 0)
+}
+
+/// The files next to `archive` that belong to it: its signature and key shares.
+pub fn sidecars(archive: &Path) -> Result<Vec<PathBuf>> {
+    let name = archive.file_name().unwrap_or_default().to_string_lossy();
+    let dir = match archive.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut paths = Vec::new();
+    for entry in
+        std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?
+    {
+        let entry = entry.with_context(|| format!("Failed to read {}", dir.display()))?;
+        let other = entry.file_name().to_string_lossy().into_owned();
+        if let Some(suffix) = other.strip_prefix(name.as_ref())
+            && (suffix == ".minisig" || is_share(suffix))
+        {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Returns whether `name` ends like a key share written by `protect --shares`.
+fn is_share(name: &str) -> bool {
+    name.rsplit_once(".share")
+        .is_some_and(|(_, index)| !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Maps a time to the period it falls in, such as its year and month.
+type Period = fn(&DateTime<Utc>) -> (i32, u32);
+
+/// Marks the archives that `policy` keeps; `archives` must be sorted newest first.
+pub fn apply(archives: &mut [Archive], policy: Policy) {
+    let rules: [(&'static str, u32, Period); 3] = [
+        ("daily", policy.daily, |t| (t.year(), t.ordinal())),
+        ("weekly", policy.weekly, |t| {
+            (t.iso_week().year(), t.iso_week().week())
+        }),
+        ("monthly", policy.monthly, |t| (t.year(), t.month())),
+    ];
+    for (rule, count, period) in rules {
+        let mut kept = 0;
+        let mut last = None;
+        for archive in archives.iter_mut() {
+            if kept == count {
+                break;
+            }
+            let current = period(&archive.time);
+            if last != Some(current) {
+                last = Some(current);
+                archive.kept_by.push(rule);
+                kept += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archives(times: &[&str]) -> Vec<Archive> {
+        times
+            .iter()
+            .map(|time| Archive {
+                path: PathBuf::from(format!("host-docs-{time}.sage")),
+                time: naming::parse_timestamp(time).unwrap(),
+                kept_by: Vec::new(),
+            })
+            .collect()
+    }
+
+    fn kept(archives: &[Archive]) -> Vec<&str> {
+        archives
+            .iter()
+            .filter(|archive| !archive.kept_by.is_empty())
+            .map(|archive| archive.path.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn keeps_the_newest_archive_of_each_period() {
+        let mut list = archives(&[
+            "20261014T180000Z",
+            "20261014T090000Z",
+            "20261013T090000Z",
+            "20261005T090000Z",
+            "20260920T090000Z",
+            "20260815T090000Z",
+        ]);
+        let policy = Policy {
+            daily: 2,
+            weekly: 2,
+            monthly: 2,
+        };
+        apply(&mut list, policy);
+        assert_eq!(
+            kept(&list),
+            [
+                "host-docs-20261014T180000Z.sage",
+                "host-docs-20261013T090000Z.sage",
+                "host-docs-20261005T090000Z.sage",
+                "host-docs-20260920T090000Z.sage",
+            ]
+        );
+        assert_eq!(list[0].kept_by, ["daily", "weekly", "monthly"]);
+    }
+
+    #[test]
+    fn an_empty_policy_keeps_nothing() {
+        let mut list = archives(&["20261014T180000Z"]);
+        apply(&mut list, Policy::default());
+        assert!(kept(&list).is_empty());
+    }
+}
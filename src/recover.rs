@@ -0,0 +1,1979 @@
+use crate::cancel::{self, CancelReader};
+use crate::checksum::{CHECKSUM_ENTRY, Checksums};
+use crate::compress::{self, Codec};
+use crate::dedup::{self, DedupReader};
+use crate::diff::{self, Difference};
+use crate::exclude::ExcludeSet;
+use crate::fileattrs::Attributes;
+use crate::incremental::{INCREMENT_ENTRY, Increment};
+use crate::memory;
+use crate::normalize::Normalization;
+use crate::output::{AtomicFile, DEFAULT_OUTPUT_MODE};
+use crate::ownership::{self, IdMap, OwnershipMapper};
+use crate::progress::{Progress, ProgressReader, warn_progress};
+use crate::salvage::{Losses, Rebased, Reopen, Salvage, SalvageReader, find_header};
+use crate::seekable::{OrderedFrames, ReadSeek, SeekableReader};
+use crate::select::PathSelector;
+use crate::unpack::{Conflicts, OnConflict, PathPolicy};
+use crate::xattrs::{self, XattrRestorer};
+use crate::{AGE_MAGIC, ARMOR_BEGIN_MARKER, fec, integrity, manifest, sparse, tar_format};
+use age::secrecy::SecretString;
+use anyhow::{Context, Result, anyhow};
+use log::{debug, info, warn};
+use std::borrow::Cow;
+use std::cell::OnceCell;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::SystemTime;
+
+/// The decrypted and decompressed contents of a protected archive.
+pub enum Contents<R: Read> {
+    /// A tar archive of files and directories.
+    Archive(tar::Archive<R>),
+    /// A single stream, protected from standard input.
+    Stream(R),
+}
+
+/// What [`RecoverOptions::open_seekable`] found.
+enum Seekable<'a, R> {
+    /// The tar archive, read through layers that all seek.
+    Archive(tar::Archive<Box<dyn ReadSeek + 'a>>),
+    /// The input, back where it started, of an archive written without seekable frames.
+    Sequential(R),
+}
+
+/// The tar stream of a seekable archive, read through error correction and decryption.
+type SeekablePayload<R> =
+    SeekableReader<integrity::ChunkLocator<Payload<integrity::SourceReader<fec::FecReader<R>>>>>;
+
+/// The compressed payload of an archive: decrypted, or as it is if it was not encrypted.
+enum Payload<R> {
+    Decrypted(age::stream::StreamReader<R>),
+    Plain(R),
+}
+
+impl<R: Read> Read for Payload<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Payload::Decrypted(reader) => reader.read(buf),
+            Payload::Plain(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl<R: Read + Seek> Seek for Payload<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Payload::Decrypted(reader) => reader.seek(pos),
+            Payload::Plain(reader) => reader.seek(pos),
+        }
+    }
+}
+
+/// Frames each recovery thread may have decompressed ahead of the extraction.
+const FRAMES_AHEAD: usize = 2;
+
+/// Hard links to hard links that [`RecoverOptions::cat_seekable`] follows.
+const LINK_HOPS: usize = 8;
+
+/// What [`cat_entries`] found at the path it was asked for.
+enum Cat {
+    /// The contents of a file, that many bytes of them.
+    Written(u64),
+    /// A hard link to the file at this path.
+    HardLink(PathBuf),
+}
+
+/// Supplies the passphrase of a passphrase-encrypted archive when it is needed.
+pub type PassphraseProvider = Box<dyn Fn() -> Result<SecretString>>;
+
+/// Settings for reading a protected archive: error correction -> age -> decompression -> tar.
+#[derive(Default)]
+pub struct RecoverOptions {
+    identities: Vec<Box<dyn age::Identity>>,
+    passphrase: Option<PassphraseProvider>,
+    max_work_factor: Option<u8>,
+    max_window_log: Option<u32>,
+    max_memory: Option<u64>,
+    uid_map: Option<IdMap>,
+    gid_map: Option<IdMap>,
+    progress: Option<Arc<dyn Progress>>,
+    select: Option<PathSelector>,
+    preserve_owner: Option<bool>,
+    preserve_permissions: Option<bool>,
+    preserve_mtime: Option<bool>,
+    preserve_xattrs: Option<bool>,
+    overwrite: bool,
+    keep_corrupt: bool,
+    paths: PathPolicy,
+    on_conflict: OnConflict,
+    threads: Option<usize>,
+    /// The groups error correction repaired in the archives read.
+    repairs: fec::RepairLog,
+    /// The passphrase once it was supplied, so that it is asked for only once.
+    supplied_passphrase: OnceCell<SecretString>,
+}
+
+impl RecoverOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decrypts with `identity`, in addition to any identities added before.
+    pub fn identity(mut self, identity: Box<dyn age::Identity>) -> Self {
+        self.identities.push(identity);
+        self
+    }
+
+    /// Decrypts with every identity in `identities`.
+    pub fn identities(
+        mut self,
+        identities: impl IntoIterator<Item = Box<dyn age::Identity>>,
+    ) -> Self {
+        self.identities.extend(identities);
+        self
+    }
+
+    /// Uses `passphrase` for passphrase-encrypted archives.
+    pub fn passphrase(self, passphrase: SecretString) -> Self {
+        self.passphrase_provider(Box::new(move || Ok(passphrase.clone())))
+    }
+
+    /// Calls `provider` for the passphrase, only once an archive turns out to need one.
+    pub fn passphrase_provider(mut self, provider: PassphraseProvider) -> Self {
+        self.passphrase = Some(provider);
+        self
+    }
+
+    /// Limits the scrypt work factor accepted for passphrase-encrypted archives.
+    pub fn max_work_factor(mut self, max_work_factor: u8) -> Self {
+        self.max_work_factor = Some(max_work_factor);
+        self
+    }
+
+    /// Refuses zstd windows larger than 2^`window_log` bytes, which decompressing needs as
+    /// much memory as. Without a limit, the window an archive records in its header is
+    /// accepted, and otherwise zstd's default of 128 MiB.
+    pub fn max_window_log(mut self, window_log: u32) -> Self {
+        self.max_window_log = Some(window_log);
+        self
+    }
+
+    /// Keeps the memory decompression and error correction use to about `bytes`, by
+    /// refusing zstd windows that do not fit and recovering seekable archives on fewer
+    /// threads; see [`crate::memory`].
+    pub fn max_memory(mut self, bytes: u64) -> Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    /// Translates stored owner UIDs when extracting.
+    pub fn uid_map(mut self, map: IdMap) -> Self {
+        self.uid_map = Some(map);
+        self
+    }
+
+    /// Translates stored owner GIDs when extracting.
+    pub fn gid_map(mut self, map: IdMap) -> Self {
+        self.gid_map = Some(map);
+        self
+    }
+
+    /// Reports the bytes of the archive read, each entry extracted, each group error
+    /// correction repaired and each warning about the files to `progress`.
+    pub fn progress(mut self, progress: Arc<dyn Progress>) -> Self {
+        self.repairs = fec::RepairLog::reporting_to(progress.clone());
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Whether to restore stored ownership. By default ownership is restored when running as
+    /// root or when a uid/gid map is set.
+    pub fn preserve_owner(mut self, preserve: bool) -> Self {
+        self.preserve_owner = Some(preserve);
+        self
+    }
+
+    /// Whether to restore exact permission bits, including setuid, setgid and sticky bits; on
+    /// by default. Without them, those bits and group and other write access are cleared.
+    pub fn preserve_permissions(mut self, preserve: bool) -> Self {
+        self.preserve_permissions = Some(preserve);
+        self
+    }
+
+    /// Whether to restore modification times; on by default. Without them, extracted entries
+    /// keep the time of extraction.
+    pub fn preserve_mtime(mut self, preserve: bool) -> Self {
+        self.preserve_mtime = Some(preserve);
+        self
+    }
+
+    /// Whether to restore stored extended attributes, including POSIX ACLs and SELinux labels;
+    /// on by default. Attributes that cannot be set, usually for lack of root, are skipped with
+    /// a warning.
+    pub fn preserve_xattrs(mut self, preserve: bool) -> Self {
+        self.preserve_xattrs = Some(preserve);
+        self
+    }
+
+    /// Lets a single-stream archive replace an existing output file; off by default. What
+    /// happens to existing files in a directory is decided by
+    /// [`on_conflict`](RecoverOptions::on_conflict).
+    pub fn overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Keeps extracted files that do not match the checksums stored in the archive, with a
+    /// warning; off by default, when such files are removed and recovery fails.
+    pub fn keep_corrupt(mut self, keep: bool) -> Self {
+        self.keep_corrupt = keep;
+        self
+    }
+
+    /// Removes the first `count` components from the path of every entry extracted, and
+    /// skips entries with no more, as tar's `--strip-components` does.
+    pub fn strip_components(mut self, count: usize) -> Self {
+        self.paths.strip_components = count;
+        self
+    }
+
+    /// Extracts entries with absolute paths where they point, instead of skipping them with
+    /// a warning; off by default. Entries with `..` in their path are always skipped. See
+    /// [`crate::unpack`].
+    pub fn allow_absolute_paths(mut self, allow: bool) -> Self {
+        self.paths.allow_absolute = allow;
+        self
+    }
+
+    /// Extracts names in the Unicode normalization `form`; kept as stored by default. See
+    /// [`crate::normalize`].
+    pub fn normalize_filenames(mut self, form: Normalization) -> Self {
+        self.paths.normalize = form;
+        self
+    }
+
+    /// What to do with entries whose paths already exist in the output directory; by
+    /// default they replace what is there.
+    pub fn on_conflict(mut self, policy: OnConflict) -> Self {
+        self.on_conflict = policy;
+        self
+    }
+
+    /// Recovers archive files written with [seekable frames](crate::seekable) on up to
+    /// `threads` threads, each repairing, decrypting and decompressing its share of the
+    /// frames; defaults to the number of CPUs. Other archives are read on one thread, as
+    /// are archives read from a stream and extractions of selected paths.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads.max(1));
+        self
+    }
+
+    /// Extracts only the entries selected by `select`, and the directories leading to them.
+    pub fn select(mut self, select: PathSelector) -> Self {
+        self.select = Some(select);
+        self
+    }
+
+    /// Takes the error correction groups that had to be repaired in the archives read since
+    /// the last call, each with the entry being read at the time. Repairs succeeded, but
+    /// they show that the medium holding the archive is degrading.
+    pub fn take_repairs(&self) -> Vec<fec::Repair> {
+        self.repairs.take()
+    }
+
+    /// Where the archives read record the groups they repair.
+    pub(crate) fn repair_log(&self) -> &fec::RepairLog {
+        &self.repairs
+    }
+
+    /// Opens the archive at `input_path` for reading.
+    pub fn open_file(&self, input_path: &Path) -> crate::Result<tar::Archive<Box<dyn Read>>> {
+        debug!("Opening encrypted input file: {}", input_path.display());
+        let input_file = File::open(input_path)
+            .with_context(|| format!("Failed to open input file: {}", input_path.display()))?;
+        self.open(input_file)
+    }
+
+    /// Opens a protected archive stream for reading its tar entries.
+    ///
+    /// Fails for archives that hold a single stream; use [`RecoverOptions::open_contents`]
+    /// to read those.
+    pub fn open<'a, R: Read + 'a>(
+        &self,
+        input: R,
+    ) -> crate::Result<tar::Archive<Box<dyn Read + 'a>>> {
+        match self.open_contents(input)? {
+            Contents::Archive(archive) => Ok(archive),
+            Contents::Stream(_) => {
+                warn!("Archive holds a single stream, not files.");
+                Err(anyhow!(
+                    "Archive holds a single stream rather than files; recover it to a file or to standard output."
+                ).into())
+            }
+        }
+    }
+
+    /// Opens a protected archive stream for reading: correct errors -> decrypt -> decompress.
+    pub fn open_contents<'a, R: Read + 'a>(
+        &self,
+        input: R,
+    ) -> crate::Result<Contents<Box<dyn Read + 'a>>> {
+        let input = CancelReader::new(input);
+        let input: Box<dyn Read + 'a> = match &self.progress {
+            Some(progress) => Box::new(ProgressReader::new(input, progress.clone())),
+            None => Box::new(input),
+        };
+        let (source, header) = open_fec_source(input, Some(&self.repairs))?;
+
+        let decryptor = self.decrypt(integrity::SourceReader::new(source), &header)?;
+
+        let mut payload: Box<dyn Read + 'a> = Box::new(integrity::ChunkLocator::new(decryptor));
+        let (codec, stream, dedup) = match header.codec {
+            Some(codec) => (codec, Some(header.stream), Some(header.dedup)),
+            None => {
+                let prefix = peek(&mut payload, compress::DETECT_LEN)?;
+                let codec = Codec::detect(&prefix);
+                info!("Archive does not record its codec; detected {codec}.");
+                payload = Box::new(Cursor::new(prefix).chain(payload));
+                (codec, None, None)
+            }
+        };
+        let mut decoder = codec
+            .decoder(payload, self.window_log_max(&header)?)
+            .with_context(|| format!("Failed to create {codec} decoder"))?;
+
+        let dedup = match dedup {
+            Some(dedup) => dedup,
+            None => {
+                let prefix = peek(&mut decoder, dedup::DEDUP_MAGIC.len())?;
+                decoder = Box::new(Cursor::new(prefix.clone()).chain(decoder));
+                prefix == dedup::DEDUP_MAGIC
+            }
+        };
+        if dedup {
+            debug!("Archive is deduplicated.");
+            decoder =
+                Box::new(DedupReader::new(decoder).context("Failed to read deduplicated payload")?);
+        }
+
+        let stream = match stream {
+            Some(stream) => stream,
+            None => {
+                let prefix = peek(&mut decoder, compress::DETECT_LEN)?;
+                let stream = !compress::is_tar(&prefix);
+                decoder = Box::new(Cursor::new(prefix).chain(decoder));
+                stream
+            }
+        };
+        Ok(if stream {
+            debug!("Archive holds a single stream.");
+            Contents::Stream(decoder)
+        } else {
+            Contents::Archive(tar::Archive::new(decoder))
+        })
+    }
+
+    /// The largest zstd window to decompress the archive with `header` with, as a power of
+    /// two.
+    fn window_log_max(&self, header: &fec::FileHeader) -> Result<u32> {
+        let recorded = header.window_log.map_or(0, u32::from);
+        let needed = recorded.max(compress::DEFAULT_WINDOW_LOG_MAX);
+        let within_memory = match self.max_memory {
+            Some(limit) => Some(memory::window_log_within(limit, header.params).ok_or_else(
+                || {
+                    warn!("The memory limit is too low for the error correction groups.");
+                    anyhow!(
+                        "The memory limit of {} MiB is too low to read error correction groups of \
+                     {} data and {} parity shards of {} KiB.",
+                        limit >> 20,
+                        header.params.data_shards,
+                        header.params.parity_shards,
+                        header.params.shard_size >> 10
+                    )
+                },
+            )?),
+            None => None,
+        };
+        let limit = match (self.max_window_log, within_memory) {
+            (Some(explicit), within_memory) => {
+                Some(within_memory.map_or(explicit, |m| explicit.min(m)))
+            }
+            (None, within_memory) => within_memory.map(|m| m.min(needed)),
+        };
+        match limit {
+            Some(limit) if recorded > limit => {
+                warn!("The archive needs a zstd window of 2^{recorded} bytes, above the limit.");
+                Err(anyhow!(
+                    "The archive was compressed with a window of 2^{recorded} bytes and needs as \
+                     much memory to recover, but the limit is 2^{limit} bytes."
+                ))
+            }
+            Some(limit) => Ok(limit),
+            None => Ok(needed),
+        }
+    }
+
+    /// Lowers `threads` to what fits the memory limit, each recovering the seekable archive
+    /// with `header` on its own.
+    fn threads_within_memory(&self, threads: usize, header: &fec::FileHeader) -> usize {
+        let Some(limit) = self.max_memory else {
+            return threads;
+        };
+        let window_log = memory::zstd_window_log(header.compression_level.into());
+        let fitting = threads.min(memory::recovery_threads(limit, header.params, window_log));
+        if fitting < threads {
+            debug!(
+                "Recovering on {fitting} threads instead of {threads} to stay within the memory limit."
+            );
+        }
+        fitting
+    }
+
+    /// Decrypts the age file read from `source` with the identities, or with the passphrase
+    /// if it is passphrase-encrypted.
+    fn decrypt<R: Read>(&self, source: R, header: &fec::FileHeader) -> Result<Payload<R>> {
+        if header.unencrypted {
+            info!("Archive is not encrypted.");
+            return Ok(Payload::Plain(source));
+        }
+        debug!("Initializing age decryption.");
+        let decryptor = age::Decryptor::new(source)?;
+
+        // Identities are always tried first. An scrypt stanza must be the only stanza in an
+        // age header, so no identity can match a passphrase-encrypted archive and we fall
+        // back to asking for the passphrase instead.
+        let payload = if decryptor.is_scrypt() {
+            if !self.identities.is_empty() {
+                info!(
+                    "Archive is passphrase-encrypted; none of the {} supplied identities can open it.",
+                    self.identities.len()
+                );
+            }
+            info!("Falling back to passphrase decryption.");
+            let identity = self.passphrase_identity()?;
+            debug!("Decrypting with passphrase.");
+            decryptor.decrypt(std::iter::once(&identity as &dyn age::Identity))?
+        } else {
+            if self.identities.is_empty() {
+                warn!("No valid identities provided.");
+                return Err(crate::Error::NoIdentities.into());
+            }
+            debug!("Decrypting with {} identities.", self.identities.len());
+            decryptor.decrypt(self.identities.iter().map(|i| i.as_ref()))?
+        };
+        Ok(Payload::Decrypted(payload))
+    }
+
+    /// Returns the identity for the passphrase, asking the provider for it the first time.
+    fn passphrase_identity(&self) -> Result<age::scrypt::Identity> {
+        let passphrase = match self.supplied_passphrase.get() {
+            Some(passphrase) => passphrase.clone(),
+            None => {
+                let Some(provider) = &self.passphrase else {
+                    warn!("Archive is passphrase-encrypted but no passphrase was provided.");
+                    return Err(crate::Error::NoPassphrase.into());
+                };
+                let passphrase = provider()?;
+                let _ = self.supplied_passphrase.set(passphrase.clone());
+                passphrase
+            }
+        };
+        let mut identity = age::scrypt::Identity::new(passphrase);
+        if let Some(max_work_factor) = self.max_work_factor {
+            identity.set_max_work_factor(max_work_factor);
+        }
+        Ok(identity)
+    }
+
+    /// Opens an archive written with [seekable frames](crate::seekable) for random access,
+    /// or hands `input` back, rewound, if the archive was written without them.
+    fn open_seekable<'a, R: Read + Seek + 'a>(&self, mut input: R) -> Result<Seekable<'a, R>> {
+        if seekable_header(&mut input)?.is_none() {
+            return Ok(Seekable::Sequential(input));
+        }
+        debug!("Archive has seekable frames; reading only what is needed.");
+        let input = CancelReader::new(input);
+        let input: Box<dyn ReadSeek + 'a> = match &self.progress {
+            Some(progress) => Box::new(ProgressReader::new(input, progress.clone())),
+            None => Box::new(input),
+        };
+        let decoder: Box<dyn ReadSeek + 'a> = Box::new(self.seekable_payload(input)?);
+        Ok(Seekable::Archive(tar::Archive::new(decoder)))
+    }
+
+    /// Opens the tar stream of the seekable archive `input`.
+    fn seekable_payload<R: Read + Seek>(&self, input: R) -> Result<SeekablePayload<R>> {
+        let mut source = fec::FecReader::new(input).map_err(header_error)?;
+        source.log_repairs(self.repairs.clone());
+        let header = source.header();
+        let payload = self.decrypt(integrity::SourceReader::new(source), &header)?;
+        SeekableReader::new(integrity::ChunkLocator::new(payload))
+            .context("Failed to read the seek table")
+    }
+
+    /// Extracts the seekable archive at `input_path` with `threads` threads, which each open
+    /// the file on their own and handle every `threads`-th frame, while this thread unpacks
+    /// the tar stream they produce.
+    fn extract_parallel(
+        &self,
+        input_path: &Path,
+        output_path: &Path,
+        threads: usize,
+    ) -> Result<()> {
+        let open = || -> Result<Box<dyn ReadSeek + Send>> {
+            let input = File::open(input_path)
+                .with_context(|| format!("Failed to open input file: {}", input_path.display()))?;
+            let input = CancelReader::new(input);
+            Ok(match &self.progress {
+                Some(progress) => Box::new(ProgressReader::new(input, progress.clone())),
+                None => Box::new(input),
+            })
+        };
+        let source = fec::FecReader::new(open()?).map_err(header_error)?;
+        let scrypt = !source.header().unencrypted && age::Decryptor::new(source)?.is_scrypt();
+        let workers = if scrypt {
+            // Deriving the key from the passphrase is slow by design, so every thread does
+            // it at the same time.
+            let identity = self.passphrase_identity()?;
+            let inputs = (0..threads).map(|_| open()).collect::<Result<Vec<_>>>()?;
+            let repairs = &self.repairs;
+            thread::scope(|scope| {
+                let opening: Vec<_> = inputs
+                    .into_iter()
+                    .map(|input| scope.spawn(|| open_seekable_payload(input, &identity, repairs)))
+                    .collect();
+                opening
+                    .into_iter()
+                    .map(|worker| worker.join().expect("opening the archive panicked"))
+                    .collect::<Result<Vec<_>>>()
+            })?
+        } else {
+            // Other identities cannot be shared between threads, so this one unlocks all.
+            (0..threads)
+                .map(|_| self.seekable_payload(open()?))
+                .collect::<Result<Vec<_>>>()?
+        };
+        let count = workers[0].frame_count();
+        info!("Recovering {count} frames on {threads} threads.");
+
+        thread::scope(|scope| {
+            let mut receivers = Vec::with_capacity(threads);
+            for (first, mut payload) in workers.into_iter().enumerate() {
+                let (sender, receiver) = mpsc::sync_channel(FRAMES_AHEAD);
+                receivers.push(receiver);
+                scope.spawn(move || {
+                    for index in (first..count).step_by(threads) {
+                        let frame = payload.read_frame(index);
+                        let failed = frame.is_err();
+                        // The extraction stopped if nobody receives any more.
+                        if sender.send(frame).is_err() || failed {
+                            break;
+                        }
+                    }
+                });
+            }
+            let mut archive = tar::Archive::new(OrderedFrames::new(receivers, count));
+            self.prepare_extraction(&mut archive, output_path)?;
+            self.unpack_entries(archive.entries()?, output_path)
+        })?;
+        debug!(
+            "Recovery complete. Files extracted to: {}",
+            output_path.display()
+        );
+        Ok(())
+    }
+
+    /// Recovers the archive at `input_path` into `output_path`.
+    ///
+    /// File archives are extracted into the directory `output_path`; single-stream archives
+    /// are written to the file `output_path`.
+    pub fn recover_file(&self, input_path: &Path, output_path: &Path) -> crate::Result<()> {
+        debug!("Opening encrypted input file: {}", input_path.display());
+        let input_file = File::open(input_path)
+            .with_context(|| format!("Failed to open input file: {}", input_path.display()))?;
+        if let Some(progress) = &self.progress {
+            progress.set_total(input_file.metadata()?.len());
+        }
+        let mut threads = self
+            .threads
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+        let mut input_file = input_file;
+        if threads > 1
+            && self.select.is_none()
+            && let Some(header) = seekable_header(&mut input_file)?
+        {
+            threads = self.threads_within_memory(threads, &header);
+        } else {
+            threads = 1;
+        }
+        if threads > 1 {
+            let result = self.extract_parallel(input_path, output_path, threads);
+            if let Some(progress) = &self.progress {
+                progress.finish();
+            }
+            return Ok(result?);
+        }
+        self.recover_seekable(input_file, output_path)
+    }
+
+    /// Recovers `input` into `output_path` like [`RecoverOptions::recover`], but skips the
+    /// entries that were not selected without reading them if the archive was written with
+    /// [`ProtectOptions::seekable`](crate::ProtectOptions::seekable).
+    pub fn recover_seekable<R: Read + Seek>(
+        &self,
+        input: R,
+        output_path: &Path,
+    ) -> crate::Result<()> {
+        let mut archive = match self.open_seekable(input)? {
+            Seekable::Archive(archive) => archive,
+            Seekable::Sequential(input) => return self.recover(input, output_path),
+        };
+        self.prepare_extraction(&mut archive, output_path)?;
+        let result = archive
+            .entries_with_seek()
+            .map_err(anyhow::Error::from)
+            .and_then(|entries| self.unpack_entries(entries, output_path));
+        if let Some(progress) = &self.progress {
+            progress.finish();
+        }
+        result?;
+        debug!(
+            "Recovery complete. Files extracted to: {}",
+            output_path.display()
+        );
+        Ok(())
+    }
+
+    /// Recovers the archive stream `input` into `output_path`, like [`RecoverOptions::recover_file`].
+    pub fn recover<R: Read>(&self, input: R, output_path: &Path) -> crate::Result<()> {
+        let result = match self.open_contents(input)? {
+            Contents::Archive(archive) => self.extract(archive, output_path),
+            Contents::Stream(_) if self.select.is_some() => {
+                warn!("Archive holds a single stream; paths cannot be selected.");
+                Err(anyhow!(
+                    "Archive holds a single stream rather than files; paths cannot be selected."
+                ))
+            }
+            Contents::Stream(mut stream) => {
+                debug!("Writing stream to output file: {}", output_path.display());
+                let mut output_file =
+                    AtomicFile::create(output_path, DEFAULT_OUTPUT_MODE, self.overwrite)
+                        .with_context(|| {
+                            format!("Failed to create output file: {}", output_path.display())
+                        })?;
+                let bytes = io::copy(&mut stream, &mut output_file)?;
+                output_file.commit()?;
+                debug!("Recovery complete. {} bytes written.", bytes);
+                Ok(())
+            }
+        };
+        if let Some(progress) = &self.progress {
+            progress.finish();
+        }
+        Ok(result?)
+    }
+
+    /// Recovers what is left of the archive at `input_path` into the directory
+    /// `output_path`, skipping what error correction cannot repair; see [`crate::salvage`].
+    ///
+    /// Archives that are not compressed, or that were written with seekable frames, are
+    /// read past every lost chunk. Others are extracted up to the first one, as by
+    /// [`RecoverOptions::salvage`]. The returned [`Salvage`] lists what was lost, and has
+    /// also been logged.
+    pub fn salvage_file(&self, input_path: &Path, output_path: &Path) -> crate::Result<Salvage> {
+        debug!("Opening encrypted input file: {}", input_path.display());
+        let mut input_file = File::open(input_path)
+            .with_context(|| format!("Failed to open input file: {}", input_path.display()))?;
+        if let Some(progress) = &self.progress {
+            progress.set_total(input_file.metadata()?.len());
+        }
+        let header = file_header(&mut input_file)?
+            .filter(|header| !header.stream && !header.dedup)
+            .filter(|header| header.seekable || header.codec == Some(Codec::None));
+        let result = match header {
+            Some(header) => self.salvage_around(input_path, output_path, header.seekable),
+            None => {
+                debug!("Damage cannot be read past in this archive; salvaging up to it.");
+                self.salvage(input_file, output_path)
+            }
+        };
+        if let Some(progress) = &self.progress {
+            progress.finish();
+        }
+        result
+    }
+
+    /// Recovers what can be read of the archive stream `input` into `output_path`, which
+    /// stops at the first damage that error correction cannot repair and keeps what was
+    /// recovered before it. The returned [`Salvage`] names the file that was cut short
+    /// there, and has also been logged.
+    pub fn salvage<R: Read>(&self, input: R, output_path: &Path) -> crate::Result<Salvage> {
+        let salvage = match self.open_contents(input)? {
+            Contents::Archive(mut archive) => {
+                self.prepare_extraction(&mut archive, output_path)?;
+                let mut extraction = Extraction::new(self, output_path, Some(Losses::default()))?;
+                let result = archive
+                    .entries()
+                    .map_err(anyhow::Error::from)
+                    .and_then(|entries| extraction.unpack(entries));
+                if let Err(e) = result {
+                    if cancel::is_requested() {
+                        return Err(e.into());
+                    }
+                    warn_progress!(
+                        self.progress.as_ref(),
+                        "The rest of the archive cannot be read: {e:#}"
+                    );
+                    extraction.stop();
+                }
+                extraction.finish()?.unwrap_or_default()
+            }
+            Contents::Stream(_) if self.select.is_some() => {
+                warn!("Archive holds a single stream; paths cannot be selected.");
+                return Err(anyhow!(
+                    "Archive holds a single stream rather than files; paths cannot be selected."
+                )
+                .into());
+            }
+            Contents::Stream(mut stream) => {
+                debug!("Writing stream to output file: {}", output_path.display());
+                let mut output_file =
+                    AtomicFile::create(output_path, DEFAULT_OUTPUT_MODE, self.overwrite)
+                        .with_context(|| {
+                            format!("Failed to create output file: {}", output_path.display())
+                        })?;
+                let mut salvage = Salvage::default();
+                if let Err(e) = io::copy(&mut stream, &mut output_file) {
+                    if cancel::is_requested() {
+                        return Err(e.into());
+                    }
+                    warn_progress!(
+                        self.progress.as_ref(),
+                        "The rest of the stream cannot be read: {e}"
+                    );
+                    salvage.stopped = true;
+                    salvage.truncated.push(output_path.display().to_string());
+                }
+                output_file.commit()?;
+                salvage
+            }
+        };
+        salvage.report(self.progress.as_ref());
+        Ok(salvage)
+    }
+
+    /// Salvages the archive at `input_path`, whose tar stream is not compressed or is in
+    /// seekable frames, reading lost chunks and frames as zeros. After a lost tar header,
+    /// extraction goes on from the next intact one.
+    fn salvage_around(
+        &self,
+        input_path: &Path,
+        output_path: &Path,
+        seekable: bool,
+    ) -> crate::Result<Salvage> {
+        let open = || -> Result<Box<dyn ReadSeek + '_>> {
+            let input = File::open(input_path)
+                .with_context(|| format!("Failed to open input file: {}", input_path.display()))?;
+            let input = CancelReader::new(input);
+            let input: Box<dyn ReadSeek> = match &self.progress {
+                Some(progress) => Box::new(ProgressReader::new(input, progress.clone())),
+                None => Box::new(input),
+            };
+            let mut source = fec::FecReader::new(input).map_err(header_error)?;
+            source.set_skip_unrecoverable(true);
+            source.log_repairs(self.repairs.clone());
+            let header = source.header();
+            let payload = self.decrypt(integrity::SourceReader::new(source), &header)?;
+            Ok(Box::new(integrity::ChunkLocator::new(payload)))
+        };
+        let mut first = Some(open()?);
+        let reopen: Reopen<'_> = Box::new(move || match first.take() {
+            Some(payload) => Ok(payload),
+            None => open().map_err(io::Error::other),
+        });
+        let losses = Losses::default();
+        let mut stream: Box<dyn ReadSeek + '_> = if seekable {
+            let payload = SalvageReader::new(reopen, Losses::default())?;
+            match SeekableReader::new(payload) {
+                Ok(mut frames) => {
+                    frames.salvage(losses.clone());
+                    Box::new(frames)
+                }
+                Err(e) => {
+                    warn_progress!(
+                        self.progress.as_ref(),
+                        "The seek table is lost ({e}); salvaging up to the first damage."
+                    );
+                    let input = File::open(input_path).with_context(|| {
+                        format!("Failed to open input file: {}", input_path.display())
+                    })?;
+                    return self.salvage(input, output_path);
+                }
+            }
+        } else {
+            Box::new(SalvageReader::new(reopen, losses.clone())?)
+        };
+
+        let mut extraction = Extraction::new(self, output_path, Some(losses.clone()))?;
+        let mut start = 0;
+        loop {
+            let mut archive = tar::Archive::new(Rebased::new(&mut stream, start)?);
+            archive.set_ignore_zeros(true);
+            self.prepare_extraction(&mut archive, output_path)?;
+            extraction.base = start;
+            let result = archive
+                .entries_with_seek()
+                .map_err(anyhow::Error::from)
+                .and_then(|entries| extraction.unpack(entries));
+            let Err(e) = result else {
+                break;
+            };
+            let position = stream.stream_position()?;
+            if cancel::is_requested() || !losses.any_before(position) {
+                return Err(e.into());
+            }
+            // What was read after the damage was not a tar entry.
+            if let Some(path) = extraction.current.take() {
+                debug!("Entry {} lies in the damage: {e:#}", path.display());
+            }
+            match find_header(&mut stream, position)? {
+                Some(next) => {
+                    info!("Damaged archive headers before byte {next}; extracting on from there.");
+                    start = next;
+                }
+                None => {
+                    warn_progress!(
+                        self.progress.as_ref(),
+                        "No intact archive entries follow byte {position}."
+                    );
+                    break;
+                }
+            }
+        }
+        let salvage = extraction.finish()?.unwrap_or_default();
+        salvage.report(self.progress.as_ref());
+        Ok(salvage)
+    }
+
+    /// Writes the contents of the archive stream `input` to `writer`.
+    ///
+    /// Single-stream archives are written as they were protected; file archives are written
+    /// as an uncompressed tar stream.
+    ///
+    /// Fails if paths were selected, as the tar stream is passed through unchanged.
+    pub fn recover_to_writer<R: Read, W: Write>(
+        &self,
+        input: R,
+        mut writer: W,
+    ) -> crate::Result<W> {
+        if self.select.is_some() {
+            warn!("Selected paths cannot be written to a stream.");
+            return Err(anyhow!(
+                "Selecting paths requires extracting to a directory, not writing to a stream."
+            )
+            .into());
+        }
+        let bytes = match self.open_contents(input)? {
+            Contents::Archive(archive) => io::copy(&mut archive.into_inner(), &mut writer)?,
+            Contents::Stream(mut stream) => io::copy(&mut stream, &mut writer)?,
+        };
+        writer.flush()?;
+        if let Some(progress) = &self.progress {
+            progress.finish();
+        }
+        debug!("Recovery complete. {} bytes written.", bytes);
+        Ok(writer)
+    }
+
+    /// Writes a JSON listing of the archive stream `input` to `writer` without extracting it.
+    pub fn write_manifest<R: Read, W: Write>(&self, input: R, writer: W) -> crate::Result<usize> {
+        let mut archive = self.open(input)?;
+        manifest::write_manifest(
+            archive
+                .entries()
+                .context("Failed to read archive entries")?,
+            writer,
+        )
+    }
+
+    /// Like [`RecoverOptions::write_manifest`], but skips the contents of the entries
+    /// without reading them if the archive was written with seekable frames.
+    pub fn write_manifest_seekable<R: Read + Seek, W: Write>(
+        &self,
+        input: R,
+        writer: W,
+    ) -> crate::Result<usize> {
+        match self.open_seekable(input)? {
+            Seekable::Archive(mut archive) => manifest::write_manifest(
+                archive
+                    .entries_with_seek()
+                    .context("Failed to read archive entries")?,
+                writer,
+            ),
+            Seekable::Sequential(input) => self.write_manifest(input, writer),
+        }
+    }
+
+    /// Writes one line per entry of the archive stream `input` to `writer`; see
+    /// [`manifest::write_listing`].
+    pub fn write_listing<R: Read, W: Write>(
+        &self,
+        input: R,
+        writer: W,
+        long: bool,
+    ) -> crate::Result<usize> {
+        let mut archive = self.open(input)?;
+        manifest::write_listing(
+            archive
+                .entries()
+                .context("Failed to read archive entries")?,
+            writer,
+            long,
+        )
+    }
+
+    /// Like [`RecoverOptions::write_listing`], but skips the contents of the entries without
+    /// reading them if the archive was written with seekable frames.
+    pub fn write_listing_seekable<R: Read + Seek, W: Write>(
+        &self,
+        input: R,
+        writer: W,
+        long: bool,
+    ) -> crate::Result<usize> {
+        match self.open_seekable(input)? {
+            Seekable::Archive(mut archive) => manifest::write_listing(
+                archive
+                    .entries_with_seek()
+                    .context("Failed to read archive entries")?,
+                writer,
+                long,
+            ),
+            Seekable::Sequential(input) => self.write_listing(input, writer, long),
+        }
+    }
+
+    /// Writes the contents of the file archived at `path` in the archive stream `input` to
+    /// `writer`, and returns how many bytes that was.
+    ///
+    /// Fails on a hard link, as the file it links to comes before it and the stream has
+    /// passed it; [`RecoverOptions::cat_seekable`] reads the archive again instead.
+    pub fn cat<R: Read, W: Write>(
+        &self,
+        input: R,
+        path: &Path,
+        mut writer: W,
+    ) -> crate::Result<u64> {
+        let mut archive = self.open(input)?;
+        let entries = archive
+            .entries()
+            .context("Failed to read archive entries")?;
+        match cat_entries(entries, path, &mut writer)? {
+            Cat::Written(bytes) => Ok(bytes),
+            Cat::HardLink(target) => {
+                warn!(
+                    "{} is a hard link to {}, which the stream has passed.",
+                    path.display(),
+                    target.display()
+                );
+                Err(anyhow!(
+                    "{} is a hard link to {}; cat that path instead, or read the archive from a file.",
+                    path.display(),
+                    target.display()
+                ).into())
+            }
+        }
+    }
+
+    /// Like [`RecoverOptions::cat`], but skips the contents of the other entries without
+    /// reading them if the archive was written with seekable frames, and follows hard links
+    /// by reading the archive again.
+    pub fn cat_seekable<R: Read + Seek, W: Write>(
+        &self,
+        mut input: R,
+        path: &Path,
+        mut writer: W,
+    ) -> crate::Result<u64> {
+        let mut path = Cow::Borrowed(path);
+        // Each link followed reads the archive again, so a loop must not go on forever.
+        for _ in 0..=LINK_HOPS {
+            input.seek(SeekFrom::Start(0))?;
+            let found = match self.open_seekable(&mut input)? {
+                Seekable::Archive(mut archive) => cat_entries(
+                    archive
+                        .entries_with_seek()
+                        .context("Failed to read archive entries")?,
+                    &path,
+                    &mut writer,
+                )?,
+                Seekable::Sequential(input) => cat_entries(
+                    self.open(input)?
+                        .entries()
+                        .context("Failed to read archive entries")?,
+                    &path,
+                    &mut writer,
+                )?,
+            };
+            match found {
+                Cat::Written(bytes) => return Ok(bytes),
+                Cat::HardLink(target) => {
+                    debug!(
+                        "{} is a hard link to {}; reading the archive again.",
+                        path.display(),
+                        target.display()
+                    );
+                    path = Cow::Owned(target);
+                }
+            }
+        }
+        warn!("Hard links to {} form a loop.", path.display());
+        Err(anyhow!("Hard links to {} form a loop.", path.display()).into())
+    }
+
+    /// Serves the seekable archive `input` as a read-only file system at `mountpoint` until
+    /// it is unmounted or sage is interrupted; see [`mount`](crate::mount).
+    ///
+    /// Fails on archives written without seekable frames, which can only be read in order.
+    #[cfg(target_os = "linux")]
+    pub fn mount<R: Read + Seek>(&self, input: R, mountpoint: &Path) -> crate::Result<()> {
+        match self.open_seekable(input)? {
+            Seekable::Archive(archive) => crate::mount::mount(archive, mountpoint),
+            Seekable::Sequential(_) => {
+                warn!("The archive was written without seekable frames.");
+                Err(anyhow!(
+                    "Only archives written with --seekable can be mounted; recover this one instead."
+                ).into())
+            }
+        }
+    }
+
+    /// Compares the archive stream `input` with the directory `dir`; see
+    /// [`diff::diff_entries`].
+    pub fn diff<R: Read>(
+        &self,
+        input: R,
+        dir: &Path,
+        exclude: &ExcludeSet,
+    ) -> crate::Result<Vec<Difference>> {
+        let mut archive = self.open(input)?;
+        diff::diff_entries(
+            archive
+                .entries()
+                .context("Failed to read archive entries")?,
+            dir,
+            exclude,
+        )
+    }
+
+    /// Like [`RecoverOptions::diff`], but skips the contents of the entries without reading
+    /// them if the archive was written with seekable frames.
+    pub fn diff_seekable<R: Read + Seek>(
+        &self,
+        input: R,
+        dir: &Path,
+        exclude: &ExcludeSet,
+    ) -> crate::Result<Vec<Difference>> {
+        match self.open_seekable(input)? {
+            Seekable::Archive(mut archive) => diff::diff_entries(
+                archive
+                    .entries_with_seek()
+                    .context("Failed to read archive entries")?,
+                dir,
+                exclude,
+            ),
+            Seekable::Sequential(input) => self.diff(input, dir, exclude),
+        }
+    }
+
+    fn extract<R: Read>(&self, mut archive: tar::Archive<R>, output_path: &Path) -> Result<()> {
+        self.prepare_extraction(&mut archive, output_path)?;
+        self.unpack_entries(archive.entries()?, output_path)?;
+        debug!(
+            "Recovery complete. Files extracted to: {}",
+            output_path.display()
+        );
+
+        Ok(())
+    }
+
+    /// Creates the parent of `output_path` and applies the options that `tar` handles.
+    fn prepare_extraction<R: Read>(
+        &self,
+        archive: &mut tar::Archive<R>,
+        output_path: &Path,
+    ) -> Result<()> {
+        debug!(
+            "Extracting tar archive to output path: {}",
+            output_path.display()
+        );
+        if let Some(parent) = output_path.parent()
+            && !parent.exists()
+        {
+            debug!(
+                "Output directory does not exist. Creating: {}",
+                parent.display()
+            );
+            fs::create_dir_all(parent)?;
+        }
+        archive.set_preserve_permissions(self.preserve_permissions.unwrap_or(true));
+        archive.set_preserve_mtime(self.preserve_mtime.unwrap_or(true));
+        Ok(())
+    }
+
+    /// Extracts entry by entry, skipping entries that were not selected, reporting progress,
+    /// applying stored ownership through the uid/gid maps, then checking the extracted files
+    /// against the stored checksums and finally removing the paths an incremental archive
+    /// lists as deleted.
+    ///
+    /// Like [`tar::Archive::unpack`], directories are created last, deepest first, so that
+    /// read-only directories do not prevent extracting their contents.
+    fn unpack_entries<R: Read>(
+        &self,
+        entries: tar::Entries<'_, R>,
+        output_path: &Path,
+    ) -> Result<()> {
+        let mut extraction = Extraction::new(self, output_path, None)?;
+        extraction.unpack(entries)?;
+        extraction.finish()?;
+        Ok(())
+    }
+
+    /// Extracts `entry` where the path policy puts it, returning that path, or `None` if it
+    /// was skipped.
+    fn unpack_entry<R: Read>(
+        &self,
+        mut entry: tar::Entry<'_, R>,
+        output_path: &Path,
+        conflicts: &mut Conflicts,
+        mapper: Option<&mut OwnershipMapper>,
+        restorer: Option<&mut XattrRestorer>,
+    ) -> Result<Option<PathBuf>> {
+        let rel_path = entry.path()?.into_owned();
+        let kind = entry.header().entry_type();
+        let Some(path) = self.paths.destination(
+            output_path,
+            &rel_path,
+            kind.is_symlink(),
+            self.progress.as_ref(),
+        )?
+        else {
+            return Ok(None);
+        };
+        let Some(path) = conflicts.resolve(&rel_path, path, kind.is_dir())? else {
+            return Ok(None);
+        };
+        let attrs = match restorer {
+            Some(_) => xattrs::from_entry(&mut entry)?,
+            None => Vec::new(),
+        };
+        let mtime = if self.preserve_mtime.unwrap_or(true) {
+            tar_format::mtime(&mut entry)?
+        } else {
+            None
+        };
+        let mut attributes = Attributes::from_entry(&mut entry)?;
+        if !self.preserve_permissions.unwrap_or(true) {
+            attributes.flags = 0;
+        }
+        if !self.preserve_mtime.unwrap_or(true) {
+            attributes.created = None;
+        }
+        if let Some(progress) = &self.progress {
+            progress.set_entry(&rel_path);
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        if kind.is_hard_link() {
+            // tar resolves the target of a hard link against the working directory.
+            let target = entry
+                .link_name()?
+                .ok_or_else(|| anyhow!("Hard link {} has no target", rel_path.display()))?;
+            let target = match conflicts.moved(&target) {
+                Some(moved) => moved.map(Path::to_path_buf),
+                None => {
+                    self.paths
+                        .destination(output_path, &target, false, self.progress.as_ref())?
+                }
+            };
+            let Some(target) = target else {
+                warn_progress!(
+                    self.progress.as_ref(),
+                    "Skipping hard link {} to a skipped path.",
+                    rel_path.display()
+                );
+                return Ok(None);
+            };
+            if path
+                .symlink_metadata()
+                .is_ok_and(|metadata| !metadata.is_dir())
+            {
+                fs::remove_file(&path)?;
+            }
+            fs::hard_link(&target, &path).with_context(|| {
+                format!("Failed to link {} to {}", path.display(), target.display())
+            })?;
+        } else {
+            entry
+                .unpack(&path)
+                .with_context(|| format!("Failed to extract {}", path.display()))?;
+        }
+        let header = entry.header();
+        let chowned = mapper.is_some();
+        if let Some(mapper) = mapper {
+            mapper.apply(&path, header.uid()?, header.gid()?)?;
+        }
+        if let Some(restorer) = restorer
+            && !attrs.is_empty()
+        {
+            restorer.apply(&path, &attrs)?;
+        }
+        if sparse::is_file(header.entry_type()) || header.entry_type().is_dir() {
+            let mode = header.mode()?;
+            if !self.preserve_permissions.unwrap_or(true) {
+                set_mode(&path, mode & 0o755)?;
+            } else if chowned && mode & 0o6000 != 0 {
+                // Changing the owner clears the setuid and setgid bits, so apply them again.
+                set_mode(&path, mode & 0o7777)?;
+            }
+            // tar sets whole seconds only; PAX archives may know better.
+            if let Some(mtime) = mtime {
+                set_mtime(&path, mtime)
+                    .with_context(|| format!("Failed to set the time of {}", path.display()))?;
+            }
+            attributes
+                .apply(&path)
+                .with_context(|| format!("Failed to set the attributes of {}", path.display()))?;
+        }
+
+        Ok(Some(path))
+    }
+
+    /// The `--path` patterns of `select` that select the entry archived at `path`, as stored
+    /// or normalized.
+    fn matching(&self, select: &PathSelector, path: &Path) -> Vec<usize> {
+        let indices = select.matching(path);
+        match self.paths.normalize.path(path) {
+            Cow::Owned(normalized) if indices.is_empty() => select.matching(&normalized),
+            _ => indices,
+        }
+    }
+
+    /// Whether extraction applies stored ownership.
+    fn restores_owner(&self) -> bool {
+        self.preserve_owner.unwrap_or_else(|| {
+            self.uid_map.is_some() || self.gid_map.is_some() || ownership::is_root()
+        })
+    }
+}
+
+/// The state of one extraction, which a salvage carries from one readable stretch of the
+/// archive to the next.
+struct Extraction<'o> {
+    options: &'o RecoverOptions,
+    output_path: PathBuf,
+    mapper: Option<OwnershipMapper>,
+    restorer: Option<XattrRestorer>,
+    matched: Vec<bool>,
+    increment: Option<Increment>,
+    checksums: Option<Checksums>,
+    extracted: Vec<(PathBuf, PathBuf)>,
+    conflicts: Conflicts,
+    /// What a salvage lost so far, if this is one.
+    salvage: Option<Salvage>,
+    /// The ranges of the tar stream a salvage lost.
+    losses: Losses,
+    /// Where in the tar stream the entries being read start.
+    base: u64,
+    /// The path of every entry a salvage read.
+    seen: HashSet<String>,
+    /// The entry being extracted.
+    current: Option<PathBuf>,
+}
+
+impl<'o> Extraction<'o> {
+    /// Starts extracting into `output_path`, salvaging around the ranges of the tar stream
+    /// recorded in `losses` if given.
+    fn new(
+        options: &'o RecoverOptions,
+        output_path: &Path,
+        losses: Option<Losses>,
+    ) -> Result<Self> {
+        fs::create_dir_all(output_path)?;
+        let output_path = output_path
+            .canonicalize()
+            .unwrap_or_else(|_| output_path.to_path_buf());
+        Ok(Self {
+            options,
+            output_path,
+            mapper: options
+                .restores_owner()
+                .then(|| OwnershipMapper::new(options.uid_map.clone(), options.gid_map.clone())),
+            restorer: options
+                .preserve_xattrs
+                .unwrap_or(true)
+                .then(XattrRestorer::new),
+            matched: vec![false; options.select.as_ref().map_or(0, |s| s.patterns().len())],
+            increment: None,
+            checksums: None,
+            extracted: Vec::new(),
+            conflicts: Conflicts::new(options.on_conflict)
+                .track_merged_names(options.paths.normalize != Normalization::None),
+            salvage: losses.is_some().then(Salvage::default),
+            losses: losses.unwrap_or_default(),
+            base: 0,
+            seen: HashSet::new(),
+            current: None,
+        })
+    }
+
+    /// Extracts `entries`, then the directories among them. A salvage extracts the
+    /// directories read so far even if reading the entries failed.
+    fn unpack<R: Read>(&mut self, entries: tar::Entries<'_, R>) -> Result<()> {
+        let mut directories = Vec::new();
+        let result = self.unpack_files(entries, &mut directories);
+        if result.is_err() && self.salvage.is_none() {
+            return result;
+        }
+        directories.sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
+        for entry in directories {
+            self.options.unpack_entry(
+                entry,
+                &self.output_path,
+                &mut self.conflicts,
+                self.mapper.as_mut(),
+                self.restorer.as_mut(),
+            )?;
+        }
+        result
+    }
+
+    /// Extracts every entry of `entries` but the directories, which are collected in
+    /// `directories`.
+    fn unpack_files<'a, R: Read>(
+        &mut self,
+        entries: tar::Entries<'a, R>,
+        directories: &mut Vec<tar::Entry<'a, R>>,
+    ) -> Result<()> {
+        for entry in entries {
+            let mut entry = entry?;
+            if self.salvage.is_some() {
+                self.seen
+                    .insert(entry.path()?.to_string_lossy().into_owned());
+            }
+            if entry.header().entry_type().is_file()
+                && entry.path_bytes().as_ref() == CHECKSUM_ENTRY.as_bytes()
+            {
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents)?;
+                if let Some(parsed) = Checksums::parse(&contents) {
+                    debug!("Archive stores checksums of {} files.", parsed.files.len());
+                    self.checksums = Some(parsed);
+                } else {
+                    // Writing it would bypass the path checks of `unpack_in`.
+                    warn!("{CHECKSUM_ENTRY} is not a sage checksum list; skipping it.");
+                }
+                continue;
+            }
+            if entry.header().entry_type().is_file()
+                && entry.path_bytes().as_ref() == INCREMENT_ENTRY.as_bytes()
+            {
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents)?;
+                if let Some(parsed) = Increment::parse(&contents) {
+                    debug!(
+                        "Archive is an increment with {} deleted paths.",
+                        parsed.deleted.len()
+                    );
+                    self.increment = Some(parsed);
+                } else {
+                    warn!("{INCREMENT_ENTRY} is not a sage increment; skipping it.");
+                }
+                continue;
+            }
+            if let Some(select) = &self.options.select {
+                let indices = self.options.matching(select, &entry.path()?);
+                if indices.is_empty() {
+                    continue;
+                }
+                if entry.header().entry_type() == tar::EntryType::Link
+                    && let Some(target) = entry.link_name()?
+                    && self.options.matching(select, &target).is_empty()
+                {
+                    // The data of a hard link is stored with its first path, which has
+                    // already been skipped.
+                    warn_progress!(
+                        self.options.progress.as_ref(),
+                        "Skipping hard link {}: its target {} is not selected.",
+                        entry.path()?.display(),
+                        target.display()
+                    );
+                    continue;
+                }
+                for i in indices {
+                    self.matched[i] = true;
+                }
+            }
+            if entry.header().entry_type() == tar::EntryType::Directory {
+                directories.push(entry);
+                continue;
+            }
+            let is_file = sparse::is_file(entry.header().entry_type());
+            let rel_path = entry.path()?.into_owned();
+            let start = self.base + entry.raw_file_position();
+            // Sparse files store less than their size.
+            let data = start..start + entry.header().entry_size()?;
+            self.current = Some(rel_path.clone());
+            let path = self.options.unpack_entry(
+                entry,
+                &self.output_path,
+                &mut self.conflicts,
+                self.mapper.as_mut(),
+                self.restorer.as_mut(),
+            )?;
+            self.current = None;
+            self.options.repairs.attribute(&rel_path.to_string_lossy());
+            if let Some(path) = path
+                && is_file
+            {
+                if let Some(salvage) = &mut self.salvage
+                    && self.losses.overlaps(&data)
+                {
+                    salvage
+                        .damaged
+                        .push(rel_path.to_string_lossy().into_owned());
+                }
+                self.extracted.push((rel_path, path));
+            }
+        }
+        Ok(())
+    }
+
+    /// Notes that the rest of the archive cannot be read, cutting short the entry being
+    /// extracted.
+    fn stop(&mut self) {
+        if let Some(salvage) = &mut self.salvage {
+            salvage.stopped = true;
+            if let Some(path) = self.current.take() {
+                salvage.truncated.push(path.to_string_lossy().into_owned());
+            }
+        }
+    }
+
+    /// Checks the extracted files against the stored checksums and removes the paths an
+    /// incremental archive lists as deleted. Returns what a salvage lost.
+    fn finish(mut self) -> Result<Option<Salvage>> {
+        self.conflicts.report();
+        // A salvage keeps the files it could only recover in part.
+        let keep_corrupt = self.options.keep_corrupt || self.salvage.is_some();
+        match &self.checksums {
+            Some(checksums) => checksums.check_extracted(
+                &self.extracted,
+                keep_corrupt,
+                self.options.progress.as_ref(),
+            )?,
+            None => debug!("Archive stores no checksums; extracted files are not checked."),
+        }
+        if let Some(mut increment) = self.increment {
+            if let Some(select) = &self.options.select {
+                increment
+                    .deleted
+                    .retain(|path| !self.options.matching(select, Path::new(path)).is_empty());
+            }
+            if self.options.paths.strip_components > 0
+                || self.options.paths.normalize != Normalization::None
+            {
+                increment.deleted = (increment.deleted.iter())
+                    .filter_map(|path| self.options.paths.strip(Path::new(path)))
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .collect();
+            }
+            increment.apply(&self.output_path)?;
+        }
+        if let Some(select) = &self.options.select {
+            for (pattern, _) in select
+                .patterns()
+                .iter()
+                .zip(self.matched)
+                .filter(|(_, m)| !m)
+            {
+                warn_progress!(
+                    self.options.progress.as_ref(),
+                    "Path pattern matched no archive entries: {pattern}"
+                );
+            }
+        }
+        if let Some(salvage) = &mut self.salvage {
+            salvage.lost_bytes = self.losses.total();
+            match &self.checksums {
+                Some(checksums) => {
+                    salvage.lost = (checksums.files.keys())
+                        .filter(|path| !self.seen.contains(*path))
+                        .filter(|path| {
+                            (self.options.select.as_ref()).is_none_or(|select| {
+                                !self.options.matching(select, Path::new(path)).is_empty()
+                            })
+                        })
+                        .cloned()
+                        .collect();
+                }
+                None => salvage.unlisted = !salvage.is_complete(),
+            }
+        }
+        Ok(self.salvage)
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_mtime(path: &Path, mtime: SystemTime) -> io::Result<()> {
+    // futimens only needs the file open, so this also works for directories.
+    File::open(path)?.set_modified(mtime)
+}
+
+#[cfg(windows)]
+fn set_mtime(path: &Path, mtime: SystemTime) -> io::Result<()> {
+    crate::fileattrs::open_attributes(path)?.set_modified(mtime)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn set_mtime(path: &Path, mtime: SystemTime) -> io::Result<()> {
+    if path.is_dir() {
+        return Ok(());
+    }
+    File::options().write(true).open(path)?.set_modified(mtime)
+}
+
+/// Writes the contents of the file archived at `path` among `entries` to `writer`.
+fn cat_entries<R: Read, W: Write>(
+    entries: tar::Entries<'_, R>,
+    path: &Path,
+    writer: &mut W,
+) -> Result<Cat> {
+    for entry in entries {
+        let mut entry = entry.context("Failed to read archive entry")?;
+        if !same_entry(&entry.path()?, path) {
+            continue;
+        }
+        let kind = entry.header().entry_type();
+        if kind == tar::EntryType::Link {
+            let target = entry
+                .link_name()?
+                .with_context(|| format!("Hard link {} has no target", path.display()))?;
+            return Ok(Cat::HardLink(target.into_owned()));
+        }
+        if !sparse::is_file(kind) {
+            warn!("{} is not a file: {:?}", path.display(), kind);
+            return Err(anyhow!("{} is not a file in the archive.", path.display()));
+        }
+        debug!("Writing the contents of {}.", path.display());
+        let bytes = io::copy(&mut entry, writer)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        writer.flush()?;
+        return Ok(Cat::Written(bytes));
+    }
+    warn!("No entry at {} in the archive.", path.display());
+    Err(anyhow!("{} is not in the archive.", path.display()))
+}
+
+/// Whether the archived path `stored` names `path`, ignoring `./` and trailing slashes.
+fn same_entry(stored: &Path, path: &Path) -> bool {
+    let named = |p| Path::components(p).filter(|c| !matches!(c, std::path::Component::CurDir));
+    named(stored).eq(named(path))
+}
+
+/// Turns a failure to read the archive header into an error. An archive from a newer sage
+/// is reported as such rather than hidden behind the context.
+pub(crate) fn header_error(error: io::Error) -> anyhow::Error {
+    if fec::newer_format(&error).is_some() {
+        return error.into();
+    }
+    anyhow::Error::new(error).context("Failed to read error correction header")
+}
+
+/// Opens the tar stream of the passphrase-encrypted seekable archive `input`.
+fn open_seekable_payload<R: Read + Seek>(
+    input: R,
+    identity: &age::scrypt::Identity,
+    repairs: &fec::RepairLog,
+) -> Result<SeekablePayload<R>> {
+    let mut source = fec::FecReader::new(input).map_err(header_error)?;
+    source.log_repairs(repairs.clone());
+    let payload = age::Decryptor::new(integrity::SourceReader::new(source))?
+        .decrypt(std::iter::once(identity as &dyn age::Identity))?;
+    SeekableReader::new(integrity::ChunkLocator::new(Payload::Decrypted(payload)))
+        .context("Failed to read the seek table")
+}
+
+/// Returns the header of `input` if it is an archive with [seekable frames](crate::seekable)
+/// and a tar stream. Leaves `input` where it was.
+fn seekable_header<R: Read + Seek>(input: &mut R) -> io::Result<Option<fec::FileHeader>> {
+    Ok(file_header(input)?.filter(|header| header.seekable && !header.stream && !header.dedup))
+}
+
+/// Returns the header of `input` if it is an archive with an error correction layer. Leaves
+/// `input` where it was.
+fn file_header<R: Read + Seek>(input: &mut R) -> io::Result<Option<fec::FileHeader>> {
+    let start = input.stream_position()?;
+    let prefix = peek(input, fec::MAGIC.len())?;
+    input.seek(SeekFrom::Start(start))?;
+    let header = if fec::has_magic(&prefix) {
+        fec::FecReader::new(&mut *input)
+            .ok()
+            .map(|reader| reader.header())
+    } else {
+        None
+    };
+    input.seek(SeekFrom::Start(start))?;
+    Ok(header)
+}
+
+/// Reads up to `len` bytes from `reader` so they can be inspected and then chained back.
+fn peek<R: Read>(reader: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut prefix = Vec::with_capacity(len);
+    reader.take(len as u64).read_to_end(&mut prefix)?;
+    Ok(prefix)
+}
+
+/// Wraps the archive in the error correction reader, or passes plain age files and
+/// ASCII-armored archives through.
+///
+/// Also returns the archive header, synthesized for files without one. The groups repaired
+/// are recorded in `repairs` if given.
+pub(crate) fn open_fec_source<'a, R: Read + 'a>(
+    mut input: R,
+    repairs: Option<&fec::RepairLog>,
+) -> Result<(Box<dyn Read + 'a>, fec::FileHeader)> {
+    let prefix = peek(&mut input, ARMOR_BEGIN_MARKER.len())?;
+    let input = Cursor::new(prefix.clone()).chain(input);
+
+    if prefix.starts_with(ARMOR_BEGIN_MARKER) {
+        info!("Archive is ASCII-armored; it has no error correction layer.");
+        // Armored archives carry no sage header, so the codec is detected from the payload.
+        let armored = age::armor::ArmoredReader::new(BufReader::new(input));
+        return Ok((Box::new(armored), fec::FileHeader::default()));
+    }
+
+    if prefix.starts_with(AGE_MAGIC) {
+        info!("Archive is a plain age file; it has no error correction layer.");
+        // Older sage wrote these with zstd and `protect --compat` with any codec, so it is
+        // detected from the payload, like the rest of the layout.
+        return Ok((Box::new(input), fec::FileHeader::default()));
+    }
+    if !fec::has_magic(&prefix) {
+        warn!("Archive header is not recognized; attempting error correction anyway.");
+    }
+    debug!("Initializing error correction.");
+    let mut fec_reader = fec::FecReader::new(input).map_err(header_error)?;
+    if let Some(repairs) = repairs {
+        fec_reader.log_repairs(repairs.clone());
+    }
+    let header = fec_reader.header();
+    match (header.codec, header.compression_level) {
+        (None, _) => {}
+        (Some(Codec::None), _) => info!("Archive is not compressed."),
+        (Some(codec), 0) => info!("Archive was compressed with {codec}."),
+        (Some(codec), level) => info!("Archive was compressed with {codec} level {level}."),
+    }
+    Ok((Box::new(fec_reader), header))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProtectOptions;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    fn passphrase_archive() -> Vec<u8> {
+        ProtectOptions::new()
+            .passphrase(SecretString::from("correct horse"))
+            .protect_stream(&b"only the passphrase opens this"[..], Vec::new())
+            .unwrap()
+    }
+
+    #[test]
+    fn falls_back_to_the_passphrase_when_no_identity_matches() {
+        let archive = passphrase_archive();
+        let asked = Rc::new(Cell::new(0));
+        let counter = asked.clone();
+        let options = RecoverOptions::new()
+            .identity(Box::new(age::x25519::Identity::generate()))
+            .passphrase_provider(Box::new(move || {
+                counter.set(counter.get() + 1);
+                Ok(SecretString::from("correct horse"))
+            }));
+
+        let Contents::Stream(mut stream) = options.open_contents(&archive[..]).unwrap() else {
+            panic!("expected a single stream");
+        };
+        let mut contents = String::new();
+        stream.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "only the passphrase opens this");
+        assert_eq!(asked.get(), 1);
+    }
+
+    /// Returns `len` pseudo-random bytes, so that an archive is about as large as its files.
+    fn incompressible(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recovers_seekable_archives_on_several_threads() {
+        let identity = age::x25519::Identity::generate();
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input");
+        fs::create_dir(&input).unwrap();
+        let files: Vec<Vec<u8>> = (0..4).map(|i| incompressible(1_500_000 + i)).collect();
+        for (i, contents) in files.iter().enumerate() {
+            fs::write(input.join(i.to_string()), contents).unwrap();
+        }
+        let archive = dir.path().join("archive.sage");
+        ProtectOptions::new()
+            .recipient(Box::new(identity.to_public()))
+            .seekable(true)
+            .protect_to_file(&input, &archive)
+            .unwrap();
+
+        let output = dir.path().join("output");
+        RecoverOptions::new()
+            .identity(Box::new(identity))
+            .threads(3)
+            .recover_file(&archive, &output)
+            .unwrap();
+        for (i, contents) in files.iter().enumerate() {
+            assert_eq!(&fs::read(output.join(i.to_string())).unwrap(), contents);
+        }
+    }
+
+    #[test]
+    fn round_trips_long_paths_and_file_attributes() {
+        let identity = age::x25519::Identity::generate();
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input");
+        // Longer than the 260 characters Windows allows without the `\\?\` prefix.
+        let deep = (0..8).fold(PathBuf::new(), |path, i| {
+            path.join(format!("{i}{}", "d".repeat(40)))
+        });
+        fs::create_dir_all(input.join(&deep)).unwrap();
+        fs::write(input.join(&deep).join("file.txt"), "deep").unwrap();
+        let hidden = input.join("hidden.txt");
+        fs::write(&hidden, "hidden").unwrap();
+        #[cfg(windows)]
+        {
+            let status = std::process::Command::new("attrib")
+                .args(["+h", "+r"])
+                .arg(&hidden)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+
+        let archive = ProtectOptions::new()
+            .recipient(Box::new(identity.to_public()))
+            .protect(&input, Vec::new())
+            .unwrap();
+        let output = dir.path().join("output");
+        RecoverOptions::new()
+            .identity(Box::new(identity))
+            .recover(archive.as_slice(), &output)
+            .unwrap();
+        assert_eq!(
+            fs::read(output.join(&deep).join("file.txt")).unwrap(),
+            b"deep"
+        );
+        assert_eq!(fs::read(output.join("hidden.txt")).unwrap(), b"hidden");
+        #[cfg(windows)]
+        {
+            use std::os::windows::fs::MetadataExt;
+
+            let (before, after) = (
+                fs::metadata(&hidden).unwrap(),
+                fs::metadata(output.join("hidden.txt")).unwrap(),
+            );
+            assert_eq!(after.file_attributes() & 0x3, 0x3);
+            assert_eq!(after.created().unwrap(), before.created().unwrap());
+            assert_eq!(after.modified().unwrap(), before.modified().unwrap());
+        }
+    }
+
+    #[test]
+    fn skips_unselected_entries_of_seekable_archives() {
+        /// Counts the bytes read through it.
+        struct Counting<R>(R, Rc<Cell<u64>>);
+        impl<R: Read> Read for Counting<R> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = self.0.read(buf)?;
+                self.1.set(self.1.get() + n as u64);
+                Ok(n)
+            }
+        }
+        impl<R: Seek> Seek for Counting<R> {
+            fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+                self.0.seek(pos)
+            }
+        }
+
+        let identity = age::x25519::Identity::generate();
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input");
+        fs::create_dir(&input).unwrap();
+        let big = incompressible(20_000_000);
+        fs::write(input.join("big"), &big).unwrap();
+        fs::write(input.join("small"), b"wanted").unwrap();
+        let archive = ProtectOptions::new()
+            .recipient(Box::new(identity.to_public()))
+            .seekable(true)
+            .protect(&input, Vec::new())
+            .unwrap();
+
+        let read = Rc::new(Cell::new(0));
+        let output = dir.path().join("output");
+        RecoverOptions::new()
+            .identity(Box::new(identity.clone()))
+            .select(PathSelector::new(&["small".to_string()]).unwrap())
+            .recover_seekable(Counting(Cursor::new(&archive), read.clone()), &output)
+            .unwrap();
+        assert_eq!(fs::read(output.join("small")).unwrap(), b"wanted");
+        assert!(!output.join("big").exists());
+        assert!(
+            read.get() < archive.len() as u64 / 2,
+            "read {} of {} bytes",
+            read.get(),
+            archive.len()
+        );
+
+        // Readers that cannot seek still get everything.
+        let output = dir.path().join("sequential");
+        RecoverOptions::new()
+            .identity(Box::new(identity))
+            .recover(&archive[..], &output)
+            .unwrap();
+        assert_eq!(fs::read(output.join("big")).unwrap(), big);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_one_file_and_follows_hard_links_when_it_can_seek() {
+        let identity = age::x25519::Identity::generate();
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input");
+        fs::create_dir_all(input.join("etc")).unwrap();
+        fs::write(input.join("etc/app.conf"), "port = 8080\n").unwrap();
+        fs::hard_link(input.join("etc/app.conf"), input.join("link.conf")).unwrap();
+        let archive = ProtectOptions::new()
+            .recipient(Box::new(identity.to_public()))
+            .protect(&input, Vec::new())
+            .unwrap();
+        let options = RecoverOptions::new().identity(Box::new(identity));
+
+        let mut out = Vec::new();
+        let bytes = options
+            .cat(&archive[..], Path::new("./etc/app.conf"), &mut out)
+            .unwrap();
+        assert_eq!((bytes, out.as_slice()), (12, &b"port = 8080\n"[..]));
+
+        // Whichever of the two protect stored first, one of them is a hard link.
+        for path in ["etc/app.conf", "link.conf"] {
+            let mut out = Vec::new();
+            options
+                .cat_seekable(Cursor::new(&archive), Path::new(path), &mut out)
+                .unwrap();
+            assert_eq!(out, b"port = 8080\n");
+        }
+        let streamed = ["etc/app.conf", "link.conf"]
+            .iter()
+            .filter(|path| {
+                options
+                    .cat(&archive[..], Path::new(path), io::sink())
+                    .is_ok()
+            })
+            .count();
+        assert_eq!(streamed, 1);
+
+        let error = options.cat(&archive[..], Path::new("etc"), io::sink());
+        assert!(error.unwrap_err().to_string().contains("not a file"));
+        let error = options.cat(&archive[..], Path::new("missing"), io::sink());
+        assert!(
+            error
+                .unwrap_err()
+                .to_string()
+                .contains("not in the archive")
+        );
+    }
+
+    #[test]
+    fn reports_repairs_and_warnings_to_progress() {
+        #[derive(Default)]
+        struct Recording {
+            repairs: std::sync::Mutex<Vec<u64>>,
+            warnings: std::sync::Mutex<Vec<String>>,
+        }
+        impl Progress for Recording {
+            fn advance(&self, _bytes: u64) {}
+            fn block_corrected(&self, repair: &fec::Repair) {
+                self.repairs.lock().unwrap().push(repair.group);
+            }
+            fn warning(&self, message: &str) {
+                self.warnings.lock().unwrap().push(message.to_string());
+            }
+        }
+
+        let identity = age::x25519::Identity::generate();
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input");
+        fs::create_dir(&input).unwrap();
+        fs::write(input.join("file"), incompressible(100_000)).unwrap();
+        let mut archive = ProtectOptions::new()
+            .recipient(Box::new(identity.to_public()))
+            .protect(&input, Vec::new())
+            .unwrap();
+        let middle = archive.len() / 2;
+        archive[middle..middle + 16].fill(0xff);
+
+        let recording = Arc::new(Recording::default());
+        let patterns = ["file".to_string(), "missing".to_string()];
+        RecoverOptions::new()
+            .identity(Box::new(identity))
+            .select(PathSelector::new(&patterns).unwrap())
+            .progress(recording.clone())
+            .recover(&archive[..], &dir.path().join("output"))
+            .unwrap();
+        assert_eq!(*recording.repairs.lock().unwrap(), [0]);
+        assert_eq!(
+            *recording.warnings.lock().unwrap(),
+            ["Path pattern matched no archive entries: missing"]
+        );
+    }
+
+    #[test]
+    fn needs_a_passphrase_even_with_identities() {
+        let archive = passphrase_archive();
+        let options = RecoverOptions::new().identity(Box::new(age::x25519::Identity::generate()));
+        let error = options.open_contents(&archive[..]).err().unwrap();
+        assert!(error.to_string().contains("no passphrase was provided"));
+    }
+}
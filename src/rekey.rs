@@ -0,0 +1,430 @@
+//! Changing the recipients of an archive without decrypting its payload.
+//!
+//! An age file encrypts its payload with a random file key, and its header wraps that key
+//! once for every recipient. Rekeying unwraps the file key with an identity, checks the
+//! header MAC, wraps the key again for the new recipients and writes a new header with a new
+//! MAC. The payload nonce and the encrypted chunks after the header are copied as they are,
+//! so nothing is decrypted, decompressed or compressed again. The header changes length,
+//! which moves every byte after it, so the error correction layer is encoded again, exactly
+//! as [`convert`](crate::convert) does.
+//!
+//! The file key itself stays the same. Someone who could open the archive before, and kept
+//! the file key, can still open the rekeyed archive; only protecting it again changes the
+//! key.
+
+use crate::fec::{self, FecReader, FecStats, FecWriter, FileHeader};
+use crate::recover::header_error;
+use crate::{AGE_MAGIC, ARMOR_BEGIN_MARKER};
+use age::armor::{ArmoredReader, ArmoredWriter, Format};
+use age::secrecy::ExposeSecret;
+use age_core::format::{FileKey, Stanza};
+use anyhow::{Context, Result, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD_NO_PAD as BASE64;
+use hmac::{Hmac, Mac};
+use log::{debug, info, warn};
+use sha2::Sha256;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+
+/// First line of an age version 1 header.
+const AGE_V1_LINE: &[u8] = b"age-encryption.org/v1\n";
+/// Start of the line that ends an age header and carries its MAC.
+const MAC_PREFIX: &[u8] = b"---";
+/// Start of a recipient stanza.
+const STANZA_PREFIX: &str = "-> ";
+/// Longest age header read, so a damaged payload is not read to its end.
+const MAX_AGE_HEADER: u64 = 1024 * 1024;
+/// Columns of a stanza body line.
+const BODY_COLUMNS: usize = 64;
+/// Tag of the stanza of a passphrase-encrypted file, which must be its only stanza.
+const SCRYPT_TAG: &str = "scrypt";
+
+/// What [`rekey`] read and wrote.
+#[derive(Clone, Copy, Debug)]
+pub struct Rekeyed {
+    /// The sage header written, or `None` for an armored archive.
+    pub header: Option<FileHeader>,
+    /// The recipient stanzas of the old and the new age header.
+    pub old_stanzas: usize,
+    pub new_stanzas: usize,
+    /// The damage repaired while reading the input's error correction layer.
+    pub stats: FecStats,
+}
+
+/// Rewrites the archive read from `input` into `output` for `recipients`, using one of
+/// `identities` to unwrap the file key.
+///
+/// With `keep`, the stanzas of the old recipients are kept and the new ones added after
+/// them. Plain age files get an error correction layer, as [`convert`](crate::convert)
+/// gives them; armored archives stay armored.
+pub fn rekey<R: Read, W: Write>(
+    mut input: R,
+    output: W,
+    identities: &[Box<dyn age::Identity>],
+    recipients: &[Box<dyn age::Recipient + Send>],
+    keep: bool,
+) -> crate::Result<(Rekeyed, W)> {
+    if recipients.is_empty() {
+        warn!("No recipients given.");
+        return Err(anyhow!("No recipients to rekey the archive for.").into());
+    }
+    let mut prefix = Vec::with_capacity(ARMOR_BEGIN_MARKER.len());
+    (&mut input)
+        .take(ARMOR_BEGIN_MARKER.len() as u64)
+        .read_to_end(&mut prefix)?;
+    let input = Cursor::new(prefix.clone()).chain(input);
+    let rewrap = |payload: &mut dyn BufRead, output: &mut dyn Write| {
+        rewrap(payload, output, identities, recipients, keep)
+    };
+
+    if prefix.starts_with(ARMOR_BEGIN_MARKER) {
+        debug!("Archive is ASCII-armored; writing armored output.");
+        let mut payload = BufReader::new(ArmoredReader::new(BufReader::new(input)));
+        let mut writer = ArmoredWriter::wrap_output(output, Format::AsciiArmor)?;
+        let (old_stanzas, new_stanzas) = rewrap(&mut payload, &mut writer)?;
+        let rekeyed = Rekeyed {
+            header: None,
+            old_stanzas,
+            new_stanzas,
+            stats: FecStats::default(),
+        };
+        return Ok((rekeyed, writer.finish()?));
+    }
+
+    if prefix.starts_with(AGE_MAGIC) {
+        info!("Adding error correction to a plain age file.");
+        // Archives from before error correction hold zstd, but `protect --compat` may have
+        // used any codec, so it is left for recover to detect.
+        let header = FileHeader::new(Default::default());
+        let mut payload = BufReader::new(input);
+        let mut writer =
+            FecWriter::new(output, header).context("Failed to write archive header")?;
+        let (old_stanzas, new_stanzas) = rewrap(&mut payload, &mut writer)?;
+        let rekeyed = Rekeyed {
+            header: Some(header),
+            old_stanzas,
+            new_stanzas,
+            stats: FecStats::default(),
+        };
+        return Ok((rekeyed, writer.finish()?));
+    }
+
+    let mut reader = FecReader::new(input).map_err(header_error)?;
+    let old = reader.header();
+    if old.version == 0 {
+        warn!("Archive header is damaged; its metadata cannot be carried over.");
+        return Err(anyhow!(
+            "Archive header is damaged, so its compression and flags are unknown; run \
+             `sage repair` on a copy first."
+        )
+        .into());
+    }
+    if old.unencrypted {
+        warn!("Archive is not encrypted, so it has no recipients to change.");
+        return Err(anyhow!(
+            "Archive is not encrypted; recover it and protect it again to encrypt it."
+        )
+        .into());
+    }
+    let header = FileHeader {
+        compression_level: old.compression_level,
+        codec: old.codec,
+        stream: old.stream,
+        dedup: old.dedup,
+        seekable: old.seekable,
+        dictionary: old.dictionary,
+        window_log: old.window_log,
+        created: old.created,
+        content_hash: old.content_hash,
+        stripe_groups: old.stripe_groups,
+        ..FileHeader::new(old.params)
+    };
+    let mut writer = FecWriter::new(output, header).context("Failed to write archive header")?;
+    let (old_stanzas, new_stanzas) = rewrap(&mut BufReader::new(&mut reader), &mut writer)?;
+    let rekeyed = Rekeyed {
+        header: Some(header),
+        old_stanzas,
+        new_stanzas,
+        stats: reader.stats(),
+    };
+    Ok((rekeyed, writer.finish()?))
+}
+
+/// Reads the age header from `payload`, writes the new one to `output` and copies the rest
+/// of the payload. Returns the number of old and new stanzas.
+fn rewrap(
+    payload: &mut dyn BufRead,
+    output: &mut dyn Write,
+    identities: &[Box<dyn age::Identity>],
+    recipients: &[Box<dyn age::Recipient + Send>],
+    keep: bool,
+) -> Result<(usize, usize)> {
+    let (header, old_stanzas, mac) = read_header(payload)?;
+    debug!("Age header has {} recipient stanzas.", old_stanzas.len());
+    let file_key = identities
+        .iter()
+        .find_map(|identity| identity.unwrap_stanzas(&old_stanzas))
+        .transpose()
+        .context("Failed to unwrap the file key")?
+        .ok_or_else(|| {
+            warn!("None of the identities opens the archive.");
+            anyhow!("None of the given identities can open the archive.")
+        })?;
+    let mut verifier = header_mac(&file_key);
+    verifier.update(&header);
+    verifier.verify_slice(&mac).map_err(|_| {
+        warn!("The age header MAC does not match.");
+        anyhow!("The age header of the archive is damaged or was tampered with.")
+    })?;
+
+    let mut stanzas = if keep {
+        old_stanzas.iter().map(copy_stanza).collect()
+    } else {
+        Vec::new()
+    };
+    stanzas.extend(wrap_file_key(
+        &file_key,
+        recipients.iter().map(|r| r.as_ref() as &dyn age::Recipient),
+    )?);
+    if stanzas.len() > 1 && stanzas.iter().any(|stanza| stanza.tag == SCRYPT_TAG) {
+        warn!("A passphrase cannot be combined with recipients.");
+        return Err(anyhow!(
+            "A passphrase-encrypted archive has no other recipients; rekey it without --keep."
+        ));
+    }
+    output.write_all(&encode_header(&stanzas, &file_key))?;
+    let bytes = io::copy(payload, output).map_err(|e| {
+        if fec::newer_format(&e).is_some() {
+            anyhow::Error::new(e)
+        } else {
+            anyhow::Error::new(e).context("Failed to read archive")
+        }
+    })?;
+    debug!("Copied {bytes} bytes of encrypted payload.");
+    Ok((old_stanzas.len(), stanzas.len()))
+}
+
+/// Wraps `file_key` for each of `recipients`, which must all be allowed in one header.
+pub(crate) fn wrap_file_key<'a>(
+    file_key: &FileKey,
+    recipients: impl Iterator<Item = &'a dyn age::Recipient>,
+) -> Result<Vec<Stanza>> {
+    let mut stanzas = Vec::new();
+    let mut labels = None;
+    for recipient in recipients {
+        let (wrapped, recipient_labels) = recipient
+            .wrap_file_key(file_key)
+            .map_err(|e| anyhow!("Failed to wrap the file key: {e}"))?;
+        match &labels {
+            Some(labels) if *labels != recipient_labels => {
+                warn!("The recipients cannot be mixed.");
+                return Err(anyhow!(
+                    "These recipients cannot be combined in one archive."
+                ));
+            }
+            _ => labels = Some(recipient_labels),
+        }
+        stanzas.extend(wrapped);
+    }
+    Ok(stanzas)
+}
+
+/// Encodes an age header with `stanzas`, authenticated with `file_key`.
+pub(crate) fn encode_header(stanzas: &[Stanza], file_key: &FileKey) -> Vec<u8> {
+    let mut header = AGE_V1_LINE.to_vec();
+    for stanza in stanzas {
+        write_stanza(&mut header, stanza);
+    }
+    header.extend_from_slice(MAC_PREFIX);
+    let mut mac = header_mac(file_key);
+    mac.update(&header);
+    header.push(b' ');
+    header.extend_from_slice(BASE64.encode(mac.finalize().into_bytes()).as_bytes());
+    header.push(b'\n');
+    header
+}
+
+/// Reads an age header, returning the bytes its MAC covers, its stanzas and its MAC.
+fn read_header(payload: &mut dyn BufRead) -> Result<(Vec<u8>, Vec<Stanza>, Vec<u8>)> {
+    let truncated = || anyhow!("The age header of the archive is truncated or damaged.");
+    let mut header = Vec::new();
+    let mut line = Vec::new();
+    let mut limited = payload.take(MAX_AGE_HEADER);
+    limited
+        .read_until(b'\n', &mut line)
+        .context("Failed to read age header")?;
+    if line != AGE_V1_LINE {
+        return Err(anyhow!("Payload is not an age version 1 file."));
+    }
+    header.extend_from_slice(&line);
+
+    let mut stanzas: Vec<Stanza> = Vec::new();
+    // Whether the last stanza still expects body lines.
+    let mut in_body = false;
+    loop {
+        line.clear();
+        if limited
+            .read_until(b'\n', &mut line)
+            .context("Failed to read age header")?
+            == 0
+            || line.last() != Some(&b'\n')
+        {
+            return Err(truncated());
+        }
+        let text = std::str::from_utf8(&line[..line.len() - 1]).map_err(|_| truncated())?;
+        if !in_body && let Some(mac) = text.strip_prefix("--- ") {
+            header.extend_from_slice(MAC_PREFIX);
+            let mac = BASE64.decode(mac).map_err(|_| truncated())?;
+            return Ok((header, stanzas, mac));
+        }
+        header.extend_from_slice(&line);
+        if let Some(stanza) = text.strip_prefix(STANZA_PREFIX).filter(|_| !in_body) {
+            let mut args = stanza.split(' ').map(str::to_string);
+            let tag = args
+                .next()
+                .filter(|tag| !tag.is_empty())
+                .ok_or_else(truncated)?;
+            stanzas.push(Stanza {
+                tag,
+                args: args.collect(),
+                body: Vec::new(),
+            });
+            in_body = true;
+        } else if in_body {
+            let stanza = stanzas.last_mut().expect("a stanza is open");
+            let chunk = BASE64.decode(text).map_err(|_| truncated())?;
+            stanza.body.extend_from_slice(&chunk);
+            // The last line of a body is shorter than a full line, and may be empty.
+            in_body = text.len() == BODY_COLUMNS;
+        } else {
+            return Err(truncated());
+        }
+    }
+}
+
+/// Appends `stanza` to `header` in the encoding of the age format.
+fn write_stanza(header: &mut Vec<u8>, stanza: &Stanza) {
+    header.extend_from_slice(STANZA_PREFIX.as_bytes());
+    header.extend_from_slice(stanza.tag.as_bytes());
+    for arg in &stanza.args {
+        header.push(b' ');
+        header.extend_from_slice(arg.as_bytes());
+    }
+    header.push(b'\n');
+    let body = BASE64.encode(&stanza.body);
+    let mut lines = body.as_bytes().chunks(BODY_COLUMNS);
+    let mut last_len = 0;
+    for line in &mut lines {
+        header.extend_from_slice(line);
+        header.push(b'\n');
+        last_len = line.len();
+    }
+    if last_len == BODY_COLUMNS || body.is_empty() {
+        header.push(b'\n');
+    }
+}
+
+fn copy_stanza(stanza: &Stanza) -> Stanza {
+    Stanza {
+        tag: stanza.tag.clone(),
+        args: stanza.args.clone(),
+        body: stanza.body.clone(),
+    }
+}
+
+/// The HMAC that authenticates an age header, keyed with the file key.
+fn header_mac(file_key: &FileKey) -> Hmac<Sha256> {
+    let key = age_core::primitives::hkdf(&[], b"header", file_key.expose_secret());
+    Hmac::new_from_slice(&key).expect("HMAC takes keys of any length")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Contents, ProtectOptions, RecoverOptions};
+
+    const CONTENTS: &[u8] = b"rotate the keys, keep the data";
+
+    fn read(archive: &[u8], identity: &age::x25519::Identity) -> Result<Vec<u8>> {
+        let options = RecoverOptions::new().identity(Box::new(identity.clone()));
+        let mut contents = Vec::new();
+        match options.open_contents(archive)? {
+            Contents::Stream(mut stream) => stream.read_to_end(&mut contents)?,
+            Contents::Archive(_) => unreachable!(),
+        };
+        Ok(contents)
+    }
+
+    fn payload(archive: &[u8]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        FecReader::new(archive)
+            .unwrap()
+            .read_to_end(&mut payload)
+            .unwrap();
+        payload
+    }
+
+    #[test]
+    fn changes_the_recipients_and_keeps_the_payload() {
+        let old = age::x25519::Identity::generate();
+        let new = age::x25519::Identity::generate();
+        let archive = ProtectOptions::new()
+            .recipient(Box::new(old.to_public()))
+            .protect_stream(CONTENTS, Vec::new())
+            .unwrap();
+
+        let identities: Vec<Box<dyn age::Identity>> = vec![Box::new(old.clone())];
+        let recipients: Vec<Box<dyn age::Recipient + Send>> = vec![Box::new(new.to_public())];
+        let (rekeyed, output) = rekey(
+            archive.as_slice(),
+            Vec::new(),
+            &identities,
+            &recipients,
+            false,
+        )
+        .unwrap();
+        assert_eq!(rekeyed.new_stanzas, 1);
+        assert!(rekeyed.header.unwrap().stream);
+        assert_eq!(read(&output, &new).unwrap(), CONTENTS);
+        assert!(read(&output, &old).is_err());
+
+        // Everything after the age header is copied as it is.
+        let (old_payload, new_payload) = (payload(&archive), payload(&output));
+        let tail = |payload: &[u8]| {
+            let end = payload.windows(4).position(|w| w == b"\n---").unwrap();
+            let end = end + payload[end + 1..].iter().position(|&b| b == b'\n').unwrap() + 2;
+            payload[end..].to_vec()
+        };
+        assert_eq!(tail(&old_payload), tail(&new_payload));
+
+        let (rekeyed, kept) = rekey(
+            output.as_slice(),
+            Vec::new(),
+            &[Box::new(new.clone()) as _],
+            &[Box::new(old.to_public()) as _],
+            true,
+        )
+        .unwrap();
+        assert_eq!(rekeyed.new_stanzas, 2);
+        assert_eq!(read(&kept, &old).unwrap(), CONTENTS);
+        assert_eq!(read(&kept, &new).unwrap(), CONTENTS);
+    }
+
+    #[test]
+    fn refuses_identities_that_do_not_open_the_archive() {
+        let owner = age::x25519::Identity::generate();
+        let stranger = age::x25519::Identity::generate();
+        let archive = ProtectOptions::new()
+            .recipient(Box::new(owner.to_public()))
+            .protect_stream(CONTENTS, Vec::new())
+            .unwrap();
+        let result = rekey(
+            archive.as_slice(),
+            Vec::new(),
+            &[Box::new(stranger.clone()) as _],
+            &[Box::new(stranger.to_public()) as _],
+            false,
+        );
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,1028 @@
+//! Protecting into a file in a way that survives being stopped, for `protect --resume`.
+//!
+//! A resumable run writes the archive to [`partial_path`] and, at the first entry or block
+//! boundary after every checkpoint interval of tar stream, makes everything written so far
+//! durable and records in [`checkpoint_path`] what is needed to carry on from exactly there:
+//! how much of the partial file is final, the data of the error correction group and of the
+//! encryption chunk that are not complete yet, how far the walk of the input got, and the
+//! checksums of the files archived so far. Compression ends its frame at every checkpoint,
+//! so none of its state needs to be kept; the frames of a zstd payload decompress as one.
+//!
+//! The payload is encrypted here rather than by the age crate, whose writer cannot be
+//! stopped and started again, with the same STREAM construction, so the result is an
+//! ordinary archive. Carrying on needs the payload key, so the checkpoint holds it; it is
+//! only ever readable by its owner and is removed when the run completes.
+//!
+//! Files larger than a block are hashed a block at a time with the BLAKE3 tree, so a
+//! checkpoint in the middle of one keeps the hash of what came before.
+
+use crate::checksum::Checksums;
+use crate::compress::{Codec, Encoder};
+use crate::fec::{FecCheckpoint, FecWriter, FileHeader};
+use crate::output::{self, DEFAULT_OUTPUT_MODE};
+use crate::rekey;
+use crate::throttle::ThrottledWriter;
+use age::secrecy::ExposeSecret;
+use age_core::format::FileKey;
+use anyhow::{Context, Result, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use blake3::hazmat::{self, ChainingValue, HasherExt, Mode};
+use chacha20poly1305::aead::{Aead, Payload as AeadPayload};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce};
+use log::{debug, warn};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+
+/// Tar stream written between two checkpoints when no other interval is given: 1 GiB.
+pub const DEFAULT_CHECKPOINT_INTERVAL: u64 = 1 << 30;
+
+/// Plaintext bytes in an age STREAM chunk.
+const CHUNK_LEN: usize = 64 * 1024;
+/// Length of the nonce between the age header and the payload.
+const PAYLOAD_NONCE_LEN: usize = 16;
+/// Smallest block files are hashed and checkpointed in: a BLAKE3 chunk.
+const MIN_BLOCK_LEN: u64 = 1024;
+/// Version of the checkpoint file format.
+const CHECKPOINT_VERSION: u32 = 1;
+
+/// Where a resumable run keeps the archive to `output_path` until it is complete.
+pub fn partial_path(output_path: &Path) -> PathBuf {
+    with_suffix(output_path, ".partial")
+}
+
+/// Where a resumable run records its last checkpoint.
+pub fn checkpoint_path(output_path: &Path) -> PathBuf {
+    with_suffix(output_path, ".checkpoint")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Encrypts an age payload with STREAM, in a state that can be saved between any two writes.
+struct StreamWriter<W> {
+    inner: W,
+    key: [u8; 32],
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    /// Plaintext of the chunk being filled. A full chunk is only sealed once more data
+    /// arrives, so that `finish` can always flag the last one.
+    chunk: Vec<u8>,
+}
+
+impl<W: Write> StreamWriter<W> {
+    fn new(inner: W, key: [u8; 32]) -> Self {
+        Self::resume(
+            inner,
+            CipherState {
+                key,
+                counter: 0,
+                chunk: Vec::new(),
+            },
+        )
+    }
+
+    fn resume(inner: W, state: CipherState) -> Self {
+        let mut chunk = state.chunk;
+        chunk.reserve(CHUNK_LEN - chunk.len());
+        Self {
+            inner,
+            key: state.key,
+            cipher: ChaCha20Poly1305::new(&state.key.into()),
+            counter: state.counter,
+            chunk,
+        }
+    }
+
+    fn state(&self) -> CipherState {
+        CipherState {
+            key: self.key,
+            counter: self.counter,
+            chunk: self.chunk.clone(),
+        }
+    }
+
+    fn seal(&mut self, last: bool) -> io::Result<()> {
+        let mut nonce = [0u8; 12];
+        nonce[3..11].copy_from_slice(&self.counter.to_be_bytes());
+        nonce[11] = last.into();
+        let sealed = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                AeadPayload {
+                    msg: &self.chunk,
+                    aad: &[],
+                },
+            )
+            .map_err(|_| io::Error::other("failed to encrypt a payload chunk"))?;
+        self.inner.write_all(&sealed)?;
+        self.chunk.clear();
+        self.counter += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        self.seal(true)?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for StreamWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut remaining = buf;
+        while !remaining.is_empty() {
+            if self.chunk.len() == CHUNK_LEN {
+                self.seal(false)?;
+            }
+            let take = remaining.len().min(CHUNK_LEN - self.chunk.len());
+            self.chunk.extend_from_slice(&remaining[..take]);
+            remaining = &remaining[take..];
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The state of a [`StreamWriter`] between two writes.
+struct CipherState {
+    key: [u8; 32],
+    counter: u64,
+    chunk: Vec<u8>,
+}
+
+/// The payload, encrypted or left as it is.
+enum Payload<W> {
+    Encrypted(StreamWriter<W>),
+    Plain(W),
+}
+
+impl<W: Write> Payload<W> {
+    fn get_mut(&mut self) -> &mut W {
+        match self {
+            Payload::Encrypted(writer) => &mut writer.inner,
+            Payload::Plain(inner) => inner,
+        }
+    }
+
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Payload::Encrypted(writer) => writer.finish(),
+            Payload::Plain(inner) => Ok(inner),
+        }
+    }
+}
+
+impl<W: Write> Write for Payload<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Payload::Encrypted(writer) => writer.write(buf),
+            Payload::Plain(inner) => inner.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Payload::Encrypted(writer) => writer.flush(),
+            Payload::Plain(inner) => inner.flush(),
+        }
+    }
+}
+
+type Archive = FecWriter<ThrottledWriter<File>>;
+
+/// Compression, encryption and error correction of a resumable run, all on one thread.
+struct Pipeline {
+    /// Only `None` while a checkpoint ends the compressed frame.
+    encoder: Option<Encoder<Payload<Archive>>>,
+    codec: Codec,
+    level: i32,
+    threads: u32,
+    /// Bytes of tar stream written, counting those before the run was resumed.
+    written: u64,
+}
+
+impl Pipeline {
+    fn new(payload: Payload<Archive>, codec: Codec, level: i32, threads: u32) -> Result<Self> {
+        let encoder = codec
+            .encoder(payload, level, threads)
+            .with_context(|| format!("Failed to create {codec} encoder"))?;
+        Ok(Self {
+            encoder: Some(encoder),
+            codec,
+            level,
+            threads,
+            written: 0,
+        })
+    }
+
+    fn encoder(&mut self) -> &mut Encoder<Payload<Archive>> {
+        self.encoder
+            .as_mut()
+            .expect("the encoder is only taken by checkpoints")
+    }
+
+    /// Ends the compressed frame, makes everything written so far durable and returns the
+    /// length of the archive file and the state to carry on from.
+    fn checkpoint(&mut self) -> io::Result<(u64, FecCheckpoint, Option<CipherState>)> {
+        let encoder = self.encoder.take().expect("checkpoints do not nest");
+        let mut payload = encoder.finish()?;
+        let cipher = match &payload {
+            Payload::Encrypted(writer) => Some(writer.state()),
+            Payload::Plain(_) => None,
+        };
+        let fec = payload.get_mut().checkpoint()?;
+        let file = payload.get_mut().get_mut().get_mut();
+        file.sync_data()?;
+        let archive_len = file.stream_position()?;
+        self.encoder = Some(self.codec.encoder(payload, self.level, self.threads)?);
+        Ok((archive_len, fec, cipher))
+    }
+
+    fn finish(mut self) -> io::Result<File> {
+        let encoder = self
+            .encoder
+            .take()
+            .expect("the encoder is only taken by checkpoints");
+        let file = encoder.finish()?.finish()?.finish()?.into_inner();
+        file.sync_all()?;
+        Ok(file)
+    }
+}
+
+/// Writes into the pipeline of a run, shared by the tar builder and the run's readers.
+pub(crate) struct PipelineWriter(Rc<RefCell<Pipeline>>);
+
+impl Write for PipelineWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pipeline = self.0.borrow_mut();
+        let n = pipeline.encoder().write(buf)?;
+        pipeline.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().encoder().flush()
+    }
+}
+
+/// Hashes a file with BLAKE3 a block at a time, keeping the tree of the whole blocks so far
+/// as a state that can be saved at every block boundary.
+pub(crate) struct BlockHasher {
+    block_len: u64,
+    /// Chaining values of the complete subtrees of whole blocks, largest first.
+    stack: Vec<ChainingValue>,
+    /// Whole blocks in the stack.
+    blocks: u64,
+    /// Hashes the block being filled.
+    current: blake3::Hasher,
+    current_len: u64,
+}
+
+impl BlockHasher {
+    /// Starts hashing in blocks of `block_len` bytes, a power of two of at least 1 KiB.
+    pub(crate) fn new(block_len: u64) -> Self {
+        Self::resume(block_len, Vec::new(), 0)
+    }
+
+    fn resume(block_len: u64, stack: Vec<ChainingValue>, blocks: u64) -> Self {
+        debug_assert!(block_len.is_power_of_two() && block_len >= MIN_BLOCK_LEN);
+        let mut current = blake3::Hasher::new();
+        current.set_input_offset(blocks * block_len);
+        Self {
+            block_len,
+            stack,
+            blocks,
+            current,
+            current_len: 0,
+        }
+    }
+
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.current_len == self.block_len {
+                self.push_block();
+            }
+            let take = data.len().min((self.block_len - self.current_len) as usize);
+            self.current.update(&data[..take]);
+            self.current_len += take as u64;
+            data = &data[take..];
+        }
+    }
+
+    /// Moves the full current block into the stack, which is only done once more data is
+    /// known to follow, as the last block of a file is finalized differently.
+    fn push_block(&mut self) {
+        let mut cv = self.current.finalize_non_root();
+        self.blocks += 1;
+        // Every complete pair of subtrees of the same size becomes one.
+        let mut total = self.blocks;
+        while total & 1 == 0 {
+            let left = self
+                .stack
+                .pop()
+                .expect("a complete pair has a left subtree");
+            cv = hazmat::merge_subtrees_non_root(&left, &cv, Mode::Hash);
+            total >>= 1;
+        }
+        self.stack.push(cv);
+        self.current = blake3::Hasher::new();
+        self.current.set_input_offset(self.blocks * self.block_len);
+        self.current_len = 0;
+    }
+
+    /// Returns the state at a block boundary, where more data follows.
+    fn state(&mut self) -> (Vec<ChainingValue>, u64) {
+        self.push_block();
+        (self.stack.clone(), self.blocks)
+    }
+
+    pub(crate) fn finalize(&self) -> blake3::Hash {
+        if self.blocks == 0 {
+            return self.current.finalize();
+        }
+        let mut stack = self.stack.clone();
+        let mut right = self.current.finalize_non_root();
+        loop {
+            let left = stack
+                .pop()
+                .expect("a file of more than one block has a stack");
+            if stack.is_empty() {
+                return hazmat::merge_subtrees_root(&left, &right, Mode::Hash);
+            }
+            right = hazmat::merge_subtrees_non_root(&left, &right, Mode::Hash);
+        }
+    }
+}
+
+/// What a checkpoint records, as written to [`checkpoint_path`].
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    version: u32,
+    /// The input being protected, so that a run is not resumed on another one.
+    input: PathBuf,
+    codec: String,
+    level: i32,
+    data_shards: u8,
+    parity_shards: u8,
+    shard_size: u32,
+    stripe_groups: Option<u16>,
+    /// Length of the partial archive that is final.
+    archive_len: u64,
+    /// Error correction group of the buffered data, and the data itself.
+    group: u64,
+    group_data: String,
+    /// Frames of the complete groups of the current stripe.
+    stripe: String,
+    /// Payload key, next chunk counter and buffered plaintext, if encrypted.
+    cipher: Option<SavedCipher>,
+    /// Bytes of tar stream written.
+    written: u64,
+    /// Entries of the walk of the input that are complete.
+    entries: u64,
+    checksums: Checksums,
+    /// The file the checkpoint was taken in, if it was taken in one.
+    file: Option<SavedFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedCipher {
+    key: String,
+    counter: u64,
+    chunk: String,
+}
+
+/// How far through a file a checkpoint was taken in.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct SavedFile {
+    path: PathBuf,
+    size: u64,
+    /// Modification time in nanoseconds since the Unix epoch, to notice the file changed.
+    mtime: u128,
+    /// Bytes of the file archived, a whole number of blocks.
+    offset: u64,
+    /// The chaining values of the [`BlockHasher`], hex-encoded.
+    stack: Vec<String>,
+    blocks: u64,
+}
+
+impl SavedFile {
+    pub(crate) fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Fails unless `metadata` is that of the file the checkpoint was taken in.
+    pub(crate) fn check(&self, rel_path: &Path, metadata: &fs::Metadata) -> Result<()> {
+        if self.path != rel_path || self.size != metadata.len() || self.mtime != mtime(metadata) {
+            warn!("{} changed since the checkpoint.", rel_path.display());
+            return Err(anyhow!(
+                "{} changed since the checkpoint was taken in it; delete the checkpoint to \
+                 start over.",
+                self.path.display()
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn mtime(metadata: &fs::Metadata) -> u128 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |time| time.as_nanos())
+}
+
+/// How a new run encrypts its payload.
+pub(crate) enum Encryption<'a> {
+    Recipients(Vec<&'a dyn age::Recipient>),
+    None,
+}
+
+/// The settings a run is started with, which a resumed run must share.
+pub(crate) struct Settings {
+    pub(crate) header: FileHeader,
+    pub(crate) level: i32,
+    pub(crate) threads: u32,
+    pub(crate) interval: u64,
+    pub(crate) output_mode: u32,
+    pub(crate) throttle: Option<std::sync::Arc<crate::throttle::Throttle>>,
+}
+
+/// A resumable run, started afresh or from the last checkpoint of an earlier one.
+pub(crate) struct Run {
+    pipeline: Rc<RefCell<Pipeline>>,
+    input: PathBuf,
+    checkpoint_path: PathBuf,
+    interval: u64,
+    block_len: u64,
+    /// Tar stream written at the last checkpoint.
+    last: u64,
+    /// Entries already archived by the earlier run, and the file it stopped in.
+    resumed_entries: u64,
+    resumed_file: Option<SavedFile>,
+    resumed_checksums: Option<Checksums>,
+}
+
+impl Run {
+    /// Carries on from the checkpoint of `output_path`, or starts a new run if it has none.
+    pub(crate) fn open(
+        input: &Path,
+        output_path: &Path,
+        settings: Settings,
+        encryption: Encryption,
+    ) -> Result<Self> {
+        let checkpoint_path = checkpoint_path(output_path);
+        let partial_path = partial_path(output_path);
+        let block_len = prev_power_of_two(settings.interval).max(MIN_BLOCK_LEN);
+        let input = fs::canonicalize(input)
+            .with_context(|| format!("Failed to open input: {}", input.display()))?;
+        let fec_params = settings.header.params;
+        let stripe_groups = settings.header.stripe_groups;
+        let codec = settings.header.codec.unwrap_or_default();
+
+        let checkpoint =
+            match fs::read(&checkpoint_path) {
+                Ok(data) => Some(serde_json::from_slice::<Checkpoint>(&data).with_context(
+                    || format!("Failed to read checkpoint: {}", checkpoint_path.display()),
+                )?),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    return Err(anyhow!(e).context(format!(
+                        "Failed to read checkpoint: {}",
+                        checkpoint_path.display()
+                    )));
+                }
+            };
+        let mut resumed = None;
+        let pipeline = match checkpoint {
+            Some(checkpoint) => {
+                check(
+                    &checkpoint,
+                    &checkpoint_path,
+                    &input,
+                    &settings,
+                    &encryption,
+                )?;
+                debug!(
+                    "Resuming after {} bytes of tar stream and {} entries.",
+                    checkpoint.written, checkpoint.entries
+                );
+                let mut file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(&partial_path)
+                    .with_context(|| {
+                        format!("Failed to open partial archive: {}", partial_path.display())
+                    })?;
+                if file.metadata()?.len() < checkpoint.archive_len {
+                    warn!("The partial archive is shorter than its checkpoint.");
+                    return Err(anyhow!(
+                        "{} is shorter than its checkpoint records; delete it and {} to start \
+                         over.",
+                        partial_path.display(),
+                        checkpoint_path.display()
+                    ));
+                }
+                // What was written after the checkpoint is written again.
+                file.set_len(checkpoint.archive_len)?;
+                file.seek(SeekFrom::End(0))?;
+                let fec = FecWriter::resume(
+                    ThrottledWriter::new(file, settings.throttle),
+                    FecCheckpoint {
+                        params: fec_params,
+                        stripe_groups,
+                        group: checkpoint.group,
+                        buffer: decode(&checkpoint.group_data)?,
+                        stripe: decode(&checkpoint.stripe)?,
+                    },
+                )
+                .context("Failed to resume error correction")?;
+                let payload = match &checkpoint.cipher {
+                    Some(cipher) => Payload::Encrypted(StreamWriter::resume(
+                        fec,
+                        CipherState {
+                            key: decode(&cipher.key)?
+                                .try_into()
+                                .map_err(|_| anyhow!("The checkpoint holds an invalid key."))?,
+                            counter: cipher.counter,
+                            chunk: decode(&cipher.chunk)?,
+                        },
+                    )),
+                    None => Payload::Plain(fec),
+                };
+                let mut pipeline = Pipeline::new(payload, codec, settings.level, settings.threads)?;
+                pipeline.written = checkpoint.written;
+                resumed = Some(checkpoint);
+                pipeline
+            }
+            None => {
+                if partial_path.exists() {
+                    warn!(
+                        "{} has no checkpoint; starting over.",
+                        partial_path.display()
+                    );
+                }
+                debug!("Starting a resumable run into {}.", partial_path.display());
+                let file = output::open_output_file(&partial_path, settings.output_mode)
+                    .with_context(|| {
+                        format!(
+                            "Failed to create partial archive: {}",
+                            partial_path.display()
+                        )
+                    })?;
+                file.set_len(0)?;
+                let mut fec = FecWriter::new(
+                    ThrottledWriter::new(file, settings.throttle),
+                    settings.header,
+                )
+                .context("Failed to write archive header")?;
+                let payload = match encryption {
+                    Encryption::Recipients(recipients) => {
+                        let file_key =
+                            FileKey::init_with_mut(|key| rand::rngs::OsRng.fill_bytes(key));
+                        let stanzas = rekey::wrap_file_key(&file_key, recipients.into_iter())?;
+                        fec.write_all(&rekey::encode_header(&stanzas, &file_key))?;
+                        let nonce: [u8; PAYLOAD_NONCE_LEN] = rand::random();
+                        fec.write_all(&nonce)?;
+                        let key = age_core::primitives::hkdf(
+                            &nonce,
+                            b"payload",
+                            file_key.expose_secret(),
+                        );
+                        Payload::Encrypted(StreamWriter::new(fec, key))
+                    }
+                    Encryption::None => Payload::Plain(fec),
+                };
+                Pipeline::new(payload, codec, settings.level, settings.threads)?
+            }
+        };
+        Ok(Self {
+            pipeline: Rc::new(RefCell::new(pipeline)),
+            input,
+            checkpoint_path,
+            interval: settings.interval,
+            block_len,
+            last: resumed.as_ref().map_or(0, |checkpoint| checkpoint.written),
+            resumed_entries: resumed.as_ref().map_or(0, |checkpoint| checkpoint.entries),
+            resumed_file: resumed
+                .as_mut()
+                .and_then(|checkpoint| checkpoint.file.take()),
+            resumed_checksums: resumed.map(|checkpoint| checkpoint.checksums),
+        })
+    }
+
+    /// A writer into the pipeline of the run.
+    pub(crate) fn writer(&self) -> PipelineWriter {
+        PipelineWriter(self.pipeline.clone())
+    }
+
+    /// Bytes of tar stream the earlier run had written, to report as progress.
+    pub(crate) fn resumed_bytes(&self) -> u64 {
+        self.last
+    }
+
+    /// The checksums of the files the earlier run archived, once.
+    pub(crate) fn take_checksums(&mut self) -> Option<Checksums> {
+        self.resumed_checksums.take()
+    }
+
+    /// Whether the entry `index` of the walk was archived, or started, by the earlier run.
+    pub(crate) fn skips(&self, index: u64) -> bool {
+        index < self.resumed_entries
+            || (index == self.resumed_entries && self.resumed_file.is_some())
+    }
+
+    /// The file the earlier run stopped in, if it is the entry `index`.
+    pub(crate) fn take_file(&mut self, index: u64) -> Option<SavedFile> {
+        if index != self.resumed_entries {
+            return None;
+        }
+        self.resumed_file.take()
+    }
+
+    /// Fails if the earlier run stopped in a file the walk did not come back to.
+    pub(crate) fn check_finished_file(&self) -> Result<()> {
+        match &self.resumed_file {
+            Some(file) => {
+                warn!("{} is gone since the checkpoint.", file.path.display());
+                Err(anyhow!(
+                    "{} is no longer in the input; delete the checkpoint to start over.",
+                    file.path.display()
+                ))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Files larger than this are checkpointed in.
+    pub(crate) fn block_len(&self) -> u64 {
+        self.block_len
+    }
+
+    /// Whether a checkpoint interval of tar stream was written since the last one.
+    pub(crate) fn due(&self) -> bool {
+        self.pipeline.borrow().written - self.last >= self.interval
+    }
+
+    /// Takes a checkpoint after the first `entries` entries of the walk, in `file` if it
+    /// is taken in the middle of one.
+    pub(crate) fn save(
+        &mut self,
+        entries: u64,
+        checksums: &Checksums,
+        file: Option<SavedFile>,
+    ) -> io::Result<()> {
+        let mut pipeline = self.pipeline.borrow_mut();
+        let (archive_len, fec, cipher) = pipeline.checkpoint()?;
+        let checkpoint = Checkpoint {
+            version: CHECKPOINT_VERSION,
+            input: self.input.clone(),
+            codec: pipeline.codec.to_string(),
+            level: pipeline.level,
+            data_shards: fec.params.data_shards,
+            parity_shards: fec.params.parity_shards,
+            shard_size: fec.params.shard_size,
+            stripe_groups: fec.stripe_groups,
+            archive_len,
+            group: fec.group,
+            group_data: BASE64.encode(&fec.buffer),
+            stripe: BASE64.encode(&fec.stripe),
+            cipher: cipher.map(|cipher| SavedCipher {
+                key: BASE64.encode(cipher.key),
+                counter: cipher.counter,
+                chunk: BASE64.encode(&cipher.chunk),
+            }),
+            written: pipeline.written,
+            entries,
+            checksums: checksums.clone(),
+            file,
+        };
+        let data = serde_json::to_vec(&checkpoint)?;
+        let temp = with_suffix(&self.checkpoint_path, ".tmp");
+        {
+            // The checkpoint holds the payload key, whatever the mode of the archive.
+            let mut out = output::open_output_file(&temp, DEFAULT_OUTPUT_MODE)?;
+            out.set_len(0)?;
+            out.write_all(&data)?;
+            out.sync_all()?;
+        }
+        fs::rename(&temp, &self.checkpoint_path)?;
+        sync_parent(&self.checkpoint_path);
+        debug!(
+            "Checkpoint after {} bytes of tar stream, {} bytes of archive.",
+            pipeline.written, archive_len
+        );
+        self.last = pipeline.written;
+        Ok(())
+    }
+
+    /// Finishes the archive and moves it to `output_path`, removing the checkpoint.
+    pub(crate) fn finish(self, output_path: &Path, overwrite: bool) -> Result<()> {
+        let pipeline = Rc::try_unwrap(self.pipeline)
+            .map_err(|_| anyhow!("The pipeline is still in use."))?
+            .into_inner();
+        drop(pipeline.finish().context("Failed to finish the archive")?);
+        if !overwrite && output_path.symlink_metadata().is_ok() {
+            warn!("{} already exists.", output_path.display());
+            return Err(anyhow!(
+                "{} already exists; the complete archive is left at {}.",
+                output_path.display(),
+                partial_path(output_path).display()
+            ));
+        }
+        let partial_path = partial_path(output_path);
+        fs::rename(&partial_path, output_path).with_context(|| {
+            format!(
+                "Failed to move {} to {}",
+                partial_path.display(),
+                output_path.display()
+            )
+        })?;
+        sync_parent(output_path);
+        match fs::remove_file(&self.checkpoint_path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Could not remove {}: {e}", self.checkpoint_path.display()),
+        }
+        debug!("Moved the complete archive to {}.", output_path.display());
+        Ok(())
+    }
+
+    /// Reads the file `file`, the entry `index` of the walk at `rel_path`, checkpointing
+    /// at its block boundaries; from `saved` on if the earlier run stopped in it.
+    pub(crate) fn reader<'a>(
+        &'a mut self,
+        file: File,
+        index: u64,
+        rel_path: &Path,
+        metadata: &fs::Metadata,
+        checksums: &'a Checksums,
+        saved: Option<SavedFile>,
+    ) -> Result<CheckpointReader<'a>> {
+        let (hasher, offset) = match saved {
+            Some(saved) => {
+                let stack = saved
+                    .stack
+                    .iter()
+                    .map(|cv| {
+                        blake3::Hash::from_hex(cv)
+                            .map(|hash| *hash.as_bytes())
+                            .map_err(|_| anyhow!("The checkpoint holds an invalid hash."))
+                    })
+                    .collect::<Result<_>>()?;
+                (
+                    BlockHasher::resume(self.block_len, stack, saved.blocks),
+                    saved.offset,
+                )
+            }
+            None => (BlockHasher::new(self.block_len), 0),
+        };
+        Ok(CheckpointReader {
+            block_len: self.block_len,
+            run: self,
+            file,
+            hasher,
+            index,
+            rel_path: rel_path.to_path_buf(),
+            size: metadata.len(),
+            mtime: mtime(metadata),
+            offset,
+            checksums,
+        })
+    }
+
+    /// Pads the entry of a file of `size` bytes to a whole tar block.
+    pub(crate) fn pad(&self, size: u64) -> io::Result<()> {
+        let padding = (512 - size % 512) % 512;
+        self.writer().write_all(&[0; 512][..padding as usize])
+    }
+}
+
+/// Reads a file into a resumable run, hashing it, and takes a checkpoint at the first block
+/// boundary after each interval.
+///
+/// Everything the reader returned has been written when it is read again, as tar copies
+/// a read at a time, so a checkpoint at the start of a read covers all of it.
+pub(crate) struct CheckpointReader<'a> {
+    run: &'a mut Run,
+    file: File,
+    hasher: BlockHasher,
+    index: u64,
+    rel_path: PathBuf,
+    size: u64,
+    mtime: u128,
+    block_len: u64,
+    offset: u64,
+    checksums: &'a Checksums,
+}
+
+impl CheckpointReader<'_> {
+    pub(crate) fn hash(&self) -> blake3::Hash {
+        self.hasher.finalize()
+    }
+}
+
+impl Read for CheckpointReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // The entry's header promised exactly `size` bytes.
+        if self.offset >= self.size {
+            return Ok(0);
+        }
+        let into_block = self.offset % self.block_len;
+        if into_block == 0 && self.hasher.current_len == self.block_len && self.run.due() {
+            let (stack, blocks) = self.hasher.state();
+            let saved = SavedFile {
+                path: self.rel_path.clone(),
+                size: self.size,
+                mtime: self.mtime,
+                offset: self.offset,
+                stack: stack
+                    .iter()
+                    .map(|cv| blake3::Hash::from(*cv).to_hex().to_string())
+                    .collect(),
+                blocks,
+            };
+            self.run.save(self.index, self.checksums, Some(saved))?;
+        }
+        let limit = (self.block_len - into_block).min(self.size - self.offset);
+        let len = buf.len().min(limit as usize);
+        let n = self.file.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} shrank while it was archived", self.rel_path.display()),
+            ));
+        }
+        self.hasher.update(&buf[..n]);
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+/// Fails unless `checkpoint` was taken by a run of the same input with `settings`.
+fn check(
+    checkpoint: &Checkpoint,
+    checkpoint_path: &Path,
+    input: &Path,
+    settings: &Settings,
+    encryption: &Encryption,
+) -> Result<()> {
+    let params = settings.header.params;
+    let codec = settings.header.codec.unwrap_or_default();
+    if checkpoint.version != CHECKPOINT_VERSION {
+        warn!("Unknown checkpoint version {}.", checkpoint.version);
+        return Err(anyhow!(
+            "{} was written by another version of sage; delete it to start over.",
+            checkpoint_path.display()
+        ));
+    }
+    if checkpoint.input != input {
+        warn!("The checkpoint is of another input.");
+        return Err(anyhow!(
+            "{} was taken protecting {}, not {}.",
+            checkpoint_path.display(),
+            checkpoint.input.display(),
+            input.display()
+        ));
+    }
+    let same = checkpoint.codec == codec.to_string()
+        && checkpoint.level == settings.level
+        && checkpoint.data_shards == params.data_shards
+        && checkpoint.parity_shards == params.parity_shards
+        && checkpoint.shard_size == params.shard_size
+        && checkpoint.stripe_groups == settings.header.stripe_groups
+        && checkpoint.cipher.is_some() == matches!(encryption, Encryption::Recipients(_));
+    if !same {
+        warn!("The checkpoint was taken with other options.");
+        return Err(anyhow!(
+            "{} was taken with other compression, encryption or error correction options; \
+             resume with the same options, or delete it to start over.",
+            checkpoint_path.display()
+        ));
+    }
+    Ok(())
+}
+
+fn prev_power_of_two(n: u64) -> u64 {
+    match n {
+        0 => 1,
+        n => 1 << (63 - n.leading_zeros()),
+    }
+}
+
+fn decode(data: &str) -> Result<Vec<u8>> {
+    BASE64
+        .decode(data)
+        .map_err(|_| anyhow!("The checkpoint is damaged."))
+}
+
+/// Makes a rename into the parent directory of `path` durable, where that can be done.
+fn sync_parent(path: &Path) {
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        let parent = if parent.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            parent
+        };
+        if let Err(e) = File::open(parent).and_then(|dir| dir.sync_all()) {
+            debug!("Could not sync {}: {e}", parent.display());
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_hashes_match_blake3() {
+        let block_len = 4096;
+        for len in [
+            0,
+            1,
+            1024,
+            4095,
+            4096,
+            4097,
+            3 * 4096,
+            4 * 4096 + 5,
+            9 * 4096,
+        ] {
+            let data: Vec<u8> = (0..len).map(|i| (i * 31 % 256) as u8).collect();
+            let mut hasher = BlockHasher::new(block_len);
+            for piece in data.chunks(1000) {
+                hasher.update(piece);
+            }
+            assert_eq!(hasher.finalize(), blake3::hash(&data), "{len} bytes");
+
+            // Saved and resumed at every block boundary with more data after it.
+            let mut hasher = BlockHasher::new(block_len);
+            for block in data.chunks(block_len as usize) {
+                hasher.update(block);
+                if hasher.current_len == block_len && ((hasher.blocks + 1) * block_len) < len as u64
+                {
+                    let (stack, blocks) = hasher.state();
+                    hasher = BlockHasher::resume(block_len, stack, blocks);
+                }
+            }
+            assert_eq!(
+                hasher.finalize(),
+                blake3::hash(&data),
+                "{len} bytes, resumed"
+            );
+        }
+    }
+
+    #[test]
+    fn encrypts_a_payload_age_decrypts() {
+        let identity = age::x25519::Identity::generate();
+        let recipient = identity.to_public();
+        let data: Vec<u8> = (0..3 * CHUNK_LEN + 17).map(|i| (i % 251) as u8).collect();
+        for len in [0, 5, CHUNK_LEN, data.len()] {
+            let file_key = FileKey::init_with_mut(|key| rand::rngs::OsRng.fill_bytes(key));
+            let stanzas = rekey::wrap_file_key(
+                &file_key,
+                std::iter::once(&recipient as &dyn age::Recipient),
+            )
+            .unwrap();
+            let mut file = rekey::encode_header(&stanzas, &file_key);
+            let nonce: [u8; PAYLOAD_NONCE_LEN] = rand::random();
+            file.extend_from_slice(&nonce);
+            let key = age_core::primitives::hkdf(&nonce, b"payload", file_key.expose_secret());
+
+            // Stopped and resumed half-way, as a checkpoint does.
+            let (first, second) = data[..len].split_at(len / 2);
+            let mut writer = StreamWriter::new(file, key);
+            writer.write_all(first).unwrap();
+            let state = writer.state();
+            let mut writer = StreamWriter::resume(writer.inner, state);
+            writer.write_all(second).unwrap();
+            let file = writer.finish().unwrap();
+
+            let decryptor = age::Decryptor::new(&file[..]).unwrap();
+            let mut reader = decryptor
+                .decrypt(std::iter::once(&identity as &dyn age::Identity))
+                .unwrap();
+            let mut plain = Vec::new();
+            reader.read_to_end(&mut plain).unwrap();
+            assert_eq!(plain, &data[..len], "{len} bytes");
+        }
+    }
+}
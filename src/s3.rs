@@ -0,0 +1,576 @@
+//! Streaming archives to and from S3-compatible object storage.
+//!
+//! An output of the form `s3://bucket/path/archive.sage` is uploaded with a multipart upload
+//! while it is written, so the archive never has to fit on local disk, and an input URL is
+//! read with a streaming `GET` that resumes with a `Range` request if the connection drops,
+//! and seeks like an [HTTP input](crate::http). Requests are signed with AWS Signature Version 4 using
+//! the usual environment variables (see [`S3Config::from_env`]), which also work for MinIO,
+//! Backblaze B2 and other S3-compatible services when `AWS_ENDPOINT_URL` points at them.
+
+use crate::http::RangedReader;
+use hmac::{Hmac, Mac};
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+use std::fmt::Write as _;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use ureq::http::{Method, Request, Response};
+use ureq::{Agent, Body};
+
+/// Prefix of paths that name an S3 object.
+pub const S3_SCHEME: &str = "s3://";
+
+/// Size of the first parts of an upload. S3 allows at most [`MAX_PARTS`] parts, so every
+/// [`PARTS_PER_STEP`] parts the part size grows by this much, which allows for archives of
+/// up to about 880 GB.
+const PART_SIZE_STEP: usize = 16 * 1024 * 1024;
+const PARTS_PER_STEP: u32 = 1000;
+const MAX_PARTS: u32 = 10_000;
+/// How often a failed request is tried before giving up.
+const ATTEMPTS: u32 = 4;
+const DEFAULT_REGION: &str = "us-east-1";
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// Returns true if `path` names an S3 object rather than a local file.
+pub fn is_s3_url(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|path| path.starts_with(S3_SCHEME))
+}
+
+/// A bucket and object key, parsed from an `s3://bucket/key` URL.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct S3Location {
+    pub bucket: String,
+    pub key: String,
+}
+
+impl S3Location {
+    pub fn parse(url: &str) -> io::Result<Self> {
+        let (bucket, key) = url
+            .strip_prefix(S3_SCHEME)
+            .and_then(|rest| rest.split_once('/'))
+            .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+            .ok_or_else(|| invalid_input(&format!("{url} is not an s3://bucket/key URL")))?;
+        Ok(Self {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for S3Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{S3_SCHEME}{}/{}", self.bucket, self.key)
+    }
+}
+
+/// Credentials and endpoint for S3 requests.
+#[derive(Clone, Debug)]
+pub struct S3Config {
+    pub access_key: String,
+    pub secret_key: String,
+    pub session_token: Option<String>,
+    pub region: String,
+    /// The service URL, such as `http://localhost:9000` for MinIO, or `None` for AWS.
+    pub endpoint: Option<String>,
+}
+
+impl S3Config {
+    /// Reads the configuration from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`,
+    /// `AWS_SESSION_TOKEN`, `AWS_REGION` (or `AWS_DEFAULT_REGION`) and `AWS_ENDPOINT_URL_S3`
+    /// (or `AWS_ENDPOINT_URL`).
+    pub fn from_env() -> io::Result<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let (Some(access_key), Some(secret_key)) =
+            (var("AWS_ACCESS_KEY_ID"), var("AWS_SECRET_ACCESS_KEY"))
+        else {
+            return Err(invalid_input(
+                "S3 credentials are missing; set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY",
+            ));
+        };
+        Ok(Self {
+            access_key,
+            secret_key,
+            session_token: var("AWS_SESSION_TOKEN"),
+            region: var("AWS_REGION")
+                .or_else(|| var("AWS_DEFAULT_REGION"))
+                .unwrap_or_else(|| DEFAULT_REGION.to_string()),
+            endpoint: var("AWS_ENDPOINT_URL_S3").or_else(|| var("AWS_ENDPOINT_URL")),
+        })
+    }
+}
+
+/// Signs and sends requests for one object.
+struct Client {
+    agent: Agent,
+    config: S3Config,
+    location: S3Location,
+}
+
+impl Client {
+    fn new(config: S3Config, location: S3Location) -> Self {
+        let agent = Agent::config_builder()
+            .http_status_as_error(false)
+            .build()
+            .into();
+        Self {
+            agent,
+            config,
+            location,
+        }
+    }
+
+    /// The scheme and host, and the path of the object, with the bucket in the host for AWS
+    /// and in the path for custom endpoints.
+    fn object_url(&self) -> (String, String, String) {
+        let key = uri_encode(&self.location.key, false);
+        let bucket = &self.location.bucket;
+        match &self.config.endpoint {
+            Some(endpoint) => {
+                let (scheme, host) = endpoint
+                    .trim_end_matches('/')
+                    .split_once("://")
+                    .unwrap_or(("https", endpoint));
+                (
+                    scheme.to_string(),
+                    host.to_string(),
+                    format!("/{bucket}/{key}"),
+                )
+            }
+            None if !bucket.contains('.') => (
+                "https".to_string(),
+                format!("{bucket}.s3.{}.amazonaws.com", self.config.region),
+                format!("/{key}"),
+            ),
+            None => (
+                "https".to_string(),
+                format!("s3.{}.amazonaws.com", self.config.region),
+                format!("/{bucket}/{key}"),
+            ),
+        }
+    }
+
+    /// Sends a signed request for the object with the `query` parameters and `body`.
+    fn send(
+        &self,
+        method: Method,
+        query: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<Response<Body>> {
+        self.send_from(method, query, body, 0)
+    }
+
+    /// Like [`Client::send`], asking only for the bytes from `offset` on if it is not zero.
+    fn send_from(
+        &self,
+        method: Method,
+        query: &[(&str, &str)],
+        body: &[u8],
+        offset: u64,
+    ) -> io::Result<Response<Body>> {
+        let (scheme, host, path) = self.object_url();
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(k, v)| (uri_encode(k, true), uri_encode(v, true)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+        let now = chrono::DateTime::from_timestamp(now, 0).unwrap_or_default();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = &amz_date[..8];
+        let payload_hash = if body.is_empty() {
+            EMPTY_SHA256.to_string()
+        } else {
+            hex(&Sha256::digest(body))
+        };
+
+        let mut headers = vec![
+            ("host", host.clone()),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.config.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+            .collect();
+        let canonical_request = format!(
+            "{method}\n{path}\n{query}\n{canonical_headers}\n{signed_headers}\n{payload_hash}"
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", self.config.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = [date, self.config.region.as_str(), "s3", "aws4_request"]
+            .iter()
+            .fold(
+                format!("AWS4{}", self.config.secret_key).into_bytes(),
+                |key, part| hmac(&key, part.as_bytes()),
+            );
+        let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+            self.config.access_key
+        );
+
+        let url = match query.is_empty() {
+            true => format!("{scheme}://{host}{path}"),
+            false => format!("{scheme}://{host}{path}?{query}"),
+        };
+        let mut request = Request::builder().method(method).uri(url);
+        for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+            request = request.header(name, value);
+        }
+        if offset > 0 {
+            // Only the signed headers are covered by the signature, and the range is not one.
+            request = request.header("range", format!("bytes={offset}-"));
+        }
+        let request = request
+            .header("authorization", authorization)
+            .body(body.to_vec())
+            .map_err(io::Error::other)?;
+        self.agent.run(request).map_err(io::Error::other)
+    }
+
+    /// Sends a request, retrying on network errors and server errors, and fails on any
+    /// status other than success.
+    fn send_checked(
+        &self,
+        method: Method,
+        query: &[(&str, &str)],
+        body: &[u8],
+        action: &str,
+    ) -> io::Result<Response<Body>> {
+        self.send_checked_from(method, query, body, 0, action)
+    }
+
+    /// Like [`Client::send_checked`], asking only for the bytes from `offset` on.
+    fn send_checked_from(
+        &self,
+        method: Method,
+        query: &[(&str, &str)],
+        body: &[u8],
+        offset: u64,
+        action: &str,
+    ) -> io::Result<Response<Body>> {
+        let mut attempt = 1;
+        loop {
+            let result = self.send_from(method.clone(), query, body, offset);
+            let retry = match &result {
+                Ok(response) => response.status().is_server_error(),
+                Err(_) => true,
+            };
+            if !retry || attempt == ATTEMPTS {
+                let mut response = result?;
+                if !response.status().is_success() {
+                    return Err(status_error(&mut response, action, &self.location));
+                }
+                return Ok(response);
+            }
+            match result {
+                Ok(response) => warn!("{action} failed with {}; retrying.", response.status()),
+                Err(e) => warn!("{action} failed: {e}; retrying."),
+            }
+            thread::sleep(Duration::from_secs(1 << attempt));
+            attempt += 1;
+        }
+    }
+
+    /// Returns whether the object exists, or `None` if the service would not say.
+    fn exists(&self) -> io::Result<Option<bool>> {
+        let response = self.send(Method::HEAD, &[], &[])?;
+        Ok(match response.status().as_u16() {
+            200..=299 => Some(true),
+            404 => Some(false),
+            status => {
+                debug!("Existence check of {} returned {status}.", self.location);
+                None
+            }
+        })
+    }
+}
+
+/// Uploads everything written to it to an S3 object, part by part.
+///
+/// The object only appears once [`S3Writer::commit`] completes the upload. Dropping the
+/// writer without committing it, for example on an error, aborts the upload so no parts are
+/// left behind.
+pub struct S3Writer {
+    client: Client,
+    upload_id: String,
+    part: Vec<u8>,
+    etags: Vec<String>,
+    uploaded: u64,
+    finished: bool,
+}
+
+impl S3Writer {
+    /// Starts a multipart upload to the `s3://` URL `url`.
+    ///
+    /// Fails with [`io::ErrorKind::AlreadyExists`] if the object exists, unless `overwrite`
+    /// is set.
+    pub fn create(url: &str, overwrite: bool) -> io::Result<Self> {
+        let client = Client::new(S3Config::from_env()?, S3Location::parse(url)?);
+        if !overwrite && client.exists()? == Some(true) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "object already exists",
+            ));
+        }
+        debug!("Starting multipart upload to {}", client.location);
+        let mut response =
+            client.send_checked(Method::POST, &[("uploads", "")], &[], "Starting upload")?;
+        let body = read_body(&mut response)?;
+        let upload_id = xml_element(&body, "UploadId")
+            .ok_or_else(|| io::Error::other("upload response has no UploadId"))?
+            .to_string();
+        debug!("Upload id: {upload_id}");
+        Ok(Self {
+            client,
+            upload_id,
+            part: Vec::with_capacity(PART_SIZE_STEP),
+            etags: Vec::new(),
+            uploaded: 0,
+            finished: false,
+        })
+    }
+
+    /// The size of the part being filled, which grows with the number of parts uploaded.
+    fn part_size(&self) -> usize {
+        PART_SIZE_STEP * (1 + self.etags.len() / PARTS_PER_STEP as usize)
+    }
+
+    fn upload_part(&mut self) -> io::Result<()> {
+        let number = self.etags.len() as u32 + 1;
+        if number > MAX_PARTS {
+            return Err(io::Error::other("archive is too large for an S3 upload"));
+        }
+        debug!("Uploading part {number} ({} bytes)", self.part.len());
+        let number_str = number.to_string();
+        let response = self.client.send_checked(
+            Method::PUT,
+            &[("partNumber", &number_str), ("uploadId", &self.upload_id)],
+            &self.part,
+            &format!("Uploading part {number}"),
+        )?;
+        let etag = response
+            .headers()
+            .get("etag")
+            .and_then(|etag| etag.to_str().ok())
+            .ok_or_else(|| io::Error::other(format!("part {number} response has no ETag")))?
+            .to_string();
+        self.etags.push(etag);
+        self.uploaded += self.part.len() as u64;
+        self.part.clear();
+        Ok(())
+    }
+
+    /// Uploads the last part and completes the upload, making the object appear.
+    pub fn commit(mut self) -> io::Result<()> {
+        if !self.part.is_empty() || self.etags.is_empty() {
+            self.upload_part()?;
+        }
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for (i, etag) in self.etags.iter().enumerate() {
+            let _ = write!(
+                body,
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                i + 1,
+                xml_escape(etag)
+            );
+        }
+        body.push_str("</CompleteMultipartUpload>");
+        let mut response = self.client.send_checked(
+            Method::POST,
+            &[("uploadId", &self.upload_id)],
+            body.as_bytes(),
+            "Completing upload",
+        )?;
+        // S3 can report a failure after the status line, in the body of a 200 response.
+        let reply = read_body(&mut response)?;
+        if let Some(code) = xml_element(&reply, "Code") {
+            let message = xml_element(&reply, "Message").unwrap_or_default();
+            return Err(io::Error::other(format!(
+                "Completing upload of {} failed: {code}: {message}",
+                self.client.location
+            )));
+        }
+        self.finished = true;
+        info!(
+            "Uploaded {} bytes in {} parts to {}",
+            self.uploaded,
+            self.etags.len(),
+            self.client.location
+        );
+        Ok(())
+    }
+}
+
+impl Write for S3Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.part_size() - self.part.len());
+        self.part.extend_from_slice(&buf[..n]);
+        if self.part.len() == self.part_size() {
+            self.upload_part()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for S3Writer {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        debug!("Aborting upload to {}", self.client.location);
+        match self
+            .client
+            .send(Method::DELETE, &[("uploadId", &self.upload_id)], &[])
+        {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => warn!(
+                "Could not abort upload {} to {} ({}); its parts may be billed until removed.",
+                self.upload_id,
+                self.client.location,
+                response.status()
+            ),
+            Err(e) => warn!(
+                "Could not abort upload {} to {}: {e}; its parts may be billed until removed.",
+                self.upload_id, self.client.location
+            ),
+        }
+    }
+}
+
+/// Reads an S3 object as a stream, resuming the download if the connection drops.
+pub struct S3Reader(RangedReader);
+
+impl S3Reader {
+    /// Starts downloading the object at the `s3://` URL `url`.
+    pub fn open(url: &str) -> io::Result<Self> {
+        let client = Client::new(S3Config::from_env()?, S3Location::parse(url)?);
+        debug!("Downloading {}", client.location);
+        let name = client.location.to_string();
+        let fetch = Box::new(move |offset| {
+            client.send_checked_from(Method::GET, &[], &[], offset, "Downloading")
+        });
+        Ok(Self(RangedReader::open(name, fetch)?))
+    }
+
+    /// The size of the object, if the service reported it.
+    pub fn size(&self) -> Option<u64> {
+        self.0.size()
+    }
+
+    /// Whether the service reported the size of the object and accepts ranges, so that
+    /// seeks do not download the object again from its start.
+    pub fn is_seekable(&self) -> bool {
+        self.0.is_seekable()
+    }
+}
+
+impl Read for S3Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Seek for S3Reader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+fn read_body(response: &mut Response<Body>) -> io::Result<String> {
+    response
+        .body_mut()
+        .read_to_string()
+        .map_err(io::Error::other)
+}
+
+/// Builds an error from a failed response, including the S3 error code if there is one.
+fn status_error(response: &mut Response<Body>, action: &str, location: &S3Location) -> io::Error {
+    let status = response.status();
+    let body = read_body(response).unwrap_or_default();
+    let detail = match (xml_element(&body, "Code"), xml_element(&body, "Message")) {
+        (Some(code), Some(message)) => format!("{code}: {message}"),
+        (Some(code), None) => code.to_string(),
+        _ => status.to_string(),
+    };
+    let kind = match status.as_u16() {
+        404 => io::ErrorKind::NotFound,
+        401 | 403 => io::ErrorKind::PermissionDenied,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("{action} {location} failed: {detail}"))
+}
+
+/// Returns the text of the first `<name>` element in `xml`.
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{name}>"))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{name}>"))?;
+    Some(&xml[start..end])
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Percent-encodes `text` as SigV4 requires, keeping `/` unless `encode_slash`.
+fn uri_encode(text: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => {
+                let _ = write!(encoded, "%{byte:02X}");
+            }
+        }
+    }
+    encoded
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_string())
+}
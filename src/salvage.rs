@@ -0,0 +1,484 @@
+//! Recovering what is left of an archive that is damaged beyond repair, for `--salvage`.
+//!
+//! Error correction rebuilds damaged shards as long as each group has enough of them left.
+//! Once a group has lost more, its data is gone, and so is every age chunk it held: each
+//! fails authentication. A salvage reads around them instead of failing.
+//!
+//! Where the tar stream is not compressed, or compressed in [seekable frames](crate::seekable),
+//! what comes after a lost chunk can still be read: a [`SalvageReader`] reads lost chunks,
+//! or frames, as zeros of the same length, so every later entry stays where the tar headers
+//! say it is. Files that lost part of their contents are extracted with zeros in their
+//! place, and after a header that was lost, extraction carries on at the next header found
+//! in the stream. Other archives cannot be read past the first lost chunk, so extraction
+//! stops there, keeping what came before it.
+//!
+//! The [`Salvage`] report then lists exactly what was lost, from the list of checksums at
+//! the end of the archive, which names every file.
+
+use crate::cancel;
+use crate::integrity::AGE_CHUNK_SIZE;
+use crate::progress::{Progress, warn_progress};
+use crate::seekable::ReadSeek;
+use log::{debug, info, warn};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+/// The size of a tar block; headers start at multiples of it.
+const TAR_BLOCK: u64 = 512;
+
+/// The ranges of a stream that could not be read, shared between the readers that find them
+/// and the extraction that reports them.
+#[derive(Clone, Debug, Default)]
+pub struct Losses(Arc<Mutex<Vec<Range<u64>>>>);
+
+impl Losses {
+    fn ranges(&self) -> std::sync::MutexGuard<'_, Vec<Range<u64>>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Records that `range` was lost, merging it with the ranges it touches.
+    pub fn record(&self, range: Range<u64>) {
+        let mut ranges = self.ranges();
+        ranges.push(range);
+        ranges.sort_by_key(|range| range.start);
+        let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+        for range in ranges.drain(..) {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        *ranges = merged;
+    }
+
+    /// The lost range that holds `position`, if any.
+    pub fn containing(&self, position: u64) -> Option<Range<u64>> {
+        self.ranges()
+            .iter()
+            .find(|range| range.contains(&position))
+            .cloned()
+    }
+
+    /// Whether any of `range` was lost.
+    pub fn overlaps(&self, range: &Range<u64>) -> bool {
+        self.ranges()
+            .iter()
+            .any(|lost| lost.start < range.end && range.start < lost.end)
+    }
+
+    /// Whether anything before `position` was lost.
+    pub fn any_before(&self, position: u64) -> bool {
+        self.ranges()
+            .first()
+            .is_some_and(|lost| lost.start < position)
+    }
+
+    /// The number of bytes lost.
+    pub fn total(&self) -> u64 {
+        self.ranges()
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum()
+    }
+}
+
+/// Opens the decrypted payload again, from the start.
+pub type Reopen<'a> = Box<dyn FnMut() -> io::Result<Box<dyn ReadSeek + 'a>> + 'a>;
+
+/// Reads a decrypted payload, reading the age chunks that fail to decrypt as zeros.
+///
+/// age's reader cannot go on once a chunk failed, so the payload is opened again after
+/// every lost chunk and read on from the chunk after it. A payload that ends early loses
+/// everything after that point.
+pub struct SalvageReader<'a> {
+    open: Reopen<'a>,
+    inner: Option<Box<dyn ReadSeek + 'a>>,
+    /// Whether `inner` is at `position`.
+    in_place: bool,
+    len: u64,
+    position: u64,
+    losses: Losses,
+}
+
+impl<'a> SalvageReader<'a> {
+    /// Reads the payload `open` opens, recording the chunks lost in `losses`.
+    pub fn new(mut open: Reopen<'a>, losses: Losses) -> io::Result<Self> {
+        let mut inner = open()?;
+        let len = inner.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            open,
+            inner: Some(inner),
+            in_place: false,
+            len,
+            position: 0,
+            losses,
+        })
+    }
+
+    /// Reads from the payload, opening it again if it failed before.
+    fn read_inner(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inner = match &mut self.inner {
+            Some(inner) => inner,
+            None => {
+                debug!("Opening the payload again at byte {}.", self.position);
+                self.in_place = false;
+                self.inner.insert((self.open)()?)
+            }
+        };
+        let attempt = (|| {
+            if !self.in_place {
+                inner.seek(SeekFrom::Start(self.position))?;
+                self.in_place = true;
+            }
+            inner.read(buf)
+        })();
+        attempt.or_else(|e| {
+            if cancel::is_requested() {
+                return Err(e);
+            }
+            self.inner = None;
+            let lost = if e.kind() == io::ErrorKind::UnexpectedEof {
+                self.position..self.len
+            } else {
+                let start = self.position / AGE_CHUNK_SIZE * AGE_CHUNK_SIZE;
+                start..(start + AGE_CHUNK_SIZE).min(self.len)
+            };
+            warn!(
+                "Payload bytes {}..{} cannot be read; salvaging past them: {e}",
+                lost.start, lost.end
+            );
+            self.losses.record(lost);
+            Ok(0)
+        })
+    }
+}
+
+impl Read for SalvageReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.position >= self.len || buf.is_empty() {
+                return Ok(0);
+            }
+            if let Some(lost) = self.losses.containing(self.position) {
+                let n = buf
+                    .len()
+                    .min((lost.end.min(self.len) - self.position) as usize);
+                buf[..n].fill(0);
+                self.position += n as u64;
+                self.in_place = false;
+                return Ok(n);
+            }
+            let n = self.read_inner(buf)?;
+            if n > 0 {
+                self.position += n as u64;
+                return Ok(n);
+            }
+            if self.inner.is_some() {
+                // The payload ended before its length.
+                self.losses.record(self.position..self.len);
+            }
+        }
+    }
+}
+
+impl Seek for SalvageReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        if position != self.position {
+            self.position = position;
+            self.in_place = false;
+        }
+        Ok(self.position)
+    }
+}
+
+/// Reads `inner` from `base` on, as if it started there, as tar expects of the reader an
+/// archive is read from.
+pub struct Rebased<R> {
+    inner: R,
+    base: u64,
+}
+
+impl<R: Seek> Rebased<R> {
+    /// Moves `inner` to `base` and reads on from there.
+    pub fn new(mut inner: R, base: u64) -> io::Result<Self> {
+        inner.seek(SeekFrom::Start(base))?;
+        Ok(Self { inner, base })
+    }
+}
+
+impl<R: Read> Read for Rebased<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Seek> Seek for Rebased<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => SeekFrom::Start(self.base + offset),
+            pos => pos,
+        };
+        Ok(self.inner.seek(pos)?.saturating_sub(self.base))
+    }
+}
+
+/// The offset of the first intact tar header at or after `from` in the tar stream `reader`,
+/// or `None` if there is none before its end.
+pub fn find_header<R: Read + Seek>(reader: &mut R, from: u64) -> io::Result<Option<u64>> {
+    let mut offset = from.next_multiple_of(TAR_BLOCK);
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut block = [0u8; TAR_BLOCK as usize];
+    loop {
+        match reader.read_exact(&mut block) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        if is_header(&block) {
+            debug!("Found a tar header at byte {offset}.");
+            return Ok(Some(offset));
+        }
+        offset += TAR_BLOCK;
+    }
+}
+
+/// Whether `block` is a ustar or GNU tar header with a matching checksum.
+fn is_header(block: &[u8; TAR_BLOCK as usize]) -> bool {
+    let header = tar::Header::from_byte_slice(block);
+    if header.as_ustar().is_none() && header.as_gnu().is_none() {
+        return false;
+    }
+    // The checksum is taken with its own field counted as spaces.
+    let sum: u32 = block
+        .iter()
+        .enumerate()
+        .map(|(i, &byte)| if (148..156).contains(&i) { b' ' } else { byte } as u32)
+        .sum();
+    header.cksum().is_ok_and(|cksum| cksum == sum)
+}
+
+/// What a salvage could not recover.
+///
+/// Serialized with `lost_count` and `lost_after` next to its fields, as
+/// [`Salvage::lost_count`] and [`Salvage::lost_after`] give them.
+#[derive(Clone, Debug, Default)]
+pub struct Salvage {
+    /// Files whose entries could not be read at all.
+    pub lost: Vec<String>,
+    /// Files that were cut short where the archive stopped being readable.
+    pub truncated: Vec<String>,
+    /// Files extracted with zeros in place of the parts that were lost.
+    pub damaged: Vec<String>,
+    /// Bytes of the tar stream that were lost.
+    pub lost_bytes: u64,
+    /// Whether the archive could not be read past the first damage, so that everything
+    /// after it was lost.
+    pub stopped: bool,
+    /// Whether files were lost without being listed, because the list of checksums that
+    /// names them was lost too.
+    pub unlisted: bool,
+}
+
+impl Salvage {
+    /// Whether nothing was lost.
+    pub fn is_complete(&self) -> bool {
+        self.lost.is_empty()
+            && self.truncated.is_empty()
+            && self.damaged.is_empty()
+            && self.lost_bytes == 0
+            && !self.stopped
+    }
+
+    /// How many files were lost, or `None` if that is unknown because the archive could
+    /// not be read past the damage or the list of checksums naming its files was lost.
+    /// [`Salvage::lost`] then holds only the lost files known by name.
+    pub fn lost_count(&self) -> Option<usize> {
+        (!self.stopped && !self.unlisted).then_some(self.lost.len())
+    }
+
+    /// The entry after which an unknown number of files were lost: the one cut short where
+    /// the archive stopped being readable.
+    pub fn lost_after(&self) -> Option<&str> {
+        self.stopped
+            .then(|| self.truncated.last())
+            .flatten()
+            .map(String::as_str)
+    }
+
+    /// Logs every file that was lost, cut short or damaged, and passes each warning on to
+    /// `progress`.
+    pub fn report(&self, progress: Option<&Arc<dyn Progress>>) {
+        if self.is_complete() {
+            info!("Salvage complete: nothing was lost.");
+            return;
+        }
+        for path in &self.lost {
+            warn_progress!(progress, "Lost: {path}");
+        }
+        for path in &self.truncated {
+            warn_progress!(progress, "Truncated: {path}");
+        }
+        for path in &self.damaged {
+            warn_progress!(
+                progress,
+                "Damaged, with zeros in place of the lost parts: {path}"
+            );
+        }
+        if self.unlisted {
+            warn_progress!(
+                progress,
+                "Which files the lost parts of the archive held is unknown, as the list of \
+                 checksums that names them was lost too."
+            );
+        }
+        let lost = match (self.lost_count(), self.lost_after()) {
+            (Some(count), _) => format!("{count} files lost"),
+            (None, Some(path)) => format!("an unknown number of files after {path} lost"),
+            (None, None) => "an unknown number of files lost".to_string(),
+        };
+        warn_progress!(
+            progress,
+            "Salvaged what was left of the archive: {lost}, {} truncated, {} damaged.",
+            self.truncated.len(),
+            self.damaged.len()
+        );
+    }
+}
+
+impl Serialize for Salvage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Salvage", 8)?;
+        state.serialize_field("lost", &self.lost)?;
+        state.serialize_field("lost_count", &self.lost_count())?;
+        state.serialize_field("lost_after", &self.lost_after())?;
+        state.serialize_field("truncated", &self.truncated)?;
+        state.serialize_field("damaged", &self.damaged)?;
+        state.serialize_field("lost_bytes", &self.lost_bytes)?;
+        state.serialize_field("stopped", &self.stopped)?;
+        state.serialize_field("unlisted", &self.unlisted)?;
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn reads_lost_chunks_as_zeros() {
+        let payload: Vec<u8> = (0..4 * AGE_CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+        // The second chunk fails every time it is read.
+        struct Failing(Cursor<Vec<u8>>);
+        impl Read for Failing {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let position = self.0.position();
+                if (AGE_CHUNK_SIZE..2 * AGE_CHUNK_SIZE).contains(&position) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "bad tag"));
+                }
+                let end = if position < AGE_CHUNK_SIZE {
+                    AGE_CHUNK_SIZE
+                } else {
+                    u64::MAX
+                };
+                let len = buf
+                    .len()
+                    .min((end - position).min(usize::MAX as u64) as usize);
+                self.0.read(&mut buf[..len])
+            }
+        }
+        impl Seek for Failing {
+            fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+                self.0.seek(pos)
+            }
+        }
+        let opened = std::cell::Cell::new(0);
+        let source = payload.clone();
+        let losses = Losses::default();
+        let mut reader = SalvageReader::new(
+            Box::new(|| {
+                opened.set(opened.get() + 1);
+                Ok(Box::new(Failing(Cursor::new(source.clone()))) as Box<dyn ReadSeek>)
+            }),
+            losses.clone(),
+        )
+        .unwrap();
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        drop(reader);
+
+        let chunk = AGE_CHUNK_SIZE as usize;
+        assert_eq!(read.len(), payload.len());
+        assert_eq!(read[..chunk], payload[..chunk]);
+        assert!(read[chunk..2 * chunk].iter().all(|&b| b == 0));
+        assert_eq!(read[2 * chunk..], payload[2 * chunk..]);
+        assert_eq!(losses.total(), AGE_CHUNK_SIZE);
+        assert_eq!(opened.get(), 2);
+    }
+
+    #[test]
+    fn finds_the_next_intact_header() {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, contents) in [("a.txt", vec![1u8; 1500]), ("b.txt", vec![2u8; 10])] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            builder
+                .append_data(&mut header, name, contents.as_slice())
+                .unwrap();
+        }
+        let mut tar = builder.into_inner().unwrap();
+        // Lose the header of a.txt.
+        tar[..512].fill(0);
+        let mut reader = Cursor::new(tar);
+        assert_eq!(find_header(&mut reader, 0).unwrap(), Some(2048));
+        assert_eq!(find_header(&mut reader, 2049).unwrap(), None);
+
+        let losses = Losses::default();
+        losses.record(10..20);
+        losses.record(0..5);
+        losses.record(5..10);
+        assert_eq!(losses.containing(12), Some(0..20));
+        assert!(losses.overlaps(&(19..30)));
+        assert!(!losses.overlaps(&(20..30)));
+        assert_eq!(losses.total(), 20);
+    }
+
+    #[test]
+    fn reports_an_unknown_number_lost_when_salvage_stopped() {
+        let salvage = Salvage {
+            truncated: vec!["c.txt".to_string()],
+            stopped: true,
+            unlisted: true,
+            ..Salvage::default()
+        };
+        assert_eq!(salvage.lost_count(), None);
+        assert_eq!(salvage.lost_after(), Some("c.txt"));
+        let json = serde_json::to_value(&salvage).unwrap();
+        assert_eq!(json["lost_count"], serde_json::Value::Null);
+        assert_eq!(json["lost_after"], "c.txt");
+
+        let salvage = Salvage {
+            lost: vec!["a.txt".to_string(), "b.txt".to_string()],
+            damaged: vec!["c.txt".to_string()],
+            ..Salvage::default()
+        };
+        assert_eq!(salvage.lost_count(), Some(2));
+        let json = serde_json::to_value(&salvage).unwrap();
+        assert_eq!(json["lost_count"], 2);
+        assert_eq!(json["lost_after"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,382 @@
+//! zstd's seekable format, for reading part of an archive without decompressing all of it.
+//!
+//! The tar stream is compressed as independent zstd frames of [`FRAME_SIZE`] bytes each,
+//! followed by a skippable frame holding the seek table: the compressed and decompressed
+//! size of every frame. Decoders that do not know the format skip that frame and read the
+//! rest as ordinary zstd, so such archives recover with any version of sage.
+//!
+//! age encrypts in independent 64 KiB chunks and error correction groups have a fixed size,
+//! so every layer below can seek too. Reading one entry of a large archive then only
+//! repairs, decrypts and decompresses the groups, chunks and frames that hold it, and
+//! skipping an entry costs nothing. Frames are also independent of each other, so
+//! [`OrderedFrames`] lets several threads each decrypt and decompress their share of them.
+
+use crate::salvage::Losses;
+use log::{debug, warn};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::mpsc::Receiver;
+use zstd::bulk::{Compressor, Decompressor};
+use zstd::stream::raw::CParameter;
+
+/// Bytes of the tar stream compressed into each frame.
+pub const FRAME_SIZE: usize = 2 * 1024 * 1024;
+
+/// Largest decompressed frame accepted, so a damaged seek table cannot exhaust memory.
+const MAX_FRAME_SIZE: u32 = 64 * 1024 * 1024;
+const SKIPPABLE_MAGIC: u32 = 0x184d_2a5e;
+const SEEKABLE_MAGIC: u32 = 0x8f92_eab1;
+const SKIPPABLE_HEADER_LEN: u64 = 8;
+/// Number of frames, descriptor and magic.
+const FOOTER_LEN: u64 = 9;
+/// Descriptor flag of seek tables that store a checksum for every frame.
+const CHECKSUM_FLAG: u8 = 0x80;
+/// Descriptor bits that must be zero.
+const RESERVED_BITS: u8 = 0x7c;
+
+/// A reader that can also seek, so that it can be boxed as one trait object.
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek + ?Sized> ReadSeek for T {}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Compresses everything written to it into independent frames and ends with a seek table.
+pub struct SeekableWriter<W: Write> {
+    inner: W,
+    compressor: Compressor<'static>,
+    buffer: Vec<u8>,
+    /// Compressed and decompressed size of every frame written so far.
+    frames: Vec<(u32, u32)>,
+}
+
+impl<W: Write> SeekableWriter<W> {
+    /// Compresses into `inner` at `level`, spreading each frame over up to `threads` workers.
+    pub fn new(inner: W, level: i32, threads: u32) -> io::Result<Self> {
+        let mut compressor = Compressor::new(level)?;
+        compressor.set_parameter(CParameter::ChecksumFlag(true))?;
+        if threads > 1 {
+            compressor.set_parameter(CParameter::NbWorkers(threads))?;
+        }
+        debug!(
+            "Initializing seekable zstd compression with level {} and frames of {} bytes.",
+            level, FRAME_SIZE
+        );
+        Ok(Self {
+            inner,
+            compressor,
+            buffer: Vec::with_capacity(FRAME_SIZE),
+            frames: Vec::new(),
+        })
+    }
+
+    /// Compresses the buffered data, writes the seek table and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_frame()?;
+        let entries_len = self.frames.len() * 8;
+        let mut table =
+            Vec::with_capacity((SKIPPABLE_HEADER_LEN + FOOTER_LEN) as usize + entries_len);
+        table.extend_from_slice(&SKIPPABLE_MAGIC.to_le_bytes());
+        table.extend_from_slice(&((entries_len as u64 + FOOTER_LEN) as u32).to_le_bytes());
+        for (compressed, decompressed) in &self.frames {
+            table.extend_from_slice(&compressed.to_le_bytes());
+            table.extend_from_slice(&decompressed.to_le_bytes());
+        }
+        table.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        table.push(0);
+        table.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+        self.inner.write_all(&table)?;
+        debug!("Wrote a seek table of {} frames.", self.frames.len());
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_frame(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let compressed = self.compressor.compress(&self.buffer)?;
+        self.inner.write_all(&compressed)?;
+        self.frames
+            .push((compressed.len() as u32, self.buffer.len() as u32));
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for SeekableWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(FRAME_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == FRAME_SIZE {
+            self.write_frame()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decompresses a stream in the seekable format, decompressing only the frames it reads.
+pub struct SeekableReader<R: Read + Seek> {
+    inner: R,
+    /// Compressed and decompressed offset of the start of every frame, and of the end.
+    offsets: Vec<(u64, u64)>,
+    decompressor: Decompressor<'static>,
+    /// The frame in `buffer`, if any.
+    frame: Option<usize>,
+    buffer: Vec<u8>,
+    position: u64,
+    /// Where frames that cannot be read are recorded, if they are read as zeros.
+    losses: Option<Losses>,
+}
+
+impl<R: Read + Seek> SeekableReader<R> {
+    /// Reads the seek table at the end of `inner`, whose start is offset 0.
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let end = inner.seek(SeekFrom::End(0))?;
+        if end < SKIPPABLE_HEADER_LEN + FOOTER_LEN {
+            return Err(invalid_data("payload is too short to hold a seek table"));
+        }
+        inner.seek(SeekFrom::Start(end - FOOTER_LEN))?;
+        let mut footer = [0u8; FOOTER_LEN as usize];
+        inner.read_exact(&mut footer)?;
+        let u32_at =
+            |bytes: &[u8], i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let descriptor = footer[4];
+        if u32_at(&footer, 5) != SEEKABLE_MAGIC || descriptor & RESERVED_BITS != 0 {
+            return Err(invalid_data("payload does not end with a seek table"));
+        }
+        let count = u32_at(&footer, 0) as u64;
+        let entry_len = if descriptor & CHECKSUM_FLAG != 0 {
+            12
+        } else {
+            8
+        };
+        let table_len = SKIPPABLE_HEADER_LEN + count * entry_len + FOOTER_LEN;
+        if table_len > end {
+            return Err(invalid_data("seek table is larger than the payload"));
+        }
+        inner.seek(SeekFrom::Start(end - table_len))?;
+        let mut table = vec![0u8; (table_len - FOOTER_LEN) as usize];
+        inner.read_exact(&mut table)?;
+        if u32_at(&table, 0) != SKIPPABLE_MAGIC
+            || u32_at(&table, 4) as u64 != table_len - SKIPPABLE_HEADER_LEN
+        {
+            return Err(invalid_data("seek table frame is damaged"));
+        }
+
+        let mut offsets = Vec::with_capacity(count as usize + 1);
+        let (mut compressed, mut decompressed) = (0u64, 0u64);
+        offsets.push((0, 0));
+        for entry in table[SKIPPABLE_HEADER_LEN as usize..].chunks_exact(entry_len as usize) {
+            let frame_len = u32_at(entry, 4);
+            if frame_len > MAX_FRAME_SIZE {
+                return Err(invalid_data("seek table lists an oversized frame"));
+            }
+            compressed += u32_at(entry, 0) as u64;
+            decompressed += frame_len as u64;
+            offsets.push((compressed, decompressed));
+        }
+        if compressed + table_len != end {
+            return Err(invalid_data("seek table does not match the payload"));
+        }
+        debug!(
+            "Seek table lists {} frames holding {} bytes.",
+            count, decompressed
+        );
+        Ok(Self {
+            inner,
+            offsets,
+            decompressor: Decompressor::new()?,
+            frame: None,
+            buffer: Vec::new(),
+            position: 0,
+            losses: None,
+        })
+    }
+
+    /// Reads frames that cannot be read or decompressed as zeros instead of failing, and
+    /// records their place in the decompressed stream in `losses`.
+    pub fn salvage(&mut self, losses: Losses) {
+        self.losses = Some(losses);
+    }
+
+    /// The number of frames in the stream.
+    pub fn frame_count(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    /// Reads and decompresses frame `index`, which must be below [`Self::frame_count`].
+    pub fn read_frame(&mut self, index: usize) -> io::Result<Vec<u8>> {
+        self.load_frame(index)?;
+        self.frame = None;
+        Ok(std::mem::take(&mut self.buffer))
+    }
+
+    /// The decompressed length of the stream.
+    fn len(&self) -> u64 {
+        self.offsets.last().map_or(0, |&(_, end)| end)
+    }
+
+    /// Reads and decompresses `frame` into the buffer.
+    fn load_frame(&mut self, frame: usize) -> io::Result<()> {
+        let (start, decompressed_start) = self.offsets[frame];
+        let (end, decompressed_end) = self.offsets[frame + 1];
+        self.inner.seek(SeekFrom::Start(start))?;
+        let mut compressed = vec![0u8; (end - start) as usize];
+        self.inner.read_exact(&mut compressed)?;
+        let expected = (decompressed_end - decompressed_start) as usize;
+        self.buffer = self.decompressor.decompress(&compressed, expected)?;
+        if self.buffer.len() != expected {
+            return Err(invalid_data(&format!(
+                "compressed frame {frame} does not match the seek table"
+            )));
+        }
+        self.frame = Some(frame);
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for SeekableReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len() || buf.is_empty() {
+            return Ok(0);
+        }
+        let frame = self
+            .offsets
+            .partition_point(|&(_, start)| start <= self.position)
+            - 1;
+        if self.frame != Some(frame)
+            && let Err(e) = self.load_frame(frame)
+        {
+            let Some(losses) = self
+                .losses
+                .as_ref()
+                .filter(|_| !crate::cancel::is_requested())
+            else {
+                return Err(e);
+            };
+            let (start, end) = (self.offsets[frame].1, self.offsets[frame + 1].1);
+            warn!("Frame {frame} (bytes {start}..{end} of the tar stream) cannot be read: {e}");
+            losses.record(start..end);
+            self.buffer = vec![0; (end - start) as usize];
+            self.frame = Some(frame);
+        }
+        let offset = (self.position - self.offsets[frame].1) as usize;
+        let n = buf.len().min(self.buffer.len() - offset);
+        buf[..n].copy_from_slice(&self.buffer[offset..offset + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for SeekableReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.len().checked_add_signed(offset),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+/// Reads frames that several workers decompress, in order.
+///
+/// Frame `i` arrives from `workers[i % workers.len()]`, so each worker handles every n-th
+/// frame and sends it, or the error that stopped it, down its bounded channel.
+pub struct OrderedFrames {
+    workers: Vec<Receiver<io::Result<Vec<u8>>>>,
+    count: usize,
+    next: usize,
+    frame: Vec<u8>,
+    position: usize,
+}
+
+impl OrderedFrames {
+    /// Reads `count` frames from `workers`.
+    pub fn new(workers: Vec<Receiver<io::Result<Vec<u8>>>>, count: usize) -> Self {
+        Self {
+            workers,
+            count,
+            next: 0,
+            frame: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl Read for OrderedFrames {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.frame.len() {
+            if self.next == self.count {
+                return Ok(0);
+            }
+            let worker = &self.workers[self.next % self.workers.len()];
+            self.frame = worker
+                .recv()
+                .map_err(|_| io::Error::other("a recovery thread stopped unexpectedly"))??;
+            self.position = 0;
+            self.next += 1;
+        }
+        let n = buf.len().min(self.frame.len() - self.position);
+        buf[..n].copy_from_slice(&self.frame[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 / 3) as u8).collect()
+    }
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut writer = SeekableWriter::new(Vec::new(), 3, 1).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn is_ordinary_zstd() {
+        let data = payload(2 * FRAME_SIZE + 1000);
+        let compressed = compress(&data);
+        assert_eq!(zstd::decode_all(compressed.as_slice()).unwrap(), data);
+    }
+
+    #[test]
+    fn reads_from_any_offset() {
+        let data = payload(3 * FRAME_SIZE + 1000);
+        let mut reader = SeekableReader::new(Cursor::new(compress(&data))).unwrap();
+        for offset in [FRAME_SIZE as u64 * 2 + 17, 5, FRAME_SIZE as u64 - 3] {
+            reader.seek(SeekFrom::Start(offset)).unwrap();
+            let mut buf = [0u8; 100];
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(buf[..], data[offset as usize..offset as usize + 100]);
+        }
+        reader.seek(SeekFrom::End(-10)).unwrap();
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, data[data.len() - 10..]);
+    }
+
+    #[test]
+    fn rejects_streams_without_a_seek_table() {
+        let compressed = zstd::encode_all(payload(1000).as_slice(), 3).unwrap();
+        assert!(SeekableReader::new(Cursor::new(compressed)).is_err());
+    }
+}
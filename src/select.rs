@@ -0,0 +1,62 @@
+//! Glob patterns for choosing which archive entries to extract.
+
+use anyhow::Context;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::path::Path;
+
+/// A set of patterns selecting archive entries by path.
+///
+/// Patterns match the whole path of an entry, with `*` not crossing directory boundaries and
+/// `**` matching any number of directories. An entry is selected if its path or any of its
+/// parent directories matches, so selecting a directory selects everything below it.
+#[derive(Clone, Debug)]
+pub struct PathSelector {
+    set: GlobSet,
+    patterns: Vec<String>,
+}
+
+impl PathSelector {
+    /// Compiles `patterns`, failing on the first invalid glob.
+    pub fn new<I, S>(patterns: I) -> crate::Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut set = GlobSetBuilder::new();
+        let mut compiled = Vec::new();
+        for pattern in patterns {
+            let pattern = pattern.as_ref();
+            let glob = pattern.trim_start_matches("./").trim_matches('/');
+            set.add(
+                GlobBuilder::new(glob)
+                    .literal_separator(true)
+                    .build()
+                    .with_context(|| format!("Invalid path pattern: {pattern}"))?,
+            );
+            compiled.push(pattern.to_string());
+        }
+
+        Ok(Self {
+            set: set.build().map_err(anyhow::Error::from)?,
+            patterns: compiled,
+        })
+    }
+
+    /// The patterns, in the order they were given.
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    /// Returns the indices of the patterns that select the entry at `path`.
+    pub fn matching(&self, path: &Path) -> Vec<usize> {
+        let path = path.strip_prefix(".").unwrap_or(path);
+        let mut indices: Vec<usize> = path
+            .ancestors()
+            .filter(|p| !p.as_os_str().is_empty())
+            .flat_map(|p| self.set.matches(p))
+            .collect();
+        indices.sort_unstable();
+        indices.dedup();
+        indices
+    }
+}
@@ -0,0 +1,888 @@
+//! Writing archives to and reading them from remote servers over SFTP.
+//!
+//! An output of the form `sftp://user@host/path/archive.sage` is uploaded while it is
+//! written, and an input URL is downloaded while it is read. sage speaks SFTP version 3 to
+//! the `sftp` subsystem of an `ssh` process, so hosts, keys, agents and `known_hosts` are
+//! taken from the usual ssh configuration. `SAGE_SSH_COMMAND` replaces the `ssh` command,
+//! for example to add options. In non-interactive mode, ssh runs with `BatchMode=yes`, so it
+//! fails instead of asking for a password or host key confirmation.
+//!
+//! Uploads go to a temporary file next to the destination, which is renamed into place once
+//! the archive is complete. If the connection drops, sage reconnects and resends the writes
+//! the server had not yet confirmed, so the upload resumes where it stopped; downloads resume
+//! at the last byte read. The encrypted stream is new on every run, so an upload can only be
+//! resumed by the run that started it.
+
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// Prefix of paths that name a file on an SFTP server.
+pub const SFTP_SCHEME: &str = "sftp://";
+/// Environment variable with the command used instead of `ssh`, split into words like a
+/// shell would.
+pub const SSH_COMMAND_ENV: &str = "SAGE_SSH_COMMAND";
+
+/// Bytes sent or requested per SFTP read or write, which every server accepts.
+const CHUNK_SIZE: usize = 32 * 1024;
+/// Requests sent before waiting for replies.
+const MAX_PENDING: usize = 64;
+/// How often sage connects before giving up on a dropped connection.
+const ATTEMPTS: u32 = 5;
+/// How often a single transfer may reconnect before sage gives up on the server.
+const MAX_RECONNECTS: u32 = 20;
+
+const SSH_FXP_INIT: u8 = 1;
+const SSH_FXP_VERSION: u8 = 2;
+const SSH_FXP_OPEN: u8 = 3;
+const SSH_FXP_CLOSE: u8 = 4;
+const SSH_FXP_READ: u8 = 5;
+const SSH_FXP_WRITE: u8 = 6;
+const SSH_FXP_FSTAT: u8 = 8;
+const SSH_FXP_REMOVE: u8 = 13;
+const SSH_FXP_STAT: u8 = 17;
+const SSH_FXP_RENAME: u8 = 18;
+const SSH_FXP_STATUS: u8 = 101;
+const SSH_FXP_HANDLE: u8 = 102;
+const SSH_FXP_DATA: u8 = 103;
+const SSH_FXP_ATTRS: u8 = 105;
+const SSH_FXP_EXTENDED: u8 = 200;
+
+const SSH_FXF_READ: u32 = 0x01;
+const SSH_FXF_WRITE: u32 = 0x02;
+const SSH_FXF_CREAT: u32 = 0x08;
+const SSH_FXF_TRUNC: u32 = 0x10;
+const SSH_FXF_EXCL: u32 = 0x20;
+
+const SSH_FILEXFER_ATTR_SIZE: u32 = 0x01;
+const SSH_FILEXFER_ATTR_PERMISSIONS: u32 = 0x04;
+
+const SSH_FX_OK: u32 = 0;
+const SSH_FX_EOF: u32 = 1;
+const SSH_FX_NO_SUCH_FILE: u32 = 2;
+const SSH_FX_PERMISSION_DENIED: u32 = 3;
+
+const POSIX_RENAME: &str = "posix-rename@openssh.com";
+
+/// Returns true if `path` names a file on an SFTP server rather than a local file.
+pub fn is_sftp_url(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|path| path.starts_with(SFTP_SCHEME))
+}
+
+/// A server and file path, parsed from an `sftp://[user@]host[:port]/path` URL.
+///
+/// As with curl, the path is absolute; `sftp://host/~/backup.sage` names a file in the
+/// home directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SftpLocation {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+    pub path: String,
+}
+
+impl SftpLocation {
+    pub fn parse(url: &str) -> io::Result<Self> {
+        let invalid = || invalid_input(&format!("{url} is not an sftp://[user@]host/path URL"));
+        let rest = url.strip_prefix(SFTP_SCHEME).ok_or_else(invalid)?;
+        let (authority, path) = rest.split_once('/').ok_or_else(invalid)?;
+        let (user, host) = match authority.rsplit_once('@') {
+            Some((user, host)) => (Some(user.to_string()), host),
+            None => (None, authority),
+        };
+        let (host, port) = match host.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port.parse().map_err(|_| invalid())?)),
+            None => (host, None),
+        };
+        // A leading dash would be taken for an ssh option.
+        if host.is_empty() || host.starts_with('-') || path.is_empty() {
+            return Err(invalid());
+        }
+        let path = match path.strip_prefix("~/") {
+            Some(relative) => relative.to_string(),
+            None => format!("/{path}"),
+        };
+        Ok(Self {
+            user,
+            host: host.to_string(),
+            port,
+            path,
+        })
+    }
+
+    /// The path of the temporary file an upload is written to.
+    fn temp_path(&self) -> String {
+        let (dir, name) = match self.path.rsplit_once('/') {
+            Some((dir, name)) => (format!("{dir}/"), name),
+            None => (String::new(), self.path.as_str()),
+        };
+        format!("{dir}.{name}.{:08x}.part", rand::random::<u32>())
+    }
+}
+
+impl std::fmt::Display for SftpLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{SFTP_SCHEME}")?;
+        if let Some(user) = &self.user {
+            write!(f, "{user}@")?;
+        }
+        write!(f, "{}", self.host)?;
+        if let Some(port) = self.port {
+            write!(f, ":{port}")?;
+        }
+        match self.path.strip_prefix('/') {
+            Some(path) => write!(f, "/{path}"),
+            None => write!(f, "/~/{}", self.path),
+        }
+    }
+}
+
+/// A reply from the server.
+enum Reply {
+    Status(u32, String),
+    Handle(Vec<u8>),
+    Data(Vec<u8>),
+    Attrs(Option<u64>),
+}
+
+/// An SFTP session over an `ssh` child process.
+struct Session {
+    child: Child,
+    input: BufWriter<ChildStdin>,
+    output: BufReader<ChildStdout>,
+    next_id: u32,
+    posix_rename: bool,
+}
+
+impl Session {
+    /// Starts a session. In `batch` mode ssh fails instead of asking for a password.
+    fn connect(location: &SftpLocation, batch: bool) -> io::Result<Self> {
+        let command = std::env::var(SSH_COMMAND_ENV)
+            .ok()
+            .filter(|command| !command.trim().is_empty())
+            .unwrap_or_else(|| "ssh".to_string());
+        let words = shlex::split(&command).filter(|words| !words.is_empty());
+        let Some((program, args)) = words.as_ref().and_then(|words| words.split_first()) else {
+            return Err(invalid_input(&format!(
+                "{SSH_COMMAND_ENV} is not a valid command: {command}"
+            )));
+        };
+        let mut ssh = Command::new(program);
+        ssh.args(args);
+        if batch {
+            ssh.args(["-o", "BatchMode=yes"]);
+        }
+        if let Some(port) = location.port {
+            ssh.arg("-p").arg(port.to_string());
+        }
+        if let Some(user) = &location.user {
+            ssh.arg("-l").arg(user);
+        }
+        ssh.args(["-s", &location.host, "sftp"]);
+        debug!("Connecting: {ssh:?}");
+        let mut child = ssh
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to run ssh: {e}")))?;
+        let input = BufWriter::new(child.stdin.take().expect("stdin is piped"));
+        let output = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        let mut session = Self {
+            child,
+            input,
+            output,
+            next_id: 0,
+            posix_rename: false,
+        };
+
+        let mut init = Vec::new();
+        put_u32(&mut init, 3);
+        session.send_packet(SSH_FXP_INIT, &init)?;
+        session.input.flush()?;
+        let (kind, payload) = session.recv_packet().map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("{} did not start an SFTP session: {e}", location.host),
+            )
+        })?;
+        let mut payload = Parser(&payload);
+        if kind != SSH_FXP_VERSION || payload.u32()? < 3 {
+            return Err(io::Error::other("server does not speak SFTP version 3"));
+        }
+        while !payload.0.is_empty() {
+            let name = payload.string()?;
+            payload.string()?;
+            session.posix_rename |= name == POSIX_RENAME.as_bytes();
+        }
+        debug!("SFTP session to {} started.", location.host);
+        Ok(session)
+    }
+
+    fn send_packet(&mut self, kind: u8, payload: &[u8]) -> io::Result<()> {
+        put_u32_to(&mut self.input, payload.len() as u32 + 1)?;
+        self.input.write_all(&[kind])?;
+        self.input.write_all(payload)
+    }
+
+    fn recv_packet(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        let mut len = [0u8; 4];
+        self.output
+            .read_exact(&mut len)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => {
+                    io::Error::new(e.kind(), "the server closed the connection")
+                }
+                _ => e,
+            })?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 || len > 4 * CHUNK_SIZE + 1024 {
+            return Err(invalid_data("SFTP packet has an invalid length"));
+        }
+        let mut packet = vec![0u8; len];
+        self.output.read_exact(&mut packet)?;
+        let payload = packet.split_off(1);
+        Ok((packet[0], payload))
+    }
+
+    /// Sends a request whose payload starts with a fresh id, returning the id.
+    fn send(&mut self, kind: u8, build: impl FnOnce(&mut Vec<u8>)) -> io::Result<u32> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let mut payload = Vec::new();
+        put_u32(&mut payload, id);
+        build(&mut payload);
+        self.send_packet(kind, &payload)?;
+        Ok(id)
+    }
+
+    /// Reads the next reply and the id of the request it answers.
+    fn recv(&mut self) -> io::Result<(u32, Reply)> {
+        let (kind, payload) = self.recv_packet()?;
+        let mut payload = Parser(&payload);
+        let id = payload.u32()?;
+        let reply = match kind {
+            SSH_FXP_STATUS => {
+                let code = payload.u32()?;
+                let message = payload.string().unwrap_or_default();
+                Reply::Status(code, String::from_utf8_lossy(message).into_owned())
+            }
+            SSH_FXP_HANDLE => Reply::Handle(payload.string()?.to_vec()),
+            SSH_FXP_DATA => Reply::Data(payload.string()?.to_vec()),
+            SSH_FXP_ATTRS => Reply::Attrs(payload.attrs_size()?),
+            _ => return Err(invalid_data("unexpected SFTP reply")),
+        };
+        Ok((id, reply))
+    }
+
+    /// Sends a request and waits for its reply.
+    fn call(&mut self, kind: u8, build: impl FnOnce(&mut Vec<u8>)) -> io::Result<Reply> {
+        let id = self.send(kind, build)?;
+        self.input.flush()?;
+        loop {
+            let (reply_id, reply) = self.recv()?;
+            if reply_id == id {
+                return Ok(reply);
+            }
+            debug!("Ignoring SFTP reply to stale request {reply_id}.");
+        }
+    }
+
+    /// Calls a request that is answered with a status, failing unless it is OK.
+    fn call_ok(
+        &mut self,
+        kind: u8,
+        what: &str,
+        build: impl FnOnce(&mut Vec<u8>),
+    ) -> io::Result<()> {
+        match self.call(kind, build)? {
+            Reply::Status(SSH_FX_OK, _) => Ok(()),
+            Reply::Status(code, message) => Err(status_error(code, &message, what)),
+            _ => Err(invalid_data("unexpected SFTP reply")),
+        }
+    }
+
+    fn open(&mut self, path: &str, flags: u32, mode: Option<u32>) -> io::Result<Vec<u8>> {
+        let reply = self.call(SSH_FXP_OPEN, |p| {
+            put_string(p, path.as_bytes());
+            put_u32(p, flags);
+            match mode {
+                Some(mode) => {
+                    put_u32(p, SSH_FILEXFER_ATTR_PERMISSIONS);
+                    put_u32(p, mode);
+                }
+                None => put_u32(p, 0),
+            }
+        })?;
+        match reply {
+            Reply::Handle(handle) => Ok(handle),
+            Reply::Status(code, message) => Err(status_error(code, &message, path)),
+            _ => Err(invalid_data("unexpected SFTP reply")),
+        }
+    }
+
+    /// Returns the size of the file at `path`, or `None` if it does not exist.
+    fn stat(&mut self, path: &str) -> io::Result<Option<u64>> {
+        match self.call(SSH_FXP_STAT, |p| put_string(p, path.as_bytes()))? {
+            Reply::Attrs(size) => Ok(Some(size.unwrap_or(0))),
+            Reply::Status(SSH_FX_NO_SUCH_FILE, _) => Ok(None),
+            Reply::Status(code, message) => Err(status_error(code, &message, path)),
+            _ => Err(invalid_data("unexpected SFTP reply")),
+        }
+    }
+
+    fn fstat(&mut self, handle: &[u8]) -> io::Result<Option<u64>> {
+        match self.call(SSH_FXP_FSTAT, |p| put_string(p, handle))? {
+            Reply::Attrs(size) => Ok(size),
+            Reply::Status(code, message) => Err(status_error(code, &message, "fstat")),
+            _ => Err(invalid_data("unexpected SFTP reply")),
+        }
+    }
+
+    fn close(&mut self, handle: &[u8]) -> io::Result<()> {
+        self.call_ok(SSH_FXP_CLOSE, "close", |p| put_string(p, handle))
+    }
+
+    fn remove(&mut self, path: &str) -> io::Result<()> {
+        self.call_ok(SSH_FXP_REMOVE, path, |p| put_string(p, path.as_bytes()))
+    }
+
+    /// Renames `from` to `to`, replacing `to` if `overwrite` is set.
+    fn rename(&mut self, from: &str, to: &str, overwrite: bool) -> io::Result<()> {
+        if overwrite && self.posix_rename {
+            return self.call_ok(SSH_FXP_EXTENDED, to, |p| {
+                put_string(p, POSIX_RENAME.as_bytes());
+                put_string(p, from.as_bytes());
+                put_string(p, to.as_bytes());
+            });
+        }
+        if overwrite && self.stat(to)?.is_some() {
+            debug!("Server lacks {POSIX_RENAME}; removing {to} before renaming.");
+            self.remove(to)?;
+        }
+        self.call_ok(SSH_FXP_RENAME, to, |p| {
+            put_string(p, from.as_bytes());
+            put_string(p, to.as_bytes());
+        })
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Closing stdin ends the subsystem; kill in case ssh hangs on a dead connection.
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Connects to `location` again after the connection dropped, retrying with a growing delay.
+fn connect_with_retries(location: &SftpLocation, batch: bool) -> io::Result<Session> {
+    let mut attempt = 1;
+    loop {
+        match Session::connect(location, batch) {
+            Ok(session) => return Ok(session),
+            Err(e) if attempt < ATTEMPTS && !crate::cancel::is_requested() => {
+                warn!("Connecting to {} failed: {e}; retrying.", location.host);
+                thread::sleep(Duration::from_secs(1 << attempt));
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// A write that the server has not confirmed yet.
+struct PendingWrite {
+    /// The id of the request, or `None` until it is sent.
+    id: Option<u32>,
+    offset: u64,
+    data: Vec<u8>,
+}
+
+/// Uploads everything written to it to a file on an SFTP server.
+///
+/// The file only appears at its path once [`SftpWriter::commit`] renames it into place.
+/// Dropping the writer without committing it, for example on an error, removes the
+/// temporary file.
+pub struct SftpWriter {
+    location: SftpLocation,
+    batch: bool,
+    temp_path: String,
+    session: Session,
+    handle: Vec<u8>,
+    overwrite: bool,
+    chunk: Vec<u8>,
+    offset: u64,
+    pending: VecDeque<PendingWrite>,
+    reconnects: u32,
+    finished: bool,
+}
+
+impl SftpWriter {
+    /// Starts an upload to the `sftp://` URL `url`, creating the file with permissions
+    /// `mode`.
+    ///
+    /// Fails with [`io::ErrorKind::AlreadyExists`] if the file exists, unless `overwrite` is
+    /// set. With `non_interactive`, ssh never prompts.
+    pub fn create(
+        url: &str,
+        mode: u32,
+        overwrite: bool,
+        non_interactive: bool,
+    ) -> io::Result<Self> {
+        let location = SftpLocation::parse(url)?;
+        let mut session = Session::connect(&location, non_interactive)?;
+        if !overwrite && session.stat(&location.path)?.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "file already exists",
+            ));
+        }
+        let temp_path = location.temp_path();
+        debug!("Uploading to temporary file: {temp_path}");
+        let handle = session.open(
+            &temp_path,
+            SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC | SSH_FXF_EXCL,
+            Some(mode),
+        )?;
+        Ok(Self {
+            location,
+            batch: non_interactive,
+            temp_path,
+            session,
+            handle,
+            overwrite,
+            chunk: Vec::with_capacity(CHUNK_SIZE),
+            offset: 0,
+            pending: VecDeque::new(),
+            reconnects: 0,
+            finished: false,
+        })
+    }
+
+    /// Runs `f` on the session, reconnecting and resending unconfirmed writes if the
+    /// connection drops.
+    fn with_retries<T>(&mut self, mut f: impl FnMut(&mut Self) -> io::Result<T>) -> io::Result<T> {
+        loop {
+            match f(self) {
+                Err(e) if is_connection_error(&e) && !crate::cancel::is_requested() => {
+                    self.reconnects += 1;
+                    if self.reconnects > MAX_RECONNECTS {
+                        return Err(e);
+                    }
+                    warn!(
+                        "Connection to {} lost ({e}); reconnecting to resume the upload.",
+                        self.location.host
+                    );
+                    self.reconnect()?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn reconnect(&mut self) -> io::Result<()> {
+        self.session = connect_with_retries(&self.location, self.batch)?;
+        self.handle = self.session.open(&self.temp_path, SSH_FXF_WRITE, None)?;
+        let confirmed = self
+            .pending
+            .front()
+            .map_or(self.offset, |write| write.offset);
+        info!(
+            "Resuming upload at byte {confirmed}; resending {} unconfirmed writes.",
+            self.pending.len()
+        );
+        let mut pending = std::mem::take(&mut self.pending);
+        for write in &mut pending {
+            write.id = Some(send_write(
+                &mut self.session,
+                &self.handle,
+                write.offset,
+                &write.data,
+            )?);
+        }
+        self.pending = pending;
+        Ok(())
+    }
+
+    /// Waits for the reply to the oldest pending write.
+    fn confirm_one(&mut self) -> io::Result<()> {
+        self.session.input.flush()?;
+        let (id, reply) = self.session.recv()?;
+        let Some(index) = self.pending.iter().position(|write| write.id == Some(id)) else {
+            debug!("Ignoring SFTP reply to stale request {id}.");
+            return Ok(());
+        };
+        match reply {
+            Reply::Status(SSH_FX_OK, _) => {
+                self.pending.remove(index);
+                Ok(())
+            }
+            Reply::Status(code, message) => Err(status_error(code, &message, &self.temp_path)),
+            _ => Err(invalid_data("unexpected SFTP reply")),
+        }
+    }
+
+    /// Sends the filled chunk, first waiting for replies if too many writes are pending.
+    fn send_chunk(&mut self) -> io::Result<()> {
+        let data = std::mem::replace(&mut self.chunk, Vec::with_capacity(CHUNK_SIZE));
+        let offset = self.offset;
+        self.offset += data.len() as u64;
+        self.pending.push_back(PendingWrite {
+            id: None,
+            offset,
+            data,
+        });
+        self.with_retries(|writer| {
+            while writer.pending.len() > MAX_PENDING {
+                writer.confirm_one()?;
+            }
+            let last = writer.pending.back_mut().expect("a write was just queued");
+            if last.id.is_none() {
+                last.id = Some(send_write(
+                    &mut writer.session,
+                    &writer.handle,
+                    offset,
+                    &last.data,
+                )?);
+            }
+            Ok(())
+        })
+    }
+
+    /// Sends the rest of the data, waits for the server to confirm all of it and renames
+    /// the file into place.
+    pub fn commit(mut self) -> io::Result<()> {
+        if !self.chunk.is_empty() {
+            self.send_chunk()?;
+        }
+        self.with_retries(|writer| {
+            while !writer.pending.is_empty() {
+                writer.confirm_one()?;
+            }
+            Ok(())
+        })?;
+        let handle = std::mem::take(&mut self.handle);
+        self.session.close(&handle)?;
+        let (temp, path, overwrite) = (
+            self.temp_path.clone(),
+            self.location.path.clone(),
+            self.overwrite,
+        );
+        self.session.rename(&temp, &path, overwrite)?;
+        self.finished = true;
+        info!("Uploaded {} bytes to {}", self.offset, self.location);
+        Ok(())
+    }
+}
+
+impl Write for SftpWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(CHUNK_SIZE - self.chunk.len());
+        self.chunk.extend_from_slice(&buf[..n]);
+        if self.chunk.len() == CHUNK_SIZE {
+            self.send_chunk()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SftpWriter {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        debug!("Removing unfinished upload: {}", self.temp_path);
+        let handle = std::mem::take(&mut self.handle);
+        let temp_path = self.temp_path.clone();
+        let removed = (|| {
+            // Take the replies to the writes first, whatever they say.
+            self.session.input.flush()?;
+            for _ in self.pending.drain(..).filter(|write| write.id.is_some()) {
+                self.session.recv()?;
+            }
+            self.session.close(&handle)?;
+            self.session.remove(&temp_path)
+        })();
+        // An interrupt also stops ssh, so try once more over a new connection.
+        let removed = removed.or_else(|e| {
+            if !is_connection_error(&e) {
+                return Err(e);
+            }
+            debug!("Connection lost; reconnecting to remove the unfinished upload.");
+            Session::connect(&self.location, true)?.remove(&temp_path)
+        });
+        if let Err(e) = removed {
+            warn!(
+                "Could not remove unfinished upload {temp_path} on {}: {e}",
+                self.location.host
+            );
+        }
+    }
+}
+
+fn send_write(session: &mut Session, handle: &[u8], offset: u64, data: &[u8]) -> io::Result<u32> {
+    session.send(SSH_FXP_WRITE, |p| {
+        put_string(p, handle);
+        p.extend_from_slice(&offset.to_be_bytes());
+        put_string(p, data);
+    })
+}
+
+/// Downloads a file from an SFTP server as it is read.
+pub struct SftpReader {
+    location: SftpLocation,
+    batch: bool,
+    session: Session,
+    handle: Vec<u8>,
+    size: Option<u64>,
+    /// The offset of the next byte to return.
+    offset: u64,
+    /// Outstanding reads by id, with their offsets, in the order they were sent.
+    requested: VecDeque<(u32, u64)>,
+    /// Replies that arrived before those to earlier reads.
+    arrived: BTreeMap<u64, Vec<u8>>,
+    /// The offset up to which reads have been requested.
+    next_request: u64,
+    buffer: Vec<u8>,
+    position: usize,
+    /// Whether the last reply was short, so the reads in flight have the wrong offsets.
+    short_read: bool,
+    eof: bool,
+    reconnects: u32,
+}
+
+impl SftpReader {
+    /// Opens the file at the `sftp://` URL `url`. With `non_interactive`, ssh never prompts.
+    pub fn open(url: &str, non_interactive: bool) -> io::Result<Self> {
+        let location = SftpLocation::parse(url)?;
+        let mut session = Session::connect(&location, non_interactive)?;
+        let handle = session.open(&location.path, SSH_FXF_READ, None)?;
+        let size = session.fstat(&handle)?;
+        debug!("Downloading {location} ({size:?} bytes)");
+        Ok(Self {
+            location,
+            batch: non_interactive,
+            session,
+            handle,
+            size,
+            offset: 0,
+            requested: VecDeque::new(),
+            arrived: BTreeMap::new(),
+            next_request: 0,
+            buffer: Vec::new(),
+            position: 0,
+            short_read: false,
+            eof: false,
+            reconnects: 0,
+        })
+    }
+
+    /// The size of the file, if the server reported it.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// Fills the buffer with the data at the current offset, returning false at the end of
+    /// the file.
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.short_read {
+            self.drain()?;
+            self.next_request = self.offset;
+            self.short_read = false;
+        }
+        loop {
+            if let Some(data) = self.arrived.remove(&self.offset) {
+                if data.is_empty() {
+                    self.drain()?;
+                    return Ok(false);
+                }
+                let full = data.len() == CHUNK_SIZE;
+                self.offset += data.len() as u64;
+                self.buffer = data;
+                self.position = 0;
+                // After a short read, the rest is requested again by the next call.
+                self.short_read = !full;
+                return Ok(true);
+            }
+            while self.requested.len() < MAX_PENDING {
+                let offset = self.next_request;
+                let handle = self.handle.clone();
+                let id = self.session.send(SSH_FXP_READ, |p| {
+                    put_string(p, &handle);
+                    p.extend_from_slice(&offset.to_be_bytes());
+                    put_u32(p, CHUNK_SIZE as u32);
+                })?;
+                self.requested.push_back((id, offset));
+                self.next_request += CHUNK_SIZE as u64;
+            }
+            self.session.input.flush()?;
+            let (id, reply) = self.session.recv()?;
+            let Some(index) = self.requested.iter().position(|(sent, _)| *sent == id) else {
+                debug!("Ignoring SFTP reply to stale request {id}.");
+                continue;
+            };
+            let (_, offset) = self.requested.remove(index).expect("index is in range");
+            match reply {
+                Reply::Data(data) => {
+                    self.arrived.insert(offset, data);
+                }
+                // Recorded as empty data, which ends the file once everything before it is read.
+                Reply::Status(SSH_FX_EOF, _) => {
+                    self.arrived.insert(offset, Vec::new());
+                }
+                Reply::Status(code, message) => {
+                    return Err(status_error(code, &message, &self.location.path));
+                }
+                _ => return Err(invalid_data("unexpected SFTP reply")),
+            }
+        }
+    }
+
+    /// Waits for every outstanding read and forgets their data.
+    fn drain(&mut self) -> io::Result<()> {
+        self.session.input.flush()?;
+        for _ in self.requested.drain(..) {
+            self.session.recv()?;
+        }
+        self.arrived.clear();
+        Ok(())
+    }
+
+    fn reconnect(&mut self) -> io::Result<()> {
+        self.session = connect_with_retries(&self.location, self.batch)?;
+        self.handle = self.session.open(&self.location.path, SSH_FXF_READ, None)?;
+        info!("Resuming download at byte {}.", self.offset);
+        self.requested.clear();
+        self.arrived.clear();
+        self.next_request = self.offset;
+        self.short_read = false;
+        Ok(())
+    }
+}
+
+impl Read for SftpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.buffer.len() {
+            if self.eof {
+                return Ok(0);
+            }
+            loop {
+                match self.fill() {
+                    Ok(true) => break,
+                    Ok(false) => {
+                        self.eof = true;
+                        return Ok(0);
+                    }
+                    Err(e) if is_connection_error(&e) && !crate::cancel::is_requested() => {
+                        self.reconnects += 1;
+                        if self.reconnects > MAX_RECONNECTS {
+                            return Err(e);
+                        }
+                        warn!(
+                            "Connection to {} lost ({e}); reconnecting to resume the download.",
+                            self.location.host
+                        );
+                        self.reconnect()?;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        let n = buf.len().min(self.buffer.len() - self.position);
+        buf[..n].copy_from_slice(&self.buffer[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// Returns true for errors that mean the connection to the server was lost.
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+fn status_error(code: u32, message: &str, what: &str) -> io::Error {
+    let kind = match code {
+        SSH_FX_NO_SUCH_FILE => io::ErrorKind::NotFound,
+        SSH_FX_PERMISSION_DENIED => io::ErrorKind::PermissionDenied,
+        _ => io::ErrorKind::Other,
+    };
+    let message = if message.is_empty() {
+        format!("SFTP error {code}")
+    } else {
+        message.to_string()
+    };
+    io::Error::new(kind, format!("{what}: {message}"))
+}
+
+/// Reads the fields of SFTP packets.
+struct Parser<'a>(&'a [u8]);
+
+impl<'a> Parser<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(invalid_data("SFTP packet is truncated"));
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    /// Reads file attributes, returning only the size.
+    fn attrs_size(&mut self) -> io::Result<Option<u64>> {
+        let flags = self.u32()?;
+        // The size comes first, and the other attributes are not needed.
+        let size = (flags & SSH_FILEXFER_ATTR_SIZE != 0)
+            .then(|| self.u64())
+            .transpose()?;
+        Ok(size)
+    }
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u32_to(writer: &mut impl Write, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_be_bytes())
+}
+
+fn put_string(buf: &mut Vec<u8>, value: &[u8]) {
+    put_u32(buf, value.len() as u32);
+    buf.extend_from_slice(value);
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_string())
+}
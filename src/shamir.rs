@@ -0,0 +1,251 @@
+//! Splitting a secret into shares, any `threshold` of which recover it.
+//!
+//! This is Shamir's secret sharing over GF(2^8): every byte of the secret is the constant
+//! term of a random polynomial of degree `threshold - 1`, and share `i` holds the value of
+//! every polynomial at `x = i`. Fewer than `threshold` shares reveal nothing about the
+//! secret; any `threshold` of them give it back by interpolation.
+//!
+//! `protect --shares` splits a one-time age identity this way and encrypts the archive to
+//! it, so the shares together take the place of a key file. A share is written as text:
+//!
+//! ```text
+//! sage-share-v1 <set> <threshold> <index> <data>
+//! ```
+//!
+//! where `set` is a random hex id shared by the shares of one split, and `data` is hex.
+//! Lines starting with `#` are comments.
+
+use crate::Error;
+use anyhow::{Result, anyhow};
+use log::warn;
+use rand::RngCore;
+use std::fmt;
+use std::str::FromStr;
+
+/// First word of the line that holds a share.
+const SHARE_PREFIX: &str = "sage-share-v1";
+
+/// Bytes of the random id that ties the shares of one split together.
+const SET_LEN: usize = 8;
+
+/// One share of a split secret.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Share {
+    /// Identifies the split this share belongs to.
+    pub set: [u8; SET_LEN],
+    /// The number of shares needed to recover the secret.
+    pub threshold: u8,
+    /// The x coordinate of the share, from 1.
+    pub index: u8,
+    pub data: Vec<u8>,
+}
+
+/// Splits `secret` into `count` shares, any `threshold` of which recover it.
+pub fn split(secret: &[u8], threshold: u8, count: u8) -> crate::Result<Vec<Share>> {
+    if threshold < 2 || threshold > count {
+        return Err(anyhow!(
+            "The threshold must be at least 2 and at most the number of shares ({count})."
+        )
+        .into());
+    }
+    let mut rng = rand::rngs::OsRng;
+    let mut set = [0; SET_LEN];
+    rng.fill_bytes(&mut set);
+    let mut shares: Vec<Share> = (1..=count)
+        .map(|index| Share {
+            set,
+            threshold,
+            index,
+            data: Vec::with_capacity(secret.len()),
+        })
+        .collect();
+    let mut coefficients = vec![0; threshold as usize];
+    for &byte in secret {
+        coefficients[0] = byte;
+        rng.fill_bytes(&mut coefficients[1..]);
+        for share in &mut shares {
+            // Horner's rule, from the highest coefficient down.
+            let y = coefficients
+                .iter()
+                .rev()
+                .fold(0, |y, &c| mul(y, share.index) ^ c);
+            share.data.push(y);
+        }
+    }
+    Ok(shares)
+}
+
+/// Recovers the secret from `shares`, which must come from the same split and number at
+/// least its threshold; too few different shares are [`Error::NoIdentities`].
+pub fn combine(shares: &[Share]) -> crate::Result<Vec<u8>> {
+    let first = shares.first().ok_or_else(|| anyhow!("No shares given."))?;
+    for share in shares {
+        if share.set != first.set || share.threshold != first.threshold {
+            return Err(anyhow!(
+                "Share {} belongs to a different archive than share {}.",
+                share.index,
+                first.index
+            )
+            .into());
+        }
+        if share.data.len() != first.data.len() || share.index == 0 {
+            return Err(anyhow!("Share {} is damaged.", share.index).into());
+        }
+    }
+    let mut used: Vec<&Share> = Vec::new();
+    for share in shares {
+        if !used.iter().any(|other| other.index == share.index) {
+            used.push(share);
+        }
+    }
+    let threshold = first.threshold as usize;
+    if used.len() < threshold {
+        warn!(
+            "{} different shares given, but {} are needed.",
+            used.len(),
+            threshold
+        );
+        return Err(Error::NoIdentities);
+    }
+    used.truncate(threshold);
+
+    // Lagrange interpolation at x = 0; subtraction is addition, a xor, in GF(2^8).
+    let weights: Vec<u8> = used
+        .iter()
+        .map(|share| {
+            used.iter()
+                .filter(|other| other.index != share.index)
+                .fold(1, |weight, other| {
+                    mul(weight, div(other.index, other.index ^ share.index))
+                })
+        })
+        .collect();
+    Ok((0..first.data.len())
+        .map(|i| {
+            used.iter()
+                .zip(&weights)
+                .fold(0, |secret, (share, &weight)| {
+                    secret ^ mul(share.data[i], weight)
+                })
+        })
+        .collect())
+}
+
+impl fmt::Display for Share {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{SHARE_PREFIX} {} {} {} {}",
+            hex(&self.set),
+            self.threshold,
+            self.index,
+            hex(&self.data)
+        )
+    }
+}
+
+impl FromStr for Share {
+    type Err = anyhow::Error;
+
+    /// Parses the share in `text`, skipping blank lines and `#` comments.
+    fn from_str(text: &str) -> Result<Self> {
+        let line = text
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .ok_or_else(|| anyhow!("No share found."))?;
+        let invalid = || anyhow!("Not a sage key share.");
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [SHARE_PREFIX, set, threshold, index, data] = fields[..] else {
+            return Err(invalid());
+        };
+        Ok(Share {
+            set: unhex(set)
+                .and_then(|set| set.try_into().ok())
+                .ok_or_else(invalid)?,
+            threshold: threshold.parse().map_err(|_| invalid())?,
+            index: index.parse().map_err(|_| invalid())?,
+            data: unhex(data).ok_or_else(invalid)?,
+        })
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Multiplies in GF(2^8) with the AES polynomial.
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+/// Divides in GF(2^8); `b` is never zero for distinct share indices.
+fn div(a: u8, b: u8) -> u8 {
+    // b^254 is the inverse of b, since the multiplicative group has order 255.
+    let mut inverse = 1;
+    let mut power = b;
+    let mut exponent = 254u8;
+    while exponent != 0 {
+        if exponent & 1 != 0 {
+            inverse = mul(inverse, power);
+        }
+        power = mul(power, power);
+        exponent >>= 1;
+    }
+    mul(a, inverse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"AGE-SECRET-KEY-1 stands in for any secret";
+
+    #[test]
+    fn any_threshold_of_the_shares_recover_the_secret() {
+        let shares = split(SECRET, 3, 5).unwrap();
+        for picked in [[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+            let subset: Vec<Share> = picked.iter().map(|&i| shares[i].clone()).collect();
+            assert_eq!(combine(&subset).unwrap(), SECRET);
+        }
+        assert!(matches!(combine(&shares[..2]), Err(Error::NoIdentities)));
+        assert!(combine(&[shares[0].clone(), shares[0].clone(), shares[1].clone()]).is_err());
+    }
+
+    #[test]
+    fn shares_of_different_splits_do_not_mix() {
+        let first = split(SECRET, 2, 3).unwrap();
+        let second = split(SECRET, 2, 3).unwrap();
+        assert!(combine(&[first[0].clone(), second[1].clone()]).is_err());
+    }
+
+    #[test]
+    fn shares_round_trip_through_text() {
+        let share = split(SECRET, 2, 2).unwrap().remove(1);
+        let text = format!("# a comment\n{share}\n");
+        assert_eq!(text.parse::<Share>().unwrap(), share);
+        assert!("sage-share-v1 00 2 1 ab".parse::<Share>().is_err());
+    }
+}
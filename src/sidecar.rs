@@ -0,0 +1,556 @@
+//! Parity sidecar files, for `protect --parity-sidecar`.
+//!
+//! The archive stays a plain age file, the same one `--compat` writes, so age and tar read
+//! it without sage, and its error correction goes to a separate file named after it with
+//! `.ecc` appended, as [`path`] returns. The age file is cut into groups of `data_shards`
+//! shards, as in the [inline layout](crate::fec), and the sidecar holds the CRC32 of every
+//! data shard, which tells the damaged ones apart, and the Reed–Solomon parity shards of
+//! every group. Any `data_shards` intact shards of a group are enough to rebuild it, so the
+//! archive can be repaired as long as both files are there.
+//!
+//! Layout:
+//!
+//! ```text
+//! header   MAGIC | version | data | parity | reserved | shard_size | crc32
+//! group    crc32 of every data shard | crc32 of those
+//!          parity shard ...: crc32 | shard bytes
+//! trailer  length | crc32
+//! ```
+//!
+//! `length` is the size of the age file, which is only known once it is written. Every
+//! group but the last holds `data_shards * shard_size` bytes of it, and the last one
+//! shards the rest evenly, as in the inline layout, so a group is found without reading
+//! the ones before it. All integers are little-endian.
+
+use crate::fec::{FecParams, FecStats};
+use log::{debug, error, warn};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Magic bytes at the start of a sidecar.
+pub const MAGIC: &[u8; 8] = b"SAGEECC\0";
+/// Version of the sidecar layout.
+pub const VERSION: u8 = 1;
+
+const HEADER_LEN: usize = 20;
+const TRAILER_LEN: usize = 12;
+const CRC_LEN: usize = 4;
+
+/// The sidecar of the archive at `archive`: its path with `.ecc` appended.
+pub fn path(archive: &Path) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push(".ecc");
+    PathBuf::from(path)
+}
+
+/// Opens the archive at `archive` through its sidecar, if it has one.
+///
+/// A sidecar that cannot be read, or that was written for a shorter file, is ignored with a
+/// warning, and the archive is read as it is.
+pub fn open(archive: &Path) -> io::Result<Option<SidecarReader<File, File>>> {
+    let sidecar_path = path(archive);
+    let sidecar = match File::open(&sidecar_path) {
+        Ok(sidecar) => sidecar,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let file = File::open(archive)?;
+    let len = file.metadata()?.len();
+    let reader = match SidecarReader::new(file, sidecar) {
+        Ok(reader) => reader,
+        Err(e) => {
+            warn!("Ignoring parity sidecar {}: {e}", sidecar_path.display());
+            return Ok(None);
+        }
+    };
+    if len > reader.length() {
+        warn!(
+            "Ignoring parity sidecar {}: it was written for {} bytes, but the archive has {len}.",
+            sidecar_path.display(),
+            reader.length()
+        );
+        return Ok(None);
+    }
+    debug!(
+        "Reading the archive through parity sidecar {}.",
+        sidecar_path.display()
+    );
+    Ok(Some(reader))
+}
+
+fn encode_header(params: FecParams) -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+    header[..8].copy_from_slice(MAGIC);
+    header[8] = VERSION;
+    header[9] = params.data_shards;
+    header[10] = params.parity_shards;
+    header[12..16].copy_from_slice(&params.shard_size.to_le_bytes());
+    let crc = crc32fast::hash(&header[..HEADER_LEN - CRC_LEN]);
+    header[HEADER_LEN - CRC_LEN..].copy_from_slice(&crc.to_le_bytes());
+    header
+}
+
+fn decode_header(header: &[u8; HEADER_LEN]) -> io::Result<FecParams> {
+    if !header.starts_with(MAGIC) {
+        return Err(invalid_data("not a sage parity sidecar"));
+    }
+    let (checked, crc) = header.split_at(HEADER_LEN - CRC_LEN);
+    if crc32fast::hash(checked) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return Err(invalid_data("the header of the parity sidecar is damaged"));
+    }
+    if header[8] != VERSION {
+        return Err(invalid_data(&format!(
+            "parity sidecar version {} is not supported; please upgrade sage to read it",
+            header[8]
+        )));
+    }
+    let params = FecParams {
+        data_shards: header[9],
+        parity_shards: header[10],
+        shard_size: u32::from_le_bytes(header[12..16].try_into().unwrap()),
+    };
+    params.validate()?;
+    Ok(params)
+}
+
+/// The shard size of a group holding `data_len` bytes of the age file.
+fn shard_size(params: FecParams, data_len: usize) -> usize {
+    if data_len == params.group_capacity() {
+        params.shard_size as usize
+    } else {
+        data_len.div_ceil(params.data_shards as usize).max(1)
+    }
+}
+
+/// The bytes the sidecar stores for a group with shards of `shard_size` bytes.
+fn record_len(params: FecParams, shard_size: usize) -> usize {
+    (params.data_shards as usize + 1) * CRC_LEN
+        + params.parity_shards as usize * (CRC_LEN + shard_size)
+}
+
+fn crc_at(table: &[u8], index: usize) -> u32 {
+    u32::from_le_bytes(
+        table[index * CRC_LEN..(index + 1) * CRC_LEN]
+            .try_into()
+            .unwrap(),
+    )
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reads until `buf` is full or the end is reached, returning the number of bytes read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Passes everything written through it on unchanged, and writes its parity to a sidecar.
+pub struct SidecarWriter<W: Write, E: Write> {
+    inner: W,
+    sidecar: E,
+    params: FecParams,
+    codec: ReedSolomon,
+    buffer: Vec<u8>,
+    length: u64,
+    groups: u64,
+}
+
+impl<W: Write, E: Write> SidecarWriter<W, E> {
+    /// Writes the sidecar header to `sidecar` and prepares to encode groups with `params`.
+    pub fn new(inner: W, mut sidecar: E, params: FecParams) -> io::Result<Self> {
+        let codec = params.codec()?;
+        sidecar.write_all(&encode_header(params))?;
+        Ok(Self {
+            inner,
+            sidecar,
+            params,
+            codec,
+            buffer: Vec::with_capacity(params.group_capacity()),
+            length: 0,
+            groups: 0,
+        })
+    }
+
+    /// Encodes the final group, ends the sidecar and returns both writers.
+    pub fn finish(mut self) -> io::Result<(W, E)> {
+        if !self.buffer.is_empty() {
+            self.write_group()?;
+        }
+        let mut trailer = [0u8; TRAILER_LEN];
+        trailer[..8].copy_from_slice(&self.length.to_le_bytes());
+        let crc = crc32fast::hash(&trailer[..8]);
+        trailer[8..].copy_from_slice(&crc.to_le_bytes());
+        self.sidecar.write_all(&trailer)?;
+        self.inner.flush()?;
+        self.sidecar.flush()?;
+        debug!("Wrote parity of {} groups to the sidecar.", self.groups);
+        Ok((self.inner, self.sidecar))
+    }
+
+    fn write_group(&mut self) -> io::Result<()> {
+        let shard_size = shard_size(self.params, self.buffer.len());
+        let mut shards = vec![vec![0u8; shard_size]; self.params.total_shards()];
+        for (shard, chunk) in shards.iter_mut().zip(self.buffer.chunks(shard_size)) {
+            shard[..chunk.len()].copy_from_slice(chunk);
+        }
+        self.codec
+            .encode(&mut shards)
+            .map_err(|e| invalid_data(&format!("failed to compute parity: {e}")))?;
+
+        let (data, parity) = shards.split_at(self.params.data_shards as usize);
+        let mut table = Vec::with_capacity((data.len() + 1) * CRC_LEN);
+        for shard in data {
+            table.extend_from_slice(&crc32fast::hash(shard).to_le_bytes());
+        }
+        let crc = crc32fast::hash(&table);
+        table.extend_from_slice(&crc.to_le_bytes());
+        self.sidecar.write_all(&table)?;
+        for shard in parity {
+            self.sidecar
+                .write_all(&crc32fast::hash(shard).to_le_bytes())?;
+            self.sidecar.write_all(shard)?;
+        }
+
+        self.buffer.clear();
+        self.groups += 1;
+        Ok(())
+    }
+}
+
+impl<W: Write, E: Write> Write for SidecarWriter<W, E> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        let capacity = self.params.group_capacity();
+        let mut remaining = &buf[..n];
+        while !remaining.is_empty() {
+            let take = remaining.len().min(capacity - self.buffer.len());
+            self.buffer.extend_from_slice(&remaining[..take]);
+            remaining = &remaining[take..];
+            if self.buffer.len() == capacity {
+                self.write_group()?;
+            }
+        }
+        self.length += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads an age file, checking it against its sidecar and repairing damaged shards.
+pub struct SidecarReader<R: Read, E: Read> {
+    inner: R,
+    sidecar: E,
+    params: FecParams,
+    codec: ReedSolomon,
+    /// The length of the age file, from the trailer of the sidecar.
+    length: u64,
+    /// The next group to read.
+    group: u64,
+    /// The group read last, and where it starts in the age file.
+    output: Vec<u8>,
+    start: u64,
+    position: usize,
+    stats: FecStats,
+    skip_unrecoverable: bool,
+}
+
+impl<R: Read, E: Read + Seek> SidecarReader<R, E> {
+    /// Reads the header and trailer of `sidecar` to check the age file read from `inner`.
+    pub fn new(inner: R, mut sidecar: E) -> io::Result<Self> {
+        let mut header = [0u8; HEADER_LEN];
+        sidecar.seek(SeekFrom::Start(0))?;
+        sidecar.read_exact(&mut header)?;
+        let params = decode_header(&header)?;
+        let codec = params.codec()?;
+
+        let mut trailer = [0u8; TRAILER_LEN];
+        sidecar.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+        sidecar.read_exact(&mut trailer)?;
+        let (length, crc) = trailer.split_at(8);
+        if crc32fast::hash(length) != u32::from_le_bytes(crc.try_into().unwrap()) {
+            return Err(invalid_data(
+                "the parity sidecar is truncated or its trailer is damaged",
+            ));
+        }
+        let length = u64::from_le_bytes(length.try_into().unwrap());
+        sidecar.seek(SeekFrom::Start(HEADER_LEN as u64))?;
+        debug!(
+            "Parity sidecar of {length} bytes with {} data + {} parity shards of {} bytes.",
+            params.data_shards, params.parity_shards, params.shard_size
+        );
+        Ok(Self {
+            inner,
+            sidecar,
+            params,
+            codec,
+            length,
+            group: 0,
+            output: Vec::new(),
+            start: 0,
+            position: 0,
+            stats: FecStats::default(),
+            skip_unrecoverable: false,
+        })
+    }
+}
+
+impl<R: Read, E: Read> SidecarReader<R, E> {
+    /// The length of the age file the sidecar was written for.
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn params(&self) -> FecParams {
+        self.params
+    }
+
+    pub fn stats(&self) -> FecStats {
+        self.stats
+    }
+
+    /// Zero-fills groups that are damaged beyond repair instead of failing.
+    pub fn set_skip_unrecoverable(&mut self, skip: bool) {
+        self.skip_unrecoverable = skip;
+    }
+
+    fn groups(&self) -> u64 {
+        self.length.div_ceil(self.params.group_capacity() as u64)
+    }
+
+    /// Reads, checks and repairs the next group into the output buffer.
+    fn read_group(&mut self) -> io::Result<()> {
+        let capacity = self.params.group_capacity() as u64;
+        let data_len = (self.length - self.group * capacity).min(capacity) as usize;
+        let shard_size = shard_size(self.params, data_len);
+        let data_shards = self.params.data_shards as usize;
+
+        let mut data = vec![0u8; data_shards * shard_size];
+        let read = read_full(&mut self.inner, &mut data[..data_len])?;
+        let mut record = vec![0u8; record_len(self.params, shard_size)];
+        let recorded = read_full(&mut self.sidecar, &mut record)?;
+        let (table, parity) = record.split_at((data_shards + 1) * CRC_LEN);
+        let table_intact = recorded >= table.len()
+            && crc32fast::hash(&table[..data_shards * CRC_LEN]) == crc_at(table, data_shards);
+        if !table_intact {
+            warn!(
+                "Parity sidecar group {}: the checksums of its data shards are damaged; \
+                 taking the archive as it is.",
+                self.group
+            );
+        }
+
+        let mut shards: Vec<Option<Vec<u8>>> = Vec::with_capacity(self.params.total_shards());
+        for (index, shard) in data.chunks(shard_size).enumerate() {
+            let present = read >= ((index + 1) * shard_size).min(data_len);
+            let intact =
+                present && (!table_intact || crc32fast::hash(shard) == crc_at(table, index));
+            shards.push(intact.then(|| shard.to_vec()));
+        }
+        for frame in parity.chunks(CRC_LEN + shard_size) {
+            let (crc, shard) = frame.split_at(CRC_LEN);
+            let intact = crc32fast::hash(shard) == u32::from_le_bytes(crc.try_into().unwrap());
+            shards.push(intact.then(|| shard.to_vec()));
+        }
+
+        let damaged = shards.iter().filter(|shard| shard.is_none()).count();
+        self.start = self.group * capacity;
+        self.position = 0;
+        if damaged > self.params.parity_shards as usize {
+            if !self.skip_unrecoverable {
+                error!(
+                    "Error correction group {}: {} of {} shards damaged, at most {} can be repaired.",
+                    self.group,
+                    damaged,
+                    shards.len(),
+                    self.params.parity_shards
+                );
+                return Err(crate::Error::CorruptBlock {
+                    group: self.group,
+                    offset: self.start,
+                }
+                .into_io());
+            }
+            warn!(
+                "Error correction group {}: {} of {} shards damaged, skipping.",
+                self.group,
+                damaged,
+                shards.len()
+            );
+            data.truncate(data_len);
+            data[read..].fill(0);
+            self.output = data;
+            self.stats.unrecoverable_groups += 1;
+            self.stats
+                .first_unrecoverable
+                .get_or_insert((self.group, self.start));
+        } else {
+            if damaged > 0 {
+                self.codec
+                    .reconstruct_data(&mut shards)
+                    .map_err(|e| invalid_data(&format!("failed to repair group: {e}")))?;
+                self.stats.repaired_groups += 1;
+                self.stats.repaired_shards += damaged as u64;
+                if damaged as u64 > self.stats.most_damaged_shards {
+                    self.stats.most_damaged_shards = damaged as u64;
+                    self.stats.most_damaged_group = self.group;
+                }
+                warn!(
+                    "Error correction group {}: repaired {} damaged shards.",
+                    self.group, damaged
+                );
+            }
+            self.output.clear();
+            for shard in shards.iter().take(data_shards) {
+                self.output
+                    .extend_from_slice(shard.as_deref().unwrap_or_default());
+            }
+            self.output.truncate(data_len);
+        }
+        self.group += 1;
+        self.stats.groups += 1;
+        Ok(())
+    }
+}
+
+impl<R: Read, E: Read> Read for SidecarReader<R, E> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.output.len() {
+            if self.group >= self.groups() {
+                return Ok(0);
+            }
+            self.read_group()?;
+        }
+        let n = buf.len().min(self.output.len() - self.position);
+        buf[..n].copy_from_slice(&self.output[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek, E: Read + Seek> Seek for SidecarReader<R, E> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let current = self.start + self.position as u64;
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.length.checked_add_signed(delta),
+            SeekFrom::Current(delta) => current.checked_add_signed(delta),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the archive",
+            )
+        })?;
+        if (self.start..=self.start + self.output.len() as u64).contains(&target) {
+            self.position = (target - self.start) as usize;
+            return Ok(target);
+        }
+        // Every group before the one holding `target` is full, so both files are read from
+        // where it starts.
+        let capacity = self.params.group_capacity() as u64;
+        self.group = (target / capacity).min(self.groups());
+        self.inner.seek(SeekFrom::Start(self.group * capacity))?;
+        let record = record_len(self.params, self.params.shard_size as usize) as u64;
+        self.sidecar
+            .seek(SeekFrom::Start(HEADER_LEN as u64 + self.group * record))?;
+        self.output.clear();
+        self.start = target;
+        self.position = 0;
+        if target < self.length {
+            self.read_group()?;
+            self.position = (target - self.start) as usize;
+        }
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const PARAMS: FecParams = FecParams {
+        data_shards: 4,
+        parity_shards: 2,
+        shard_size: 1024,
+    };
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    fn protect(payload: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut writer = SidecarWriter::new(Vec::new(), Vec::new(), PARAMS).unwrap();
+        for chunk in payload.chunks(700) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn read_back(archive: &[u8], sidecar: &[u8]) -> io::Result<(Vec<u8>, FecStats)> {
+        let mut reader = SidecarReader::new(archive, Cursor::new(sidecar))?;
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out)?;
+        Ok((out, reader.stats()))
+    }
+
+    #[test]
+    fn keeps_the_archive_as_it_is_and_repairs_it_with_the_sidecar() {
+        let data = payload(3 * 4096 + 1000);
+        let (archive, sidecar) = protect(&data);
+        assert_eq!(archive, data);
+        let (out, stats) = read_back(&archive, &sidecar).unwrap();
+        assert_eq!(out, data);
+        assert_eq!((stats.groups, stats.repaired_groups), (4, 0));
+
+        // A burst within two shards of a group, and a cut into the last group.
+        let mut damaged = archive.clone();
+        damaged[4200..6000].fill(0xaa);
+        damaged.truncate(data.len() - 200);
+        let (out, stats) = read_back(&damaged, &sidecar).unwrap();
+        assert_eq!(out, data);
+        assert_eq!((stats.repaired_groups, stats.repaired_shards), (2, 3));
+
+        // Three shards of one group are more than its parity.
+        let mut lost = archive.clone();
+        lost[0..3000].fill(0);
+        assert!(read_back(&lost, &sidecar).is_err());
+        let mut reader = SidecarReader::new(lost.as_slice(), Cursor::new(&sidecar)).unwrap();
+        reader.set_skip_unrecoverable(true);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out.len(), data.len());
+        assert_eq!(reader.stats().unrecoverable_groups, 1);
+    }
+
+    #[test]
+    fn seeks_to_any_offset() {
+        let data = payload(5 * 4096 + 123);
+        let (mut archive, sidecar) = protect(&data);
+        archive[9000..9100].fill(0);
+        let mut reader = SidecarReader::new(Cursor::new(archive), Cursor::new(sidecar)).unwrap();
+        for offset in [20_000u64, 8999, 0, 4096, data.len() as u64 - 5] {
+            reader.seek(SeekFrom::Start(offset)).unwrap();
+            let mut buf = [0u8; 5];
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, data[offset as usize..offset as usize + 5]);
+        }
+        assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), data.len() as u64);
+        assert_eq!(reader.read(&mut [0u8; 8]).unwrap(), 0);
+    }
+}
@@ -0,0 +1,133 @@
+//! Detached minisign signatures of finished archives.
+//!
+//! age proves that only the recipients can read an archive, but anyone who knows a recipient
+//! can make one. A signature made with an Ed25519 minisign key proves who made it. Signatures
+//! are written next to the archive, as `<archive>.minisig`, in the format of the `minisign`
+//! tool, so `minisign -V` can check them too.
+
+use crate::output::AtomicFile;
+use age::secrecy::{ExposeSecret, SecretString};
+use anyhow::{Context, Result, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use log::{debug, info, warn};
+use minisign::{PublicKey, SecretKey, SecretKeyBox, SignatureBox};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Extension appended to the archive path for its signature.
+pub const SIGNATURE_EXTENSION: &str = "minisig";
+
+/// Permissions of signature files, which reveal nothing about the contents.
+const SIGNATURE_MODE: u32 = 0o644;
+
+/// Where the signature of the archive at `archive_path` is kept by default.
+pub fn signature_path(archive_path: &Path) -> PathBuf {
+    let mut path = OsString::from(archive_path.as_os_str());
+    path.push(".");
+    path.push(SIGNATURE_EXTENSION);
+    PathBuf::from(path)
+}
+
+/// Reads the minisign secret key at `path`, calling `passphrase` only if it is encrypted.
+pub fn read_secret_key(
+    path: &Path,
+    passphrase: impl FnOnce() -> Result<SecretString>,
+) -> crate::Result<SecretKey> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read signing key: {}", path.display()))?;
+    let encrypted = contents
+        .lines()
+        .nth(1)
+        .and_then(|line| BASE64.decode(line.trim()).ok())
+        .and_then(|bytes| SecretKey::from_bytes(&bytes).ok())
+        .map(|key| key.is_encrypted())
+        .ok_or_else(|| anyhow!("{} is not a minisign secret key.", path.display()))?;
+    let key_box = SecretKeyBox::from_string(&contents).map_err(anyhow::Error::from)?;
+    let key = if encrypted {
+        debug!("Signing key is encrypted; asking for its passphrase.");
+        let passphrase = passphrase()?.expose_secret().to_string();
+        key_box.into_secret_key(Some(passphrase))
+    } else {
+        key_box.into_unencrypted_secret_key()
+    };
+    Ok(key.with_context(|| format!("Failed to read signing key: {}", path.display()))?)
+}
+
+/// Reads a minisign public key, given either as its base64 string or as the path of a
+/// public key file.
+pub fn read_public_key(key: &str) -> crate::Result<PublicKey> {
+    if let Ok(key) = PublicKey::from_base64(key.trim()) {
+        return Ok(key);
+    }
+    Ok(PublicKey::from_file(key).with_context(|| format!("Failed to read public key: {key}"))?)
+}
+
+/// Signs the archive at `archive_path` with `key`, writing the signature to
+/// [`signature_path`]. An existing signature is only replaced with `overwrite`.
+pub fn sign_file(archive_path: &Path, key: &SecretKey, overwrite: bool) -> crate::Result<PathBuf> {
+    let path = signature_path(archive_path);
+    debug!("Signing {} into {}", archive_path.display(), path.display());
+    let input = File::open(archive_path)
+        .with_context(|| format!("Failed to open archive: {}", archive_path.display()))?;
+    let file_name = archive_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let trusted_comment = format!("timestamp:{timestamp}\tfile:{file_name}\thashed");
+    let signature = minisign::sign(
+        None,
+        key,
+        BufReader::new(input),
+        Some(&trusted_comment),
+        Some("signature from sage secret key"),
+    )
+    .context("Failed to sign archive")?;
+
+    let mut output = AtomicFile::create(&path, SIGNATURE_MODE, overwrite)
+        .with_context(|| format!("Failed to create signature file: {}", path.display()))?;
+    output.write_all(signature.to_string().as_bytes())?;
+    output.commit()?;
+    Ok(path)
+}
+
+/// Checks the signature at `signature_path` of the archive at `archive_path` against `key`.
+pub fn verify_file(
+    archive_path: &Path,
+    signature_path: &Path,
+    key: &PublicKey,
+) -> crate::Result<()> {
+    debug!(
+        "Checking signature {} of {}",
+        signature_path.display(),
+        archive_path.display()
+    );
+    let signature = SignatureBox::from_file(signature_path).with_context(|| {
+        format!(
+            "Failed to read signature file: {}",
+            signature_path.display()
+        )
+    })?;
+    let input = File::open(archive_path)
+        .with_context(|| format!("Failed to open archive: {}", archive_path.display()))?;
+    if let Err(e) = minisign::verify(key, &signature, BufReader::new(input), true, false, false) {
+        warn!("Signature check failed: {e}");
+        return Err(anyhow!(
+            "{} does not carry a valid signature by the given key: {e}",
+            archive_path.display()
+        )
+        .into());
+    }
+    match signature.trusted_comment() {
+        Ok(comment) => info!("Good signature; trusted comment: {comment}"),
+        Err(_) => info!("Good signature."),
+    }
+    Ok(())
+}
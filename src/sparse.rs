@@ -0,0 +1,276 @@
+//! Storing sparse files, such as disk images and preallocated databases, without their holes.
+//!
+//! A hole is a range of a file that reads as zeros but takes no space on disk. Protect finds
+//! the data regions of a file with `SEEK_DATA` and `SEEK_HOLE`, on the platforms that have
+//! them, and stores only those in a GNU sparse entry, whose headers list where each region
+//! goes. Recover recreates the holes by seeking past them as it writes the file. Files
+//! without holes, and every file on other platforms, are stored as usual.
+
+use std::fs::{File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+/// Regions start and end on tar blocks, which the format requires of all but the last.
+const BLOCK: u64 = 512;
+/// Regions listed in the entry header itself; the rest go in extension headers of 21 each.
+const HEADER_REGIONS: usize = 4;
+
+/// Whether entries of type `kind` hold the contents of a regular file, stored whole or
+/// sparse.
+pub fn is_file(kind: tar::EntryType) -> bool {
+    kind.is_file() || kind.is_gnu_sparse()
+}
+
+/// Where the data of a sparse file is: everything else reads as zeros.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SparseMap {
+    /// The data regions, in order. The last ends at the end of the file, even if it is
+    /// empty there.
+    regions: Vec<Range<u64>>,
+}
+
+impl SparseMap {
+    /// Builds the map of a file of `len` bytes whose data is in `data`, sorted, rounding the
+    /// regions out to whole tar blocks. Returns `None` if that leaves no holes.
+    pub(crate) fn new(data: impl IntoIterator<Item = Range<u64>>, len: u64) -> Option<Self> {
+        let mut regions: Vec<Range<u64>> = Vec::new();
+        for region in data {
+            let start = region.start / BLOCK * BLOCK;
+            let end = region.end.div_ceil(BLOCK).saturating_mul(BLOCK).min(len);
+            match regions.last_mut() {
+                Some(last) if start <= last.end => last.end = last.end.max(end),
+                _ if start < end => regions.push(start..end),
+                _ => {}
+            }
+        }
+        if regions.first() == Some(&(0..len)) {
+            return None;
+        }
+        if regions.last().is_none_or(|last| last.end < len) {
+            regions.push(len..len);
+        }
+        Some(Self { regions })
+    }
+
+    /// Finds the data regions of `file`, whose metadata is `metadata`, or returns `None` if
+    /// it has no holes or the platform cannot tell.
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    pub fn find(file: &File, metadata: &Metadata) -> io::Result<Option<Self>> {
+        use std::os::unix::fs::MetadataExt;
+
+        let len = metadata.len();
+        // A file with as many blocks allocated as its size has no holes.
+        if len == 0 || metadata.blocks() * 512 >= len {
+            return Ok(None);
+        }
+        let mut data = Vec::new();
+        let mut pos = 0;
+        while pos < len {
+            let Some(start) = seek(file, pos, libc::SEEK_DATA)? else {
+                break;
+            };
+            // Past the end of the file, there is an implicit hole.
+            let end = seek(file, start, libc::SEEK_HOLE)?.unwrap_or(len).min(len);
+            if end <= start {
+                break;
+            }
+            data.push(start..end);
+            pos = end;
+        }
+        Ok(Self::new(data, len))
+    }
+
+    /// Finds the data regions of `file`: never, on this platform.
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    pub fn find(_file: &File, _metadata: &Metadata) -> io::Result<Option<Self>> {
+        Ok(None)
+    }
+
+    /// The size of the file.
+    pub fn size(&self) -> u64 {
+        self.regions.last().map_or(0, |last| last.end)
+    }
+
+    /// The bytes of data stored, leaving out the holes.
+    pub fn stored(&self) -> u64 {
+        self.regions.iter().map(|r| r.end - r.start).sum()
+    }
+
+    /// Makes `header` that of a sparse entry with this map, and returns the extension
+    /// headers that must follow it, before the data.
+    fn describe(&self, header: &mut tar::Header) -> Vec<u8> {
+        header.set_entry_type(tar::EntryType::GNUSparse);
+        header.set_size(self.stored());
+        let (first, rest) = self
+            .regions
+            .split_at(self.regions.len().min(HEADER_REGIONS));
+        if let Some(gnu) = header.as_gnu_mut() {
+            gnu.set_real_size(self.size());
+            for (slot, region) in gnu.sparse.iter_mut().zip(first) {
+                slot.set_offset(region.start);
+                slot.set_length(region.end - region.start);
+            }
+            gnu.set_is_extended(!rest.is_empty());
+        }
+        let mut extensions = Vec::new();
+        let mut chunks = rest
+            .chunks(tar::GnuExtSparseHeader::new().sparse.len())
+            .peekable();
+        while let Some(chunk) = chunks.next() {
+            let mut extension = tar::GnuExtSparseHeader::new();
+            for (slot, region) in extension.sparse.iter_mut().zip(chunk) {
+                slot.set_offset(region.start);
+                slot.set_length(region.end - region.start);
+            }
+            extension.set_is_extended(chunks.peek().is_some());
+            extensions.extend_from_slice(extension.as_bytes());
+        }
+        extensions
+    }
+}
+
+/// Moves the offset of `file` to the next data or hole at or after `offset`, or returns
+/// `None` if there is none.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn seek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<Option<u64>> {
+    use std::os::unix::io::AsRawFd;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let lseek = libc::lseek64;
+    #[cfg(target_os = "freebsd")]
+    let lseek = libc::lseek;
+    let offset = offset
+        .try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "offset out of range"))?;
+    // SAFETY: lseek only moves the offset of the descriptor, which `file` keeps open.
+    match unsafe { lseek(file.as_raw_fd(), offset, whence) } {
+        -1 => match io::Error::last_os_error() {
+            e if e.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+            e => Err(e),
+        },
+        pos => Ok(Some(pos as u64)),
+    }
+}
+
+/// Reads the contents of a sparse entry from the file it describes: its extension headers,
+/// then its data regions. Hashes the whole file along the way, holes included, so that its
+/// checksum is that of the file as recover recreates it.
+pub struct SparseReader<R> {
+    inner: R,
+    regions: std::vec::IntoIter<Range<u64>>,
+    /// The extension headers not read yet.
+    extensions: io::Cursor<Vec<u8>>,
+    /// What is left of the region being read.
+    current: Range<u64>,
+    hasher: blake3::Hasher,
+}
+
+impl<R: Read + Seek> SparseReader<R> {
+    /// Reads the data of `inner` that `map` lists, making `header` that of a sparse entry.
+    pub fn new(inner: R, map: SparseMap, header: &mut tar::Header) -> Self {
+        let extensions = io::Cursor::new(map.describe(header));
+        Self {
+            inner,
+            regions: map.regions.into_iter(),
+            extensions,
+            current: 0..0,
+            hasher: blake3::Hasher::new(),
+        }
+    }
+
+    /// The hash of the whole file, once all of it has been read.
+    pub fn hash(&self) -> blake3::Hash {
+        self.hasher.finalize()
+    }
+
+    /// Hashes the zeros of the hole from the end of the last region to `end`.
+    fn hash_hole(&mut self, end: u64) {
+        static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+        let mut hole = end - self.current.end;
+        while hole > 0 {
+            let n = hole.min(ZEROS.len() as u64) as usize;
+            self.hasher.update(&ZEROS[..n]);
+            hole -= n as u64;
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for SparseReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.extensions.read(buf)?;
+        if n > 0 {
+            return Ok(n);
+        }
+        while self.current.is_empty() {
+            let Some(next) = self.regions.next() else {
+                return Ok(0);
+            };
+            self.hash_hole(next.start);
+            self.inner.seek(SeekFrom::Start(next.start))?;
+            self.current = next;
+        }
+        let want = buf
+            .len()
+            .min((self.current.end - self.current.start) as usize);
+        let n = self.inner.read(&mut buf[..want])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the file shrank while it was being archived",
+            ));
+        }
+        self.hasher.update(&buf[..n]);
+        self.current.start += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rounds_regions_to_blocks_and_marks_the_end() {
+        assert_eq!(SparseMap::new([0..2048, 2048..4096], 4096), None);
+        assert_eq!(SparseMap::new([0..100, 600..4096], 4096), None);
+        let map = SparseMap::new([100..700, 8192..9000], 1 << 20).unwrap();
+        assert_eq!(map.regions, [0..1024, 8192..9216, (1 << 20)..(1 << 20)]);
+        assert_eq!(map.stored(), 2048);
+        assert_eq!(map.size(), 1 << 20);
+        let empty = SparseMap::new([], 5000).unwrap();
+        assert_eq!(empty.regions, vec![5000..5000]);
+    }
+
+    #[test]
+    fn round_trips_through_tar() {
+        let len = 100 * 8192;
+        let data: Vec<_> = (0..40u64)
+            .map(|i| i * 2 * 8192..i * 2 * 8192 + 1000)
+            .collect();
+        let mut contents = vec![0u8; len as usize];
+        for (i, region) in data.iter().enumerate() {
+            contents[region.start as usize..region.end as usize].fill(i as u8 + 1);
+        }
+        let map = SparseMap::new(data, len).unwrap();
+        let stored = map.stored();
+
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        let mut reader = SparseReader::new(io::Cursor::new(&contents), map, &mut header);
+        let mut builder = tar::Builder::new(Vec::new());
+        builder
+            .append_data(&mut header, "disk.img", &mut reader)
+            .unwrap();
+        assert_eq!(reader.hash(), blake3::hash(&contents));
+        let archive = builder.into_inner().unwrap();
+        assert!((archive.len() as u64) < stored + 8192);
+
+        let mut archive = tar::Archive::new(archive.as_slice());
+        let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+        assert!(is_file(entry.header().entry_type()));
+        assert_eq!(entry.header().size().unwrap(), len);
+        let mut read = Vec::new();
+        entry.read_to_end(&mut read).unwrap();
+        assert!(read == contents);
+    }
+}
@@ -0,0 +1,345 @@
+//! The tar format protect writes entries in, for `--tar-format`.
+//!
+//! [`TarFormat::Pax`], the default, writes ustar headers, preceded by a PAX extended header
+//! for each entry that does not fit in one: long paths and link targets, sizes of 8 GiB and
+//! more, large owner ids, and modification times before 1970 or with fractions of a second.
+//! [`TarFormat::Gnu`] writes GNU headers, with long paths and link targets in GNU long name
+//! entries and large numbers in base-256, and keeps whole seconds of modification times.
+//!
+//! In either format extended attributes are PAX records, and sparse files GNU sparse
+//! entries, which GNU tar and bsdtar read in both. An entry the format cannot represent
+//! fails to archive with an error suggesting the other format, instead of being truncated.
+
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The largest number the 12-byte octal fields of a ustar header hold, such as the size.
+const USTAR_MAX_SIZE: u64 = 0o777_7777_7777;
+/// The largest number the 8-byte octal fields hold, such as the owner ids.
+const USTAR_MAX_ID: u64 = 0o777_7777;
+
+/// The tar format of the entries protect writes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TarFormat {
+    /// POSIX pax: ustar headers, with extended headers for what does not fit.
+    #[default]
+    Pax,
+    /// GNU tar's format: long names in entries of their own, and whole seconds.
+    Gnu,
+}
+
+impl TarFormat {
+    /// An empty header of this format.
+    pub fn header(self) -> tar::Header {
+        match self {
+            TarFormat::Pax => tar::Header::new_ustar(),
+            TarFormat::Gnu => tar::Header::new_gnu(),
+        }
+    }
+
+    /// Sets the modification time of `header` to `mtime`, adding to `extensions` what the
+    /// header cannot hold. Fails for times before 1970 in GNU headers, which cannot hold them.
+    pub fn set_mtime(
+        self,
+        header: &mut tar::Header,
+        extensions: &mut Extensions,
+        mtime: SystemTime,
+    ) -> io::Result<()> {
+        let since_epoch = mtime.duration_since(UNIX_EPOCH);
+        match (self, since_epoch) {
+            (TarFormat::Gnu, Ok(since)) => header.set_mtime(since.as_secs()),
+            (TarFormat::Gnu, Err(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "GNU headers cannot store modification times before 1970; use \
+                     --tar-format pax",
+                ));
+            }
+            (TarFormat::Pax, Ok(since)) => {
+                header.set_mtime(since.as_secs().min(USTAR_MAX_SIZE));
+                if since.subsec_nanos() != 0 || since.as_secs() > USTAR_MAX_SIZE {
+                    extensions.push(b"mtime", format_time("", since).as_bytes());
+                }
+            }
+            (TarFormat::Pax, Err(before)) => {
+                header.set_mtime(0);
+                extensions.push(b"mtime", format_time("-", before.duration()).as_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends an entry at `path` with `header` and the contents read from `data`, linking
+    /// to `link` if it is a link. `extensions` are written in a PAX header before it, along
+    /// with, in PAX, whatever of the entry does not fit in `header`.
+    pub fn append<W: Write>(
+        self,
+        builder: &mut tar::Builder<W>,
+        header: &mut tar::Header,
+        mut extensions: Extensions,
+        path: &Path,
+        link: Option<&Path>,
+        data: impl Read,
+    ) -> io::Result<()> {
+        if self == TarFormat::Gnu {
+            extensions.append(builder, path)?;
+            return match link {
+                Some(link) => builder.append_link(header, path, link),
+                None => builder.append_data(header, path, data),
+            };
+        }
+        let mut records = Extensions::default();
+        if header.set_path(path).is_err() {
+            let bytes = portable_bytes(path, "path")?;
+            set_truncated(&mut header.as_old_mut().name, &bytes);
+            records.push_path(b"path", &bytes);
+        }
+        if let Some(link) = link
+            && header.set_link_name(link).is_err()
+        {
+            let bytes = portable_bytes(link, "link target")?;
+            set_truncated(&mut header.as_old_mut().linkname, &bytes);
+            records.push_path(b"linkpath", &bytes);
+        }
+        let size = header.entry_size()?;
+        if size > USTAR_MAX_SIZE {
+            records.push(b"size", size.to_string().as_bytes());
+        }
+        for (key, id) in [(b"uid", header.uid()?), (b"gid", header.gid()?)] {
+            if id > USTAR_MAX_ID {
+                records.push(key, id.to_string().as_bytes());
+            }
+        }
+        records.0.append(&mut extensions.0);
+        records.append(builder, path)?;
+        header.set_cksum();
+        builder.append(header, data)
+    }
+}
+
+/// The records of a PAX extended header for the entry appended next.
+#[derive(Debug, Default)]
+pub struct Extensions(Vec<u8>);
+
+impl Extensions {
+    /// Adds a `"<length> <key>=<value>\n"` record, where the length counts the whole record.
+    pub fn push(&mut self, key: &[u8], value: &[u8]) {
+        let rest = key.len() + value.len() + 3;
+        let mut len = rest + 1;
+        while len != rest + len.to_string().len() {
+            len = rest + len.to_string().len();
+        }
+        self.0.extend_from_slice(format!("{len} ").as_bytes());
+        self.0.extend_from_slice(key);
+        self.0.push(b'=');
+        self.0.extend_from_slice(value);
+        self.0.push(b'\n');
+    }
+
+    /// Adds a path record, marking it as raw bytes if it is not UTF-8.
+    fn push_path(&mut self, key: &[u8], path: &[u8]) {
+        if std::str::from_utf8(path).is_err() {
+            self.push(b"hdrcharset", b"BINARY");
+        }
+        self.push(key, path);
+    }
+
+    /// Adds a record of `time` in seconds since 1970, with their fraction, and negative
+    /// before.
+    pub fn push_time(&mut self, key: &[u8], time: SystemTime) {
+        let value = match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => format_time("", since),
+            Err(before) => format_time("-", before.duration()),
+        };
+        self.push(key, value.as_bytes());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Appends the extended header, if there are any records, for the entry at `path`.
+    fn append<W: Write>(self, builder: &mut tar::Builder<W>, path: &Path) -> io::Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::XHeader);
+        // The name of an extended header is informational; keep it short enough for ustar.
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().chars().take(80).collect::<String>())
+            .unwrap_or_default();
+        header.set_path(format!("PaxHeaders/{name}"))?;
+        header.set_mode(0o644);
+        header.set_size(self.0.len() as u64);
+        header.set_cksum();
+        builder.append(&header, self.0.as_slice())
+    }
+}
+
+/// Reads the modification time of `entry` from its PAX header, which may be more precise
+/// than the one in its tar header, or earlier than 1970.
+pub fn mtime<R: Read>(entry: &mut tar::Entry<R>) -> io::Result<Option<SystemTime>> {
+    time(entry, b"mtime")
+}
+
+/// Reads the time in the PAX record `key` of `entry`, if it has one.
+pub fn time<R: Read>(entry: &mut tar::Entry<R>, key: &[u8]) -> io::Result<Option<SystemTime>> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(None);
+    };
+    for extension in extensions.flatten() {
+        if extension.key_bytes() == key {
+            return Ok(std::str::from_utf8(extension.value_bytes())
+                .ok()
+                .and_then(parse_time));
+        }
+    }
+    Ok(None)
+}
+
+/// Formats a time `since` the epoch, before it if `sign` is `-`, as PAX records do: in
+/// seconds, with as many decimals as needed.
+fn format_time(sign: &str, since: Duration) -> String {
+    let secs = since.as_secs();
+    match since.subsec_nanos() {
+        0 => format!("{sign}{secs}"),
+        nanos => {
+            let fraction = format!("{nanos:09}");
+            format!("{sign}{secs}.{}", fraction.trim_end_matches('0'))
+        }
+    }
+}
+
+/// Parses a time written by [`format_time`], or by another tool.
+fn parse_time(value: &str) -> Option<SystemTime> {
+    let (before, value) = match value.strip_prefix('-') {
+        Some(value) => (true, value),
+        None => (false, value),
+    };
+    let (secs, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let nanos = format!("{:0<9}", &fraction[..fraction.len().min(9)]);
+    let since = Duration::new(secs.parse().ok()?, nanos.parse().ok()?);
+    if before {
+        UNIX_EPOCH.checked_sub(since)
+    } else {
+        UNIX_EPOCH.checked_add(since)
+    }
+}
+
+/// The bytes of `path` with `/` separators, for a PAX record. Fails if the path holds a
+/// newline, which the tar crate reads as the end of a record.
+fn portable_bytes(path: &Path, what: &str) -> io::Result<Vec<u8>> {
+    #[cfg(unix)]
+    let bytes = path.as_os_str().as_encoded_bytes().to_vec();
+    #[cfg(not(unix))]
+    let bytes = path.to_string_lossy().replace('\\', "/").into_bytes();
+    if bytes.contains(&b'\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "PAX headers cannot store a long {what} with a newline in it; use --tar-format \
+                 gnu"
+            ),
+        ));
+    }
+    Ok(bytes)
+}
+
+/// Fills `field` with as much of `bytes` as fits, for readers that ignore PAX headers.
+fn set_truncated(field: &mut [u8], bytes: &[u8]) {
+    let len = bytes.len().min(field.len());
+    field.fill(0);
+    field[..len].copy_from_slice(&bytes[..len]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes an entry of `size` bytes at `path` dated `time`, and reads back its header,
+    /// size and PAX modification time.
+    fn round_trip(
+        format: TarFormat,
+        path: &str,
+        size: u64,
+        time: SystemTime,
+    ) -> (tar::Header, u64, Option<SystemTime>) {
+        let mut header = format.header();
+        let mut extensions = Extensions::default();
+        header.set_mode(0o644);
+        header.set_size(size);
+        header.set_uid(1 << 30);
+        header.set_gid(0);
+        format
+            .set_mtime(&mut header, &mut extensions, time)
+            .unwrap();
+        let mut builder = tar::Builder::new(Vec::new());
+        let path = Path::new(path);
+        format
+            .append(
+                &mut builder,
+                &mut header,
+                extensions,
+                path,
+                None,
+                io::empty(),
+            )
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let mut archive = tar::Archive::new(archive.as_slice());
+        let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap(), path);
+        let time = mtime(&mut entry).unwrap();
+        (entry.header().clone(), entry.size(), time)
+    }
+
+    #[test]
+    fn stores_what_ustar_cannot_in_pax_records() {
+        let long = "dir/".repeat(80) + "file.txt";
+        let precise = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        let (header, size, time) = round_trip(TarFormat::Pax, &long, 0, precise);
+        assert!(header.as_ustar().is_some());
+        assert_eq!(size, 0);
+        assert_eq!(time, Some(precise));
+
+        let large = (USTAR_MAX_SIZE + 1) * 2;
+        let early = UNIX_EPOCH - Duration::from_millis(1500);
+        let (_, size, time) = round_trip(TarFormat::Pax, "disk.img", large, early);
+        assert_eq!(size, large);
+        assert_eq!(time, Some(early));
+    }
+
+    #[test]
+    fn gnu_keeps_whole_seconds_and_refuses_early_times() {
+        let long = "dir/".repeat(80) + "file.txt";
+        let precise = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        let (header, _, time) = round_trip(TarFormat::Gnu, &long, 0, precise);
+        assert!(header.as_gnu().is_some());
+        assert_eq!(header.mtime().unwrap(), 1_700_000_000);
+        assert_eq!(time, None);
+
+        let mut header = TarFormat::Gnu.header();
+        let early = UNIX_EPOCH - Duration::from_secs(1);
+        let error = TarFormat::Gnu
+            .set_mtime(&mut header, &mut Extensions::default(), early)
+            .unwrap_err();
+        assert!(error.to_string().contains("--tar-format pax"));
+    }
+
+    #[test]
+    fn parses_pax_times() {
+        let since = Duration::new(12, 500_000_000);
+        assert_eq!(format_time("", since), "12.5");
+        assert_eq!(parse_time("12.5"), Some(UNIX_EPOCH + since));
+        assert_eq!(parse_time("-12.5"), Some(UNIX_EPOCH - since));
+        assert_eq!(parse_time("7"), Some(UNIX_EPOCH + Duration::from_secs(7)));
+        assert_eq!(parse_time("1.x"), None);
+    }
+}
@@ -0,0 +1,135 @@
+//! Limiting how fast protect reads its input and writes the archive, for `--bwlimit`.
+//!
+//! A [`Throttle`] lets bytes through at a fixed rate, sleeping when they come faster. Rate
+//! left unused while sage waits on something else is saved up for a short while only, so a
+//! pause does not turn into a burst that saturates the link. Reads and writes are split
+//! into small pieces, so no single call sleeps for long and interrupting stays prompt.
+
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long unused rate is saved up for.
+const BURST: Duration = Duration::from_millis(100);
+/// The smallest piece a read or write is split into.
+const MIN_CHUNK: usize = 4096;
+
+/// Lets bytes through at up to a fixed number per second, shared by every reader or writer
+/// given it.
+pub struct Throttle {
+    bytes_per_sec: u64,
+    /// When the bytes let through so far may all have passed at the rate.
+    next: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.max(1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// The most bytes to pass in one piece: a tenth of a second's worth.
+    fn chunk(&self) -> usize {
+        (self.bytes_per_sec / 10).clamp(MIN_CHUNK as u64, usize::MAX as u64) as usize
+    }
+
+    /// Waits until `bytes` more bytes may have passed at the rate.
+    fn consume(&self, bytes: usize) {
+        let wait = {
+            let mut next = self.next.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            if let Some(earliest) = now.checked_sub(BURST) {
+                *next = (*next).max(earliest);
+            }
+            *next += Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+            next.saturating_duration_since(now)
+        };
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Reads from `inner` at most as fast as the throttle allows, if there is one.
+pub struct ThrottledReader<R> {
+    inner: R,
+    throttle: Option<Arc<Throttle>>,
+}
+
+impl<R: Read> ThrottledReader<R> {
+    pub fn new(inner: R, throttle: Option<Arc<Throttle>>) -> Self {
+        Self { inner, throttle }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(throttle) = &self.throttle else {
+            return self.inner.read(buf);
+        };
+        let len = buf.len().min(throttle.chunk());
+        let n = self.inner.read(&mut buf[..len])?;
+        throttle.consume(n);
+        Ok(n)
+    }
+}
+
+/// Writes to `inner` at most as fast as the throttle allows, if there is one.
+pub struct ThrottledWriter<W> {
+    inner: W,
+    throttle: Option<Arc<Throttle>>,
+}
+
+impl<W: Write> ThrottledWriter<W> {
+    pub fn new(inner: W, throttle: Option<Arc<Throttle>>) -> Self {
+        Self { inner, throttle }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for ThrottledWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(throttle) = &self.throttle else {
+            return self.inner.write(buf);
+        };
+        let len = buf.len().min(throttle.chunk());
+        let n = self.inner.write(&buf[..len])?;
+        throttle.consume(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_to_the_rate() {
+        let throttle = Arc::new(Throttle::new(1 << 20));
+        let started = Instant::now();
+        let mut reader = ThrottledReader::new(&[7u8; 150 << 10][..], Some(throttle.clone()));
+        let mut writer = ThrottledWriter::new(Vec::new(), Some(throttle));
+        io::copy(&mut reader, &mut writer).unwrap();
+        // 300 KiB through a shared 1 MiB/s, less the burst saved up at the start.
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        assert_eq!(writer.into_inner(), vec![7u8; 150 << 10]);
+
+        let mut unlimited = ThrottledWriter::new(Vec::new(), None);
+        unlimited.write_all(&[1; 1 << 20]).unwrap();
+        assert_eq!(unlimited.into_inner().len(), 1 << 20);
+    }
+}
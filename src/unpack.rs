@@ -0,0 +1,551 @@
+//! Where recover extracts archive entries to, and which entries it refuses.
+//!
+//! Every entry path is checked before anything is written. Paths with a `..` component are
+//! always refused, and absolute paths unless they are allowed. A path that leads through a
+//! symlink on disk, whether an earlier entry created it or it was there before, is refused
+//! too, as is an entry that would replace a symlink with anything but another symlink, so
+//! that an archive cannot write outside the output directory. Leading components can be
+//! stripped, as with tar's `--strip-components`.
+//!
+//! On Windows, names the filesystem cannot hold, such as `aux.c`, `notes?.txt` or names
+//! ending in a dot, are extracted with `_` in place of the characters at fault, or before
+//! reserved names: a `:` would otherwise write an alternate data stream.
+//!
+//! An entry whose path already exists, other than a directory where the archive has a
+//! directory too, is a conflict, which is resolved by an [`OnConflict`] policy. On Windows
+//! and macOS, whose filesystems do not tell names apart by case, two entries whose paths
+//! differ only in case are a conflict too, and are renamed rather than overwritten, as are
+//! two entries whose names only differ in Unicode normalization when names are normalized.
+
+use crate::normalize::Normalization;
+use crate::progress::{Progress, warn_progress};
+use anyhow::{Context, Result, anyhow};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+/// How the paths of archive entries map to paths on disk.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PathPolicy {
+    /// Leading components removed from every path; entries with no more are skipped.
+    pub strip_components: usize,
+    /// Extracts absolute paths where they point, instead of refusing them. Symlinks on the
+    /// way are followed for these, since system directories are often links.
+    pub allow_absolute: bool,
+    /// The Unicode normalization form names are extracted in.
+    pub normalize: Normalization,
+}
+
+impl PathPolicy {
+    /// Where the entry archived at `path` is extracted to, below `output` unless it is an
+    /// absolute path that is allowed, or `None`, with a warning also passed on to `progress`,
+    /// if it is refused. An entry
+    /// that is itself a symlink, `is_symlink`, may replace a symlink on disk.
+    pub fn destination(
+        &self,
+        output: &Path,
+        path: &Path,
+        is_symlink: bool,
+        progress: Option<&Arc<dyn Progress>>,
+    ) -> crate::Result<Option<PathBuf>> {
+        if path.components().any(|c| c == Component::ParentDir) {
+            warn_progress!(
+                progress,
+                "Skipping unsafe archive path with `..`: {}",
+                path.display()
+            );
+            return Ok(None);
+        }
+        let root: PathBuf = path
+            .components()
+            .take_while(|c| matches!(c, Component::Prefix(_) | Component::RootDir))
+            .collect();
+        let absolute = !root.as_os_str().is_empty();
+        if absolute && !self.allow_absolute {
+            warn_progress!(
+                progress,
+                "Skipping absolute archive path: {}",
+                path.display()
+            );
+            return Ok(None);
+        }
+        let Some(mut rel) = self.strip(path) else {
+            if self.strip_components > 0 {
+                debug!("Nothing is left of {} once stripped.", path.display());
+            }
+            return Ok(None);
+        };
+        if cfg!(windows)
+            && let Some(renamed) = windows_path(&rel)
+        {
+            warn_progress!(
+                progress,
+                "Extracting {} as {}: Windows does not allow the name.",
+                path.display(),
+                renamed.display()
+            );
+            rel = renamed;
+        }
+        if absolute {
+            return Ok(Some(root.join(rel)));
+        }
+        if let Some(link) = symlink_on_the_way(output, &rel, is_symlink)
+            .with_context(|| format!("Failed to check {}", output.join(&rel).display()))?
+        {
+            warn_progress!(
+                progress,
+                "Skipping {}: it would be written through or over the symlink {}.",
+                path.display(),
+                link.display()
+            );
+            return Ok(None);
+        }
+        Ok(Some(output.join(rel)))
+    }
+
+    /// The path archived at `path` without its root and stripped components, in the
+    /// normalization form, or `None` if nothing is left.
+    pub fn strip(&self, path: &Path) -> Option<PathBuf> {
+        let rel: PathBuf = path
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .skip(self.strip_components)
+            .collect();
+        (!rel.as_os_str().is_empty()).then(|| self.normalize.path(&rel).into_owned())
+    }
+}
+
+/// Names Windows reserves for devices, with or without an extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$", "COM0", "COM1", "COM2", "COM3", "COM4",
+    "COM5", "COM6", "COM7", "COM8", "COM9", "COM¹", "COM²", "COM³", "LPT0", "LPT1", "LPT2", "LPT3",
+    "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9", "LPT¹", "LPT²", "LPT³",
+];
+
+/// `rel` with every component Windows cannot hold made into one it can, or `None` if all
+/// of them are fine already.
+fn windows_path(rel: &Path) -> Option<PathBuf> {
+    let mut renamed = false;
+    let path = rel
+        .components()
+        .map(|component| {
+            let name = component.as_os_str().to_string_lossy();
+            match windows_name(&name) {
+                Some(name) => {
+                    renamed = true;
+                    name.into()
+                }
+                None => component.as_os_str().to_os_string(),
+            }
+        })
+        .collect();
+    renamed.then_some(path)
+}
+
+/// A name Windows can hold in place of `name`, or `None` if it can hold `name` itself.
+fn windows_name(name: &str) -> Option<String> {
+    let mut fixed: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_ascii_control() => '_',
+            c => c,
+        })
+        .collect();
+    // Windows drops trailing dots and spaces, so `notes.` would be written as `notes`.
+    let kept = fixed.trim_end_matches(['.', ' ']).len();
+    if kept < fixed.len() {
+        fixed.replace_range(kept.., &"_".repeat(fixed.len() - kept));
+    }
+    let stem = fixed.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        fixed.insert(0, '_');
+    }
+    (fixed != name).then_some(fixed)
+}
+
+/// The first symlink among the existing parents of `rel` below `output`, or `rel` itself if
+/// it is a symlink and may not be replaced.
+fn symlink_on_the_way(
+    output: &Path,
+    rel: &Path,
+    replaces_link: bool,
+) -> io::Result<Option<PathBuf>> {
+    let mut path = output.to_path_buf();
+    let mut components = rel.components().peekable();
+    while let Some(component) = components.next() {
+        path.push(component);
+        let is_last = components.peek().is_none();
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                if !(is_last && replaces_link) {
+                    return Ok(Some(path));
+                }
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+/// What to do with an entry whose path already exists on disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnConflict {
+    /// Replace what is there.
+    #[default]
+    Overwrite,
+    /// Keep what is there and leave the entry out.
+    Skip,
+    /// Extract the entry under a free name, such as `notes.1.txt`.
+    Rename,
+    /// Stop the recovery.
+    Error,
+}
+
+/// Resolves the conflicts of one extraction with an [`OnConflict`] policy, and remembers
+/// where each entry in conflict went, for hard links to it.
+#[derive(Debug, Default)]
+pub struct Conflicts {
+    policy: OnConflict,
+    /// Entries in conflict by archive path: where they were written, or `None` if skipped.
+    moved: HashMap<PathBuf, Option<PathBuf>>,
+    /// Whether paths that differ only in case are the same path, as on Windows and macOS.
+    fold_case: bool,
+    /// Whether different archive paths may be extracted to the same path.
+    merged_names: bool,
+    /// The archive path of each entry extracted so far, by its destination, in lower case
+    /// if paths are folded, if those are tracked.
+    folded: HashMap<String, PathBuf>,
+    skipped: u64,
+    renamed: u64,
+}
+
+impl Conflicts {
+    pub fn new(policy: OnConflict) -> Self {
+        Self {
+            policy,
+            fold_case: cfg!(any(windows, target_os = "macos")),
+            ..Self::default()
+        }
+    }
+
+    /// Notes whether different archive paths may be extracted to the same path, as they are
+    /// when names are normalized, so that each after the first is a conflict.
+    pub fn track_merged_names(mut self, merged: bool) -> Self {
+        self.merged_names = merged;
+        self
+    }
+
+    /// Where to write the entry archived at `rel_path`, which belongs at `path`, or `None` to
+    /// skip it. Fails if the policy is to stop at conflicts.
+    pub fn resolve(
+        &mut self,
+        rel_path: &Path,
+        path: PathBuf,
+        is_dir: bool,
+    ) -> crate::Result<Option<PathBuf>> {
+        let mut policy = self.policy;
+        if let Some(first) = self.collision(rel_path, &path, is_dir)
+            && policy == OnConflict::Overwrite
+        {
+            warn!(
+                "{} is extracted to the same path as {}; renaming it.",
+                rel_path.display(),
+                first.display()
+            );
+            policy = OnConflict::Rename;
+        }
+        let existing = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Some(path)),
+            Err(e) => {
+                return Err(anyhow!(e)
+                    .context(format!("Failed to check {}", path.display()))
+                    .into());
+            }
+        };
+        if policy == OnConflict::Overwrite || (is_dir && existing.is_dir()) {
+            return Ok(Some(path));
+        }
+        let resolved = match policy {
+            OnConflict::Overwrite => unreachable!("returned above"),
+            OnConflict::Skip => {
+                debug!("Keeping existing {}.", path.display());
+                self.skipped += 1;
+                None
+            }
+            OnConflict::Rename => {
+                let free = free_name(&path)?;
+                debug!("Extracting {} as {}.", rel_path.display(), free.display());
+                self.renamed += 1;
+                Some(free)
+            }
+            OnConflict::Error => {
+                warn!("{} already exists.", path.display());
+                return Err(anyhow!(
+                    "{} already exists; recover into an empty directory, or choose what to do \
+                     with existing files with --on-conflict.",
+                    path.display()
+                )
+                .into());
+            }
+        };
+        self.moved.insert(rel_path.to_path_buf(), resolved.clone());
+        Ok(resolved)
+    }
+
+    /// The earlier entry extracted to `path` from another archive path than `rel_path`, if
+    /// paths are tracked and there is one, and `rel_path` is not a directory, which is
+    /// merged with the earlier one instead.
+    fn collision(&mut self, rel_path: &Path, path: &Path, is_dir: bool) -> Option<PathBuf> {
+        if !self.fold_case && !self.merged_names {
+            return None;
+        }
+        let key = if self.fold_case {
+            path.to_string_lossy().to_lowercase()
+        } else {
+            path.to_string_lossy().into_owned()
+        };
+        match self.folded.get(&key) {
+            Some(first) if first != rel_path && !is_dir => Some(first.clone()),
+            Some(_) => None,
+            None => {
+                self.folded.insert(key, rel_path.to_path_buf());
+                None
+            }
+        }
+    }
+
+    /// Where the entry archived at `rel_path` went if it was in conflict: `Some(None)` if it
+    /// was skipped.
+    pub fn moved(&self, rel_path: &Path) -> Option<Option<&Path>> {
+        self.moved.get(rel_path).map(Option::as_deref)
+    }
+
+    /// Logs how many entries were skipped or renamed, if any.
+    pub fn report(&self) {
+        if self.skipped > 0 {
+            info!(
+                "Skipped {} entries whose paths already existed.",
+                self.skipped
+            );
+        }
+        if self.renamed > 0 {
+            info!(
+                "Renamed {} entries whose paths already existed.",
+                self.renamed
+            );
+        }
+    }
+}
+
+/// The first of `name.1.ext`, `name.2.ext` and so on next to `path` that does not exist.
+fn free_name(path: &Path) -> Result<PathBuf> {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    for n in 1.. {
+        let candidate = path.with_file_name(format!("{stem}.{n}{extension}"));
+        match fs::symlink_metadata(&candidate) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(candidate),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to check {}", candidate.display()));
+            }
+            Ok(_) => {}
+        }
+    }
+    unreachable!("some name is free")
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_traversal_absolute_paths_and_symlinks() {
+        let output = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), output.path().join("evil")).unwrap();
+        let policy = PathPolicy::default();
+        let destination = |policy: PathPolicy, path: &str, is_symlink| {
+            policy
+                .destination(output.path(), Path::new(path), is_symlink, None)
+                .unwrap()
+        };
+
+        assert_eq!(
+            destination(policy, "./sub/file", false),
+            Some(output.path().join("sub/file"))
+        );
+        assert_eq!(destination(policy, "sub/../../file", false), None);
+        assert_eq!(destination(policy, "/etc/passwd", false), None);
+        assert_eq!(destination(policy, "evil/file", false), None);
+        assert_eq!(destination(policy, "evil", false), None);
+        assert_eq!(
+            destination(policy, "evil", true),
+            Some(output.path().join("evil"))
+        );
+
+        let stripped = PathPolicy {
+            strip_components: 1,
+            ..policy
+        };
+        assert_eq!(
+            destination(stripped, "top/sub/file", false),
+            Some(output.path().join("sub/file"))
+        );
+        assert_eq!(destination(stripped, "top", false), None);
+
+        let absolute = PathPolicy {
+            allow_absolute: true,
+            ..policy
+        };
+        assert_eq!(
+            destination(absolute, "/etc/passwd", false),
+            Some(PathBuf::from("/etc/passwd"))
+        );
+    }
+
+    #[test]
+    fn makes_names_windows_can_hold() {
+        assert_eq!(windows_name("notes.txt"), None);
+        assert_eq!(windows_name("auxiliary.c"), None);
+        assert_eq!(windows_name("aux.c").as_deref(), Some("_aux.c"));
+        assert_eq!(windows_name("Nul").as_deref(), Some("_Nul"));
+        assert_eq!(
+            windows_name("COM1 .tar.gz").as_deref(),
+            Some("_COM1 .tar.gz")
+        );
+        assert_eq!(windows_name("what?: \"x\"").as_deref(), Some("what__ _x_"));
+        assert_eq!(windows_name("ends in. .").as_deref(), Some("ends in___"));
+        assert_eq!(windows_name("tab\there").as_deref(), Some("tab_here"));
+        assert_eq!(
+            windows_path(Path::new("con/ok/file:stream")),
+            Some(PathBuf::from("_con/ok/file_stream"))
+        );
+        assert_eq!(windows_path(Path::new("ok/fine")), None);
+    }
+
+    #[test]
+    fn renames_entries_that_differ_only_in_case() {
+        let output = tempfile::tempdir().unwrap();
+        let mut conflicts = Conflicts::new(OnConflict::Overwrite);
+        conflicts.fold_case = true;
+        let first = output.path().join("README");
+        assert_eq!(
+            conflicts
+                .resolve(Path::new("README"), first.clone(), false)
+                .unwrap(),
+            Some(first.clone())
+        );
+        fs::write(&first, "first").unwrap();
+        // A second copy of the same path is overwritten as usual.
+        assert_eq!(
+            conflicts
+                .resolve(Path::new("README"), first.clone(), false)
+                .unwrap(),
+            Some(first.clone())
+        );
+        // This filesystem tells the names apart, so stand in for one that does not.
+        let second = output.path().join("readme");
+        fs::write(&second, "first").unwrap();
+        assert_eq!(
+            conflicts
+                .resolve(Path::new("readme"), second, false)
+                .unwrap(),
+            Some(output.path().join("readme.1"))
+        );
+        assert_eq!(
+            conflicts.moved(Path::new("readme")),
+            Some(Some(output.path().join("readme.1").as_path()))
+        );
+
+        let mut conflicts = Conflicts::new(OnConflict::Skip);
+        conflicts.fold_case = true;
+        let dir = output.path().join("Docs");
+        fs::create_dir(&dir).unwrap();
+        conflicts
+            .resolve(Path::new("Docs"), dir.clone(), true)
+            .unwrap();
+        let same_dir = output.path().join("docs");
+        assert_eq!(
+            conflicts
+                .resolve(Path::new("docs"), same_dir.clone(), true)
+                .unwrap(),
+            Some(same_dir)
+        );
+    }
+
+    #[test]
+    fn normalizes_names_and_renames_entries_they_merge() {
+        let output = tempfile::tempdir().unwrap();
+        let policy = PathPolicy {
+            normalize: Normalization::Nfc,
+            ..PathPolicy::default()
+        };
+        let composed = output.path().join("caf\u{e9}");
+        assert_eq!(
+            policy
+                .destination(output.path(), Path::new("cafe\u{301}"), false, None)
+                .unwrap(),
+            Some(composed.clone())
+        );
+
+        let mut conflicts = Conflicts::new(OnConflict::Overwrite).track_merged_names(true);
+        assert_eq!(
+            conflicts
+                .resolve(Path::new("caf\u{e9}"), composed.clone(), false)
+                .unwrap(),
+            Some(composed.clone())
+        );
+        fs::write(&composed, "composed").unwrap();
+        assert_eq!(
+            conflicts
+                .resolve(Path::new("cafe\u{301}"), composed, false)
+                .unwrap(),
+            Some(output.path().join("caf\u{e9}.1"))
+        );
+    }
+
+    #[test]
+    fn resolves_conflicts_by_policy() {
+        let output = tempfile::tempdir().unwrap();
+        let path = output.path().join("notes.txt");
+        fs::write(&path, "mine").unwrap();
+        fs::write(output.path().join("notes.1.txt"), "also mine").unwrap();
+        let rel = Path::new("notes.txt");
+
+        let mut conflicts = Conflicts::new(OnConflict::Rename);
+        let renamed = conflicts.resolve(rel, path.clone(), false).unwrap();
+        assert_eq!(renamed, Some(output.path().join("notes.2.txt")));
+        assert_eq!(conflicts.moved(rel), Some(renamed.as_deref()));
+        assert_eq!(conflicts.renamed, 1);
+
+        let mut conflicts = Conflicts::new(OnConflict::Skip);
+        assert_eq!(conflicts.resolve(rel, path.clone(), false).unwrap(), None);
+        assert_eq!(conflicts.moved(rel), Some(None));
+        let dir = output.path().to_path_buf();
+        assert_eq!(
+            conflicts.resolve(Path::new(""), dir.clone(), true).unwrap(),
+            Some(dir)
+        );
+        assert_eq!(conflicts.skipped, 1);
+
+        let mut conflicts = Conflicts::new(OnConflict::Error);
+        assert!(conflicts.resolve(rel, path.clone(), false).is_err());
+        let new = output.path().join("new.txt");
+        assert_eq!(
+            conflicts
+                .resolve(Path::new("new.txt"), new.clone(), false)
+                .unwrap(),
+            Some(new)
+        );
+    }
+}
@@ -0,0 +1,247 @@
+use crate::cancel::CancelReader;
+use crate::checksum::{CHECKSUM_ENTRY, Checksums, HashingReader};
+use crate::{AGE_MAGIC, ARMOR_BEGIN_MARKER, Contents, RecoverOptions, fec, sidecar, sparse};
+use anyhow::{Context, Result, anyhow};
+use log::{debug, info, warn};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Checks an archive end to end without writing any plaintext.
+///
+/// The error correction layer is scanned first and needs no identities. If every group is
+/// intact or repairable and `options` is given, the archive is then decrypted and
+/// decompressed into a sink, which checks the age MAC, the zstd checksums, every tar
+/// header checksum and the stored [checksums](crate::checksum) of the files. Without
+/// `options`, only the age header is parsed, unless the archive is not encrypted, in which
+/// case its contents are checked all the same. A plain age file with a
+/// [parity sidecar](crate::sidecar) is scanned against the sidecar instead.
+///
+/// Returns how many more shards any group could lose and still be repaired, or `None` if the
+/// archive has no error correction layer. With `simulate_loss`, verification fails if losing
+/// that many more shards from a group could make it unrepairable.
+pub fn verify_file(
+    input_path: &Path,
+    options: Option<&RecoverOptions>,
+    simulate_loss: Option<u8>,
+) -> crate::Result<Option<u64>> {
+    let input_file = File::open(input_path)
+        .with_context(|| format!("Failed to open input file: {}", input_path.display()))?;
+    let mut prefix = Vec::with_capacity(ARMOR_BEGIN_MARKER.len());
+    input_file
+        .take(ARMOR_BEGIN_MARKER.len() as u64)
+        .read_to_end(&mut prefix)?;
+
+    let mut unencrypted = false;
+    // A plain age file with a parity sidecar is checked against the sidecar, even if its
+    // age header is damaged.
+    let spare = if let Some(mut sidecar) = sidecar::open(input_path)? {
+        debug!("Scanning parity sidecar.");
+        sidecar.set_skip_unrecoverable(true);
+        io::copy(&mut CancelReader::new(&mut sidecar), &mut io::sink())?;
+        Some(check_stats(
+            sidecar.stats(),
+            sidecar.params(),
+            simulate_loss,
+        )?)
+    } else if !prefix.starts_with(AGE_MAGIC) && !prefix.starts_with(ARMOR_BEGIN_MARKER) {
+        debug!("Scanning error correction layer.");
+        let input_file = CancelReader::new(File::open(input_path)?);
+        let mut fec_reader =
+            fec::FecReader::new(input_file).map_err(crate::recover::header_error)?;
+        fec_reader.set_skip_unrecoverable(true);
+        unencrypted = fec_reader.header().unencrypted;
+        io::copy(&mut fec_reader, &mut io::sink())?;
+
+        Some(check_stats(
+            fec_reader.stats(),
+            fec_reader.params(),
+            simulate_loss,
+        )?)
+    } else {
+        warn!("Archive has no error correction layer; skipping parity checks.");
+        if simulate_loss.is_some() {
+            return Err(anyhow!(
+                "Archive has no error correction layer, so it cannot survive any lost data."
+            )
+            .into());
+        }
+        None
+    };
+
+    let no_identities = RecoverOptions::new();
+    let options = match options {
+        Some(options) => options,
+        None if unencrypted => {
+            info!("Archive is not encrypted; checking its contents without identities.");
+            &no_identities
+        }
+        None => {
+            check_age_header(input_path)?;
+            warn!("No identities given; skipping decryption and content checks.");
+            return Ok(spare);
+        }
+    };
+
+    debug!("Checking decryption, decompression and archive entries.");
+    verify_contents(open_archive(input_path)?, options)?;
+    Ok(spare)
+}
+
+/// Reports what scanning the error correction of an archive found, and returns its safety
+/// margin.
+fn check_stats(
+    stats: fec::FecStats,
+    params: fec::FecParams,
+    simulate_loss: Option<u8>,
+) -> Result<u64> {
+    info!(
+        "Error correction: {} groups checked, {} damaged but repairable ({} shards), {} beyond repair.",
+        stats.groups, stats.repaired_groups, stats.repaired_shards, stats.unrecoverable_groups
+    );
+    if let Some((group, offset)) = stats.first_unrecoverable {
+        return Err(
+            anyhow::Error::from(crate::Error::CorruptBlock { group, offset }).context(format!(
+                "{} error correction groups are damaged beyond repair.",
+                stats.unrecoverable_groups
+            )),
+        );
+    }
+    let spare = stats.spare_shards(params);
+    let shards = params.data_shards as u64 + params.parity_shards as u64;
+    if stats.most_damaged_shards == 0 {
+        info!("Safety margin: every group can lose {spare} of its {shards} shards.");
+    } else {
+        info!(
+            "Safety margin: every group can lose {} more of its {} shards; group {} already has {} damaged.",
+            spare, shards, stats.most_damaged_group, stats.most_damaged_shards
+        );
+    }
+    if let Some(loss) = simulate_loss {
+        check_simulated_loss(loss, spare, &stats)?;
+    }
+    Ok(spare)
+}
+
+/// Parses the age header of the archive, which needs no identities.
+fn check_age_header(input_path: &Path) -> Result<()> {
+    debug!("Checking the age header.");
+    let (source, _) =
+        crate::recover::open_fec_source(CancelReader::new(open_archive(input_path)?), None)?;
+    age::Decryptor::new(source).context("The age header of the archive is invalid")?;
+    info!("age header: valid.");
+    Ok(())
+}
+
+/// Opens the archive, through its parity sidecar if it has one.
+fn open_archive(input_path: &Path) -> Result<Box<dyn Read>> {
+    if let Some(sidecar) = sidecar::open(input_path)? {
+        return Ok(Box::new(sidecar));
+    }
+    let input_file = File::open(input_path)
+        .with_context(|| format!("Failed to open input file: {}", input_path.display()))?;
+    Ok(Box::new(input_file))
+}
+
+/// What [`verify_contents`] read from an archive.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ContentStats {
+    /// The number of entries, or 0 for an archive that holds a single stream.
+    pub entries: u64,
+    /// The bytes of file contents, or of the stream.
+    pub bytes: u64,
+    /// The number of files checked against the stored checksums.
+    pub checked_files: u64,
+}
+
+/// Decrypts and decompresses the archive stream `input` into a sink, reading every entry as
+/// extraction would, which checks the age MAC, the compression checksums, every tar header
+/// checksum and the stored [checksums](crate::checksum) of the files.
+///
+/// Nothing is written to disk, so this proves an archive can be restored without the space
+/// to restore it.
+pub fn verify_contents<R: Read>(input: R, options: &RecoverOptions) -> crate::Result<ContentStats> {
+    let mut archive = match options.open_contents(input)? {
+        Contents::Archive(archive) => archive,
+        Contents::Stream(mut stream) => {
+            let bytes = io::copy(&mut stream, &mut io::sink()).context("Failed to read stream")?;
+            info!(
+                "Contents: a single stream of {} bytes; age MAC and checksums verified.",
+                bytes
+            );
+            return Ok(ContentStats {
+                bytes,
+                ..ContentStats::default()
+            });
+        }
+    };
+    let (mut entries, mut bytes) = (0u64, 0u64);
+    let mut hashes = Vec::new();
+    let mut checksums = None;
+    for entry in archive
+        .entries()
+        .context("Failed to read archive entries")?
+    {
+        let mut entry = entry.context("Failed to read archive entry")?;
+        let path = entry.path()?.into_owned();
+        let is_file = sparse::is_file(entry.header().entry_type());
+        if is_file && path == Path::new(CHECKSUM_ENTRY) {
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            checksums = Checksums::parse(&contents);
+            continue;
+        }
+        let mut reader = HashingReader::new(&mut entry);
+        bytes += io::copy(&mut reader, &mut io::sink())
+            .with_context(|| format!("Failed to read entry: {}", path.display()))?;
+        options.repair_log().attribute(&path.to_string_lossy());
+        if is_file {
+            hashes.push((path, reader.hash()));
+        }
+        entries += 1;
+    }
+    info!(
+        "Contents: {} entries, {} bytes; age MAC and checksums verified.",
+        entries, bytes
+    );
+    let mut stats = ContentStats {
+        entries,
+        bytes,
+        checked_files: 0,
+    };
+
+    let Some(checksums) = checksums else {
+        warn!("Archive stores no file checksums; skipping them.");
+        return Ok(stats);
+    };
+    let mut corrupt = 0;
+    for (path, hash) in &hashes {
+        if checksums.matches(path, hash) == Some(false) {
+            warn!("Checksum mismatch: {}", path.display());
+            corrupt += 1;
+        }
+    }
+    if corrupt > 0 {
+        return Err(anyhow!("{corrupt} files do not match their checksums.").into());
+    }
+    info!("File checksums: {} files verified.", hashes.len());
+    stats.checked_files = hashes.len() as u64;
+    Ok(stats)
+}
+
+/// Fails if losing `loss` more shards from a group could make it unrepairable, given the
+/// `spare` shards every group can still lose.
+fn check_simulated_loss(loss: u8, spare: u64, stats: &fec::FecStats) -> Result<()> {
+    if loss as u64 <= spare {
+        info!("Simulated loss: the archive survives losing {loss} more shards from any group.");
+        return Ok(());
+    }
+    warn!(
+        "Simulated loss: losing {loss} more shards from group {} would make it unrepairable.",
+        stats.most_damaged_group
+    );
+    Err(anyhow!(
+        "The archive cannot survive losing {loss} more shards from a group: group {} can only lose {spare}.",
+        stats.most_damaged_group
+    ))
+}
@@ -0,0 +1,252 @@
+//! Waiting for changes below a directory, for `sage watch`.
+//!
+//! On Linux the tree is watched with inotify, with a watch on every directory and new
+//! directories added as they appear. Elsewhere it is scanned every few seconds and the sizes
+//! and modification times of its files compared.
+
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Longest time spent blocked at once, so an interruption is noticed promptly.
+const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Notices changes to the files and directories below a root directory.
+pub struct Watcher {
+    inner: imp::Watcher,
+}
+
+impl Watcher {
+    /// Starts watching everything below `root`.
+    pub fn new(root: &Path) -> io::Result<Self> {
+        Ok(Self {
+            inner: imp::Watcher::new(root)?,
+        })
+    }
+
+    /// Blocks until something changes, or `timeout` passes, and returns whether something
+    /// changed. Fails once cancellation was requested.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> io::Result<bool> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            sage::cancel::check()?;
+            let slice = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(left) => left.min(CHECK_INTERVAL),
+                    None => return Ok(false),
+                },
+                None => CHECK_INTERVAL,
+            };
+            if self.inner.wait(slice)? {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Blocks until nothing changed for `quiet_period`.
+    pub fn settle(&mut self, quiet_period: Duration) -> io::Result<()> {
+        while self.wait(Some(quiet_period))? {}
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use log::{debug, warn};
+    use std::collections::HashMap;
+    use std::ffi::CString;
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    const MASK: u32 = libc::IN_MODIFY
+        | libc::IN_ATTRIB
+        | libc::IN_CLOSE_WRITE
+        | libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_DELETE_SELF
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_MOVE_SELF
+        | libc::IN_ONLYDIR;
+    const EVENT_LEN: usize = std::mem::size_of::<libc::inotify_event>();
+
+    pub struct Watcher {
+        fd: OwnedFd,
+        dirs: HashMap<libc::c_int, PathBuf>,
+    }
+
+    impl Watcher {
+        pub fn new(root: &Path) -> io::Result<Self> {
+            // SAFETY: inotify_init1 takes no pointers; the result is checked below.
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut watcher = Self {
+                // SAFETY: `fd` is a new descriptor that nothing else owns.
+                fd: unsafe { OwnedFd::from_raw_fd(fd) },
+                dirs: HashMap::new(),
+            };
+            watcher.add_tree(root)?;
+            debug!("Watching {} directories.", watcher.dirs.len());
+            Ok(watcher)
+        }
+
+        /// Watches `dir` and every directory below it, without following symlinks.
+        fn add_tree(&mut self, dir: &Path) -> io::Result<()> {
+            let path = CString::new(dir.as_os_str().as_bytes())?;
+            // SAFETY: `path` is a NUL-terminated string that outlives the call.
+            let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), MASK) };
+            if wd < 0 {
+                let e = io::Error::last_os_error();
+                if e.raw_os_error() == Some(libc::ENOSPC) {
+                    warn!(
+                        "Ran out of inotify watches at {}; raise fs.inotify.max_user_watches.",
+                        dir.display()
+                    );
+                }
+                return Err(e);
+            }
+            self.dirs.insert(wd, dir.to_path_buf());
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    self.add_tree(&entry.path())?;
+                }
+            }
+            Ok(())
+        }
+
+        pub fn wait(&mut self, timeout: Duration) -> io::Result<bool> {
+            let mut poll = libc::pollfd {
+                fd: self.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: `poll` is a valid pollfd for the duration of the call.
+            let ready = unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as libc::c_int) };
+            if ready < 0 {
+                let e = io::Error::last_os_error();
+                return match e.kind() {
+                    io::ErrorKind::Interrupted => Ok(false),
+                    _ => Err(e),
+                };
+            }
+            if ready == 0 {
+                return Ok(false);
+            }
+            self.read_events()?;
+            Ok(true)
+        }
+
+        /// Reads the queued events, watching the directories that were created.
+        fn read_events(&mut self) -> io::Result<()> {
+            let mut buf = vec![0u8; 64 * 1024];
+            loop {
+                // SAFETY: `buf` is valid for writes of its whole length.
+                let n =
+                    unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+                if n < 0 {
+                    let e = io::Error::last_os_error();
+                    return match e.kind() {
+                        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(()),
+                        _ => Err(e),
+                    };
+                }
+                let mut offset = 0;
+                while offset + EVENT_LEN <= n as usize {
+                    // SAFETY: the kernel wrote a whole event header at `offset`.
+                    let event: libc::inotify_event =
+                        unsafe { std::ptr::read_unaligned(buf[offset..].as_ptr().cast()) };
+                    let name = &buf[offset + EVENT_LEN..offset + EVENT_LEN + event.len as usize];
+                    offset += EVENT_LEN + event.len as usize;
+                    self.handle(&event, name);
+                }
+            }
+        }
+
+        fn handle(&mut self, event: &libc::inotify_event, name: &[u8]) {
+            if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                debug!("Too many changes at once; some were not reported.");
+            }
+            if event.mask & libc::IN_IGNORED != 0 {
+                self.dirs.remove(&event.wd);
+                return;
+            }
+            let created = libc::IN_CREATE | libc::IN_MOVED_TO;
+            if event.mask & libc::IN_ISDIR == 0 || event.mask & created == 0 {
+                return;
+            }
+            let Some(parent) = self.dirs.get(&event.wd) else {
+                return;
+            };
+            let name = name.split(|&b| b == 0).next().unwrap_or_default();
+            let dir = parent.join(std::ffi::OsStr::from_bytes(name));
+            debug!("Watching new directory {}", dir.display());
+            if let Err(e) = self.add_tree(&dir) {
+                warn!("Cannot watch {}: {e}", dir.display());
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::collections::BTreeMap;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant, SystemTime};
+
+    /// How often the tree is scanned.
+    const SCAN_INTERVAL: Duration = Duration::from_secs(5);
+
+    type Snapshot = BTreeMap<PathBuf, (u64, Option<SystemTime>)>;
+
+    pub struct Watcher {
+        root: PathBuf,
+        snapshot: Snapshot,
+        next_scan: Instant,
+    }
+
+    impl Watcher {
+        pub fn new(root: &Path) -> io::Result<Self> {
+            Ok(Self {
+                root: root.to_path_buf(),
+                snapshot: scan(root)?,
+                next_scan: Instant::now() + SCAN_INTERVAL,
+            })
+        }
+
+        pub fn wait(&mut self, timeout: Duration) -> io::Result<bool> {
+            let now = Instant::now();
+            if now < self.next_scan {
+                std::thread::sleep(timeout.min(self.next_scan - now));
+                return Ok(false);
+            }
+            self.next_scan = now + SCAN_INTERVAL;
+            let snapshot = scan(&self.root)?;
+            let changed = snapshot != self.snapshot;
+            self.snapshot = snapshot;
+            Ok(changed)
+        }
+    }
+
+    fn scan(root: &Path) -> io::Result<Snapshot> {
+        let mut snapshot = Snapshot::new();
+        let mut dirs = vec![root.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() {
+                    dirs.push(entry.path());
+                }
+                snapshot.insert(entry.path(), (metadata.len(), metadata.modified().ok()));
+            }
+        }
+        Ok(snapshot)
+    }
+}
@@ -0,0 +1,191 @@
+//! Extended attributes, stored as PAX records.
+//!
+//! Attributes are written as `SCHILY.xattr.*` records, as GNU tar and bsdtar do. The tar crate
+//! splits PAX data at newlines, though, so values containing a newline byte are written as
+//! `LIBARCHIVE.xattr.*` records instead, with a URL-encoded name and a base64 value, which
+//! bsdtar also reads.
+//!
+//! POSIX ACLs are the `system.posix_acl_access` and `system.posix_acl_default` attributes on
+//! Linux, so they travel with the other attributes, as do SELinux labels (`security.selinux`)
+//! and macOS metadata such as `com.apple.quarantine` and `com.apple.ResourceFork`.
+
+use crate::tar_format::Extensions;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use log::{debug, warn};
+use std::ffi::OsString;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Prefix of the PAX records that carry extended attributes.
+const PAX_XATTR_PREFIX: &[u8] = b"SCHILY.xattr.";
+/// Prefix of the PAX records that carry extended attributes with encoded names and values.
+const PAX_LIBARCHIVE_XATTR_PREFIX: &[u8] = b"LIBARCHIVE.xattr.";
+
+/// An extended attribute name and value.
+pub type Xattr = (OsString, Vec<u8>);
+
+/// Reads every extended attribute of `path`, or none if the filesystem does not support them.
+#[cfg(unix)]
+pub fn read(path: &Path) -> io::Result<Vec<Xattr>> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut attrs = Vec::new();
+    for name in names {
+        if let Some(value) = xattr::get(path, &name)? {
+            attrs.push((name, value));
+        }
+    }
+    // Filesystems list attributes in an order of their own.
+    attrs.sort();
+    Ok(attrs)
+}
+
+/// Reads every extended attribute of `path`, or none if the filesystem does not support them.
+#[cfg(not(unix))]
+pub fn read(_path: &Path) -> io::Result<Vec<Xattr>> {
+    Ok(Vec::new())
+}
+
+/// Adds PAX records carrying `attrs` to the `extensions` of the entry appended next.
+pub fn push_records(extensions: &mut Extensions, attrs: &[Xattr]) {
+    for (name, value) in attrs {
+        let name = name.as_encoded_bytes();
+        if value.contains(&b'\n') || name.contains(&b'\n') {
+            let mut key = PAX_LIBARCHIVE_XATTR_PREFIX.to_vec();
+            key.extend_from_slice(url_encode(name).as_bytes());
+            extensions.push(&key, BASE64.encode(value).as_bytes());
+        } else {
+            let mut key = PAX_XATTR_PREFIX.to_vec();
+            key.extend_from_slice(name);
+            extensions.push(&key, value);
+        }
+    }
+}
+
+/// Returns the extended attributes stored for `entry`.
+///
+/// Records the tar crate cannot parse, such as raw values with newlines written by other
+/// tools, are skipped with a warning.
+pub fn from_entry<R: Read>(entry: &mut tar::Entry<R>) -> io::Result<Vec<Xattr>> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(Vec::new());
+    };
+    let mut attrs = Vec::new();
+    for extension in extensions {
+        let Ok(extension) = extension else {
+            warn!("Skipping a malformed PAX record; an extended attribute may be lost.");
+            continue;
+        };
+        let key = extension.key_bytes();
+        let value = extension.value_bytes();
+        let (name, value) = if let Some(name) = key.strip_prefix(PAX_XATTR_PREFIX) {
+            (name.to_vec(), value.to_vec())
+        } else if let Some(name) = key.strip_prefix(PAX_LIBARCHIVE_XATTR_PREFIX) {
+            match (url_decode(name), BASE64.decode(value)) {
+                (Some(name), Ok(value)) => (name, value),
+                _ => {
+                    warn!("Skipping a malformed extended attribute record.");
+                    continue;
+                }
+            }
+        } else {
+            continue;
+        };
+        // SAFETY: the name comes from a tar header written on a Unix system, where OS strings
+        // are arbitrary bytes.
+        let name = unsafe { OsString::from_encoded_bytes_unchecked(name) };
+        attrs.push((name, value));
+    }
+    Ok(attrs)
+}
+
+/// Percent-encodes `%`, `=` and every byte outside printable ASCII, like libarchive.
+fn url_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if b.is_ascii_graphic() && b != b'%' && b != b'=' {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
+
+fn url_decode(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut iter = bytes.iter();
+    while let Some(&b) = iter.next() {
+        if b == b'%' {
+            let hex = [*iter.next()?, *iter.next()?];
+            out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            out.push(b);
+        }
+    }
+    Some(out)
+}
+
+/// Restores stored extended attributes to extracted paths.
+///
+/// Attributes outside the `user` namespace usually require root. The first permission
+/// failure is logged once and all further attributes in that namespace are skipped, so
+/// unprivileged restores still succeed.
+#[derive(Default)]
+pub struct XattrRestorer {
+    denied: Vec<Vec<u8>>,
+}
+
+impl XattrRestorer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets every attribute in `attrs` on `path`.
+    pub fn apply(&mut self, path: &Path, attrs: &[Xattr]) -> io::Result<()> {
+        for (name, value) in attrs {
+            let namespace = namespace(name);
+            if self.denied.iter().any(|denied| denied == namespace) {
+                continue;
+            }
+            debug!("Setting {} on {}", name.to_string_lossy(), path.display());
+            match set(path, name, value) {
+                Ok(()) => {}
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::PermissionDenied | io::ErrorKind::Unsupported
+                    ) =>
+                {
+                    warn!(
+                        "Cannot restore {} extended attributes ({e}); skipping them.",
+                        String::from_utf8_lossy(namespace)
+                    );
+                    self.denied.push(namespace.to_vec());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The namespace of an attribute name, such as `user` or `security`.
+fn namespace(name: &OsString) -> &[u8] {
+    let bytes = name.as_encoded_bytes();
+    bytes.split(|&b| b == b'.').next().unwrap_or(bytes)
+}
+
+#[cfg(unix)]
+fn set(path: &Path, name: &OsString, value: &[u8]) -> io::Result<()> {
+    xattr::set(path, name, value)
+}
+
+#[cfg(not(unix))]
+fn set(_path: &Path, _name: &OsString, _value: &[u8]) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}